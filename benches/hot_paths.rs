@@ -0,0 +1,259 @@
+use std::hint::black_box;
+
+use criterion::Criterion;
+use criterion::criterion_group;
+use criterion::criterion_main;
+
+use pbrt_rust::bbox::BBox;
+use pbrt_rust::geometry::point::Point;
+use pbrt_rust::geometry::vector::Vector;
+use pbrt_rust::intersection::Intersectable;
+use pbrt_rust::primitive::FullyRefinable;
+use pbrt_rust::primitive::Primitive;
+use pbrt_rust::ray::Ray;
+use pbrt_rust::rng::RNG;
+use pbrt_rust::sampler::Sampler;
+use pbrt_rust::sampler::sample::Sample;
+use pbrt_rust::shape::Shape;
+use pbrt_rust::spectrum::Spectrum;
+use pbrt_rust::spectrum::SpectrumType;
+use pbrt_rust::texture::imagewrap::ImageWrap;
+use pbrt_rust::texture::mipmap::MIPMap;
+use pbrt_rust::transform::transform::Transform;
+use pbrt_rust::utils::kdtree::KNearest;
+use pbrt_rust::utils::kdtree::KdTree;
+
+// All of the workloads below are synthetic and seeded so that numbers are
+// comparable from run to run.
+
+fn sphere_at(v: Vector, r: f32) -> Primitive {
+    Primitive::simple(Shape::sphere(
+        Transform::translate(&v), Transform::translate(&(-v)),
+        false, r, -r, r, 360.0))
+}
+
+// An n x n x n lattice of small spheres filling [0, n]^3
+fn sphere_lattice(n: usize) -> Vec<Primitive> {
+    let mut prims = Vec::with_capacity(n * n * n);
+    for x in 0..n {
+        for y in 0..n {
+            for z in 0..n {
+                prims.push(sphere_at(Vector::new_with(
+                    x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5), 0.3));
+            }
+        }
+    }
+    prims
+}
+
+// Rays starting outside of [0, n]^3 aimed at random points inside of it
+fn random_rays(n: usize, num_rays: usize) -> Vec<Ray> {
+    let mut rng = RNG::new(0);
+    let extent = n as f32;
+    (0..num_rays).map(|_| {
+        let o = Point::new_with(-1.0, extent * rng.random_float(),
+                                extent * rng.random_float());
+        let target = Point::new_with(extent * rng.random_float(),
+                                     extent * rng.random_float(),
+                                     extent * rng.random_float());
+        Ray::new_with(o.clone(), target - &o, 0.0)
+    }).collect()
+}
+
+fn bench_bbox(c: &mut Criterion) {
+    let bbox = BBox::new_with(Point::new_with(0.0, 0.0, 0.0),
+                              Point::new_with(8.0, 8.0, 8.0));
+    let rays = random_rays(8, 1024);
+    c.bench_function("bbox_intersect_1024_rays", |b| b.iter(|| {
+        for r in rays.iter() {
+            black_box(bbox.intersect(r));
+        }
+    }));
+}
+
+fn bench_triangle(c: &mut Criterion) {
+    // A single quad in the z = 0 plane split into two triangles
+    let points = [Point::new_with(0.0, 0.0, 0.0), Point::new_with(8.0, 0.0, 0.0),
+                  Point::new_with(8.0, 8.0, 0.0), Point::new_with(0.0, 8.0, 0.0)];
+    let mesh = Primitive::simple(Shape::triangle_mesh(
+        Transform::new(), Transform::new(), false, &[0, 1, 2, 0, 2, 3],
+        &points, None, None, None, None));
+    let tris = mesh.fully_refine();
+
+    let mut rng = RNG::new(0);
+    let rays: Vec<Ray> = (0..1024).map(|_| {
+        let o = Point::new_with(8.0 * rng.random_float(), 8.0 * rng.random_float(), 1.0);
+        Ray::new_with(o, Vector::new_with(0.0, 0.0, -1.0), 0.0)
+    }).collect();
+
+    c.bench_function("triangle_intersect_1024_rays", |b| b.iter(|| {
+        for r in rays.iter() {
+            black_box(tris[0].intersect(r));
+        }
+    }));
+
+    c.bench_function("triangle_intersect_p_1024_rays", |b| b.iter(|| {
+        for r in rays.iter() {
+            black_box(tris[0].intersect_p(r));
+        }
+    }));
+}
+
+fn bench_bvh(c: &mut Criterion) {
+    c.bench_function("bvh_build_sah_4096_spheres", |b| {
+        b.iter_with_setup(|| sphere_lattice(16),
+                          |prims| black_box(Primitive::bvh(prims, 4, "sah")))
+    });
+
+    let bvh = Primitive::bvh(sphere_lattice(16), 4, "sah");
+    let rays = random_rays(16, 1024);
+    c.bench_function("bvh_intersect_1024_rays", |b| b.iter(|| {
+        for r in rays.iter() {
+            // Rays are shortened on every hit, so trace a fresh copy
+            black_box(bvh.intersect(&r.clone()));
+        }
+    }));
+
+    c.bench_function("bvh_intersect_p_1024_rays", |b| b.iter(|| {
+        for r in rays.iter() {
+            black_box(bvh.intersect_p(r));
+        }
+    }));
+}
+
+// A foliage-like cloud of leaves with widely varying sizes packed into a
+// few large BVH leaves, which is where the ordering of primitives within a
+// leaf matters the most for shadow rays.
+fn bench_bvh_foliage(c: &mut Criterion) {
+    let mut rng = RNG::new(0);
+    let leaves: Vec<_> = (0..4096).map(|_| {
+        let v = Vector::new_with(16.0 * rng.random_float(),
+                                 16.0 * rng.random_float(),
+                                 16.0 * rng.random_float());
+        sphere_at(v, 0.05 + 0.5 * rng.random_float() * rng.random_float())
+    }).collect();
+
+    let bvh = Primitive::bvh(leaves, 16, "sah");
+    let rays = random_rays(16, 1024);
+    c.bench_function("bvh_intersect_p_foliage_1024_rays", |b| b.iter(|| {
+        for r in rays.iter() {
+            black_box(bvh.intersect_p(r));
+        }
+    }));
+}
+
+fn bench_spectrum(c: &mut Criterion) {
+    let mut rng = RNG::new(0);
+    let rgbs: Vec<Spectrum> = (0..1024).map(|_| {
+        Spectrum::from_rgb([rng.random_float(), rng.random_float(),
+                            rng.random_float()])
+    }).collect();
+    let sampled: Vec<Spectrum> = rgbs.iter().map(|s| {
+        s.into_sampled_spectrum(SpectrumType::Reflectance)
+    }).collect();
+
+    // Start from a zero of the same representation as the inputs
+    let accumulate = |spectra: &[Spectrum]| {
+        spectra.iter().fold(spectra[0] * 0.0, |acc, s| acc + *s * *s * 0.5)
+    };
+
+    c.bench_function("spectrum_rgb_mul_add_1024", |b| b.iter(|| {
+        black_box(accumulate(&rgbs))
+    }));
+
+    c.bench_function("spectrum_sampled_mul_add_1024", |b| b.iter(|| {
+        black_box(accumulate(&sampled))
+    }));
+
+    c.bench_function("spectrum_sampled_to_xyz_1024", |b| b.iter(|| {
+        for s in sampled.iter() {
+            black_box(s.to_xyz());
+        }
+    }));
+}
+
+fn bench_sampler(c: &mut Criterion) {
+    c.bench_function("stratified_sampler_32x32_pixels_4x4_spp", |b| b.iter(|| {
+        let mut sampler = Sampler::stratified(0, 32, 0, 32, 4, 4, true, 0.0, 1.0);
+        let mut samples = vec![Sample::empty(); sampler.maximum_sample_count()];
+        let mut rng = RNG::new(0);
+        let mut total = 0;
+        loop {
+            let count = sampler.get_more_samples(&mut samples, &mut rng);
+            if count == 0 { break; }
+            total += count;
+        }
+        black_box(total)
+    }));
+}
+
+fn bench_texture(c: &mut Criterion) {
+    let mut rng = RNG::new(0);
+    let res = 256;
+    let texels: Vec<f32> = (0..(res * res)).map(|_| rng.random_float()).collect();
+    let trilinear = MIPMap::new(res, res, texels.clone(), true, 8.0, ImageWrap::Repeat);
+    let ewa = MIPMap::new(res, res, texels, false, 8.0, ImageWrap::Repeat);
+
+    let lookups: Vec<(f32, f32, f32, f32)> = (0..1024).map(|_| {
+        (rng.random_float(), rng.random_float(),
+         0.01 * rng.random_float(), 0.01 * rng.random_float())
+    }).collect();
+
+    c.bench_function("mipmap_trilinear_lookup_1024", |b| b.iter(|| {
+        for &(s, t, dsdx, dtdy) in lookups.iter() {
+            black_box(trilinear.lookup(s, t, dsdx, 0.0, 0.0, dtdy));
+        }
+    }));
+
+    c.bench_function("mipmap_ewa_lookup_1024", |b| b.iter(|| {
+        for &(s, t, dsdx, dtdy) in lookups.iter() {
+            black_box(ewa.lookup(s, t, dsdx, 0.0, 0.0, dtdy));
+        }
+    }));
+}
+
+fn bench_photon_gather(c: &mut Criterion) {
+    // Photons clumped into the lower half of the unit cube so that half of
+    // the lookups land in empty space, as they would above a floor
+    let mut rng = RNG::new(0);
+    let photons: Vec<Point> = (0..20000).map(|_| {
+        Point::new_with(rng.random_float(), rng.random_float(), 0.5 * rng.random_float())
+    }).collect();
+    let kdtree = KdTree::new(&photons);
+
+    let lookups: Vec<Point> = (0..256).map(|_| {
+        Point::new_with(rng.random_float(), rng.random_float(), rng.random_float())
+    }).collect();
+    let k = 50;
+    let max_dist_sq = 0.1 * 0.1;
+
+    c.bench_function("photon_gather_kdtree_k50_256", |b| {
+        let mut nearest = KNearest::new(k);
+        b.iter(|| {
+            for m in lookups.iter() {
+                kdtree.k_nearest(m, max_dist_sq, &mut nearest);
+                black_box(nearest.max_dist_sq());
+            }
+        })
+    });
+
+    c.bench_function("photon_gather_naive_k50_256", |b| b.iter(|| {
+        for m in lookups.iter() {
+            let mut found: Vec<f32> = photons.iter()
+                .map(|p| (p - m).length_squared())
+                .filter(|&d| d <= max_dist_sq)
+                .collect();
+            if found.len() > k {
+                found.select_nth_unstable_by(k - 1, |a, b| a.partial_cmp(b).unwrap());
+                found.truncate(k);
+            }
+            black_box(found.iter().cloned().fold(None, |acc: Option<f32>, d| {
+                Some(acc.map_or(d, |a| a.max(d)))
+            }));
+        }
+    }));
+}
+
+criterion_group!(benches, bench_bbox, bench_triangle, bench_bvh, bench_bvh_foliage,
+                 bench_spectrum, bench_sampler, bench_texture, bench_photon_gather);
+criterion_main!(benches);
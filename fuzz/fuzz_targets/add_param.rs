@@ -0,0 +1,30 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use pbrt_rust::params::{add_param, ParamSet, ParamValues};
+
+// Mirrors ParamValues so that the library doesn't need to depend on arbitrary
+#[derive(Arbitrary, Debug)]
+enum Values {
+    Numbers(Vec<f32>),
+    Strings(Vec<String>),
+    Bools(Vec<bool>)
+}
+
+#[derive(Arbitrary, Debug)]
+struct Param {
+    decl: String,
+    values: Values
+}
+
+fuzz_target!(|param: Param| {
+    let values = match param.values {
+        Values::Numbers(v) => ParamValues::Numbers(v),
+        Values::Strings(v) => ParamValues::Strings(v),
+        Values::Bools(v) => ParamValues::Bools(v)
+    };
+
+    let mut params = ParamSet::new();
+    add_param(&mut params, &param.decl, values);
+});
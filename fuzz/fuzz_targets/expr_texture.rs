@@ -0,0 +1,12 @@
+#![no_main]
+
+use std::collections::HashMap;
+
+use libfuzzer_sys::fuzz_target;
+use pbrt_rust::texture::expr::ExprTexture;
+
+// Texture names that aren't declared are reported as errors, so the
+// parser is fuzzed without any textures to refer to.
+fuzz_target!(|source: &str| {
+    let _ = ExprTexture::parse(source, &HashMap::new(), &HashMap::new());
+});
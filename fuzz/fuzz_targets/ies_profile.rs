@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pbrt_rust::light::ies::IESProfile;
+
+// Malformed profiles should come back as errors rather than panics
+fuzz_target!(|data: &[u8]| {
+    if let Ok(contents) = std::str::from_utf8(data) {
+        let _ = IESProfile::parse(contents);
+    }
+});
@@ -1,24 +1,11 @@
-use light::{Light, LightSample};
-use geometry::point::Point;
-use geometry::vector::Vector;
-use spectrum::Spectrum;
-use scene::Scene;
-use visibility_tester::VisibilityTester;
-
-#[derive(Clone, PartialEq, Debug)]
-pub struct AreaLight;
-
-impl Light for AreaLight {
-    fn sample_l(&self, _p: &Point, _f: f32, _ls: LightSample, _ff: f32)
-                -> (Spectrum, Vector, f32, VisibilityTester) {
-        unimplemented!()
-    }
-
-    fn power(&self, _s: &Scene) -> Spectrum {
-        unimplemented!()
-    }
-
-    fn is_delta_light(&self) -> bool {
-        unimplemented!()
-    }
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::vector::Vector;
+use crate::light::Light;
+use crate::spectrum::Spectrum;
+
+// Lights that are attached to geometry in the scene. Rays that hit the
+// geometry see the light's emitted radiance.
+pub trait AreaLight : Light {
+    // The radiance leaving the surface point dg in direction w at time
+    fn l(&self, dg: &DifferentialGeometry, w: &Vector, time: f32) -> Spectrum;
 }
@@ -1,9 +1,9 @@
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
 use std::f32;
-use ray::Ray;
-use utils::Lerp;
+use crate::ray::Ray;
+use crate::utils::Lerp;
 
 pub trait Union<T = Self> : Sized {
     fn union(&self, v: &T) -> Self;
@@ -215,11 +215,11 @@ impl HasBounds for BBox {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use geometry::point::Point;
-    use geometry::normal::Normalize;
-    use geometry::vector::Vector;
-    use intersection::Intersectable;
-    use ray::Ray;
+    use crate::geometry::point::Point;
+    use crate::geometry::normal::Normalize;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::ray::Ray;
     use std::f32;
 
     #[test]
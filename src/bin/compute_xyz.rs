@@ -1,4 +1,3 @@
-extern crate pbrt_rust;
 use pbrt_rust::spectrum::Spectrum;
 
 const NUM_CIE_SAMPLES: usize = 471;
@@ -1,18 +1,498 @@
-use spectrum::Spectrum;
+use std::f32::consts::PI;
+use std::sync::Arc;
 
+use crate::bsdf;
+use crate::bsdf::BSDF;
+use crate::bsdf::BxDF;
+use crate::bsdf::fresnel::Fresnel;
+use crate::bsdf::utils::*;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::normal::Normalize;
+use crate::geometry::vector::{Cross, Dot, Vector};
+use crate::intersection::{Intersectable, Intersection};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::utils::interpolation::{catmull_rom_weights, integrate_catmull_rom,
+                                  sample_catmull_rom_2d};
+
+// The number of albedos and radii that profiles are tabulated at
+const NUM_RHO_SAMPLES: usize = 100;
+const NUM_RADIUS_SAMPLES: usize = 64;
+
+// The number of points along the beam that the profile is integrated with
+const NUM_BEAM_SAMPLES: usize = 100;
+
+fn fr_dielectric(cos_theta: f32, eta: f32) -> f32 {
+    Fresnel::dielectric(1.0, eta).evaluate(cos_theta)[0]
+}
+
+// Polynomial fits to the first two moments of the Fresnel reflectance of
+// a dielectric, i.e. the integrals of F(cos) cos^i over the hemisphere
+fn fresnel_moment1(eta: f32) -> f32 {
+    let (eta2, eta3, eta4, eta5) = (eta * eta, eta * eta * eta, eta.powi(4), eta.powi(5));
+    if eta < 1.0 {
+        0.45966 - 1.73965 * eta + 3.37668 * eta2 - 3.904945 * eta3 + 2.49277 * eta4 -
+            0.68441 * eta5
+    } else {
+        -4.61686 + 11.1136 * eta - 10.4646 * eta2 + 5.11455 * eta3 - 1.27198 * eta4 +
+            0.12746 * eta5
+    }
+}
+
+fn fresnel_moment2(eta: f32) -> f32 {
+    let (eta2, eta3, eta4, eta5) = (eta * eta, eta * eta * eta, eta.powi(4), eta.powi(5));
+    if eta < 1.0 {
+        0.27614 - 0.87350 * eta + 1.12077 * eta2 - 0.65095 * eta3 + 0.07883 * eta4 +
+            0.04860 * eta5
+    } else {
+        let (r_eta, r_eta2, r_eta3) = (1.0 / eta, 1.0 / eta2, 1.0 / eta3);
+        -547.033 + 45.3087 * r_eta3 - 218.725 * r_eta2 + 458.843 * r_eta + 404.557 * eta -
+            189.519 * eta2 + 54.9327 * eta3 - 9.00603 * eta4 + 0.63942 * eta5
+    }
+}
+
+// The multiple scattering part of the radial profile of a medium with unit
+// extinction, found with the photon beam diffusion of Habel et al. (2013),
+// i.e. by summing dipoles placed along the refracted beam.
+fn beam_diffusion_ms(sigma_s: f32, sigma_a: f32, g: f32, eta: f32, r: f32) -> f32 {
+    // Compute reduced scattering coefficients and the diffusion coefficient
+    let sigmap_s = sigma_s * (1.0 - g);
+    let sigmap_t = sigma_a + sigmap_s;
+    let rhop = sigmap_s / sigmap_t;
+    let d_g = (2.0 * sigma_a + sigmap_s) / (3.0 * sigmap_t * sigmap_t);
+    let sigma_tr = (sigma_a / d_g).sqrt();
+
+    // Find the linear extrapolation distance and the exitance weights
+    let fm1 = fresnel_moment1(eta);
+    let fm2 = fresnel_moment2(eta);
+    let ze = -2.0 * d_g * (1.0 + 3.0 * fm2) / (1.0 - 2.0 * fm1);
+    let c_phi = 0.25 * (1.0 - 2.0 * fm1);
+    let c_e = 0.5 * (1.0 - 3.0 * fm2);
+
+    let ed: f32 = (0..NUM_BEAM_SAMPLES).map(|i| {
+        // Sample the depth of the real point source and mirror it
+        let zr = -(1.0 - (i as f32 + 0.5) / NUM_BEAM_SAMPLES as f32).ln() / sigmap_t;
+        let zv = -zr + 2.0 * ze;
+        let dr = (r * r + zr * zr).sqrt();
+        let dv = (r * r + zv * zv).sqrt();
+
+        // The fluence and the gradient of the fluence due to the dipole
+        let phi_d = 0.25 / PI / d_g *
+            ((-sigma_tr * dr).exp() / dr - (-sigma_tr * dv).exp() / dv);
+        let ed_n = 0.25 / PI *
+            (zr * (1.0 + sigma_tr * dr) * (-sigma_tr * dr).exp() / (dr * dr * dr) -
+             zv * (1.0 + sigma_tr * dv) * (-sigma_tr * dv).exp() / (dv * dv * dv));
+
+        // Light that hasn't scattered before reaching the source is left to
+        // beam_diffusion_ss
+        let e = phi_d * c_phi + ed_n * c_e;
+        let kappa = 1.0 - (-2.0 * sigmap_t * (dr + zr)).exp();
+        kappa * rhop * rhop * e
+    }).sum();
+
+    ed / NUM_BEAM_SAMPLES as f32
+}
+
+// The single scattering part of the radial profile, from light that
+// scatters once along the refracted beam
+fn beam_diffusion_ss(sigma_s: f32, sigma_a: f32, g: f32, eta: f32, r: f32) -> f32 {
+    let sigma_t = sigma_a + sigma_s;
+    let rho = sigma_s / sigma_t;
+
+    // Points closer to the surface than t_crit can't scatter light to r
+    // without it being totally internally reflected
+    let t_crit = r * (eta * eta - 1.0).max(0.0).sqrt();
+    let ess: f32 = (0..NUM_BEAM_SAMPLES).map(|i| {
+        let ti = t_crit - (1.0 - (i as f32 + 0.5) / NUM_BEAM_SAMPLES as f32).ln() / sigma_t;
+        let d = (r * r + ti * ti).sqrt();
+        let cos_theta_o = ti / d;
+        let phase = (1.0 - g * g) / (4.0 * PI * (1.0 + g * g + 2.0 * g * cos_theta_o).powf(1.5));
+        rho * (-sigma_t * (d + t_crit)).exp() / (d * d) * phase *
+            (1.0 - fr_dielectric(-cos_theta_o, eta)) * cos_theta_o.abs()
+    }).sum();
+
+    ess / NUM_BEAM_SAMPLES as f32
+}
+
+// The radial profiles of media with unit extinction, tabulated by their
+// albedo and the distance from where light enters them. Each row also holds
+// the running integral of the profile for sampling radii, and the effective
+// albedo, i.e. the integral of the whole row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BSSRDFTable {
+    rho_samples: Vec<f32>,
+    radius_samples: Vec<f32>,
+    profile: Vec<f32>,
+    profile_cdf: Vec<f32>,
+    rho_eff: Vec<f32>
+}
+
+impl BSSRDFTable {
+    // Tabulates the profiles of media with the asymmetry g and the relative
+    // index of refraction eta using photon beam diffusion
+    pub fn beam_diffusion(g: f32, eta: f32) -> BSSRDFTable {
+        // Radii grow exponentially to cover both the sharp peak of the
+        // profile and its long tail. Albedos are spaced more densely close to
+        // one, where the profile changes the most.
+        let radius_samples: Vec<f32> = (0..NUM_RADIUS_SAMPLES).map(|i| {
+            if i == 0 { 0.0 } else { 2.5e-3 * 1.2f32.powi(i as i32 - 1) }
+        }).collect();
+        let rho_samples: Vec<f32> = (0..NUM_RHO_SAMPLES).map(|i| {
+            (1.0 - (-8.0 * i as f32 / (NUM_RHO_SAMPLES - 1) as f32).exp()) / (1.0 - (-8.0f32).exp())
+        }).collect();
+
+        let mut profile = Vec::with_capacity(NUM_RHO_SAMPLES * NUM_RADIUS_SAMPLES);
+        let mut profile_cdf = Vec::with_capacity(NUM_RHO_SAMPLES * NUM_RADIUS_SAMPLES);
+        let mut rho_eff = Vec::with_capacity(NUM_RHO_SAMPLES);
+        for &rho in rho_samples.iter() {
+            // The profile is tabulated in polar coordinates, which includes
+            // the 2 pi r of the integral over the disk
+            let row: Vec<f32> = radius_samples.iter().map(|&r| {
+                2.0 * PI * r * (beam_diffusion_ss(rho, 1.0 - rho, g, eta, r) +
+                                beam_diffusion_ms(rho, 1.0 - rho, g, eta, r))
+            }).collect();
+
+            let (total, cdf) = integrate_catmull_rom(&radius_samples, &row);
+            rho_eff.push(total);
+            profile.extend(row);
+            profile_cdf.extend(cdf);
+        }
+
+        BSSRDFTable { rho_samples, radius_samples, profile, profile_cdf, rho_eff }
+    }
+
+    // Interpolates the profile and the effective albedo of the media with
+    // the given albedo at the optical radius r, if both lie in the table
+    fn lookup(&self, rho: f32, r: f32) -> Option<(f32, f32)> {
+        let (rho_offset, rho_weights) = catmull_rom_weights(&self.rho_samples, rho)?;
+        let (radius_offset, radius_weights) = catmull_rom_weights(&self.radius_samples, r)?;
+
+        let (mut sr, mut rho_eff) = (0.0, 0.0);
+        for (i, &wi) in rho_weights.iter().enumerate().filter(|(_, &w)| w != 0.0) {
+            let row = (rho_offset + i as i32) as usize;
+            rho_eff += wi * self.rho_eff[row];
+            for (j, &wj) in radius_weights.iter().enumerate().filter(|(_, &w)| w != 0.0) {
+                let col = (radius_offset + j as i32) as usize;
+                sr += wi * wj * self.profile[row * self.radius_samples.len() + col];
+            }
+        }
+
+        Some((sr, rho_eff))
+    }
+}
+
+// A BSSRDF that separates into a spatial term, which only depends on the
+// distance between where light enters and leaves the surface, and a
+// directional term for each of those points. The spatial term is found by
+// scaling a tabulated profile by the extinction of the medium.
 #[derive(Clone, Debug, PartialEq)]
 pub struct BSSRDF {
     eta: f32,
     sig_a: Spectrum,
-    sigp_s: Spectrum
+    sigp_s: Spectrum,
+    table: Arc<BSSRDFTable>
 }
 
 impl BSSRDF {
-    pub fn new(sa: Spectrum, sps: Spectrum, et: f32) -> BSSRDF {
-        BSSRDF { eta: et, sig_a: sa, sigp_s: sps }
+    pub fn new(sa: Spectrum, sps: Spectrum, et: f32, table: Arc<BSSRDFTable>) -> BSSRDF {
+        BSSRDF { eta: et, sig_a: sa, sigp_s: sps, table }
     }
 
     pub fn eta(&self) -> f32 { self.eta }
     pub fn sigma_a(&self) -> Spectrum { self.sig_a }
     pub fn sigma_prime_s(&self) -> Spectrum { self.sigp_s }
+
+    // The extinction and albedo of each color channel
+    fn coefficients(&self) -> ([f32; 3], [f32; 3]) {
+        let (sig_a, sigp_s) = (self.sig_a.to_rgb(), self.sigp_s.to_rgb());
+        let mut sigma_t = [0.0; 3];
+        let mut rho = [0.0; 3];
+        for (c, (a, s)) in sig_a.iter().zip(sigp_s.iter()).enumerate() {
+            sigma_t[c] = a + s;
+            rho[c] = if sigma_t[c] > 0.0 { s / sigma_t[c] } else { 0.0 };
+        }
+        (sigma_t, rho)
+    }
+
+    // The radial profile at the distance r from where light entered
+    pub fn sr(&self, r: f32) -> Spectrum {
+        let (sigma_t, rho) = self.coefficients();
+        let mut sr = [0.0; 3];
+        for (c, v) in sr.iter_mut().enumerate() {
+            let r_optical = r * sigma_t[c];
+            if let Some((profile, _)) = self.table.lookup(rho[c], r_optical) {
+                // Undo the polar coordinates of the table and scale it from
+                // unit extinction to the medium's
+                let profile = if r_optical != 0.0 {
+                    profile / (2.0 * PI * r_optical)
+                } else { profile };
+                *v = (profile * sigma_t[c] * sigma_t[c]).max(0.0);
+            }
+        }
+
+        Spectrum::from_rgb(sr)
+    }
+
+    // Samples a radius in proportion to the profile of the given channel,
+    // or returns None if the channel doesn't scatter
+    pub fn sample_sr(&self, channel: usize, u: f32) -> Option<f32> {
+        let (sigma_t, rho) = self.coefficients();
+        if sigma_t[channel] == 0.0 {
+            return None;
+        }
+
+        let t = &self.table;
+        sample_catmull_rom_2d(&t.rho_samples, &t.radius_samples, &t.profile, &t.profile_cdf,
+                              rho[channel], u)
+            .map(|(r, _, _)| r / sigma_t[channel])
+    }
+
+    pub fn pdf_sr(&self, channel: usize, r: f32) -> f32 {
+        let (sigma_t, rho) = self.coefficients();
+        let r_optical = r * sigma_t[channel];
+        match self.table.lookup(rho[channel], r_optical) {
+            Some((profile, rho_eff)) if rho_eff > 0.0 => {
+                let profile = if r_optical != 0.0 {
+                    profile / (2.0 * PI * r_optical)
+                } else { profile };
+                (profile * sigma_t[channel] * sigma_t[channel] / rho_eff).max(0.0)
+            },
+            _ => 0.0
+        }
+    }
+
+    // The directional term for light leaving or entering along a direction
+    // with the given cosine to the normal, normalized to integrate to one
+    pub fn sw(&self, cos_theta: f32) -> f32 {
+        let c = 1.0 - 2.0 * fresnel_moment1(1.0 / self.eta);
+        (1.0 - fr_dielectric(cos_theta, self.eta)) / (c * PI)
+    }
+
+    // The shading frame at po that points are sampled around: the normal
+    // and two tangents
+    fn frame(po: &DifferentialGeometry) -> [Vector; 3] {
+        let ns = Vector::from(po.nn.clone()).normalize();
+        let ss = po.dpdu.clone().normalize();
+        let ts = ns.cross_with(&ss);
+        [ns, ss, ts]
+    }
+
+    // Samples the point pi where light that leaves the surface at po entered
+    // it. Points are sampled on a disk around po according to the profile,
+    // and then projected onto the surface along one of the axes of po's
+    // shading frame by tracing a probe ray through the scene. Only surfaces
+    // with the given material count. Returns pi along with the spatial term
+    // of the BSSRDF and the density of sampling it.
+    pub fn sample_sp(&self, scene: &Scene, po: &DifferentialGeometry, material: &Material,
+                     u1: f32, u2: (f32, f32)) -> Option<(Intersection, Spectrum, f32)> {
+        // Choose the axis to project along, usually the normal
+        let [ns, ss, ts] = BSSRDF::frame(po);
+        let (vx, vy, vz, u1) = if u1 < 0.5 {
+            (ss, ts, ns, u1 * 2.0)
+        } else if u1 < 0.75 {
+            (ts, ns, ss, (u1 - 0.5) * 4.0)
+        } else {
+            (ns, ss, ts, (u1 - 0.75) * 4.0)
+        };
+
+        // Choose the channel whose profile to sample
+        let channel = ((u1 * 3.0) as usize).min(2);
+        let u1 = u1 * 3.0 - channel as f32;
+
+        // Sample a point on the disk, and bound the probe ray by the sphere
+        // that holds nearly all of the profile
+        let r = self.sample_sr(channel, u2.0)?;
+        let r_max = self.sample_sr(channel, 0.999)?;
+        if r < 0.0 || r >= r_max {
+            return None;
+        }
+
+        let phi = 2.0 * PI * u2.1;
+        let l = 2.0 * (r_max * r_max - r * r).sqrt();
+        let start = &po.p + r * (phi.cos() * &vx + phi.sin() * &vy) - l * 0.5 * &vz;
+        let target = &start + l * &vz;
+
+        // Find every intersection with the material along the probe ray
+        let mut found = Vec::new();
+        let mut base = start;
+        loop {
+            let d = &target - &base;
+            if d.length_squared() == 0.0 {
+                break;
+            }
+
+            let ray = Ray::new_with(base.clone(), d, 1e-4);
+            ray.set_maxt(1.0);
+            let isect = match scene.intersect(&ray) {
+                Some(isect) => isect,
+                None => break
+            };
+
+            base = isect.dg.p.clone();
+            if isect.material().is_some_and(|m| ::std::ptr::eq(m, material)) {
+                found.push(isect);
+            }
+        }
+
+        if found.is_empty() {
+            return None;
+        }
+
+        let which = ((u1 * found.len() as f32) as usize).min(found.len() - 1);
+        let num_found = found.len() as f32;
+        let pi = found.swap_remove(which);
+        let pdf = self.pdf_sp(po, &pi.dg) / num_found;
+        let sp = self.sr((&po.p - &pi.dg.p).length());
+        Some((pi, sp, pdf))
+    }
+
+    // The density of sample_sp picking pi, summed over each of the ways it
+    // could have been found
+    pub fn pdf_sp(&self, po: &DifferentialGeometry, pi: &DifferentialGeometry) -> f32 {
+        let frame = BSSRDF::frame(po);
+        let d = &po.p - &pi.p;
+        let ni = Vector::from(pi.nn.clone());
+        let d_local: Vec<f32> = frame.iter().map(|v| v.dot(&d)).collect();
+        let n_local: Vec<f32> = frame.iter().map(|v| v.dot(&ni)).collect();
+
+        // The radius of pi projected along each axis of the frame, and how
+        // often each is chosen
+        let r_proj = [
+            (d_local[1] * d_local[1] + d_local[2] * d_local[2]).sqrt(),
+            (d_local[0] * d_local[0] + d_local[2] * d_local[2]).sqrt(),
+            (d_local[0] * d_local[0] + d_local[1] * d_local[1]).sqrt()
+        ];
+        let axis_prob = [0.5, 0.25, 0.25];
+
+        (0..3).map(|axis| {
+            (0..3).map(|channel| {
+                self.pdf_sr(channel, r_proj[axis]) * n_local[axis].abs() * axis_prob[axis] / 3.0
+            }).sum::<f32>()
+        }).sum()
+    }
+
+    // The BSDF that light entering the surface at pi follows, which only
+    // holds the directional term
+    pub fn entry_bsdf(&self, pi: &Intersection) -> BSDF {
+        let mut bsdf = BSDF::new(pi.dg.clone(), pi.dg.nn.clone());
+        bsdf.add_bxdf(SubsurfaceEntry { bssrdf: self.clone() });
+        bsdf
+    }
+}
+
+// Treats the directional term of a BSSRDF as a diffuse BRDF, so that the
+// light arriving at a point where it enters can be found like any other.
+#[derive(Clone, Debug)]
+struct SubsurfaceEntry {
+    bssrdf: BSSRDF
+}
+
+impl BxDF for SubsurfaceEntry {
+    fn matches_flags(&self, ty: bsdf::BxDFType) -> bool {
+        (bsdf::BxDFType::BSDF_REFLECTION | bsdf::BxDFType::BSDF_DIFFUSE).contains(ty)
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        if same_hemisphere(wo, wi) {
+            Spectrum::from(self.bssrdf.sw(abs_cos_theta(wi)))
+        } else {
+            Spectrum::from(0.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::point::Point;
+    use crate::geometry::normal::Normal;
+
+    fn skin() -> BSSRDF {
+        BSSRDF::new(Spectrum::from_rgb([0.032, 0.17, 0.48]),
+                    Spectrum::from_rgb([0.74, 0.88, 1.01]), 1.33,
+                    Arc::new(BSSRDFTable::beam_diffusion(0.0, 1.33)))
+    }
+
+    #[test]
+    fn it_tabulates_beam_diffusion() {
+        let table = BSSRDFTable::beam_diffusion(0.0, 1.33);
+
+        // Media that scatter more light reflect more of it. The diffusion
+        // approximation slightly overshoots for media that don't absorb any
+        // light, but otherwise they reflect less than they receive.
+        assert_eq!(table.rho_eff[0], 0.0);
+        for w in table.rho_eff.windows(2) {
+            assert!(w[0] <= w[1]);
+        }
+        let (_, rho_eff) = table.lookup(0.95, 0.0).unwrap();
+        assert!(rho_eff > 0.3 && rho_eff < 1.0, "Effective albedo: {}", rho_eff);
+        assert!(*table.rho_eff.last().unwrap() < 1.05);
+
+        // The profiles fall off with the distance
+        let (near, _) = table.lookup(0.8, 0.1).unwrap();
+        let (far, _) = table.lookup(0.8, 5.0).unwrap();
+        assert!(near > far && far > 0.0);
+        assert!(table.lookup(0.8, 1e6).is_none());
+    }
+
+    #[test]
+    fn it_samples_radii_by_their_profile() {
+        let bssrdf = skin();
+        let sr = bssrdf.sr(0.5);
+        assert!(sr.to_rgb().iter().all(|&c| c > 0.0));
+        assert!(bssrdf.sr(1.0).to_rgb()[0] < sr.to_rgb()[0]);
+
+        for channel in 0..3 {
+            // The density of radii integrates to one over the disk...
+            let r_max = bssrdf.sample_sr(channel, 0.999).unwrap();
+            let n = 4000;
+            let dr = r_max / n as f32;
+            let total: f32 = (0..n).map(|i| {
+                let r = (i as f32 + 0.5) * dr;
+                bssrdf.pdf_sr(channel, r) * 2.0 * PI * r * dr
+            }).sum();
+            assert!((total - 0.999).abs() < 0.02, "Channel {}: {}", channel, total);
+
+            // ... and half of the samples lie within the median
+            let median = bssrdf.sample_sr(channel, 0.5).unwrap();
+            let half: f32 = (0..n).map(|i| {
+                let r = (i as f32 + 0.5) * median / n as f32;
+                bssrdf.pdf_sr(channel, r) * 2.0 * PI * r * median / n as f32
+            }).sum();
+            assert!((half - 0.5).abs() < 0.02, "Channel {}: {}", channel, half);
+        }
+
+        // Media that don't scatter can't be sampled
+        let black = BSSRDF::new(Spectrum::from(0.0), Spectrum::from(0.0), 1.33,
+                                bssrdf.table.clone());
+        assert!(black.sample_sr(0, 0.5).is_none());
+    }
+
+    #[test]
+    fn its_directional_term_is_normalized() {
+        let bssrdf = skin();
+        let n = 1000;
+        let total: f32 = (0..n).map(|i| {
+            let cos_theta = (i as f32 + 0.5) / n as f32;
+            bssrdf.sw(cos_theta) * cos_theta * 2.0 * PI / n as f32
+        }).sum();
+        assert!((total - 1.0).abs() < 0.02, "Integral: {}", total);
+    }
+
+    #[test]
+    fn it_finds_the_density_of_points_in_the_plane() {
+        let bssrdf = skin();
+        let mut po = DifferentialGeometry::new();
+        po.nn = Normal::new_with(0.0, 0.0, 1.0);
+        po.dpdu = Vector::new_with(1.0, 0.0, 0.0);
+        let mut pi = po.clone();
+        pi.p = Point::new_with(0.3, 0.4, 0.0);
+
+        // Points in the tangent plane are only found by projecting along the
+        // normal, which happens for half of the samples
+        let expected: f32 = (0..3).map(|c| bssrdf.pdf_sr(c, 0.5)).sum::<f32>() * 0.5 / 3.0;
+        assert!((bssrdf.pdf_sp(&po, &pi) - expected).abs() < 1e-6);
+    }
 }
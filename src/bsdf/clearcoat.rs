@@ -0,0 +1,190 @@
+use crate::bsdf;
+use crate::bsdf::BxDF;
+use crate::bsdf::fresnel::Fresnel;
+use crate::bsdf::utils::*;
+use crate::geometry::vector::Vector;
+use crate::spectrum::Spectrum;
+
+// Kulla and Conty's (2017) fit to the reflectance of a smooth dielectric
+// with relative index of refraction eta >= 1, averaged over light arriving
+// from every direction outside of it
+pub fn average_fresnel(eta: f32) -> f32 {
+    (eta - 1.0) / (4.08567 + 1.00071 * eta)
+}
+
+// The direction inside the coating that w, which lies above it, refracts
+// into, reflected back up so that the base sees it in the upper hemisphere
+fn refract_in(w: &Vector, eta: f32) -> Vector {
+    let (x, y) = (w.x / eta, w.y / eta);
+    Vector::new_with(x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+}
+
+// The direction that w, which lies inside the coating, leaves it in, unless
+// it's totally internally reflected
+fn refract_out(w: &Vector, eta: f32) -> Option<Vector> {
+    let (x, y) = (w.x * eta, w.y * eta);
+    let sin2 = x * x + y * y;
+    if sin2 >= 1.0 {
+        None
+    } else {
+        Some(Vector::new_with(x, y, (1.0 - sin2).sqrt()))
+    }
+}
+
+// A BxDF underneath a smooth dielectric coating, e.g. the paint under the
+// clear coat of a car or wood under lacquer. Light refracts through the
+// coating on its way to the base and back out, and is tinted each time it
+// crosses it. The reflection off of the top of the coating is a separate
+// SpecularReflection, see BSDF::with_clear_coat.
+#[derive(Debug)]
+pub struct ClearCoat {
+    base: Box<dyn BxDF>,
+    eta: f32,
+    tint: Spectrum,
+    compensation: Spectrum
+}
+
+impl ClearCoat {
+    // The tint is the color of white light once it has crossed the coating
+    // straight down. The compensation scales the base to account for light
+    // bouncing between it and the coating more than once.
+    pub fn new(base: Box<dyn BxDF>, eta: f32, tint: Spectrum,
+               compensation: Spectrum) -> ClearCoat {
+        ClearCoat { base, eta, tint, compensation }
+    }
+
+    // How much of the light that the base scatters from wi_in to wo_in makes
+    // it through the coating from wi and back out along wo
+    fn transmittance(&self, wo: &Vector, wo_in: &Vector,
+                     wi: &Vector, wi_in: &Vector) -> Spectrum {
+        let fresnel = Fresnel::dielectric(1.0, self.eta);
+        let t_o = Spectrum::from(1.0) - fresnel.evaluate(cos_theta(wo));
+        let t_i = Spectrum::from(1.0) - fresnel.evaluate(cos_theta(wi));
+        let absorbed = self.tint.powf(1.0 / cos_theta(wo_in) + 1.0 / cos_theta(wi_in));
+
+        // Radiance is compressed into the smaller solid angle inside the
+        // coating on the way in and spread back out on the way out
+        t_o * t_i * absorbed * self.compensation / (self.eta * self.eta)
+    }
+}
+
+impl BxDF for ClearCoat {
+    fn matches_flags(&self, ty: bsdf::BxDFType) -> bool {
+        self.base.matches_flags(ty)
+    }
+
+    // The coating only covers the outside of the surface
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        if wo.z <= 0.0 {
+            return self.base.f(wo, wi);
+        }
+
+        if wi.z <= 0.0 {
+            return Spectrum::from(0.0);
+        }
+
+        let wo_in = refract_in(wo, self.eta);
+        let wi_in = refract_in(wi, self.eta);
+        self.base.f(&wo_in, &wi_in) * self.transmittance(wo, &wo_in, wi, &wi_in)
+    }
+
+    fn sample_f(&self, wo: &Vector, u1: f32, u2: f32) -> (Vector, f32, Spectrum) {
+        if wo.z <= 0.0 {
+            return self.base.sample_f(wo, u1, u2);
+        }
+
+        // Sample the base inside the coating and refract the result out
+        let no_sample = (Vector::new(), 0.0, Spectrum::from(0.0));
+        let wo_in = refract_in(wo, self.eta);
+        let (wi_in, pdf, f) = self.base.sample_f(&wo_in, u1, u2);
+        if pdf == 0.0 || wi_in.z <= 0.0 {
+            return no_sample;
+        }
+
+        let wi = match refract_out(&wi_in, self.eta) {
+            Some(wi) => wi,
+            None => return no_sample
+        };
+
+        // The solid angle shrinks by cos(theta_i) / (eta^2 cos(theta_i_in))
+        // going into the coating
+        let pdf = pdf * cos_theta(&wi) / (self.eta * self.eta * cos_theta(&wi_in));
+        let f = f * self.transmittance(wo, &wo_in, &wi, &wi_in);
+        (wi, pdf, f)
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        if wo.z <= 0.0 {
+            return self.base.pdf(wo, wi);
+        }
+
+        if wi.z <= 0.0 {
+            return 0.0;
+        }
+
+        let wo_in = refract_in(wo, self.eta);
+        let wi_in = refract_in(wi, self.eta);
+        self.base.pdf(&wo_in, &wi_in) * cos_theta(wi) /
+            (self.eta * self.eta * cos_theta(&wi_in))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsdf::BSDF;
+    use crate::bsdf::BxDFType;
+    use crate::bsdf::lambertian::Lambertian;
+    use crate::diff_geom::DifferentialGeometry;
+    use crate::geometry::normal::Normal;
+    use crate::geometry::point::Point;
+    use crate::rng::RNG;
+
+    fn coated_lambertian(r: f32, eta: f32, tint: Spectrum) -> BSDF {
+        let dg = DifferentialGeometry::new_with(
+            Point::new(), Vector::new_with(1.0, 0.0, 0.0),
+            Vector::new_with(0.0, 1.0, 0.0), Normal::new(), Normal::new(),
+            0.0, 0.0, None);
+        let mut bsdf = BSDF::new(dg, Normal::new_with(0.0, 0.0, 1.0));
+        bsdf.add_bxdf(Lambertian::new(Spectrum::from(r)));
+        bsdf.with_clear_coat(eta, tint)
+    }
+
+    #[test]
+    fn clear_coats_keep_white_bases_white() {
+        let mut rng = RNG::new(0);
+        let wo = Vector::new_with(0.0, 0.0, 1.0);
+
+        // Without compensation less than half of the light would get out
+        let bsdf = coated_lambertian(1.0, 1.5, Spectrum::from(1.0));
+        assert_eq!(bsdf.num_components(), 2);
+        let rho = bsdf.rho_hd(&wo, &mut rng, BxDFType::BSDF_ALL, 32).y();
+        assert!(rho > 0.9 && rho < 1.02, "{}", rho);
+
+        // The coating's reflection is uncolored, but the base is tinted
+        let tinted = coated_lambertian(1.0, 1.5, Spectrum::from_rgb([1.0, 0.5, 0.5]));
+        let rgb = tinted.rho_hd(&wo, &mut rng, BxDFType::BSDF_ALL, 32).to_rgb();
+        assert!(rgb[1] < 0.5 * rgb[0] && rgb[1] > 0.04);
+    }
+
+    #[test]
+    fn clear_coats_sample_what_they_evaluate() {
+        let coat = ClearCoat::new(Box::new(Lambertian::new(Spectrum::from(0.5))), 1.5,
+                                  Spectrum::from(0.8), Spectrum::from(1.0));
+        let wo = Vector::new_with(0.6, 0.0, 0.8);
+        for &(u1, u2) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.7)].iter() {
+            let (wi, pdf, f) = coat.sample_f(&wo, u1, u2);
+            if pdf == 0.0 {
+                continue;
+            }
+            assert!((pdf - coat.pdf(&wo, &wi)).abs() < 1e-4);
+            assert!((f.y() - coat.f(&wo, &wi).y()).abs() < 1e-4);
+        }
+
+        // Grazing directions inside the coating are trapped by it
+        assert!(refract_out(&Vector::new_with(0.8, 0.0, 0.6), 1.5).is_none());
+        let w = Vector::new_with(0.6, 0.0, 0.8);
+        let back = refract_out(&refract_in(&w, 1.5), 1.5).unwrap();
+        assert!((back - w).length() < 1e-5);
+    }
+}
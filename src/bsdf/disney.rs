@@ -0,0 +1,239 @@
+use std::f32::consts::PI;
+
+use crate::bsdf;
+use crate::bsdf::BxDF;
+use crate::bsdf::utils::*;
+use crate::geometry::normal::Normalize;
+use crate::geometry::vector::{Dot, Vector, spherical_direction};
+use crate::spectrum::Spectrum;
+
+// The lobes of Burley's (2012, 2015) principled BRDF that don't fit any of
+// the other BxDFs. The specular lobes are Microfacet and
+// MicrofacetTransmission with a Fresnel::Disney.
+
+fn schlick_weight(cos_theta: f32) -> f32 {
+    (1.0 - cos_theta).clamp(0.0, 1.0).powi(5)
+}
+
+// Smith's masking function for the GGX distribution with slope alpha
+fn smith_g_ggx(cos_theta: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let cos2_theta = cos_theta * cos_theta;
+    1.0 / (cos_theta + (alpha2 + cos2_theta - alpha2 * cos2_theta).sqrt())
+}
+
+// The "generalized Trowbridge-Reitz" distribution with an exponent of one,
+// which has a longer tail than GGX
+fn gtr1(cos_theta: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    (alpha2 - 1.0) / (PI * alpha2.ln() * (1.0 + (alpha2 - 1.0) * cos_theta * cos_theta))
+}
+
+// A diffuse lobe that darkens at grazing angles, along with retro-reflection
+// that brightens them for rough surfaces
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisneyDiffuse {
+    r: Spectrum,
+    roughness: f32
+}
+
+impl DisneyDiffuse {
+    pub fn new(r: Spectrum, roughness: f32) -> DisneyDiffuse {
+        DisneyDiffuse { r, roughness }
+    }
+}
+
+impl BxDF for DisneyDiffuse {
+    fn matches_flags(&self, ty: bsdf::BxDFType) -> bool {
+        (bsdf::BxDFType::BSDF_REFLECTION | bsdf::BxDFType::BSDF_DIFFUSE).contains(ty)
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        let fo = schlick_weight(abs_cos_theta(wo));
+        let fi = schlick_weight(abs_cos_theta(wi));
+        let diffuse = (1.0 - fo / 2.0) * (1.0 - fi / 2.0);
+
+        let wh = wi + wo;
+        if wh.length_squared() == 0.0 {
+            return self.r * (diffuse / PI);
+        }
+
+        let cos_theta_d = wi.dot(&wh.normalize());
+        let rr = 2.0 * self.roughness * cos_theta_d * cos_theta_d;
+        let retro = rr * (fo + fi + fo * fi * (rr - 1.0));
+        self.r * ((diffuse + retro) / PI)
+    }
+}
+
+// The extra reflection at grazing angles of cloth
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisneySheen {
+    r: Spectrum
+}
+
+impl DisneySheen {
+    pub fn new(r: Spectrum) -> DisneySheen { DisneySheen { r } }
+}
+
+impl BxDF for DisneySheen {
+    fn matches_flags(&self, ty: bsdf::BxDFType) -> bool {
+        (bsdf::BxDFType::BSDF_REFLECTION | bsdf::BxDFType::BSDF_DIFFUSE).contains(ty)
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        let wh = wi + wo;
+        if wh.length_squared() == 0.0 {
+            return Spectrum::from(0.0);
+        }
+
+        self.r * schlick_weight(wi.dot(&wh.normalize()))
+    }
+}
+
+// A second, white specular lobe with a fixed index of refraction of 1.5,
+// whose gloss interpolates its roughness
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisneyClearcoat {
+    weight: f32,
+    gloss: f32
+}
+
+impl DisneyClearcoat {
+    // Glossier clear coats have sharper highlights
+    pub fn new(weight: f32, gloss: f32) -> DisneyClearcoat {
+        let gloss = gloss.clamp(0.0, 1.0);
+        DisneyClearcoat { weight, gloss: (1.0 - gloss) * 0.1 + gloss * 0.001 }
+    }
+}
+
+impl BxDF for DisneyClearcoat {
+    fn matches_flags(&self, ty: bsdf::BxDFType) -> bool {
+        (bsdf::BxDFType::BSDF_REFLECTION | bsdf::BxDFType::BSDF_GLOSSY).contains(ty)
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        let wh = wi + wo;
+        if wh.length_squared() == 0.0 {
+            return Spectrum::from(0.0);
+        }
+
+        // The coating reflects 4% of light at normal incidence, which is
+        // what an index of refraction of 1.5 gives
+        let wh = wh.normalize();
+        let d = gtr1(abs_cos_theta(&wh), self.gloss);
+        let f = 0.04 + 0.96 * schlick_weight(wo.dot(&wh));
+        let g = smith_g_ggx(abs_cos_theta(wo), 0.25) * smith_g_ggx(abs_cos_theta(wi), 0.25);
+        Spectrum::from(self.weight * g * f * d / 4.0)
+    }
+
+    fn sample_f(&self, wo: &Vector, u1: f32, u2: f32) -> (Vector, f32, Spectrum) {
+        if wo.z == 0.0 {
+            return (Vector::new(), 0.0, Spectrum::from(0.0));
+        }
+
+        // Sample a microfacet normal from the GTR1 distribution and reflect
+        // wo about it
+        let alpha2 = self.gloss * self.gloss;
+        let cos_theta = ((1.0 - alpha2.powf(1.0 - u1)) / (1.0 - alpha2)).max(0.0).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let wh = spherical_direction(sin_theta, cos_theta, 2.0 * PI * u2);
+        let wh = if same_hemisphere(wo, &wh) { wh } else { -wh };
+
+        let wi = -wo + 2.0 * wo.dot(&wh) * &wh;
+        if !same_hemisphere(wo, &wi) {
+            return (wi, 0.0, Spectrum::from(0.0));
+        }
+
+        (wi.clone(), self.pdf(wo, &wi), self.f(wo, &wi))
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        if !same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+
+        let wh = wi + wo;
+        if wh.length_squared() == 0.0 {
+            return 0.0;
+        }
+
+        let wh = wh.normalize();
+        let d = gtr1(abs_cos_theta(&wh), self.gloss);
+        d * abs_cos_theta(&wh) / (4.0 * wo.abs_dot(&wh))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::montecarlo::{stratified_sample_2d, uniform_sample_hemisphere};
+    use crate::montecarlo::uniform_hemisphere_pdf;
+    use crate::rng::RNG;
+
+    fn samples() -> Vec<f32> {
+        let mut samples = vec![0.0; 2 * 64 * 64];
+        stratified_sample_2d(&mut samples, 64, 64, &mut RNG::new(0), true);
+        samples
+    }
+
+    #[test]
+    fn disney_diffuse_is_lambertian_at_normal_incidence() {
+        let smooth = DisneyDiffuse::new(Spectrum::from(1.0), 0.0);
+        let n = Vector::new_with(0.0, 0.0, 1.0);
+        assert!((smooth.f(&n, &n).y() - 1.0 / PI).abs() < 1e-5);
+
+        // Rough surfaces reflect more light back towards grazing angles
+        let rough = DisneyDiffuse::new(Spectrum::from(1.0), 1.0);
+        let grazing = Vector::new_with(0.99, 0.0, 0.141).normalize();
+        assert!(rough.f(&grazing, &grazing).y() > smooth.f(&grazing, &grazing).y());
+
+        // And neither reflects much more light than they receive
+        let samples = samples();
+        for bxdf in [smooth, rough] {
+            let rho = bxdf.rho_hd(&Vector::new_with(0.0, 0.6, 0.8), &samples).y();
+            assert!(rho > 0.5 && rho < 1.2, "Reflectance: {}", rho);
+        }
+    }
+
+    #[test]
+    fn sheen_only_shows_at_grazing_angles() {
+        let sheen = DisneySheen::new(Spectrum::from(1.0));
+        let n = Vector::new_with(0.0, 0.0, 1.0);
+        assert_eq!(sheen.f(&n, &n).y(), 0.0);
+
+        let wo = Vector::new_with(0.99, 0.0, 0.05).normalize();
+        let wi = Vector::new_with(-0.99, 0.0, 0.05).normalize();
+        assert!(sheen.f(&wo, &wi).y() > 0.5);
+    }
+
+    #[test]
+    fn clear_coats_sample_what_they_evaluate() {
+        let samples = samples();
+        let num_samples = (samples.len() / 2) as f32;
+        for &gloss in [0.0, 0.5, 1.0].iter() {
+            let coat = DisneyClearcoat::new(1.0, gloss);
+            let wo = Vector::new_with(0.48, 0.36, 0.8);
+            for u in samples.chunks_exact(2).step_by(7) {
+                let (wi, pdf, f) = coat.sample_f(&wo, u[0], u[1]);
+                if pdf == 0.0 {
+                    continue;
+                }
+
+                assert!(same_hemisphere(&wo, &wi));
+                assert!((pdf - coat.pdf(&wo, &wi)).abs() < 1e-3 * pdf.max(1.0));
+                assert!((f.y() - coat.f(&wo, &wi).y()).abs() < 1e-3 * f.y().max(1.0));
+            }
+
+            // The density integrates to nearly one, and the coating only
+            // reflects a small fraction of the light
+            let total: f32 = samples.chunks_exact(2).map(|u| {
+                let wi = uniform_sample_hemisphere(u[0], u[1]);
+                coat.pdf(&wo, &wi) / uniform_hemisphere_pdf()
+            }).sum::<f32>() / num_samples;
+            assert!(total > 0.8 && total < 1.05, "Total density: {}", total);
+
+            let rho = coat.rho_hd(&wo, &samples).y();
+            assert!(rho > 0.0 && rho < 0.1, "Reflectance: {}", rho);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::bsdf;
+use crate::bsdf::BxDF;
+use crate::bsdf::utils::*;
+use crate::geometry::vector::*;
+use crate::geometry::normal::Normalize;
+use crate::spectrum::Spectrum;
+use crate::utils::interpolation::*;
+
+const HEADER: &[u8; 8] = b"SCATFUN\x01";
+
+// A BSDF tabulated over pairs of elevations, mu = cos(theta), of the
+// incident and outgoing directions. The dependence on the difference in
+// their azimuths is stored as the coefficients of a cosine series, which
+// lets layered materials be precomputed by external tools and then
+// evaluated and sampled efficiently.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FourierBSDFTable {
+    eta: f32,
+    m_max: usize,
+    n_channels: usize,
+    mu: Vec<f32>,
+    // The running integral of a0 over mu_i for each mu_o
+    cdf: Vec<f32>,
+    a0: Vec<f32>,
+    // For each pair of elevations, where its coefficients start in a and
+    // how many of them there are. RGB tables store all of the coefficients
+    // for luminance, then red, then blue.
+    a_offset: Vec<usize>,
+    m: Vec<usize>,
+    a: Vec<f32>,
+    recip: Vec<f32>
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn next_bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self.bytes.get(self.pos..(self.pos + n))
+            .ok_or_else(|| String::from("Unexpected end of BSDF data"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn next_i32(&mut self) -> Result<i32, String> {
+        self.next_bytes(4).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn next_f32(&mut self) -> Result<f32, String> {
+        self.next_bytes(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn next_usize(&mut self) -> Result<usize, String> {
+        let x = self.next_i32()?;
+        usize::try_from(x).map_err(|_| format!("Invalid size in BSDF data: {}", x))
+    }
+
+    fn floats(&mut self, n: usize) -> Result<Vec<f32>, String> {
+        (0..n).map(|_| self.next_f32()).collect()
+    }
+}
+
+impl FourierBSDFTable {
+    pub fn read<P: AsRef<Path>>(filename: &P) -> Result<FourierBSDFTable, String> {
+        let bytes = fs::read(filename).map_err(|e| e.to_string())?;
+        FourierBSDFTable::parse(&bytes)
+    }
+
+    // Parses the little endian binary format written by layerlab
+    pub fn parse(bytes: &[u8]) -> Result<FourierBSDFTable, String> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.next_bytes(HEADER.len())? != HEADER {
+            return Err(String::from("Not a tabulated BSDF file"));
+        }
+
+        let flags = r.next_i32()?;
+        let n_mu = r.next_usize()?;
+        let n_coeffs = r.next_usize()?;
+        let m_max = r.next_usize()?;
+        let n_channels = r.next_usize()?;
+        let n_bases = r.next_i32()?;
+        r.next_bytes(3 * 4)?;
+        let eta = r.next_f32()?;
+        r.next_bytes(4 * 4)?;
+
+        // Only isotropic monochromatic or RGB tables of a single material,
+        // i.e. not ones that blend between several bases, are supported
+        if flags != 1 || (n_channels != 1 && n_channels != 3) || n_bases != 1 {
+            return Err(String::from("Unsupported kind of tabulated BSDF"));
+        }
+
+        let mu = r.floats(n_mu)?;
+        let cdf = r.floats(n_mu * n_mu)?;
+        let mut a_offset = Vec::with_capacity(n_mu * n_mu);
+        let mut m = Vec::with_capacity(n_mu * n_mu);
+        for _ in 0..(n_mu * n_mu) {
+            a_offset.push(r.next_usize()?);
+            m.push(r.next_usize()?);
+        }
+        let a = r.floats(n_coeffs)?;
+
+        if a_offset.iter().zip(m.iter()).any(|(&o, &len)| len > m_max ||
+                                             o + len * n_channels > a.len()) {
+            return Err(String::from("BSDF coefficients out of range"));
+        }
+
+        let a0 = a_offset.iter().zip(m.iter())
+            .map(|(&o, &len)| if len > 0 { a[o] } else { 0.0 })
+            .collect();
+        let recip = (0..m_max).map(|i| 1.0 / (i as f32)).collect();
+
+        Ok(FourierBSDFTable { eta, m_max, n_channels, mu, cdf, a0, a_offset, m, a, recip })
+    }
+
+    pub fn eta(&self) -> f32 { self.eta }
+
+    fn weights_and_offset(&self, cos_theta: f32) -> Option<(i32, [f32; 4])> {
+        catmull_rom_weights(&self.mu, cos_theta)
+    }
+
+    fn ak(&self, offset_i: usize, offset_o: usize) -> &[f32] {
+        let idx = offset_o * self.mu.len() + offset_i;
+        &self.a[self.a_offset[idx]..(self.a_offset[idx] + self.m[idx] * self.n_channels)]
+    }
+
+    // Interpolates the coefficients of every channel between the nodes
+    // around mu_i and mu_o, returning them along with how many there are
+    fn interpolated_ak(&self, mu_i: f32, mu_o: f32) -> Option<(Vec<f32>, usize)> {
+        let (offset_i, weights_i) = self.weights_and_offset(mu_i)?;
+        let (offset_o, weights_o) = self.weights_and_offset(mu_o)?;
+
+        let mut ak = vec![0.0; self.m_max * self.n_channels];
+        let mut m_max = 0;
+        for (b, &weight_o) in weights_o.iter().enumerate() {
+            for (a, &weight_i) in weights_i.iter().enumerate() {
+                let weight = weight_i * weight_o;
+                if weight == 0.0 {
+                    continue;
+                }
+
+                let ap = self.ak((offset_i + a as i32) as usize, (offset_o + b as i32) as usize);
+                let m = ap.len() / self.n_channels;
+                m_max = m_max.max(m);
+                for c in 0..self.n_channels {
+                    for k in 0..m {
+                        ak[c * self.m_max + k] += weight * ap[c * m + k];
+                    }
+                }
+            }
+        }
+
+        Some((ak, m_max))
+    }
+}
+
+// The cosine of the difference in azimuth between two directions
+fn cos_dphi(wa: &Vector, wb: &Vector) -> f32 {
+    let denom = ((wa.x * wa.x + wa.y * wa.y) * (wb.x * wb.x + wb.y * wb.y)).sqrt();
+    if denom == 0.0 {
+        1.0
+    } else {
+        ((wa.x * wb.x + wa.y * wb.y) / denom).clamp(-1.0, 1.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FourierBSDF {
+    table: Arc<FourierBSDFTable>
+}
+
+impl FourierBSDF {
+    pub fn new(table: Arc<FourierBSDFTable>) -> FourierBSDF { FourierBSDF { table } }
+
+    // Evaluates the cosine series of each channel, which are stored as the
+    // luminance and the red and blue channels
+    fn spectrum(&self, ak: &[f32], m_max: usize, cos_phi: f32, mu_i: f32, mu_o: f32) -> Spectrum {
+        let y = fourier(&ak[0..m_max], cos_phi).max(0.0);
+
+        // The table was made for light leaving along wo, so radiance that's
+        // transmitted is scaled by the change in the index of refraction
+        let mut scale = if mu_i != 0.0 { 1.0 / mu_i.abs() } else { 0.0 };
+        if mu_i * mu_o > 0.0 {
+            let eta = if mu_i > 0.0 { 1.0 / self.table.eta } else { self.table.eta };
+            scale *= eta * eta;
+        }
+
+        if self.table.n_channels == 1 {
+            return Spectrum::from(y * scale);
+        }
+
+        let stride = self.table.m_max;
+        let r = fourier(&ak[stride..(stride + m_max)], cos_phi);
+        let b = fourier(&ak[(2 * stride)..(2 * stride + m_max)], cos_phi);
+        let g = 1.39829 * y - 0.100913 * b - 0.297375 * r;
+        Spectrum::from_rgb([r * scale, g * scale, b * scale]).clamp(0.0, f32::MAX)
+    }
+}
+
+impl BxDF for FourierBSDF {
+    fn matches_flags(&self, ty: bsdf::BxDFType) -> bool {
+        (bsdf::BxDFType::BSDF_REFLECTION | bsdf::BxDFType::BSDF_TRANSMISSION |
+         bsdf::BxDFType::BSDF_GLOSSY).contains(ty)
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        // The table is parameterized by the direction that light arrives
+        // from, which is the opposite of wi
+        let mu_i = -cos_theta(wi);
+        let mu_o = cos_theta(wo);
+        let cos_phi = cos_dphi(&-wi, wo);
+
+        match self.table.interpolated_ak(mu_i, mu_o) {
+            Some((ak, m_max)) => self.spectrum(&ak, m_max, cos_phi, mu_i, mu_o),
+            None => Spectrum::from(0.0)
+        }
+    }
+
+    fn sample_f(&self, wo: &Vector, u1: f32, u2: f32) -> (Vector, f32, Spectrum) {
+        let none = (Vector::new(), 0.0, Spectrum::from(0.0));
+        let table = &self.table;
+
+        // Sample the elevation of wi using the first coefficients, then the
+        // azimuth from the cosine series at the sampled elevation
+        let mu_o = cos_theta(wo);
+        let (mu_i, _, pdf_mu) = match sample_catmull_rom_2d(&table.mu, &table.mu, &table.a0,
+                                                            &table.cdf, mu_o, u2) {
+            Some(sample) => sample,
+            None => return none
+        };
+
+        let (ak, m_max) = match table.interpolated_ak(mu_i, mu_o) {
+            Some(ak) if ak.1 > 0 => ak,
+            _ => return none
+        };
+
+        let (phi, _, pdf_phi) = sample_fourier(&ak[0..m_max], &table.recip, u1);
+        let pdf = (pdf_phi * pdf_mu).max(0.0);
+
+        // Rotate wo around the normal by phi and give it the new elevation
+        let sin2_theta_i = (1.0 - mu_i * mu_i).max(0.0);
+        let norm = (sin2_theta_i / sin_theta2(wo)).sqrt();
+        let norm = if norm.is_finite() { norm } else { 0.0 };
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let wi = -Vector::new_with(norm * (cos_phi * wo.x - sin_phi * wo.y),
+                                   norm * (sin_phi * wo.x + cos_phi * wo.y),
+                                   mu_i).normalize();
+
+        (wi, pdf, self.spectrum(&ak, m_max, cos_phi, mu_i, mu_o))
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        let table = &self.table;
+        let mu_i = -cos_theta(wi);
+        let mu_o = cos_theta(wo);
+        let cos_phi = cos_dphi(&-wi, wo);
+
+        let (ak, m_max) = match table.interpolated_ak(mu_i, mu_o) {
+            Some(ak) => ak,
+            None => return 0.0
+        };
+
+        // The total of the first coefficients over every mu_i normalizes them
+        let (offset_o, weights_o) = table.weights_and_offset(mu_o).unwrap();
+        let n_mu = table.mu.len();
+        let rho: f32 = weights_o.iter().enumerate().filter(|(_, &w)| w != 0.0)
+            .map(|(o, &w)| {
+                let row = (offset_o + o as i32) as usize;
+                w * table.cdf[row * n_mu + n_mu - 1] * (2.0 * PI)
+            }).sum();
+
+        let y = fourier(&ak[0..m_max], cos_phi);
+        if rho > 0.0 && y > 0.0 { y / rho } else { 0.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A Lambertian reflector with the given albedo, tabulated at a few
+    // elevations and serialized the way it would be stored on disk
+    fn lambertian_table(albedo: f32, n_channels: usize) -> Vec<u8> {
+        let mu: Vec<f32> = (0..9).map(|i| -1.0 + 0.25 * (i as f32)).collect();
+        let n_mu = mu.len();
+
+        let mut offsets = Vec::new();
+        let mut a = Vec::new();
+        let mut a0 = Vec::new();
+        for &mu_o in mu.iter() {
+            for &mu_i in mu.iter() {
+                if mu_o > 0.0 && mu_i < 0.0 {
+                    offsets.push((a.len() as i32, 1));
+                    let coeff = albedo / PI * mu_i.abs();
+                    a.extend(vec![coeff; n_channels]);
+                    a0.push(coeff);
+                } else {
+                    offsets.push((a.len() as i32, 0));
+                    a0.push(0.0);
+                }
+            }
+        }
+
+        let cdf: Vec<f32> = a0.chunks(n_mu).flat_map(|row| integrate_catmull_rom(&mu, row).1)
+            .collect();
+
+        let mut bytes = HEADER.to_vec();
+        let ints = [1, n_mu as i32, a.len() as i32, 1, n_channels as i32, 1, 0, 0, 0];
+        bytes.extend(ints.iter().flat_map(|x| x.to_le_bytes()));
+        bytes.extend(1.0f32.to_le_bytes());
+        bytes.extend([0i32; 4].iter().flat_map(|x| x.to_le_bytes()));
+        bytes.extend(mu.iter().chain(cdf.iter()).flat_map(|x| x.to_le_bytes()));
+        bytes.extend(offsets.iter().flat_map(|&(o, m)| [o, m]).flat_map(|x| x.to_le_bytes()));
+        bytes.extend(a.iter().flat_map(|x| x.to_le_bytes()));
+        bytes
+    }
+
+    #[test]
+    fn it_reads_tabulated_bsdfs() {
+        let table = FourierBSDFTable::parse(&lambertian_table(0.5, 3)).unwrap();
+        assert_eq!(table.eta(), 1.0);
+        assert_eq!(table.mu.len(), 9);
+        assert_eq!(table.a0[8 * 9], 0.5 / PI);
+
+        let mut bad_header = lambertian_table(0.5, 1);
+        bad_header[0] = b'X';
+        assert!(FourierBSDFTable::parse(&bad_header).is_err());
+
+        let truncated = lambertian_table(0.5, 1);
+        assert!(FourierBSDFTable::parse(&truncated[0..(truncated.len() - 4)]).is_err());
+    }
+
+    #[test]
+    fn it_evaluates_tabulated_bsdfs() {
+        for &n_channels in [1, 3].iter() {
+            let table = FourierBSDFTable::parse(&lambertian_table(0.5, n_channels)).unwrap();
+            let bxdf = FourierBSDF::new(Arc::new(table));
+
+            let wo = Vector::new_with(0.8, 0.0, 0.6);
+            let wi = Vector::new_with(0.0, -0.8, 0.6);
+            let f = bxdf.f(&wo, &wi).to_rgb();
+            for c in f.iter() {
+                assert!((c - 0.5 / PI).abs() < 1e-4);
+            }
+
+            // Nothing is transmitted
+            assert_eq!(bxdf.f(&wo, &-wi).y(), 0.0);
+        }
+    }
+
+    #[test]
+    fn it_samples_tabulated_bsdfs() {
+        let table = FourierBSDFTable::parse(&lambertian_table(0.5, 1)).unwrap();
+        let bxdf = FourierBSDF::new(Arc::new(table));
+        let wo = Vector::new_with(0.6, 0.0, 0.8);
+
+        let n = 16;
+        for i in 0..n {
+            for j in 0..n {
+                let u1 = (i as f32 + 0.5) / (n as f32);
+                let u2 = (j as f32 + 0.5) / (n as f32);
+                let (wi, pdf, f) = bxdf.sample_f(&wo, u1, u2);
+                assert!((wi.length() - 1.0).abs() < 1e-4);
+                assert!(wi.z > -1e-3);
+                assert!((pdf - bxdf.pdf(&wo, &wi)).abs() < 1e-3 * pdf.max(1.0));
+                assert!((f.y() - bxdf.f(&wo, &wi).y()).abs() < 1e-3);
+            }
+        }
+    }
+}
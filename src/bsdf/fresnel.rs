@@ -1,5 +1,5 @@
-use geometry::vector::Vector;
-use spectrum::Spectrum;
+use crate::geometry::vector::Vector;
+use crate::spectrum::Spectrum;
 
 fn fr_diel(cosi: f32, cost: f32, etai: &Spectrum,
            etat: &Spectrum) -> Spectrum {
@@ -29,6 +29,157 @@ fn fr_cond(cosi: f32, eta: &Spectrum,
     (rparl2 + rperp2) / 2.0
 }
 
+// Measured indices of refraction (wavelength in nm, eta, k) of common
+// metals, after Johnson and Christy (1972) for copper, gold and silver and
+// Rakic (1995) for aluminum
+const COPPER: [(f32, f32, f32); 7] = [
+    (400.0, 1.18, 2.21), (450.0, 1.12, 2.51), (500.0, 1.12, 2.60),
+    (550.0, 1.02, 2.58), (600.0, 0.25, 3.41), (650.0, 0.21, 3.67),
+    (700.0, 0.21, 4.21)];
+
+const GOLD: [(f32, f32, f32); 7] = [
+    (400.0, 1.66, 1.96), (450.0, 1.40, 1.88), (500.0, 0.97, 1.87),
+    (550.0, 0.35, 2.70), (600.0, 0.20, 3.09), (650.0, 0.14, 3.70),
+    (700.0, 0.13, 4.10)];
+
+const ALUMINUM: [(f32, f32, f32); 7] = [
+    (400.0, 0.49, 4.86), (450.0, 0.62, 5.47), (500.0, 0.77, 6.08),
+    (550.0, 0.96, 6.69), (600.0, 1.20, 7.26), (650.0, 1.47, 7.79),
+    (700.0, 1.83, 8.31)];
+
+const SILVER: [(f32, f32, f32); 7] = [
+    (400.0, 0.05, 2.10), (450.0, 0.04, 2.66), (500.0, 0.05, 3.13),
+    (550.0, 0.06, 3.59), (600.0, 0.06, 4.00), (650.0, 0.05, 4.42),
+    (700.0, 0.04, 4.84)];
+
+// Metals with a measured index of refraction. Its spectra are converted to
+// RGB like every other spectrum that the renderer works with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conductor {
+    Copper,
+    Gold,
+    Silver,
+    Aluminum
+}
+
+impl Conductor {
+    pub fn from_name(name: &str) -> Option<Conductor> {
+        match name {
+            "copper" => Some(Conductor::Copper),
+            "gold" => Some(Conductor::Gold),
+            "silver" => Some(Conductor::Silver),
+            "aluminum" => Some(Conductor::Aluminum),
+            _ => None
+        }
+    }
+
+    // Newer versions of pbrt name the spectra of metals by their chemical
+    // symbol, e.g. "metal-Cu-eta" and "metal-Cu-k" for copper
+    pub fn named_spectrum(name: &str) -> Option<Spectrum> {
+        let mut parts = name.split('-');
+        if parts.next() != Some("metal") {
+            return None;
+        }
+
+        let metal = match parts.next()? {
+            "Cu" => Conductor::Copper,
+            "Au" => Conductor::Gold,
+            "Ag" => Conductor::Silver,
+            "Al" => Conductor::Aluminum,
+            _ => return None
+        };
+
+        match (parts.next()?, parts.next()) {
+            ("eta", None) => Some(metal.eta()),
+            ("k", None) => Some(metal.k()),
+            _ => None
+        }
+    }
+
+    fn table(&self) -> &'static [(f32, f32, f32)] {
+        match self {
+            Conductor::Copper => &COPPER,
+            Conductor::Gold => &GOLD,
+            Conductor::Silver => &SILVER,
+            Conductor::Aluminum => &ALUMINUM
+        }
+    }
+
+    // The real part of the index of refraction
+    pub fn eta(&self) -> Spectrum {
+        let samples: Vec<(f32, f32)> = self.table().iter().map(|&(l, n, _)| (l, n)).collect();
+        Spectrum::from_samples(&samples).into_rgb_spectrum()
+    }
+
+    // The absorption coefficient, i.e. the imaginary part of the index of
+    // refraction
+    pub fn k(&self) -> Spectrum {
+        let samples: Vec<(f32, f32)> = self.table().iter().map(|&(l, _, k)| (l, k)).collect();
+        Spectrum::from_samples(&samples).into_rgb_spectrum()
+    }
+}
+
+// The wavelength in nanometers that the index of refraction of glass is
+// usually quoted at, i.e. the sodium D line
+const NOMINAL_WAVELENGTH: f32 = 589.3;
+
+// The index of refraction of a dielectric, which may vary with wavelength.
+// The Cauchy equation, eta = a + b / lambda^2, is enough for most glasses,
+// while the Sellmeier equation,
+//
+//   eta^2 = 1 + sum_i b_i lambda^2 / (lambda^2 - c_i),
+//
+// fits measured ones over the visible range. Both take lambda in
+// micrometers, as their coefficients are usually given.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexOfRefraction {
+    Constant(f32),
+    Cauchy { a: f32, b: f32 },
+    Sellmeier { b: [f32; 3], c: [f32; 3] }
+}
+
+impl IndexOfRefraction {
+    // Sellmeier coefficients of some common dielectrics, after Schott for
+    // BK7, Malitson (1965) for fused silica and Peter (1923) for diamond
+    pub fn from_name(name: &str) -> Option<IndexOfRefraction> {
+        let (b, c) = match name {
+            "bk7" | "glass-BK7" => ([1.039_612, 0.231_792_34, 1.010_469_5],
+                                    [0.006_000_699, 0.020_017_914, 103.560_65]),
+            "fusedsilica" => ([0.696_166_3, 0.407_942_6, 0.897_479_4],
+                             [0.004_679_148, 0.013_512_063, 97.934_003]),
+            "diamond" => ([0.3306, 4.3356, 0.0], [0.030_625, 0.011_236, 0.0]),
+            _ => return None
+        };
+
+        Some(IndexOfRefraction::Sellmeier { b, c })
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        match self {
+            IndexOfRefraction::Constant(_) => false,
+            IndexOfRefraction::Cauchy { b, .. } => *b != 0.0,
+            IndexOfRefraction::Sellmeier { .. } => true
+        }
+    }
+
+    // The index of refraction at the given wavelength in nanometers
+    pub fn eta(&self, lambda: f32) -> f32 {
+        let l2 = (lambda * 1e-3) * (lambda * 1e-3);
+        match self {
+            IndexOfRefraction::Constant(eta) => *eta,
+            IndexOfRefraction::Cauchy { a, b } => a + b / l2,
+            IndexOfRefraction::Sellmeier { b, c } => {
+                let sum: f32 = b.iter().zip(c.iter()).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+
+    // The index of refraction used by paths that don't follow a single
+    // wavelength
+    pub fn nominal_eta(&self) -> f32 { self.eta(NOMINAL_WAVELENGTH) }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Fresnel {
     Conductor {
@@ -39,6 +190,13 @@ pub enum Fresnel {
         eta_i: f32,
         eta_t: f32
     },
+    // The Disney BRDF's blend between a dielectric and Schlick's
+    // approximation of a metal with normal incidence reflectance r0
+    Disney {
+        r0: Spectrum,
+        metallic: f32,
+        eta: f32
+    },
     NoOp
 }
 
@@ -57,6 +215,10 @@ impl Fresnel {
         }
     }
 
+    pub fn disney(r0: Spectrum, metallic: f32, eta: f32) -> Fresnel {
+        Fresnel::Disney { r0, metallic, eta }
+    }
+
     pub fn noop() -> Fresnel { Fresnel::NoOp }
 
     pub fn evaluate(&self, cosi: f32) -> Spectrum {
@@ -86,6 +248,11 @@ impl Fresnel {
                             &Spectrum::from(et))
                 }
             }
+            Fresnel::Disney { r0, metallic, eta } => {
+                let dielectric = Fresnel::dielectric(1.0, *eta).evaluate(cosi);
+                let schlick = *r0 + (1.0 - cosi.abs()).powi(5) * (Spectrum::from(1.0) - *r0);
+                (1.0 - metallic) * dielectric + *metallic * schlick
+            },
             &Fresnel::NoOp => Spectrum::from(1.0)
         }
     }
@@ -0,0 +1,397 @@
+use std::f32::consts::PI;
+
+use crate::bsdf;
+use crate::bsdf::BxDF;
+use crate::bsdf::fresnel::Fresnel;
+use crate::bsdf::utils::*;
+use crate::geometry::vector::Vector;
+use crate::spectrum::Spectrum;
+use crate::utils::Degrees;
+
+// The number of scattering events that are modelled separately: light
+// reflecting off the surface (R), passing straight through (TT) and
+// reflecting once inside (TRT). Everything after that is lumped together.
+const P_MAX: usize = 3;
+
+const SQRT_PI_OVER_8: f32 = 0.626_657_07;
+
+fn safe_sqrt(x: f32) -> f32 { x.max(0.0).sqrt() }
+fn safe_asin(x: f32) -> f32 { x.clamp(-1.0, 1.0).asin() }
+
+// The modified Bessel function of the first kind, and its logarithm for
+// when it's too large to be represented
+fn i0(x: f32) -> f32 {
+    let mut val = 0.0;
+    let mut x2i = 1.0;
+    let mut ifact = 1.0f32;
+    let mut i4 = 1.0;
+    for i in 0..10 {
+        if i > 1 {
+            ifact *= i as f32;
+        }
+        val += x2i / (i4 * ifact * ifact);
+        x2i *= x * x;
+        i4 *= 4.0;
+    }
+    val
+}
+
+fn log_i0(x: f32) -> f32 {
+    if x > 12.0 {
+        x + 0.5 * (-(2.0 * PI).ln() + (1.0 / x).ln() + 1.0 / (8.0 * x))
+    } else {
+        i0(x).ln()
+    }
+}
+
+// The longitudinal scattering function
+fn mp(cos_theta_i: f32, cos_theta_o: f32, sin_theta_i: f32, sin_theta_o: f32, v: f32) -> f32 {
+    let a = cos_theta_i * cos_theta_o / v;
+    let b = sin_theta_i * sin_theta_o / v;
+    if v <= 0.1 {
+        (log_i0(a) - b - 1.0 / v + std::f32::consts::LN_2 + (1.0 / (2.0 * v)).ln()).exp()
+    } else {
+        ((-b).exp() * i0(a)) / ((1.0 / v).sinh() * 2.0 * v)
+    }
+}
+
+// The fraction of light that's attenuated by each scattering event
+fn ap(cos_theta_o: f32, eta: f32, h: f32, t: Spectrum) -> [Spectrum; P_MAX + 1] {
+    let cos_gamma_o = safe_sqrt(1.0 - h * h);
+    let cos_theta = cos_theta_o * cos_gamma_o;
+    let f = Fresnel::dielectric(1.0, eta).evaluate(cos_theta).max_component_value();
+
+    let mut ap = [Spectrum::from(0.0); P_MAX + 1];
+    ap[0] = Spectrum::from(f);
+    ap[1] = (1.0 - f) * (1.0 - f) * t;
+    for p in 2..P_MAX {
+        ap[p] = ap[p - 1] * t * f;
+    }
+    ap[P_MAX] = ap[P_MAX - 1] * t * f / (Spectrum::from(1.0) - t * f);
+    ap
+}
+
+fn phi(p: usize, gamma_o: f32, gamma_t: f32) -> f32 {
+    let p = p as f32;
+    2.0 * p * gamma_t - 2.0 * gamma_o + p * PI
+}
+
+fn logistic(x: f32, s: f32) -> f32 {
+    let x = x.abs();
+    (-x / s).exp() / (s * (1.0 + (-x / s).exp()).powi(2))
+}
+
+fn logistic_cdf(x: f32, s: f32) -> f32 { 1.0 / (1.0 + (-x / s).exp()) }
+
+fn trimmed_logistic(x: f32, s: f32, a: f32, b: f32) -> f32 {
+    logistic(x, s) / (logistic_cdf(b, s) - logistic_cdf(a, s))
+}
+
+fn sample_trimmed_logistic(u: f32, s: f32, a: f32, b: f32) -> f32 {
+    let k = logistic_cdf(b, s) - logistic_cdf(a, s);
+    let x = -s * (1.0 / (u * k + logistic_cdf(a, s)) - 1.0).ln();
+    x.clamp(a, b)
+}
+
+// The azimuthal scattering function
+fn np(phi_diff: f32, p: usize, s: f32, gamma_o: f32, gamma_t: f32) -> f32 {
+    let mut dphi = phi_diff - phi(p, gamma_o, gamma_t);
+    while dphi > PI { dphi -= 2.0 * PI; }
+    while dphi < -PI { dphi += 2.0 * PI; }
+    trimmed_logistic(dphi, s, -PI, PI)
+}
+
+// Splits one sample into two by taking alternating bits
+fn compact_1_by_1(x: u32) -> u32 {
+    let mut x = x & 0x55555555;
+    x = (x ^ (x >> 1)) & 0x33333333;
+    x = (x ^ (x >> 2)) & 0x0f0f0f0f;
+    x = (x ^ (x >> 4)) & 0x00ff00ff;
+    (x ^ (x >> 8)) & 0x0000ffff
+}
+
+fn demux_float(f: f32) -> (f32, f32) {
+    let v = (f as f64 * (1u64 << 32) as f64) as u64;
+    let bits = (compact_1_by_1(v as u32), compact_1_by_1((v >> 1) as u32));
+    (bits.0 as f32 / (1 << 16) as f32, bits.1 as f32 / (1 << 16) as f32)
+}
+
+// The angles that a direction makes with the hair. The hair runs along
+// dpdu, which is the y axis of the shading frame, so theta is measured
+// from the plane normal to y and phi goes around it starting at x.
+fn hair_angles(w: &Vector) -> (f32, f32, f32) {
+    let sin_theta = w.y;
+    (sin_theta, safe_sqrt(1.0 - sin_theta * sin_theta), w.z.atan2(w.x))
+}
+
+// A model of light scattering in a single hair fiber, treated as a rough
+// dielectric cylinder whose interior absorbs light. Directions are given
+// in the shading frame of a curve that's as wide as the hair, with h the
+// offset across it from -1 to 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HairBSDF {
+    h: f32,
+    gamma_o: f32,
+    eta: f32,
+    sigma_a: Spectrum,
+    v: [f32; P_MAX + 1],
+    s: f32,
+    sin_2k_alpha: [f32; 3],
+    cos_2k_alpha: [f32; 3]
+}
+
+impl HairBSDF {
+    // beta_m and beta_n are the longitudinal and azimuthal roughness, from
+    // zero to one, and alpha is the angle in degrees that the scales on
+    // the surface of the hair are tilted by.
+    pub fn new(h: f32, eta: f32, sigma_a: Spectrum, beta_m: f32, beta_n: f32,
+               alpha: f32) -> HairBSDF {
+        assert!((-1.0..=1.0).contains(&h));
+        assert!((0.0..=1.0).contains(&beta_m) && (0.0..=1.0).contains(&beta_n));
+
+        // Map the roughness onto the variance of each scattering event
+        let v0 = (0.726 * beta_m + 0.812 * beta_m * beta_m + 3.7 * beta_m.powi(20)).powi(2);
+        let v = [v0, 0.25 * v0, 4.0 * v0, 4.0 * v0];
+        let s = SQRT_PI_OVER_8 *
+            (0.265 * beta_n + 1.194 * beta_n * beta_n + 5.372 * beta_n.powi(22));
+
+        // Each bounce inside the hair tilts it by twice as much
+        let mut sin_2k_alpha = [alpha.as_radians().sin(), 0.0, 0.0];
+        let mut cos_2k_alpha = [safe_sqrt(1.0 - sin_2k_alpha[0] * sin_2k_alpha[0]), 0.0, 0.0];
+        for i in 1..3 {
+            sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+            cos_2k_alpha[i] = cos_2k_alpha[i - 1] * cos_2k_alpha[i - 1] -
+                sin_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+        }
+
+        HairBSDF {
+            h, gamma_o: safe_asin(h), eta, sigma_a, v, s, sin_2k_alpha, cos_2k_alpha
+        }
+    }
+
+    // The absorption coefficient of hair with the given concentrations of
+    // the brown-black eumelanin and red-yellow pheomelanin pigments
+    pub fn sigma_a_from_concentration(ce: f32, cp: f32) -> Spectrum {
+        let eumelanin_sigma_a = [0.419, 0.697, 1.37];
+        let pheomelanin_sigma_a = [0.187, 0.4, 1.05];
+        Spectrum::from_rgb([ce * eumelanin_sigma_a[0] + cp * pheomelanin_sigma_a[0],
+                            ce * eumelanin_sigma_a[1] + cp * pheomelanin_sigma_a[1],
+                            ce * eumelanin_sigma_a[2] + cp * pheomelanin_sigma_a[2]])
+    }
+
+    // The absorption coefficient that gives hair with the given azimuthal
+    // roughness roughly the given color after many bounces
+    pub fn sigma_a_from_reflectance(c: &Spectrum, beta_n: f32) -> Spectrum {
+        let d = 5.969 - 0.215 * beta_n + 2.532 * beta_n.powi(2) - 10.73 * beta_n.powi(3) +
+            5.574 * beta_n.powi(4) + 0.245 * beta_n.powi(5);
+        let rgb = c.to_rgb();
+        Spectrum::from_rgb([(rgb[0].ln() / d).powi(2),
+                            (rgb[1].ln() / d).powi(2),
+                            (rgb[2].ln() / d).powi(2)])
+    }
+
+    // The scales tilt the direction that each scattering event reflects
+    // light around
+    fn tilted(&self, p: usize, sin_theta_o: f32, cos_theta_o: f32) -> (f32, f32) {
+        let (sin_op, cos_op) = match p {
+            0 => (sin_theta_o * self.cos_2k_alpha[1] - cos_theta_o * self.sin_2k_alpha[1],
+                  cos_theta_o * self.cos_2k_alpha[1] + sin_theta_o * self.sin_2k_alpha[1]),
+            1 => (sin_theta_o * self.cos_2k_alpha[0] + cos_theta_o * self.sin_2k_alpha[0],
+                  cos_theta_o * self.cos_2k_alpha[0] - sin_theta_o * self.sin_2k_alpha[0]),
+            2 => (sin_theta_o * self.cos_2k_alpha[2] + cos_theta_o * self.sin_2k_alpha[2],
+                  cos_theta_o * self.cos_2k_alpha[2] - sin_theta_o * self.sin_2k_alpha[2]),
+            _ => (sin_theta_o, cos_theta_o)
+        };
+        (sin_op, cos_op.abs())
+    }
+
+    // The angle that light refracted into the hair makes with its axis, and
+    // the attenuation of each scattering event
+    fn refracted(&self, sin_theta_o: f32, cos_theta_o: f32) -> (f32, [Spectrum; P_MAX + 1]) {
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = safe_sqrt(1.0 - sin_theta_t * sin_theta_t);
+
+        let etap = (self.eta * self.eta - sin_theta_o * sin_theta_o).sqrt() / cos_theta_o;
+        let sin_gamma_t = self.h / etap;
+        let cos_gamma_t = safe_sqrt(1.0 - sin_gamma_t * sin_gamma_t);
+        let gamma_t = safe_asin(sin_gamma_t);
+
+        let t = (-self.sigma_a * (2.0 * cos_gamma_t / cos_theta_t)).exp();
+        (gamma_t, ap(cos_theta_o, self.eta, self.h, t))
+    }
+
+    // The probability of sampling each scattering event
+    fn ap_pdf(&self, ap: &[Spectrum; P_MAX + 1]) -> [f32; P_MAX + 1] {
+        let sum_y: f32 = ap.iter().map(|a| a.y()).sum();
+        let mut pdf = [0.0; P_MAX + 1];
+        for (p, a) in ap.iter().enumerate() {
+            pdf[p] = a.y() / sum_y;
+        }
+        pdf
+    }
+
+    fn pdf_with(&self, wo: &Vector, sin_theta_i: f32, cos_theta_i: f32, dphi: f32) -> f32 {
+        let (sin_theta_o, cos_theta_o, _) = hair_angles(wo);
+        let (gamma_t, ap) = self.refracted(sin_theta_o, cos_theta_o);
+        let ap_pdf = self.ap_pdf(&ap);
+
+        let lobes: f32 = (0..P_MAX).map(|p| {
+            let (sin_op, cos_op) = self.tilted(p, sin_theta_o, cos_theta_o);
+            mp(cos_theta_i, cos_op, sin_theta_i, sin_op, self.v[p]) * ap_pdf[p] *
+                np(dphi, p, self.s, self.gamma_o, gamma_t)
+        }).sum();
+
+        lobes + mp(cos_theta_i, cos_theta_o, sin_theta_i, sin_theta_o, self.v[P_MAX]) *
+            ap_pdf[P_MAX] / (2.0 * PI)
+    }
+}
+
+impl BxDF for HairBSDF {
+    fn matches_flags(&self, ty: bsdf::BxDFType) -> bool {
+        (bsdf::BxDFType::BSDF_REFLECTION | bsdf::BxDFType::BSDF_TRANSMISSION |
+         bsdf::BxDFType::BSDF_GLOSSY).contains(ty)
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        let (sin_theta_o, cos_theta_o, phi_o) = hair_angles(wo);
+        let (sin_theta_i, cos_theta_i, phi_i) = hair_angles(wi);
+        let (gamma_t, ap) = self.refracted(sin_theta_o, cos_theta_o);
+
+        // Sum the contributions of each scattering event
+        let dphi = phi_i - phi_o;
+        let lobes: Spectrum = (0..P_MAX).map(|p| {
+            let (sin_op, cos_op) = self.tilted(p, sin_theta_o, cos_theta_o);
+            mp(cos_theta_i, cos_op, sin_theta_i, sin_op, self.v[p]) * ap[p] *
+                np(dphi, p, self.s, self.gamma_o, gamma_t)
+        }).sum();
+
+        let fsum = lobes + mp(cos_theta_i, cos_theta_o, sin_theta_i, sin_theta_o, self.v[P_MAX]) *
+            ap[P_MAX] / (2.0 * PI);
+
+        if abs_cos_theta(wi) > 0.0 {
+            fsum / abs_cos_theta(wi)
+        } else {
+            fsum
+        }
+    }
+
+    fn sample_f(&self, wo: &Vector, u1: f32, u2: f32) -> (Vector, f32, Spectrum) {
+        let (sin_theta_o, cos_theta_o, phi_o) = hair_angles(wo);
+        let (gamma_t, ap) = self.refracted(sin_theta_o, cos_theta_o);
+        let ap_pdf = self.ap_pdf(&ap);
+
+        // Four sample values are needed, so split each one in two
+        let (mut u_lobe, u_phi) = demux_float(u1);
+        let (u_m, u_cos_phi) = demux_float(u2);
+
+        // Choose which scattering event to sample
+        let mut p = 0;
+        while p < P_MAX && u_lobe >= ap_pdf[p] {
+            u_lobe -= ap_pdf[p];
+            p += 1;
+        }
+
+        // Sample the longitudinal scattering function around the tilted
+        // direction
+        let (sin_op, cos_op) = self.tilted(p, sin_theta_o, cos_theta_o);
+        let u_m = u_m.max(1e-5);
+        let cos_theta = 1.0 + self.v[p] * (u_m + (1.0 - u_m) * (-2.0 / self.v[p]).exp()).ln();
+        let sin_theta = safe_sqrt(1.0 - cos_theta * cos_theta);
+        let cos_phi = (2.0 * PI * u_cos_phi).cos();
+        let sin_theta_i = -cos_theta * sin_op + sin_theta * cos_phi * cos_op;
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+
+        // Sample the azimuthal scattering function
+        let dphi = if p < P_MAX {
+            phi(p, self.gamma_o, gamma_t) + sample_trimmed_logistic(u_phi, self.s, -PI, PI)
+        } else {
+            2.0 * PI * u_phi
+        };
+
+        let phi_i = phi_o + dphi;
+        let wi = Vector::new_with(cos_theta_i * phi_i.cos(), sin_theta_i, cos_theta_i * phi_i.sin());
+        let pdf = self.pdf_with(wo, sin_theta_i, cos_theta_i, dphi);
+        let f = self.f(wo, &wi);
+        (wi, pdf, f)
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        let (_, _, phi_o) = hair_angles(wo);
+        let (sin_theta_i, cos_theta_i, phi_i) = hair_angles(wi);
+        self.pdf_with(wo, sin_theta_i, cos_theta_i, phi_i - phi_o)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::montecarlo::uniform_sample_sphere;
+    use crate::rng::RNG;
+
+    fn random_dir(rng: &mut RNG) -> Vector {
+        uniform_sample_sphere(rng.random_float(), rng.random_float())
+    }
+
+    #[test]
+    fn it_conserves_energy_without_absorption() {
+        // With nothing absorbed, all of the light that hits the hair leaves
+        // it in some direction. Importance sampling keeps the estimate from
+        // being too noisy for the smoother hairs.
+        let mut rng = RNG::new(0);
+        for &beta in [0.1, 0.3, 0.5, 0.8].iter() {
+            let h = -1.0 + 2.0 * rng.random_float();
+            let hair = HairBSDF::new(h, 1.55, Spectrum::from(0.0), beta, beta, 0.0);
+            let wo = random_dir(&mut rng);
+
+            let num_samples = 20000;
+            let sum = (0..num_samples).fold(0.0, |sum, _| {
+                let (wi, pdf, f) = hair.sample_f(&wo, rng.random_float(), rng.random_float());
+                if pdf > 0.0 { sum + f.y() * abs_cos_theta(&wi) / pdf } else { sum }
+            });
+            let total = sum / (num_samples as f32);
+            assert!((total - 1.0).abs() < 0.05, "beta {}: {}", beta, total);
+        }
+    }
+
+    #[test]
+    fn it_samples_its_own_distribution() {
+        let mut rng = RNG::new(1);
+        let sigma_a = HairBSDF::sigma_a_from_concentration(1.3, 0.0);
+        for &beta in [0.3, 0.7].iter() {
+            let hair = HairBSDF::new(0.3, 1.55, sigma_a, beta, beta, 2.0);
+            let wo = random_dir(&mut rng);
+
+            // The density integrates to one over the sphere
+            let num_samples = 20000;
+            let total = (0..num_samples).fold(0.0, |sum, _| {
+                sum + hair.pdf(&wo, &random_dir(&mut rng))
+            }) * 4.0 * PI / (num_samples as f32);
+            assert!((total - 1.0).abs() < 0.05, "beta {}: {}", beta, total);
+
+            // Samples report the same values as evaluating them directly
+            for _ in 0..100 {
+                let (wi, pdf, f) = hair.sample_f(&wo, rng.random_float(), rng.random_float());
+                assert!((wi.length() - 1.0).abs() < 1e-4);
+                assert!((pdf - hair.pdf(&wo, &wi)).abs() <= 1e-3 * pdf.max(1.0));
+                assert!((f.y() - hair.f(&wo, &wi).y()).abs() <= 1e-3 * f.y().max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn darker_hair_absorbs_more() {
+        let blond = HairBSDF::sigma_a_from_concentration(0.3, 0.0);
+        let brown = HairBSDF::sigma_a_from_concentration(1.3, 0.0);
+        let red = HairBSDF::sigma_a_from_concentration(0.3, 8.0);
+        assert!(brown.y() > blond.y());
+
+        // Pheomelanin absorbs blue far more than red
+        let rgb = red.to_rgb();
+        assert!(rgb[2] > 2.0 * rgb[0]);
+
+        // Asking for a lighter color gives less absorption
+        let light = HairBSDF::sigma_a_from_reflectance(&Spectrum::from(0.8), 0.3);
+        let dark = HairBSDF::sigma_a_from_reflectance(&Spectrum::from(0.1), 0.3);
+        assert!(dark.y() > light.y());
+    }
+}
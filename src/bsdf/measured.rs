@@ -1,11 +1,18 @@
-use bsdf;
-use bsdf::BxDF;
-use bsdf::utils::*;
-use geometry::vector::*;
-use geometry::normal::Normalize;
-use geometry::point::Point;
-use spectrum::Spectrum;
-use utils::kdtree::*;
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::bsdf;
+use crate::bsdf::BxDF;
+use crate::bsdf::utils::*;
+use crate::geometry::vector::*;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::montecarlo::Distribution1D;
+use crate::montecarlo::cosine_sample_hemisphere;
+use crate::spectrum::Spectrum;
+use crate::utils::kdtree::*;
 
 fn brdf_remap(wo: &Vector, wi: &Vector) -> Point {
     let cosi = cos_theta(wi);
@@ -54,11 +61,11 @@ impl HasPoint for IrregIsotropicSample {
 
 #[derive(Debug, Clone)]
 pub struct IrregIsotropic {
-    iso_data: KdTree<IrregIsotropicSample>
+    iso_data: Arc<KdTree<IrregIsotropicSample>>
 }
 
 impl IrregIsotropic {
-    pub fn new(data: KdTree<IrregIsotropicSample>) -> IrregIsotropic {
+    pub fn new(data: Arc<KdTree<IrregIsotropicSample>>) -> IrregIsotropic {
         IrregIsotropic { iso_data: data }
     }
 }
@@ -110,29 +117,215 @@ impl BxDF for IrregIsotropic {
             last_max_dist_sq *= 2.0;
         }
     }
+}
+
+// Reads the irregularly sampled isotropic BRDFs used by pbrt-v2: the
+// number of wavelengths and the wavelengths themselves, followed by the
+// spherical angles of wi and wo and the measured value at each wavelength
+// for every sample.
+pub fn read_irregular_isotropic<P: AsRef<Path>>(filename: &P)
+                                                -> Result<KdTree<IrregIsotropicSample>, String> {
+    let contents = fs::read_to_string(filename).map_err(|e| e.to_string())?;
+    parse_irregular_isotropic(&contents)
+}
+
+pub fn parse_irregular_isotropic(contents: &str) -> Result<KdTree<IrregIsotropicSample>, String> {
+    let values = contents.lines()
+        .flat_map(|l| l.split('#').next().unwrap_or("").split_whitespace())
+        .map(|tok| tok.parse::<f32>()
+             .map_err(|_| format!("Unexpected token in measured BRDF data: {}", tok)))
+        .collect::<Result<Vec<f32>, String>>()?;
 
-    fn sample_f(&self, _: &Vector, _: f32, _: f32) -> (Vector, f32, Spectrum) {
-        unimplemented!()
+    let num_wls = match values.first() {
+        Some(&n) if n >= 1.0 => n as usize,
+        _ => return Err(String::from("Missing wavelengths in measured BRDF data")),
+    };
+
+    // The wavelengths have to fit in the data before anything is computed
+    // from their count, which might be arbitrarily large
+    let end = || String::from("Unexpected end of measured BRDF data");
+    if num_wls > values.len() - 1 {
+        return Err(end());
+    }
+
+    let (wls, data) = values[1..].split_at(num_wls);
+    let stride = num_wls.checked_add(4).ok_or_else(end)?;
+    if data.is_empty() || data.len() % stride != 0 {
+        return Err(end());
     }
+
+    let samples: Vec<IrregIsotropicSample> = data.chunks_exact(stride).flat_map(|s| {
+        let dir = |theta: f32, phi: f32| spherical_direction(theta.sin(), theta.cos(), phi);
+        let wi = dir(s[0], s[1]);
+        let wo = dir(s[2], s[3]);
+        let pairs: Vec<(f32, f32)> = wls.iter().cloned().zip(s[4..].iter().cloned()).collect();
+        let v = Spectrum::from_samples(&pairs).into_rgb_spectrum();
+
+        // BRDFs are reciprocal, so each measurement holds for both directions
+        vec![IrregIsotropicSample::new(&brdf_remap(&wo, &wi), &v),
+             IrregIsotropicSample::new(&brdf_remap(&wi, &wo), &v)]
+    }).collect();
+
+    Ok(KdTree::new(&samples))
 }
 
+// The resolution of the MERL BRDF database, whose tables are indexed by the
+// elevation of the half angle vector and the elevation and azimuth of wi
+// relative to it. Reciprocity means that only half of the azimuths are
+// stored.
+const MERL_THETA_H: usize = 90;
+const MERL_THETA_D: usize = 90;
+const MERL_PHI_D: usize = 180;
+
+// The scales that take the stored values to reflectances
+const MERL_SCALE: [f32; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+// Measured BRDFs tabulated in half angle coordinates, along with a
+// distribution over the elevation of the half angle vector for sampling
+// them. Each entry holds the RGB reflectance of that bin.
 #[derive(Debug, Clone)]
-pub struct RegularHalfangle {
+pub struct RegularHalfangleTable {
     num_theta_h: usize,
     num_theta_d: usize,
     num_phi_d: usize,
-    brdf: Vec<f32>
+    brdf: Vec<f32>,
+    theta_h_distrib: Distribution1D
 }
 
-impl RegularHalfangle {
-    pub fn new(nthh: usize, nthd: usize, nphd: usize, d: Vec<f32>) -> RegularHalfangle {
-        assert_eq!(nthh * nthd * nphd, d.len());
-        RegularHalfangle {
+impl RegularHalfangleTable {
+    pub fn new(nthh: usize, nthd: usize, nphd: usize, d: Vec<f32>) -> RegularHalfangleTable {
+        assert_eq!(3 * nthh * nthd * nphd, d.len());
+
+        // Half angles are sampled in proportion to the average brightness
+        // at each elevation. The elevations are stored in bins spaced by
+        // their square root, so account for the solid angle that each
+        // covers.
+        let bin_size = 3 * nthd * nphd;
+        let func: Vec<f32> = d.chunks(bin_size).enumerate().map(|(i, bin)| {
+            let t = (i as f32 + 0.5) / (nthh as f32);
+            let theta_h = 0.5 * PI * t * t;
+            let avg = bin.iter().sum::<f32>() / (bin_size as f32);
+            avg * theta_h.sin() * t
+        }).collect();
+
+        RegularHalfangleTable {
             num_theta_h: nthh,
             num_theta_d: nthd,
             num_phi_d: nphd,
-            brdf: d
+            brdf: d,
+            theta_h_distrib: Distribution1D::new(&func)
+        }
+    }
+
+    pub fn read_merl<P: AsRef<Path>>(filename: &P) -> Result<RegularHalfangleTable, String> {
+        let bytes = fs::read(filename).map_err(|e| e.to_string())?;
+        let table = RegularHalfangleTable::parse_merl(&bytes)?;
+        if (table.num_theta_h, table.num_theta_d, table.num_phi_d) !=
+            (MERL_THETA_H, MERL_THETA_D, MERL_PHI_D) {
+            return Err(String::from("Unexpected resolution for MERL BRDF data"));
+        }
+
+        Ok(table)
+    }
+
+    // The binary format of the MERL database: the three dimensions of the
+    // table as 32 bit integers followed by the red, green and blue tables
+    // as doubles, all little endian
+    pub fn parse_merl(bytes: &[u8]) -> Result<RegularHalfangleTable, String> {
+        let int = |i: usize| {
+            let b = &bytes[(4 * i)..(4 * i + 4)];
+            i32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        };
+
+        if bytes.len() < 12 {
+            return Err(String::from("Unexpected end of MERL BRDF data"));
+        }
+
+        let dims = [int(0), int(1), int(2)];
+        if dims.iter().any(|&d| d <= 0) {
+            return Err(format!("Invalid MERL BRDF dimensions: {:?}", dims));
+        }
+
+        // The dimensions come straight from the file, so their product can
+        // overflow
+        let (nthh, nthd, nphd) = (dims[0] as usize, dims[1] as usize, dims[2] as usize);
+        let mismatch = || String::from("MERL BRDF data doesn't match its dimensions");
+        let n = nthh.checked_mul(nthd).and_then(|n| n.checked_mul(nphd)).ok_or_else(mismatch)?;
+        let data = &bytes[12..];
+        if Some(data.len()) != n.checked_mul(3 * 8) {
+            return Err(mismatch());
+        }
+
+        // Interleave the channels, clamping the negative values that mark
+        // missing measurements
+        let mut brdf = vec![0.0; 3 * n];
+        for (c, channel) in data.chunks_exact(8 * n).enumerate() {
+            for (i, b) in channel.chunks_exact(8).enumerate() {
+                let v = f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
+                brdf[3 * i + c] = (v as f32 * MERL_SCALE[c]).max(0.0);
+            }
+        }
+
+        Ok(RegularHalfangleTable::new(nthh, nthd, nphd, brdf))
+    }
+
+    // Linearly interpolates between the bins around the given fractional
+    // indices. The azimuths wrap around, the elevations don't.
+    fn lookup(&self, theta_h: f32, theta_d: f32, phi_d: f32) -> [f32; 3] {
+        let split = |x: f32, cnt: usize, wrap: bool| {
+            let x = x.clamp(0.0, (cnt - 1) as f32 + if wrap { 0.999 } else { 0.0 });
+            let i0 = (x as usize).min(cnt - 1);
+            let i1 = if wrap { (i0 + 1) % cnt } else { (i0 + 1).min(cnt - 1) };
+            [(i0, 1.0 - (x - i0 as f32)), (i1, x - i0 as f32)]
+        };
+
+        let mut rgb = [0.0; 3];
+        for &(h, wh) in split(theta_h, self.num_theta_h, false).iter() {
+            for &(d, wd) in split(theta_d, self.num_theta_d, false).iter() {
+                for &(p, wp) in split(phi_d, self.num_phi_d, true).iter() {
+                    let w = wh * wd * wp;
+                    if w == 0.0 {
+                        continue;
+                    }
+
+                    let index = p + self.num_phi_d * (d + h * self.num_theta_d);
+                    for (c, v) in rgb.iter_mut().enumerate() {
+                        *v += w * self.brdf[3 * index + c];
+                    }
+                }
+            }
+        }
+
+        rgb
+    }
+
+    // The density of sampling the half angle vector wh, which must lie in
+    // the upper hemisphere
+    fn pdf_wh(&self, wh: &Vector) -> f32 {
+        let theta_h = spherical_theta(wh);
+        let t = (theta_h / (0.5 * PI)).max(0.0).sqrt();
+        let sin_theta_h = sin_theta(wh);
+        if t == 0.0 || sin_theta_h == 0.0 {
+            return 0.0;
         }
+
+        let n = self.theta_h_distrib.count();
+        let bin = ((t * n as f32) as usize).min(n - 1);
+        let pdf_t = self.theta_h_distrib.discrete_pdf(bin) * (n as f32);
+
+        // Account for the mapping from t to theta_h and the azimuth
+        pdf_t / (PI * t * 2.0 * PI * sin_theta_h)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegularHalfangle {
+    table: Arc<RegularHalfangleTable>
+}
+
+impl RegularHalfangle {
+    pub fn new(table: Arc<RegularHalfangleTable>) -> RegularHalfangle {
+        RegularHalfangle { table }
     }
 }
 
@@ -157,30 +350,147 @@ impl BxDF for RegularHalfangle {
         // Compute index into measured BRDF tables
         let (wd_theta, wd_phi) = {
             let (t, p) = (spherical_theta(&wd), spherical_phi(&wd));
-            if p > ::std::f32::consts::PI {
-                (t, p - ::std::f32::consts::PI)
+            if p > PI {
+                (t, p - PI)
             } else {
                 (t, p)
             }
         };
 
-        // Compute wh_theta_index, wd_theta_index, and wd_phi_index
-        let remap = |v: f32, mx: f32, cnt: usize| { (((v / mx) * (cnt as f32)) as usize).clamp(0, cnt - 1) };
-        let wh_theta_index = remap((wh_theta / (::std::f32::consts::PI / 2.0)).max(0.0).sqrt(),
-                                   1.0, self.num_theta_h);
-        let wd_theta_index = remap(wd_theta, ::std::f32::consts::PI / 2.0, self.num_theta_d);
-        let wd_phi_index = remap(wd_phi, ::std::f32::consts::PI, self.num_phi_d);
+        let table = &self.table;
+        let wh_theta_index = (wh_theta / (PI / 2.0)).max(0.0).sqrt() * (table.num_theta_h as f32);
+        let wd_theta_index = wd_theta / (PI / 2.0) * (table.num_theta_d as f32);
+        let wd_phi_index = wd_phi / PI * (table.num_phi_d as f32);
 
-        let index = wd_phi_index + self.num_phi_d *
-            (wd_theta_index + wh_theta_index * self.num_theta_d);
+        Spectrum::from_rgb(table.lookup(wh_theta_index, wd_theta_index, wd_phi_index))
+    }
 
-        let rgb = [self.brdf[index * 3 + 0],
-                   self.brdf[index * 3 + 1],
-                   self.brdf[index * 3 + 2]];
-        Spectrum::from_rgb(rgb)
+    // Half of the samples follow the measured distribution of half angles
+    // and the rest the cosine-weighted hemisphere, which covers wherever
+    // the measurements are dim.
+    fn sample_f(&self, wo: &Vector, u1: f32, u2: f32) -> (Vector, f32, Spectrum) {
+        let wi = if u1 < 0.5 {
+            let mut wi = cosine_sample_hemisphere(2.0 * u1, u2);
+            if wo.z < 0.0 {
+                wi.z *= -1.0;
+            }
+            wi
+        } else {
+            let (t, _, _) = self.table.theta_h_distrib.sample_continuous(2.0 * (u1 - 0.5));
+            let theta_h = 0.5 * PI * t * t;
+            let mut wh = spherical_direction(theta_h.sin(), theta_h.cos(), 2.0 * PI * u2);
+            if wo.z < 0.0 {
+                wh.z *= -1.0;
+            }
+            -wo + 2.0 * wo.dot(&wh) * &wh
+        };
+
+        if !same_hemisphere(wo, &wi) {
+            return (wi, 0.0, Spectrum::from(0.0));
+        }
+
+        (wi.clone(), self.pdf(wo, &wi), self.f(wo, &wi))
     }
 
-    fn sample_f(&self, _: &Vector, _: f32, _: f32) -> (Vector, f32, Spectrum) {
-        unimplemented!()
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        if !same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+
+        let wh = (wo + wi).normalize();
+        let wh = if wh.z < 0.0 { -wh } else { wh };
+        let pdf_wi = self.table.pdf_wh(&wh) / (4.0 * wo.abs_dot(&wh));
+        0.5 * (abs_cos_theta(wi) / PI + pdf_wi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::montecarlo::uniform_sample_hemisphere;
+
+    // A MERL file with the given resolution whose red channel is brighter
+    // for half angles closer to the normal
+    fn merl_data(nthh: usize, nthd: usize, nphd: usize) -> Vec<u8> {
+        let n = nthh * nthd * nphd;
+        let mut bytes: Vec<u8> = [nthh, nthd, nphd].iter()
+            .flat_map(|&d| (d as i32).to_le_bytes()).collect();
+        for c in 0..3 {
+            for i in 0..n {
+                let theta_h = i / (nthd * nphd);
+                let v = if c == 0 { 1500.0 * (nthh - theta_h) as f64 } else { -1.0 };
+                bytes.extend(v.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn it_reads_merl_brdfs() {
+        let table = RegularHalfangleTable::parse_merl(&merl_data(4, 3, 6)).unwrap();
+        assert_eq!((table.num_theta_h, table.num_theta_d, table.num_phi_d), (4, 3, 6));
+        assert_eq!(&table.brdf[0..3], &[4.0, 0.0, 0.0]);
+        assert_eq!(table.lookup(0.5, 1.0, 5.5), [3.5, 0.0, 0.0]);
+
+        let data = merl_data(4, 3, 6);
+        assert!(RegularHalfangleTable::parse_merl(&data[0..(data.len() - 1)]).is_err());
+        assert!(RegularHalfangleTable::parse_merl(&[0; 12]).is_err());
+
+        // Dimensions whose product overflows are rejected
+        let huge: Vec<u8> = [i32::MAX; 3].iter().flat_map(|d| d.to_le_bytes()).collect();
+        assert!(RegularHalfangleTable::parse_merl(&huge).is_err());
+    }
+
+    #[test]
+    fn it_reads_irregular_brdfs() {
+        // Each measurement is stored for both directions
+        let data = parse_irregular_isotropic("2 400 700\n0.5 0 0.5 3.14 1 1 # a sample").unwrap();
+        assert_eq!(data.size(), 2);
+        assert!(parse_irregular_isotropic("2 400 700 0.5 0 0.5 3.14 1").is_err());
+        assert!(parse_irregular_isotropic("").is_err());
+
+        // Wavelength counts that are larger than the data are rejected
+        assert!(parse_irregular_isotropic("1e30 400 0.5 0 0.5 3.14 1").is_err());
+        assert!(parse_irregular_isotropic("inf 400 0.5 0 0.5 3.14 1").is_err());
+    }
+
+    #[test]
+    fn it_samples_measured_brdfs() {
+        let table = Arc::new(RegularHalfangleTable::parse_merl(&merl_data(16, 8, 16)).unwrap());
+        let bxdf = RegularHalfangle::new(table);
+        let wo = Vector::new_with(0.0, 0.6, 0.8);
+
+        // Reflecting wo about the normal finds the brightest half angles
+        let mirror = Vector::new_with(0.0, -0.6, 0.8);
+        let f = bxdf.f(&wo, &mirror).to_rgb();
+        assert!(f[0] > 15.0 && f[1] == 0.0);
+        assert!(bxdf.f(&wo, &Vector::new_with(0.0, 0.6, 0.8)).to_rgb()[0] < f[0]);
+
+        let n = 32;
+        let mut total = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let u1 = (i as f32 + 0.5) / (n as f32);
+                let u2 = (j as f32 + 0.5) / (n as f32);
+                let (wi, pdf, f) = bxdf.sample_f(&wo, u1, u2);
+                if pdf == 0.0 {
+                    continue;
+                }
+
+                assert!(wi.z > 0.0);
+                assert!((pdf - bxdf.pdf(&wo, &wi)).abs() < 1e-3 * pdf.max(1.0));
+                total += f.to_rgb()[0] * abs_cos_theta(&wi) / pdf;
+            }
+        }
+
+        // Sampling agrees with integrating over the hemisphere uniformly
+        let uniform: f32 = (0..(n * n)).map(|i| {
+            let u1 = ((i / n) as f32 + 0.5) / (n as f32);
+            let u2 = ((i % n) as f32 + 0.5) / (n as f32);
+            let wi = uniform_sample_hemisphere(u1, u2);
+            bxdf.f(&wo, &wi).to_rgb()[0] * cos_theta(&wi) * 2.0 * PI
+        }).sum();
+        let (total, uniform) = (total / (n * n) as f32, uniform / (n * n) as f32);
+        assert!((total - uniform).abs() < 0.05 * uniform, "{} vs {}", total, uniform);
     }
 }
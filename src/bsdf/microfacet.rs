@@ -1,17 +1,50 @@
-use bsdf;
-use bsdf::BxDF;
-use bsdf::fresnel::Fresnel;
-use bsdf::utils::*;
-use geometry::normal::Normalize;
-use geometry::vector::Vector;
-use geometry::vector::Dot;
-use spectrum::Spectrum;
-use utils::Degrees;
+use crate::bsdf;
+use crate::bsdf::BxDF;
+use crate::bsdf::fresnel::Fresnel;
+use crate::bsdf::utils::*;
+use crate::geometry::normal::Normalize;
+use crate::geometry::vector::Vector;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Dot;
+use crate::montecarlo::cosine_sample_hemisphere;
+use crate::spectrum::Spectrum;
+use crate::utils::Degrees;
+
+// Which family of microfacet distributions a material uses. Blinn is
+// parameterized by exponents, which materials find from the reciprocal of
+// their roughness, and GGX by the slope of the microfacets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DistributionKind {
+    #[default]
+    Blinn,
+    GGX
+}
+
+impl DistributionKind {
+    pub fn from_name(name: &str) -> Option<DistributionKind> {
+        match name {
+            "blinn" => Some(DistributionKind::Blinn),
+            "ggx" | "trowbridgereitz" => Some(DistributionKind::GGX),
+            _ => None
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MicrofacetDistribution {
     Blinn(f32),
-    Anisotropic(f32, f32)
+    Anisotropic(f32, f32),
+    // Trowbridge-Reitz, with the roughness along the shading frame's x and
+    // y axes
+    GGX(f32, f32)
+}
+
+// Maps the perceptually linear roughness that materials are given to the
+// slope of GGX microfacets
+fn roughness_to_alpha(rough: f32) -> f32 {
+    let x = rough.max(1e-3).ln();
+    1.62142 + 0.819955 * x + 0.1734 * x * x + 0.0171201 * x * x * x +
+        0.000640711 * x * x * x * x
 }
 
 impl MicrofacetDistribution {
@@ -29,6 +62,28 @@ impl MicrofacetDistribution {
         MicrofacetDistribution::Anisotropic(x1, x2)
     }
 
+    pub fn ggx(alpha: f32) -> MicrofacetDistribution {
+        let alpha = alpha.max(1e-3);
+        MicrofacetDistribution::GGX(alpha, alpha)
+    }
+
+    // The distribution of the given kind for a material's roughness, which
+    // may differ along the u and v directions of its surface. The shading
+    // frame's y axis follows u, so the u roughness shapes the highlight
+    // along y.
+    pub fn from_roughness(kind: DistributionKind, u_rough: f32,
+                          v_rough: f32) -> MicrofacetDistribution {
+        match kind {
+            DistributionKind::Blinn if u_rough == v_rough =>
+                MicrofacetDistribution::blinn(1.0 / u_rough),
+            DistributionKind::Blinn =>
+                MicrofacetDistribution::anisotropic(1.0 / v_rough, 1.0 / u_rough),
+            DistributionKind::GGX =>
+                MicrofacetDistribution::GGX(roughness_to_alpha(v_rough),
+                                            roughness_to_alpha(u_rough))
+        }
+    }
+
     fn d(&self, wh: &Vector) -> f32 {
         let invtwopi = 1.0 / (2.0 * ::std::f32::consts::PI);
         match self {
@@ -46,8 +101,107 @@ impl MicrofacetDistribution {
                 let e = (ex * wh.x * wh.x + ey * wh.y * wh.y) / d;
                 ((ex + 2.0) * (ey + 2.0)).sqrt() * invtwopi * costhetah.powf(e)
             }
+            &MicrofacetDistribution::GGX(ax, ay) => {
+                let cos2_theta = wh.z * wh.z;
+                if cos2_theta == 0.0 {
+                    return 0.0;
+                }
+
+                let tan2_theta = sin_theta2(wh) / cos2_theta;
+                let (cos_phi, sin_phi) = (cos_phi(wh), sin_phi(wh));
+                let e = (cos_phi * cos_phi / (ax * ax) + sin_phi * sin_phi / (ay * ay)) *
+                    tan2_theta;
+                1.0 / (::std::f32::consts::PI * ax * ay * cos2_theta * cos2_theta *
+                       (1.0 + e) * (1.0 + e))
+            }
+        }
+    }
+
+    // The ratio of the microfacets' area that's hidden from w to the area
+    // that's visible, for Smith's masking function
+    fn lambda(&self, w: &Vector) -> f32 {
+        match self {
+            &MicrofacetDistribution::GGX(ax, ay) => {
+                let abs_tan_theta = (sin_theta(w) / cos_theta(w)).abs();
+                if !abs_tan_theta.is_finite() {
+                    return 0.0;
+                }
+
+                let (cos_phi, sin_phi) = (cos_phi(w), sin_phi(w));
+                let alpha = (cos_phi * cos_phi * ax * ax + sin_phi * sin_phi * ay * ay).sqrt();
+                let alpha2_tan2_theta = (alpha * abs_tan_theta) * (alpha * abs_tan_theta);
+                (-1.0 + (1.0 + alpha2_tan2_theta).sqrt()) / 2.0
+            }
+            _ => panic!("Only GGX uses Smith's masking function")
+        }
+    }
+
+    // The fraction of microfacets with normal wh that are visible from w
+    fn g1(&self, w: &Vector) -> f32 { 1.0 / (1.0 + self.lambda(w)) }
+
+    fn g(&self, wo: &Vector, wi: &Vector, wh: &Vector) -> f32 {
+        match self {
+            MicrofacetDistribution::GGX(..) =>
+                1.0 / (1.0 + self.lambda(wo) + self.lambda(wi)),
+            _ => {
+                // The Torrance-Sparrow V-cavity model
+                let ndotwh = abs_cos_theta(wh);
+                let ndotwo = abs_cos_theta(wo);
+                let ndotwi = abs_cos_theta(wi);
+                let wodotwh = wo.abs_dot(wh);
+                (2.0 * ndotwh * ndotwo / wodotwh)
+                    .min(2.0 * ndotwh * ndotwi / wodotwh)
+                    .min(1.0)
+            }
         }
     }
+
+    // Samples a microfacet normal from those visible from wo, returning it
+    // along with its density. Only GGX can be sampled this way, the others
+    // return None.
+    fn sample_wh(&self, wo: &Vector, u1: f32, u2: f32) -> Option<(Vector, f32)> {
+        let (ax, ay) = match self {
+            &MicrofacetDistribution::GGX(ax, ay) => (ax, ay),
+            _ => return None
+        };
+
+        // Stretch wo so that the distribution becomes a hemisphere with a
+        // roughness of one, and sample the projected area of the hemisphere
+        // that's visible from it
+        let flip = wo.z < 0.0;
+        let wo = if flip { -wo } else { wo.clone() };
+        let vh = Vector::new_with(ax * wo.x, ay * wo.y, wo.z).normalize();
+        let len_sq = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if len_sq > 0.0 {
+            Vector::new_with(-vh.y, vh.x, 0.0) / len_sq.sqrt()
+        } else {
+            Vector::new_with(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross_with(&t1);
+
+        let r = u1.sqrt();
+        let phi = 2.0 * ::std::f32::consts::PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+
+        // Unstretch the sampled normal
+        let wh = Vector::new_with(ax * nh.x, ay * nh.y, nh.z.max(1e-6)).normalize();
+        let wh = if flip { -wh } else { wh };
+        Some((wh.clone(), self.pdf_wh(&wo, &wh)))
+    }
+
+    fn pdf_wh(&self, wo: &Vector, wh: &Vector) -> f32 {
+        let cos_theta_o = abs_cos_theta(wo);
+        if cos_theta_o == 0.0 {
+            return 0.0;
+        }
+
+        self.d(wh) * self.g1(wo) * wo.abs_dot(wh) / cos_theta_o
+    }
+
+    fn can_sample(&self) -> bool { matches!(self, MicrofacetDistribution::GGX(..)) }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -66,16 +220,6 @@ impl Microfacet {
             fresnel: f
         }
     }
-
-    fn g(&self, wo: &Vector, wi: &Vector, wh: &Vector) -> f32 {
-        let ndotwh = abs_cos_theta(wh);
-        let ndotwo = abs_cos_theta(wo);
-        let ndotwi = abs_cos_theta(wi);
-        let wodotwh = wo.abs_dot(wh);
-        (2.0 * ndotwh * ndotwo / wodotwh)
-            .min(2.0 * ndotwh * ndotwi / wodotwh)
-            .min(1.0)
-    }
 }
 
 impl BxDF for Microfacet {
@@ -94,12 +238,152 @@ impl BxDF for Microfacet {
         let wh = (wo + wi).normalize();
         let cos_theta_h = wi.dot(&wh);
         let f = self.fresnel.evaluate(cos_theta_h);
-        (self.r * self.distribution.d(&wh) * self.g(&wo, &wi, &wh) * f) /
+        (self.r * self.distribution.d(&wh) * self.distribution.g(&wo, &wi, &wh) * f) /
             (4.0 * cos_theta_i * cos_theta_o)
     }
 
-    fn sample_f(&self, _: &Vector, _: f32, _: f32) -> (Vector, f32, Spectrum) {
-        unimplemented!()
+    // Distributions that can't be sampled directly fall back to sampling
+    // the cosine-weighted hemisphere
+    fn sample_f(&self, wo: &Vector, u1: f32, u2: f32) -> (Vector, f32, Spectrum) {
+        let wi = match self.distribution.sample_wh(wo, u1, u2) {
+            Some((wh, _)) => {
+                let wi = -wo + 2.0 * wo.dot(&wh) * &wh;
+                if !same_hemisphere(wo, &wi) {
+                    return (wi, 0.0, Spectrum::from(0.0));
+                }
+                wi
+            },
+            None => {
+                let mut wi = cosine_sample_hemisphere(u1, u2);
+                if wo.z < 0.0 {
+                    wi.z *= -1.0;
+                }
+                wi
+            }
+        };
+
+        (wi.clone(), self.pdf(wo, &wi), self.f(wo, &wi))
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        if !same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+
+        if self.distribution.can_sample() {
+            // Account for reflecting wo about the sampled normal
+            let wh = (wo + wi).normalize();
+            self.distribution.pdf_wh(wo, &wh) / (4.0 * wo.abs_dot(&wh))
+        } else {
+            abs_cos_theta(wi) / ::std::f32::consts::PI
+        }
+    }
+}
+
+// Refracts w about the normal n, where eta is the ratio of the indices of
+// refraction on w's side to those on the other side. Returns None if w is
+// totally internally reflected.
+fn refract(w: &Vector, n: &Vector, eta: f32) -> Option<Vector> {
+    let cos_theta_i = n.dot(w);
+    let sin2_theta_t = eta * eta * (1.0 - cos_theta_i * cos_theta_i).max(0.0);
+    if sin2_theta_t >= 1.0 {
+        return None;
+    }
+
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+    Some(eta * -w + (eta * cos_theta_i - cos_theta_t) * n)
+}
+
+// Light refracting through a rough dielectric boundary. As with
+// SpecularTransmission, radiance isn't scaled by the change in the index of
+// refraction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MicrofacetTransmission {
+    t: Spectrum,
+    distribution: MicrofacetDistribution,
+    eta_a: f32,
+    eta_b: f32
+}
+
+impl MicrofacetTransmission {
+    // Only GGX distributions can be used, since they're sampled directly.
+    // The normal points towards the side with index of refraction eta_a.
+    pub fn new(t: Spectrum, dist: MicrofacetDistribution,
+               eta_a: f32, eta_b: f32) -> MicrofacetTransmission {
+        assert!(dist.can_sample());
+        MicrofacetTransmission { t, distribution: dist, eta_a, eta_b }
+    }
+
+    // The relative index of refraction of the side that wo lies on
+    fn eta(&self, wo: &Vector) -> f32 {
+        if cos_theta(wo) > 0.0 { self.eta_b / self.eta_a } else { self.eta_a / self.eta_b }
+    }
+
+    // The normal of the microfacet that refracts wo into wi, along with the
+    // change of density from microfacet normals to refracted directions
+    fn half_vector(&self, wo: &Vector, wi: &Vector) -> Option<(Vector, f32)> {
+        if same_hemisphere(wo, wi) {
+            return None;
+        }
+
+        let eta = self.eta(wo);
+        let wh = (wo + eta * wi).normalize();
+        let wh = if wh.z < 0.0 { -wh } else { wh };
+        let denom = wo.dot(&wh) + eta * wi.dot(&wh);
+        if denom == 0.0 || wo.dot(&wh) * wi.dot(&wh) > 0.0 {
+            return None;
+        }
+
+        let dwh_dwi = (eta * eta * wi.dot(&wh)).abs() / (denom * denom);
+        Some((wh, dwh_dwi))
+    }
+}
+
+impl BxDF for MicrofacetTransmission {
+    fn matches_flags(&self, ty: bsdf::BxDFType) -> bool {
+        (bsdf::BxDFType::BSDF_TRANSMISSION | bsdf::BxDFType::BSDF_GLOSSY).contains(ty)
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        let cos_theta_o = cos_theta(wo);
+        let cos_theta_i = cos_theta(wi);
+        if cos_theta_o == 0.0 || cos_theta_i == 0.0 {
+            return Spectrum::from(0.0);
+        }
+
+        let (wh, dwh_dwi) = match self.half_vector(wo, wi) {
+            Some(h) => h,
+            None => return Spectrum::from(0.0)
+        };
+
+        let f = Fresnel::dielectric(self.eta_a, self.eta_b).evaluate(wo.dot(&wh));
+        (Spectrum::from(1.0) - f) * self.t *
+            (self.distribution.d(&wh) * self.distribution.g(wo, wi, &wh) *
+             dwh_dwi * wo.abs_dot(&wh) / (cos_theta_i * cos_theta_o).abs())
+    }
+
+    fn sample_f(&self, wo: &Vector, u1: f32, u2: f32) -> (Vector, f32, Spectrum) {
+        let no_sample = (Vector::new(), 0.0, Spectrum::from(0.0));
+        if wo.z == 0.0 {
+            return no_sample;
+        }
+
+        let wh = match self.distribution.sample_wh(wo, u1, u2) {
+            Some((wh, _)) if wo.dot(&wh) > 0.0 => wh,
+            _ => return no_sample
+        };
+
+        match refract(wo, &wh, 1.0 / self.eta(wo)) {
+            Some(wi) => (wi.clone(), self.pdf(wo, &wi), self.f(wo, &wi)),
+            None => no_sample
+        }
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        match self.half_vector(wo, wi) {
+            Some((wh, dwh_dwi)) => self.distribution.pdf_wh(wo, &wh) * dwh_dwi,
+            None => 0.0
+        }
     }
 }
 
@@ -142,8 +426,117 @@ impl BxDF for FresnelBlend {
             self.schlick_fresnel(wi.dot(&wh));
         diffuse + specular
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::montecarlo::{stratified_sample_2d, uniform_sample_hemisphere};
+    use crate::montecarlo::uniform_hemisphere_pdf;
+    use crate::rng::RNG;
+
+    #[test]
+    fn ggx_samples_the_normals_visible_from_wo() {
+        let mut samples = vec![0.0; 2 * 64 * 64];
+        stratified_sample_2d(&mut samples, 64, 64, &mut RNG::new(0), true);
+        let num_samples = (samples.len() / 2) as f32;
+
+        for &(ax, ay) in [(0.3, 0.3), (0.1, 0.5)].iter() {
+            let distribution = MicrofacetDistribution::GGX(ax, ay);
+            let wo = Vector::new_with(0.48, 0.36, 0.8);
+
+            // The projected area of the microfacets is that of the surface
+            let projected: f32 = samples.chunks_exact(2).map(|u| {
+                let wh = uniform_sample_hemisphere(u[0], u[1]);
+                distribution.d(&wh) * cos_theta(&wh) / uniform_hemisphere_pdf()
+            }).sum();
+            assert!((projected / num_samples - 1.0).abs() < 1e-2);
+
+            for u in samples.chunks_exact(2).step_by(7) {
+                let (wh, pdf) = distribution.sample_wh(&wo, u[0], u[1]).unwrap();
+                assert!(wh.z > 0.0 && wo.dot(&wh) > 0.0);
+                assert!((pdf - distribution.pdf_wh(&wo, &wh)).abs() < 1e-3 * pdf.max(1.0));
+            }
+
+            // Without any Fresnel loss, only the light that's masked or
+            // reflected below the surface is lost
+            let brdf = Microfacet::new(Spectrum::from(1.0), Fresnel::noop(), distribution);
+            let rho = brdf.rho_hd(&wo, &samples).y();
+            let rho_uniform: f32 = samples.chunks_exact(2).map(|u| {
+                let wi = uniform_sample_hemisphere(u[0], u[1]);
+                brdf.f(&wo, &wi).y() * cos_theta(&wi) / uniform_hemisphere_pdf()
+            }).sum::<f32>() / num_samples;
+            assert!(rho > 0.75 && rho <= 1.0);
+            assert!((rho - rho_uniform).abs() < 1e-2);
+
+            let (wi, pdf, f) = brdf.sample_f(&wo, 0.3, 0.6);
+            assert!((pdf - brdf.pdf(&wo, &wi)).abs() < 1e-3 * pdf.max(1.0));
+            assert!((f.y() - brdf.f(&wo, &wi).y()).abs() < 1e-3 * f.y().max(1.0));
+        }
+
+        // Other distributions are sampled with the cosine-weighted hemisphere
+        let blinn = Microfacet::new(Spectrum::from(1.0), Fresnel::noop(),
+                                    MicrofacetDistribution::blinn(10.0));
+        let wo = Vector::new_with(0.0, 0.6, -0.8);
+        let (wi, pdf, _) = blinn.sample_f(&wo, 0.3, 0.6);
+        assert!(wi.z < 0.0);
+        assert!((pdf - abs_cos_theta(&wi) / ::std::f32::consts::PI).abs() < 1e-5);
+    }
+
+    #[test]
+    fn rough_dielectrics_sample_what_they_transmit() {
+        let mut samples = vec![0.0; 2 * 64 * 64];
+        stratified_sample_2d(&mut samples, 64, 64, &mut RNG::new(0), true);
+        let num_samples = (samples.len() / 2) as f32;
+
+        let btdf = MicrofacetTransmission::new(
+            Spectrum::from(1.0), MicrofacetDistribution::ggx(0.3), 1.0, 1.5);
+        for wo in [Vector::new_with(0.48, 0.36, 0.8), Vector::new_with(0.0, 0.6, -0.8)] {
+            // Refracted directions lie on the other side, with the densities
+            // and values that they're evaluated with
+            for u in samples.chunks_exact(2).step_by(7) {
+                let (wi, pdf, f) = btdf.sample_f(&wo, u[0], u[1]);
+                if pdf == 0.0 {
+                    continue;
+                }
+
+                assert!(!same_hemisphere(&wo, &wi));
+                assert!((pdf - btdf.pdf(&wo, &wi)).abs() < 1e-3 * pdf.max(1.0));
+                assert!((f.y() - btdf.f(&wo, &wi).y()).abs() < 1e-3 * f.y().max(1.0));
+            }
+
+            // The density integrates to one over the other side
+            let total: f32 = samples.chunks_exact(2).map(|u| {
+                let mut wi = uniform_sample_hemisphere(u[0], u[1]);
+                if wo.z > 0.0 {
+                    wi.z *= -1.0;
+                }
+                btdf.pdf(&wo, &wi) / uniform_hemisphere_pdf()
+            }).sum::<f32>() / num_samples;
+            if wo.z > 0.0 {
+                assert!((total - 1.0).abs() < 0.02, "Total density: {}", total);
+            } else {
+                // Some directions are totally internally reflected instead
+                assert!(total > 0.5 && total < 0.9, "Total density: {}", total);
+            }
+
+            // Some light is reflected rather than transmitted
+            let rho = btdf.rho_hd(&wo, &samples).y();
+            assert!(rho > 0.5 && rho < 1.0, "Transmittance: {}", rho);
+        }
+    }
+
+    #[test]
+    fn distributions_can_be_made_from_roughness() {
+        let ggx = MicrofacetDistribution::from_roughness(DistributionKind::GGX, 0.5, 0.1);
+        match ggx {
+            MicrofacetDistribution::GGX(ax, ay) => assert!(ax < ay),
+            _ => panic!("Expected a GGX distribution")
+        }
 
-    fn sample_f(&self, _: &Vector, _: f32, _: f32) -> (Vector, f32, Spectrum) {
-        unimplemented!()
+        assert_eq!(MicrofacetDistribution::from_roughness(DistributionKind::Blinn, 0.1, 0.1),
+                   MicrofacetDistribution::Blinn(10.0));
+        assert_eq!(DistributionKind::from_name("trowbridgereitz"), Some(DistributionKind::GGX));
+        assert_eq!(DistributionKind::from_name("beckmann"), None);
     }
 }
@@ -1,20 +1,33 @@
 mod utils;
 pub mod bssrdf;
+pub mod clearcoat;
+pub mod disney;
+pub mod fourier;
 pub mod fresnel;
+pub mod hair;
 pub mod lambertian;
 pub mod measured;
 pub mod microfacet;
 pub mod orennayar;
 pub mod specular;
 
-use bsdf::utils::*;
-use diff_geom::DifferentialGeometry;
-use geometry::vector::*;
-use geometry::normal::*;
-use rng::RNG;
-use spectrum::Spectrum;
+use crate::bsdf::fresnel::IndexOfRefraction;
+use crate::bsdf::utils::*;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::vector::*;
+use crate::geometry::normal::*;
+use crate::montecarlo::cosine_sample_hemisphere;
+use crate::montecarlo::stratified_sample_2d;
+use crate::montecarlo::uniform_hemisphere_pdf;
+use crate::montecarlo::uniform_sample_hemisphere;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::spectrum::Spectrum;
+
+use bitflags::bitflags;
 
 use std::clone::Clone;
+use std::f32::consts::PI;
 use std::fmt::Debug;
 use std::marker::Sized;
 
@@ -39,26 +52,128 @@ bitflags! {
 pub trait BxDF : Debug + 'static {
     fn matches_flags(&self, _: BxDFType) -> bool;
     fn f(&self, _: &Vector, _: &Vector) -> Spectrum;
-    fn sample_f(&self, _: &Vector, _: f32, _: f32) -> (Vector, f32, Spectrum);
 
+    // By default, BxDFs are sampled with a cosine-weighted distribution over
+    // the hemisphere that wo lies in.
+    fn sample_f(&self, wo: &Vector, u1: f32, u2: f32) -> (Vector, f32, Spectrum) {
+        let mut wi = cosine_sample_hemisphere(u1, u2);
+        if wo.z < 0.0 {
+            wi.z *= -1.0;
+        }
+
+        (wi.clone(), self.pdf(wo, &wi), self.f(wo, &wi))
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        if same_hemisphere(wo, wi) {
+            abs_cos_theta(wi) / ::std::f32::consts::PI
+        } else {
+            0.0
+        }
+    }
+
+    // The hemispherical-directional reflectance, i.e. the fraction of light
+    // arriving from v that's scattered, estimated by sampling the BxDF with
+    // each pair of values in samples.
     fn rho_hd(&self, v: &Vector, samples: &[f32]) -> Spectrum {
-        unimplemented!()
+        let num_samples = samples.len() / 2;
+        let r: Spectrum = samples.chunks_exact(2).map(|u| {
+            let (wi, pdf, f) = self.sample_f(v, u[0], u[1]);
+            if pdf > 0.0 { f * (abs_cos_theta(&wi) / pdf) } else { Spectrum::from(0.0) }
+        }).sum();
+
+        if num_samples > 0 { r / (num_samples as f32) } else { r }
     }
 
+    // The hemispherical-hemispherical reflectance, i.e. the fraction of
+    // light arriving uniformly from every direction that's scattered. Each
+    // pair of values in samples1 picks the outgoing direction and the
+    // corresponding pair in samples2 samples the BxDF from it.
     fn rho_hh(&self, samples1: &[f32], samples2: &[f32]) -> Spectrum {
-        unimplemented!()
+        let num_samples = samples1.len().min(samples2.len()) / 2;
+        let r: Spectrum = samples1.chunks_exact(2).zip(samples2.chunks_exact(2)).map(|(u1, u2)| {
+            let wo = uniform_sample_hemisphere(u1[0], u1[1]);
+            let pdf_o = uniform_hemisphere_pdf();
+            let (wi, pdf_i, f) = self.sample_f(&wo, u2[0], u2[1]);
+            if pdf_i > 0.0 {
+                f * (abs_cos_theta(&wi) * abs_cos_theta(&wo) / (pdf_o * pdf_i))
+            } else {
+                Spectrum::from(0.0)
+            }
+        }).sum();
+
+        if num_samples > 0 { r / (PI * num_samples as f32) } else { r }
     }
+
+    // Changes the index of refraction of the inside of the surface, which
+    // dispersive BSDFs do once they know which wavelength they're scattering.
+    // Only BxDFs for dielectric boundaries depend on it.
+    fn set_eta(&mut self, _eta: f32) { }
+}
+
+pub struct BSDFSample {
+    pub u_dir: (f32, f32),
+    pub u_component: f32
 }
 
-pub struct BSDFSample;
 impl BSDFSample {
-    pub fn new(rng: &mut RNG) -> BSDFSample { BSDFSample }
+    pub fn new(rng: &mut RNG) -> BSDFSample {
+        let u_dir = (rng.random_float(), rng.random_float());
+        BSDFSample { u_dir, u_component: rng.random_float() }
+    }
+
+    // Takes the num-th of the BSDF samples that offsets reserved from
+    // sample, or returns None if sample doesn't have them.
+    pub fn from_sample(sample: &Sample, offsets: &BSDFSampleOffsets,
+                       num: usize) -> Option<BSDFSample> {
+        let u_component = *sample.one_d(offsets.component_offset)?.get(num)?;
+        let u_dir = sample.two_d(offsets.dir_offset)?;
+        Some(BSDFSample { u_dir: (u_dir[2 * num], u_dir[2 * num + 1]), u_component })
+    }
+}
+
+// The arrays of a Sample that hold n_samples BSDF samples
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BSDFSampleOffsets {
+    pub n_samples: usize,
+    component_offset: usize,
+    dir_offset: usize
+}
+
+impl BSDFSampleOffsets {
+    pub fn new(count: usize, sample: &mut Sample) -> BSDFSampleOffsets {
+        BSDFSampleOffsets {
+            n_samples: count,
+            component_offset: sample.add_1d(count),
+            dir_offset: sample.add_2d(count)
+        }
+    }
+}
+
+// BxDF::matches_flags reports whether a BxDF has all of the given flags. A
+// BSDF component should only be used when every one of its flags was
+// requested, so check each flag that wasn't asked for.
+fn is_component_of(bxdf: &dyn BxDF, flags: BxDFType) -> bool {
+    let all = [BxDFType::BSDF_REFLECTION, BxDFType::BSDF_TRANSMISSION,
+               BxDFType::BSDF_DIFFUSE, BxDFType::BSDF_GLOSSY,
+               BxDFType::BSDF_SPECULAR];
+    all.iter().all(|&ty| flags.contains(ty) || !bxdf.matches_flags(ty))
+}
+
+// Every flag that the BxDF has
+fn bxdf_type(bxdf: &dyn BxDF) -> BxDFType {
+    let all = [BxDFType::BSDF_REFLECTION, BxDFType::BSDF_TRANSMISSION,
+               BxDFType::BSDF_DIFFUSE, BxDFType::BSDF_GLOSSY,
+               BxDFType::BSDF_SPECULAR];
+    all.iter().filter(|&&ty| bxdf.matches_flags(ty))
+        .fold(BxDFType::empty(), |acc, &ty| acc | ty)
 }
 
 #[derive(Debug)]
 pub struct BSDF {
     pub dg_shading: DifferentialGeometry,
     pub eta: f32,
+    dispersion: Option<IndexOfRefraction>,
     nn: Normal,
     ng: Normal,
     sn: Vector,
@@ -68,7 +183,12 @@ pub struct BSDF {
 
 impl BSDF {
     pub fn new_with_eta(dg: DifferentialGeometry, n_geom: Normal, e: f32) -> BSDF {
-        let shading_normal = dg.nn.clone();
+        // The local shading frame always has the outside of the surface,
+        // which is the side that the geometric normal points towards, in
+        // its upper hemisphere. Otherwise BxDFs that depend on which side
+        // wo is on, like those for glass, would swap their indices of
+        // refraction when the shading normal faces away.
+        let shading_normal = dg.nn.clone().face_forward(Vector::from(n_geom.clone()));
         let shading_normal_t = dg.dpdu.clone().normalize();
         let shading_normal_s =
             Vector::from(shading_normal.clone())
@@ -77,6 +197,7 @@ impl BSDF {
         BSDF {
             dg_shading: dg,
             eta: e,
+            dispersion: None,
             nn: shading_normal,
             ng: n_geom,
             sn: shading_normal_s,
@@ -89,6 +210,41 @@ impl BSDF {
         BSDF::new_with_eta(dg, n_geom, 1.0)
     }
 
+    // Whether wo lies on the outside of the surface, in which case rays
+    // transmitted along it enter the object.
+    pub fn is_entering(&self, wo_w: &Vector) -> bool {
+        wo_w.dot(&self.ng) > 0.0
+    }
+
+    // The ratio of the indices of refraction across the surface for light
+    // travelling along -wo, i.e. eta when entering and 1/eta when leaving.
+    pub fn relative_eta(&self, wo_w: &Vector) -> f32 {
+        if self.is_entering(wo_w) { self.eta } else { 1.0 / self.eta }
+    }
+
+    // Makes the index of refraction inside the surface depend on
+    // wavelength. Until set_wavelength is called, eta is the index's
+    // nominal value.
+    pub fn with_dispersion(mut self, index: IndexOfRefraction) -> BSDF {
+        if index.is_dispersive() {
+            self.dispersion = Some(index);
+        }
+        self
+    }
+
+    pub fn is_dispersive(&self) -> bool { self.dispersion.is_some() }
+
+    // Scatters light of a single wavelength in nanometers, which only
+    // changes dispersive BSDFs
+    pub fn set_wavelength(&mut self, lambda: f32) {
+        if let Some(index) = self.dispersion {
+            self.eta = index.eta(lambda);
+            for bxdf in self.bxdfs.iter_mut() {
+                bxdf.set_eta(self.eta);
+            }
+        }
+    }
+
     pub fn add_bxdf<T: BxDF>(&mut self, bxdf: T) {
         self.bxdfs.push(Box::new(bxdf));
     }
@@ -96,7 +252,7 @@ impl BSDF {
     pub fn num_components(&self) -> usize { self.bxdfs.len() }
     pub fn num_components_matching(&self, flags: BxDFType) -> usize {
         self.bxdfs.iter().fold(0, |acc, bxdf| {
-            if bxdf.matches_flags(flags) {
+            if is_component_of(bxdf.as_ref(), flags) {
                 acc + 1
             } else {
                 acc
@@ -118,6 +274,7 @@ impl BSDF {
         let s2 = (Spectrum::from(1.0) - s.clone()).clamp(0.0, ::std::f32::MAX);
 
         let mut ret = BSDF::new_with_eta(self.dg_shading.clone(), self.ng.clone(), self.eta);
+        ret.dispersion = self.dispersion.or(other.dispersion);
         for b in self.bxdfs.into_iter() {
             ret.add_bxdf(ScaledBxDF::new(b, s.clone()));
         }
@@ -129,18 +286,53 @@ impl BSDF {
         ret
     }
 
+    // Covers the reflection of this BSDF with a smooth dielectric coating
+    // with the index of refraction eta, e.g. lacquer. Light is tinted by the
+    // given color every time it crosses the coating straight down. Any
+    // components that transmit light are left uncoated.
+    pub fn with_clear_coat(self, eta: f32, tint: Spectrum) -> BSDF {
+        let (transmitting, coated): (Vec<_>, Vec<_>) = self.bxdfs.into_iter()
+            .partition(|b| b.matches_flags(BxDFType::BSDF_TRANSMISSION));
+
+        // Light that the base scatters back at the coating is partly
+        // reflected down again, which the coated components would otherwise
+        // lose. Sum the series of bounces between the layers using the
+        // albedo of the base and the coating's average internal reflectance.
+        let n = 4;
+        let samples: Vec<f32> = (0..(n * n)).flat_map(|i| {
+            vec![((i / n) as f32 + 0.5) / n as f32, ((i % n) as f32 + 0.5) / n as f32]
+        }).collect();
+        let up = Vector::new_with(0.0, 0.0, 1.0);
+        let albedo: Spectrum = coated.iter().map(|b| b.rho_hd(&up, &samples)).sum();
+        let internal = 1.0 - (1.0 - clearcoat::average_fresnel(eta)) / (eta * eta);
+        let trapped = (albedo * tint * tint * internal).clamp(0.0, 0.99);
+        let compensation = Spectrum::from(1.0) / (Spectrum::from(1.0) - trapped);
+
+        let mut ret = BSDF::new_with_eta(self.dg_shading, self.ng, self.eta);
+        ret.dispersion = self.dispersion;
+        ret.add_bxdf(specular::SpecularReflection::new(
+            Spectrum::from(1.0), fresnel::Fresnel::dielectric(1.0, eta)));
+        for b in coated.into_iter() {
+            ret.add_bxdf(clearcoat::ClearCoat::new(b, eta, tint, compensation));
+        }
+        ret.bxdfs.extend(transmitting);
+        ret
+    }
+
     pub fn f(&self, wo_w: Vector, wi_w: Vector, in_flags: BxDFType) -> Spectrum {
-        let flags = if wi_w.dot(&self.ng) * wo_w.dot(&self.ng) > 0.0 {
-            in_flags & !BxDFType::BSDF_TRANSMISSION
+        // BxDFs that both reflect and transmit, like hair, count on either
+        // side of the surface
+        let side = if wi_w.dot(&self.ng) * wo_w.dot(&self.ng) > 0.0 {
+            BxDFType::BSDF_REFLECTION
         } else {
-            in_flags & !BxDFType::BSDF_REFLECTION
+            BxDFType::BSDF_TRANSMISSION
         };
 
         let wo = self.world_to_local(wo_w);
         let wi = self.world_to_local(wi_w);
 
         self.bxdfs.iter().fold(Spectrum::from(0.0), |f, bxdf| {
-            if bxdf.matches_flags(flags) {
+            if is_component_of(bxdf.as_ref(), in_flags) && bxdf.matches_flags(side) {
                 f + bxdf.f(&wo, &wi)
             } else {
                 f
@@ -148,9 +340,94 @@ impl BSDF {
         })
     }
 
-    pub fn sample_f(&self, vo: &Vector, sample: BSDFSample,
-                    bxdf_type: BxDFType) -> (Vector, f32, Spectrum) {
-        unimplemented!()
+    pub fn sample_f(&self, wo_w: &Vector, sample: BSDFSample,
+                    flags: BxDFType) -> (Vector, f32, Spectrum) {
+        let (wi, pdf, f, _) = self.sample_f_with_type(wo_w, sample, flags);
+        (wi, pdf, f)
+    }
+
+    // Same as sample_f, but also returns the type of the BxDF that was
+    // sampled, so that paths know what kind of bounce they took.
+    pub fn sample_f_with_type(&self, wo_w: &Vector, sample: BSDFSample, flags: BxDFType)
+                              -> (Vector, f32, Spectrum, BxDFType) {
+        let no_sample = (Vector::new(), 0.0, Spectrum::from(0.0), BxDFType::empty());
+
+        // Choose which BxDF to sample
+        let matching_comps = self.num_components_matching(flags);
+        if matching_comps == 0 {
+            return no_sample;
+        }
+
+        let which = ((sample.u_component * (matching_comps as f32)) as usize)
+            .min(matching_comps - 1);
+        let bxdf = self.bxdfs.iter()
+            .filter(|b| is_component_of(b.as_ref(), flags))
+            .nth(which).unwrap();
+
+        // Sample chosen BxDF
+        let wo = self.world_to_local(wo_w.clone());
+        let (wi, mut pdf, mut f) = bxdf.sample_f(&wo, sample.u_dir.0, sample.u_dir.1);
+        if pdf == 0.0 {
+            return no_sample;
+        }
+        let wi_w = self.local_to_world(wi.clone());
+
+        // Compute overall PDF and BSDF value with all matching BxDFs. Specular
+        // components are delta distributions, so their values can't be
+        // combined with the other components.
+        if !bxdf.matches_flags(BxDFType::BSDF_SPECULAR) && matching_comps > 1 {
+            pdf = self.bxdfs.iter()
+                .filter(|b| is_component_of(b.as_ref(), flags))
+                .fold(0.0, |acc, b| acc + b.pdf(&wo, &wi));
+            f = self.f(wo_w.clone(), wi_w.clone(), flags);
+        }
+
+        if matching_comps > 1 {
+            pdf /= matching_comps as f32;
+        }
+
+        (wi_w, pdf, f, bxdf_type(bxdf.as_ref()))
+    }
+
+    // The reflectance of the matching components in the direction wo_w,
+    // estimated with sqrt_samples^2 stratified samples
+    pub fn rho_hd(&self, wo_w: &Vector, rng: &mut RNG, flags: BxDFType,
+                  sqrt_samples: usize) -> Spectrum {
+        let mut samples = vec![0.0; 2 * sqrt_samples * sqrt_samples];
+        stratified_sample_2d(&mut samples, sqrt_samples, sqrt_samples, rng, true);
+
+        let wo = self.world_to_local(wo_w.clone());
+        self.bxdfs.iter()
+            .filter(|b| is_component_of(b.as_ref(), flags))
+            .map(|b| b.rho_hd(&wo, &samples))
+            .sum()
+    }
+
+    // The reflectance of the matching components over every direction
+    pub fn rho_hh(&self, rng: &mut RNG, flags: BxDFType, sqrt_samples: usize) -> Spectrum {
+        let mut samples1 = vec![0.0; 2 * sqrt_samples * sqrt_samples];
+        let mut samples2 = vec![0.0; 2 * sqrt_samples * sqrt_samples];
+        stratified_sample_2d(&mut samples1, sqrt_samples, sqrt_samples, rng, true);
+        stratified_sample_2d(&mut samples2, sqrt_samples, sqrt_samples, rng, true);
+
+        self.bxdfs.iter()
+            .filter(|b| is_component_of(b.as_ref(), flags))
+            .map(|b| b.rho_hh(&samples1, &samples2))
+            .sum()
+    }
+
+    pub fn pdf(&self, wo_w: &Vector, wi_w: &Vector, flags: BxDFType) -> f32 {
+        let wo = self.world_to_local(wo_w.clone());
+        let wi = self.world_to_local(wi_w.clone());
+        let (pdf, matching_comps) = self.bxdfs.iter()
+            .filter(|b| is_component_of(b.as_ref(), flags))
+            .fold((0.0, 0), |(pdf, n), b| (pdf + b.pdf(&wo, &wi), n + 1));
+
+        if matching_comps > 0 {
+            pdf / (matching_comps as f32)
+        } else {
+            0.0
+        }
     }
 }
 
@@ -169,8 +446,16 @@ fn other_hemi(v: &Vector) -> Vector {
 
 impl<T: BxDF> BxDF for BRDFtoBTDF<T> {
     fn matches_flags(&self, ty: BxDFType) -> bool {
+        // Swap reflection and transmission flags for the underlying BRDF
         let flags = BxDFType::BSDF_REFLECTION | BxDFType::BSDF_TRANSMISSION;
-        self.brdf.matches_flags(ty ^ flags)
+        let mut brdf_ty = ty - flags;
+        if ty.contains(BxDFType::BSDF_REFLECTION) {
+            brdf_ty |= BxDFType::BSDF_TRANSMISSION;
+        }
+        if ty.contains(BxDFType::BSDF_TRANSMISSION) {
+            brdf_ty |= BxDFType::BSDF_REFLECTION;
+        }
+        self.brdf.matches_flags(brdf_ty)
     }
 
     fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
@@ -183,13 +468,75 @@ impl<T: BxDF> BxDF for BRDFtoBTDF<T> {
         (other_hemi(&wi), pdf, v)
     }
 
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        self.brdf.pdf(wo, &other_hemi(wi))
+    }
+
     fn rho_hd(&self, v: &Vector, samples: &[f32]) -> Spectrum {
-        self.brdf.rho_hd(v, samples)
+        self.brdf.rho_hd(&other_hemi(v), samples)
     }
 
     fn rho_hh(&self, samples1: &[f32], samples2: &[f32]) -> Spectrum {
         self.brdf.rho_hh(samples1, samples2)
     }
+
+    fn set_eta(&mut self, eta: f32) { self.brdf.set_eta(eta) }
+}
+
+// Makes a reflection-only BxDF look the same from both sides of a surface
+// by mirroring directions below the surface into the upper hemisphere.
+#[derive(Debug, Clone)]
+pub struct TwoSided<T: BxDF> {
+    bxdf: T
+}
+
+impl<T: BxDF> TwoSided<T> {
+    pub fn new(input: T) -> TwoSided<T> { TwoSided { bxdf: input } }
+}
+
+fn upper_hemi(wo: &Vector, wi: &Vector) -> (Vector, Vector) {
+    if wo.z < 0.0 {
+        (other_hemi(wo), other_hemi(wi))
+    } else {
+        (wo.clone(), wi.clone())
+    }
+}
+
+impl<T: BxDF> BxDF for TwoSided<T> {
+    fn matches_flags(&self, ty: BxDFType) -> bool {
+        self.bxdf.matches_flags(ty)
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        let (wo, wi) = upper_hemi(wo, wi);
+        self.bxdf.f(&wo, &wi)
+    }
+
+    fn sample_f(&self, wo: &Vector, u1: f32,
+                u2: f32) -> (Vector, f32, Spectrum) {
+        if wo.z < 0.0 {
+            let (wi, pdf, v) = self.bxdf.sample_f(&other_hemi(wo), u1, u2);
+            (other_hemi(&wi), pdf, v)
+        } else {
+            self.bxdf.sample_f(wo, u1, u2)
+        }
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        let (wo, wi) = upper_hemi(wo, wi);
+        self.bxdf.pdf(&wo, &wi)
+    }
+
+    fn rho_hd(&self, v: &Vector, samples: &[f32]) -> Spectrum {
+        let (v, _) = upper_hemi(v, v);
+        self.bxdf.rho_hd(&v, samples)
+    }
+
+    fn rho_hh(&self, samples1: &[f32], samples2: &[f32]) -> Spectrum {
+        self.bxdf.rho_hh(samples1, samples2)
+    }
+
+    fn set_eta(&mut self, eta: f32) { self.bxdf.set_eta(eta) }
 }
 
 #[derive(Debug)]
@@ -222,6 +569,10 @@ impl BxDF for ScaledBxDF {
         (wi, pdf, self.scale * v)
     }
 
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        self.bxdf.pdf(wo, wi)
+    }
+
     fn rho_hd(&self, v: &Vector, samples: &[f32]) -> Spectrum {
         self.bxdf.rho_hd(v, samples) * self.scale
     }
@@ -229,4 +580,169 @@ impl BxDF for ScaledBxDF {
     fn rho_hh(&self, samples1: &[f32], samples2: &[f32]) -> Spectrum {
         self.bxdf.rho_hh(samples1, samples2) * self.scale
     }
+
+    fn set_eta(&mut self, eta: f32) { self.bxdf.set_eta(eta) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsdf::lambertian::Lambertian;
+    use crate::geometry::point::Point;
+
+    fn make_bsdf() -> BSDF {
+        let dg = DifferentialGeometry::new_with(
+            Point::new(), Vector::new_with(1.0, 0.0, 0.0),
+            Vector::new_with(0.0, 1.0, 0.0), Normal::new(), Normal::new(),
+            0.0, 0.0, None);
+        BSDF::new(dg, Normal::new_with(0.0, 0.0, 1.0))
+    }
+
+    #[test]
+    fn it_only_uses_components_whose_flags_were_requested() {
+        let mut bsdf = make_bsdf();
+        bsdf.add_bxdf(Lambertian::new(Spectrum::from(1.0)));
+        bsdf.add_bxdf(BRDFtoBTDF::new(Lambertian::new(Spectrum::from(1.0))));
+
+        assert_eq!(bsdf.num_components_matching(BxDFType::BSDF_ALL), 2);
+        assert_eq!(bsdf.num_components_matching(BxDFType::BSDF_ALL_REFLECTION), 1);
+        assert_eq!(bsdf.num_components_matching(BxDFType::BSDF_ALL_TRANSMISSION), 1);
+        assert_eq!(bsdf.num_components_matching(BxDFType::BSDF_REFLECTION), 0);
+        assert_eq!(bsdf.num_components_matching(
+            BxDFType::BSDF_ALL - BxDFType::BSDF_DIFFUSE), 0);
+    }
+
+    #[test]
+    fn it_reports_the_type_of_the_sampled_component() {
+        use crate::bsdf::fresnel::Fresnel;
+        use crate::bsdf::specular::SpecularReflection;
+
+        let mut bsdf = make_bsdf();
+        bsdf.add_bxdf(Lambertian::new(Spectrum::from(1.0)));
+        bsdf.add_bxdf(SpecularReflection::new(Spectrum::from(1.0),
+                                              Fresnel::dielectric(1.0, 1.5)));
+
+        let wo = Vector::new_with(0.0, 0.0, 1.0);
+        let sample = |u_component| BSDFSample { u_dir: (0.3, 0.6), u_component };
+        let (.., ty) = bsdf.sample_f_with_type(&wo, sample(0.25), BxDFType::BSDF_ALL);
+        assert_eq!(ty, BxDFType::BSDF_REFLECTION | BxDFType::BSDF_DIFFUSE);
+        let (.., ty) = bsdf.sample_f_with_type(&wo, sample(0.75), BxDFType::BSDF_ALL);
+        assert_eq!(ty, BxDFType::BSDF_REFLECTION | BxDFType::BSDF_SPECULAR);
+        let (.., ty) = bsdf.sample_f_with_type(&wo, sample(0.5), BxDFType::BSDF_TRANSMISSION);
+        assert!(ty.is_empty());
+    }
+
+    #[test]
+    fn it_samples_directions_consistent_with_its_pdf() {
+        let mut bsdf = make_bsdf();
+        bsdf.add_bxdf(Lambertian::new(Spectrum::from(0.5)));
+
+        let wo = Vector::new_with(0.0, 0.6, 0.8);
+        let mut rng = RNG::new(0);
+        for _ in 0..100 {
+            let (wi, pdf, f) = bsdf.sample_f(&wo, BSDFSample::new(&mut rng),
+                                             BxDFType::BSDF_ALL);
+            assert!(wi.z >= 0.0);
+            assert!((pdf - bsdf.pdf(&wo, &wi, BxDFType::BSDF_ALL)).abs() < 1e-5);
+            assert!((f.y() - 0.5 / ::std::f32::consts::PI).abs() < 1e-5);
+        }
+
+        // Nothing to sample if no components match
+        let (_, pdf, f) = bsdf.sample_f(&wo, BSDFSample::new(&mut rng),
+                                        BxDFType::BSDF_ALL_TRANSMISSION);
+        assert_eq!(pdf, 0.0);
+        assert!(f.is_black());
+    }
+
+    #[test]
+    fn it_can_make_brdfs_two_sided() {
+        use crate::bsdf::fresnel::Fresnel;
+        use crate::bsdf::microfacet::Microfacet;
+        use crate::bsdf::microfacet::MicrofacetDistribution;
+
+        let brdf = TwoSided::new(Microfacet::new(
+            Spectrum::from(1.0), Fresnel::dielectric(1.5, 1.0),
+            MicrofacetDistribution::blinn(10.0)));
+
+        let wo = Vector::new_with(0.0, 0.6, 0.8);
+        let wi = Vector::new_with(0.6, 0.0, 0.8);
+        let f = brdf.f(&wo, &wi);
+        assert!(!f.is_black());
+        assert_eq!(brdf.f(&other_hemi(&wo), &other_hemi(&wi)), f);
+        assert_eq!(brdf.pdf(&other_hemi(&wo), &other_hemi(&wi)), brdf.pdf(&wo, &wi));
+
+        // Samples stay on the same side as wo
+        let (wi, pdf, _) = brdf.sample_f(&other_hemi(&wo), 0.3, 0.7);
+        assert!(wi.z < 0.0);
+        assert!((pdf - brdf.pdf(&other_hemi(&wo), &wi)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn it_refracts_consistently_when_shading_normals_face_away() {
+        use crate::bsdf::specular::SpecularTransmission;
+
+        // Shading normal points down, but the outside is above the surface
+        let dg = DifferentialGeometry::new_with(
+            Point::new(), Vector::new_with(0.0, 1.0, 0.0),
+            Vector::new_with(1.0, 0.0, 0.0), Normal::new(), Normal::new(),
+            0.0, 0.0, None);
+        assert!(dg.nn.z < 0.0);
+
+        let mut bsdf = BSDF::new_with_eta(dg, Normal::new_with(0.0, 0.0, 1.0), 1.5);
+        bsdf.add_bxdf(SpecularTransmission::new(Spectrum::from(1.0), 1.0, 1.5));
+
+        let flags = BxDFType::BSDF_TRANSMISSION | BxDFType::BSDF_SPECULAR;
+        let mut rng = RNG::new(0);
+
+        // Entering the glass bends the ray towards the normal...
+        let wo = Vector::new_with(0.6, 0.0, 0.8);
+        assert!(bsdf.is_entering(&wo));
+        assert_eq!(bsdf.relative_eta(&wo), 1.5);
+        let (wi, pdf, f) = bsdf.sample_f(&wo, BSDFSample::new(&mut rng), flags);
+        assert!(pdf > 0.0 && !f.is_black());
+        assert!(wi.z < 0.0);
+        assert!((wi.x + 0.6 / 1.5).abs() < 1e-5);
+
+        // ... and leaving it bends the ray away from the normal
+        let wo = Vector::new_with(0.4, 0.0, -(1.0f32 - 0.16).sqrt());
+        assert!(!bsdf.is_entering(&wo));
+        assert_eq!(bsdf.relative_eta(&wo), 1.0 / 1.5);
+        let (wi, _, _) = bsdf.sample_f(&wo, BSDFSample::new(&mut rng), flags);
+        assert!(wi.z > 0.0);
+        assert!((wi.x + 0.4 * 1.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn it_estimates_reflectance_by_sampling() {
+        use crate::bsdf::orennayar::OrenNayar;
+        use crate::bsdf::specular::SpecularReflection;
+        use crate::bsdf::fresnel::Fresnel;
+
+        // Without any roughness, Oren-Nayar is Lambertian
+        let bxdf = OrenNayar::new(Spectrum::from(0.5), 0.0);
+        let mut rng = RNG::new(0);
+        let mut samples1 = vec![0.0; 2 * 256];
+        let mut samples2 = vec![0.0; 2 * 256];
+        stratified_sample_2d(&mut samples1, 16, 16, &mut rng, true);
+        stratified_sample_2d(&mut samples2, 16, 16, &mut rng, true);
+
+        let wo = Vector::new_with(0.0, 0.6, 0.8);
+        assert!((bxdf.rho_hd(&wo, &samples1).y() - 0.5).abs() < 1e-2);
+        assert!((bxdf.rho_hh(&samples1, &samples2).y() - 0.5).abs() < 2e-2);
+
+        // Perfect mirrors reflect everything in their one direction
+        let mirror = SpecularReflection::new(Spectrum::from(1.0), Fresnel::noop());
+        assert!((mirror.rho_hd(&wo, &samples1).y() - 1.0).abs() < 1e-4);
+
+        // Only the matching components of a BSDF count
+        let mut bsdf = make_bsdf();
+        bsdf.add_bxdf(bxdf);
+        bsdf.add_bxdf(BRDFtoBTDF::new(Lambertian::new(Spectrum::from(0.25))));
+        let rho = |flags| bsdf.rho_hh(&mut RNG::new(1), flags, 16).y();
+        assert!((rho(BxDFType::BSDF_ALL_REFLECTION) - 0.5).abs() < 2e-2);
+        assert!((rho(BxDFType::BSDF_ALL_TRANSMISSION) - 0.25).abs() < 1e-4);
+        assert!((rho(BxDFType::BSDF_ALL) - 0.75).abs() < 2e-2);
+        let rho_hd = bsdf.rho_hd(&wo, &mut rng, BxDFType::BSDF_ALL_REFLECTION, 16).y();
+        assert!((rho_hd - 0.5).abs() < 1e-2);
+    }
 }
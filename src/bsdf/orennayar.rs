@@ -1,9 +1,9 @@
-use bsdf;
-use bsdf::BxDF;
-use bsdf::utils::*;
-use geometry::vector::Vector;
-use spectrum::Spectrum;
-use utils::Degrees;
+use crate::bsdf;
+use crate::bsdf::BxDF;
+use crate::bsdf::utils::*;
+use crate::geometry::vector::Vector;
+use crate::spectrum::Spectrum;
+use crate::utils::Degrees;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct OrenNayar {
@@ -58,8 +58,4 @@ impl BxDF for OrenNayar {
         let invpi = 1.0 / ::std::f32::consts::PI;
         self.r * invpi * (self.a + self.b * maxcos * sinalpha * tanbeta)
     }
-
-    fn sample_f(&self, _: &Vector, _: f32, _: f32) -> (Vector, f32, Spectrum) {
-        unimplemented!()
-    }
 }
@@ -1,9 +1,9 @@
-use bsdf::fresnel::Fresnel;
-use bsdf;
-use bsdf::BxDF;
-use bsdf::utils::*;
-use geometry::vector::Vector;
-use spectrum::Spectrum;
+use crate::bsdf::fresnel::Fresnel;
+use crate::bsdf;
+use crate::bsdf::BxDF;
+use crate::bsdf::utils::*;
+use crate::geometry::vector::Vector;
+use crate::spectrum::Spectrum;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct SpecularReflection {
@@ -38,6 +38,14 @@ impl BxDF for SpecularReflection {
         let v = self.fresnel.evaluate(cos_theta(&wo));
         (wi.clone(), 1.0, v * self.r / abs_cos_theta(&wi))
     }
+
+    fn pdf(&self, _: &Vector, _: &Vector) -> f32 { 0.0 }
+
+    fn set_eta(&mut self, eta: f32) {
+        if let Fresnel::Dielectric { ref mut eta_t, .. } = self.fresnel {
+            *eta_t = eta;
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -49,7 +57,7 @@ pub struct SpecularTransmission {
 }
 
 impl SpecularTransmission {
-    fn new(_t: Spectrum, _etai: f32, _etat: f32) -> SpecularTransmission {
+    pub fn new(_t: Spectrum, _etai: f32, _etat: f32) -> SpecularTransmission {
         SpecularTransmission {
             t: _t,
             etai: _etai,
@@ -69,6 +77,8 @@ impl BxDF for SpecularTransmission {
         Spectrum::from(0f32)
     }
 
+    fn pdf(&self, _: &Vector, _: &Vector) -> f32 { 0.0 }
+
     fn sample_f(&self, wo: &Vector, u1: f32,
                 u2: f32) -> (Vector, f32, Spectrum) {
         let ct = cos_theta(&wo);
@@ -96,21 +106,27 @@ impl BxDF for SpecularTransmission {
 
         let wi = Vector::new_with(sint_over_sini * -wo.x, sint_over_sini * -wo.y, cost);
 
+        // Whatever isn't reflected is transmitted
         let pdf = 1f32;
         let f = self.fresnel.evaluate(ct);
-        let v = (et * et) / (ei * ei) * (Spectrum::from(1f32) - self.t);
+        let v = (Spectrum::from(1f32) - f) * self.t;
         (wi.clone(), pdf, v / abs_cos_theta(&wi))
     }
+
+    fn set_eta(&mut self, eta: f32) {
+        self.etat = eta;
+        self.fresnel = Fresnel::dielectric(self.etai, eta);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bsdf;
-    use bsdf::BxDF;
-    use bsdf::fresnel::Fresnel;
-    use geometry::vector::Vector;
-    use spectrum::Spectrum;
+    use crate::bsdf;
+    use crate::bsdf::BxDF;
+    use crate::bsdf::fresnel::Fresnel;
+    use crate::geometry::vector::Vector;
+    use crate::spectrum::Spectrum;
 
     #[test]
     fn spec_refl_can_be_created() {
@@ -210,4 +226,15 @@ mod tests {
                                         0.0, 0.0);
         assert!((wi4 - Vector::new_with(-0.5, 0.0, 3f32.sqrt() / 2.0)).length_squared() < 1e-6);
     }
+
+    #[test]
+    fn spec_trans_transmits_what_isnt_reflected() {
+        let btdf = SpecularTransmission::new(Spectrum::from(0.5f32), 1.0, 1.5);
+        let (wi, pdf, f) = btdf.sample_f(&Vector::new_with(0.0, 0.0, 1.0), 0.0, 0.0);
+        assert_eq!(wi, Vector::new_with(0.0, 0.0, -1.0));
+        assert_eq!(pdf, 1.0);
+
+        // At normal incidence 4% of the light is reflected
+        assert!((f.y() - 0.96 * 0.5).abs() < 1e-4);
+    }
 }
@@ -1,4 +1,4 @@
-use geometry::vector::Vector;
+use crate::geometry::vector::Vector;
 
 use std::f32;
 
@@ -6,6 +6,7 @@ pub fn cos_theta(v: &Vector) -> f32 { v.z }
 pub fn abs_cos_theta(v: &Vector) -> f32 { v.z.abs() }
 pub fn sin_theta2(v: &Vector) -> f32 { 0f32.max(1.0 - v.z*v.z) }
 pub fn sin_theta(v: &Vector) -> f32 { sin_theta2(v).sqrt() }
+pub fn same_hemisphere(w: &Vector, wp: &Vector) -> bool { w.z * wp.z > 0.0 }
 
 pub fn cos_phi(v: &Vector) -> f32 {
     let vx = v.x;
@@ -0,0 +1,228 @@
+use std::path::Path;
+use std::time::Duration;
+
+use image::RgbImage;
+use image::imageops::FilterType;
+
+use crate::camera::film::Film;
+
+// Each thumbnail is labelled with two lines of text beneath it: the name
+// of the image and how long it took to render.
+const PADDING: u32 = 4;
+const GLYPH_SCALE: u32 = 2;
+const GLYPH_ADVANCE: u32 = 4 * GLYPH_SCALE;
+const LINE_HEIGHT: u32 = 6 * GLYPH_SCALE;
+const BACKGROUND: [u8; 3] = [32, 32, 32];
+const TEXT: [u8; 3] = [255, 255, 255];
+
+// A tiny 3x5 pixel font, one row per entry with the leftmost pixel in the
+// highest bit. Labels are upper cased before they're drawn.
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        ' ' => [0b000; 5],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010]
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Thumbnail {
+    label: String,
+    time: Duration,
+    img: RgbImage
+}
+
+// Collects downscaled copies of rendered images, e.g. from a batch of
+// scene files or the frames of an animation, and lays them out in a grid
+// so that they can be checked at a glance.
+#[derive(Debug, Clone)]
+pub struct ContactSheet {
+    thumb_size: u32,
+    columns: u32,
+    thumbs: Vec<Thumbnail>
+}
+
+impl ContactSheet {
+    // Thumbnails are scaled to fit in a square thumb_size pixels wide
+    pub fn new(thumb_size: u32, columns: u32) -> ContactSheet {
+        assert!(thumb_size > 0 && columns > 0);
+        ContactSheet { thumb_size, columns, thumbs: Vec::new() }
+    }
+
+    pub fn num_images(&self) -> usize { self.thumbs.len() }
+
+    pub fn add_image(&mut self, label: &str, img: &RgbImage, time: Duration) {
+        let scale = self.thumb_size as f32 / img.width().max(img.height()) as f32;
+        let w = ((img.width() as f32 * scale).round() as u32).clamp(1, self.thumb_size);
+        let h = ((img.height() as f32 * scale).round() as u32).clamp(1, self.thumb_size);
+        let img = image::imageops::resize(img, w, h, FilterType::Triangle);
+        self.thumbs.push(Thumbnail { label: String::from(label), time, img });
+    }
+
+    pub fn add_film(&mut self, label: &str, film: &Film, time: Duration) {
+        let rgb = film.get_rgba8(1.0).chunks(4).flat_map(|p| p[0..3].to_vec()).collect();
+        let img = RgbImage::from_raw(film.x_res() as u32, film.y_res() as u32, rgb).unwrap();
+        self.add_image(label, &img, time);
+    }
+
+    // Adds an image that has already been written, labelled with its file
+    // name. Returns false with a warning if it couldn't be read.
+    pub fn add_image_file(&mut self, filename: &str, time: Duration) -> bool {
+        match image::open(filename) {
+            Ok(img) => {
+                let label = Path::new(filename).file_name()
+                    .map_or(filename, |f| f.to_str().unwrap_or(filename));
+                self.add_image(label, &img.to_rgb8(), time);
+                true
+            },
+            Err(e) => {
+                println!("WARNING: Unable to add \"{}\" to the contact sheet: {}", filename, e);
+                false
+            }
+        }
+    }
+
+    fn cell_size(&self) -> (u32, u32) {
+        (self.thumb_size + 2 * PADDING,
+         self.thumb_size + 2 * PADDING + 2 * LINE_HEIGHT)
+    }
+
+    // Draws as much of the label as fits in the given width
+    fn draw_text(img: &mut RgbImage, text: &str, x: u32, y: u32, width: u32) {
+        let max_chars = (width / GLYPH_ADVANCE) as usize;
+        for (i, c) in text.to_ascii_uppercase().chars().take(max_chars).enumerate() {
+            let gx = x + (i as u32) * GLYPH_ADVANCE;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) == 0 {
+                        continue;
+                    }
+
+                    for sy in 0..GLYPH_SCALE {
+                        for sx in 0..GLYPH_SCALE {
+                            img.put_pixel(gx + col * GLYPH_SCALE + sx,
+                                          y + (row as u32) * GLYPH_SCALE + sy,
+                                          image::Rgb(TEXT));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn image(&self) -> RgbImage {
+        let (cell_w, cell_h) = self.cell_size();
+        let num = self.thumbs.len() as u32;
+        let cols = self.columns.min(num.max(1));
+        let rows = num.div_ceil(cols).max(1);
+        let mut sheet = RgbImage::from_pixel(cols * cell_w, rows * cell_h,
+                                             image::Rgb(BACKGROUND));
+
+        for (i, thumb) in self.thumbs.iter().enumerate() {
+            let (x0, y0) = ((i as u32 % cols) * cell_w, (i as u32 / cols) * cell_h);
+
+            // Center the thumbnail in the square above its labels
+            let tx = x0 + PADDING + (self.thumb_size - thumb.img.width()) / 2;
+            let ty = y0 + PADDING + (self.thumb_size - thumb.img.height()) / 2;
+            image::imageops::replace(&mut sheet, &thumb.img, tx as i64, ty as i64);
+
+            let text_y = y0 + PADDING + self.thumb_size + (LINE_HEIGHT - 5 * GLYPH_SCALE);
+            let time = format!("{:.2}s", thumb.time.as_secs_f32());
+            ContactSheet::draw_text(&mut sheet, &thumb.label, x0 + PADDING, text_y,
+                                    self.thumb_size);
+            ContactSheet::draw_text(&mut sheet, &time, x0 + PADDING, text_y + LINE_HEIGHT,
+                                    self.thumb_size);
+        }
+
+        sheet
+    }
+
+    pub fn write(&self, filename: &str) {
+        if let Err(e) = self.image().save(filename) {
+            println!("WARNING: Unable to write contact sheet \"{}\": {}", filename, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+
+    #[test]
+    fn it_lays_out_thumbnails_in_a_grid() {
+        let mut sheet = ContactSheet::new(32, 2);
+        let red = RgbImage::from_pixel(64, 32, image::Rgb([255, 0, 0]));
+        sheet.add_image("red.png", &red, Duration::from_millis(1500));
+        let film = Film::image(16, 16, Filter::mean(0.5, 0.5), [0.0, 1.0, 0.0, 1.0],
+                               String::from(""), false);
+        sheet.add_film("black", &film, Duration::from_secs(2));
+        sheet.add_image("blue.png", &RgbImage::from_pixel(8, 8, image::Rgb([0, 0, 255])),
+                        Duration::from_secs(0));
+        assert_eq!(sheet.num_images(), 3);
+
+        // Three thumbnails in two columns take up two rows
+        let img = sheet.image();
+        let (cell_w, cell_h) = (32 + 2 * PADDING, 32 + 2 * PADDING + 2 * LINE_HEIGHT);
+        assert_eq!((img.width(), img.height()), (2 * cell_w, 2 * cell_h));
+
+        // The wide image keeps its aspect ratio, leaving the background
+        // above and below it
+        let center = PADDING + 16;
+        assert_eq!(img.get_pixel(center, center).0, [255, 0, 0]);
+        assert_eq!(img.get_pixel(center, PADDING + 2).0, BACKGROUND);
+        assert_eq!(img.get_pixel(cell_w + center, center).0, [0, 0, 0]);
+        assert_eq!(img.get_pixel(center, cell_h + center).0, [0, 0, 255]);
+        assert_eq!(img.get_pixel(cell_w + center, cell_h + center).0, BACKGROUND);
+
+        // Each thumbnail is labelled with its name and time
+        let label_rows = (PADDING + 32)..cell_h;
+        let text_in_cell = |x0: u32, y0: u32| label_rows.clone().any(|y| {
+            (x0..(x0 + cell_w)).any(|x| img.get_pixel(x, y0 + y).0 == TEXT)
+        });
+        assert!(text_in_cell(0, 0));
+        assert!(text_in_cell(cell_w, 0));
+        assert!(text_in_cell(0, cell_h));
+        assert!(!text_in_cell(cell_w, cell_h));
+    }
+}
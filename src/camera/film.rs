@@ -1,17 +1,21 @@
-extern crate image;
+#[cfg(feature = "image")]
+use image::ImageBuffer;
 
-use self::image::ImageBuffer;
+use crate::camera::CameraSample;
+use crate::filter::Filter;
+use crate::spectrum::Spectrum;
 
-use camera::CameraSample;
-use filter::Filter;
-use spectrum::Spectrum;
-
-use spectrum::xyz_to_rgb;
-use utils::get_crop_window;
+use crate::spectrum::xyz_to_rgb;
 
 const FILTER_TABLE_DIM: usize = 16;
 const FILTER_TABLE_SIZE: usize = FILTER_TABLE_DIM * FILTER_TABLE_DIM;
 
+// Gamma corrects a linear RGB value and quantizes it to eight bits
+fn to_byte(p: f32) -> u8 {
+    (255.0 * p.powf(1.0 / 2.2) + 0.5).clamp(0.0, 255.0) as u8
+}
+
+#[cfg(feature = "image")]
 fn write_img(filename: &String, rgb: &[f32],
              x_pixel_count: usize, y_pixel_count: usize) {
     assert!(rgb.len() == x_pixel_count * y_pixel_count * 3);
@@ -19,9 +23,6 @@ fn write_img(filename: &String, rgb: &[f32],
     let img = ImageBuffer::from_fn(
         x_pixel_count as u32, y_pixel_count as u32, |x, y| {
         let idx = 3 * ((y as usize) * x_pixel_count + (x as usize));
-        let to_byte = |p: f32| {
-            (255.0 * p.powf(1.0 / 2.2) + 0.5).clamp(0.0, 255.0) as u8
-        };
         let r = to_byte(rgb[idx + 0]);
         let g = to_byte(rgb[idx + 1]);
         let b = to_byte(rgb[idx + 2]);
@@ -32,6 +33,13 @@ fn write_img(filename: &String, rgb: &[f32],
     img.save(filename);
 }
 
+// Builds without the image crate have nowhere to write to, and get their
+// pixels from Film::get_rgba8 instead
+#[cfg(not(feature = "image"))]
+fn write_img(filename: &String, _: &[f32], _: usize, _: usize) {
+    println!("WARNING: Built without image support. Not writing {}.", filename);
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Pixel {
     xyz: [f32; 3],
@@ -51,6 +59,56 @@ impl Pixel {
     }
 }
 
+// Returns the pixels of the named light group, adding the group if it
+// doesn't exist yet
+fn group_pixels<'a>(groups: &'a mut Vec<(String, Vec<Pixel>)>, name: &str,
+                    num_pixels: usize) -> &'a mut Vec<Pixel> {
+    let idx = match groups.iter().position(|(g, _)| g == name) {
+        Some(idx) => idx,
+        None => {
+            groups.push((String::from(name), vec![Pixel::new(); num_pixels]));
+            groups.len() - 1
+        }
+    };
+    &mut groups[idx].1
+}
+
+// Converts accumulated pixels to their final linear RGB values
+fn pixels_to_rgb(pixels: &[Pixel], splat_scale: f32) -> Vec<f32> {
+    let mut rgb: Vec<f32> = vec![0.0; 3 * pixels.len()];
+    for (offset, pixel) in pixels.iter().enumerate() {
+        // Convert pixel XYZ color to RGB
+        let mut prgb = xyz_to_rgb(pixel.xyz.clone());
+
+        // Normalize pixel with weight sum
+        let weight_sum = pixel.weight_sum;
+        if weight_sum != 0.0 {
+            let inv_wt = 1.0 / weight_sum;
+            prgb[0] = (prgb[0] * inv_wt).max(0.0);
+            prgb[1] = (prgb[1] * inv_wt).max(0.0);
+            prgb[2] = (prgb[2] * inv_wt).max(0.0);
+        }
+
+        // Add splat value at pixel
+        let splat_rgb = xyz_to_rgb(pixel.splat_xyz.clone());
+        rgb[3 * offset + 0] = prgb[0] + splat_rgb[0] * splat_scale;
+        rgb[3 * offset + 1] = prgb[1] + splat_rgb[1] * splat_scale;
+        rgb[3 * offset + 2] = prgb[2] + splat_rgb[2] * splat_scale;
+    }
+    rgb
+}
+
+// Inserts the group name before the extension, e.g. "out.png" and "key"
+// gives "out.key.png"
+fn group_filename(filename: &str, group: &str) -> String {
+    let path = ::std::path::Path::new(filename);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => path.with_extension(format!("{}.{}", group, ext))
+            .to_string_lossy().into_owned(),
+        None => format!("{}.{}", filename, group)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum FilmTy {
     Image {
@@ -62,7 +120,11 @@ pub enum FilmTy {
         x_pixel_count: usize,
         y_pixel_count: usize,
         pixels: Vec<Pixel>,  // !SPEED! Use a z-ordering here
-        filter_table: Vec<f32>
+        filter_table: Vec<f32>,
+
+        // Pixels for each light group, in the order the groups first
+        // received a sample. See add_group_sample.
+        light_groups: Vec<(String, Vec<Pixel>)>
     },
 }
 
@@ -116,38 +178,57 @@ impl Film {
                 // Allocate film image storage
                 pixels: vec![Pixel::new(); x_count * y_count],
 
-                filter_table: ft
+                filter_table: ft,
+                light_groups: Vec::new()
             }
         }
     }
 
-    pub fn get_sub_film(&self, num: usize, count: usize) -> Film {
-        let aspect = (self.x_res as f32) / (self.y_res as f32);
-        let (x0, x1, y0, y1) = get_crop_window(num, count, aspect);
-
+    // Returns an empty film that covers every pixel that a sample generated
+    // within the half-open image space window [x0, x1) X [y0, y1) can
+    // contribute to, clamped to the extent of this film. Render tasks
+    // accumulate into these sub-films and merge them back with add_sub_film.
+    pub fn get_sub_film(&self, x0: i32, x1: i32, y0: i32, y1: i32) -> Film {
         match &self.ty {
-            &FilmTy::Image { ref filter, ref crop_window, ref filename, .. } => {
-                // If this isn't our crop window then we need to rethink our
-                // design... perhaps construct a new type for subfilms?
-                let ox0 = crop_window[0];
-                let ox1 = crop_window[1];
-                let oy0 = crop_window[2];
-                let oy1 = crop_window[3];
-
-                let dx = ox1 - ox0;
-                let dy = oy1 - oy0;
-
-                let cw = [ ox0 + x0 * dx, ox0 + x1 * dx,
-                           oy0 + y0 * dy, oy0 + y1 * dy ];
-
-                Film::image(self.x_res, self.y_res, filter.clone(),
-                            cw, filename.clone(), false)
+            &FilmTy::Image { ref filter, crop_window, ref filename,
+                             ref filter_table, .. } => {
+                let (gx0, gx1, gy0, gy1) = self.get_pixel_extent();
+
+                let px0 = ((x0 as f32) - 0.5 - filter.x_width()).ceil() as i32;
+                let px1 = ((x1 as f32) - 0.5 + filter.x_width()).floor() as i32 + 1;
+                let py0 = ((y0 as f32) - 0.5 - filter.y_width()).ceil() as i32;
+                let py1 = ((y1 as f32) - 0.5 + filter.y_width()).floor() as i32 + 1;
+
+                let x_start = ::std::cmp::max(px0, gx0);
+                let x_end = ::std::cmp::max(::std::cmp::min(px1, gx1), x_start);
+                let y_start = ::std::cmp::max(py0, gy0);
+                let y_end = ::std::cmp::max(::std::cmp::min(py1, gy1), y_start);
+
+                let x_count = (x_end - x_start) as usize;
+                let y_count = (y_end - y_start) as usize;
+
+                Film {
+                    x_res: self.x_res,
+                    y_res: self.y_res,
+                    ty: FilmTy::Image {
+                        filter: filter.clone(),
+                        crop_window,
+                        filename: filename.clone(),
+                        x_pixel_start: x_start,
+                        x_pixel_count: x_count,
+                        y_pixel_start: y_start,
+                        y_pixel_count: y_count,
+                        pixels: vec![Pixel::new(); x_count * y_count],
+                        filter_table: filter_table.clone(),
+                        light_groups: Vec::new()
+                    }
+                }
             }
         }
     }
 
-    fn assign_pixels(&mut self, new_pix: &[Pixel],
-                     lx0: i32, lx1: i32, ly0: i32, ly1: i32) {
+    fn merge_pixels(&mut self, new_pix: &[Pixel], group: Option<&str>,
+                    lx0: i32, lx1: i32, ly0: i32, ly1: i32) {
         let (gx0, gx1, gy0, gy1) = self.get_pixel_extent();
 
         assert!(lx0 >= gx0);
@@ -157,21 +238,39 @@ impl Film {
         assert!(ly1 <= gy1);
 
         match &mut self.ty {
-            &mut FilmTy::Image { ref mut pixels, .. } => {
+            &mut FilmTy::Image { ref mut pixels, ref mut light_groups, .. } => {
+                let pixels = match group {
+                    Some(name) => group_pixels(light_groups, name, pixels.len()),
+                    None => pixels
+                };
+
                 let lstride = lx1 - lx0;
                 let gstride = gx1 - gx0;
-                for x in lx0..lx1 {
-                    for y in ly0..ly1 {
+
+                // Always walk the sub-film in scanline order so that the
+                // floating point sums come out the same on every run.
+                for y in ly0..ly1 {
+                    for x in lx0..lx1 {
                         let local_idx = (y - ly0) * lstride + (x - lx0);
                         let global_idx = (y - gy0) * gstride + (x - gx0);
-                        pixels[global_idx as usize] =
-                            new_pix[local_idx as usize].clone();
+                        let src = &new_pix[local_idx as usize];
+                        let dst = &mut pixels[global_idx as usize];
+                        for i in 0..3 {
+                            dst.xyz[i] += src.xyz[i];
+                            dst.splat_xyz[i] += src.splat_xyz[i];
+                        }
+                        dst.weight_sum += src.weight_sum;
                     }
                 }
             }
         }
     }
 
+    // Adds the contributions of a sub-film created with get_sub_film to this
+    // film. Sub-films may overlap where their filter footprints meet, so in
+    // order for the final image to be reproducible regardless of how many
+    // threads were used, callers must merge sub-films in a fixed order
+    // (SamplerRenderer uses task index order).
     pub fn add_sub_film(&mut self, f: Film) {
         assert_eq!(f.x_res, self.x_res);
         assert_eq!(f.y_res, self.y_res);
@@ -179,8 +278,11 @@ impl Film {
         let (lx0, lx1, ly0, ly1) = f.get_pixel_extent();
 
         match &f.ty {
-            &FilmTy::Image { ref pixels, .. } => {
-                self.assign_pixels(&pixels, lx0, lx1, ly0, ly1);
+            &FilmTy::Image { ref pixels, ref light_groups, .. } => {
+                self.merge_pixels(pixels, None, lx0, lx1, ly0, ly1);
+                for (name, group) in light_groups.iter() {
+                    self.merge_pixels(group, Some(name), lx0, lx1, ly0, ly1);
+                }
             }
         }
     }
@@ -190,10 +292,27 @@ impl Film {
 
     pub fn num_pixels(&self) -> usize { self.x_res * self.y_res }
     pub fn add_sample(&mut self, sample: &CameraSample, ls: &Spectrum) {
+        self.add_filtered_sample(None, sample, ls)
+    }
+
+    // Adds the part of a sample's radiance that came from the lights in the
+    // given group to that group's image, which is created the first time
+    // the group receives a sample. The group images are written next to the
+    // main image so that each group's lighting can be rebalanced without
+    // rendering again. Light from ungrouped lights, and the indirect light
+    // that the irradiance cache interpolates, is in the main image but not
+    // in any group's.
+    pub fn add_group_sample(&mut self, group: &str, sample: &CameraSample,
+                            ls: &Spectrum) {
+        self.add_filtered_sample(Some(group), sample, ls)
+    }
+
+    fn add_filtered_sample(&mut self, group: Option<&str>,
+                           sample: &CameraSample, ls: &Spectrum) {
         match &mut self.ty {
             &mut FilmTy::Image { ref filter, x_pixel_start, x_pixel_count,
                                  y_pixel_start, y_pixel_count, ref mut pixels,
-                                 ref filter_table, .. } => {
+                                 ref filter_table, ref mut light_groups, .. } => {
                 // Compute sample's raster extent
                 let dimage_x = sample.image_x - 0.5;
                 let dimage_y = sample.image_y - 0.5;
@@ -209,6 +328,11 @@ impl Film {
 
                 if (x1 - x0) < 0 || (y1 - y0) < 0  { return; }
 
+                let pixels = match group {
+                    Some(name) => group_pixels(light_groups, name, pixels.len()),
+                    None => pixels
+                };
+
                 // Loop over filter support and add sample to pixel arrays
                 let xyz = ls.to_xyz();
 
@@ -313,42 +437,55 @@ impl Film {
         unimplemented!()
     }
 
-    pub fn write_image(&self, splat_scale: f32) {
+    // Returns the final linear RGB value of every pixel, three floats per
+    // pixel in scanline order.
+    pub fn get_rgb(&self, splat_scale: f32) -> Vec<f32> {
         match &self.ty {
-            &FilmTy::Image { ref pixels, ref filename,
-                             x_pixel_count, y_pixel_count, .. } => {
-                // Convert image to RGB and compute final pixel values
-                let n_pix = x_pixel_count * y_pixel_count;
-                let rgb: Vec<f32> = vec![0.0; n_pix];
-
-                for y in 0..y_pixel_count {
-                    for x in 0..x_pixel_count {
-                        let offset = y * x_pixel_count + x;
-
-                        let pixel: &Pixel = &pixels[offset];
-
-                        // Convert pixel XYZ color to RGB
-                        let mut rgb = xyz_to_rgb(pixel.xyz.clone());
-
-                        // Normalize pixel with weight sum
-                        let weight_sum = pixel.weight_sum;
-                        if weight_sum != 0.0 {
-                            let inv_wt = 1.0 / weight_sum;
-                            rgb[0] = (rgb[0] * inv_wt).max(0.0);
-                            rgb[1] = (rgb[1] * inv_wt).max(0.0);
-                            rgb[2] = (rgb[2] * inv_wt).max(0.0);
-                        }
+            &FilmTy::Image { ref pixels, .. } => pixels_to_rgb(pixels, splat_scale)
+        }
+    }
 
-                        // Add splat value at pixel
-                        let splat_rgb = xyz_to_rgb(pixel.splat_xyz.clone());
-                        rgb[0] = splat_rgb[0] * splat_scale;
-                        rgb[1] = splat_rgb[1] * splat_scale;
-                        rgb[2] = splat_rgb[2] * splat_scale;
-                    }
-                }
+    // The names of the light groups that have received samples
+    pub fn light_groups(&self) -> Vec<&str> {
+        match &self.ty {
+            &FilmTy::Image { ref light_groups, .. } =>
+                light_groups.iter().map(|(name, _)| name.as_str()).collect()
+        }
+    }
+
+    // Like get_rgb, but for the image of a single light group
+    pub fn get_group_rgb(&self, group: &str, splat_scale: f32) -> Option<Vec<f32>> {
+        match &self.ty {
+            &FilmTy::Image { ref light_groups, .. } => {
+                light_groups.iter().find(|(name, _)| name == group)
+                    .map(|(_, pixels)| pixels_to_rgb(pixels, splat_scale))
+            }
+        }
+    }
+
+    // Returns the final image as gamma corrected 8-bit RGBA, which is the
+    // layout expected by e.g. an HTML canvas. Nothing touches the file
+    // system, so this works on targets without one.
+    pub fn get_rgba8(&self, splat_scale: f32) -> Vec<u8> {
+        self.get_rgb(splat_scale).chunks(3).flat_map(|p| {
+            vec![to_byte(p[0]), to_byte(p[1]), to_byte(p[2]), 255]
+        }).collect()
+    }
 
+    pub fn write_image(&self, splat_scale: f32) {
+        match &self.ty {
+            &FilmTy::Image { ref filename, x_pixel_count, y_pixel_count,
+                             ref light_groups, .. } => {
                 // Write RGB image
+                let rgb = self.get_rgb(splat_scale);
                 write_img(filename, &rgb, x_pixel_count, y_pixel_count);
+
+                // Write an image for each light group as well
+                for (name, pixels) in light_groups.iter() {
+                    let rgb = pixels_to_rgb(pixels, splat_scale);
+                    write_img(&group_filename(filename, name), &rgb,
+                              x_pixel_count, y_pixel_count);
+                }
             }
         }
     }
@@ -357,7 +494,7 @@ impl Film {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use filter::Filter;
+    use crate::filter::Filter;
 
     #[test]
     fn it_can_be_created() {
@@ -406,4 +543,134 @@ mod tests {
                                    [0.0, ot, ot, tt], String::from(""), false);
         assert_eq!(adjacent.get_pixel_extent(), (0, 48, 4, 8));
     }
+
+    #[test]
+    fn it_has_sub_films_that_cover_the_filter_footprint() {
+        let film = Film::image(142, 12, Filter::mean(3.0, 3.0),
+                               [0.0, 1.0, 0.0, 1.0], String::from(""), false);
+
+        let sub = film.get_sub_film(10, 20, 2, 6);
+        assert_eq!(sub.get_pixel_extent(), (7, 23, 0, 9));
+
+        // Sub-films never extend past the parent film
+        let corner = film.get_sub_film(-3, 5, -3, 5);
+        assert_eq!(corner.get_pixel_extent(), (0, 8, 0, 8));
+    }
+
+    #[test]
+    fn it_merges_sub_films_deterministically() {
+        let make_film = || {
+            Film::image(16, 16, Filter::triangle(2.0, 2.0),
+                        [0.0, 1.0, 0.0, 1.0], String::from(""), false)
+        };
+
+        // Two tiles whose filter footprints overlap in the middle of the image
+        let windows = [(0, 8, 0, 16), (8, 16, 0, 16)];
+        let render_tiles = || {
+            let film = make_film();
+            windows.iter().enumerate().map(|(i, &(x0, x1, y0, y1))| {
+                let mut sub = film.get_sub_film(x0, x1, y0, y1);
+                for j in 0..64 {
+                    let fx = (x0 as f32) + ((j * 7 + i) % 8) as f32 + 0.3;
+                    let fy = (y0 as f32) + ((j * 3) % 16) as f32 + 0.7;
+                    let cs = CameraSample::new(fx, fy, 0.0, 0.0, 0.0);
+                    sub.add_sample(&cs, &Spectrum::from(0.1 * (j as f32) + 0.01));
+                }
+                sub
+            }).collect::<Vec<_>>()
+        };
+
+        let mut first = make_film();
+        for sub in render_tiles() {
+            first.add_sub_film(sub);
+        }
+
+        for _ in 0..4 {
+            let mut film = make_film();
+            for sub in render_tiles() {
+                film.add_sub_film(sub);
+            }
+            assert_eq!(film, first);
+        }
+
+        // The pixels on the tile border should have received contributions
+        // from both tiles
+        let mut left_only = make_film();
+        left_only.add_sub_film(render_tiles().remove(0));
+        match (&first.ty, &left_only.ty) {
+            (&FilmTy::Image { pixels: ref all, .. },
+             &FilmTy::Image { pixels: ref left, .. }) => {
+                let border = 4 * 16 + 8;
+                assert!(all[border].weight_sum > left[border].weight_sum);
+                assert!(left[border].weight_sum > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn it_converts_to_rgba_bytes() {
+        let mut film = Film::image(4, 1, Filter::mean(0.5, 0.5),
+                                   [0.0, 1.0, 0.0, 1.0], String::from(""),
+                                   false);
+
+        // Only the first pixel receives a sample
+        let cs = CameraSample::new(0.5, 0.5, 0.0, 0.0, 0.0);
+        film.add_sample(&cs, &Spectrum::from(1.0));
+
+        let rgba = film.get_rgba8(1.0);
+        assert_eq!(rgba.len(), 4 * 4);
+        for c in rgba[0..3].iter() {
+            assert!(*c >= 254);
+        }
+        assert_eq!(&rgba[4..], &[0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255]);
+        assert_eq!(rgba[3], 255);
+
+        let rgb = film.get_rgb(1.0);
+        assert_eq!(rgb.len(), 3 * 4);
+        assert!((rgb[1] - 1.0).abs() < 1e-2);
+        assert!(rgb[3..].iter().all(|c| *c == 0.0));
+    }
+
+    #[test]
+    fn it_keeps_an_image_per_light_group() {
+        let mut film = Film::image(8, 8, Filter::mean(0.5, 0.5),
+                                   [0.0, 1.0, 0.0, 1.0], String::from("out.png"),
+                                   false);
+        assert!(film.light_groups().is_empty());
+
+        // Render two tiles, only one of which sees the "key" group
+        let mut left = film.get_sub_film(0, 4, 0, 8);
+        let cs = CameraSample::new(1.5, 1.5, 0.0, 0.0, 0.0);
+        left.add_sample(&cs, &Spectrum::from(1.0));
+        left.add_group_sample("key", &cs, &Spectrum::from(0.25));
+
+        let mut right = film.get_sub_film(4, 8, 0, 8);
+        let cs = CameraSample::new(6.5, 6.5, 0.0, 0.0, 0.0);
+        right.add_sample(&cs, &Spectrum::from(1.0));
+        right.add_group_sample("fill", &cs, &Spectrum::from(1.0));
+
+        film.add_sub_film(left);
+        film.add_sub_film(right);
+        assert_eq!(film.light_groups(), vec!["key", "fill"]);
+
+        let key = film.get_group_rgb("key", 1.0).unwrap();
+        assert_eq!(key.len(), 3 * 64);
+        assert!((key[3 * (8 + 1) + 1] - 0.25).abs() < 1e-2);
+        assert_eq!(key[3 * (6 * 8 + 6) + 1], 0.0);
+
+        let fill = film.get_group_rgb("fill", 1.0).unwrap();
+        assert!((fill[3 * (6 * 8 + 6) + 1] - 1.0).abs() < 1e-2);
+        assert!(film.get_group_rgb("rim", 1.0).is_none());
+
+        // The main image still holds everything
+        let rgb = film.get_rgb(1.0);
+        assert!((rgb[3 * (8 + 1) + 1] - 1.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn it_names_light_group_images_after_the_film() {
+        assert_eq!(group_filename("out.png", "key"), "out.key.png");
+        assert_eq!(group_filename("dir/out.exr", "fill"), "dir/out.fill.exr");
+        assert_eq!(group_filename("out", "key"), "out.key");
+    }
 }
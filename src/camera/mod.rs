@@ -1,19 +1,23 @@
 mod projective;
+#[cfg(feature = "image")]
+pub mod contact_sheet;
 pub mod film;
 
-use camera::film::Film;
-use camera::projective::Projection;
-use geometry::point::Point;
-use geometry::normal::Normalize;
-use geometry::vector::Vector;
-use ray::Ray;
-use ray::RayDifferential;
-use spectrum::Spectrum;
-use transform::animated::AnimatedTransform;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use utils::Lerp;
-use utils::Degrees;
+use crate::camera::film::Film;
+use crate::camera::projective::Projection;
+use crate::geometry::point::Point;
+use crate::geometry::normal::Normalize;
+use crate::geometry::vector::Vector;
+use crate::montecarlo::concentric_sample_disk;
+use crate::ray::Ray;
+use crate::ray::RayDifferential;
+use crate::ray::RayKind;
+use crate::spectrum::Spectrum;
+use crate::transform::animated::AnimatedTransform;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Lerp;
+use crate::utils::Degrees;
 
 #[derive(Debug, Clone)]
 pub struct CameraSample {
@@ -38,6 +42,74 @@ impl CameraSample {
     pub fn empty() -> CameraSample {
         CameraSample::new(0.0, 0.0, 0.0, 0.0, 0.0)
     }
+
+    pub fn image_pos(&self) -> (f32, f32) { (self.image_x, self.image_y) }
+    pub fn lens_pos(&self) -> (f32, f32) { (self.lens_u, self.lens_v) }
+    pub fn time(&self) -> f32 { self.time }
+}
+
+// The opening that light passes through on its way into the camera, which
+// gives out of focus highlights their shape. With no blades it's round, and
+// otherwise it's the regular polygon that the blades form, turned by
+// rotation degrees. Towards the edges of the image the lens barrel cuts off
+// part of the opening too, which turns round highlights into cat's eyes.
+// cat_eye is how far off center the barrel is at the edges of the image, in
+// units of the aperture's radius.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aperture {
+    blades: usize,
+    rotation: f32,
+    cat_eye: f32
+}
+
+impl Aperture {
+    pub fn round() -> Aperture { Aperture::new(0, 0.0, 0.0) }
+
+    pub fn new(blades: usize, rotation: f32, cat_eye: f32) -> Aperture {
+        let blades = if blades > 0 && blades < 3 {
+            println!("WARNING: Apertures need at least three blades, using a round one");
+            0
+        } else {
+            blades
+        };
+
+        Aperture { blades, rotation: rotation.as_radians(), cat_eye: cat_eye.max(0.0) }
+    }
+
+    // Samples a point uniformly over the aperture, scaled to fit in the
+    // unit disk
+    pub fn sample(&self, u1: f32, u2: f32) -> (f32, f32) {
+        if self.blades == 0 {
+            return concentric_sample_disk(u1, u2);
+        }
+
+        // Pick one of the triangles between the center and the polygon's
+        // edges, and then a point within it
+        let n = self.blades as f32;
+        let blade = ((u1 * n) as usize).min(self.blades - 1);
+        let u1 = u1 * n - (blade as f32);
+        let vertex = |i: usize| {
+            let phi = self.rotation + 2.0 * ::std::f32::consts::PI * (i as f32) / n;
+            (phi.cos(), phi.sin())
+        };
+
+        let (x0, y0) = vertex(blade);
+        let (x1, y1) = vertex(blade + 1);
+        let su = u1.sqrt();
+        (su * ((1.0 - u2) * x0 + u2 * x1), su * ((1.0 - u2) * y0 + u2 * y1))
+    }
+
+    // Whether light through the point (u, v) of the aperture reaches the
+    // film at (fx, fy), given in [-1, 1] from one edge of the image to the
+    // other
+    pub fn passes(&self, u: f32, v: f32, fx: f32, fy: f32) -> bool {
+        if self.cat_eye == 0.0 {
+            return true;
+        }
+
+        let (du, dv) = (u - self.cat_eye * fx, v - self.cat_eye * fy);
+        du * du + dv * dv <= 1.0
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -141,6 +213,34 @@ impl Camera {
         }
     }
 
+    // Changes the shape of the lens aperture of cameras that have depth of
+    // field
+    pub fn with_aperture(self, aperture: Aperture) -> Camera {
+        match self {
+            Camera::Orthographic { base, proj, dx_camera, dy_camera } =>
+                Camera::Orthographic { base, proj: proj.with_aperture(aperture),
+                                       dx_camera, dy_camera },
+            Camera::Perspective { base, proj, dx_camera, dy_camera } =>
+                Camera::Perspective { base, proj: proj.with_aperture(aperture),
+                                      dx_camera, dy_camera },
+            cam => cam
+        }
+    }
+
+    // Tilts the plane of focus of cameras that have depth of field, in
+    // degrees around the x and y axes
+    pub fn with_focus_tilt(self, tilt_x: f32, tilt_y: f32) -> Camera {
+        match self {
+            Camera::Orthographic { base, proj, dx_camera, dy_camera } =>
+                Camera::Orthographic { base, proj: proj.with_focus_tilt(tilt_x, tilt_y),
+                                       dx_camera, dy_camera },
+            Camera::Perspective { base, proj, dx_camera, dy_camera } =>
+                Camera::Perspective { base, proj: proj.with_focus_tilt(tilt_x, tilt_y),
+                                      dx_camera, dy_camera },
+            cam => cam
+        }
+    }
+
     pub fn base(&self) -> &CameraBase {
         match self {
             &Camera::Perspective { ref base, .. } => { base },
@@ -173,7 +273,7 @@ impl Camera {
             proj.raster_to_camera().xf(p_raster)
         });
 
-        match self {
+        let ray = match self {
             &Camera::Orthographic { .. } =>
                 Ray::new_with(p_camera.unwrap(), Vector::forward(), 0.0),
             &Camera::Perspective { .. } =>
@@ -191,7 +291,9 @@ impl Camera {
                                                theta.sin() * phi.sin()),
                               0.0)
             }
-        }
+        };
+
+        ray.with_kind(RayKind::Camera)
     }
 
     pub fn film(&self) -> &Film { &(self.base().film) }
@@ -201,12 +303,10 @@ impl Camera {
         let mut ray = self.generate_base_ray(sample);
 
         // Modify ray for depth of field
-        if let Some(proj) = self.proj() {
-            proj.handle_dof(sample, &mut ray);
-        }
+        let weight = self.proj().map_or(1.0, |proj| proj.handle_dof(sample, &mut ray));
 
         ray.set_time(self.base().shutter_open.lerp(&self.base().shutter_close, sample.time));
-        (1.0, self.base().cam_to_world.xf(ray))
+        (weight, self.base().cam_to_world.xf(ray))
     }
 
     pub fn generate_ray_differential(&self, sample: &CameraSample)
@@ -262,12 +362,10 @@ impl Camera {
         }
 
         // Modify ray for depth of field
-        if let Some(proj) = self.proj() {
-            proj.handle_dof(sample, &mut rd.ray);
-        }
+        let weight = self.proj().map_or(1.0, |proj| proj.handle_dof(sample, &mut rd.ray));
 
         rd.ray.set_time(self.base().shutter_open.lerp(&self.base().shutter_close, sample.time));
-        (1.0, self.base().cam_to_world.xf(rd))
+        (weight, self.base().cam_to_world.xf(rd))
     }
 }
 
@@ -302,4 +400,58 @@ mod tests {
         // Check DOF properties
         unimplemented!()
     }
+
+    #[test]
+    fn it_shapes_the_aperture_with_blades() {
+        use crate::rng::RNG;
+
+        let mut rng = RNG::new(0);
+        let hexagon = Aperture::new(6, 30.0, 0.0);
+        let round = Aperture::round();
+        let edge_dist = (::std::f32::consts::PI / 6.0).cos();
+        let (mut sum_u, mut sum_v) = (0.0, 0.0);
+        for _ in 0..1024 {
+            let (u1, u2) = (rng.random_float(), rng.random_float());
+            let (x, y) = round.sample(u1, u2);
+            assert!(x * x + y * y <= 1.0 + 1e-5);
+
+            // Every edge of the hexagon is as far from the center, and the
+            // middle of the first one is at 60 degrees
+            let (x, y) = hexagon.sample(u1, u2);
+            for i in 0..6 {
+                let phi = (60.0 * (i as f32) + 60.0).as_radians();
+                assert!(x * phi.cos() + y * phi.sin() <= edge_dist + 1e-5);
+            }
+            sum_u += x;
+            sum_v += y;
+        }
+
+        assert!((sum_u / 1024.0).abs() < 0.05 && (sum_v / 1024.0).abs() < 0.05);
+
+        // Too few blades to make a polygon
+        assert_eq!(Aperture::new(2, 0.0, 0.0), Aperture::round());
+    }
+
+    #[test]
+    fn it_cuts_cat_eyes_at_the_edges_of_the_image() {
+        use crate::rng::RNG;
+
+        let aperture = Aperture::new(0, 0.0, 0.5);
+        let mut rng = RNG::new(0);
+        let mut num_center = 0;
+        let mut num_corner = 0;
+        for _ in 0..1024 {
+            let (u, v) = aperture.sample(rng.random_float(), rng.random_float());
+            if aperture.passes(u, v, 0.0, 0.0) { num_center += 1; }
+            if aperture.passes(u, v, 1.0, 1.0) {
+                // The barrel hides the side of the aperture away from it
+                num_corner += 1;
+                assert!(u + v > -0.8);
+            }
+        }
+
+        assert_eq!(num_center, 1024);
+        assert!(num_corner > 256 && num_corner < 768);
+        assert!(Aperture::round().passes(1.0, 0.0, 1.0, -1.0));
+    }
 }
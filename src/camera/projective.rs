@@ -1,12 +1,14 @@
-use camera::CameraBase;
-use camera::CameraSample;
-use camera::film::Film;
-use geometry::normal::Normalize;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use ray::Ray;
-use transform::animated::AnimatedTransform;
-use transform::transform::Transform;
+use crate::camera::Aperture;
+use crate::camera::CameraBase;
+use crate::camera::CameraSample;
+use crate::camera::film::Film;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::ray::Ray;
+use crate::transform::animated::AnimatedTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Degrees;
 
 macro_rules! check_mat {
     ($m1: expr, $m2: expr) => {{
@@ -26,9 +28,6 @@ macro_rules! check_mat {
     }}
 }
 
-// !FIXME! This doesn't belong here!
-fn concentric_sample_disk(x: f32, y: f32) -> (f32, f32) { (x, y) }
-
 #[derive(Debug, Clone)]
 pub struct Projection {
     camera_to_screen: Transform,
@@ -37,7 +36,11 @@ pub struct Projection {
     raster_to_camera: Transform,
 
     lens_radius: f32,
-    focal_distance: f32
+    focal_distance: f32,
+    aperture: Aperture,
+    // How steeply the plane of focus rises along x and y in camera space
+    focus_slope: (f32, f32),
+    film_res: (f32, f32)
 }
 
 impl Projection {
@@ -67,43 +70,74 @@ impl Projection {
             screen_to_raster: screen_to_raster,
             raster_to_camera: raster_to_cam,
             lens_radius: lensr,
-            focal_distance: focald
+            focal_distance: focald,
+            aperture: Aperture::round(),
+            focus_slope: (0.0, 0.0),
+            film_res: (film.x_res() as f32, film.y_res() as f32)
         }
     }
 
+    pub fn with_aperture(self, aperture: Aperture) -> Projection {
+        Projection { aperture, ..self }
+    }
+
+    // Tilts the plane of focus, like a tilt-shift lens, by tilt_x degrees
+    // around the x axis and tilt_y degrees around the y axis. It still
+    // passes through the point at the focal distance in the middle of the
+    // view.
+    pub fn with_focus_tilt(self, tilt_x: f32, tilt_y: f32) -> Projection {
+        let focus_slope = (tilt_y.as_radians().tan(), tilt_x.as_radians().tan());
+        Projection { focus_slope, ..self }
+    }
+
     pub fn camera_to_screen(&self) -> &Transform { &self.camera_to_screen }
     pub fn raster_to_screen(&self) -> &Transform { &self.raster_to_screen }
     pub fn screen_to_raster(&self) -> &Transform { &self.screen_to_raster }
     pub fn raster_to_camera(&self) -> &Transform { &self.raster_to_camera }
 
-    pub fn handle_dof(&self, sample: &CameraSample, ray: &mut Ray) {
+    // Moves the ray to start at a point on the lens, returning the weight
+    // of the ray, which is zero if the lens barrel blocks it
+    pub fn handle_dof(&self, sample: &CameraSample, ray: &mut Ray) -> f32 {
         if self.lens_radius <= 0.0 {
-            return;
+            return 1.0;
         }
 
         // Sample point on lens
-        let (mut u, mut v) = concentric_sample_disk(sample.lens_u, sample.lens_v);
-        u *= self.lens_radius;
-        v *= self.lens_radius;
+        let (u, v) = self.aperture.sample(sample.lens_u, sample.lens_v);
+        let fx = 2.0 * sample.image_x / self.film_res.0 - 1.0;
+        let fy = 2.0 * sample.image_y / self.film_res.1 - 1.0;
+        if !self.aperture.passes(u, v, fx, fy) {
+            return 0.0;
+        }
 
-        // Compute point on plane of focus
-        let ft = self.focal_distance / ray.d.z;
+        let u = u * self.lens_radius;
+        let v = v * self.lens_radius;
+
+        // Compute point on plane of focus. Rays that never reach a tilted
+        // plane are left as they are.
+        let (sx, sy) = self.focus_slope;
+        let d_n = ray.d.z - sx * ray.d.x - sy * ray.d.y;
+        if d_n <= 0.0 {
+            return 1.0;
+        }
+        let ft = (self.focal_distance + sx * ray.o.x + sy * ray.o.y - ray.o.z) / d_n;
         let p_focus = ray.point_at(ft);
 
         // Update ray for effect of lens
         ray.o = Point::new_with(u, v, 0.0);
         ray.d = (p_focus - &ray.o).normalize();
+        1.0
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use camera::film::Film;
-    use filter::Filter;
-    use transform::transform::ApplyTransform;
-    use transform::transform::Transform;
-    use geometry::vector::Vector;
+    use crate::camera::film::Film;
+    use crate::filter::Filter;
+    use crate::transform::transform::ApplyTransform;
+    use crate::transform::transform::Transform;
+    use crate::geometry::vector::Vector;
 
     // Test a simple orthographic projection with a near and far plane at one
     // and two respectively....
@@ -253,4 +287,59 @@ mod tests {
     #[test]
     fn it_can_adjust_rays_for_dof() {
     }
+
+    #[test]
+    fn it_focuses_rays_through_the_aperture() {
+        let film = mk_film();
+        let p = Projection::new(&film, Transform::new(), [0.0, 1.0, 0.0, 1.0], 0.5, 4.0)
+            .with_aperture(Aperture::new(5, 0.0, 0.25));
+
+        let focus = Point::new_with(0.0, 0.0, 4.0);
+        let mut num_blocked = 0;
+        for i in 0..8 {
+            for j in 0..8 {
+                let (lu, lv) = ((i as f32 + 0.5) / 8.0, (j as f32 + 0.5) / 8.0);
+
+                // Rays from the center of the image all pass and meet at the
+                // plane of focus
+                let mut ray = Ray::new_with(Point::new(), Vector::forward(), 0.0);
+                let sample = CameraSample::new(320.0, 240.0, lu, lv, 0.0);
+                assert_eq!(p.handle_dof(&sample, &mut ray), 1.0);
+                assert!(ray.o.x * ray.o.x + ray.o.y * ray.o.y <= 0.25 + 1e-5);
+                assert!((ray.point_at(4.0 / ray.d.z) - &focus).length() < 1e-4);
+
+                // Some of the ones from the corner are blocked
+                let mut ray = Ray::new_with(Point::new(), Vector::forward(), 0.0);
+                let sample = CameraSample::new(640.0, 480.0, lu, lv, 0.0);
+                if p.handle_dof(&sample, &mut ray) == 0.0 {
+                    num_blocked += 1;
+                }
+            }
+        }
+
+        assert!(num_blocked > 0 && num_blocked < 64);
+    }
+
+    #[test]
+    fn it_can_tilt_the_plane_of_focus() {
+        let film = mk_film();
+        let p = Projection::new(&film, Transform::new(), [0.0, 1.0, 0.0, 1.0], 0.5, 4.0)
+            .with_focus_tilt(45.0, 0.0);
+
+        // The plane of focus rises by one unit of depth for every unit up,
+        // and stays put where it crosses the middle of the view
+        for focus in [Point::new_with(0.0, 1.0, 5.0), Point::new_with(2.0, -1.0, 3.0),
+                      Point::new_with(0.0, 0.0, 4.0)].iter() {
+            for i in 0..16 {
+                let (lu, lv) = ((i % 4) as f32 / 4.0 + 0.125, (i / 4) as f32 / 4.0 + 0.125);
+                let mut ray = Ray::new_with(Point::new(), Vector::from(focus.clone()).normalize(),
+                                            0.0);
+                let sample = CameraSample::new(320.0, 240.0, lu, lv, 0.0);
+                assert_eq!(p.handle_dof(&sample, &mut ray), 1.0);
+
+                let t = (focus.z - ray.o.z) / ray.d.z;
+                assert!((ray.point_at(t) - focus).length() < 1e-3);
+            }
+        }
+    }
 }
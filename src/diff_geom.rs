@@ -1,13 +1,13 @@
-use geometry::normal::Normal;
-use geometry::normal::Normalize;
-use geometry::point::Point;
-use geometry::vector::Cross;
-use geometry::vector::Dot;
-use geometry::vector::Vector;
-use shape::ShapeBase;
-use ray::RayDifferential;
-
-use utils::solve_linear_system_2x2;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::shape::ShapeBase;
+use crate::ray::RayDifferential;
+
+use crate::utils::solve_linear_system_2x2;
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub struct DifferentialGeometry {
@@ -1,4 +1,4 @@
-use utils::sinc_1d;
+use crate::utils::sinc_1d;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct FilterBase {
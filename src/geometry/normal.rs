@@ -1,6 +1,6 @@
-use geometry::vector::Cross;
-use geometry::vector::Dot;
-use geometry::vector::Vector;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
 
 pub trait Normalize {
     fn normalize(self) -> Self;
@@ -205,8 +205,8 @@ impl Cross for Normal {
 mod tests {
     use super::*;
     use std::f32;
-    use geometry::vector::Vector;
-    use geometry::vector::Dot;
+    use crate::geometry::vector::Vector;
+    use crate::geometry::vector::Dot;
 
     #[test]
     fn it_can_be_created() {
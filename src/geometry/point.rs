@@ -1,5 +1,5 @@
-use geometry::vector::Vector;
-use utils::Lerp;
+use crate::geometry::vector::Vector;
+use crate::utils::Lerp;
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct Point {
@@ -161,8 +161,8 @@ impl ::std::ops::IndexMut<usize> for Point {
 mod tests {
     use super::*;
     use std::f32;
-    use utils::Lerp;
-    use geometry::vector::Vector;
+    use crate::utils::Lerp;
+    use crate::geometry::vector::Vector;
 
     #[test]
     fn it_can_be_created() {
@@ -1,4 +1,4 @@
-use utils::Lerp;
+use crate::utils::Lerp;
 
 pub trait Dot<T = Self> {
     fn dot(&self, v2: &T) -> f32;
@@ -45,6 +45,10 @@ impl Vector {
     pub fn length(&self) -> f32 {
         self.length_squared().sqrt()
     }
+
+    pub fn abs(&self) -> Vector {
+        Vector::new_with(self.x.abs(), self.y.abs(), self.z.abs())
+    }
 }
 
 impl<'a, 'b> ::std::ops::Sub<&'b Vector> for &'a Vector {
@@ -220,7 +224,7 @@ pub fn spherical_phi(v: &Vector) -> f32 {
 mod tests {
     use super::*;
     use std::f32;
-    use utils::Lerp;
+    use crate::utils::Lerp;
 
     #[test]
     fn it_can_be_created() {
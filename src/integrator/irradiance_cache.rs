@@ -0,0 +1,562 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bsdf::BSDF;
+use crate::bsdf::BSDFSample;
+use crate::bsdf::BxDFType;
+use crate::camera::Camera;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::geometry::vector::coordinate_system;
+use crate::geometry::vector::spherical_direction_for_basis;
+use crate::intersection::Intersection;
+use crate::light::LightSample;
+use crate::ray::RayDifferential;
+use crate::renderer::Renderer;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::utils::Degrees;
+use crate::utils::octree::Octree;
+
+use crate::integrator::BounceDepths;
+use crate::integrator::add_clamped;
+use crate::integrator::clamp_radiance;
+use crate::integrator::DirectLightingOffsets;
+use crate::integrator::emitted_radiance;
+use crate::integrator::select_wavelength;
+use crate::integrator::specular_reflect;
+use crate::integrator::specular_transmit;
+use crate::integrator::LightGroupRadiance;
+use crate::integrator::uniform_sample_all_lights_grouped;
+
+#[derive(Clone, Debug)]
+struct IrradianceSample {
+    e: Spectrum,
+    n: Normal,
+    p: Point,
+    w_avg: Vector,
+    max_dist: f32,
+
+    // Rotational and translational gradients of the irradiance for each
+    // RGB channel, following Ward and Heckbert's "Irradiance Gradients"
+    r_grad: [Vector; 3],
+    t_grad: [Vector; 3]
+}
+
+impl IrradianceSample {
+    fn extrapolate(&self, p: &Point, n: &Normal) -> Spectrum {
+        let n_rot = Vector::from(&self.n).cross(Vector::from(n));
+        let dp = p - &self.p;
+        let mut rgb = self.e.to_rgb();
+        for (c, x) in rgb.iter_mut().enumerate() {
+            *x = (*x + n_rot.dot(&self.r_grad[c]) + dp.dot(&self.t_grad[c])).max(0.0);
+        }
+        Spectrum::from_rgb(rgb)
+    }
+}
+
+// Radiance and hit distance for a single cell of the stratified hemisphere
+#[derive(Clone, Debug)]
+struct HemisphereSample {
+    rgb: [f32; 3],
+    dist: f32
+}
+
+// Returns the sine and cosine of the polar angle at the center of the j-th
+// of m rings of a cosine weighted hemisphere
+fn ring_center(j: usize, m: usize) -> (f32, f32) {
+    let u = ((j as f32) + 0.5) / (m as f32);
+    (u.sqrt(), (1.0 - u).sqrt())
+}
+
+// Computes the rotational and translational irradiance gradients from an
+// m x n stratified, cosine weighted set of hemisphere samples. The basis
+// (s, t) spans the tangent plane of the shading point. The rotational
+// gradient is set up so that rotating the normal from n to n' changes the
+// irradiance by (n x n') . r_grad.
+fn irradiance_gradients(samples: &[HemisphereSample], m: usize, n: usize,
+                        s: &Vector, t: &Vector) -> ([Vector; 3], [Vector; 3]) {
+    let mut r_grad = [Vector::new(), Vector::new(), Vector::new()];
+    let mut t_grad = [Vector::new(), Vector::new(), Vector::new()];
+    let cell = |j: usize, k: usize| &samples[j * n + k];
+
+    for k in 0..n {
+        let phi = 2.0 * PI * (k as f32) / (n as f32);
+        let u_k = phi.cos() * s + phi.sin() * t;
+        let v_k = -phi.sin() * s + phi.cos() * t;
+        let k_prev = (k + n - 1) % n;
+
+        for c in 0..3 {
+            // Rotational gradient
+            let r_sum = (0..m).fold(0.0, |acc, j| {
+                let (sin_theta, cos_theta) = ring_center(j, m);
+                acc + cell(j, k).rgb[c] * sin_theta / cos_theta
+            });
+            r_grad[c] = &r_grad[c] + &v_k * (r_sum * PI / ((m * n) as f32));
+
+            // Translational gradient across the boundaries between rings...
+            let u_sum = (1..m).fold(0.0, |acc, j| {
+                let sin_theta_minus = ((j as f32) / (m as f32)).sqrt();
+                let cos2_theta_minus = 1.0 - sin_theta_minus * sin_theta_minus;
+                let r_min = cell(j, k).dist.min(cell(j - 1, k).dist);
+                acc + sin_theta_minus * cos2_theta_minus / r_min *
+                    (cell(j, k).rgb[c] - cell(j - 1, k).rgb[c])
+            });
+
+            // ... and between wedges
+            let v_sum = (0..m).fold(0.0, |acc, j| {
+                let cos_theta_minus = (1.0 - (j as f32) / (m as f32)).sqrt();
+                let cos_theta_plus = (1.0 - ((j + 1) as f32) / (m as f32)).sqrt();
+                let (sin_theta, _) = ring_center(j, m);
+                let hs = cell(j, k);
+                let r_min = hs.dist.min(cell(j, k_prev).dist);
+                acc + (cos_theta_minus - cos_theta_plus) / (sin_theta * r_min) *
+                    (hs.rgb[c] - cell(j, k_prev).rgb[c])
+            });
+
+            t_grad[c] = &t_grad[c] +
+                &u_k * (u_sum * 2.0 * PI / (n as f32)) + &v_k * v_sum;
+        }
+    }
+
+    (r_grad, t_grad)
+}
+
+#[derive(Clone, Debug)]
+pub struct IrradianceCacheIntegrator {
+    min_weight: f32,
+    min_sample_pixel_spacing: f32,
+    max_sample_pixel_spacing: f32,
+    cos_max_sample_angle_difference: f32,
+    n_samples: usize,
+    max_specular_depth: usize,
+    max_indirect_depth: usize,
+    depths: BounceDepths,
+    max_indirect: f32,
+    direct: DirectLightingOffsets,
+
+    // The cache is shared between every copy of the integrator (one per
+    // render task) and is only created once we know the scene bounds.
+    octree: Arc<RwLock<Option<Octree<IrradianceSample>>>>
+}
+
+impl IrradianceCacheIntegrator {
+    pub fn new(min_weight: f32, min_spacing: f32, max_spacing: f32,
+               max_angle: f32, max_specular_depth: usize,
+               max_indirect_depth: usize, n_samples: usize)
+               -> IrradianceCacheIntegrator {
+        IrradianceCacheIntegrator {
+            min_weight,
+            min_sample_pixel_spacing: min_spacing,
+            max_sample_pixel_spacing: max_spacing,
+            cos_max_sample_angle_difference: max_angle.as_radians().cos(),
+            n_samples,
+            max_specular_depth,
+            max_indirect_depth,
+            depths: BounceDepths::unlimited(),
+            max_indirect: f32::INFINITY,
+            direct: DirectLightingOffsets::default(),
+            octree: Arc::new(RwLock::new(None))
+        }
+    }
+
+    pub fn with_bounce_depths(self, depths: BounceDepths)
+                              -> IrradianceCacheIntegrator {
+        IrradianceCacheIntegrator { depths, ..self }
+    }
+
+    pub fn with_indirect_clamp(self, max_indirect: f32)
+                               -> IrradianceCacheIntegrator {
+        IrradianceCacheIntegrator { max_indirect, ..self }
+    }
+
+    pub fn preprocess(&mut self, scene: &Scene, _: &Camera) {
+        let mut wb = scene.world_bound();
+        let delta = 0.01 * (&wb.p_max - &wb.p_min);
+        wb.p_min = &wb.p_min - &delta;
+        wb.p_max = &wb.p_max + &delta;
+        *self.octree.write().unwrap() = Some(Octree::new(wb));
+    }
+
+    pub fn request_samples(&mut self, sampler: &Sampler, sample: &mut Sample,
+                           scene: &Scene) {
+        self.direct = DirectLightingOffsets::all_lights(sampler, sample, scene);
+    }
+
+    pub fn li<R: Renderer>(&self, scene: &Scene, renderer: &R,
+                           rayd: &RayDifferential, isect: &mut Intersection,
+                           sample: &Sample, rng: &mut RNG,
+                           groups: Option<&mut LightGroupRadiance>) -> Spectrum {
+        let mut bsdf = if let Some(b) = isect.get_bsdf(rayd) { b } else {
+            return Spectrum::from(0.0)
+        };
+
+        // Paths through dispersive surfaces follow a single wavelength
+        let mut rayd = rayd.clone();
+        let weight = select_wavelength(&mut rayd, &mut bsdf, rng);
+        let rayd = &rayd;
+        let ray = &rayd.ray;
+
+        let wo = -(&ray.d);
+        let p = bsdf.dg_shading.p.clone();
+        let n = bsdf.dg_shading.nn.clone();
+
+        // Compute emitted light if ray hit an area light source. The light
+        // groups' shares are gathered in split and weighted along with l.
+        let track = groups.is_some();
+        let mut split = LightGroupRadiance::new();
+        let mut l = emitted_radiance(isect, &wo, ray.time, track.then_some(&mut split));
+
+        // Add direct lighting at the intersection point
+        l = l + uniform_sample_all_lights_grouped(
+            scene, renderer, &p, &n, &wo, isect, ray.time, &bsdf,
+            sample, Some(&self.direct), rng, track.then_some(&mut split));
+
+        // Trace rays for specular reflection and refraction
+        if ray.depth + 1 < self.max_specular_depth {
+            let bounces = &ray.bounces;
+            if self.depths.allows(bounces, BxDFType::BSDF_REFLECTION | BxDFType::BSDF_SPECULAR) {
+                let mut refl_groups = LightGroupRadiance::new();
+                let refl = specular_reflect(rayd, &bsdf, rng, isect, renderer, scene, sample,
+                                            track.then_some(&mut refl_groups));
+                add_clamped(&mut l, track.then_some(&mut split), refl, &refl_groups,
+                            Spectrum::from(1.0), self.max_indirect);
+            }
+            if self.depths.allows(bounces, BxDFType::BSDF_TRANSMISSION | BxDFType::BSDF_SPECULAR) {
+                let mut tmit_groups = LightGroupRadiance::new();
+                let tmit = specular_transmit(rayd, &bsdf, rng, isect, renderer, scene, sample,
+                                             track.then_some(&mut tmit_groups));
+                add_clamped(&mut l, track.then_some(&mut split), tmit, &tmit_groups,
+                            Spectrum::from(1.0), self.max_indirect);
+            }
+        }
+        if let Some(g) = groups {
+            g.add_scaled(&split, weight);
+        }
+
+        // Estimate indirect lighting with irradiance cache
+        let ng = isect.dg.nn.clone().face_forward(wo.clone());
+
+        // Compute pixel spacing in world space at intersection point
+        let pixel_spacing = isect.dg.dpdx.cross_with(&isect.dg.dpdy).length().sqrt();
+
+        let refl_flags = BxDFType::BSDF_REFLECTION |
+            BxDFType::BSDF_DIFFUSE | BxDFType::BSDF_GLOSSY;
+        l = l + self.indirect_lo(&p, &ng, pixel_spacing, &wo, isect,
+                                 &bsdf, refl_flags, ray, rng, scene, renderer, sample);
+
+        let trans_flags = BxDFType::BSDF_TRANSMISSION |
+            BxDFType::BSDF_DIFFUSE | BxDFType::BSDF_GLOSSY;
+        l = l + self.indirect_lo(&p, &(-ng), pixel_spacing, &wo, isect,
+                                 &bsdf, trans_flags, ray, rng, scene, renderer, sample);
+        l * weight
+    }
+
+    fn indirect_lo<R: Renderer>(&self, p: &Point, n: &Normal, pixel_spacing: f32,
+                                wo: &Vector, isect: &Intersection, bsdf: &BSDF,
+                                flags: BxDFType, ray: &crate::ray::Ray, rng: &mut RNG,
+                                scene: &Scene, renderer: &R, sample: &Sample)
+                                -> Spectrum {
+        // Only gather light for the lobes that the path may still follow
+        let flags = self.depths.allowed(&ray.bounces, flags);
+        if bsdf.num_components_matching(flags) == 0 {
+            return Spectrum::from(0.0);
+        }
+
+        let interpolated = self.interpolate_e(p, n);
+        let (e, wi) = match interpolated {
+            Some(ewi) => ewi,
+
+            // Don't recurse any further if we've bounced too many times,
+            // just treat the remaining indirect light as black
+            None if ray.depth >= self.max_indirect_depth => {
+                return Spectrum::from(0.0)
+            },
+
+            None => self.compute_irradiance(
+                p, n, pixel_spacing, isect, ray, rng, scene, renderer, sample)
+        };
+
+        bsdf.f(wo.clone(), wi, flags) * e
+    }
+
+    // Computes irradiance at p by sampling the hemisphere around n, and adds
+    // the result to the cache. Returns the irradiance along with the
+    // average incident direction.
+    fn compute_irradiance<R: Renderer>(&self, p: &Point, n: &Normal,
+                                       pixel_spacing: f32, isect: &Intersection,
+                                       ray: &crate::ray::Ray, rng: &mut RNG,
+                                       scene: &Scene, renderer: &R,
+                                       sample: &Sample) -> (Spectrum, Vector) {
+        // Choose the number of rings (m) and wedges (n) used to stratify
+        // the hemisphere. Ward recommends roughly pi times as many wedges.
+        let num_rings = ((self.n_samples as f32 / PI).sqrt().round() as usize).max(1);
+        let num_wedges = (self.n_samples / num_rings).max(3);
+
+        let nv = Vector::from(n);
+        let (s, t) = coordinate_system(&nv);
+
+        let mut hemisphere = Vec::with_capacity(num_rings * num_wedges);
+        let mut e = Spectrum::from(0.0);
+        let mut w_avg = Vector::new();
+        let mut inv_dist_sum = 0.0;
+        for j in 0..num_rings {
+            for k in 0..num_wedges {
+                // Cosine weighted sample in the (j, k) stratum
+                let u1 = ((j as f32) + rng.random_float()) / (num_rings as f32);
+                let u2 = ((k as f32) + rng.random_float()) / (num_wedges as f32);
+                let cos_theta = (1.0 - u1).sqrt();
+                let sin_theta = u1.sqrt();
+                let w = spherical_direction_for_basis(
+                    sin_theta, cos_theta, 2.0 * PI * u2,
+                    s.clone(), t.clone(), nv.clone());
+
+                let mut rd = isect.spawn_ray(&w);
+                rd.ray.time = ray.time;
+                rd.ray.depth = ray.depth + 1;
+                rd.ray.bounces = ray.bounces;
+                rd.ray.bounces.add(BxDFType::BSDF_DIFFUSE);
+                let li = clamp_radiance(renderer.li_simple(scene, &rd, sample, rng),
+                                        self.max_indirect);
+                let dist = rd.ray.maxt();
+
+                w_avg = w_avg + w * li.y();
+                e = e + li;
+                inv_dist_sum += 1.0 / dist;
+                hemisphere.push(HemisphereSample { rgb: li.to_rgb(), dist });
+            }
+        }
+
+        let num_taken = num_rings * num_wedges;
+        e = e * (PI / (num_taken as f32));
+
+        // Compute the radius over which this sample can be reused from the
+        // harmonic mean distance to the surrounding geometry
+        let mut max_dist = (num_taken as f32) / inv_dist_sum;
+        if pixel_spacing > 0.0 {
+            max_dist = max_dist.max(self.min_sample_pixel_spacing * pixel_spacing)
+                .min(self.max_sample_pixel_spacing * pixel_spacing);
+        }
+
+        let wi = if w_avg.length_squared() > 0.0 { w_avg.normalize() } else { nv };
+        let (r_grad, t_grad) = irradiance_gradients(
+            &hemisphere, num_rings, num_wedges, &s, &t);
+
+        // Add computed irradiance value to cache
+        if max_dist.is_finite() && max_dist > 0.0 {
+            let sample = IrradianceSample {
+                e,
+                n: n.clone(),
+                p: p.clone(),
+                w_avg: wi.clone(),
+                max_dist,
+                r_grad,
+                t_grad
+            };
+
+            let delta = Vector::new_with(max_dist, max_dist, max_dist);
+            let sample_extent = BBox::new_with(p - &delta, p + &delta);
+            if let Some(ref mut octree) = *self.octree.write().unwrap() {
+                octree.add(sample, &sample_extent);
+            }
+        }
+
+        (e, wi)
+    }
+
+    // Looks up the irradiance at p from nearby samples in the cache. Returns
+    // None if there weren't enough samples close enough to reuse.
+    fn interpolate_e(&self, p: &Point, n: &Normal) -> Option<(Spectrum, Vector)> {
+        let octree = self.octree.read().unwrap();
+        let octree = octree.as_ref()?;
+
+        let mut e = Spectrum::from(0.0);
+        let mut w_avg = Vector::new();
+        let mut sum_wt = 0.0;
+        octree.lookup(p, |sample| {
+            // Skip samples whose surface normals are too different
+            if n.dot(&sample.n) < self.cos_max_sample_angle_difference {
+                return true;
+            }
+
+            // Skip samples that are too far away
+            let d2 = p.distance_squared(&sample.p);
+            if d2 > sample.max_dist * sample.max_dist {
+                return true;
+            }
+
+            // Skip samples that are in front of the point being shaded
+            let p_to_sample = &sample.p - p;
+            if n.dot(&p_to_sample) > 0.01 {
+                return true;
+            }
+
+            // Compute estimate error and possibly use sample
+            let err = d2.sqrt() / sample.max_dist;
+            if err < 1.0 {
+                let wt = (1.0 - err) * (1.0 - err);
+                e = e + wt * sample.extrapolate(p, n);
+                w_avg = &w_avg + &sample.w_avg * wt;
+                sum_wt += wt;
+            }
+
+            true
+        });
+
+        if sum_wt < self.min_weight {
+            return None;
+        }
+
+        let wi = if w_avg.length_squared() > 0.0 {
+            w_avg.normalize()
+        } else {
+            Vector::from(n)
+        };
+
+        Some((e / sum_wt, wi))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    use crate::diff_geom::DifferentialGeometry;
+    use crate::ray::Ray;
+    use crate::transform::transform::Transform;
+
+    // A renderer that sees the same radiance in every direction, at a
+    // fixed distance
+    struct ConstantRenderer {
+        l: Spectrum,
+        dist: f32,
+        calls: Cell<usize>
+    }
+
+    impl Renderer for ConstantRenderer {
+        fn render(&mut self, _: &Scene) { }
+
+        fn li<'a>(&self, _: &'a Scene, ray: &RayDifferential, _: &Sample,
+                  _: &mut RNG) -> (Spectrum, Option<Intersection>, Spectrum) {
+            self.calls.set(self.calls.get() + 1);
+            ray.ray.set_maxt(self.dist);
+            (self.l.clone(), None, Spectrum::from(1.0))
+        }
+
+        fn transmittance(&self, _: &Scene, _: &RayDifferential, _: &Sample,
+                         _: &mut RNG) -> Spectrum {
+            Spectrum::from(1.0)
+        }
+    }
+
+    fn make_integrator(scene: &Scene) -> IrradianceCacheIntegrator {
+        let mut ic = IrradianceCacheIntegrator::new(0.5, 2.5, 15.0, 10.0, 5, 3, 64);
+        *ic.octree.write().unwrap() = Some(Octree::new(scene.world_bound()));
+        ic
+    }
+
+    fn compute_at<R: Renderer>(ic: &IrradianceCacheIntegrator, renderer: &R,
+                               scene: &Scene, p: &Point, n: &Normal)
+                               -> (Spectrum, Vector) {
+        let mut rng = RNG::new(0);
+        let mut dg = DifferentialGeometry::new();
+        dg.p = p.clone();
+        dg.nn = n.clone();
+        let isect = Intersection::new_with(dg, Transform::new(), Transform::new(), 0, 0,
+                                           Vector::new());
+        ic.compute_irradiance(p, n, 0.0, &isect, &Ray::new(), &mut rng,
+                              scene, renderer, &Sample::empty())
+    }
+
+    #[test]
+    fn it_computes_irradiance_from_uniform_radiance() {
+        let scene = Scene::new();
+        let ic = make_integrator(&scene);
+        let renderer = ConstantRenderer {
+            l: Spectrum::from(2.0), dist: 0.5, calls: Cell::new(0)
+        };
+
+        let n = Normal::new_with(0.0, 0.0, 1.0);
+        let (e, wi) = compute_at(&ic, &renderer, &scene, &Point::new(), &n);
+
+        assert!((e.y() - 2.0 * PI).abs() < 1e-3);
+        assert!(wi.dot(&Vector::new_with(0.0, 0.0, 1.0)) > 0.9);
+
+        // Uniform radiance shouldn't have any gradient
+        let octree = ic.octree.read().unwrap();
+        octree.as_ref().unwrap().lookup(&Point::new(), |sample| {
+            for c in 0..3 {
+                assert!(sample.r_grad[c].length() < 1e-3);
+                assert!(sample.t_grad[c].length() < 1e-3);
+            }
+            true
+        });
+    }
+
+    #[test]
+    fn it_reuses_nearby_samples() {
+        let scene = Scene::new();
+        let ic = make_integrator(&scene);
+        let renderer = ConstantRenderer {
+            l: Spectrum::from(1.0), dist: 0.5, calls: Cell::new(0)
+        };
+
+        let n = Normal::new_with(0.0, 0.0, 1.0);
+        assert!(ic.interpolate_e(&Point::new(), &n).is_none());
+        compute_at(&ic, &renderer, &scene, &Point::new(), &n);
+
+        // Close by with the same orientation
+        let near = Point::new_with(0.05, 0.0, 0.0);
+        let (e, _) = ic.interpolate_e(&near, &n).unwrap();
+        assert!((e.y() - PI).abs() < 1e-3);
+
+        // Too far away
+        assert!(ic.interpolate_e(&Point::new_with(0.6, 0.0, 0.0), &n).is_none());
+
+        // Facing a different direction
+        let n2 = Normal::new_with(1.0, 0.0, 0.0);
+        assert!(ic.interpolate_e(&near, &n2).is_none());
+    }
+
+    #[test]
+    fn it_has_gradients_that_point_towards_brighter_regions() {
+        // A renderer where the hemisphere towards +x is brighter
+        struct SlopedRenderer;
+        impl Renderer for SlopedRenderer {
+            fn render(&mut self, _: &Scene) { }
+
+            fn li<'a>(&self, _: &'a Scene, ray: &RayDifferential, _: &Sample,
+                      _: &mut RNG) -> (Spectrum, Option<Intersection>, Spectrum) {
+                ray.ray.set_maxt(1.0);
+                (Spectrum::from(1.0 + ray.ray.d.x), None, Spectrum::from(1.0))
+            }
+
+            fn transmittance(&self, _: &Scene, _: &RayDifferential, _: &Sample,
+                             _: &mut RNG) -> Spectrum {
+                Spectrum::from(1.0)
+            }
+        }
+
+        let scene = Scene::new();
+        let ic = make_integrator(&scene);
+        let n = Normal::new_with(0.0, 0.0, 1.0);
+        compute_at(&ic, &SlopedRenderer, &scene, &Point::new(), &n);
+
+        // Tilting the normal towards +x should increase the irradiance
+        let tilted = Normal::new_with(0.1, 0.0, 1.0).normalize();
+        let (e_flat, _) = ic.interpolate_e(&Point::new(), &n).unwrap();
+        let (e_tilted, _) = ic.interpolate_e(&Point::new(), &tilted).unwrap();
+        assert!(e_tilted.y() > e_flat.y());
+    }
+}
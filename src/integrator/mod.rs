@@ -1,32 +1,182 @@
+mod irradiance_cache;
+mod path;
+mod single_scattering;
 mod whitted;
 
-use bsdf;
-use bsdf::BxDFType;
-use bsdf::BSDF;
-use bsdf::BSDFSample;
-use camera::Camera;
-use geometry::vector::Dot;
-use geometry::vector::Vector;
-use intersection::Intersection;
-use ray::RayDifferential;
-use renderer::Renderer;
-use rng::RNG;
-use sampler::sample::Sample;
-use sampler::Sampler;
-use scene::Scene;
-use spectrum::Spectrum;
-
-use integrator::whitted::WhittedIntegrator;
+use crate::bsdf;
+use crate::bsdf::BxDFType;
+use crate::bsdf::BSDF;
+use crate::bsdf::BSDFSample;
+use crate::bsdf::BSDFSampleOffsets;
+use crate::camera::Camera;
+use crate::geometry::normal::Normal;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::light::Light;
+use crate::light::LightSample;
+use crate::light::LightSampleOffsets;
+use crate::montecarlo::power_heuristic;
+use crate::radiance_check;
+use crate::ray::BounceCounts;
+use crate::ray::RayDifferential;
+use crate::renderer::Renderer;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::trace;
+
+use crate::integrator::irradiance_cache::IrradianceCacheIntegrator;
+use crate::integrator::path::PathIntegrator;
+use crate::integrator::single_scattering::SingleScatteringIntegrator;
+use crate::integrator::whitted::WhittedIntegrator;
+
+// Limits on the number of times a path may scatter off of each type of
+// BxDF. Lobes whose types aren't in the mask are never followed, so e.g.
+// a mask without BSDF_TRANSMISSION keeps paths from refracting at all.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BounceDepths {
+    pub diffuse: usize,
+    pub glossy: usize,
+    pub specular: usize,
+    pub mask: BxDFType
+}
+
+impl BounceDepths {
+    pub fn new(diffuse: usize, glossy: usize, specular: usize) -> BounceDepths {
+        BounceDepths { diffuse, glossy, specular, mask: BxDFType::BSDF_ALL }
+    }
+
+    // Places no limits beyond the integrator's own maximum depth
+    pub fn unlimited() -> BounceDepths {
+        BounceDepths::new(usize::MAX, usize::MAX, usize::MAX)
+    }
+
+    pub fn with_mask(self, mask: BxDFType) -> BounceDepths {
+        BounceDepths { mask, ..self }
+    }
+
+    // Returns the subset of flags that a path that has already bounced the
+    // given number of times may still sample.
+    pub fn allowed(&self, bounces: &BounceCounts, flags: BxDFType) -> BxDFType {
+        let mut allowed = flags & self.mask;
+        if bounces.diffuse >= self.diffuse {
+            allowed.remove(BxDFType::BSDF_DIFFUSE);
+        }
+        if bounces.glossy >= self.glossy {
+            allowed.remove(BxDFType::BSDF_GLOSSY);
+        }
+        if bounces.specular >= self.specular {
+            allowed.remove(BxDFType::BSDF_SPECULAR);
+        }
+        allowed
+    }
+
+    // Returns true if every flag may still be sampled
+    pub fn allows(&self, bounces: &BounceCounts, flags: BxDFType) -> bool {
+        self.allowed(bounces, flags).contains(flags)
+    }
+}
+
+// Scales l down so that its luminance is at most max_y, keeping its hue.
+// Clamping indirect contributions like this suppresses fireflies from rare,
+// very bright paths at the cost of darkening the image slightly.
+pub fn clamp_radiance(l: Spectrum, max_y: f32) -> Spectrum {
+    l * clamp_scale(&l, max_y)
+}
+
+// The factor that clamp_radiance scales l by
+fn clamp_scale(l: &Spectrum, max_y: f32) -> f32 {
+    let y = l.y();
+    if y > max_y { max_y / y } else { 1.0 }
+}
+
+// Adds weight * l, clamped by clamp_radiance, to total. If groups is given,
+// the share of l that came from each grouped light, l_groups, is weighted
+// and scaled down by the same factor before it's added to groups, so that
+// the group channels still add up to the clamped image.
+pub fn add_clamped(total: &mut Spectrum, groups: Option<&mut LightGroupRadiance>,
+                   l: Spectrum, l_groups: &LightGroupRadiance, weight: Spectrum,
+                   max_y: f32) {
+    let l = weight * l;
+    let s = clamp_scale(&l, max_y);
+    *total = *total + l * s;
+    if let Some(g) = groups {
+        g.add_scaled(l_groups, weight * s);
+    }
+}
+
+// Returns the ray leaving isect in direction wi after a specular bounce of ray
+fn spawn_specular_ray(ray: &RayDifferential, wi: &Vector,
+                      isect: &Intersection) -> RayDifferential {
+    let mut rd = isect.spawn_ray(wi);
+    rd.ray.time = ray.ray.time;
+    rd.ray.depth = ray.ray.depth + 1;
+    rd.ray.bounces = ray.ray.bounces;
+    rd.ray.bounces.add(BxDFType::BSDF_SPECULAR);
+    rd.ray.wavelength = ray.ray.wavelength;
+    rd
+}
+
+// Dispersive BSDFs bend each wavelength by a different amount, so a path
+// that meets one follows a single wavelength from then on, which is picked
+// here if the ray doesn't carry one yet. Returns the weight of the path's
+// radiance at that wavelength.
+pub fn select_wavelength(ray: &mut RayDifferential, bsdf: &mut BSDF,
+                         rng: &mut RNG) -> Spectrum {
+    if !bsdf.is_dispersive() {
+        return Spectrum::from(1.0);
+    }
+
+    match ray.ray.wavelength {
+        Some(lambda) => {
+            bsdf.set_wavelength(lambda);
+            Spectrum::from(1.0)
+        },
+        None => {
+            let lambda = Spectrum::sample_wavelength(rng.random_float());
+            ray.ray.wavelength = Some(lambda);
+            bsdf.set_wavelength(lambda);
+            Spectrum::from_wavelength(lambda)
+        }
+    }
+}
+
+// Returns the radiance arriving along a bounced ray, weighted by the
+// throughput of the bounce. If groups is given, the part of it that came
+// from grouped lights is weighted the same way and added to them.
+fn li_along_bounce<R: Renderer>(
+    scene: &Scene, renderer: &R, rd: &RayDifferential, sample: &Sample,
+    rng: &mut RNG, weight: Spectrum, groups: Option<&mut LightGroupRadiance>) -> Spectrum {
+    match groups {
+        Some(g) => {
+            let mut bounced = LightGroupRadiance::new();
+            let li = renderer.li_grouped(scene, rd, sample, rng, Some(&mut bounced));
+            g.add_scaled(&bounced, weight);
+            weight * li
+        },
+        None => weight * renderer.li_simple(scene, rd, sample, rng)
+    }
+}
 
 pub fn specular_reflect<R: Renderer>(
     ray: &RayDifferential, bsdf: &BSDF,
     rng: &mut RNG, isect: &Intersection, renderer: &R,
-    scene: &Scene, sample: &Sample) -> Spectrum {
+    scene: &Scene, sample: &Sample,
+    groups: Option<&mut LightGroupRadiance>) -> Spectrum {
     let wo = -(&ray.ray.d);
     let p = &(bsdf.dg_shading.p);
     let n = &(bsdf.dg_shading.nn);
     let (wi, pdf, f) = bsdf.sample_f(
         &wo, BSDFSample::new(rng), bsdf::BxDFType::BSDF_REFLECTION | bsdf::BxDFType::BSDF_SPECULAR);
+    let f = radiance_check::check("bsdf", Some(isect.primitive_id), f);
+    trace::record("bsdf_sample", |e| {
+        e.str("type", "specular_reflection").vector("wi", &wi).float("pdf", pdf).spectrum("f", &f)
+    });
 
     let win = wi.abs_dot(n);
     if pdf <= 0f32 || f.is_black() || win == 0f32 {
@@ -34,9 +184,9 @@ pub fn specular_reflect<R: Renderer>(
     }
 
     // Compute ray differential rd for specular reflection
-    let rd =
+    let rd = {
+        let mut reflected_ray = spawn_specular_ray(ray, &wi, isect);
         if ray.has_differentials {
-            let mut reflected_ray = ray.clone();
             reflected_ray.has_differentials = true;
             reflected_ray.rx_origin = p + &isect.dg.dpdx;
             reflected_ray.ry_origin = p + &isect.dg.dpdy;
@@ -62,35 +212,38 @@ pub fn specular_reflect<R: Renderer>(
             reflected_ray.ry_dir =
                 &wi - dwody + 2.0 *
                 Vector::from(n.dot(&wo) * dndy + ddndy * n);
+        }
+        reflected_ray
+    };
 
-            reflected_ray
-        } else {
-            ray.clone()
-        };
-
-    f * renderer.li_simple(scene, &rd, sample, rng) * win / pdf
+    li_along_bounce(scene, renderer, &rd, sample, rng, f * win / pdf, groups)
 }
 
 pub fn specular_transmit<R: Renderer>(
     ray: &RayDifferential, bsdf: &BSDF,
     rng: &mut RNG, isect: &Intersection, renderer: &R,
-    scene: &Scene, sample: &Sample) -> Spectrum {
+    scene: &Scene, sample: &Sample,
+    groups: Option<&mut LightGroupRadiance>) -> Spectrum {
     let wo = -(&ray.ray.d);
     let p = &(bsdf.dg_shading.p);
     let n = &(bsdf.dg_shading.nn);
     let (wi, pdf, f) = bsdf.sample_f(
         &wo, BSDFSample::new(rng),
         bsdf::BxDFType::BSDF_TRANSMISSION | bsdf::BxDFType::BSDF_SPECULAR);
+    let f = radiance_check::check("bsdf", Some(isect.primitive_id), f);
+    trace::record("bsdf_sample", |e| {
+        e.str("type", "specular_transmission").vector("wi", &wi).float("pdf", pdf).spectrum("f", &f)
+    });
 
     let win = wi.abs_dot(n);
     if pdf <= 0f32 || f.is_black() || win == 0f32 {
         return Spectrum::from(0.0);
     }
 
-    // Compute ray differential rd for specular reflection
-    let rd =
+    // Compute ray differential rd for specular transmission
+    let rd = {
+        let mut reflected_ray = spawn_specular_ray(ray, &wi, isect);
         if ray.has_differentials {
-            let mut reflected_ray = ray.clone();
             reflected_ray.has_differentials = true;
             reflected_ray.rx_origin = p + &isect.dg.dpdx;
             reflected_ray.ry_origin = p + &isect.dg.dpdy;
@@ -110,11 +263,7 @@ pub fn specular_transmit<R: Renderer>(
             let ddndy = n.dot(&dwody) + wo.dot(&dndy);
 
             let w = ray.ray.d.clone();
-            let eta = if n.dot(&wo) < 0.0 {
-                1.0 / bsdf.eta
-            } else {
-                bsdf.eta
-            };
+            let eta = bsdf.relative_eta(&wo);
 
             let mu = eta * n.dot(&w) - n.dot(&wi);
             let (dmudx, dmudy) = {
@@ -127,22 +276,289 @@ pub fn specular_transmit<R: Renderer>(
 
             reflected_ray.ry_dir =
                 &wi + eta * dwody - Vector::from(mu * dndy + dmudy * n);
+        }
+        reflected_ray
+    };
 
-            reflected_ray
-        } else {
-            ray.clone()
-        };
+    li_along_bounce(scene, renderer, &rd, sample, rng, f * win / pdf, groups)
+}
 
-    f * renderer.li_simple(scene, &rd, sample, rng) * win / pdf
+// Returns true if the intersection lies on the geometry of the given area
+// light, in which case the light's emission is visible along the ray.
+fn hits_light(isect: &Intersection, light: &dyn Light) -> bool {
+    isect.primitive.as_ref()
+        .and_then(|prim| prim.area_light())
+        .is_some_and(|area_light| {
+            let area_light_ptr = area_light.as_ref() as *const _ as *const u8;
+            area_light_ptr == light as *const dyn Light as *const u8
+        })
+}
+
+// Estimates the light reflected at p towards wo due to direct illumination
+// from a single light. One sample is taken by sampling the light and, unless
+// the light is described by a delta distribution, one more by sampling the
+// BSDF. The two are combined with the power heuristic so that whichever
+// strategy better matches the integrand dominates: light sampling for small
+// lights and diffuse surfaces, BSDF sampling for large lights and glossy
+// surfaces. Specular components should be left out of flags, since they are
+// handled by tracing rays with specular_reflect and specular_transmit.
+pub fn estimate_direct<R: Renderer>(
+    scene: &Scene, renderer: &R, light: &dyn Light, p: &Point, n: &Normal,
+    wo: &Vector, isect: &Intersection, time: f32, bsdf: &BSDF, rng: &mut RNG,
+    sample: &Sample, light_sample: LightSample, bsdf_sample: BSDFSample,
+    flags: BxDFType) -> Spectrum {
+    let mut ld = Spectrum::from(0.0);
+
+    // Sample light source with multiple importance sampling
+    let (li, wi, light_pdf, visibility) = light.sample_l(p, light_sample, time);
+    let visibility = visibility.leaving_surface(&isect.p_error, &isect.dg.nn);
+    let li = radiance_check::check("light", None, li);
+    if light_pdf > 0.0 && !li.is_black() {
+        let f = radiance_check::check("bsdf", None, bsdf.f(wo.clone(), wi.clone(), flags));
+        let unoccluded = !f.is_black() && visibility.unoccluded(scene);
+        trace::record("light_sample", |e| {
+            e.vector("wi", &wi).spectrum("Li", &li).float("pdf", light_pdf)
+                .spectrum("f", &f).boolean("unoccluded", unoccluded)
+                .boolean("delta", light.is_delta_light())
+        });
+        if unoccluded {
+            // Add light's contribution to reflected radiance
+            let li = li * visibility.transmittance(scene, renderer, sample, rng);
+            if light.is_delta_light() {
+                ld = ld + f * li * wi.abs_dot(n) / light_pdf;
+            } else {
+                let bsdf_pdf = bsdf.pdf(wo, &wi, flags);
+                let weight = power_heuristic(1, light_pdf, 1, bsdf_pdf);
+                ld = ld + f * li * wi.abs_dot(n) * weight / light_pdf;
+            }
+        }
+    }
+
+    // Sample BSDF with multiple importance sampling
+    if !light.is_delta_light() {
+        let (wi, bsdf_pdf, f) = bsdf.sample_f(wo, bsdf_sample, flags);
+        let f = radiance_check::check("bsdf", None, f);
+        if !f.is_black() && bsdf_pdf > 0.0 {
+            let light_pdf = light.pdf(p, &wi);
+            if light_pdf == 0.0 {
+                return ld;
+            }
+            let weight = power_heuristic(1, bsdf_pdf, 1, light_pdf);
+
+            // Add light contribution from BSDF sampling
+            let mut ray = isect.spawn_ray(&wi);
+            ray.ray.set_time(time);
+            let li = match scene.intersect(&ray.ray) {
+                Some(ref light_isect) if hits_light(light_isect, light) =>
+                    light_isect.le(&(-(&wi)), time),
+                Some(_) => Spectrum::from(0.0),
+                None => light.le(&ray)
+            };
+
+            trace::record("bsdf_sample", |e| {
+                e.str("type", "direct").vector("wi", &wi).float("pdf", bsdf_pdf)
+                    .spectrum("f", &f).float("light_pdf", light_pdf).spectrum("Li", &li)
+            });
+
+            if !li.is_black() {
+                let li = li * renderer.transmittance(scene, &ray, sample, rng);
+                ld = ld + f * li * wi.abs_dot(n) * weight / bsdf_pdf;
+            }
+        }
+    }
+
+    ld
+}
+
+// Radiance split up by the output group of the light that it came from.
+// Light from lights without a group is only part of the total image.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LightGroupRadiance {
+    groups: Vec<(String, Spectrum)>
+}
+
+impl LightGroupRadiance {
+    pub fn new() -> LightGroupRadiance { LightGroupRadiance::default() }
+
+    pub fn add(&mut self, group: &str, l: Spectrum) {
+        match self.groups.iter_mut().find(|(g, _)| g == group) {
+            Some((_, lg)) => *lg = *lg + l,
+            None => self.groups.push((String::from(group), l))
+        }
+    }
+
+    // Adds each of other's groups, weighted by s
+    pub fn add_scaled(&mut self, other: &LightGroupRadiance, s: Spectrum) {
+        for (group, l) in other.iter() {
+            self.add(group, *l * s);
+        }
+    }
+
+    pub fn scale(&mut self, s: Spectrum) {
+        for (_, l) in self.groups.iter_mut() {
+            *l = *l * s;
+        }
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<'_, (String, Spectrum)> {
+        self.groups.iter()
+    }
+}
+
+// The light emitted towards wo by the area light that isect hit, if any.
+// If groups is given and the light is in one, it's added to that group.
+pub fn emitted_radiance(isect: &Intersection, wo: &Vector, time: f32,
+                        groups: Option<&mut LightGroupRadiance>) -> Spectrum {
+    let le = isect.le(wo, time);
+    if let Some(g) = groups {
+        let area_light = isect.primitive.as_ref().and_then(|p| p.area_light());
+        if let Some(name) = area_light.as_ref().and_then(|al| al.group()) {
+            g.add(name, le);
+        }
+    }
+    le
+}
+
+// The radiance that reaches the origin of a ray that escapes the scene
+// without hitting anything, i.e. the sum of what the lights emit towards
+// it. Environment lights are seen this way in the background and in
+// reflections. If groups is given, each light's share is added to its group.
+pub fn escaped_radiance(scene: &Scene, ray: &RayDifferential,
+                        mut groups: Option<&mut LightGroupRadiance>) -> Spectrum {
+    scene.lights().iter().fold(Spectrum::from(0.0), |acc, light| {
+        let le = radiance_check::check("light", None, light.le(ray));
+        if let (Some(g), Some(name)) = (groups.as_mut(), light.group()) {
+            g.add(name, le);
+        }
+        acc + le
+    })
+}
+
+// The sample arrays that the direct lighting estimators draw their light
+// and BSDF samples from, instead of from the RNG, so that they're as well
+// distributed as the sampler can make them.
+#[derive(Clone, Debug, Default)]
+pub struct DirectLightingOffsets {
+    light: Vec<LightSampleOffsets>,
+    bsdf: Vec<BSDFSampleOffsets>,
+    light_num: Option<usize>
+}
+
+impl DirectLightingOffsets {
+    // Requests arrays for sampling every light in the scene, rounding the
+    // number of samples each light asks for to what the sampler can do well.
+    pub fn all_lights(sampler: &Sampler, sample: &mut Sample,
+                      scene: &Scene) -> DirectLightingOffsets {
+        let (light, bsdf) = scene.lights().iter().map(|light| {
+            let n = sampler.round_size(light.num_samples().max(1));
+            (LightSampleOffsets::new(n, sample), BSDFSampleOffsets::new(n, sample))
+        }).unzip();
+        DirectLightingOffsets { light, bsdf, light_num: None }
+    }
+
+    // Requests arrays for sampling a single light, and for choosing it.
+    pub fn one_light(sample: &mut Sample) -> DirectLightingOffsets {
+        DirectLightingOffsets {
+            light: vec![LightSampleOffsets::new(1, sample)],
+            bsdf: vec![BSDFSampleOffsets::new(1, sample)],
+            light_num: Some(sample.add_1d(1))
+        }
+    }
+
+    // The number of samples to take of the idx-th light
+    fn num_samples(&self, idx: usize) -> Option<usize> {
+        self.light.get(idx).map(|o| o.n_samples)
+    }
+
+    // The num-th light and BSDF samples for the idx-th light, falling back
+    // to the RNG if sample doesn't hold them.
+    fn samples(offsets: Option<&DirectLightingOffsets>, sample: &Sample, idx: usize,
+               num: usize, rng: &mut RNG) -> (LightSample, BSDFSample) {
+        let light_sample = offsets
+            .and_then(|o| LightSample::from_sample(sample, o.light.get(idx)?, num))
+            .unwrap_or_else(|| LightSample::new(rng));
+        let bsdf_sample = offsets
+            .and_then(|o| BSDFSample::from_sample(sample, o.bsdf.get(idx)?, num))
+            .unwrap_or_else(|| BSDFSample::new(rng));
+        (light_sample, bsdf_sample)
+    }
+}
+
+// Estimates direct lighting at p by sampling every light in the scene, taking
+// as many samples from each light as it asks for, or as offsets reserved.
+pub fn uniform_sample_all_lights<R: Renderer>(
+    scene: &Scene, renderer: &R, p: &Point, n: &Normal, wo: &Vector,
+    isect: &Intersection, time: f32, bsdf: &BSDF, sample: &Sample,
+    offsets: Option<&DirectLightingOffsets>, rng: &mut RNG) -> Spectrum {
+    uniform_sample_all_lights_grouped(scene, renderer, p, n, wo, isect,
+                                      time, bsdf, sample, offsets, rng, None)
+}
+
+// Same as uniform_sample_all_lights, but also adds the contribution of each
+// light that belongs to an output group to that group.
+pub fn uniform_sample_all_lights_grouped<R: Renderer>(
+    scene: &Scene, renderer: &R, p: &Point, n: &Normal, wo: &Vector,
+    isect: &Intersection, time: f32, bsdf: &BSDF, sample: &Sample,
+    offsets: Option<&DirectLightingOffsets>, rng: &mut RNG,
+    mut groups: Option<&mut LightGroupRadiance>) -> Spectrum {
+    let flags = BxDFType::BSDF_ALL - BxDFType::BSDF_SPECULAR;
+    scene.lights().iter().enumerate().fold(Spectrum::from(0.0), |l, (i, light)| {
+        let num_samples = offsets.and_then(|o| o.num_samples(i))
+            .unwrap_or_else(|| light.num_samples().max(1));
+        let ld = (0..num_samples).fold(Spectrum::from(0.0), |ld, j| {
+            let (light_sample, bsdf_sample) =
+                DirectLightingOffsets::samples(offsets, sample, i, j, rng);
+            ld + estimate_direct(scene, renderer, light.as_ref(), p, n, wo,
+                                 isect, time, bsdf, rng, sample,
+                                 light_sample, bsdf_sample, flags)
+        }) / (num_samples as f32);
+
+        if let (Some(g), Some(name)) = (groups.as_mut(), light.group()) {
+            g.add(name, ld);
+        }
+        l + ld
+    })
+}
+
+// Estimates direct lighting at p from a single light, chosen at random in
+// proportion to its power so that bright lights are sampled more often, or
+// by its expected contribution to p if the scene has a light BVH. The
+// estimate is divided by the probability of choosing the light, so it's
+// unbiased, but noisier than sampling every light. If groups is given, the
+// estimate is also added to the group of the light that was chosen, if it's
+// in one; the light is chosen and sampled exactly the same way either way.
+pub fn uniform_sample_one_light<R: Renderer>(
+    scene: &Scene, renderer: &R, p: &Point, n: &Normal, wo: &Vector,
+    isect: &Intersection, time: f32, bsdf: &BSDF, sample: &Sample,
+    offsets: Option<&DirectLightingOffsets>, rng: &mut RNG,
+    groups: Option<&mut LightGroupRadiance>) -> Spectrum {
+    let u_light = offsets
+        .and_then(|o| sample.one_d(o.light_num?)?.first().cloned())
+        .unwrap_or_else(|| rng.random_float());
+    let (light, light_pdf) = match scene.sample_light_at(p, n, u_light) {
+        Some((light, pdf)) if pdf > 0.0 => (light.clone(), pdf),
+        _ => return Spectrum::from(0.0)
+    };
+
+    let (light_sample, bsdf_sample) =
+        DirectLightingOffsets::samples(offsets, sample, 0, 0, rng);
+    let ld = estimate_direct(scene, renderer, light.as_ref(), p, n, wo,
+                             isect, time, bsdf, rng, sample, light_sample,
+                             bsdf_sample, BxDFType::BSDF_ALL - BxDFType::BSDF_SPECULAR)
+        / light_pdf;
+
+    if let (Some(g), Some(name)) = (groups, light.group()) {
+        g.add(name, ld);
+    }
+    ld
 }
 
 #[derive(Clone, Debug)]
 pub struct Integrator;
 
 impl Integrator {
-    fn preprocess(&mut self, scene: &Scene, camera: &Camera) {
-        unimplemented!()
-    }
+    // Integrators don't need to do any preprocessing by default
+    fn preprocess(&mut self, _: &Scene, _: &Camera) { }
 }
 
 #[derive(Clone, Debug)]
@@ -150,6 +566,14 @@ pub enum SurfaceIntegrator {
     Whitted {
         base: Integrator,
         surf: WhittedIntegrator
+    },
+    IrradianceCache {
+        base: Integrator,
+        surf: IrradianceCacheIntegrator
+    },
+    Path {
+        base: Integrator,
+        surf: PathIntegrator
     }
 }
 
@@ -161,41 +585,512 @@ impl SurfaceIntegrator {
         }
     }
 
+    pub fn irradiance_cache(min_weight: f32, min_spacing: f32, max_spacing: f32,
+                            max_angle: f32, max_specular_depth: usize,
+                            max_indirect_depth: usize, n_samples: usize)
+                            -> SurfaceIntegrator {
+        SurfaceIntegrator::IrradianceCache {
+            base: Integrator,
+            surf: IrradianceCacheIntegrator::new(
+                min_weight, min_spacing, max_spacing, max_angle,
+                max_specular_depth, max_indirect_depth, n_samples)
+        }
+    }
+
+    // Traces paths of up to max_depth bounces, which are randomly terminated
+    // with russian roulette once they have bounced roulette_depth times.
+    pub fn path(max_depth: usize, roulette_depth: usize) -> SurfaceIntegrator {
+        SurfaceIntegrator::Path {
+            base: Integrator,
+            surf: PathIntegrator::new(max_depth, roulette_depth)
+        }
+    }
+
+    // Limits the number of bounces of each BxDF type that the integrator
+    // will follow, on top of its own maximum depth.
+    pub fn with_bounce_depths(self, depths: BounceDepths) -> SurfaceIntegrator {
+        match self {
+            SurfaceIntegrator::Whitted { base, surf } =>
+                SurfaceIntegrator::Whitted {
+                    base, surf: surf.with_bounce_depths(depths)
+                },
+            SurfaceIntegrator::IrradianceCache { base, surf } =>
+                SurfaceIntegrator::IrradianceCache {
+                    base, surf: surf.with_bounce_depths(depths)
+                },
+            SurfaceIntegrator::Path { base, surf } =>
+                SurfaceIntegrator::Path {
+                    base, surf: surf.with_bounce_depths(depths)
+                }
+        }
+    }
+
+    // Clamps the luminance of each indirect sample to at most max_y. By
+    // default indirect light is left unclamped.
+    pub fn with_indirect_clamp(self, max_y: f32) -> SurfaceIntegrator {
+        match self {
+            SurfaceIntegrator::Whitted { base, surf } =>
+                SurfaceIntegrator::Whitted {
+                    base, surf: surf.with_indirect_clamp(max_y)
+                },
+            SurfaceIntegrator::IrradianceCache { base, surf } =>
+                SurfaceIntegrator::IrradianceCache {
+                    base, surf: surf.with_indirect_clamp(max_y)
+                },
+            SurfaceIntegrator::Path { base, surf } =>
+                SurfaceIntegrator::Path {
+                    base, surf: surf.with_indirect_clamp(max_y)
+                }
+        }
+    }
+
     pub fn li<R:Renderer>(
-        &self, _: &Scene, _: &R, _: &RayDifferential,
-        _: &mut Intersection, _: &Sample, _: &mut RNG) -> Spectrum {
-        unimplemented!()
+        &self, scene: &Scene, renderer: &R, ray: &RayDifferential,
+        isect: &mut Intersection, sample: &Sample, rng: &mut RNG) -> Spectrum {
+        self.li_with_groups(scene, renderer, ray, isect, sample, rng, None)
+    }
+
+    // Computes the same radiance as li, and if groups is given, adds the
+    // share of it that came from each grouped light to it, weighted and
+    // clamped the same way as the total. Path traced groups follow every
+    // bounce, so if every light is in a group they add up to the total. The
+    // irradiance cache interpolates indirect light without knowing where it
+    // came from, so that part of its total isn't in any group.
+    pub fn li_with_groups<R:Renderer>(
+        &self, scene: &Scene, renderer: &R, ray: &RayDifferential,
+        isect: &mut Intersection, sample: &Sample, rng: &mut RNG,
+        groups: Option<&mut LightGroupRadiance>) -> Spectrum {
+        match self {
+            &SurfaceIntegrator::Whitted { ref surf, .. } =>
+                surf.li(scene, renderer, ray, isect, sample, rng, groups),
+            &SurfaceIntegrator::IrradianceCache { ref surf, .. } =>
+                surf.li(scene, renderer, ray, isect, sample, rng, groups),
+            &SurfaceIntegrator::Path { ref surf, .. } =>
+                surf.li(scene, renderer, ray, isect, sample, rng, groups)
+        }
     }
 
     pub fn preprocess(&mut self, scene: &Scene, camera: &Camera) {
         match self {
-            &mut SurfaceIntegrator::Whitted { ref mut base, .. } =>
-                base.preprocess(scene, camera)
+            &mut SurfaceIntegrator::Whitted { ref mut base, .. } |
+            &mut SurfaceIntegrator::Path { ref mut base, .. } =>
+                base.preprocess(scene, camera),
+            &mut SurfaceIntegrator::IrradianceCache { ref mut base, ref mut surf } => {
+                base.preprocess(scene, camera);
+                surf.preprocess(scene, camera);
+            }
         }
     }
 
-    pub fn request_samples(&self, _: &Sampler, _: &mut Sample, _: &Scene) {
-        unimplemented!()
+    pub fn request_samples(&mut self, sampler: &Sampler, sample: &mut Sample,
+                           scene: &Scene) {
+        match self {
+            &mut SurfaceIntegrator::Whitted { ref mut surf, .. } =>
+                surf.request_samples(sampler, sample, scene),
+            &mut SurfaceIntegrator::IrradianceCache { ref mut surf, .. } =>
+                surf.request_samples(sampler, sample, scene),
+            &mut SurfaceIntegrator::Path { ref mut surf, .. } =>
+                surf.request_samples(sampler, sample, scene)
+        }
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct VolumeIntegrator {
-    base: Integrator
+pub enum VolumeIntegrator {
+    SingleScattering {
+        base: Integrator,
+        vol: SingleScatteringIntegrator
+    }
 }
 
 impl VolumeIntegrator {
+    pub fn single_scattering(step_size: f32, equiangular: bool) -> VolumeIntegrator {
+        VolumeIntegrator::SingleScattering {
+            base: Integrator,
+            vol: SingleScatteringIntegrator::new(step_size, equiangular)
+        }
+    }
+
+    // Adapts ray marching steps to the density of the media, see
+    // SingleScatteringIntegrator::with_cfl
+    pub fn with_cfl(self, cfl: f32) -> VolumeIntegrator {
+        match self {
+            VolumeIntegrator::SingleScattering { base, vol } =>
+                VolumeIntegrator::SingleScattering { base, vol: vol.with_cfl(cfl) }
+        }
+    }
+
+    pub fn with_majorant_res(self, res: usize) -> VolumeIntegrator {
+        match self {
+            VolumeIntegrator::SingleScattering { base, vol } =>
+                VolumeIntegrator::SingleScattering { base, vol: vol.with_majorant_res(res) }
+        }
+    }
+
+    // Returns the radiance added along the ray by the scene's participating
+    // media, and stores the transmittance along the ray in t.
     pub fn li<R:Renderer>(
-        &self, _: &Scene, _: &R, _: &RayDifferential,
-        _: &Sample, _: &mut RNG, _: &mut Spectrum) -> Spectrum {
-        unimplemented!()
+        &self, scene: &Scene, renderer: &R, ray: &RayDifferential,
+        sample: &Sample, rng: &mut RNG, t: &mut Spectrum) -> Spectrum {
+        match self {
+            &VolumeIntegrator::SingleScattering { ref vol, .. } =>
+                vol.li(scene, renderer, ray, sample, rng, t)
+        }
+    }
+
+    pub fn transmittance<R:Renderer>(
+        &self, scene: &Scene, renderer: &R, ray: &RayDifferential,
+        sample: &Sample, rng: &mut RNG) -> Spectrum {
+        match self {
+            &VolumeIntegrator::SingleScattering { ref vol, .. } =>
+                vol.transmittance(scene, renderer, ray, sample, rng)
+        }
     }
 
     pub fn preprocess(&mut self, scene: &Scene, camera: &Camera) {
-        self.base.preprocess(scene, camera);
+        match self {
+            &mut VolumeIntegrator::SingleScattering { ref mut base, ref mut vol } => {
+                base.preprocess(scene, camera);
+                vol.preprocess(scene, camera);
+            }
+        }
+    }
+
+    pub fn request_samples(&self, sampler: &Sampler, sample: &mut Sample,
+                           scene: &Scene) {
+        match self {
+            &VolumeIntegrator::SingleScattering { ref vol, .. } =>
+                vol.request_samples(sampler, sample, scene)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    use crate::bbox::HasBounds;
+    use crate::bsdf::lambertian::Lambertian;
+    use crate::diff_geom::DifferentialGeometry;
+    use crate::light::LightBuilder;
+    use crate::light::point::PointLight;
+    use crate::primitive::Primitive;
+    use crate::shape::Shape;
+    use std::sync::Arc;
+    use crate::transform::transform::Transform;
+    use crate::visibility_tester::VisibilityTester;
+
+    // A renderer for scenes without participating media, so that the
+    // integrators can be tested on their own
+    pub(crate) struct NoVolumeRenderer;
+
+    impl Renderer for NoVolumeRenderer {
+        fn render(&mut self, _: &Scene) { }
+
+        fn li<'a>(&self, _: &'a Scene, _: &RayDifferential, _: &Sample,
+                  _: &mut RNG) -> (Spectrum, Option<Intersection>, Spectrum) {
+            unimplemented!()
+        }
+
+        fn transmittance(&self, _: &Scene, _: &RayDifferential, _: &Sample,
+                         _: &mut RNG) -> Spectrum {
+            Spectrum::from(1.0)
+        }
+    }
+
+    // A light that surrounds the scene with constant radiance, sampled
+    // uniformly over the sphere of directions
+    #[derive(Debug)]
+    struct UniformEnvironmentLight(Spectrum);
+
+    impl Light for UniformEnvironmentLight {
+        fn le(&self, _: &RayDifferential) -> Spectrum { self.0 }
+
+        fn sample_l(&self, p: &Point, ls: LightSample, time: f32)
+                    -> (Spectrum, Vector, f32, VisibilityTester) {
+            let z = 1.0 - 2.0 * ls.u_pos.0;
+            let r = 0f32.max(1.0 - z * z).sqrt();
+            let phi = 2.0 * PI * ls.u_pos.1;
+            let wi = Vector::new_with(r * phi.cos(), r * phi.sin(), z);
+            let vis = VisibilityTester::ray(p.clone(), wi.clone(), time);
+            (self.0, wi, 1.0 / (4.0 * PI), vis)
+        }
+
+        fn pdf(&self, _: &Point, _: &Vector) -> f32 { 1.0 / (4.0 * PI) }
+
+        fn sample_l_ray(&self, scene: &Scene, ls: LightSample, u1: f32, u2: f32,
+                        time: f32) -> (Spectrum, crate::ray::Ray, Normal, f32) {
+            let d = crate::montecarlo::uniform_sample_sphere(u1, u2);
+            let (ray, pdf) = crate::light::sample_ray_into_scene(
+                scene, &d, ls.u_pos.0, ls.u_pos.1, time);
+            (self.0, ray, Normal::from(d), pdf / (4.0 * PI))
+        }
+
+        fn power(&self, scene: &Scene) -> Spectrum {
+            let (_, r) = scene.world_bound().bounding_sphere();
+            self.0 * PI * r * r
+        }
+
+        fn is_delta_light(&self) -> bool { false }
+    }
+
+    // An intersection at p with the surface normal n whose position is exact
+    fn surface_at(p: &Point, n: &Normal) -> Intersection {
+        let mut dg = DifferentialGeometry::new();
+        dg.p = p.clone();
+        dg.nn = n.clone();
+        Intersection::new_with(dg, Transform::new(), Transform::new(), 0, 0, Vector::new())
+    }
+
+    // The default scene holds a unit sphere at the origin, so keep the
+    // shading point well above it
+    fn diffuse_bsdf(r: f32) -> BSDF {
+        let dg = DifferentialGeometry::new_with(
+            Point::new_with(0.0, 0.0, 10.0), Vector::new_with(1.0, 0.0, 0.0),
+            Vector::new_with(0.0, 1.0, 0.0), Normal::new(), Normal::new(),
+            0.0, 0.0, None);
+        let mut bsdf = BSDF::new(dg, Normal::new_with(0.0, 0.0, 1.0));
+        bsdf.add_bxdf(Lambertian::new(Spectrum::from(r)));
+        bsdf
+    }
+
+    fn estimate(light: &dyn Light, bsdf: &BSDF, rng: &mut RNG) -> Spectrum {
+        let p = bsdf.dg_shading.p.clone();
+        let n = bsdf.dg_shading.nn.clone();
+        let light_sample = LightSample::new(rng);
+        let bsdf_sample = BSDFSample::new(rng);
+        estimate_direct(&Scene::new(), &NoVolumeRenderer, light, &p,
+                        &n, &Vector::new_with(0.0, 0.0, 1.0), &surface_at(&p, &n), 0.0, bsdf,
+                        rng, &Sample::empty(), light_sample, bsdf_sample,
+                        BxDFType::BSDF_ALL - BxDFType::BSDF_SPECULAR)
+    }
+
+    #[test]
+    fn it_estimates_direct_lighting_from_delta_lights_exactly() {
+        let light = PointLight::new(
+            Transform::translate(&Vector::new_with(0.0, 0.0, 12.0)),
+            Spectrum::from(4.0));
+        let bsdf = diffuse_bsdf(0.5);
+        let mut rng = RNG::new(0);
+
+        let ld = estimate(&light, &bsdf, &mut rng);
+        assert!((ld.y() - 0.5 / PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_combines_light_and_bsdf_samples_without_bias() {
+        // A white Lambertian surface lit by a uniform environment reflects
+        // exactly the incident radiance.
+        let light = UniformEnvironmentLight(Spectrum::from(2.0));
+        let bsdf = diffuse_bsdf(1.0);
+        let mut rng = RNG::new(0);
+
+        let num_samples = 4096;
+        let total = (0..num_samples).fold(0.0, |acc, _| {
+            acc + estimate(&light, &bsdf, &mut rng).y()
+        });
+
+        let avg = total / (num_samples as f32);
+        assert!((avg - 2.0).abs() < 0.05, "Average radiance: {}", avg);
+    }
+
+    // Two point lights that each deliver an irradiance of one at the shading
+    // point used by diffuse_bsdf
+    fn two_light_scene() -> Scene {
+        let lights: Vec<Arc<dyn Light>> = vec![
+            Arc::new(PointLight::new(
+                Transform::translate(&Vector::new_with(0.0, 0.0, 12.0)),
+                Spectrum::from(4.0)).with_group("key")),
+            Arc::new(PointLight::new(
+                Transform::translate(&Vector::new_with(0.0, 0.0, 13.0)),
+                Spectrum::from(9.0)))];
+        let sphere = Primitive::simple(Shape::sphere(
+            Transform::new(), Transform::new(), false, 1.0, -1.0, 1.0, 360.0));
+        Scene::new_with(Arc::new(sphere), lights, None)
+    }
+
+    #[test]
+    fn it_sums_the_contribution_of_all_lights() {
+        let scene = two_light_scene();
+        let bsdf = diffuse_bsdf(0.5);
+        let p = bsdf.dg_shading.p.clone();
+        let n = bsdf.dg_shading.nn.clone();
+        let mut rng = RNG::new(0);
+
+        let ld = uniform_sample_all_lights(
+            &scene, &NoVolumeRenderer, &p, &n, &Vector::new_with(0.0, 0.0, 1.0),
+            &surface_at(&p, &n), 0.0, &bsdf, &Sample::empty(), None, &mut rng);
+        assert!((ld.y() - 1.0 / PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_chooses_one_light_with_the_sample_it_reserved() {
+        let scene = two_light_scene();
+        let bsdf = diffuse_bsdf(0.5);
+        let p = bsdf.dg_shading.p.clone();
+        let n = bsdf.dg_shading.nn.clone();
+        let mut rng = RNG::new(0);
+
+        // Lay the arrays out the way Sample::new does, with the 2D arrays
+        // after the 1D ones
+        let mut sample = Sample::empty();
+        let offsets = DirectLightingOffsets::one_light(&mut sample);
+        assert_eq!((sample.num_1d.len(), sample.num_2d.len()), (3, 2));
+        for x in sample.offset_2d.iter_mut() { *x += 3; }
+        sample.samples = vec![0.5; 7];
+
+        let mut estimate = |u: f32| {
+            sample.samples[sample.offset_1d[2]] = u;
+            uniform_sample_one_light(
+                &scene, &NoVolumeRenderer, &p, &n, &Vector::new_with(0.0, 0.0, 1.0),
+                &surface_at(&p, &n), 0.0, &bsdf, &sample, Some(&offsets), &mut rng, None).y()
+        };
+
+        assert!((estimate(0.1) - 0.5 / PI * 13.0 / 4.0).abs() < 1e-4);
+        assert!((estimate(0.9) - 0.5 / PI * 13.0 / 9.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_scales_one_light_by_its_selection_probability() {
+        let scene = two_light_scene();
+        let bsdf = diffuse_bsdf(0.5);
+        let p = bsdf.dg_shading.p.clone();
+        let n = bsdf.dg_shading.nn.clone();
+        let mut rng = RNG::new(0);
+
+        // Both lights contribute equally, but the second one has 9/4 of the
+        // power of the first, so it's picked 9 times out of 13
+        assert!((scene.light_pdf(0) - 4.0 / 13.0).abs() < 1e-5);
+        assert!((scene.light_pdf(1) - 9.0 / 13.0).abs() < 1e-5);
+
+        let mut sum = 0.0;
+        for _ in 0..1024 {
+            let ld = uniform_sample_one_light(
+                &scene, &NoVolumeRenderer, &p, &n,
+                &Vector::new_with(0.0, 0.0, 1.0), &surface_at(&p, &n), 0.0, &bsdf,
+                &Sample::empty(), None, &mut rng, None);
+            let first = (ld.y() - 0.5 / PI * 13.0 / 4.0).abs() < 1e-4;
+            let second = (ld.y() - 0.5 / PI * 13.0 / 9.0).abs() < 1e-4;
+            assert!(first || second);
+            sum += ld.y();
+        }
+
+        // ... which still gives the total on average
+        assert!((sum / 1024.0 - 1.0 / PI).abs() < 0.05 / PI);
+
+        let empty = Scene::new();
+        let ld = uniform_sample_one_light(
+            &empty, &NoVolumeRenderer, &p, &n, &Vector::new_with(0.0, 0.0, 1.0),
+            &surface_at(&p, &n), 0.0, &bsdf, &Sample::empty(), None, &mut rng, None);
+        assert!(ld.is_black());
+    }
+
+    #[test]
+    fn it_limits_bounces_by_type() {
+        let depths = BounceDepths::new(2, 4, 8);
+        let all = BxDFType::BSDF_ALL;
+        let mut bounces = BounceCounts::default();
+        assert_eq!(depths.allowed(&bounces, all), all);
+
+        bounces.diffuse = 2;
+        assert_eq!(depths.allowed(&bounces, all), all - BxDFType::BSDF_DIFFUSE);
+
+        bounces.glossy = 4;
+        bounces.specular = 7;
+        assert!(depths.allows(&bounces,
+                              BxDFType::BSDF_REFLECTION | BxDFType::BSDF_SPECULAR));
+        bounces.specular = 8;
+        assert!(!depths.allows(&bounces,
+                               BxDFType::BSDF_REFLECTION | BxDFType::BSDF_SPECULAR));
+        assert_eq!(depths.allowed(&bounces, all),
+                   BxDFType::BSDF_REFLECTION | BxDFType::BSDF_TRANSMISSION);
+
+        // Masked out lobes are never allowed
+        let no_refraction = BounceDepths::unlimited()
+            .with_mask(all - BxDFType::BSDF_TRANSMISSION);
+        assert!(!no_refraction.allows(
+            &BounceCounts::default(),
+            BxDFType::BSDF_TRANSMISSION | BxDFType::BSDF_SPECULAR));
+        assert!(no_refraction.allows(
+            &BounceCounts::default(),
+            BxDFType::BSDF_REFLECTION | BxDFType::BSDF_SPECULAR));
     }
 
-    pub fn request_samples(&self, _: &Sampler, _: &mut Sample, _: &Scene) {
-        unimplemented!()
+    #[test]
+    fn it_spawns_specular_rays_from_the_hit_point() {
+        let mut rd = RayDifferential::new_with(
+            Point::new_with(0.0, 0.0, 5.0), Vector::new_with(0.0, 0.0, -1.0), 0.0);
+        rd.ray.time = 0.5;
+        rd.ray.bounces.diffuse = 1;
+
+        let scene = Scene::new();
+        let isect = scene.intersect(&rd.ray).unwrap();
+        let p = rd.point_at(rd.ray.maxt());
+        let wi = Vector::new_with(0.0, 0.0, 1.0);
+
+        let spawned = spawn_specular_ray(&rd, &wi, &isect);
+        assert!(spawned.ray.o.distance(&p) < 1e-3);
+
+        // It starts just off of the surface, on the side that it leaves to
+        let n = &isect.dg.nn;
+        assert!(n.dot(&(&spawned.ray.o - &isect.dg.p)) * n.dot(&wi) > 0.0);
+        assert_eq!(spawned.ray.d, wi);
+        assert_eq!(spawned.ray.time, 0.5);
+        assert_eq!(spawned.ray.depth, 1);
+        assert_eq!(spawned.ray.bounces,
+                   BounceCounts { diffuse: 1, glossy: 0, specular: 1 });
+        assert_eq!(spawned.ray.mint(), 0.0);
+    }
+
+    #[test]
+    fn it_clamps_radiance_by_luminance() {
+        let dim = Spectrum::from_rgb([0.1, 0.2, 0.3]);
+        assert_eq!(clamp_radiance(dim, 1.0), dim);
+        assert_eq!(clamp_radiance(dim, f32::INFINITY), dim);
+
+        let bright = Spectrum::from_rgb([40.0, 20.0, 10.0]);
+        let clamped = clamp_radiance(bright, 2.0);
+        assert!((clamped.y() - 2.0).abs() < 1e-4);
+
+        // The hue is unchanged
+        let rgb = clamped.to_rgb();
+        assert!((rgb[0] / rgb[1] - 2.0).abs() < 1e-4);
+        assert!((rgb[1] / rgb[2] - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_splits_direct_lighting_by_light_group() {
+        let scene = two_light_scene();
+        let bsdf = diffuse_bsdf(0.5);
+        let p = bsdf.dg_shading.p.clone();
+        let n = bsdf.dg_shading.nn.clone();
+        let mut rng = RNG::new(0);
+
+        // Only the first light is in a group
+        let mut groups = LightGroupRadiance::new();
+        let ld = uniform_sample_all_lights_grouped(
+            &scene, &NoVolumeRenderer, &p, &n, &Vector::new_with(0.0, 0.0, 1.0),
+            &surface_at(&p, &n), 0.0, &bsdf, &Sample::empty(), None, &mut rng, Some(&mut groups));
+        assert!((ld.y() - 1.0 / PI).abs() < 1e-4);
+
+        let split: Vec<_> = groups.iter().collect();
+        assert_eq!(split.len(), 1);
+        assert_eq!(split[0].0, "key");
+        assert!((split[0].1.y() - 0.5 / PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_accumulates_light_groups() {
+        let mut groups = LightGroupRadiance::new();
+        groups.add("key", Spectrum::from(1.0));
+        groups.add("fill", Spectrum::from(2.0));
+        groups.add("key", Spectrum::from(3.0));
+        groups.scale(Spectrum::from(0.5));
+
+        let names: Vec<_> = groups.iter().map(|g| g.0.as_str()).collect();
+        assert_eq!(names, vec!["key", "fill"]);
+        assert_eq!(groups.iter().next().unwrap().1, Spectrum::from(2.0));
+        assert_eq!(groups.iter().nth(1).unwrap().1, Spectrum::from(1.0));
     }
 }
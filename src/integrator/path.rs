@@ -0,0 +1,457 @@
+use crate::bsdf::BSDF;
+use crate::bsdf::BSDFSample;
+use crate::bsdf::BSDFSampleOffsets;
+use crate::bsdf::BxDFType;
+use crate::bsdf::bssrdf::BSSRDF;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::integrator::BounceDepths;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::material::Material;
+use crate::ray::RayDifferential;
+use crate::renderer::Renderer;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+
+use crate::integrator::add_clamped;
+use crate::integrator::DirectLightingOffsets;
+use crate::integrator::emitted_radiance;
+use crate::integrator::escaped_radiance;
+use crate::integrator::LightGroupRadiance;
+use crate::integrator::uniform_sample_one_light;
+use crate::integrator::select_wavelength;
+
+// The number of vertices of a path whose samples come from the sampler
+const SAMPLE_DEPTH: usize = 3;
+
+// Russian roulette never terminates paths with a probability lower than
+// this, so that it still culls paths that carry almost all of their light.
+const MIN_ROULETTE_TERMINATION: f32 = 0.05;
+
+// Decides whether a path with throughput beta survives russian roulette,
+// and if so returns the factor that its throughput has to be scaled by to
+// keep the estimate unbiased. Paths survive with probability proportional
+// to their largest throughput component. Refracting into a denser medium
+// scales radiance by eta squared, which says nothing about how much light
+// the path will carry, so eta_scale undoes it. Otherwise paths inside of
+// glass would be terminated far too often.
+fn roulette(beta: &Spectrum, eta_scale: f32, u: f32) -> Option<f32> {
+    let rr_beta = beta.max_component_value() * eta_scale;
+    if rr_beta >= 1.0 {
+        return Some(1.0);
+    }
+
+    let q = MIN_ROULETTE_TERMINATION.max(1.0 - rr_beta);
+    if u < q { None } else { Some(1.0 / (1.0 - q)) }
+}
+
+#[derive(Clone, Debug)]
+pub struct PathIntegrator {
+    max_depth: usize,
+    roulette_depth: usize,
+    depths: BounceDepths,
+    max_indirect: f32,
+
+    // The sample arrays for the direct lighting and the BSDF sample at each
+    // of the first SAMPLE_DEPTH vertices of a path
+    direct: Vec<DirectLightingOffsets>,
+    path: Vec<BSDFSampleOffsets>
+}
+
+impl PathIntegrator {
+    // Paths bounce at most max_depth times, and are subject to russian
+    // roulette once they have bounced roulette_depth times.
+    pub fn new(max_depth: usize, roulette_depth: usize) -> PathIntegrator {
+        PathIntegrator {
+            max_depth,
+            roulette_depth,
+            depths: BounceDepths::unlimited(),
+            max_indirect: f32::INFINITY,
+            direct: Vec::new(),
+            path: Vec::new()
+        }
+    }
+
+    pub fn with_bounce_depths(self, depths: BounceDepths) -> PathIntegrator {
+        PathIntegrator { depths, ..self }
+    }
+
+    pub fn with_indirect_clamp(self, max_indirect: f32) -> PathIntegrator {
+        PathIntegrator { max_indirect, ..self }
+    }
+
+    // Past the first few vertices of a path, the samples are far enough
+    // along that taking them from the RNG doesn't make much difference.
+    pub fn request_samples(&mut self, _: &Sampler, sample: &mut Sample, _: &Scene) {
+        self.direct = (0..SAMPLE_DEPTH).map(|_| DirectLightingOffsets::one_light(sample))
+            .collect();
+        self.path = (0..SAMPLE_DEPTH).map(|_| BSDFSampleOffsets::new(1, sample)).collect();
+    }
+
+    // Follows light that refracted into a translucent surface at the shading
+    // point of bsdf to a point pi where it left. Returns pi, the direction
+    // that light arrived at pi from, and the factor that the path's
+    // throughput is scaled by, along with the light arriving at pi directly
+    // from the light sources scaled by that factor. If groups is given, the
+    // latter's share from each grouped light is added to it.
+    fn sample_subsurface<R : Renderer>(&self, scene: &Scene, renderer: &R,
+                                       bssrdf: &BSSRDF, bsdf: &BSDF,
+                                       material: &Material, ray: &RayDifferential,
+                                       sample: &Sample, rng: &mut RNG,
+                                       groups: Option<&mut LightGroupRadiance>)
+                                       -> Option<(Intersection, Vector, Spectrum, Spectrum)> {
+        let (u1, u2, u3) = (rng.random_float(), rng.random_float(), rng.random_float());
+        let (pi, sp, pdf) = bssrdf.sample_sp(scene, &bsdf.dg_shading, material, u1, (u2, u3))?;
+        if sp.is_black() || pdf == 0.0 {
+            return None;
+        }
+
+        // The directional term at pi acts like a diffuse BSDF
+        let weight = sp / pdf;
+        let entry = bssrdf.entry_bsdf(&pi);
+        let wo = Vector::from(&pi.dg.nn);
+        let mut exit_groups = LightGroupRadiance::new();
+        let ld = uniform_sample_one_light(scene, renderer, &pi.dg.p, &pi.dg.nn, &wo,
+                                          &pi, ray.ray.time, &entry, sample, None, rng,
+                                          Some(&mut exit_groups));
+        if let Some(g) = groups {
+            g.add_scaled(&exit_groups, weight);
+        }
+
+        let (wi, pdf, f) = entry.sample_f(&wo, BSDFSample::new(rng), BxDFType::BSDF_ALL);
+        if f.is_black() || pdf == 0.0 {
+            return None;
+        }
+
+        let weight_i = weight * f * wi.abs_dot(&pi.dg.nn) / pdf;
+        Some((pi, wi, weight_i, weight * ld))
+    }
+
+    // Computes the radiance along the path starting with rayd. If groups is
+    // given, the share of it that came from each grouped light is added to
+    // it. Lights are chosen and sampled the same way whether or not groups
+    // are tracked, so tracking them doesn't change the total.
+    pub fn li<R : Renderer>(&self, scene: &Scene,
+                            renderer: &R,
+                            rayd: &RayDifferential,
+                            first_isect: &mut Intersection,
+                            sample: &Sample,
+                            rng: &mut RNG,
+                            mut groups: Option<&mut LightGroupRadiance>) -> Spectrum {
+        let mut l = Spectrum::from(0.0);
+        let mut beta = Spectrum::from(1.0);
+        let mut eta_scale = 1.0;
+        let mut specular_bounce = false;
+        let mut ray = rayd.clone();
+        let mut next_isect: Option<Intersection> = None;
+        let mut bounces = 0;
+        loop {
+            let isect: &Intersection = next_isect.as_ref().unwrap_or(first_isect);
+            let wo = -(&ray.ray.d);
+
+            // Emission was already accounted for by sampling lights at the
+            // previous vertex, unless it was a specular bounce
+            if bounces == 0 || specular_bounce {
+                let mut hit_groups = LightGroupRadiance::new();
+                let le = emitted_radiance(isect, &wo, ray.ray.time, Some(&mut hit_groups));
+                add_clamped(&mut l, groups.as_deref_mut(), le, &hit_groups, beta,
+                            f32::INFINITY);
+            }
+
+            let mut bsdf = if let Some(b) = isect.get_bsdf(&ray) { b } else { break };
+            beta = beta * select_wavelength(&mut ray, &mut bsdf, rng);
+            let p = &(bsdf.dg_shading.p);
+            let n = &(bsdf.dg_shading.nn);
+
+            // Sample direct lighting, which is only clamped once the path
+            // has bounced
+            let mut vertex_groups = LightGroupRadiance::new();
+            let ld = uniform_sample_one_light(
+                scene, renderer, p, n, &wo, isect, ray.ray.time, &bsdf, sample,
+                self.direct.get(bounces), rng, Some(&mut vertex_groups));
+            let max_y = if bounces == 0 { f32::INFINITY } else { self.max_indirect };
+            add_clamped(&mut l, groups.as_deref_mut(), ld, &vertex_groups, beta, max_y);
+
+            if bounces == self.max_depth {
+                break;
+            }
+
+            // Sample the BSDF to pick the path's next direction
+            let flags = self.depths.allowed(&ray.ray.bounces, BxDFType::BSDF_ALL);
+            let bsdf_sample = self.path.get(bounces)
+                .and_then(|o| BSDFSample::from_sample(sample, o, 0))
+                .unwrap_or_else(|| BSDFSample::new(rng));
+            let (wi, pdf, f, ty) = bsdf.sample_f_with_type(&wo, bsdf_sample, flags);
+            if f.is_black() || pdf == 0.0 {
+                break;
+            }
+
+            beta = beta * f * wi.abs_dot(n) / pdf;
+            specular_bounce = ty.contains(BxDFType::BSDF_SPECULAR);
+
+            // Light that refracts into a translucent surface leaves it again
+            // somewhere nearby, so the path continues from there instead
+            let bssrdf = if ty.contains(BxDFType::BSDF_TRANSMISSION) {
+                isect.get_bssrdf(&ray)
+            } else { None };
+            let mut next = match (bssrdf, isect.material()) {
+                (Some(bssrdf), Some(material)) => {
+                    let mut exit_groups = LightGroupRadiance::new();
+                    let exit = self.sample_subsurface(
+                        scene, renderer, &bssrdf, &bsdf, material, &ray, sample, rng,
+                        Some(&mut exit_groups));
+                    let (pi, wi, weight, ld) = if let Some(e) = exit { e } else { break };
+                    add_clamped(&mut l, groups.as_deref_mut(), ld, &exit_groups, beta,
+                                self.max_indirect);
+                    beta = beta * weight;
+                    specular_bounce = false;
+                    pi.spawn_ray(&wi)
+                },
+                _ => {
+                    if ty.contains(BxDFType::BSDF_SPECULAR | BxDFType::BSDF_TRANSMISSION) {
+                        let eta = bsdf.relative_eta(&wo);
+                        eta_scale *= eta * eta;
+                    }
+                    isect.spawn_ray(&wi)
+                }
+            };
+
+            next.ray.time = ray.ray.time;
+            next.ray.depth = ray.ray.depth + 1;
+            next.ray.bounces = ray.ray.bounces;
+            next.ray.bounces.add(ty);
+            next.ray.wavelength = ray.ray.wavelength;
+            ray = next;
+
+            bounces += 1;
+            if bounces > self.roulette_depth {
+                match roulette(&beta, eta_scale, rng.random_float()) {
+                    Some(s) => beta = beta * s,
+                    None => break
+                }
+            }
+
+            // Find the next vertex of the path, or the lights that it sees
+            // if it leaves the scene
+            beta = beta * renderer.transmittance(scene, &ray, sample, rng);
+            match scene.intersect(&ray.ray) {
+                Some(hit) => next_isect = Some(hit),
+                None => {
+                    if specular_bounce {
+                        let mut escaped_groups = LightGroupRadiance::new();
+                        let le = escaped_radiance(scene, &ray, Some(&mut escaped_groups));
+                        add_clamped(&mut l, groups, le, &escaped_groups, beta,
+                                    self.max_indirect);
+                    }
+                    break;
+                }
+            }
+        }
+
+        l
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::point::Point;
+    use crate::integrator::tests::NoVolumeRenderer;
+    use crate::light::Light;
+    use crate::light::diffuse::DiffuseAreaLight;
+    use crate::light::point::PointLight;
+    use crate::primitive::Primitive;
+    use crate::shape::Shape;
+    use crate::texture::ConstantTexture;
+    use crate::transform::transform::Transform;
+
+    use std::f32::consts::PI;
+    use std::sync::Arc;
+
+    fn matte(kd: f32) -> Arc<Material> {
+        Arc::new(Material::matte(Arc::new(ConstantTexture::new(Spectrum::from(kd))),
+                                 Arc::new(ConstantTexture::new(0.0)), None))
+    }
+
+    fn li(integrator: &PathIntegrator, scene: &Scene, o: Point, d: Vector,
+          rng: &mut RNG) -> Spectrum {
+        let ray = RayDifferential::new_with(o, d, 0.0);
+        let mut isect = scene.intersect(&ray.ray).unwrap();
+        integrator.li(scene, &NoVolumeRenderer, &ray, &mut isect, &Sample::empty(), rng, None)
+    }
+
+    #[test]
+    fn roulette_is_unbiased() {
+        // Bright paths always survive
+        assert_eq!(roulette(&Spectrum::from(2.0), 1.0, 0.0), Some(1.0));
+
+        // Dim ones survive with probability equal to their throughput, and
+        // are scaled up by its inverse
+        let beta = Spectrum::from_rgb([0.1, 0.3, 0.2]);
+        let n = 1000;
+        let expected = (0..n).map(|i| {
+            let u = ((i as f32) + 0.5) / (n as f32);
+            roulette(&beta, 1.0, u).unwrap_or(0.0)
+        }).sum::<f32>() / (n as f32);
+        assert!((expected - 1.0).abs() < 1e-2);
+        assert!(roulette(&beta, 1.0, 0.69).is_none());
+        assert!((roulette(&beta, 1.0, 0.71).unwrap() - 1.0 / 0.3).abs() < 1e-4);
+
+        // Paths that refracted into glass aren't penalized for it
+        assert_eq!(roulette(&beta, 1.5 * 1.5 / 0.3, 0.0), Some(1.0));
+
+        // Even paths that carry almost all of their light may be culled
+        assert!(roulette(&Spectrum::from(0.99), 1.0, 0.01).is_none());
+    }
+
+    #[test]
+    fn it_only_sees_direct_light_on_convex_objects() {
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+        let light: Arc<dyn Light> = Arc::new(PointLight::new(
+            Transform::translate(&Vector::new_with(12.0, 0.0, 0.0)),
+            Spectrum::from(4.0)));
+        let scene = Scene::new_with(
+            Arc::new(Primitive::geometric(sphere, matte(0.5))), vec![light], None);
+
+        let integrator = PathIntegrator::new(5, 3);
+        let mut rng = RNG::new(0);
+        for _ in 0..16 {
+            let l = li(&integrator, &scene, Point::new_with(5.0, 0.0, 0.0),
+                       Vector::new_with(-1.0, 0.0, 0.0), &mut rng);
+            assert!((l.y() - 0.5 / PI * 4.0 / 121.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn it_scatters_light_below_translucent_surfaces() {
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+        let spectrum = |v: f32| Arc::new(ConstantTexture::new(Spectrum::from(v)));
+        let marble = Arc::new(Material::subsurface(
+            1.0, spectrum(0.0), spectrum(0.5), spectrum(20.0), 1.33, None));
+        let light: Arc<dyn Light> = Arc::new(PointLight::new(
+            Transform::translate(&Vector::new_with(12.0, 0.0, 0.0)),
+            Spectrum::from(4.0)));
+        let scene = Scene::new_with(
+            Arc::new(Primitive::geometric(sphere, marble)), vec![light], None);
+
+        // Without any specular reflection, all of the light that reaches the
+        // eye has scattered below the surface
+        let integrator = PathIntegrator::new(5, 3);
+        let mut rng = RNG::new(0);
+        let total = (0..256).fold(Spectrum::from(0.0), |acc, _| {
+            acc + li(&integrator, &scene, Point::new_with(5.0, 0.0, 0.0),
+                     Vector::new_with(-1.0, 0.0, 0.0), &mut rng)
+        });
+        let avg = total.y() / 256.0;
+        assert!(avg.is_finite() && avg > 0.0, "Average radiance: {}", avg);
+
+        // It can't reflect more light than a white diffuse surface would
+        assert!(avg < 1.0 / PI * 4.0 / 121.0, "Average radiance: {}", avg);
+    }
+
+    #[test]
+    fn it_splits_light_groups_along_specular_paths() {
+        use crate::bsdf::fresnel::IndexOfRefraction;
+        use crate::light::LightBuilder;
+        use crate::light::sunsky::SunSkyLight;
+
+        let sky: Arc<dyn Light> = Arc::new(
+            SunSkyLight::new(Transform::new(), Vector::new_with(0.0, 0.0, 1.0), 3.0,
+                             Spectrum::from(0.0), Spectrum::from(1.0))
+                .with_group("sky"));
+
+        // The sky is only seen through a clear glass ball
+        let xf = Transform::translate(&Vector::new_with(3.0, 0.0, 3.0));
+        let sphere = Shape::sphere(xf.clone(), xf.inverse(), false, 1.0, -1.0, 1.0, 360.0);
+        let glass = Material::glass(Arc::new(ConstantTexture::new(Spectrum::from(0.0))),
+                                    Arc::new(ConstantTexture::new(Spectrum::from(1.0))),
+                                    IndexOfRefraction::Constant(1.5), None);
+        let scene = Scene::new_with(
+            Arc::new(Primitive::geometric(sphere, Arc::new(glass))), vec![sky], None);
+
+        let ray = RayDifferential::new_with(Point::new(), Vector::new_with(1.0, 0.0, 1.0), 0.0);
+        let mut isect = scene.intersect(&ray.ray).unwrap();
+        let mut groups = LightGroupRadiance::new();
+        let l = PathIntegrator::new(5, 3).li(&scene, &NoVolumeRenderer, &ray, &mut isect,
+                                             &Sample::empty(), &mut RNG::new(0),
+                                             Some(&mut groups));
+        assert!(!l.is_black());
+
+        let split: Vec<_> = groups.iter().collect();
+        assert_eq!(split.len(), 1);
+        assert_eq!(split[0].0, "sky");
+        assert!((split[0].1.y() - l.y()).abs() < 1e-4 * l.y());
+    }
+
+    #[test]
+    fn it_converges_inside_of_a_glowing_furnace() {
+        // Inside of a closed sphere that emits radiance e and reflects a
+        // fraction a of the light that reaches it, the radiance is the sum
+        // of e * a^k over all bounces, i.e. e / (1 - a).
+        let xf = Transform::new();
+        let sphere = Shape::sphere(xf.clone(), xf.clone(), true, 1.0, -1.0, 1.0, 360.0);
+        let light = Arc::new(DiffuseAreaLight::new(xf, Spectrum::from(1.0), 1, sphere.clone()));
+        let prim = Primitive::geometric_area_light(sphere, matte(0.5), light.clone());
+        let scene = Scene::new_with(Arc::new(prim), vec![light], None);
+
+        let integrator = PathIntegrator::new(100, 3);
+        let mut rng = RNG::new(0);
+        let num_samples = 4096;
+        let total = (0..num_samples).fold(0.0, |acc, i| {
+            let phi = 2.0 * PI * (i as f32) / (num_samples as f32);
+            let d = Vector::new_with(phi.cos(), phi.sin(), 0.3);
+            acc + li(&integrator, &scene, Point::new(), d, &mut rng).y()
+        });
+
+        let avg = total / (num_samples as f32);
+        assert!((avg - 2.0).abs() < 0.05 * 2.0, "Average radiance: {}", avg);
+
+        // Limiting the depth loses the light from later bounces
+        let shallow = PathIntegrator::new(0, 3);
+        let l = li(&shallow, &scene, Point::new(), Vector::new_with(0.0, 0.0, 1.0), &mut rng);
+        assert!(l.y() < 2.0);
+    }
+
+    #[test]
+    fn light_groups_follow_every_bounce() {
+        use crate::light::LightBuilder;
+
+        let xf = Transform::new();
+        let sphere = Shape::sphere(xf.clone(), xf.clone(), true, 1.0, -1.0, 1.0, 360.0);
+        let light = Arc::new(DiffuseAreaLight::new(xf, Spectrum::from(1.0), 1, sphere.clone())
+                             .with_group("glow"));
+        let prim = Primitive::geometric_area_light(sphere, matte(0.5), light.clone());
+        let scene = Scene::new_with(Arc::new(prim), vec![light], None);
+
+        // Tracking groups doesn't change the radiance, and all of it comes
+        // from the one grouped light, even once it's clamped
+        for integrator in [PathIntegrator::new(10, 3),
+                           PathIntegrator::new(10, 3).with_indirect_clamp(0.1)] {
+            for i in 0..16 {
+                let phi = 2.0 * PI * (i as f32) / 16.0;
+                let ray = RayDifferential::new_with(
+                    Point::new(), Vector::new_with(phi.cos(), phi.sin(), 0.3), 0.0);
+                let mut isect = scene.intersect(&ray.ray).unwrap();
+                let l = integrator.li(&scene, &NoVolumeRenderer, &ray, &mut isect,
+                                      &Sample::empty(), &mut RNG::new(i), None);
+
+                let mut groups = LightGroupRadiance::new();
+                let grouped = integrator.li(&scene, &NoVolumeRenderer, &ray, &mut isect,
+                                            &Sample::empty(), &mut RNG::new(i),
+                                            Some(&mut groups));
+                assert_eq!(l, grouped);
+
+                let split: Vec<_> = groups.iter().collect();
+                assert_eq!(split.len(), 1);
+                assert_eq!(split[0].0, "glow");
+                assert!((split[0].1.y() - l.y()).abs() < 1e-4 * l.y());
+            }
+        }
+    }
+}
@@ -0,0 +1,519 @@
+use std::sync::Arc;
+
+use crate::camera::Camera;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Dot;
+use crate::light::Light;
+use crate::light::LightSample;
+use crate::ray::Ray;
+use crate::ray::RayDifferential;
+use crate::renderer::Renderer;
+use crate::rng::RNG;
+use crate::sampler::Sampler;
+use crate::sampler::sample::Sample;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::volume::VolumeRegion;
+use crate::volume::majorant::MajorantGrid;
+
+// Once the transmittance along a ray drops below this value, further steps
+// are subject to russian roulette.
+const ROULETTE_TRANSMITTANCE: f32 = 1e-3;
+const ROULETTE_CONTINUE_PROB: f32 = 0.5;
+
+// The default resolution of the grid that bounds the density for adaptive
+// steps
+const MAJORANT_GRID_RES: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct SingleScatteringIntegrator {
+    step_size: f32,
+    equiangular: bool,
+    cfl: Option<f32>,
+    majorant_res: usize,
+    majorants: Option<Arc<MajorantGrid>>
+}
+
+impl SingleScatteringIntegrator {
+    // When equiangular is set, light scattered from point and spot lights is
+    // estimated by sampling distances along the ray proportionally to the
+    // inverse squared distance to the light (Kulla and Fajardo 2012) rather
+    // than at the ray marching steps. This concentrates samples near lights
+    // that sit inside of the medium, where most of the scattering happens.
+    pub fn new(step_size: f32, equiangular: bool) -> SingleScatteringIntegrator {
+        SingleScatteringIntegrator {
+            step_size,
+            equiangular,
+            cfl: None,
+            majorant_res: MAJORANT_GRID_RES,
+            majorants: None
+        }
+    }
+
+    // Rather than taking steps of a fixed size, adapt them to the density
+    // so that no step is optically thicker than cfl. Steps get shorter
+    // where the density changes quickly and skip over empty space.
+    pub fn with_cfl(self, cfl: f32) -> SingleScatteringIntegrator {
+        SingleScatteringIntegrator { cfl: Some(cfl), ..self }
+    }
+
+    // Finer grids skip more empty space, but take longer to cross
+    pub fn with_majorant_res(self, majorant_res: usize) -> SingleScatteringIntegrator {
+        SingleScatteringIntegrator { majorant_res, ..self }
+    }
+
+    pub fn preprocess(&mut self, scene: &Scene, _: &Camera) {
+        self.build_majorants(scene);
+    }
+
+    fn build_majorants(&mut self, scene: &Scene) {
+        self.majorants = match (self.cfl, scene.volume_region()) {
+            (Some(_), Some(vr)) if !vr.world_bound().empty() =>
+                Some(Arc::new(vr.majorants(self.majorant_res))),
+            _ => None
+        };
+    }
+
+    // The CFL number and majorant grid to adapt steps with, once the grid
+    // has been built for the scene's volume region
+    fn adaptive(&self) -> Option<(f32, &MajorantGrid)> {
+        match (self.cfl, &self.majorants) {
+            (Some(cfl), Some(grid)) => Some((cfl, grid.as_ref())),
+            _ => None
+        }
+    }
+
+    // The parametric length of the step along the ray from t, and whether
+    // the medium is empty over it. Without adaptive steps, the range from
+    // t0 to t1 is split into num_steps equal steps.
+    fn step(&self, ray: &Ray, t: f32, (t0, t1): (f32, f32), num_steps: usize) -> (f32, bool) {
+        match self.adaptive() {
+            Some((cfl, grid)) => {
+                let (dt, empty) = grid.step(ray, t, cfl);
+                (dt.min(t1 - t), empty)
+            },
+            None => ((t1 - t0) / (num_steps as f32), false)
+        }
+    }
+
+    // The ray marching offsets and light samples are drawn from the RNG, so
+    // there is nothing to request from the sampler.
+    pub fn request_samples(&self, _: &Sampler, _: &mut Sample, _: &Scene) { }
+
+    pub fn transmittance<R: Renderer>(
+        &self, scene: &Scene, _: &R, ray: &RayDifferential,
+        _: &Sample, rng: &mut RNG) -> Spectrum {
+        let vr = match scene.volume_region() {
+            Some(vr) => vr,
+            None => return Spectrum::from(1.0)
+        };
+
+        // Shadow rays don't need to be as accurate as camera rays, so take
+        // larger steps along them
+        if let Some((cfl, grid)) = self.adaptive() {
+            let ray = &ray.ray;
+            let (mut t, t1) = match vr.intersect(ray) {
+                Some(ts) => ts,
+                None => return Spectrum::from(1.0)
+            };
+
+            let offset = rng.random_float();
+            let w = -(&ray.d);
+            let mut tau = Spectrum::from(0.0);
+            while t < t1 {
+                let (dt, empty) = grid.step(ray, t, 4.0 * cfl);
+                let dt = dt.min(t1 - t);
+                if !empty {
+                    let p = ray.point_at(t + offset * dt);
+                    tau = tau + vr.sigma_t(&p, &w, ray.time) * (dt * ray.d.length());
+                }
+                t += dt;
+            }
+            return (-tau).exp();
+        }
+
+        let step_size = 4.0 * self.step_size;
+        let tau = vr.tau(&ray.ray, step_size, rng.random_float());
+        (-tau).exp()
+    }
+
+    pub fn li<R: Renderer>(
+        &self, scene: &Scene, renderer: &R, rayd: &RayDifferential,
+        sample: &Sample, rng: &mut RNG, t: &mut Spectrum) -> Spectrum {
+        let ray = &rayd.ray;
+        let (vr, t0, t1) = match scene.volume_region() {
+            Some(vr) => match vr.intersect(ray) {
+                Some((t0, t1)) if t1 > t0 => (vr, t0, t1),
+                _ => {
+                    *t = Spectrum::from(1.0);
+                    return Spectrum::from(0.0);
+                }
+            },
+            None => {
+                *t = Spectrum::from(1.0);
+                return Spectrum::from(0.0);
+            }
+        };
+
+        // Prepare for volume integration stepping
+        let num_samples = ((t1 - t0) / self.step_size).ceil().max(1.0) as usize;
+        let w = -(&ray.d);
+        let lights = scene.lights();
+
+        // Lights that are sampled at the ray marching steps
+        let march_lights: Vec<&Arc<dyn Light>> = lights.iter()
+            .filter(|l| !self.equiangular || l.delta_position().is_none())
+            .collect();
+
+        let mut tr = Spectrum::from(1.0);
+        let mut lv = Spectrum::from(0.0);
+        let mut p = ray.point_at(t0);
+        let offset = rng.random_float();
+        let mut t_step = t0;
+        let mut steps = 0;
+        let adaptive = self.adaptive().is_some();
+        while t_step < t1 && (adaptive || steps < num_samples) {
+            let (step, empty) = self.step(ray, t_step, (t0, t1), num_samples);
+            let t_cur = t_step + offset * step;
+            t_step += step;
+            steps += 1;
+            if empty {
+                continue;
+            }
+
+            // Advance to sample at t_cur and update transmittance
+            let p_prev = p;
+            p = ray.point_at(t_cur);
+            let mut tau_ray = Ray::new_with(p_prev.clone(), &p - &p_prev, 0.0);
+            tau_ray.set_maxt(1.0);
+            tau_ray.set_time(ray.time);
+            tau_ray.set_depth(ray.depth);
+            let tau_step = if adaptive {
+                0.5 * step * ray.d.length()
+            } else {
+                0.5 * self.step_size
+            };
+            let step_tau = vr.tau(&tau_ray, tau_step, rng.random_float());
+            tr = tr * (-step_tau).exp();
+
+            // Possibly terminate ray marching if transmittance is small
+            if tr.y() < ROULETTE_TRANSMITTANCE {
+                if rng.random_float() > ROULETTE_CONTINUE_PROB {
+                    tr = Spectrum::from(0.0);
+                    break;
+                }
+                tr = tr / ROULETTE_CONTINUE_PROB;
+            }
+
+            // Compute single-scattering source term at p
+            let mut ls = vr.l_ve(&p, &w, ray.time);
+            let ss = vr.sigma_s(&p, &w, ray.time);
+            if !ss.is_black() && !march_lights.is_empty() {
+                // Pick a single light to sample uniformly
+                let num_lights = march_lights.len();
+                let light_num = ((rng.random_float() * (num_lights as f32)) as usize)
+                    .min(num_lights - 1);
+                let light = march_lights[light_num];
+
+                // Add contribution of light due to scattering at p
+                let (l, wo, pdf, visibility) =
+                    light.sample_l(&p, LightSample::new(rng), ray.time);
+                if !l.is_black() && pdf > 0.0 && visibility.unoccluded(scene) {
+                    let ld = l * visibility.transmittance(scene, renderer, sample, rng);
+                    ls = ls + ss * vr.p(&p, &w, &(-wo), ray.time) * ld *
+                        (num_lights as f32) / pdf;
+                }
+            }
+
+            lv = lv + tr * ls * step;
+        }
+
+        *t = tr;
+
+        // Add light scattered from point lights with equiangular sampling
+        if self.equiangular {
+            for light in lights.iter() {
+                if let Some(light_pos) = light.delta_position() {
+                    lv = lv + self.equiangular_in_scatter(
+                        scene, renderer, vr.as_ref(), light.as_ref(), &light_pos,
+                        ray, t0, t1, sample, rng);
+                }
+            }
+        }
+
+        lv
+    }
+
+    // Estimates the light from a point light at light_pos that scatters
+    // towards the ray origin over the range [t0, t1] of the ray, using a
+    // single equiangular distance sample.
+    fn equiangular_in_scatter<R: Renderer>(
+        &self, scene: &Scene, renderer: &R, vr: &dyn VolumeRegion,
+        light: &dyn Light, light_pos: &Point, ray: &Ray, t0: f32, t1: f32,
+        sample: &Sample, rng: &mut RNG) -> Spectrum {
+        // Work in world space distances along the ray
+        let len = ray.d.length();
+        let dir = ray.d.clone().normalize();
+        let (d0, d1) = (t0 * len, t1 * len);
+
+        // Find the distance along the ray to the point closest to the light
+        // and the distance of the light from the ray
+        let delta = (light_pos - &ray.o).dot(&dir);
+        let h = light_pos.distance(&(&ray.o + &dir * delta));
+        if h == 0.0 {
+            return Spectrum::from(0.0);
+        }
+
+        // Sample a distance with density proportional to the inverse squared
+        // distance to the light
+        let theta_a = ((d0 - delta) / h).atan();
+        let theta_b = ((d1 - delta) / h).atan();
+        let theta = theta_a + rng.random_float() * (theta_b - theta_a);
+        let dist = delta + h * theta.tan();
+        let pdf = h / ((theta_b - theta_a) * (h * h + (dist - delta) * (dist - delta)));
+        if pdf <= 0.0 || !pdf.is_finite() {
+            return Spectrum::from(0.0);
+        }
+
+        let p = &ray.o + &dir * dist;
+        let w = -(&dir);
+        let ss = vr.sigma_s(&p, &w, ray.time);
+        if ss.is_black() {
+            return Spectrum::from(0.0);
+        }
+
+        // Compute transmittance from the start of the medium to p
+        let mut tr_ray = Ray::new_with(ray.o.clone(), dir.clone(), d0);
+        tr_ray.set_maxt(dist);
+        tr_ray.set_time(ray.time);
+        let tr = (-vr.tau(&tr_ray, self.step_size, rng.random_float())).exp();
+
+        // Add contribution of light due to scattering at p
+        let (l, wo, light_pdf, visibility) =
+            light.sample_l(&p, LightSample::new(rng), ray.time);
+        if l.is_black() || light_pdf == 0.0 || !visibility.unoccluded(scene) {
+            return Spectrum::from(0.0);
+        }
+
+        let ld = l * visibility.transmittance(scene, renderer, sample, rng);
+        tr * ss * vr.p(&p, &w, &(-wo), ray.time) * ld / (light_pdf * pdf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::bbox::BBox;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::integrator::tests::NoVolumeRenderer;
+    use crate::light::Light;
+    use crate::light::point::PointLight;
+    use crate::primitive::Primitive;
+    use crate::shape::Shape;
+    use crate::transform::transform::Transform;
+    use crate::volume::VolumeRegion;
+    use crate::volume::homogeneous::HomogeneousVolumeDensity;
+
+    // A unit cube of homogeneous media, with the scene geometry far away
+    fn make_scene(sig_a: f32, sig_s: f32, le: f32,
+                  lights: Vec<Arc<dyn Light>>) -> Scene {
+        let far = Vector::new_with(100.0, 100.0, 100.0);
+        let sphere = Primitive::simple(Shape::sphere(
+            Transform::translate(&far), Transform::translate(&(-far)),
+            false, 1.0, -1.0, 1.0, 360.0));
+        let vr: Arc<dyn VolumeRegion> = Arc::new(HomogeneousVolumeDensity::new(
+            Spectrum::from(sig_a), Spectrum::from(sig_s), 0.0, Spectrum::from(le),
+            BBox::new_with(Point::new_with(0.0, 0.0, 0.0),
+                           Point::new_with(1.0, 1.0, 1.0)),
+            Transform::new()));
+        Scene::new_with(Arc::new(sphere), lights, Some(vr))
+    }
+
+    fn ray_through_cube() -> RayDifferential {
+        RayDifferential::new_with(Point::new_with(-1.0, 0.5, 0.5),
+                                  Vector::new_with(1.0, 0.0, 0.0), 0.0)
+    }
+
+    #[test]
+    fn it_attenuates_rays_passing_through_media() {
+        let scene = make_scene(1.0, 1.0, 0.0, vec![]);
+        let integrator = SingleScatteringIntegrator::new(0.01, false);
+        let mut rng = RNG::new(0);
+        let sample = Sample::empty();
+
+        let mut t = Spectrum::from(0.0);
+        let lv = integrator.li(&scene, &NoVolumeRenderer, &ray_through_cube(),
+                               &sample, &mut rng, &mut t);
+        assert!(lv.is_black());
+        assert!((t.y() - (-2.0f32).exp()).abs() < 1e-3);
+
+        let tr = integrator.transmittance(&scene, &NoVolumeRenderer,
+                                          &ray_through_cube(), &sample, &mut rng);
+        assert!((tr.y() - (-2.0f32).exp()).abs() < 1e-3);
+
+        // Rays that miss the medium aren't attenuated
+        let miss = RayDifferential::new_with(Point::new_with(-1.0, 2.0, 0.5),
+                                             Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        let lv = integrator.li(&scene, &NoVolumeRenderer, &miss, &sample,
+                               &mut rng, &mut t);
+        assert!(lv.is_black());
+        assert_eq!(t.y(), 1.0);
+    }
+
+    #[test]
+    fn it_accumulates_attenuated_emission() {
+        // The emission seen through an absorbing medium of thickness d is
+        // le * (1 - exp(-sigma_a * d)) / sigma_a
+        let scene = make_scene(1.0, 0.0, 1.0, vec![]);
+        let integrator = SingleScatteringIntegrator::new(0.001, false);
+        let mut rng = RNG::new(0);
+
+        let mut t = Spectrum::from(0.0);
+        let lv = integrator.li(&scene, &NoVolumeRenderer, &ray_through_cube(),
+                               &Sample::empty(), &mut rng, &mut t);
+        let expected = 1.0 - (-1.0f32).exp();
+        assert!((lv.y() - expected).abs() < 1e-2, "Lv: {}", lv.y());
+    }
+
+    #[test]
+    fn it_scatters_light_towards_the_camera() {
+        let light: Arc<dyn Light> = Arc::new(PointLight::new(
+            Transform::translate(&Vector::new_with(0.5, 3.0, 0.5)),
+            Spectrum::from(1.0)));
+        let lit = make_scene(0.0, 0.5, 0.0, vec![light]);
+        let dark = make_scene(0.0, 0.5, 0.0, vec![]);
+        let integrator = SingleScatteringIntegrator::new(0.01, false);
+        let mut rng = RNG::new(0);
+        let mut t = Spectrum::from(0.0);
+
+        let lv = integrator.li(&dark, &NoVolumeRenderer, &ray_through_cube(),
+                               &Sample::empty(), &mut rng, &mut t);
+        assert!(lv.is_black());
+
+        let lv = integrator.li(&lit, &NoVolumeRenderer, &ray_through_cube(),
+                               &Sample::empty(), &mut rng, &mut t);
+        assert!(lv.y() > 0.0);
+    }
+
+    #[test]
+    fn it_adapts_its_steps_to_the_density() {
+        use crate::volume::aggregate::AggregateVolumeRegion;
+
+        // A small glowing puff of smoke in a large empty region
+        let cube = |sig_a: f32, le: f32, p_min: f32, p_max: f32| {
+            let vr: Arc<dyn VolumeRegion> = Arc::new(HomogeneousVolumeDensity::new(
+                Spectrum::from(sig_a), Spectrum::from(0.0), 0.0, Spectrum::from(le),
+                BBox::new_with(Point::new_with(p_min, p_min, p_min),
+                               Point::new_with(p_max, p_max, p_max)),
+                Transform::new()));
+            vr
+        };
+        let vr: Arc<dyn VolumeRegion> = Arc::new(AggregateVolumeRegion::new(
+            vec![cube(0.0, 0.0, 0.0, 4.0), cube(2.0, 1.0, 0.5, 1.0)]));
+        let far = Vector::new_with(100.0, 100.0, 100.0);
+        let sphere = Primitive::simple(Shape::sphere(
+            Transform::translate(&far), Transform::translate(&(-far)),
+            false, 1.0, -1.0, 1.0, 360.0));
+        let scene = Scene::new_with(Arc::new(sphere), vec![], Some(vr));
+
+        let mut integrator = SingleScatteringIntegrator::new(0.01, false).with_cfl(0.1);
+        integrator.build_majorants(&scene);
+        assert!(integrator.adaptive().is_some());
+
+        let ray = RayDifferential::new_with(Point::new_with(-1.0, 0.75, 0.75),
+                                            Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        let mut rng = RNG::new(0);
+        let mut t = Spectrum::from(0.0);
+        let lv = integrator.li(&scene, &NoVolumeRenderer, &ray, &Sample::empty(),
+                               &mut rng, &mut t);
+        assert!((t.y() - (-1.0f32).exp()).abs() < 1e-2, "T: {}", t.y());
+        let expected = 0.5 * (1.0 - (-1.0f32).exp());
+        assert!((lv.y() - expected).abs() < 1e-2, "Lv: {}", lv.y());
+
+        let tr = integrator.transmittance(&scene, &NoVolumeRenderer, &ray,
+                                          &Sample::empty(), &mut rng);
+        assert!((tr.y() - (-1.0f32).exp()).abs() < 2e-2, "Tr: {}", tr.y());
+    }
+
+    #[test]
+    fn it_marches_voxel_grids_like_the_brute_force_march() {
+        use crate::volume::density::volume_grid::VolumeGridDensity;
+
+        // A glowing ball of smoke that fills only part of its voxel grid
+        let n = 16;
+        let d = (0..(n * n * n)).map(|i| {
+            let (x, y, z) = (i % n, (i / n) % n, i / (n * n));
+            let c = |v: usize| ((v as f32) + 0.5) / (n as f32) - 0.5;
+            let r = (c(x) * c(x) + c(y) * c(y) + c(z) * c(z)).sqrt();
+            (5.0 * (0.25 - r)).max(0.0)
+        }).collect();
+        let vr: Arc<dyn VolumeRegion> = Arc::new(VolumeGridDensity::new(
+            Spectrum::from(2.0), Spectrum::from(0.0), 0.0, Spectrum::from(1.0),
+            BBox::new_with(Point::new_with(0.0, 0.0, 0.0), Point::new_with(1.0, 1.0, 1.0)),
+            Transform::rotate_y(20.0), n, n, n, d));
+        let far = Vector::new_with(100.0, 100.0, 100.0);
+        let sphere = Primitive::simple(Shape::sphere(
+            Transform::translate(&far), Transform::translate(&(-far)),
+            false, 1.0, -1.0, 1.0, 360.0));
+        let scene = Scene::new_with(Arc::new(sphere), vec![], Some(vr));
+
+        // Pass right through the middle of the ball, which the rotation
+        // moved to (0.64, 0.5, 0.3)
+        let ray = RayDifferential::new_with(Point::new_with(-1.0, 0.5, 0.3),
+                                            Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        let march = |integrator: &SingleScatteringIntegrator| {
+            let mut rng = RNG::new(0);
+            let mut t = Spectrum::from(0.0);
+            let lv = integrator.li(&scene, &NoVolumeRenderer, &ray, &Sample::empty(),
+                                   &mut rng, &mut t);
+            (lv.y(), t.y())
+        };
+
+        let (lv, t) = march(&SingleScatteringIntegrator::new(0.0005, false));
+        assert!(lv > 0.05 && t < 0.95, "Lv: {}, T: {}", lv, t);
+
+        let mut adaptive = SingleScatteringIntegrator::new(0.0005, false)
+            .with_cfl(0.02).with_majorant_res(4);
+        adaptive.build_majorants(&scene);
+        let (adaptive_lv, adaptive_t) = march(&adaptive);
+        assert!((adaptive_lv - lv).abs() < 0.02 * lv, "Lv: {} vs {}", adaptive_lv, lv);
+        assert!((adaptive_t - t).abs() < 0.02 * t, "T: {} vs {}", adaptive_t, t);
+    }
+
+    #[test]
+    fn it_reduces_variance_with_equiangular_sampling() {
+        // A point light just off of the ray inside the fog
+        let light: Arc<dyn Light> = Arc::new(PointLight::new(
+            Transform::translate(&Vector::new_with(0.5, 0.55, 0.5)),
+            Spectrum::from(1.0)));
+        let scene = make_scene(0.0, 0.5, 0.0, vec![light]);
+
+        let stats = |integrator: SingleScatteringIntegrator| {
+            let mut rng = RNG::new(0);
+            let mut t = Spectrum::from(0.0);
+            let n = 1000;
+            let ys: Vec<f32> = (0..n).map(|_| {
+                integrator.li(&scene, &NoVolumeRenderer, &ray_through_cube(),
+                              &Sample::empty(), &mut rng, &mut t).y()
+            }).collect();
+            let mean = ys.iter().sum::<f32>() / (n as f32);
+            let var = ys.iter().map(|y| (y - mean) * (y - mean)).sum::<f32>() /
+                ((n - 1) as f32);
+            (mean, var)
+        };
+
+        // Take large steps so that ray marching only gets a handful of
+        // light samples along the ray
+        let (march_mean, march_var) = stats(SingleScatteringIntegrator::new(0.25, false));
+        let (eq_mean, eq_var) = stats(SingleScatteringIntegrator::new(0.25, true));
+
+        assert!((march_mean - eq_mean).abs() < 0.05 * eq_mean,
+                "Ray marching: {}, equiangular: {}", march_mean, eq_mean);
+        assert!(eq_var < 0.1 * march_var,
+                "Ray marching variance: {}, equiangular variance: {}", march_var, eq_var);
+    }
+}
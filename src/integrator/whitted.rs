@@ -1,78 +1,123 @@
-use bsdf::BxDFType;
-use geometry::vector::Dot;
-use integrator::Integrator;
-use integrator::SurfaceIntegrator;
-use intersection::Intersection;
-use light::LightSample;
-use ray::RayDifferential;
-use renderer::Renderer;
-use rng::RNG;
-use sampler::sample::Sample;
-use scene::Scene;
-use spectrum::Spectrum;
+use crate::bsdf::BSDFSample;
+use crate::bsdf::BxDFType;
+use crate::integrator::BounceDepths;
+use crate::integrator::Integrator;
+use crate::integrator::SurfaceIntegrator;
+use crate::intersection::Intersection;
+use crate::light::LightSample;
+use crate::ray::RayDifferential;
+use crate::renderer::Renderer;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
 
-use integrator::specular_reflect;
-use integrator::specular_transmit;
+use crate::integrator::add_clamped;
+use crate::integrator::DirectLightingOffsets;
+use crate::integrator::emitted_radiance;
+use crate::integrator::select_wavelength;
+use crate::integrator::specular_reflect;
+use crate::integrator::specular_transmit;
+use crate::integrator::LightGroupRadiance;
+use crate::integrator::uniform_sample_all_lights_grouped;
 
 #[derive(Clone, Debug)]
 pub struct WhittedIntegrator {
     // WhittedIntegrator Private Data
     max_depth: usize,
+    depths: BounceDepths,
+    max_indirect: f32,
+    direct: DirectLightingOffsets
 }
 
 impl WhittedIntegrator {
     pub fn new(d: usize) -> WhittedIntegrator {
         WhittedIntegrator {
-            max_depth: d
+            max_depth: d,
+            depths: BounceDepths::unlimited(),
+            max_indirect: f32::INFINITY,
+            direct: DirectLightingOffsets::default()
         }
     }
 
-    fn li<R : Renderer>(&self, scene: &Scene,
+    pub fn with_bounce_depths(self, depths: BounceDepths) -> WhittedIntegrator {
+        WhittedIntegrator { depths, ..self }
+    }
+
+    pub fn with_indirect_clamp(self, max_indirect: f32) -> WhittedIntegrator {
+        WhittedIntegrator { max_indirect, ..self }
+    }
+
+    pub fn request_samples(&mut self, sampler: &Sampler, sample: &mut Sample,
+                           scene: &Scene) {
+        self.direct = DirectLightingOffsets::all_lights(sampler, sample, scene);
+    }
+
+    pub fn li<R : Renderer>(&self, scene: &Scene,
                         renderer: &R,
                         rayd: &RayDifferential,
                         isect: &mut Intersection,
                         sample: &Sample,
-                        rng: &mut RNG) -> Spectrum {
+                        rng: &mut RNG,
+                        groups: Option<&mut LightGroupRadiance>) -> Spectrum {
         // Compute emitted and reflected light at ray intersection point
         // Evaluate BSDF at hit point
-        let ray = &rayd.ray;
-        let bsdf = if let Some(b) = isect.get_bsdf(rayd) { b } else {
+        let mut bsdf = if let Some(b) = isect.get_bsdf(rayd) { b } else {
             return Spectrum::from(0.0)
         };
 
+        // Paths through dispersive surfaces follow a single wavelength
+        let mut rayd = rayd.clone();
+        let weight = select_wavelength(&mut rayd, &mut bsdf, rng);
+        let rayd = &rayd;
+        let ray = &rayd.ray;
+
         // Initialize common variables for Whitted Integrator
         let p = &(bsdf.dg_shading.p);
         let n = &(bsdf.dg_shading.nn);
         let wo = -(&ray.d);
 
-        // Compute emitted light if ray hit an area light source
-        let l = scene.lights().iter().fold(isect.le(&wo), |l_acc, ref light| {
+        // Compute emitted light if ray hit an area light source, and add
+        // the contribution of each light source. The light groups' shares
+        // are gathered in split and weighted along with l at the end.
+        let track = groups.is_some();
+        let mut split = LightGroupRadiance::new();
+        let le = emitted_radiance(isect, &wo, ray.time, track.then_some(&mut split));
+        let l = le + uniform_sample_all_lights_grouped(
+            scene, renderer, p, n, &wo, isect, ray.time, &bsdf,
+            sample, Some(&self.direct), rng, track.then_some(&mut split));
 
-            // Add contribution of each light source
-            let (li, wi, pdf, visibility) =
-                light.sample_l(p, isect.ray_epsilon,
-                               LightSample::new(rng), ray.time.clone());
-            if li.is_black() || pdf == 0f32 { l_acc }
-            else {
-                let f = bsdf.f(wo.clone(), wi.clone(), BxDFType::BSDF_ALL);
-                if f.is_black() || !visibility.unoccluded(scene) { l_acc }
-                else {
-                    l_acc +
-                        f * li * wi.abs_dot(n) *
-                        visibility.transmittance(scene, renderer, sample, rng) / pdf
-                }
+        if ray.depth + 1 >= self.max_depth {
+            if let Some(g) = groups {
+                g.add_scaled(&split, Spectrum::from(1.0));
             }
-        });
+            return l;
+        }
+
+        // Trace rays for specular reflection and refraction
+        let mut l = l;
+        let refl_flags = BxDFType::BSDF_REFLECTION | BxDFType::BSDF_SPECULAR;
+        if self.depths.allows(&ray.bounces, refl_flags) {
+            let mut refl_groups = LightGroupRadiance::new();
+            let refl = specular_reflect(rayd, &bsdf, rng, isect, renderer, scene,
+                                        sample, track.then_some(&mut refl_groups));
+            add_clamped(&mut l, track.then_some(&mut split), refl, &refl_groups,
+                        Spectrum::from(1.0), self.max_indirect);
+        }
 
-        l + (
-            if ray.depth + 1 < self.max_depth {
-                // Trace rays for specular reflection and refraction
-                let refl = specular_reflect(rayd, &bsdf, rng, isect,
-                                            renderer, scene, sample);
-                let tmit = specular_transmit(rayd, &bsdf, rng, isect,
-                                             renderer, scene, sample);
-                refl + tmit
-            } else { Spectrum::from(0f32) }
-        )
+        let trans_flags = BxDFType::BSDF_TRANSMISSION | BxDFType::BSDF_SPECULAR;
+        if self.depths.allows(&ray.bounces, trans_flags) {
+            let mut tmit_groups = LightGroupRadiance::new();
+            let tmit = specular_transmit(rayd, &bsdf, rng, isect, renderer, scene,
+                                         sample, track.then_some(&mut tmit_groups));
+            add_clamped(&mut l, track.then_some(&mut split), tmit, &tmit_groups,
+                        Spectrum::from(1.0), self.max_indirect);
+        }
+
+        if let Some(g) = groups {
+            g.add_scaled(&split, weight);
+        }
+        l * weight
     }
 }
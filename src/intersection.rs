@@ -1,48 +1,72 @@
 use std::sync::Arc;
 
-use bsdf::BSDF;
-use bsdf::bssrdf::BSSRDF;
-use diff_geom::DifferentialGeometry;
-use geometry::normal::Normal;
-use geometry::vector::Vector;
-use primitive::Primitive;
-use ray::Ray;
-use ray::RayDifferential;
-use spectrum::Spectrum;
-use transform::transform::Transform;
+use crate::bsdf::BSDF;
+use crate::bsdf::bssrdf::BSSRDF;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::normal::Normal;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::material::Material;
+use crate::primitive::Primitive;
+use crate::ray::Ray;
+use crate::ray::RayDifferential;
+use crate::ray::offset_ray_origin;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::Transform;
 
 #[derive(Debug)]
 pub struct Intersection {
     pub dg: DifferentialGeometry,
     pub primitive: Option<Arc<Primitive>>, // !FIXME! This shouldn't be an option.
+    // Set by instances whose material replaces the primitive's own
+    pub material: Option<Arc<Material>>,
     pub world_to_object: Transform,
     pub object_to_world: Transform,
     pub shape_id: usize,
     pub primitive_id: usize,
-    pub ray_epsilon: f32,
+    // The bound on the error of dg.p in each dimension
+    pub p_error: Vector,
 }
 
 impl Intersection {
     pub fn new_with(_dg: DifferentialGeometry, w2o: Transform,
                     o2w: Transform, sid: usize, pid: usize,
-                    ray_eps: f32) -> Intersection {
+                    p_error: Vector) -> Intersection {
         Intersection {
             dg: _dg.clone(),
             primitive: None,
+            material: None,
             world_to_object: w2o,
             object_to_world: o2w,
             shape_id: sid,
             primitive_id: pid,
-            ray_epsilon: ray_eps,
+            p_error,
         }
     }
 
+    // The origin of a ray leaving the intersection point in direction d,
+    // which is far enough off of the surface that the ray can't hit it again
+    pub fn spawn_origin(&self, d: &Vector) -> Point {
+        offset_ray_origin(&self.dg.p, &self.p_error, &self.dg.nn, d)
+    }
+
+    // Spawns a ray leaving the intersection point in direction d
+    pub fn spawn_ray(&self, d: &Vector) -> RayDifferential {
+        RayDifferential::new_with(self.spawn_origin(d), d.clone(), 0.0)
+    }
+
+    // The material that the intersection point is shaded with
+    pub fn material(&self) -> Option<&Material> {
+        self.material.as_deref().or_else(|| self.primitive.as_ref().map(|p| p.material()))
+    }
+
     pub fn get_bsdf(&self, ray: &RayDifferential) -> Option<BSDF> {
         let mut new_dg = self.dg.clone();
         new_dg.compute_differentials(ray);
         match self.primitive {
             None => None,
-            Some(ref p) => p.get_bsdf(new_dg, &self.object_to_world)
+            Some(ref p) => p.get_bsdf(new_dg, &self.object_to_world,
+                                       self.material.as_deref())
         }
     }
 
@@ -51,11 +75,18 @@ impl Intersection {
         new_dg.compute_differentials(ray);
         match self.primitive {
             None => None,
-            Some(ref p) => p.get_bssrdf(new_dg, &self.object_to_world)
+            Some(ref p) => p.get_bssrdf(new_dg, &self.object_to_world,
+                                         self.material.as_deref())
         }
     }
 
-    pub fn le(&self, dir: &Vector) -> Spectrum { Spectrum::from(0f32) }
+    // The radiance emitted from the intersection point in direction dir at
+    // the given time if it lies on an area light
+    pub fn le(&self, dir: &Vector, time: f32) -> Spectrum {
+        self.primitive.as_ref()
+            .and_then(|p| p.area_light())
+            .map_or(Spectrum::from(0f32), |al| al.l(&self.dg, dir, time))
+    }
 }
 
 pub trait Intersectable<T = Intersection> {
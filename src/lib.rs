@@ -1,9 +1,4 @@
 #![allow(unused)]
-#[macro_use]
-extern crate bitflags;
-extern crate scoped_threadpool;
-#[macro_use]
-extern crate lazy_static;
 
 pub mod area_light;
 pub mod bbox;
@@ -20,6 +15,7 @@ pub mod montecarlo;
 pub mod primitive;
 pub mod params;
 pub mod quaternion;
+pub mod radiance_check;
 pub mod ray;
 pub mod rng;
 pub mod renderer;
@@ -29,6 +25,7 @@ pub mod shape;
 pub mod spectrum;
 pub mod scene;
 pub mod texture;
+pub mod trace;
 pub mod transform;
 pub mod utils;
 pub mod visibility_tester;
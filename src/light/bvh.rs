@@ -0,0 +1,564 @@
+use std::f32::consts::PI;
+
+use crate::bbox::BBox;
+use crate::bbox::Union;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::light::Light;
+
+use std::sync::Arc;
+
+// Number of buckets that light centroids are binned into when looking for
+// the cheapest split of a node
+const NUM_BUCKETS: usize = 12;
+
+fn safe_sqrt(x: f32) -> f32 { x.max(0.0).sqrt() }
+
+// cos(max(0, a - b)) given the sines and cosines of a and b
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b { 1.0 } else { cos_a * cos_b + sin_a * sin_b }
+}
+
+// sin(max(0, a - b)) given the sines and cosines of a and b
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b { 0.0 } else { sin_a * cos_b - cos_a * sin_b }
+}
+
+// Bounds on where a light, or a group of lights, emits from and in which
+// directions. Emission is centered around the directions within theta_o of
+// w, and falls off to nothing theta_e further out. phi is the total power,
+// which sets how important the lights are relative to others.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightBounds {
+    pub bounds: BBox,
+    pub w: Vector,
+    pub phi: f32,
+    pub cos_theta_o: f32,
+    pub cos_theta_e: f32,
+    pub two_sided: bool
+}
+
+impl LightBounds {
+    pub fn new(bounds: BBox, w: Vector, phi: f32, cos_theta_o: f32, cos_theta_e: f32,
+               two_sided: bool) -> LightBounds {
+        LightBounds { bounds, w: w.normalize(), phi, cos_theta_o, cos_theta_e, two_sided }
+    }
+
+    // Bounds for a light that emits from a single point in every direction
+    pub fn point(p: Point, phi: f32) -> LightBounds {
+        LightBounds::new(BBox::from(p), Vector::forward(), phi, -1.0, 0.0, false)
+    }
+
+    pub fn centroid(&self) -> Point {
+        0.5 * (&self.bounds.p_min + &self.bounds.p_max)
+    }
+
+    // The smallest cone that contains the directions of both a and b
+    fn union_cone(&self, b: &LightBounds) -> (Vector, f32) {
+        let theta_a = self.cos_theta_o.clamp(-1.0, 1.0).acos();
+        let theta_b = b.cos_theta_o.clamp(-1.0, 1.0).acos();
+        let theta_d = self.w.dot(&b.w).clamp(-1.0, 1.0).acos();
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return (self.w.clone(), self.cos_theta_o);
+        }
+
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return (b.w.clone(), b.cos_theta_o);
+        }
+
+        let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+        let axis = self.w.cross_with(&b.w);
+        if theta_o >= PI || axis.length_squared() == 0.0 {
+            return (self.w.clone(), -1.0);
+        }
+
+        // Rotate a's axis towards b's so the new cone just touches a's edge
+        let theta_r = theta_o - theta_a;
+        let k = axis.normalize();
+        let w = &self.w * theta_r.cos() + k.cross_with(&self.w) * theta_r.sin();
+        (w.normalize(), theta_o.cos())
+    }
+
+    pub fn union(&self, b: &LightBounds) -> LightBounds {
+        if self.phi == 0.0 { return b.clone(); }
+        if b.phi == 0.0 { return self.clone(); }
+
+        let (w, cos_theta_o) = self.union_cone(b);
+        LightBounds {
+            bounds: self.bounds.union(&b.bounds),
+            w,
+            phi: self.phi + b.phi,
+            cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(b.cos_theta_e),
+            two_sided: self.two_sided || b.two_sided
+        }
+    }
+
+    // Estimates how much the lights contribute to a point p with surface
+    // normal n by bounding the angles between them from below. A zero
+    // normal, e.g. for points in participating media, ignores the cosine
+    // at p.
+    pub fn importance(&self, p: &Point, n: &Normal) -> f32 {
+        let pc = self.centroid();
+        let diag = &self.bounds.p_max - &self.bounds.p_min;
+        let d2 = p.distance_squared(&pc).max(0.5 * diag.length()).max(1e-6);
+
+        // Angle between the cone's axis and the direction towards p
+        let wi = (p - &pc).normalize();
+        let mut cos_theta_w = self.w.dot(&wi);
+        if self.two_sided { cos_theta_w = cos_theta_w.abs(); }
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+
+        // Angle that the bounds subtend as seen from p
+        let cos_theta_b = if self.bounds.inside(p) { -1.0 } else {
+            let (center, radius) = self.bounds.bounding_sphere();
+            let sin2_theta_max = radius * radius / p.distance_squared(&center);
+            if sin2_theta_max >= 1.0 { -1.0 } else { safe_sqrt(1.0 - sin2_theta_max) }
+        };
+        let sin_theta_b = safe_sqrt(1.0 - cos_theta_b * cos_theta_b);
+
+        // Smallest angle between p and any direction the lights emit in
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o * self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p < self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.phi * cos_theta_p / d2;
+        if n.x != 0.0 || n.y != 0.0 || n.z != 0.0 {
+            let cos_theta_i = wi.dot(n).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+
+        importance.max(0.0)
+    }
+
+    // Rough measure of how costly it is to sample lights with these bounds,
+    // used to decide how to split nodes: large, bright lights that emit in
+    // many directions are costly, and the cost along the given axis is
+    // scaled up for boxes that are thin along it.
+    fn cost(&self, extent: &BBox, dim: usize) -> f32 {
+        let theta_o = self.cos_theta_o.clamp(-1.0, 1.0).acos();
+        let theta_e = self.cos_theta_e.clamp(-1.0, 1.0).acos();
+        let theta_w = (theta_o + theta_e).min(PI);
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o * self.cos_theta_o);
+        let m_omega = 2.0 * PI * (1.0 - self.cos_theta_o) +
+            0.5 * PI * (2.0 * theta_w * sin_theta_o - (theta_o - 2.0 * theta_w).cos() -
+                        2.0 * theta_o * sin_theta_o + self.cos_theta_o);
+
+        let diag = &extent.p_max - &extent.p_min;
+        let max_diag = diag.x.max(diag.y).max(diag.z);
+        let k_r = if diag[dim] > 0.0 { max_diag / diag[dim] } else { 1.0 };
+        self.phi * m_omega * k_r * self.bounds.surface_area().max(1e-6)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum LightBVHNode {
+    Leaf {
+        bounds: LightBounds,
+        light: usize
+    },
+    Inner {
+        bounds: LightBounds,
+        second_child_offset: usize
+    }
+}
+
+impl LightBVHNode {
+    fn bounds(&self) -> &LightBounds {
+        match *self {
+            LightBVHNode::Leaf { ref bounds, .. } => bounds,
+            LightBVHNode::Inner { ref bounds, .. } => bounds
+        }
+    }
+}
+
+// A bounding volume hierarchy over the scene's lights that picks lights in
+// proportion to how much they are likely to contribute at a given point,
+// rather than by their power alone. This matters for scenes with many
+// lights where most of them are far away from, or face away from, any one
+// shading point. Lights without bounds, like distant lights, can't be put
+// in the hierarchy and are instead picked uniformly with a fixed share of
+// the probability.
+#[derive(Clone, Debug)]
+pub struct LightBVH {
+    nodes: Vec<LightBVHNode>,
+    infinite_lights: Vec<usize>,
+    // For each light in the hierarchy, the path from the root to its leaf
+    // with one bit per level, set when the path takes the second child
+    light_bits: Vec<Option<(u64, usize)>>
+}
+
+impl LightBVH {
+    pub fn new(lights: &[Arc<dyn Light>]) -> LightBVH {
+        let mut infinite_lights = Vec::new();
+        let mut bounded = Vec::new();
+        for (i, light) in lights.iter().enumerate() {
+            match light.bounds() {
+                Some(b) if b.phi > 0.0 => bounded.push((i, b)),
+                Some(_) => (),
+                None => infinite_lights.push(i)
+            }
+        }
+
+        let mut bvh = LightBVH {
+            nodes: Vec::new(),
+            infinite_lights,
+            light_bits: vec![None; lights.len()]
+        };
+
+        if !bounded.is_empty() {
+            bvh.build(bounded, 0, 0);
+        }
+
+        bvh
+    }
+
+    // Updates the bounds of the hierarchy for lights that moved or changed
+    // their intensity, keeping its structure so that the cost of an update
+    // is linear in the number of lights. The structure may fit the lights
+    // worse after large changes, but lights are still picked with the
+    // probabilities that pmf reports. If lights became bounded or unbounded,
+    // or stopped emitting, the hierarchy is rebuilt from scratch instead.
+    pub fn refit(&mut self, lights: &[Arc<dyn Light>]) {
+        let same_kinds = lights.len() == self.light_bits.len() &&
+            lights.iter().enumerate().all(|(i, light)| match light.bounds() {
+                Some(b) if b.phi > 0.0 => self.light_bits[i].is_some(),
+                Some(_) => false,
+                None => self.infinite_lights.contains(&i)
+            });
+
+        if !same_kinds {
+            *self = LightBVH::new(lights);
+            return;
+        }
+
+        // Children always come after their parents, so walking backwards
+        // visits them before the nodes that bound them
+        for node_idx in (0..self.nodes.len()).rev() {
+            let new_bounds = match self.nodes[node_idx] {
+                LightBVHNode::Leaf { light, .. } => lights[light].bounds().unwrap(),
+                LightBVHNode::Inner { second_child_offset, .. } =>
+                    self.nodes[node_idx + 1].bounds()
+                        .union(self.nodes[second_child_offset].bounds())
+            };
+
+            match self.nodes[node_idx] {
+                LightBVHNode::Leaf { ref mut bounds, .. } => *bounds = new_bounds,
+                LightBVHNode::Inner { ref mut bounds, .. } => *bounds = new_bounds
+            }
+        }
+    }
+
+    fn build(&mut self, mut lights: BoundedLights, bits: u64, depth: usize)
+             -> LightBounds {
+        if lights.len() == 1 {
+            let (light, bounds) = lights.pop().unwrap();
+            self.light_bits[light] = Some((bits, depth));
+            self.nodes.push(LightBVHNode::Leaf { bounds: bounds.clone(), light });
+            return bounds;
+        }
+
+        assert!(depth < 64, "Light BVH is too deep");
+
+        let (left, right) = split(lights);
+
+        // Put in a placeholder until we know where the second child starts
+        let node_idx = self.nodes.len();
+        self.nodes.push(LightBVHNode::Inner {
+            bounds: left[0].1.clone(), second_child_offset: 0
+        });
+
+        let b0 = self.build(left, bits, depth + 1);
+        let second_child_offset = self.nodes.len();
+        let b1 = self.build(right, bits | (1 << depth), depth + 1);
+
+        let bounds = b0.union(&b1);
+        self.nodes[node_idx] = LightBVHNode::Inner { bounds: bounds.clone(), second_child_offset };
+        bounds
+    }
+
+    // The probability of picking one of the infinite lights
+    fn infinite_pmf(&self) -> f32 {
+        let num_infinite = self.infinite_lights.len() as f32;
+        let has_bvh = if self.nodes.is_empty() { 0.0 } else { 1.0 };
+        if num_infinite == 0.0 { 0.0 } else { num_infinite / (num_infinite + has_bvh) }
+    }
+
+    // Picks a light for the point p with surface normal n using u in [0, 1)
+    // and returns its index along with the probability of picking it, or
+    // None if no light is likely to contribute.
+    pub fn sample(&self, p: &Point, n: &Normal, u: f32) -> Option<(usize, f32)> {
+        let p_infinite = self.infinite_pmf();
+        if u < p_infinite {
+            let num_infinite = self.infinite_lights.len();
+            let idx = ((u / p_infinite * (num_infinite as f32)) as usize).min(num_infinite - 1);
+            return Some((self.infinite_lights[idx], p_infinite / (num_infinite as f32)));
+        }
+
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut u = ((u - p_infinite) / (1.0 - p_infinite)).min(1.0 - f32::EPSILON);
+        let mut pmf = 1.0 - p_infinite;
+        let mut node_idx = 0;
+        loop {
+            match self.nodes[node_idx] {
+                LightBVHNode::Leaf { ref bounds, light } => {
+                    return if bounds.importance(p, n) > 0.0 { Some((light, pmf)) } else { None };
+                },
+
+                LightBVHNode::Inner { second_child_offset, .. } => {
+                    let c0 = self.nodes[node_idx + 1].bounds().importance(p, n);
+                    let c1 = self.nodes[second_child_offset].bounds().importance(p, n);
+                    if c0 == 0.0 && c1 == 0.0 {
+                        return None;
+                    }
+
+                    let p0 = c0 / (c0 + c1);
+                    if u < p0 {
+                        node_idx += 1;
+                        u = (u / p0).min(1.0 - f32::EPSILON);
+                        pmf *= p0;
+                    } else {
+                        node_idx = second_child_offset;
+                        u = ((u - p0) / (1.0 - p0)).min(1.0 - f32::EPSILON);
+                        pmf *= 1.0 - p0;
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns the probability that sample picks the light with the given
+    // index for the point p with surface normal n.
+    pub fn pmf(&self, p: &Point, n: &Normal, light: usize) -> f32 {
+        if self.infinite_lights.contains(&light) {
+            return self.infinite_pmf() / (self.infinite_lights.len() as f32);
+        }
+
+        let (bits, depth) = match self.light_bits.get(light) {
+            Some(&Some(path)) => path,
+            _ => return 0.0
+        };
+
+        let mut pmf = 1.0 - self.infinite_pmf();
+        let mut node_idx = 0;
+        for level in 0..depth {
+            let second_child_offset = match self.nodes[node_idx] {
+                LightBVHNode::Inner { second_child_offset, .. } => second_child_offset,
+                LightBVHNode::Leaf { .. } => unreachable!()
+            };
+
+            let c0 = self.nodes[node_idx + 1].bounds().importance(p, n);
+            let c1 = self.nodes[second_child_offset].bounds().importance(p, n);
+            if c0 == 0.0 && c1 == 0.0 {
+                return 0.0;
+            }
+
+            if bits & (1 << level) == 0 {
+                pmf *= c0 / (c0 + c1);
+                node_idx += 1;
+            } else {
+                pmf *= c1 / (c0 + c1);
+                node_idx = second_child_offset;
+            }
+        }
+
+        if self.nodes[node_idx].bounds().importance(p, n) > 0.0 { pmf } else { 0.0 }
+    }
+}
+
+// Indices of lights in the scene along with their bounds
+type BoundedLights = Vec<(usize, LightBounds)>;
+
+// Splits the lights in two by bucketing their centroids along each axis and
+// picking the cheapest split. Lights whose centroids all coincide are split
+// in half.
+fn split(lights: BoundedLights) -> (BoundedLights, BoundedLights) {
+    let extent = lights.iter().skip(1)
+        .fold(lights[0].1.bounds.clone(), |b, (_, l)| b.union(&l.bounds));
+    let centroid_bounds = lights.iter()
+        .fold(BBox::new(), |b, (_, l)| b.union(&l.centroid()));
+
+    let bucket_of = |c: &Point, dim: usize| {
+        let lo = centroid_bounds.p_min[dim];
+        let hi = centroid_bounds.p_max[dim];
+        let b = ((NUM_BUCKETS as f32) * (c[dim] - lo) / (hi - lo)) as usize;
+        b.min(NUM_BUCKETS - 1)
+    };
+
+    let mut best: Option<(f32, usize, usize)> = None;
+    for dim in 0..3 {
+        if centroid_bounds.p_max[dim] <= centroid_bounds.p_min[dim] {
+            continue;
+        }
+
+        let mut buckets: Vec<Option<LightBounds>> = vec![None; NUM_BUCKETS];
+        for (_, l) in lights.iter() {
+            let b = bucket_of(&l.centroid(), dim);
+            buckets[b] = Some(match &buckets[b] {
+                None => l.clone(),
+                Some(bounds) => bounds.union(l)
+            });
+        }
+
+        let union_all = |bs: &[Option<LightBounds>]| {
+            bs.iter().flatten().fold(None, |acc: Option<LightBounds>, b| match acc {
+                None => Some(b.clone()),
+                Some(acc) => Some(acc.union(b))
+            })
+        };
+
+        for split_at in 1..NUM_BUCKETS {
+            let cost = match (union_all(&buckets[..split_at]), union_all(&buckets[split_at..])) {
+                (Some(b0), Some(b1)) => b0.cost(&extent, dim) + b1.cost(&extent, dim),
+                _ => continue
+            };
+
+            if best.is_none_or(|(best_cost, ..)| cost < best_cost) {
+                best = Some((cost, dim, split_at));
+            }
+        }
+    }
+
+    match best {
+        Some((_, dim, split_at)) =>
+            lights.into_iter().partition(|(_, l)| bucket_of(&l.centroid(), dim) < split_at),
+        None => {
+            let mut lights = lights;
+            let right = lights.split_off(lights.len() / 2);
+            (lights, right)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::point::PointLight;
+    use crate::light::spot::SpotLight;
+    use crate::spectrum::Spectrum;
+    use crate::transform::transform::Transform;
+
+    fn point_light(x: f32, intensity: f32) -> Arc<dyn Light> {
+        let l2w = Transform::translate(&Vector::new_with(x, 0.0, 0.0));
+        Arc::new(PointLight::new(l2w, Spectrum::from(intensity)))
+    }
+
+    #[test]
+    fn it_unions_bounds() {
+        let a = LightBounds::new(BBox::from(Point::new()), Vector::new_with(1.0, 0.0, 0.0),
+                                 1.0, 1.0, 0.5, false);
+        let b = LightBounds::new(BBox::from(Point::new_with(1.0, 1.0, 1.0)),
+                                 Vector::new_with(0.0, 1.0, 0.0), 2.0, 1.0, 0.0, true);
+        let u = a.union(&b);
+        assert_eq!(u.bounds, BBox::new_with(Point::new(), Point::new_with(1.0, 1.0, 1.0)));
+        assert_eq!(u.phi, 3.0);
+        assert_eq!(u.cos_theta_e, 0.0);
+        assert!(u.two_sided);
+
+        // The cone sits halfway between the two directions and covers both
+        let half = 0.5f32.sqrt();
+        assert!((&u.w - Vector::new_with(half, half, 0.0)).length() < 1e-4);
+        assert!((u.cos_theta_o - half).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_ignores_points_outside_of_the_emission_cone() {
+        let spot: Arc<dyn Light> =
+            Arc::new(SpotLight::new(Transform::new(), Spectrum::from(1.0), 30.0, 20.0));
+        let b = spot.bounds().unwrap();
+        let n = Normal::new();
+        assert!(b.importance(&Point::new_with(0.0, 0.0, 2.0), &n) > 0.0);
+        assert!(b.importance(&Point::new_with(0.0, 2.0, 0.0), &n) == 0.0);
+        assert!(b.importance(&Point::new_with(0.0, 0.0, -2.0), &n) == 0.0);
+
+        // Surfaces that face away from the light get less of it
+        let p = Point::new_with(0.0, 0.0, 2.0);
+        let facing = b.importance(&p, &Normal::new_with(0.0, 0.0, -1.0));
+        let edge_on = b.importance(&p, &Normal::new_with(1.0, 0.0, 0.0));
+        assert!(facing > edge_on);
+    }
+
+    #[test]
+    fn it_prefers_nearby_lights() {
+        let lights: Vec<_> = (0..8).map(|i| point_light(10.0 * (i as f32), 1.0)).collect();
+        let bvh = LightBVH::new(&lights);
+        let p = Point::new_with(0.5, 1.0, 0.0);
+        let n = Normal::new();
+
+        let pmfs: Vec<f32> = (0..8).map(|i| bvh.pmf(&p, &n, i)).collect();
+        assert!((pmfs.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+        assert!(pmfs[0] > 0.5);
+        assert!(pmfs.windows(2).all(|w| w[0] >= w[1]));
+
+        // Sampling agrees with the pmf
+        let mut counts = [0; 8];
+        for i in 0..1000 {
+            let u = ((i as f32) + 0.5) / 1000.0;
+            let (light, pmf) = bvh.sample(&p, &n, u).unwrap();
+            assert!((pmf - pmfs[light]).abs() < 1e-4);
+            counts[light] += 1;
+        }
+        for (i, &c) in counts.iter().enumerate() {
+            assert!(((c as f32) / 1000.0 - pmfs[i]).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn it_shares_probability_with_infinite_lights() {
+        use crate::light::distant::DistantLight;
+
+        let distant: Arc<dyn Light> = Arc::new(DistantLight::new(
+            Transform::new(), Spectrum::from(1.0), Vector::new_with(0.0, 0.0, 1.0)));
+        let lights = vec![point_light(0.0, 1.0), distant, point_light(5.0, 1.0)];
+        let bvh = LightBVH::new(&lights);
+        let p = Point::new_with(1.0, 1.0, 0.0);
+        let n = Normal::new();
+
+        assert_eq!(bvh.pmf(&p, &n, 1), 0.5);
+        assert!((bvh.pmf(&p, &n, 0) + bvh.pmf(&p, &n, 2) - 0.5).abs() < 1e-4);
+        assert_eq!(bvh.sample(&p, &n, 0.25), Some((1, 0.5)));
+        assert!(bvh.sample(&p, &n, 0.75).is_some_and(|(l, _)| l != 1));
+    }
+
+    #[test]
+    fn it_can_be_refit_to_lights_that_changed() {
+        let mut lights: Vec<_> = (0..8).map(|i| point_light(10.0 * (i as f32), 1.0)).collect();
+        let mut bvh = LightBVH::new(&lights);
+        let num_nodes = bvh.nodes.len();
+
+        // Brighten the light furthest away and move it next to p
+        let p = Point::new_with(0.5, 1.0, 0.0);
+        let n = Normal::new();
+        let before = bvh.pmf(&p, &n, 7);
+        lights[7] = point_light(1.0, 4.0);
+        bvh.refit(&lights);
+        assert_eq!(bvh.nodes.len(), num_nodes);
+        assert!(bvh.pmf(&p, &n, 7) > 10.0 * before);
+
+        let pmfs: Vec<f32> = (0..8).map(|i| bvh.pmf(&p, &n, i)).collect();
+        assert!((pmfs.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+        for i in 0..100 {
+            let u = ((i as f32) + 0.5) / 100.0;
+            let (light, pmf) = bvh.sample(&p, &n, u).unwrap();
+            assert!((pmf - pmfs[light]).abs() < 1e-4);
+        }
+
+        // Lights that stop emitting can't stay in the hierarchy
+        lights[3] = point_light(30.0, 0.0);
+        bvh.refit(&lights);
+        assert_eq!(bvh.pmf(&p, &n, 3), 0.0);
+        assert!(bvh.nodes.len() < num_nodes);
+    }
+}
@@ -0,0 +1,374 @@
+use crate::area_light::AreaLight;
+use crate::bbox::HasBounds;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::light::AnimatedScale;
+use crate::light::Light;
+use crate::light::LightBuilder;
+use crate::light::LightSample;
+use crate::light::bvh::LightBounds;
+use crate::montecarlo::{uniform_sample_sphere, uniform_hemisphere_pdf};
+use crate::primitive::Refinable;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::shape::Shape;
+use crate::spectrum::Spectrum;
+use crate::texture::ColorTextureReference;
+use crate::transform::transform::Transform;
+use crate::visibility_tester::VisibilityTester;
+
+use crate::light::internal;
+
+// An area light that emits the same radiance in every direction from every
+// point on the front side of its shape. The radiance may be modulated by a
+// texture over the shape's surface, e.g. for screens or stained glass.
+#[derive(Clone, Debug)]
+pub struct DiffuseAreaLight {
+    base: internal::LightBase,
+    lemit: Spectrum,
+    texture: Option<ColorTextureReference>,
+    // The texture's average value over the surface of the shape
+    avg_texture: Spectrum,
+    shape: Shape,
+    area: f32
+}
+
+impl DiffuseAreaLight {
+    pub fn new(l2w: Transform, lemit: Spectrum, ns: usize, shape: Shape)
+               -> DiffuseAreaLight {
+        // Subdivision surfaces are sampled on the triangle mesh that
+        // approximates their limit surface, which is made only once here
+        let shape = match shape {
+            Shape::LoopSubdiv(_) => shape.refine().pop().unwrap(),
+            _ => shape
+        };
+        let area = shape.area();
+        DiffuseAreaLight {
+            base: internal::LightBase::new_with_samples(l2w, ns),
+            lemit,
+            texture: None,
+            avg_texture: Spectrum::from(1.0),
+            shape,
+            area
+        }
+    }
+
+    // Scales the emitted radiance by the texture evaluated at each point on
+    // the shape. The light's power is estimated from the texture's average
+    // over the shape so that brighter emitters get sampled more often.
+    pub fn with_texture(self, texture: ColorTextureReference) -> DiffuseAreaLight {
+        const N: usize = 16;
+        let mut light = DiffuseAreaLight { texture: Some(texture.clone()), ..self };
+        let sum = (0..(N * N)).fold(Spectrum::from(0.0), |acc, i| {
+            let u1 = ((i % N) as f32 + 0.5) / (N as f32);
+            let u2 = ((i / N) as f32 + 0.5) / (N as f32);
+            match light.shape.sample(u1, u2) {
+                Some((ps, ns)) => acc + texture.evaluate(&light.surface_at(ps, ns)),
+                None => acc
+            }
+        });
+
+        light.avg_texture = sum / ((N * N) as f32);
+        light
+    }
+
+    // The surface geometry at the point ps with normal ns, sampled from the
+    // shape. Only textured lights need the parameterization of the surface
+    // there, which is found by intersecting the shape right next to ps.
+    fn surface_at(&self, ps: Point, ns: Normal) -> DifferentialGeometry {
+        if self.texture.is_some() {
+            let eps = 1e-3;
+            let ray = Ray::new_with(&ps + &(eps * Vector::from(ns.clone())),
+                                    -Vector::from(ns.clone()), 0.0);
+            ray.set_maxt(2.0 * eps);
+            if let Some(si) = self.shape.intersect(&ray) {
+                return si.dg;
+            }
+        }
+
+        let mut dg = DifferentialGeometry::new();
+        dg.p = ps;
+        dg.nn = ns;
+        dg
+    }
+
+    fn phi(&self) -> Spectrum {
+        self.lemit * self.base.scale.average() * self.avg_texture * self.area *
+            ::std::f32::consts::PI
+    }
+}
+
+impl AreaLight for DiffuseAreaLight {
+    fn l(&self, dg: &DifferentialGeometry, w: &Vector, time: f32) -> Spectrum {
+        if dg.nn.dot(w) <= 0.0 {
+            return Spectrum::from(0.0);
+        }
+
+        let l = self.lemit * self.base.scale.at(time);
+        match self.texture {
+            Some(ref tex) => l * tex.evaluate(dg),
+            None => l
+        }
+    }
+}
+
+impl LightBuilder for DiffuseAreaLight {
+    fn light_base_mut(&mut self) -> &mut internal::LightBase { &mut self.base }
+}
+
+impl Light for DiffuseAreaLight {
+    fn sample_l(&self, p: &Point, ls: LightSample, time: f32)
+                -> (Spectrum, Vector, f32, VisibilityTester) {
+        // Shapes that can't be sampled don't light anything
+        let Some((ps, ns)) = self.shape.sample_at_point(p, ls.u_pos.0, ls.u_pos.1) else {
+            let vis = VisibilityTester::segment(p.clone(), p.clone(), time);
+            return (Spectrum::from(0.0), Vector::new(), 0.0, vis);
+        };
+        let wi = (&ps - p).normalize();
+        let pdf = self.shape.pdf_wi(p, &wi);
+        let dg = self.surface_at(ps, ns);
+        let l = self.l(&dg, &(-(&wi)), time);
+        let vis = VisibilityTester::segment(p.clone(), dg.p, time);
+        (l, wi, pdf, vis)
+    }
+
+    fn pdf(&self, p: &Point, wi: &Vector) -> f32 { self.shape.pdf_wi(p, wi) }
+
+    // Emits uniformly over the hemisphere around the sampled point's normal
+    fn sample_l_ray(&self, _: &Scene, ls: LightSample, u1: f32, u2: f32, time: f32)
+                    -> (Spectrum, Ray, Normal, f32) {
+        let Some((ps, ns)) = self.shape.sample(ls.u_pos.0, ls.u_pos.1) else {
+            let ray = Ray::new_with(Point::new(), Vector::forward(), 0.0);
+            return (Spectrum::from(0.0), ray, Normal::new(), 0.0);
+        };
+        let mut dir = uniform_sample_sphere(u1, u2);
+        if dir.dot(&ns) < 0.0 { dir = -dir; }
+        let pdf = self.shape.pdf(&ps) * uniform_hemisphere_pdf();
+        let dg = self.surface_at(ps, ns.clone());
+        let l = self.l(&dg, &dir, time);
+        let mut ray = Ray::new_with(dg.p, dir, 1e-3);
+        ray.time = time;
+        (l, ray, ns, pdf)
+    }
+
+    fn power(&self, _: &Scene) -> Spectrum { self.phi() }
+
+    fn num_samples(&self) -> usize { self.base.num_samples }
+
+    fn group(&self) -> Option<&str> { self.base.group.as_deref() }
+
+    // The shape's normals could face any way, so the light is bounded as
+    // emitting into a hemisphere around every direction
+    fn bounds(&self) -> Option<LightBounds> {
+        let phi = self.phi().y();
+        Some(LightBounds::new(self.shape.world_bound(), Vector::forward(), phi,
+                              -1.0, 0.0, false))
+    }
+
+    fn is_delta_light(&self) -> bool { false }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::vector::Vector;
+    use crate::rng::RNG;
+    use crate::transform::transform::Transform;
+
+    use std::f32::consts::PI;
+
+    fn sphere_light(ro: bool) -> DiffuseAreaLight {
+        let xf = Transform::translate(&Vector::new_with(0.0, 0.0, 5.0));
+        let sphere = Shape::sphere(xf.clone(), xf.inverse(), ro, 1.0, -1.0, 1.0, 360.0);
+        DiffuseAreaLight::new(xf, Spectrum::from(2.0), 4, sphere)
+    }
+
+    #[test]
+    fn it_emits_from_the_front_side() {
+        let light = sphere_light(false);
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.0, 0.0, 4.0);
+        dg.nn = Normal::new_with(0.0, 0.0, -1.0);
+        assert_eq!(light.l(&dg, &Vector::new_with(0.0, 0.0, -1.0), 0.0),
+                   Spectrum::from(2.0));
+        assert!(light.l(&dg, &Vector::new_with(0.0, 0.0, 1.0), 0.0).is_black());
+
+        assert!(!light.is_delta_light());
+        assert_eq!(light.num_samples(), 4);
+        let power = light.power(&Scene::new()).y();
+        assert!((power - 2.0 * 4.0 * PI * PI).abs() < 1e-2 * power);
+    }
+
+    #[test]
+    fn it_can_be_textured() {
+        use crate::texture::mapping2d::UVMapping2D;
+        use crate::texture::uv::UVTexture;
+        use std::sync::Arc;
+
+        // Red follows u and green follows v over the sphere
+        let light = sphere_light(false)
+            .with_texture(Arc::new(UVTexture::new(Box::new(UVMapping2D::new()))));
+        let p = Point::new();
+        let mut rng = RNG::new(0);
+        for _ in 0..64 {
+            let (l, wi, _, _) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+            if l.is_black() {
+                continue;
+            }
+
+            // Samples see the same radiance as rays that hit the light there
+            let si = light.shape.intersect(&Ray::new_with(p.clone(), wi.clone(), 0.0)).unwrap();
+            let expected = light.l(&si.dg, &(-wi), 0.0);
+            assert!((l.y() - expected.y()).abs() < 1e-2 * expected.y().max(1e-2));
+            let rgb = (l / 2.0).to_rgb();
+            assert!((rgb[0] - si.dg.u).abs() < 1e-2 && (rgb[1] - si.dg.v).abs() < 1e-2);
+        }
+
+        // Both u and v average out to a half over the sphere
+        let untextured = sphere_light(false).power(&Scene::new());
+        let expected = (untextured * Spectrum::from_rgb([0.5, 0.5, 0.0])).y();
+        let power = light.power(&Scene::new()).y();
+        assert!((power - expected).abs() < 0.05 * expected, "Power: {} vs {}", power, expected);
+    }
+
+    #[test]
+    fn it_can_fade_out() {
+        let light = sphere_light(false).with_animated_scale(
+            AnimatedScale::new(Spectrum::from(1.0), 0.0, Spectrum::from(0.0), 1.0));
+        let mut dg = DifferentialGeometry::new();
+        dg.nn = Normal::new_with(0.0, 0.0, -1.0);
+        let w = Vector::new_with(0.0, 0.0, -1.0);
+        assert_eq!(light.l(&dg, &w, 0.0), Spectrum::from(2.0));
+        assert_eq!(light.l(&dg, &w, 0.25), Spectrum::from(1.5));
+        assert!(light.l(&dg, &w, 1.0).is_black());
+
+        let power = light.power(&Scene::new()).y();
+        let expected = 0.5 * sphere_light(false).power(&Scene::new()).y();
+        assert!((power - expected).abs() < 1e-3 * expected);
+    }
+
+    #[test]
+    fn it_samples_points_on_its_shape() {
+        let light = sphere_light(false);
+        let p = Point::new();
+        let mut rng = RNG::new(0);
+        for _ in 0..256 {
+            let (l, wi, pdf, _) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+            assert!((wi.length_squared() - 1.0).abs() < 1e-4);
+
+            // Spheres are sampled within the cone of directions that they
+            // subtend, so none of the points are on the far side
+            assert!(!l.is_black());
+            assert!(wi.z > 0.0);
+            assert!((pdf - 1.0 / (2.0 * PI * (1.0 - 24f32.sqrt() / 5.0))).abs() < 1e-2 * pdf);
+            assert!((pdf - light.pdf(&p, &wi)).abs() < 1e-4 * pdf);
+        }
+
+        // Directions that miss the shape can't be sampled
+        assert_eq!(light.pdf(&p, &Vector::new_with(0.0, 0.0, -1.0)), 0.0);
+    }
+
+    #[test]
+    fn it_samples_points_on_triangle_meshes() {
+        // Two triangles at z = 5 facing the origin, one twice as big as the
+        // other, which should get twice as many of the samples
+        let points = [Point::new_with(-1.0, -1.0, 5.0), Point::new_with(1.0, -1.0, 5.0),
+                      Point::new_with(1.0, 1.0, 5.0), Point::new_with(-1.0, 3.0, 5.0)];
+        let mesh = Shape::triangle_mesh(Transform::new(), Transform::new(), false,
+                                        &[0, 2, 1, 0, 3, 2], &points, None, None, None, None);
+        let light = DiffuseAreaLight::new(Transform::new(), Spectrum::from(2.0), 1, mesh);
+        let power = light.power(&Scene::new()).y();
+        assert!((power - 2.0 * 6.0 * PI).abs() < 1e-3 * power);
+
+        let p = Point::new();
+        let mut rng = RNG::new(0);
+        let n = 1024;
+        let mut num_in_big = 0;
+        for _ in 0..n {
+            let (l, wi, pdf, _) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+            assert_eq!(l, Spectrum::from(2.0));
+            assert!((pdf - light.pdf(&p, &wi)).abs() < 1e-3 * pdf);
+
+            // Every point on the triangles is sampled with the same density
+            // by area
+            let ps = (5.0 / wi.z) * &wi;
+            assert!((pdf - ps.length_squared() / (wi.z * 6.0)).abs() < 1e-3 * pdf);
+            if ps.y > ps.x { num_in_big += 1; }
+        }
+        assert!(((num_in_big as f32) / (n as f32) - 2.0 / 3.0).abs() < 0.05);
+
+        // Subdivision surfaces are sampled on their limit surface
+        let tetra = [Point::new_with(0.0, 0.0, 5.0), Point::new_with(1.0, 0.0, 5.0),
+                     Point::new_with(0.0, 1.0, 5.0), Point::new_with(0.0, 0.0, 6.0)];
+        let subdiv = Shape::loop_subdiv(Transform::new(), Transform::new(), false,
+                                        &[0, 1, 2, 0, 3, 1, 0, 2, 3, 1, 3, 2], &tetra, 2);
+        let light = DiffuseAreaLight::new(Transform::new(), Spectrum::from(2.0), 1, subdiv);
+        for _ in 0..16 {
+            let (_, wi, pdf, _) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+            assert!(pdf.is_finite());
+            assert!(wi.z > 0.0);
+        }
+    }
+
+    #[test]
+    fn it_emits_rays_away_from_its_surface() {
+        let light = sphere_light(false);
+        let center = Point::new_with(0.0, 0.0, 5.0);
+        let mut rng = RNG::new(0);
+        for _ in 0..64 {
+            let ls = LightSample::new(&mut rng);
+            let (l, ray, n, pdf) =
+                light.sample_l_ray(&Scene::new(), ls, rng.random_float(), rng.random_float(), 0.0);
+            assert_eq!(l, Spectrum::from(2.0));
+            assert!((ray.o.distance(&center) - 1.0).abs() < 1e-3);
+            assert!(ray.d.dot(&n) >= 0.0);
+            assert!((pdf - 1.0 / (4.0 * PI * 2.0 * PI)).abs() < 1e-2 * pdf);
+        }
+    }
+
+    #[test]
+    fn it_only_emits_inwards_when_reversed() {
+        let center = Point::new_with(0.0, 0.0, 5.0);
+        let mut rng = RNG::new(0);
+        for _ in 0..64 {
+            let (l, _, pdf, _) =
+                sphere_light(true).sample_l(&center, LightSample::new(&mut rng), 0.0);
+            assert_eq!(l, Spectrum::from(2.0));
+            assert!((pdf - 1.0 / (4.0 * PI)).abs() < 1e-3);
+
+            let (l, ..) =
+                sphere_light(false).sample_l(&center, LightSample::new(&mut rng), 0.0);
+            assert!(l.is_black());
+        }
+    }
+
+    #[test]
+    fn it_is_seen_by_rays_that_hit_it() {
+        use crate::intersection::Intersectable;
+        use crate::material::Material;
+        use crate::primitive::Primitive;
+        use crate::ray::Ray;
+        use std::sync::Arc;
+
+        let light = sphere_light(false);
+        let shape = light.shape.clone();
+        let prim = Primitive::geometric_area_light(
+            shape, Arc::new(Material::broken()), Arc::new(light));
+
+        let r = Ray::new_with(Point::new(), Vector::new_with(0.1, 0.0, 1.0), 0.0);
+        let isect = prim.intersect(&r).unwrap();
+        assert_eq!(isect.le(&Vector::new_with(0.0, 0.0, -1.0), 0.0), Spectrum::from(2.0));
+        assert!(isect.le(&Vector::new_with(0.0, 0.0, 1.0), 0.0).is_black());
+
+        // Other geometry doesn't emit anything
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+        let isect = Primitive::simple(sphere).intersect(&r).unwrap();
+        assert!(isect.le(&Vector::new_with(0.0, 0.0, -1.0), 0.0).is_black());
+    }
+}
@@ -0,0 +1,130 @@
+use crate::bbox::HasBounds;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::light::Light;
+use crate::light::LightBuilder;
+use crate::light::LightSample;
+use crate::light::sample_ray_into_scene;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::visibility_tester::VisibilityTester;
+
+use crate::light::internal;
+
+// A light infinitely far away, such as the sun, that illuminates the scene
+// from a single direction. dir points from the scene towards the light.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DistantLight {
+    base: internal::LightBase,
+    light_dir: Vector,
+    radiance: Spectrum
+}
+
+impl DistantLight {
+    pub fn new(l2w: Transform, radiance: Spectrum, dir: Vector) -> DistantLight {
+        let light_dir = l2w.xf(dir).normalize();
+        DistantLight { base: internal::LightBase::new(l2w), light_dir, radiance }
+    }
+}
+
+impl LightBuilder for DistantLight {
+    fn light_base_mut(&mut self) -> &mut internal::LightBase { &mut self.base }
+}
+
+impl Light for DistantLight {
+    fn sample_l(&self, p: &Point, _: LightSample, time: f32)
+                -> (Spectrum, Vector, f32, VisibilityTester) {
+        let vis = VisibilityTester::ray(p.clone(), self.light_dir.clone(), time);
+        (self.radiance * self.base.scale.at(time), self.light_dir.clone(), 1.0, vis)
+    }
+
+    // Rays start on the disk that covers the scene and all travel the same
+    // way, so the only density is over the disk's area.
+    fn sample_l_ray(&self, scene: &Scene, ls: LightSample, _: f32, _: f32, time: f32)
+                    -> (Spectrum, Ray, Normal, f32) {
+        let d = -self.light_dir.clone();
+        let (ray, pdf) = sample_ray_into_scene(scene, &d, ls.u_pos.0, ls.u_pos.1, time);
+        (self.radiance * self.base.scale.at(time), ray, Normal::from(d), pdf)
+    }
+
+    // The light reaches at most the disk that covers the scene's bounding
+    // sphere as seen from the light.
+    fn power(&self, scene: &Scene) -> Spectrum {
+        let (_, world_radius) = scene.world_bound().bounding_sphere();
+        self.radiance * self.base.scale.average() *
+            ::std::f32::consts::PI * world_radius * world_radius
+    }
+
+    fn num_samples(&self) -> usize { self.base.num_samples }
+
+    fn group(&self) -> Option<&str> { self.base.group.as_deref() }
+
+    fn is_delta_light(&self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::RNG;
+
+    use std::f32::consts::PI;
+
+    #[test]
+    fn it_shines_from_one_direction() {
+        let xf = Transform::rotate_x(90.0);
+        let light = DistantLight::new(xf.clone(), Spectrum::from(3.0),
+                                      Vector::new_with(0.0, 0.0, 1.0));
+        let dir = xf.xf(Vector::new_with(0.0, 0.0, 1.0));
+
+        let mut rng = RNG::new(0);
+        for p in [Point::new(), Point::new_with(10.0, -4.0, 2.0)] {
+            let (l, wi, pdf, _) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+            assert_eq!(l, Spectrum::from(3.0));
+            assert!((wi - &dir).length() < 1e-6);
+            assert_eq!(pdf, 1.0);
+        }
+
+        assert!(light.is_delta_light());
+        assert_eq!(light.pdf(&Point::new(), &dir), 0.0);
+    }
+
+    #[test]
+    fn it_emits_rays_towards_the_scene() {
+        let light = DistantLight::new(Transform::new(), Spectrum::from(2.0),
+                                      Vector::new_with(0.0, 1.0, 0.0));
+        let scene = Scene::new();
+        let (center, radius) = scene.world_bound().bounding_sphere();
+
+        let mut rng = RNG::new(0);
+        for _ in 0..16 {
+            let ls = LightSample::new(&mut rng);
+            let (l, ray, n, pdf) = light.sample_l_ray(&scene, ls, 0.5, 0.5, 0.25);
+            assert_eq!(l, Spectrum::from(2.0));
+            assert_eq!(ray.d, Vector::new_with(0.0, -1.0, 0.0));
+            assert_eq!(Vector::from(n), ray.d);
+            assert_eq!(ray.time, 0.25);
+            assert!((pdf - 1.0 / (PI * radius * radius)).abs() < 1e-6);
+
+            // Every ray starts above the scene and passes through its bounds
+            assert!((ray.o.y - center.y - radius).abs() < 1e-4);
+            let (dx, dz) = (ray.o.x - center.x, ray.o.z - center.z);
+            assert!(dx * dx + dz * dz <= radius * radius + 1e-4);
+        }
+    }
+
+    #[test]
+    fn it_has_power_proportional_to_the_scene_size() {
+        let light = DistantLight::new(Transform::new(), Spectrum::from(1.0),
+                                      Vector::new_with(0.0, 1.0, 0.0));
+
+        // The default scene is a unit sphere, whose bounds have a radius of
+        // sqrt(3) around the origin
+        let power = light.power(&Scene::new()).y();
+        assert!((power - 3.0 * PI).abs() < 1e-4);
+    }
+}
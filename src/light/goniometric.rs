@@ -0,0 +1,234 @@
+use std::f32::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::geometry::vector::spherical_phi;
+use crate::geometry::vector::spherical_theta;
+use crate::light::Light;
+use crate::light::LightBuilder;
+use crate::light::LightSample;
+use crate::light::bvh::LightBounds;
+use crate::light::ies::IESProfile;
+use crate::montecarlo::uniform_sample_sphere;
+use crate::montecarlo::uniform_sphere_pdf;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::texture::imagemap::read_image;
+use crate::texture::imagewrap::ImageWrap;
+use crate::texture::mipmap::MIPMap;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::visibility_tester::VisibilityTester;
+
+use crate::light::internal;
+
+// Resolution of the latitude-longitude map that IES profiles are baked into
+const IES_MAP_WIDTH: usize = 256;
+const IES_MAP_HEIGHT: usize = 128;
+
+// Bakes an IES profile into a latitude-longitude map normalized so that its
+// brightest direction is one. Rows go from the light's +y axis at the top
+// to the luminaire's nadir, its -y axis, at the bottom.
+fn ies_to_image(profile: &IESProfile) -> (usize, usize, Vec<Spectrum>) {
+    let max = profile.max_candela();
+    let scale = if max > 0.0 { 1.0 / max } else { 0.0 };
+    let mut texels = Vec::with_capacity(IES_MAP_WIDTH * IES_MAP_HEIGHT);
+    for j in 0..IES_MAP_HEIGHT {
+        let theta = 180.0 * ((j as f32) + 0.5) / (IES_MAP_HEIGHT as f32);
+        for i in 0..IES_MAP_WIDTH {
+            let phi = 360.0 * ((i as f32) + 0.5) / (IES_MAP_WIDTH as f32);
+            texels.push(Spectrum::from(profile.candela(180.0 - theta, phi) * scale));
+        }
+    }
+    (IES_MAP_WIDTH, IES_MAP_HEIGHT, texels)
+}
+
+fn read_distribution(texname: &str) -> Option<(usize, usize, Vec<Spectrum>)> {
+    let is_ies = Path::new(texname).extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ies"));
+    let result = if is_ies {
+        IESProfile::read(&texname).map(|profile| ies_to_image(&profile))
+    } else {
+        read_image(&texname)
+            .map(|(w, h, texels)| (w as usize, h as usize, texels))
+    };
+
+    match result {
+        Ok(img) => Some(img),
+        Err(e) => {
+            println!("WARNING: Unable to read goniometric map {}: {}", texname, e);
+            None
+        }
+    }
+}
+
+// A point light whose intensity varies with direction according to a
+// goniometric diagram, given either as an IES profile or as an image in
+// latitude-longitude layout around the light's +y axis. Without a diagram
+// it shines equally in all directions.
+#[derive(Clone, Debug)]
+pub struct GonioPhotometricLight {
+    base: internal::LightBase,
+    light_pos: Point,
+    intensity: Spectrum,
+    mipmap: Option<Arc<MIPMap<Spectrum>>>,
+    average: Spectrum
+}
+
+impl GonioPhotometricLight {
+    pub fn new(l2w: Transform, intensity: Spectrum, texname: &str)
+               -> GonioPhotometricLight {
+        let dist = if texname.is_empty() { None } else { read_distribution(texname) };
+        let (mipmap, average) = match dist {
+            Some((w, h, texels)) => {
+                // Weight each row by the solid angle that it covers
+                let mut sum = Spectrum::from(0.0);
+                let mut sum_wts = 0.0;
+                for (j, row) in texels.chunks(w).enumerate() {
+                    let sin_theta = (PI * ((j as f32) + 0.5) / (h as f32)).sin();
+                    for &s in row {
+                        sum = sum + s * sin_theta;
+                        sum_wts += sin_theta;
+                    }
+                }
+
+                // Clamp so that lookups near one pole don't wrap around to the other
+                let mipmap = MIPMap::new(w, h, texels, true, 8.0, ImageWrap::Clamp);
+                (Some(Arc::new(mipmap)), sum / sum_wts)
+            },
+            None => (None, Spectrum::from(1.0))
+        };
+
+        let light_pos = l2w.xf(Point::new());
+        GonioPhotometricLight {
+            base: internal::LightBase::new(l2w),
+            light_pos,
+            intensity,
+            mipmap,
+            average
+        }
+    }
+
+    fn scale(&self, w: Vector) -> Spectrum {
+        match &self.mipmap {
+            None => Spectrum::from(1.0),
+            Some(mipmap) => {
+                // Swap y and z so that the diagram's pole is the light's +y axis
+                let wl = self.base.world_to_light.xf(w).normalize();
+                let wp = Vector::new_with(wl.x, wl.z, wl.y);
+                let s = spherical_phi(&wp) / (2.0 * PI);
+                let t = spherical_theta(&wp) / PI;
+                mipmap.lookup(s, t, 0.0, 0.0, 0.0, 0.0)
+            }
+        }
+    }
+}
+
+impl LightBuilder for GonioPhotometricLight {
+    fn light_base_mut(&mut self) -> &mut internal::LightBase { &mut self.base }
+}
+
+impl Light for GonioPhotometricLight {
+    fn sample_l(&self, p: &Point, _: LightSample, time: f32)
+                -> (Spectrum, Vector, f32, VisibilityTester) {
+        let w_i = (&self.light_pos - p).normalize();
+        let vis = VisibilityTester::segment(p.clone(), self.light_pos.clone(), time);
+        let i = self.intensity * self.base.scale.at(time) * self.scale(-w_i.clone());
+        (i / self.light_pos.distance_squared(p), w_i, 1.0, vis)
+    }
+
+    fn sample_l_ray(&self, _: &Scene, _: LightSample, u1: f32, u2: f32, time: f32)
+                    -> (Spectrum, Ray, Normal, f32) {
+        let dir = uniform_sample_sphere(u1, u2);
+        let n = Normal::from(&dir);
+        let i = self.intensity * self.base.scale.at(time) * self.scale(dir.clone());
+        let mut ray = Ray::new_with(self.light_pos.clone(), dir, 0.0);
+        ray.time = time;
+        (i, ray, n, uniform_sphere_pdf())
+    }
+
+    fn power(&self, _: &Scene) -> Spectrum {
+        4.0 * PI * self.intensity * self.average * self.base.scale.average()
+    }
+
+    fn num_samples(&self) -> usize { self.base.num_samples }
+
+    fn group(&self) -> Option<&str> { self.base.group.as_deref() }
+
+    fn delta_position(&self) -> Option<Point> { Some(self.light_pos.clone()) }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let phi = 4.0 * PI * (self.intensity * self.average * self.base.scale.average()).y();
+        Some(LightBounds::point(self.light_pos.clone(), phi))
+    }
+
+    fn is_delta_light(&self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::RNG;
+
+    use std::env;
+    use std::fs;
+
+    const SPOT_IES: &str = "IESNA:LM-63-2002
+TILT=NONE
+1 1000 1.0 3 1 1 1 0.1 0.1 0
+1.0 1.0 100
+0 30 90
+0
+500 500 0
+";
+
+    fn sample_at(light: &GonioPhotometricLight, p: Point) -> Spectrum {
+        let mut rng = RNG::new(0);
+        let (l, ..) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+        l
+    }
+
+    #[test]
+    fn it_is_a_point_light_without_a_diagram() {
+        let light = GonioPhotometricLight::new(Transform::new(), Spectrum::from(4.0), "");
+        assert_eq!(sample_at(&light, Point::new_with(0.0, 2.0, 0.0)), Spectrum::from(1.0));
+        assert_eq!(sample_at(&light, Point::new_with(0.0, 0.0, -2.0)), Spectrum::from(1.0));
+        assert!((light.power(&Scene::new()).y() - 16.0 * PI).abs() < 1e-3);
+        assert!(light.is_delta_light());
+    }
+
+    #[test]
+    fn it_shines_like_an_ies_profile() {
+        let filename = env::temp_dir().join("pbrt_rust_goniometric_test.ies");
+        fs::write(&filename, SPOT_IES).unwrap();
+        let light = GonioPhotometricLight::new(Transform::new(), Spectrum::from(4.0),
+                                               filename.to_str().unwrap());
+        fs::remove_file(&filename).unwrap();
+
+        // Straight down, along -y, gets the full intensity
+        let down = sample_at(&light, Point::new_with(0.0, -2.0, 0.0)).y();
+        assert!((down - 1.0).abs() < 1e-2);
+
+        // Sideways and upwards get nothing
+        assert!(sample_at(&light, Point::new_with(2.0, 0.0, 0.0)).y() < 5e-2);
+        assert!(sample_at(&light, Point::new_with(0.0, 0.0, 2.0)).y() < 5e-2);
+        assert!(sample_at(&light, Point::new_with(0.0, 2.0, 0.0)).is_black());
+
+        // The light only covers part of the lower hemisphere, so it has
+        // much less power than an equally bright point light
+        let power = light.power(&Scene::new()).y();
+        assert!(power > 0.0 && power < 0.5 * 16.0 * PI);
+    }
+
+    #[test]
+    fn it_falls_back_to_a_point_light_for_missing_files() {
+        let light = GonioPhotometricLight::new(Transform::new(), Spectrum::from(1.0),
+                                               "no/such/profile.ies");
+        assert_eq!(sample_at(&light, Point::new_with(0.0, 1.0, 0.0)), Spectrum::from(1.0));
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs;
+use std::path::Path;
+
+// The candela distribution of a luminaire read from an IES LM-63 file.
+// Vertical angles are measured from the luminaire's nadir (straight down)
+// and horizontal angles around its vertical axis, both in degrees.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IESProfile {
+    vertical_angles: Vec<f32>,
+    horizontal_angles: Vec<f32>,
+    // One row of vertical samples for each horizontal angle
+    candela: Vec<Vec<f32>>
+}
+
+// Returns the index of the interval of the sorted values that contains x
+// along with how far along it x is, clamped to the ends of the values.
+fn find_interval(values: &[f32], x: f32) -> (usize, f32) {
+    if values.len() < 2 || x <= values[0] {
+        return (0, 0.0);
+    }
+
+    let last = values.len() - 1;
+    if x >= values[last] {
+        return (last - 1, 1.0);
+    }
+
+    let i = values.iter().rposition(|&v| v <= x).unwrap();
+    let dt = (x - values[i]) / (values[i + 1] - values[i]);
+    (i, if dt.is_finite() { dt } else { 0.0 })
+}
+
+impl IESProfile {
+    pub fn read<P: AsRef<Path>>(filename: &P) -> Result<IESProfile, String> {
+        let contents = fs::read_to_string(filename).map_err(|e| e.to_string())?;
+        IESProfile::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<IESProfile, String> {
+        // Everything up to and including the TILT line is free form keywords
+        let mut lines = contents.lines();
+        let tilt = loop {
+            match lines.next() {
+                None => return Err(String::from("Missing TILT line")),
+                Some(line) => {
+                    let line = line.trim();
+                    if let Some(tilt) = line.strip_prefix("TILT=") {
+                        break String::from(tilt.trim());
+                    }
+                }
+            }
+        };
+
+        let mut numbers = Vec::new();
+        for tok in lines.flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ',')) {
+            if tok.is_empty() { continue; }
+            numbers.push(tok.parse::<f32>()
+                         .map_err(|_| format!("Invalid number in IES data: {}", tok))?);
+        }
+        let mut numbers = numbers.into_iter();
+        let mut next = || numbers.next().ok_or_else(|| String::from("Unexpected end of IES data"));
+
+        match tilt.as_ref() {
+            "NONE" => (),
+            "INCLUDE" => {
+                // Lamp to luminaire geometry, followed by pairs of angles
+                // and multiplying factors. These only matter for lamps that
+                // are mounted at an angle, so they're skipped.
+                next()?;
+                let num_pairs = next()? as usize;
+                for _ in 0..(2 * num_pairs) { next()?; }
+            },
+            _ => return Err(format!("Unsupported TILT file: {}", tilt))
+        }
+
+        next()?; // Number of lamps
+        next()?; // Lumens per lamp
+        let multiplier = next()?;
+        let num_vertical = next()? as usize;
+        let num_horizontal = next()? as usize;
+        let photometric_type = next()? as i32;
+        // Units type and luminous opening dimensions
+        for _ in 0..4 { next()?; }
+        let ballast_factor = next()?;
+        // Ballast lamp photometric factor and input watts
+        for _ in 0..2 { next()?; }
+
+        if photometric_type != 1 {
+            return Err(String::from("Only type C photometry is supported"));
+        }
+
+        if num_vertical == 0 || num_horizontal == 0 {
+            return Err(String::from("IES data has no angles"));
+        }
+
+        let vertical_angles = (0..num_vertical).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+        let horizontal_angles =
+            (0..num_horizontal).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+        let mut candela = Vec::with_capacity(num_horizontal);
+        for _ in 0..num_horizontal {
+            candela.push((0..num_vertical)
+                         .map(|_| next().map(|c| c * multiplier * ballast_factor))
+                         .collect::<Result<Vec<_>, _>>()?);
+        }
+
+        Ok(IESProfile { vertical_angles, horizontal_angles, candela })
+    }
+
+    pub fn max_candela(&self) -> f32 {
+        self.candela.iter().flatten().cloned().fold(0.0, f32::max)
+    }
+
+    // Returns the intensity in the given direction, both angles in degrees.
+    // Directions outside of the measured vertical range get no light.
+    pub fn candela(&self, vertical: f32, horizontal: f32) -> f32 {
+        let v_first = self.vertical_angles[0];
+        let v_last = *self.vertical_angles.last().unwrap();
+        if vertical < v_first || vertical > v_last {
+            return 0.0;
+        }
+
+        // Fold the horizontal angle into the measured range using the
+        // symmetry that the last horizontal angle implies
+        let h = horizontal.rem_euclid(360.0);
+        let h_last = *self.horizontal_angles.last().unwrap();
+        let h = if h_last <= 0.0 {
+            0.0
+        } else if h_last <= 90.0 {
+            let h = h % 180.0;
+            if h > 90.0 { 180.0 - h } else { h }
+        } else if h_last <= 180.0 {
+            if h > 180.0 { 360.0 - h } else { h }
+        } else {
+            h
+        };
+
+        let (hi, ht) = find_interval(&self.horizontal_angles, h);
+        let (vi, vt) = find_interval(&self.vertical_angles, vertical);
+        let row = |i: usize| {
+            let r = &self.candela[i.min(self.candela.len() - 1)];
+            let c0 = r[vi];
+            let c1 = r[(vi + 1).min(r.len() - 1)];
+            (1.0 - vt) * c0 + vt * c1
+        };
+        (1.0 - ht) * row(hi) + ht * row(hi + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOWNLIGHT: &str = "IESNA:LM-63-2002
+[TEST] downlight
+[MANUFAC] nobody
+TILT=NONE
+1 1000 2.0 3 3 1 1 0.1 0.1 0
+1.0 1.0 100
+0 45 90
+0 45 90
+100 50 0
+100 40 0
+100 30 0
+";
+
+    #[test]
+    fn it_parses_type_c_profiles() {
+        let profile = IESProfile::parse(DOWNLIGHT).unwrap();
+        assert_eq!(profile.vertical_angles, vec![0.0, 45.0, 90.0]);
+        assert_eq!(profile.horizontal_angles, vec![0.0, 45.0, 90.0]);
+        assert_eq!(profile.max_candela(), 200.0);
+
+        // Straight down is the brightest, straight out gets nothing
+        assert_eq!(profile.candela(0.0, 0.0), 200.0);
+        assert_eq!(profile.candela(90.0, 30.0), 0.0);
+        assert_eq!(profile.candela(120.0, 0.0), 0.0);
+
+        // Angles in between are interpolated
+        assert!((profile.candela(22.5, 0.0) - 150.0).abs() < 1e-4);
+        assert!((profile.candela(45.0, 22.5) - 90.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_uses_quadrant_symmetry() {
+        let profile = IESProfile::parse(DOWNLIGHT).unwrap();
+        for h in [45.0, 135.0, 225.0, 315.0] {
+            assert!((profile.candela(45.0, h) - 80.0).abs() < 1e-4);
+        }
+        assert!((profile.candela(45.0, 180.0) - 100.0).abs() < 1e-4);
+        assert!((profile.candela(45.0, 270.0) - 60.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_skips_tilt_data() {
+        let tilted = DOWNLIGHT.replace("TILT=NONE", "TILT=INCLUDE\n1\n2\n0 90\n1.0 0.5");
+        assert_eq!(IESProfile::parse(&tilted), IESProfile::parse(DOWNLIGHT));
+        assert!(IESProfile::parse("TILT=NONE\n1 1000").is_err());
+        assert!(IESProfile::parse("no tilt here").is_err());
+    }
+}
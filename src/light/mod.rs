@@ -1,23 +1,40 @@
+pub mod bvh;
+pub mod diffuse;
+pub mod distant;
+pub mod goniometric;
+pub mod ies;
 pub mod point;
+pub mod portal;
+pub mod projection;
 pub mod spot;
+pub mod sunsky;
 
-use ray::RayDifferential;
-use rng::RNG;
-use spectrum::Spectrum;
-use visibility_tester::VisibilityTester;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use scene::Scene;
-use transform::transform::Transform;
+use crate::bbox::HasBounds;
+use crate::geometry::normal::Normal;
+use crate::geometry::vector::coordinate_system;
+use crate::montecarlo::concentric_sample_disk;
+use crate::ray::Ray;
+use crate::ray::RayDifferential;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::spectrum::Spectrum;
+use crate::visibility_tester::VisibilityTester;
+use crate::geometry::point::Point;
+use crate::light::bvh::LightBounds;
+use crate::geometry::vector::Vector;
+use crate::scene::Scene;
+use crate::transform::transform::Transform;
 
 mod internal {
     use super::*;
 
-    #[derive(Clone, Debug, PartialOrd, PartialEq)]
+    #[derive(Clone, Debug, PartialEq)]
     pub struct LightBase {
         pub num_samples: usize,
         pub light_to_world: Transform,
-        pub world_to_light: Transform
+        pub world_to_light: Transform,
+        pub group: Option<String>,
+        pub scale: AnimatedScale
     }
 
     impl LightBase {
@@ -25,7 +42,9 @@ mod internal {
             LightBase {
                 num_samples: 1,
                 light_to_world: l2w.clone(),
-                world_to_light: l2w.invert()
+                world_to_light: l2w.invert(),
+                group: None,
+                scale: AnimatedScale::constant(Spectrum::from(1.0))
             }
         }
 
@@ -33,17 +52,126 @@ mod internal {
             LightBase {
                 num_samples: ns,
                 light_to_world: l2w.clone(),
-                world_to_light: l2w.invert()
+                world_to_light: l2w.invert(),
+                group: None,
+                scale: AnimatedScale::constant(Spectrum::from(1.0))
             }
         }
     }
 }
 
-#[derive(PartialOrd,Ord,PartialEq,Eq)]
-pub struct LightSample;
+// Builders for the settings that every light keeps in its LightBase
+pub trait LightBuilder : Sized {
+    fn light_base_mut(&mut self) -> &mut internal::LightBase;
+
+    // Puts the light in the named output group. An empty name leaves the
+    // light out of every group.
+    fn with_group(mut self, group: &str) -> Self {
+        self.light_base_mut().group =
+            if group.is_empty() { None } else { Some(String::from(group)) };
+        self
+    }
+
+    // Animates the light's emission over the shutter interval
+    fn with_animated_scale(mut self, scale: AnimatedScale) -> Self {
+        self.light_base_mut().scale = scale;
+        self
+    }
+}
+
+// A scale on the emission of a light that changes linearly from start to
+// end over the shutter interval, so that flickering and fading lights
+// blur along with the motion in the scene. Rays outside of the interval see
+// the scale at its nearest end.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimatedScale {
+    start: Spectrum,
+    start_time: f32,
+    end: Spectrum,
+    end_time: f32
+}
+
+impl AnimatedScale {
+    pub fn new(start: Spectrum, start_time: f32, end: Spectrum, end_time: f32)
+               -> AnimatedScale {
+        AnimatedScale { start, start_time, end, end_time }
+    }
+
+    pub fn constant(s: Spectrum) -> AnimatedScale {
+        AnimatedScale::new(s, 0.0, s, 0.0)
+    }
+
+    pub fn at(&self, time: f32) -> Spectrum {
+        if time <= self.start_time || self.end_time <= self.start_time {
+            return self.start;
+        }
+
+        if time >= self.end_time {
+            return self.end;
+        }
+
+        let t = (time - self.start_time) / (self.end_time - self.start_time);
+        self.start * (1.0 - t) + self.end * t
+    }
+
+    // The scale averaged over the shutter interval, which is what the power
+    // of an animated light is scaled by
+    pub fn average(&self) -> Spectrum { (self.start + self.end) * 0.5 }
+}
+
+#[derive(Debug, PartialOrd, PartialEq)]
+pub struct LightSample {
+    pub u_pos: (f32, f32),
+    pub u_component: f32
+}
 
 impl LightSample {
-    pub fn new(rng: &mut RNG) -> LightSample { LightSample }
+    pub fn new(rng: &mut RNG) -> LightSample {
+        let u_pos = (rng.random_float(), rng.random_float());
+        LightSample { u_pos, u_component: rng.random_float() }
+    }
+
+    // Takes the num-th of the light samples that offsets reserved from
+    // sample, or returns None if sample doesn't have them.
+    pub fn from_sample(sample: &Sample, offsets: &LightSampleOffsets,
+                       num: usize) -> Option<LightSample> {
+        let u_component = *sample.one_d(offsets.component_offset)?.get(num)?;
+        let u_pos = sample.two_d(offsets.pos_offset)?;
+        Some(LightSample { u_pos: (u_pos[2 * num], u_pos[2 * num + 1]), u_component })
+    }
+}
+
+// The arrays of a Sample that hold n_samples light samples
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LightSampleOffsets {
+    pub n_samples: usize,
+    component_offset: usize,
+    pos_offset: usize
+}
+
+impl LightSampleOffsets {
+    pub fn new(count: usize, sample: &mut Sample) -> LightSampleOffsets {
+        LightSampleOffsets {
+            n_samples: count,
+            component_offset: sample.add_1d(count),
+            pos_offset: sample.add_2d(count)
+        }
+    }
+}
+
+// Samples a ray travelling in the direction d into the scene, starting on
+// the disk that covers the scene's bounding sphere as seen from d. Returns
+// the ray and the density of its origin with respect to the disk's area.
+// Lights that are infinitely far away emit rays this way.
+pub(crate) fn sample_ray_into_scene(scene: &Scene, d: &Vector, u1: f32, u2: f32,
+                                    time: f32) -> (Ray, f32) {
+    let (center, radius) = scene.world_bound().bounding_sphere();
+    let (v1, v2) = coordinate_system(d);
+    let (d1, d2) = concentric_sample_disk(u1, u2);
+    let p_disk = center + radius * (d1 * &v1 + d2 * &v2);
+    let mut ray = Ray::new_with(p_disk - radius * d, d.clone(), 0.0);
+    ray.time = time;
+    (ray, 1.0 / (::std::f32::consts::PI * radius * radius))
 }
 
 pub trait Light : ::std::marker::Send + ::std::marker::Sync + ::std::fmt::Debug {
@@ -51,8 +179,40 @@ pub trait Light : ::std::marker::Send + ::std::marker::Sync + ::std::fmt::Debug
         Spectrum::from(0.0)
     }
 
-    fn sample_l(&self, _: &Point, _: f32, _: LightSample, _: f32)
+    fn sample_l(&self, _: &Point, _: LightSample, _: f32)
                 -> (Spectrum, Vector, f32, VisibilityTester);
+
+    // The density, with respect to solid angle, of sample_l choosing wi
+    // from p. Delta lights can only be reached by sampling them.
+    fn pdf(&self, _: &Point, _: &Vector) -> f32 { 0.0 }
+
+    // Samples a ray leaving the light, for algorithms that follow light
+    // from its source such as photon mapping. ls picks where on the light
+    // the ray starts and (u1, u2) pick its direction. Returns the radiance
+    // carried by the ray, the ray, the light's surface normal at its origin
+    // and the density of choosing it, with respect to both the area of the
+    // origin and the solid angle of the direction.
+    fn sample_l_ray(&self, _: &Scene, _: LightSample, _: f32, _: f32, _: f32)
+                    -> (Spectrum, Ray, Normal, f32);
+
+    // The number of samples integrators should take from this light when
+    // estimating its direct illumination
+    fn num_samples(&self) -> usize { 1 }
+
+    // The name of the output group that this light belongs to, if any.
+    // Integrators that track groups also record the light's contribution
+    // separately under this name so that it can be rebalanced later.
+    fn group(&self) -> Option<&str> { None }
+
+    // Lights that emit from a single point report it here, which lets
+    // integrators place samples near the light.
+    fn delta_position(&self) -> Option<Point> { None }
+
+    // Bounds on where and in which directions the light emits, used to
+    // place it in a light BVH. Lights that aren't bounded in space, like
+    // distant lights, return None.
+    fn bounds(&self) -> Option<LightBounds> { None }
+
     fn power(&self, _: &Scene) -> Spectrum;
     fn is_delta_light(&self) -> bool;
 }
@@ -1,14 +1,21 @@
-use geometry::point::Point;
-use geometry::vector::Vector;
-use light::Light;
-use light::LightSample;
-use scene::Scene;
-use spectrum::Spectrum;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use visibility_tester::VisibilityTester;
-
-use light::internal;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::light::Light;
+use crate::light::LightBuilder;
+use crate::light::LightSample;
+use crate::light::bvh::LightBounds;
+use crate::montecarlo::uniform_sample_sphere;
+use crate::montecarlo::uniform_sphere_pdf;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::visibility_tester::VisibilityTester;
+
+use crate::light::internal;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct PointLight {
@@ -24,18 +31,43 @@ impl PointLight {
     }
 }
 
+impl LightBuilder for PointLight {
+    fn light_base_mut(&mut self) -> &mut internal::LightBase { &mut self.base }
+}
+
 impl Light for PointLight {
-    fn sample_l(&self, p: &Point, p_eps: f32, ls: LightSample, time: f32)
+    fn sample_l(&self, p: &Point, ls: LightSample, time: f32)
                 -> (Spectrum, Vector, f32, VisibilityTester) {
-        let w_i = &self.light_pos - p;
+        let w_i = (&self.light_pos - p).normalize();
         let pdf = 1.0;
-        let vis = VisibilityTester::segment(
-            p.clone(), p_eps, self.light_pos.clone(), 0.0, time);
-        (self.intensity.clone() / w_i.length_squared(), w_i, pdf, vis)
+        let vis = VisibilityTester::segment(p.clone(), self.light_pos.clone(), time);
+        let i = self.intensity * self.base.scale.at(time);
+        (i / self.light_pos.distance_squared(p), w_i, pdf, vis)
+    }
+
+    fn sample_l_ray(&self, _: &Scene, _: LightSample, u1: f32, u2: f32, time: f32)
+                    -> (Spectrum, Ray, Normal, f32) {
+        let dir = uniform_sample_sphere(u1, u2);
+        let n = Normal::from(&dir);
+        let mut ray = Ray::new_with(self.light_pos.clone(), dir, 0.0);
+        ray.time = time;
+        (self.intensity * self.base.scale.at(time), ray, n, uniform_sphere_pdf())
     }
 
     fn power(&self, _: &Scene) -> Spectrum {
-        ::std::f32::consts::PI * 4.0 * self.intensity
+        ::std::f32::consts::PI * 4.0 * self.intensity * self.base.scale.average()
+    }
+
+    fn num_samples(&self) -> usize { self.base.num_samples }
+
+    fn group(&self) -> Option<&str> { self.base.group.as_deref() }
+
+    fn delta_position(&self) -> Option<Point> { Some(self.light_pos.clone()) }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let phi = 4.0 * ::std::f32::consts::PI *
+            (self.intensity * self.base.scale.average()).y();
+        Some(LightBounds::point(self.light_pos.clone(), phi))
     }
 
     fn is_delta_light(&self) -> bool { true }
@@ -0,0 +1,168 @@
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+
+// A rectangular opening, like a window, that light from an environment
+// light has to pass through to reach the inside of a scene. Sampling
+// directions through the portals instead of over the whole sphere wastes no
+// samples on the walls around them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Portal {
+    corner: Point,
+    e1: Vector,
+    e2: Vector,
+    n: Vector,
+    area: f32
+}
+
+impl Portal {
+    // The portal spanned by the edges e1 and e2 leaving corner
+    pub fn new(corner: Point, e1: Vector, e2: Vector) -> Portal {
+        let c = e1.cross_with(&e2);
+        let area = c.length();
+        let n = if area > 0.0 { c / area } else { Vector::new() };
+        Portal { corner, e1, e2, n, area }
+    }
+
+    // Portals given by their four corners in order around the rectangle
+    pub fn from_corners(p: &[Point]) -> Portal {
+        assert_eq!(p.len(), 4);
+        let e1 = &p[1] - &p[0];
+        let e2 = &p[3] - &p[0];
+        if (&p[2] - &(&p[0] + &e1 + &e2)).length() > 1e-3 * (e1.length() + e2.length()) {
+            println!("WARNING: Portal corners don't form a parallelogram");
+        }
+        Portal::new(p[0].clone(), e1, e2)
+    }
+
+    pub fn area(&self) -> f32 { self.area }
+
+    // Distance along the ray from p in direction w to the portal, if it
+    // passes through it
+    fn intersect(&self, p: &Point, w: &Vector) -> Option<f32> {
+        let denom = w.dot(&self.n);
+        if denom == 0.0 || self.area == 0.0 {
+            return None;
+        }
+
+        let t = (&self.corner - p).dot(&self.n) / denom;
+        if t <= 0.0 {
+            return None;
+        }
+
+        let d = (p + &(t * w)) - &self.corner;
+        let u = d.dot(&self.e1) / self.e1.length_squared();
+        let v = d.dot(&self.e2) / self.e2.length_squared();
+        if (0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v) { Some(t) } else { None }
+    }
+
+    // Density with respect to solid angle at p of sampling the direction
+    // towards q, a point on the portal, by sampling the portal uniformly
+    // over an area of total_area
+    fn solid_angle_pdf(&self, p: &Point, q: &Point, total_area: f32) -> f32 {
+        let d = q - p;
+        let dist2 = d.length_squared();
+        let cos_theta = d.normalize().dot(&self.n).abs();
+        if cos_theta == 0.0 { 0.0 } else { dist2 / (cos_theta * total_area) }
+    }
+}
+
+// Samples a direction from p through one of the portals, each of which is
+// chosen in proportion to its area using u_component. Returns the direction
+// and its density with respect to solid angle.
+pub fn sample_portals(portals: &[Portal], p: &Point, u_component: f32,
+                      u1: f32, u2: f32) -> Option<(Vector, f32)> {
+    let total_area: f32 = portals.iter().map(Portal::area).sum();
+    if total_area <= 0.0 {
+        return None;
+    }
+
+    let mut target = u_component * total_area;
+    let portal = portals.iter().find(|portal| {
+        target -= portal.area;
+        target < 0.0
+    }).or(portals.last())?;
+
+    let q = &portal.corner + &(u1 * &portal.e1) + &(u2 * &portal.e2);
+    let d = &q - p;
+    if d.length_squared() == 0.0 {
+        return None;
+    }
+
+    // Portals may overlap as seen from p, in which case the direction
+    // could also have been sampled through the others
+    let wi = d.normalize();
+    Some((wi.clone(), portals_pdf(portals, p, &wi)))
+}
+
+// Density with respect to solid angle of sample_portals choosing wi at p
+pub fn portals_pdf(portals: &[Portal], p: &Point, wi: &Vector) -> f32 {
+    let total_area: f32 = portals.iter().map(Portal::area).sum();
+    if total_area <= 0.0 {
+        return 0.0;
+    }
+
+    portals.iter().filter_map(|portal| {
+        portal.intersect(p, wi)
+            .map(|t| portal.solid_angle_pdf(p, &(p + &(t * wi)), total_area))
+    }).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::RNG;
+
+    use std::f32::consts::PI;
+
+    // A unit square window in the plane z = 1, centered above the origin
+    fn window() -> Portal {
+        Portal::from_corners(&[Point::new_with(-0.5, -0.5, 1.0), Point::new_with(0.5, -0.5, 1.0),
+                               Point::new_with(0.5, 0.5, 1.0), Point::new_with(-0.5, 0.5, 1.0)])
+    }
+
+    #[test]
+    fn it_only_samples_directions_through_the_portals() {
+        let portals = vec![window()];
+        let p = Point::new();
+        let mut rng = RNG::new(0);
+        for _ in 0..64 {
+            let (wi, pdf) = sample_portals(&portals, &p, rng.random_float(),
+                                           rng.random_float(), rng.random_float()).unwrap();
+            assert!(wi.z > 0.0);
+            assert!(wi.x.abs() <= 0.5 * wi.z && wi.y.abs() <= 0.5 * wi.z);
+            assert!(pdf > 0.0);
+            assert!((pdf - portals_pdf(&portals, &p, &wi)).abs() < 1e-4 * pdf);
+        }
+
+        // Straight up the density is the inverse of the area that the
+        // window covers from one unit away
+        assert!((portals_pdf(&portals, &p, &Vector::new_with(0.0, 0.0, 1.0)) - 1.0).abs() < 1e-5);
+        assert_eq!(portals_pdf(&portals, &p, &Vector::new_with(0.0, 0.0, -1.0)), 0.0);
+        assert_eq!(portals_pdf(&portals, &p, &Vector::new_with(1.0, 0.0, 1.0).normalize()), 0.0);
+    }
+
+    #[test]
+    fn its_density_integrates_to_one() {
+        // Integrate the pdf over the sphere of directions
+        let portals = vec![window(), Portal::new(Point::new_with(2.0, -1.0, -1.0),
+                                                 Vector::new_with(0.0, 2.0, 0.0),
+                                                 Vector::new_with(0.0, 0.0, 2.0))];
+        let p = Point::new();
+        let n = 256;
+        let mut sum = 0.0;
+        for i in 0..n {
+            let theta = PI * ((i as f32) + 0.5) / (n as f32);
+            for j in 0..(2 * n) {
+                let phi = 2.0 * PI * ((j as f32) + 0.5) / ((2 * n) as f32);
+                let w = Vector::new_with(theta.sin() * phi.cos(), theta.sin() * phi.sin(),
+                                         theta.cos());
+                let d_omega = theta.sin() * (PI / (n as f32)) * (PI / (n as f32));
+                sum += portals_pdf(&portals, &p, &w) * d_omega;
+            }
+        }
+        assert!((sum - 1.0).abs() < 0.02, "Integral: {}", sum);
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::Arc;
+
+use crate::bbox::BBox;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::light::Light;
+use crate::light::LightBuilder;
+use crate::light::LightSample;
+use crate::light::bvh::LightBounds;
+use crate::montecarlo::uniform_cone_pdf;
+use crate::montecarlo::uniform_sample_cone;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::texture::imagemap::read_image;
+use crate::texture::imagewrap::ImageWrap;
+use crate::texture::mipmap::MIPMap;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Degrees;
+use crate::visibility_tester::VisibilityTester;
+
+use crate::light::internal;
+
+// Points closer than this to the light's image plane don't get projected
+const HITHER: f32 = 1e-3;
+
+// A point light that shines an image through a perspective frustum looking
+// down the light's +z axis, like a slide projector. Without an image it
+// lights everything in the frustum with its intensity.
+#[derive(Clone, Debug)]
+pub struct ProjectionLight {
+    base: internal::LightBase,
+    light_pos: Point,
+    intensity: Spectrum,
+    projection_map: Option<Arc<MIPMap<Spectrum>>>,
+    average: Spectrum,
+    tan_half_fov: f32,
+    screen_x: f32,
+    screen_y: f32,
+    cos_total_width: f32
+}
+
+impl ProjectionLight {
+    pub fn new(l2w: Transform, intensity: Spectrum, texname: &str, fov: f32)
+               -> ProjectionLight {
+        let image = if texname.is_empty() { None } else {
+            match read_image(&texname) {
+                Ok(img) => Some(img),
+                Err(e) => {
+                    println!("WARNING: Unable to read projection map {}: {}", texname, e);
+                    None
+                }
+            }
+        };
+
+        let (aspect, average, projection_map) = match image {
+            Some((w, h, texels)) => {
+                let average = texels.iter().fold(Spectrum::from(0.0), |acc, &s| acc + s) /
+                    (texels.len() as f32);
+                let mipmap = MIPMap::new(w as usize, h as usize, texels,
+                                         true, 8.0, ImageWrap::Black);
+                ((w as f32) / (h as f32), average, Some(Arc::new(mipmap)))
+            },
+            None => (1.0, Spectrum::from(1.0), None)
+        };
+
+        // The screen window spans [-1, 1] along the image's shorter axis
+        let (screen_x, screen_y) = if aspect > 1.0 { (aspect, 1.0) } else { (1.0, 1.0 / aspect) };
+        let tan_half_fov = (0.5 * fov.as_radians()).tan();
+        let tan_diag = tan_half_fov * (screen_x * screen_x + screen_y * screen_y).sqrt();
+
+        let light_pos = l2w.xf(Point::new());
+        ProjectionLight {
+            base: internal::LightBase::new(l2w),
+            light_pos,
+            intensity,
+            projection_map,
+            average,
+            tan_half_fov,
+            screen_x,
+            screen_y,
+            cos_total_width: 1.0 / (1.0 + tan_diag * tan_diag).sqrt()
+        }
+    }
+
+    fn projection(&self, w: Vector) -> Spectrum {
+        let wl = self.base.world_to_light.xf(w);
+
+        // Discard directions behind the projection
+        if wl.z < HITHER { return Spectrum::from(0.0); }
+
+        // Project onto the image plane and discard points outside of it
+        let px = wl.x / (wl.z * self.tan_half_fov);
+        let py = wl.y / (wl.z * self.tan_half_fov);
+        if px.abs() > self.screen_x || py.abs() > self.screen_y {
+            return Spectrum::from(0.0);
+        }
+
+        match &self.projection_map {
+            None => Spectrum::from(1.0),
+            Some(map) => {
+                let s = 0.5 * (px / self.screen_x + 1.0);
+                let t = 0.5 * (py / self.screen_y + 1.0);
+                map.lookup(s, t, 0.0, 0.0, 0.0, 0.0)
+            }
+        }
+    }
+}
+
+impl LightBuilder for ProjectionLight {
+    fn light_base_mut(&mut self) -> &mut internal::LightBase { &mut self.base }
+}
+
+impl Light for ProjectionLight {
+    fn sample_l(&self, p: &Point, _: LightSample, time: f32)
+                -> (Spectrum, Vector, f32, VisibilityTester) {
+        let w_i = (&self.light_pos - p).normalize();
+        let vis = VisibilityTester::segment(p.clone(), self.light_pos.clone(), time);
+        let i = self.intensity * self.base.scale.at(time) * self.projection(-w_i.clone());
+        (i / self.light_pos.distance_squared(p), w_i, 1.0, vis)
+    }
+
+    // Emits rays over the cone that bounds the frustum, so some of them carry
+    // no light
+    fn sample_l_ray(&self, _: &Scene, _: LightSample, u1: f32, u2: f32, time: f32)
+                    -> (Spectrum, Ray, Normal, f32) {
+        let v = uniform_sample_cone(u1, u2, self.cos_total_width);
+        let dir = self.base.light_to_world.xf(v).normalize();
+        let n = Normal::from(&dir);
+        let i = self.intensity * self.base.scale.at(time) * self.projection(dir.clone());
+        let mut ray = Ray::new_with(self.light_pos.clone(), dir, 0.0);
+        ray.time = time;
+        (i, ray, n, uniform_cone_pdf(self.cos_total_width))
+    }
+
+    // Approximates the frustum by the cone that bounds it
+    fn power(&self, _: &Scene) -> Spectrum {
+        self.intensity * self.average * self.base.scale.average() *
+            (2.0 * ::std::f32::consts::PI * (1.0 - self.cos_total_width))
+    }
+
+    fn num_samples(&self) -> usize { self.base.num_samples }
+
+    fn group(&self) -> Option<&str> { self.base.group.as_deref() }
+
+    fn delta_position(&self) -> Option<Point> { Some(self.light_pos.clone()) }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let w = self.base.light_to_world.xf(Vector::forward());
+        let phi = 4.0 * ::std::f32::consts::PI *
+            (self.intensity * self.average * self.base.scale.average()).y();
+        Some(LightBounds::new(BBox::from(self.light_pos.clone()), w, phi,
+                              self.cos_total_width, 1.0, false))
+    }
+
+    fn is_delta_light(&self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::RNG;
+
+    use std::path::Path;
+
+    fn sample_at(light: &ProjectionLight, p: Point) -> Spectrum {
+        let mut rng = RNG::new(0);
+        let (l, ..) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+        l
+    }
+
+    #[test]
+    fn it_lights_its_frustum() {
+        let l2w = Transform::translate(&Vector::new_with(0.0, 0.0, -2.0));
+        let light = ProjectionLight::new(l2w, Spectrum::from(4.0), "", 90.0);
+
+        // Straight ahead
+        assert_eq!(sample_at(&light, Point::new()), Spectrum::from(1.0));
+
+        // Just inside and outside of the edge of the 90 degree frustum
+        assert!(!sample_at(&light, Point::new_with(1.9, 0.0, 0.0)).is_black());
+        assert!(sample_at(&light, Point::new_with(2.1, 0.0, 0.0)).is_black());
+        assert!(!sample_at(&light, Point::new_with(1.9, -1.9, 0.0)).is_black());
+
+        // Behind the light
+        assert!(sample_at(&light, Point::new_with(0.0, 0.0, -3.0)).is_black());
+        assert!(light.is_delta_light());
+        assert_eq!(light.delta_position(), Some(Point::new_with(0.0, 0.0, -2.0)));
+    }
+
+    #[test]
+    fn it_has_the_power_of_its_bounding_cone() {
+        let light = ProjectionLight::new(Transform::new(), Spectrum::from(1.0), "", 90.0);
+
+        // The frustum's corners are at 45 degrees in both x and y
+        let cos_diag = 1.0 / 3f32.sqrt();
+        let expected = 2.0 * ::std::f32::consts::PI * (1.0 - cos_diag);
+        assert!((light.power(&Scene::new()).y() - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_projects_images() {
+        let this_file = Path::new(file!());
+        let test_file = Path::join(this_file.parent().unwrap().parent().unwrap(),
+                                   "texture/testdata/checkerboard_square.png");
+        let l2w = Transform::translate(&Vector::new_with(0.0, 0.0, -2.0));
+        let light = ProjectionLight::new(l2w, Spectrum::from(4.0),
+                                         test_file.to_str().unwrap(), 90.0);
+
+        // The image modulates the light, so not every point is lit equally
+        let mut values = Vec::new();
+        for i in 0..16 {
+            let x = -1.8 + 3.6 * (i as f32) / 15.0;
+            values.push(sample_at(&light, Point::new_with(x, 0.5, 0.0)).y());
+        }
+        let min = values.iter().cloned().fold(f32::MAX, f32::min);
+        let max = values.iter().cloned().fold(f32::MIN, f32::max);
+        assert!(max > min);
+
+        // Images that don't exist fall back to lighting the whole frustum
+        let missing = ProjectionLight::new(Transform::new(), Spectrum::from(1.0),
+                                           "no/such/image.png", 90.0);
+        assert_eq!(sample_at(&missing, Point::new_with(0.0, 0.0, 1.0)), Spectrum::from(1.0));
+    }
+}
@@ -1,24 +1,35 @@
-use geometry::normal::Normalize;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use light::Light;
-use light::LightSample;
-use scene::Scene;
-use spectrum::Spectrum;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use utils::Degrees;
-use visibility_tester::VisibilityTester;
-
-use light::internal;
-
-#[derive(Clone, Debug, PartialEq)]
+use crate::bbox::BBox;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::light::Light;
+use crate::light::LightBuilder;
+use crate::light::LightSample;
+use crate::light::bvh::LightBounds;
+use crate::montecarlo::uniform_cone_pdf;
+use crate::montecarlo::uniform_sample_cone;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::texture::ColorTextureReference;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Degrees;
+use crate::visibility_tester::VisibilityTester;
+
+use crate::light::internal;
+
+#[derive(Clone, Debug)]
 pub struct SpotLight {
     base: internal::LightBase,
     light_pos: Point,
     intensity: Spectrum,
     cos_total_width: f32,
-    cos_falloff_start: f32
+    cos_falloff_start: f32,
+    tan_total_width: f32,
+    gobo: Option<ColorTextureReference>
 }
 
 impl SpotLight {
@@ -31,9 +42,19 @@ impl SpotLight {
             intensity,
             cos_total_width: width.as_radians().cos(),
             cos_falloff_start: fall.as_radians().cos(),
+            tan_total_width: width.as_radians().tan(),
+            gobo: None
         }
     }
 
+    // Modulates the light by a texture stretched over the cone, like a gobo
+    // in front of a stage light. The texture is looked up with (u, v)
+    // spanning [0, 1] across the cone's widest extent in light space x and y.
+    pub fn with_gobo(mut self, gobo: ColorTextureReference) -> SpotLight {
+        self.gobo = Some(gobo);
+        self
+    }
+
     fn falloff(&self, w: Vector) -> f32 {
         let wl = self.base.world_to_light.xf(w);
         let cos_theta = wl.z;
@@ -45,24 +66,139 @@ impl SpotLight {
             delta * delta * delta * delta
         }
     }
+
+    fn gobo(&self, w: Vector) -> Spectrum {
+        match &self.gobo {
+            None => Spectrum::from(1.0),
+            Some(tex) => {
+                let wl = self.base.world_to_light.xf(w);
+                if wl.z <= 0.0 { return Spectrum::from(0.0); }
+
+                let mut dg = DifferentialGeometry::new();
+                dg.u = 0.5 * (wl.x / (wl.z * self.tan_total_width) + 1.0);
+                dg.v = 0.5 * (wl.y / (wl.z * self.tan_total_width) + 1.0);
+                tex.evaluate(&dg)
+            }
+        }
+    }
+}
+
+impl LightBuilder for SpotLight {
+    fn light_base_mut(&mut self) -> &mut internal::LightBase { &mut self.base }
 }
 
 impl Light for SpotLight {
-    fn sample_l(&self, p: &Point, p_eps: f32, ls: LightSample, time: f32)
+    fn sample_l(&self, p: &Point, ls: LightSample, time: f32)
                 -> (Spectrum, Vector, f32, VisibilityTester) {
         let w_i = (&self.light_pos - p).normalize();
         let pdf = 1.0;
-        let vis = VisibilityTester::segment(
-            p.clone(), p_eps, self.light_pos.clone(), 0.0, time);
-        let i = self.intensity.clone() * self.falloff(-w_i.clone());
-        (i / w_i.length_squared(), w_i, pdf, vis)
+        let vis = VisibilityTester::segment(p.clone(), self.light_pos.clone(), time);
+        let i = self.intensity * self.base.scale.at(time) *
+            self.falloff(-w_i.clone()) * self.gobo(-w_i.clone());
+        (i / self.light_pos.distance_squared(p), w_i, pdf, vis)
+    }
+
+    fn sample_l_ray(&self, _: &Scene, _: LightSample, u1: f32, u2: f32, time: f32)
+                    -> (Spectrum, Ray, Normal, f32) {
+        let v = uniform_sample_cone(u1, u2, self.cos_total_width);
+        let dir = self.base.light_to_world.xf(v).normalize();
+        let n = Normal::from(&dir);
+        let i = self.intensity * self.base.scale.at(time) *
+            self.falloff(dir.clone()) * self.gobo(dir.clone());
+        let mut ray = Ray::new_with(self.light_pos.clone(), dir, 0.0);
+        ray.time = time;
+        (i, ray, n, uniform_cone_pdf(self.cos_total_width))
     }
 
     fn power(&self, _: &Scene) -> Spectrum {
         let falloff_scale = 1.0 -
             0.5 * (self.cos_falloff_start + self.cos_total_width);
-        ::std::f32::consts::PI * 2.0 * falloff_scale * self.intensity
+        ::std::f32::consts::PI * 2.0 * falloff_scale * self.intensity *
+            self.base.scale.average()
+    }
+
+    fn num_samples(&self) -> usize { self.base.num_samples }
+
+    fn group(&self) -> Option<&str> { self.base.group.as_deref() }
+
+    fn delta_position(&self) -> Option<Point> { Some(self.light_pos.clone()) }
+
+    // The light is at full strength inside of the falloff cone and fades out
+    // to the edge of the total width
+    fn bounds(&self) -> Option<LightBounds> {
+        let w = self.base.light_to_world.xf(Vector::forward());
+        let phi = 4.0 * ::std::f32::consts::PI *
+            (self.intensity * self.base.scale.average()).y();
+        let theta_e = self.cos_total_width.acos() - self.cos_falloff_start.acos();
+        Some(LightBounds::new(BBox::from(self.light_pos.clone()), w, phi,
+                              self.cos_falloff_start, theta_e.max(0.0).cos(), false))
     }
 
     fn is_delta_light(&self) -> bool { true }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::RNG;
+    use crate::texture::mapping2d::UVMapping2D;
+    use crate::texture::uv::UVTexture;
+
+    use std::sync::Arc;
+
+    fn sample_at(light: &SpotLight, p: Point) -> Spectrum {
+        let mut rng = RNG::new(0);
+        let (l, ..) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+        l
+    }
+
+    // A point at distance one from the origin, angle degrees away from +z
+    fn point_at(angle: f32) -> Point {
+        Point::new_with(angle.as_radians().sin(), 0.0, angle.as_radians().cos())
+    }
+
+    #[test]
+    fn it_falls_off_towards_the_edge_of_its_cone() {
+        let light = SpotLight::new(Transform::new(), Spectrum::from(2.0), 30.0, 20.0);
+        assert_eq!(sample_at(&light, point_at(0.0)), Spectrum::from(2.0));
+        assert_eq!(sample_at(&light, point_at(19.0)), Spectrum::from(2.0));
+
+        let edge = sample_at(&light, point_at(25.0)).y();
+        assert!(edge > 0.0 && edge < 2.0);
+        assert!(sample_at(&light, point_at(31.0)).is_black());
+        assert!(sample_at(&light, point_at(180.0)).is_black());
+    }
+
+    #[test]
+    fn it_emits_rays_inside_of_its_cone() {
+        let l2w = Transform::translate(&Vector::new_with(0.0, 1.0, 0.0));
+        let light = SpotLight::new(l2w, Spectrum::from(2.0), 30.0, 20.0);
+        let cos_width = 30f32.as_radians().cos();
+        let mut rng = RNG::new(0);
+        for _ in 0..64 {
+            let ls = LightSample::new(&mut rng);
+            let (u1, u2) = (rng.random_float(), rng.random_float());
+            let (l, ray, _, pdf) = light.sample_l_ray(&Scene::new(), ls, u1, u2, 0.0);
+            assert_eq!(ray.o, Point::new_with(0.0, 1.0, 0.0));
+            assert!(ray.d.z >= cos_width - 1e-4);
+            assert!(l.y() <= 2.0);
+            assert!((pdf - 1.0 / (2.0 * ::std::f32::consts::PI * (1.0 - cos_width))).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn it_projects_its_gobo_over_the_cone() {
+        let gobo = Arc::new(UVTexture::new(Box::new(UVMapping2D::new())));
+        let light = SpotLight::new(Transform::new(), Spectrum::from(1.0), 45.0, 45.0)
+            .with_gobo(gobo);
+
+        // The center of the cone is the center of the texture
+        let rgb = sample_at(&light, point_at(0.0)).to_rgb();
+        assert!((rgb[0] - 0.5).abs() < 1e-4 && (rgb[1] - 0.5).abs() < 1e-4);
+
+        // Halfway out to the edge along +x is three quarters across it
+        let p = Point::new_with(0.5, 0.0, 1.0);
+        let rgb = (sample_at(&light, p.clone()) * p.distance_squared(&Point::new())).to_rgb();
+        assert!((rgb[0] - 0.75).abs() < 1e-4 && (rgb[1] - 0.5).abs() < 1e-4);
+    }
+}
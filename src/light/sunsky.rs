@@ -0,0 +1,360 @@
+use std::f32::consts::PI;
+
+use crate::bbox::HasBounds;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::light::Light;
+use crate::light::LightBuilder;
+use crate::light::LightSample;
+use crate::light::distant::DistantLight;
+use crate::light::portal::Portal;
+use crate::light::portal::portals_pdf;
+use crate::light::portal::sample_portals;
+use crate::light::sample_ray_into_scene;
+use crate::montecarlo::uniform_sample_sphere;
+use crate::montecarlo::uniform_sphere_pdf;
+use crate::ray::Ray;
+use crate::ray::RayDifferential;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::visibility_tester::VisibilityTester;
+
+use crate::light::internal;
+
+// Illuminance of the sun at the top of the atmosphere, in klux
+const SOLAR_ILLUMINANCE: f32 = 128.0;
+
+// Wavelengths, in micrometers, at which the sun's attenuation is computed
+// for each of red, green and blue
+const RGB_WAVELENGTHS: [f32; 3] = [0.680, 0.550, 0.440];
+
+// Resolution of the grid used to integrate the sky's radiance
+const SKY_INTEGRATION_STEPS: usize = 32;
+
+// Perez et al.'s model of the sky's luminance distribution, relative to
+// the zenith, with coefficients A through E
+fn perez(coeffs: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = *coeffs;
+    let cos_gamma = gamma.cos();
+    (1.0 + a * (b / cos_theta.max(1e-3)).exp()) *
+        (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+// Evaluates the polynomial in turbidity and sun angle that gives the
+// zenith's chromaticity
+fn zenith_chromaticity(t: f32, theta_s: f32, m: &[[f32; 4]; 3]) -> f32 {
+    let ts = [theta_s * theta_s * theta_s, theta_s * theta_s, theta_s, 1.0];
+    let row = |r: &[f32; 4]| r.iter().zip(ts.iter()).map(|(a, b)| a * b).sum::<f32>();
+    t * t * row(&m[0]) + t * row(&m[1]) + row(&m[2])
+}
+
+// Converts luminance and chromaticity to a spectrum
+fn from_xyy(x: f32, y: f32, lum: f32) -> Spectrum {
+    if y <= 0.0 { return Spectrum::from(0.0); }
+    Spectrum::from_xyz([x / y * lum, lum, (1.0 - x - y) / y * lum])
+}
+
+// A procedural daylight sky following Preetham et al.'s analytic model,
+// which gives the sky's radiance from the sun's direction and the
+// atmosphere's turbidity. The sky is defined in light space with +z up and
+// the ground below the horizon is a diffuse surface with the given albedo
+// lit by the sun and sky. Unlike in Hosek and Wilkie's model, the albedo
+// doesn't change the radiance of the sky itself. Radiance is in thousands
+// of cd/m^2, and the matching sun is available from sun_light().
+#[derive(Clone, Debug)]
+pub struct SunSkyLight {
+    base: internal::LightBase,
+    sun_dir: Vector,
+    theta_s: f32,
+    turbidity: f32,
+    scale: Spectrum,
+    perez_x: [f32; 5],
+    perez_y: [f32; 5],
+    perez_lum: [f32; 5],
+    // Chromaticity and luminance at the zenith
+    zenith: (f32, f32, f32),
+    ground: Spectrum,
+    average: Spectrum,
+    portals: Vec<Portal>
+}
+
+impl SunSkyLight {
+    pub fn new(l2w: Transform, sun_dir: Vector, turbidity: f32, albedo: Spectrum,
+               scale: Spectrum) -> SunSkyLight {
+        let sun_dir = sun_dir.normalize();
+        // The model doesn't hold for suns below the horizon
+        let theta_s = sun_dir.z.clamp(0.0, 1.0).acos();
+        let t = turbidity.max(1.0);
+
+        let perez_lum = [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251,
+                         0.1206 * t - 2.5771, -0.0670 * t + 0.3703];
+        let perez_x = [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125,
+                       -0.0641 * t - 0.8989, -0.0033 * t + 0.0452];
+        let perez_y = [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102,
+                       -0.0441 * t - 1.6537, -0.0109 * t + 0.0529];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_lum = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let zenith_x = zenith_chromaticity(t, theta_s, &[
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886]]);
+        let zenith_y = zenith_chromaticity(t, theta_s, &[
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688]]);
+
+        let mut light = SunSkyLight {
+            base: internal::LightBase::new(l2w),
+            sun_dir,
+            theta_s,
+            turbidity: t,
+            scale,
+            perez_x,
+            perez_y,
+            perez_lum,
+            zenith: (zenith_x, zenith_y, zenith_lum),
+            ground: Spectrum::from(0.0),
+            average: Spectrum::from(0.0),
+            portals: Vec::new()
+        };
+
+        // Light the ground with the sky and the sun, and then find the
+        // average radiance over all directions
+        let mut irradiance = light.sun_radiance() * light.sun_dir.z.max(0.0);
+        let mut sky_sum = Spectrum::from(0.0);
+        let n = SKY_INTEGRATION_STEPS;
+        let d_theta = 0.5 * PI / (n as f32);
+        let d_phi = 2.0 * PI / (2 * n) as f32;
+        for i in 0..n {
+            let theta = ((i as f32) + 0.5) * d_theta;
+            for j in 0..(2 * n) {
+                let phi = ((j as f32) + 0.5) * d_phi;
+                let w = Vector::new_with(theta.sin() * phi.cos(), theta.sin() * phi.sin(),
+                                         theta.cos());
+                let l = light.sky_radiance(&w);
+                let d_omega = theta.sin() * d_theta * d_phi;
+                irradiance = irradiance + l * theta.cos() * d_omega;
+                sky_sum = sky_sum + l * d_omega;
+            }
+        }
+
+        light.ground = albedo * irradiance / PI;
+        light.average = (sky_sum + light.ground * (2.0 * PI)) / (4.0 * PI);
+        light
+    }
+
+    // Only samples directions through the given world space portals. This
+    // is for interiors that are lit by the sky through small openings, and
+    // directions that don't pass through a portal are treated as blocked.
+    pub fn with_portals(mut self, portals: Vec<Portal>) -> SunSkyLight {
+        self.portals = portals;
+        self
+    }
+
+    // The sun's illuminance after passing through the atmosphere, which
+    // scatters away more blue light the lower the sun is
+    fn sun_radiance(&self) -> Spectrum {
+        if self.sun_dir.z <= 0.0 {
+            return Spectrum::from(0.0);
+        }
+
+        // Relative optical mass of the air between the sun and the ground
+        let theta_deg = self.theta_s.to_degrees();
+        let m = 1.0 / (self.theta_s.cos() + 0.15 * (93.885 - theta_deg).powf(-1.253));
+
+        // Rayleigh scattering by molecules and Angstrom's model of aerosols
+        let beta = 0.04608365 * self.turbidity - 0.04586025;
+        let rgb = RGB_WAVELENGTHS.map(|lambda| {
+            let tau_r = (-0.008735 * lambda.powf(-4.08) * m).exp();
+            let tau_a = (-beta * lambda.powf(-1.3) * m).exp();
+            SOLAR_ILLUMINANCE * tau_r * tau_a
+        });
+        Spectrum::from_rgb(rgb) * self.scale
+    }
+
+    // A distant light for the sun to go with the sky
+    pub fn sun_light(&self) -> DistantLight {
+        DistantLight::new(self.base.light_to_world.clone(), self.sun_radiance(),
+                          self.sun_dir.clone())
+    }
+
+    // Radiance of the sky in the light space direction w, which must be
+    // above the horizon
+    fn sky_radiance(&self, w: &Vector) -> Spectrum {
+        let cos_theta = w.z;
+        let gamma = w.dot(&self.sun_dir).clamp(-1.0, 1.0).acos();
+        let (zx, zy, zlum) = self.zenith;
+        let rel = |coeffs: &[f32; 5]| {
+            perez(coeffs, cos_theta, gamma) / perez(coeffs, 1.0, self.theta_s)
+        };
+        from_xyy(zx * rel(&self.perez_x), zy * rel(&self.perez_y),
+                 zlum * rel(&self.perez_lum)) * self.scale
+    }
+
+    fn radiance(&self, w: &Vector, time: f32) -> Spectrum {
+        let wl = self.base.world_to_light.xf(w.clone()).normalize();
+        let l = if wl.z > 0.0 { self.sky_radiance(&wl) } else { self.ground };
+        l * self.base.scale.at(time)
+    }
+}
+
+impl LightBuilder for SunSkyLight {
+    fn light_base_mut(&mut self) -> &mut internal::LightBase { &mut self.base }
+}
+
+impl Light for SunSkyLight {
+    fn le(&self, ray: &RayDifferential) -> Spectrum {
+        self.radiance(&ray.ray.d, ray.ray.time)
+    }
+
+    fn sample_l(&self, p: &Point, ls: LightSample, time: f32)
+                -> (Spectrum, Vector, f32, VisibilityTester) {
+        if !self.portals.is_empty() {
+            let (u1, u2) = ls.u_pos;
+            let (wi, pdf) = sample_portals(&self.portals, p, ls.u_component, u1, u2)
+                .unwrap_or((Vector::forward(), 0.0));
+            let vis = VisibilityTester::ray(p.clone(), wi.clone(), time);
+            let l = if pdf > 0.0 { self.radiance(&wi, time) } else { Spectrum::from(0.0) };
+            return (l, wi, pdf, vis);
+        }
+
+        let wl = uniform_sample_sphere(ls.u_pos.0, ls.u_pos.1);
+        let wi = self.base.light_to_world.xf(wl).normalize();
+        let vis = VisibilityTester::ray(p.clone(), wi.clone(), time);
+        (self.radiance(&wi, time), wi, uniform_sphere_pdf(), vis)
+    }
+
+    fn pdf(&self, p: &Point, wi: &Vector) -> f32 {
+        if self.portals.is_empty() { uniform_sphere_pdf() } else {
+            portals_pdf(&self.portals, p, wi)
+        }
+    }
+
+    // Picks the direction that the light arrives from over the sphere and
+    // then where the ray enters the scene
+    fn sample_l_ray(&self, scene: &Scene, ls: LightSample, u1: f32, u2: f32, time: f32)
+                    -> (Spectrum, Ray, Normal, f32) {
+        let wl = uniform_sample_sphere(u1, u2);
+        let w = self.base.light_to_world.xf(wl).normalize();
+        let d = -w.clone();
+        let (ray, area_pdf) = sample_ray_into_scene(scene, &d, ls.u_pos.0, ls.u_pos.1, time);
+        (self.radiance(&w, time), ray, Normal::from(d), uniform_sphere_pdf() * area_pdf)
+    }
+
+    fn power(&self, scene: &Scene) -> Spectrum {
+        let (_, world_radius) = scene.world_bound().bounding_sphere();
+        self.average * self.base.scale.average() * PI * world_radius * world_radius
+    }
+
+    fn num_samples(&self) -> usize { self.base.num_samples }
+
+    fn group(&self) -> Option<&str> { self.base.group.as_deref() }
+
+    fn is_delta_light(&self) -> bool { false }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn le(light: &SunSkyLight, d: Vector) -> Spectrum {
+        light.le(&RayDifferential::new_with(Point::new(), d, 0.0))
+    }
+
+    fn sky(sun_dir: Vector, albedo: f32) -> SunSkyLight {
+        SunSkyLight::new(Transform::new(), sun_dir, 3.0, Spectrum::from(albedo),
+                         Spectrum::from(1.0))
+    }
+
+    #[test]
+    fn it_is_brightest_around_the_sun() {
+        let sun_dir = Vector::new_with(1.0, 0.0, 1.0).normalize();
+        let light = sky(sun_dir.clone(), 0.0);
+
+        let zenith = le(&light, Vector::new_with(0.0, 0.0, 1.0)).y();
+        assert!(zenith > 0.0);
+        assert!((zenith - light.zenith.2).abs() < 0.05 * light.zenith.2);
+
+        let near_sun = le(&light, Vector::new_with(1.0, 0.0, 1.1)).y();
+        let away = le(&light, Vector::new_with(-1.0, 0.0, 1.0)).y();
+        assert!(near_sun > zenith && zenith > away && away > 0.0);
+
+        // Nothing comes from a black ground
+        assert!(le(&light, Vector::new_with(0.0, 0.0, -1.0)).is_black());
+        assert!(!light.is_delta_light());
+    }
+
+    #[test]
+    fn it_lights_the_ground_with_its_albedo() {
+        let sun_dir = Vector::new_with(0.0, 0.0, 1.0);
+        let grey = le(&sky(sun_dir.clone(), 0.2), Vector::new_with(0.0, 0.0, -1.0));
+        let white = le(&sky(sun_dir, 0.4), Vector::new_with(0.0, 0.0, -1.0));
+        assert!(grey.y() > 0.0);
+        assert!((white.y() - 2.0 * grey.y()).abs() < 1e-3 * white.y());
+    }
+
+    #[test]
+    fn it_samples_the_whole_sphere() {
+        use crate::rng::RNG;
+
+        let light = sky(Vector::new_with(0.0, 1.0, 1.0), 0.3);
+        let mut rng = RNG::new(0);
+        for _ in 0..64 {
+            let p = Point::new();
+            let (l, wi, pdf, _) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+            assert!((wi.length_squared() - 1.0).abs() < 1e-4);
+            assert_eq!(pdf, light.pdf(&p, &wi));
+            assert_eq!(l, le(&light, wi));
+        }
+        assert!(light.power(&Scene::new()).y() > 0.0);
+    }
+
+    #[test]
+    fn it_only_shines_through_its_portals() {
+        use crate::rng::RNG;
+
+        let window = Portal::new(Point::new_with(-1.0, -1.0, 2.0),
+                                 Vector::new_with(2.0, 0.0, 0.0),
+                                 Vector::new_with(0.0, 2.0, 0.0));
+        let light = sky(Vector::new_with(0.0, 1.0, 1.0), 0.3).with_portals(vec![window]);
+        let p = Point::new();
+        let mut rng = RNG::new(0);
+        for _ in 0..64 {
+            let (l, wi, pdf, _) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+            assert!(wi.z > 0.0 && wi.x.abs() <= 0.5 * wi.z && wi.y.abs() <= 0.5 * wi.z);
+            assert!((pdf - light.pdf(&p, &wi)).abs() < 1e-4 * pdf);
+            assert_eq!(l, le(&light, wi));
+        }
+
+        // Directions through the walls are never sampled
+        assert_eq!(light.pdf(&p, &Vector::new_with(1.0, 0.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn its_sun_reddens_towards_the_horizon() {
+        let high = sky(Vector::new_with(0.0, 0.0, 1.0), 0.0).sun_light();
+        let low = sky(Vector::new_with(1.0, 0.0, 0.1), 0.0).sun_light();
+        let mut rng = crate::rng::RNG::new(0);
+        let mut sample = |light: &DistantLight| {
+            let (l, wi, ..) = light.sample_l(&Point::new(), LightSample::new(&mut rng), 0.0);
+            (l.to_rgb(), wi)
+        };
+
+        let (high_rgb, high_wi) = sample(&high);
+        let (low_rgb, low_wi) = sample(&low);
+        assert!((high_wi - Vector::new_with(0.0, 0.0, 1.0)).length() < 1e-4);
+        assert!(low_wi.x > 0.9);
+        assert!(high_rgb[1] > low_rgb[1]);
+        assert!(low_rgb[0] / low_rgb[2] > high_rgb[0] / high_rgb[2]);
+
+        // A sun below the horizon doesn't shine
+        let (set, _) = sample(&sky(Vector::new_with(0.0, 1.0, -0.5), 0.0).sun_light());
+        assert_eq!(set, [0.0, 0.0, 0.0]);
+    }
+}
@@ -1,36 +1,119 @@
-extern crate pbrt_rust;
-
+use std::cmp::max;
+use std::cmp::min;
 use std::collections::HashMap;
+use std::fmt::Debug;
 use std::sync::Arc;
 use std::ops::Index;
 use std::ops::IndexMut;
+use std::time::Duration;
+use std::time::Instant;
 
 use pbrt_rust::area_light::AreaLight;
+use pbrt_rust::bbox::BBox;
+use pbrt_rust::bbox::Union;
+use pbrt_rust::bsdf::BxDFType;
+use pbrt_rust::bsdf::fourier::FourierBSDFTable;
+use pbrt_rust::bsdf::fresnel::{Conductor, IndexOfRefraction};
+use pbrt_rust::bsdf::microfacet::DistributionKind;
+use pbrt_rust::camera::Aperture;
+use pbrt_rust::camera::Camera;
+use pbrt_rust::camera::film::Film;
+use pbrt_rust::camera::contact_sheet::ContactSheet;
 use pbrt_rust::geometry::point::Point;
+use pbrt_rust::geometry::normal::Normalize;
 use pbrt_rust::geometry::vector::Vector;
+use pbrt_rust::geometry::vector::coordinate_system;
+use pbrt_rust::integrator::BounceDepths;
+use pbrt_rust::integrator::SurfaceIntegrator;
+use pbrt_rust::integrator::VolumeIntegrator;
+use pbrt_rust::material::DisneyMaterial;
+use pbrt_rust::material::HairAbsorption;
 use pbrt_rust::material::Material;
+use pbrt_rust::light::diffuse::DiffuseAreaLight;
+use pbrt_rust::light::distant::DistantLight;
+use pbrt_rust::light::goniometric::GonioPhotometricLight;
 use pbrt_rust::light::point::PointLight;
+use pbrt_rust::light::portal::Portal;
+use pbrt_rust::light::projection::ProjectionLight;
+use pbrt_rust::light::spot::SpotLight;
+use pbrt_rust::light::sunsky::SunSkyLight;
+use pbrt_rust::light::AnimatedScale;
 use pbrt_rust::light::Light;
+use pbrt_rust::light::LightBuilder;
 use pbrt_rust::params::{ParamSet, TextureParams};
 use pbrt_rust::primitive::{Primitive, FullyRefinable};
+use pbrt_rust::filter::Filter;
 use pbrt_rust::renderer::Renderer;
+use pbrt_rust::sampler::AdaptiveTest;
+use pbrt_rust::sampler::HaltonScramble;
+use pbrt_rust::sampler::Sampler;
+use pbrt_rust::sampler_renderer::SamplerRenderer;
 use pbrt_rust::scene::Scene;
+use pbrt_rust::shape::BackfaceCulling;
+use pbrt_rust::shape::CurveType;
+use pbrt_rust::shape::DistanceGrid;
 use pbrt_rust::shape::Shape;
 use pbrt_rust::spectrum::Spectrum;
+use pbrt_rust::texture::NoiseBasis;
 use pbrt_rust::texture::Texture;
+use pbrt_rust::texture::ScalarTextureReference;
+use pbrt_rust::texture::TextureReference;
 use pbrt_rust::texture::ConstantTexture;
+use pbrt_rust::texture::ScaleTexture;
+use pbrt_rust::texture::bilerp::BilerpTexture;
+use pbrt_rust::texture::checkerboard::{Checkerboard3DTexture, CheckerboardTexture};
+use pbrt_rust::texture::dots::DotsTexture;
+use pbrt_rust::texture::expr::ExprTexture;
+use pbrt_rust::texture::fbm::{FBmTexture, WindyTexture, WrinkledTexture};
+use pbrt_rust::texture::imagecache::LazyTextureCache;
+use pbrt_rust::texture::imagemap::{ImageEncoding, TextureCache};
+use pbrt_rust::texture::imagewrap::ImageWrap;
+use pbrt_rust::texture::mapping2d::{CylindricalMapping2D, PlanarMapping2D};
+use pbrt_rust::texture::mapping2d::{SphericalMapping2D, TextureMapping2D, UVMapping2D};
+use pbrt_rust::texture::mapping3d::{IdentityMapping3D, TextureMapping3D};
+use pbrt_rust::texture::marble::MarbleTexture;
+use pbrt_rust::texture::mix::MixTexture;
+use pbrt_rust::texture::uv::UVTexture;
 use pbrt_rust::transform::animated::AnimatedTransform;
+use pbrt_rust::transform::transform::ApplyTransform;
 use pbrt_rust::transform::transform::Transform;
+use pbrt_rust::utils::Lerp;
 use pbrt_rust::volume::VolumeRegion;
 use pbrt_rust::volume::aggregate::AggregateVolumeRegion;
 
+// The space that the scene is rendered in. Floats can't resolve small
+// distances far from the origin, so scenes that are far from it render more
+// precisely relative to the camera, where the geometry in view is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RenderSpace {
+    World,
+    // The camera's own space, centered on it and looking down +z
+    Camera,
+    // World space moved so that the camera is at the origin. Unlike camera
+    // space, it keeps the world's axes.
+    CameraWorld
+}
+
 pub struct Options {
     num_cores: usize,
     quick_render: bool,
     quiet: bool,
     verbose: bool,
     open_window: bool,
-    image_file: String
+    image_file: String,
+    // If set, only this pixel is rendered and a log of how its radiance
+    // was computed is printed as JSON instead of writing an image
+    debug_pixel: Option<(i32, i32)>,
+    render_space: RenderSpace,
+    // If set, thumbnails of every image rendered are gathered into a
+    // contact sheet written to this file
+    contact_sheet: Option<String>,
+    // If set, image textures are loaded when they're first used, and no
+    // more than this many bytes of them are kept in memory at a time
+    texture_budget: Option<usize>,
+    // If set, no image texture is filtered more anisotropically than this,
+    // whatever its own "maxanisotropy"
+    max_anisotropy: Option<f32>
 }
 
 impl Options {
@@ -41,7 +124,12 @@ impl Options {
             quiet: false,
             verbose: false,
             open_window: false,
-            image_file: String::new()
+            image_file: String::new(),
+            debug_pixel: None,
+            render_space: RenderSpace::World,
+            contact_sheet: None,
+            texture_budget: None,
+            max_anisotropy: None
         }
     }
 
@@ -52,9 +140,22 @@ impl Options {
         self.verbose = other.verbose;
         self.open_window = other.open_window;
         self.image_file = other.image_file.clone();
+        self.debug_pixel = other.debug_pixel;
+        self.render_space = other.render_space;
+        self.contact_sheet = other.contact_sheet.clone();
+        self.texture_budget = other.texture_budget;
+        self.max_anisotropy = other.max_anisotropy;
     }
 }
 
+// The size of the square that each thumbnail on a contact sheet fits in,
+// and how many of them go in each row
+const CONTACT_SHEET_THUMB_SIZE: u32 = 160;
+const CONTACT_SHEET_COLUMNS: u32 = 6;
+
+// Maximum ray depth used by integrators when Options::quick_render is set
+const QUICK_RENDER_MAX_DEPTH: i32 = 2;
+
 const STATE_UNINITIALIZED: usize = 0;
 const STATE_OPTIONS_BLOCK: usize = 1;
 const STATE_WORLD_BLOCK: usize = 2;
@@ -121,6 +222,10 @@ pub struct RenderOptions {
     transform_start_time: f32,
     transform_end_time: f32,
 
+    // The length of one scene unit in meters. The world is rendered in
+    // meters so that scenes authored in different units can be mixed.
+    meters_per_unit: f32,
+
     filter_name: String,
     filter_params: ParamSet,
 
@@ -162,6 +267,8 @@ impl RenderOptions {
             transform_start_time: 0.0,
             transform_end_time: 1.0,
 
+            meters_per_unit: 1.0,
+
             filter_name: String::from("box"),
             filter_params: ParamSet::new(),
 
@@ -174,10 +281,10 @@ impl RenderOptions {
             accelerator_name: String::from("bvh"),
             accelerator_params: ParamSet::new(),
 
-            surf_integrator_name: String::new(),
+            surf_integrator_name: String::from("whitted"),
             surf_integrator_params: ParamSet::new(),
 
-            vol_integrator_name: String::new(),
+            vol_integrator_name: String::from("single"),
             vol_integrator_params: ParamSet::new(),
 
             renderer_name: String::from("sampler"),
@@ -197,8 +304,104 @@ impl RenderOptions {
         }
     }
 
-    fn make_renderer(&self) -> Arc<dyn Renderer> {
-        unimplemented!()
+    // Overrides the parameters given in the scene description so that the
+    // scene renders quickly enough to sanity check it: a quarter of the
+    // resolution, a quarter of the samples per pixel and shallow ray depths.
+    fn apply_quick_render(&mut self) {
+        fn scale_int(params: &mut ParamSet, name: &str, def: i32, f: &dyn Fn(i32) -> i32) {
+            let v = params.find_one_int(name, def);
+            params.erase(name);
+            params.add_int(name, vec![max(1, f(v))]);
+        }
+
+        // Render at a quarter of the resolution
+        scale_int(&mut self.film_params, "xresolution", 640, &|x| x / 4);
+        scale_int(&mut self.film_params, "yresolution", 480, &|y| y / 4);
+
+        // Take a quarter of the samples per pixel. The stratified sampler
+        // takes its sample count per dimension, so halve each of those.
+        match self.sampler_name.as_ref() {
+            "stratified" => {
+                scale_int(&mut self.sampler_params, "xsamples", 2, &|n| n / 2);
+                scale_int(&mut self.sampler_params, "ysamples", 2, &|n| n / 2);
+            },
+            "adaptive" => {
+                scale_int(&mut self.sampler_params, "minsamples", 4, &|n| n / 4);
+                scale_int(&mut self.sampler_params, "maxsamples", 32, &|n| n / 4);
+            },
+            _ => scale_int(&mut self.sampler_params, "pixelsamples", 4, &|n| n / 4)
+        }
+
+        // Clamp the depth of any recursive rays
+        for &(name, def) in [("maxdepth", 5),
+                             ("maxspeculardepth", 5),
+                             ("maxindirectdepth", 3)].iter() {
+            scale_int(&mut self.surf_integrator_params, name, def,
+                      &|d| min(d, QUICK_RENDER_MAX_DEPTH));
+        }
+    }
+
+    // Takes points given in scene units to meters
+    fn units_to_meters(&self) -> Transform {
+        let m = self.meters_per_unit;
+        Transform::scale(m, m, m)
+    }
+
+    // The file that the film writes its image to
+    fn image_filename(&self, opts: &Options) -> String {
+        if opts.image_file.is_empty() {
+            self.film_params.find_one_str("filename", String::from("pbrt.png"))
+        } else {
+            opts.image_file.clone()
+        }
+    }
+
+    // Takes points in world space, in meters, to the space that the scene is
+    // rendered in. The camera is placed where it is when the shutter opens.
+    fn render_from_world(&self, space: RenderSpace) -> Transform {
+        let camera_to_world = &self.camera_to_world[0];
+        match space {
+            RenderSpace::World => Transform::new(),
+            RenderSpace::Camera => camera_to_world.inverse(),
+            RenderSpace::CameraWorld => {
+                let p = camera_to_world.t(&Point::new());
+                Transform::translate(&Vector::new_with(-p.x, -p.y, -p.z))
+            }
+        }
+    }
+
+    // Makes the camera, sampler and integrators that the options describe,
+    // and the renderer that uses them, with one thread per core unless the
+    // command line says otherwise. Returns None if the sampler is unknown.
+    fn make_renderer(&self, opts: &Options) -> Option<Arc<dyn Renderer>> {
+        if self.renderer_name != "sampler" {
+            println!("WARNING: Renderer \"{}\" unknown. Using \"sampler\".",
+                     self.renderer_name);
+        }
+
+        let filter = make_filter(&self.filter_name, &self.filter_params);
+        let film = make_film(&self.film_name, &self.film_params, filter,
+                             self.image_filename(opts), opts.open_window);
+
+        let sopen = self.camera_params.find_one_float("shutteropen", 0.0);
+        let sclose = self.camera_params.find_one_float("shutterclose", 1.0);
+        let cam2world = AnimatedTransform::new(
+            self.camera_to_world[0].clone(), self.transform_start_time,
+            self.camera_to_world[1].clone(), self.transform_end_time);
+        let camera = make_camera(&self.camera_name, &self.camera_params, cam2world,
+                                 sopen, sclose, film);
+
+        let sampler = make_sampler(&self.sampler_name, &self.sampler_params,
+                                   camera.film().get_sample_extent(), sopen, sclose)?;
+        let surf = make_surface_integrator(&self.surf_integrator_name,
+                                           &self.surf_integrator_params);
+        let vol = make_volume_integrator(&self.vol_integrator_name,
+                                         &self.vol_integrator_params);
+
+        let renderer = SamplerRenderer::new(sampler, camera, surf, vol);
+        Some(Arc::new(if opts.num_cores > 0 {
+            renderer.with_num_threads(opts.num_cores)
+        } else { renderer }))
     }
 
     fn make_scene(&mut self) -> Scene {
@@ -220,7 +423,7 @@ impl RenderOptions {
         let scene = Scene::new_with(
             Arc::new(accelerator),
             self.lights.clone(),
-            volume_region);
+            volume_region).with_light_bvh();
 
         // Erase primitives lights and volume regions from render options
         self.primitives.clear();
@@ -239,6 +442,7 @@ struct GraphicsState {
     spectrum_textures: Arc<HashMap<String, Arc<dyn Texture<Spectrum>>>>,
 
     named_materials: HashMap<String, Arc<Material>>,
+    named_material_emission: HashMap<String, Spectrum>,
     current_named_material: Option<String>,
 
     area_light: String,
@@ -255,6 +459,7 @@ impl GraphicsState {
             float_textures: Arc::new(HashMap::new()),
             spectrum_textures: Arc::new(HashMap::new()),
             named_materials: HashMap::new(),
+            named_material_emission: HashMap::new(),
             current_named_material: None,
             area_light: String::new(),
             area_light_params: ParamSet::new(),
@@ -276,6 +481,19 @@ impl GraphicsState {
                     |name| self.named_materials[name].clone())
     }
 
+    // The radiance emitted by the current material, given as "Le" on either
+    // the shape or the material. Shapes with emissive materials become
+    // diffuse area lights without needing an AreaLightSource.
+    fn emission(&self, params: &ParamSet) -> Spectrum {
+        let black = Spectrum::from(0.0);
+        let material_le = match self.current_named_material.as_ref() {
+            Some(name) if self.named_materials.contains_key(name) =>
+                self.named_material_emission.get(name).cloned().unwrap_or(black),
+            _ => self.material_params.find_one_spectrum("Le", black)
+        };
+        params.find_one_spectrum("Le", material_le)
+    }
+
     fn float_textures(&self) -> Arc<HashMap<String, Arc<dyn Texture<f32>>>> {
         self.float_textures.clone()
     }
@@ -286,35 +504,560 @@ impl GraphicsState {
 }
 
 fn make_material(name: &String, _tex_to_world: &Transform, params: TextureParams) -> Material {
-    match name.as_ref() {
+    // Any material can have its shading normals given by a normal map
+    let normal_map = params.get_spectrum_texture_or_null("normalmap");
+    let mtl = match name.as_ref() {
         "matte" => Material::matte(
             params.get_spectrum_texture("Kd", &Spectrum::from(0.5)),
             params.get_float_texture("sigma", 0.0),
             params.get_float_texture_or_null("bumpmap")),
+        "fourier" => {
+            let filename = params.find_str("bsdffile", String::new());
+            let bump_map = params.get_float_texture_or_null("bumpmap");
+            match FourierBSDFTable::read(&filename) {
+                Ok(table) => Material::fourier(Arc::new(table), bump_map),
+                Err(e) => {
+                    println!("WARNING: Unable to read BSDF \"{}\": {}. Using matte.",
+                             filename, e);
+                    Material::matte(params.get_spectrum_texture("Kd", &Spectrum::from(0.5)),
+                                    params.get_float_texture("sigma", 0.0), bump_map)
+                }
+            }
+        },
+        "clearcoat" => {
+            // The base material is made from the same parameters, and gets
+            // any normal map, which the coating then follows
+            let eta = params.get_float_texture("eta", 1.5);
+            let tint = params.get_spectrum_texture("tint", &Spectrum::from(1.0));
+            let thickness = params.get_float_texture("thickness", 1.0);
+            let base = params.find_str("basematerial", String::from("matte"));
+            let base = if base == "clearcoat" {
+                println!("WARNING: Clear coats can't be coated again. Using matte.");
+                String::from("matte")
+            } else { base };
+            let base = make_material(&base, _tex_to_world, params);
+            return Material::clear_coat(Arc::new(base), eta, tint, thickness);
+        },
+        "disney" => Material::disney(
+            DisneyMaterial::new(params.get_spectrum_texture("color", &Spectrum::from(0.5)),
+                                params.get_float_texture("metallic", 0.0),
+                                params.get_float_texture("roughness", 0.5),
+                                params.get_float_texture_or_null("bumpmap"))
+                .with_specular(params.get_float_texture("eta", 1.5),
+                               params.get_float_texture("speculartint", 0.0))
+                .with_anisotropic(params.get_float_texture("anisotropic", 0.0))
+                .with_sheen(params.get_float_texture("sheen", 0.0),
+                            params.get_float_texture("sheentint", 0.5))
+                .with_clearcoat(params.get_float_texture("clearcoat", 0.0),
+                                params.get_float_texture("clearcoatgloss", 1.0))
+                .with_transmission(params.get_float_texture("spectrans", 0.0))),
+        "subsurface" => Material::subsurface(
+            params.find_float("scale", 1.0),
+            params.get_spectrum_texture("Kr", &Spectrum::from(1.0)),
+            params.get_spectrum_texture(
+                "sigma_a", &Spectrum::from_rgb([0.0011, 0.0024, 0.014])),
+            params.get_spectrum_texture(
+                "sigma_prime_s", &Spectrum::from_rgb([2.55, 3.21, 3.77])),
+            params.find_float("eta", 1.33),
+            params.get_float_texture_or_null("bumpmap")),
+        "glass" => Material::glass(
+            params.get_spectrum_texture("Kr", &Spectrum::from(1.0)),
+            params.get_spectrum_texture("Kt", &Spectrum::from(1.0)),
+            make_index_of_refraction(&params),
+            params.get_float_texture_or_null("bumpmap")),
+        "hair" => {
+            // Absorption can be given directly, as a color, or as the
+            // concentration of pigments, which defaults to brown hair
+            let absorption = if let Some(sigma_a) = params.get_spectrum_texture_or_null("sigma_a") {
+                HairAbsorption::SigmaA(sigma_a)
+            } else if let Some(color) = params.get_spectrum_texture_or_null("color") {
+                HairAbsorption::Color(color)
+            } else {
+                HairAbsorption::Melanin(params.get_float_texture("eumelanin", 1.3),
+                                        params.get_float_texture("pheomelanin", 0.0))
+            };
+
+            Material::hair(absorption,
+                           params.get_float_texture("eta", 1.55),
+                           params.get_float_texture("beta_m", 0.3),
+                           params.get_float_texture("beta_n", 0.3),
+                           params.get_float_texture("alpha", 2.0))
+        },
+        "plastic" => {
+            let (u_rough, v_rough) = make_roughness(&params, 0.1);
+            Material::plastic(
+                params.get_spectrum_texture("Kd", &Spectrum::from(0.25)),
+                params.get_spectrum_texture("Ks", &Spectrum::from(0.25)),
+                u_rough, v_rough,
+                params.get_float_texture_or_null("bumpmap"))
+                .with_distribution(make_distribution(&params))
+        },
+        "substrate" => Material::substrate(
+            params.get_spectrum_texture("Kd", &Spectrum::from(0.5)),
+            params.get_spectrum_texture("Ks", &Spectrum::from(0.5)),
+            params.get_float_texture("uroughness", 0.1),
+            params.get_float_texture("vroughness", 0.1),
+            params.get_float_texture_or_null("bumpmap"))
+            .with_distribution(make_distribution(&params)),
+        "metal" => {
+            // Metals default to copper, but may name one of the measured
+            // conductors, whose eta and k can be overridden in turn
+            let preset = params.find_str("preset", String::from("copper"));
+            let metal = Conductor::from_name(&preset).unwrap_or_else(|| {
+                println!("WARNING: Unknown metal \"{}\". Using copper.", preset);
+                Conductor::Copper
+            });
+            let (u_rough, v_rough) = make_roughness(&params, 0.01);
+            Material::metal(
+                params.get_spectrum_texture("eta", &metal.eta()),
+                params.get_spectrum_texture("k", &metal.k()),
+                u_rough, v_rough,
+                params.get_float_texture_or_null("bumpmap"))
+                .with_distribution(make_distribution(&params))
+        },
+        "measured" => Material::measured(
+            params.find_str("filename", String::new()),
+            params.get_float_texture_or_null("bumpmap")),
         _ => panic!("Unknown material type: {}", name),
+    };
+
+    match normal_map {
+        Some(map) => mtl.with_normal_map(map),
+        None => mtl
     }
 }
 
-fn make_light(name: &str, light_to_world: &Transform, params: &ParamSet) -> Arc<dyn Light> {
+// The roughness of glossy materials along u and v, which default to the
+// same "roughness" in both directions
+fn make_roughness(params: &TextureParams, def: f32)
+                  -> (ScalarTextureReference, ScalarTextureReference) {
+    let rough = params.get_float_texture("roughness", def);
+    (params.get_float_texture_or_null("uroughness").unwrap_or_else(|| rough.clone()),
+     params.get_float_texture_or_null("vroughness").unwrap_or(rough))
+}
+
+// The index of refraction of glass, which disperses light if it's given
+// by Sellmeier or Cauchy coefficients, or names a known glass with "ior"
+fn make_index_of_refraction(params: &TextureParams) -> IndexOfRefraction {
+    let index = params.find_float("index", 1.5);
+    match (params.find_floats("sellmeierb"), params.find_floats("sellmeierc")) {
+        (Some(&[b0, b1, b2]), Some(&[c0, c1, c2])) =>
+            return IndexOfRefraction::Sellmeier { b: [b0, b1, b2], c: [c0, c1, c2] },
+        (None, None) => { },
+        _ => println!("WARNING: Sellmeier glass needs three \"sellmeierb\" and three \
+                       \"sellmeierc\" coefficients. Ignoring them.")
+    }
+
+    if let Some(&[b]) = params.find_floats("cauchyb") {
+        return IndexOfRefraction::Cauchy { a: params.find_float("cauchya", index), b };
+    }
+
+    let name = params.find_str("ior", String::new());
+    if name.is_empty() {
+        return IndexOfRefraction::Constant(index);
+    }
+
+    IndexOfRefraction::from_name(&name).unwrap_or_else(|| {
+        println!("WARNING: Unknown glass \"{}\". Using an index of refraction of {}.",
+                 name, index);
+        IndexOfRefraction::Constant(index)
+    })
+}
+
+// The distribution of microfacets for glossy materials, either "blinn" or
+// "ggx"
+fn make_distribution(params: &TextureParams) -> DistributionKind {
+    let name = params.find_str("distribution", String::from("blinn"));
+    DistributionKind::from_name(&name).unwrap_or_else(|| {
+        println!("WARNING: Unknown microfacet distribution \"{}\". Using blinn.", name);
+        DistributionKind::Blinn
+    })
+}
+
+// Paths can be limited in how many times they bounce off of each type of
+// BxDF, and bouncetypes lists the only types of lobes that they may follow.
+fn make_bounce_depths(params: &ParamSet) -> BounceDepths {
+    let depth = |name| match params.find_one_int(name, -1) {
+        d if d >= 0 => d as usize,
+        _ => usize::MAX
+    };
+    let depths = BounceDepths::new(depth("maxdiffusedepth"), depth("maxglossydepth"),
+                                   depth("maxspeculardepth"));
+
+    match params.find_str("bouncetypes") {
+        None => depths,
+        Some(types) => depths.with_mask(types.iter().fold(BxDFType::empty(), |mask, t| {
+            match t.as_ref() {
+                "reflection" => mask | BxDFType::BSDF_REFLECTION,
+                "transmission" => mask | BxDFType::BSDF_TRANSMISSION,
+                "diffuse" => mask | BxDFType::BSDF_DIFFUSE,
+                "glossy" => mask | BxDFType::BSDF_GLOSSY,
+                "specular" => mask | BxDFType::BSDF_SPECULAR,
+                _ => {
+                    println!("WARNING: Unknown bounce type \"{}\"", t);
+                    mask
+                }
+            }
+        }))
+    }
+}
+
+// Every surface integrator takes the bounce depths above, and maxindirect
+// clamps the luminance of each indirect sample to suppress fireflies.
+fn make_surface_integrator(name: &str, params: &ParamSet) -> SurfaceIntegrator {
+    let max_depth = |def| max(0, params.find_one_int("maxdepth", def)) as usize;
+    let surf = match name {
+        "whitted" => SurfaceIntegrator::whitted(max_depth(5)),
+        "irradiancecache" => SurfaceIntegrator::irradiance_cache(
+            params.find_one_float("minweight", 0.5),
+            params.find_one_float("minpixelspacing", 2.5),
+            params.find_one_float("maxpixelspacing", 15.0),
+            params.find_one_float("maxangledifference", 10.0),
+            max(0, params.find_one_int("maxspeculardepth", 5)) as usize,
+            max(0, params.find_one_int("maxindirectdepth", 3)) as usize,
+            max(1, params.find_one_int("nsamples", 4096)) as usize),
+        // Russian roulette starts once paths have bounced rrstartdepth times
+        "path" => SurfaceIntegrator::path(
+            max_depth(5), max(0, params.find_one_int("rrstartdepth", 3)) as usize),
+        _ => {
+            println!("WARNING: Surface integrator \"{}\" unknown. Using \"whitted\".", name);
+            SurfaceIntegrator::whitted(max_depth(5))
+        }
+    };
+
+    let surf = surf.with_bounce_depths(make_bounce_depths(params));
+    let max_indirect = params.find_one_float("maxindirect", 0.0);
+    if max_indirect > 0.0 { surf.with_indirect_clamp(max_indirect) } else { surf }
+}
+
+fn make_volume_integrator(name: &str, params: &ParamSet) -> VolumeIntegrator {
+    if name != "single" {
+        println!("WARNING: Volume integrator \"{}\" unknown. Using \"single\".", name);
+    }
+
+    let step_size = params.find_one_float("stepsize", 1.0);
+    let vol = VolumeIntegrator::single_scattering(
+        step_size, params.find_one_bool("equiangular", false));
+
+    // Steps are adapted to the density so that none of them is optically
+    // thicker than cfl, bounding it over a grid of majorantres^3 cells
+    let vol = match params.find_one_int("majorantres", 0) {
+        res if res > 0 => vol.with_majorant_res(res as usize),
+        _ => vol
+    };
+    let cfl = params.find_one_float("cfl", 0.0);
+    if cfl > 0.0 { vol.with_cfl(cfl) } else { vol }
+}
+
+// Makes the sampler that generates samples over extent, the film's sample
+// extent (x_start, x_end, y_start, y_end), during the camera's shutter
+// interval. Any of them can offset the samples of each pixel with a blue
+// noise mask. Returns None if the sampler type is unknown.
+fn make_sampler(name: &str, params: &ParamSet, extent: (i32, i32, i32, i32),
+                sopen: f32, sclose: f32) -> Option<Sampler> {
+    let (x0, x1, y0, y1) = extent;
+    let pixel_samples = || max(1, params.find_one_int("pixelsamples", 4)) as usize;
+    let sampler = match name {
+        "lowdiscrepancy" =>
+            Some(Sampler::low_discrepancy(x0, x1, y0, y1, pixel_samples(), sopen, sclose)),
+        "halton" => {
+            let scramble = match params.find_one_str("scramble", String::from("owen")).as_ref() {
+                "none" => HaltonScramble::None,
+                "faure" => HaltonScramble::Faure,
+                "owen" => HaltonScramble::Owen,
+                s => {
+                    println!("WARNING: Halton scrambling \"{}\" unknown. Using \"owen\".", s);
+                    HaltonScramble::Owen
+                }
+            };
+            Some(Sampler::halton(x0, x1, y0, y1, pixel_samples(), scramble, sopen, sclose))
+        },
+        "maxmindist" =>
+            Some(Sampler::max_min_dist(x0, x1, y0, y1, pixel_samples(), sopen, sclose)),
+        "stratified" => {
+            let jitter = params.find_one_bool("jitter", true);
+            let xs = max(1, params.find_one_int("xsamples", 2)) as usize;
+            let ys = max(1, params.find_one_int("ysamples", 2)) as usize;
+            Some(Sampler::stratified(x0, x1, y0, y1, xs, ys, jitter, sopen, sclose))
+        },
+        "adaptive" => {
+            let min_samples = max(1, params.find_one_int("minsamples", 4)) as usize;
+            let max_samples = max(1, params.find_one_int("maxsamples", 32)) as usize;
+            let method = match params.find_one_str("method", String::from("contrast")).as_ref() {
+                "contrast" => AdaptiveTest::ContrastThreshold,
+                "shapeid" => AdaptiveTest::CompreShapeID,
+                "variance" => AdaptiveTest::Variance(params.find_one_float("maxerror", 0.05)),
+                m => {
+                    println!("WARNING: Adaptive sampling metric \"{}\" unknown. \
+                              Using \"contrast\".", m);
+                    AdaptiveTest::ContrastThreshold
+                }
+            };
+            Some(Sampler::adaptive(x0, x1, y0, y1, min_samples, max_samples, method,
+                                   false, sopen, sclose))
+        },
+        _ => {
+            println!("WARNING: Sampler \"{}\" unknown.", name);
+            None
+        }
+    };
+    sampler.map(|s| s.with_blue_noise(params.find_one_bool("bluenoise", false)))
+}
+
+// The pixel reconstruction filters, with the default widths of pbrt-v2.
+// "sinc" is the windowed sinc that pbrt calls it.
+fn make_filter(name: &str, params: &ParamSet) -> Filter {
+    let widths = |def| (params.find_one_float("xwidth", def), params.find_one_float("ywidth", def));
+    match name {
+        "box" => {
+            let (xw, yw) = widths(0.5);
+            Filter::mean(xw, yw)
+        },
+        "triangle" => {
+            let (xw, yw) = widths(2.0);
+            Filter::triangle(xw, yw)
+        },
+        "gaussian" => {
+            let (xw, yw) = widths(2.0);
+            Filter::gaussian(xw, yw, params.find_one_float("alpha", 2.0))
+        },
+        "mitchell" => {
+            let (xw, yw) = widths(2.0);
+            Filter::mitchell(xw, yw, params.find_one_float("B", 1.0 / 3.0),
+                             params.find_one_float("C", 1.0 / 3.0))
+        },
+        "sinc" => {
+            let (xw, yw) = widths(4.0);
+            Filter::lanczos(xw, yw, params.find_one_float("tau", 3.0))
+        },
+        _ => {
+            println!("WARNING: Filter \"{}\" unknown. Using \"box\".", name);
+            Filter::mean(0.5, 0.5)
+        }
+    }
+}
+
+// The film that the image is written to. Only part of it is rendered if
+// cropwindow gives the fractions [x0, x1, y0, y1] of the image to keep.
+fn make_film(name: &str, params: &ParamSet, filter: Filter, filename: String,
+             open_window: bool) -> Film {
+    if name != "image" {
+        println!("WARNING: Film \"{}\" unknown. Using \"image\".", name);
+    }
+
+    if open_window || params.find_one_bool("display", false) {
+        println!("WARNING: Displaying the image while it renders is unsupported.");
+    }
+
+    let xres = max(1, params.find_one_int("xresolution", 640)) as usize;
+    let yres = max(1, params.find_one_int("yresolution", 480)) as usize;
+    let crop = match params.find_float("cropwindow") {
+        Some(&[x0, x1, y0, y1]) =>
+            [x0.clamp(0.0, 1.0), x1.clamp(0.0, 1.0), y0.clamp(0.0, 1.0), y1.clamp(0.0, 1.0)],
+        _ => [0.0, 1.0, 0.0, 1.0]
+    };
+    Film::image(xres, yres, filter, crop, filename, false)
+}
+
+// Makes the camera whose film is the given one. The screen window defaults
+// to [-1, 1] along the film's shorter side, and fov is the angle that it
+// spans for perspective cameras.
+fn make_camera(name: &str, params: &ParamSet, cam2world: AnimatedTransform,
+               sopen: f32, sclose: f32, film: Film) -> Camera {
+    let frame = params.find_one_float(
+        "frameaspectratio", film.x_res() as f32 / film.y_res() as f32);
+    let screen = match params.find_float("screenwindow") {
+        Some(&[x0, x1, y0, y1]) => [x0, x1, y0, y1],
+        _ if frame > 1.0 => [-frame, frame, -1.0, 1.0],
+        _ => [-1.0, 1.0, -1.0 / frame, 1.0 / frame]
+    };
+    let lens_radius = params.find_one_float("lensradius", 0.0);
+    let focal_distance = params.find_one_float("focaldistance", 1e30);
+
+    let camera = match name {
+        "orthographic" => Camera::orthographic(cam2world, screen, sopen, sclose,
+                                               lens_radius, focal_distance, film),
+        "environment" => return Camera::environment(cam2world, sopen, sclose, film),
+        _ => {
+            if name != "perspective" {
+                println!("WARNING: Camera \"{}\" unknown. Using \"perspective\".", name);
+            }
+
+            let fov = params.find_one_float("fov", 90.0);
+            let fov = params.find_one_float("halffov", fov / 2.0) * 2.0;
+            Camera::perspective(cam2world, screen, sopen, sclose, lens_radius,
+                                focal_distance, fov, film)
+        }
+    };
+
+    let (tilt_x, tilt_y) = make_focus_tilt(params);
+    camera.with_aperture(make_aperture(params)).with_focus_tilt(tilt_x, tilt_y)
+}
+
+// The shape of the lens aperture of cameras with depth of field.
+fn make_aperture(params: &ParamSet) -> Aperture {
+    let blades = params.find_one_int("apertureblades", 0);
+    let rotation = params.find_one_float("aperturerotation", 0.0);
+    let cat_eye = params.find_one_float("cateye", 0.0);
+    Aperture::new(blades.max(0) as usize, rotation, cat_eye)
+}
+
+// Tilt-shift lenses tilt the plane of focus, in degrees around the camera's
+// x and y axes
+fn make_focus_tilt(params: &ParamSet) -> (f32, f32) {
+    (params.find_one_float("tiltx", 0.0), params.find_one_float("tilty", 0.0))
+}
+
+// Lights can fade or flicker over the shutter interval, going linearly from
+// their scale when the shutter opens to scaleend times that when it closes.
+fn make_animated_scale(params: &ParamSet, start_time: f32, end_time: f32)
+                       -> AnimatedScale {
+    let end = params.find_one_spectrum("scaleend", Spectrum::from(1.0));
+    AnimatedScale::new(Spectrum::from(1.0), start_time, end, end_time)
+}
+
+// Lights are given in scene units and light_to_world takes them to meters.
+// Radiance doesn't change with scale, but the intensity of a point light
+// falls off with the squared distance, so it's scaled by the squared length
+// of a unit to light the scene the same as it was authored.
+fn make_light(name: &str, light_to_world: &Transform, params: &ParamSet,
+              meters_per_unit: f32, anim: &AnimatedScale,
+              spectrum_textures: &HashMap<String, Arc<dyn Texture<Spectrum>>>)
+              -> Arc<dyn Light> {
+    let group = params.find_one_str("group", String::new());
     match name {
         "point" => {
             let i = params.find_one_spectrum("I", Spectrum::from(1.0));
             let sc = params.find_one_spectrum("scale", Spectrum::from(1.0));
             let p = params.find_one_point("from", Point::new_with(0.0, 0.0, 0.0));
-            let l2w = Transform::translate(&Vector::new_with(p.x, p.y, p.z)) * light_to_world;
-            Arc::new(PointLight::new(l2w, i * sc))
+            let l2w = light_to_world.clone() * Transform::translate(&Vector::new_with(p.x, p.y, p.z));
+            let units = meters_per_unit * meters_per_unit;
+            let light = PointLight::new(l2w, i * sc * units);
+            Arc::new(light.with_animated_scale(anim.clone()).with_group(&group))
+        },
+        "distant" => {
+            let l = params.find_one_spectrum("L", Spectrum::from(1.0));
+            let sc = params.find_one_spectrum("scale", Spectrum::from(1.0));
+            let from = params.find_one_point("from", Point::new_with(0.0, 0.0, 0.0));
+            let to = params.find_one_point("to", Point::new_with(0.0, 0.0, 1.0));
+            let light = DistantLight::new(light_to_world.clone(), l * sc, from - to);
+            Arc::new(light.with_animated_scale(anim.clone()).with_group(&group))
+        },
+        "spot" => {
+            let i = params.find_one_spectrum("I", Spectrum::from(1.0));
+            let sc = params.find_one_spectrum("scale", Spectrum::from(1.0));
+            let coneangle = params.find_one_float("coneangle", 30.0);
+            let conedelta = params.find_one_float("conedeltaangle", 5.0);
+
+            // Compute spotlight world to light transformation
+            let from = params.find_one_point("from", Point::new_with(0.0, 0.0, 0.0));
+            let to = params.find_one_point("to", Point::new_with(0.0, 0.0, 1.0));
+            let dir = (&to - &from).normalize();
+            let (du, dv) = coordinate_system(&dir);
+            let dir_to_z = Transform::from([[du.x, du.y, du.z, 0.0],
+                                            [dv.x, dv.y, dv.z, 0.0],
+                                            [dir.x, dir.y, dir.z, 0.0],
+                                            [0.0, 0.0, 0.0, 1.0]]);
+            let l2w = light_to_world.clone() *
+                Transform::translate(&Vector::new_with(from.x, from.y, from.z)) *
+                dir_to_z.inverse();
+
+            let units = meters_per_unit * meters_per_unit;
+            let mut light = SpotLight::new(l2w, i * sc * units, coneangle, coneangle - conedelta);
+            match params.find_one_tex("gobo", String::new()).as_ref() {
+                "" => (),
+                tex => match spectrum_textures.get(tex) {
+                    Some(gobo) => light = light.with_gobo(gobo.clone()),
+                    None => println!("WARNING: Couldn't find spectrum texture named \"{}\" \
+                                      for the spot light gobo", tex)
+                }
+            }
+            Arc::new(light.with_animated_scale(anim.clone()).with_group(&group))
+        },
+        "goniometric" => {
+            let i = params.find_one_spectrum("I", Spectrum::from(1.0));
+            let sc = params.find_one_spectrum("scale", Spectrum::from(1.0));
+            let texname = params.find_one_str("mapname", String::new());
+            let units = meters_per_unit * meters_per_unit;
+            let light = GonioPhotometricLight::new(light_to_world.clone(), i * sc * units,
+                                                   &texname);
+            Arc::new(light.with_animated_scale(anim.clone()).with_group(&group))
+        },
+        "projection" => {
+            let i = params.find_one_spectrum("I", Spectrum::from(1.0));
+            let sc = params.find_one_spectrum("scale", Spectrum::from(1.0));
+            let fov = params.find_one_float("fov", 45.0);
+            let texname = params.find_one_str("mapname", String::new());
+            let units = meters_per_unit * meters_per_unit;
+            let light = ProjectionLight::new(light_to_world.clone(), i * sc * units,
+                                             &texname, fov);
+            Arc::new(light.with_animated_scale(anim.clone()).with_group(&group))
         },
         _ => panic!("Unknown light type: {}", name)
     }
 }
 
+// Makes a procedural sky and the sun that goes with it. sundir points
+// towards the sun in light space, where +z is up.
+fn make_sun_sky(light_to_world: &Transform, params: &ParamSet, anim: &AnimatedScale)
+                -> (Arc<dyn Light>, Arc<dyn Light>) {
+    let sun_dir = params.find_one_vec("sundir", Vector::new_with(0.0, 0.0, 1.0));
+    let turbidity = params.find_one_float("turbidity", 3.0);
+    let albedo = params.find_one_spectrum("albedo", Spectrum::from(0.0));
+    let sc = params.find_one_spectrum("scale", Spectrum::from(1.0));
+    let mut sky = SunSkyLight::new(light_to_world.clone(), sun_dir, turbidity, albedo, sc);
+    let group = params.find_one_str("group", String::new());
+    let sun = sky.sun_light().with_animated_scale(anim.clone()).with_group(&group);
+    sky = sky.with_animated_scale(anim.clone()).with_group(&group);
+
+    // Each group of four points is one rectangular portal
+    if let Some(corners) = params.find_point("portal") {
+        if corners.len() % 4 != 0 {
+            println!("WARNING: Portals need four corners each, ignoring the extra points");
+        }
+        let portals = corners.chunks_exact(4).map(|c| {
+            let world: Vec<Point> = c.iter().map(|p| light_to_world.xf(p.clone())).collect();
+            Portal::from_corners(&world)
+        }).collect();
+        sky = sky.with_portals(portals);
+    }
+
+    (Arc::new(sky), Arc::new(sun))
+}
+
 fn make_area_light(name: &str, light_to_world: &Transform, params: &ParamSet,
-                   shape: Shape) -> AreaLight {
-    unimplemented!()
+                   shape: Shape, anim: &AnimatedScale,
+                   spectrum_textures: &HashMap<String, Arc<dyn Texture<Spectrum>>>)
+                   -> Arc<dyn AreaLight> {
+    let group = params.find_one_str("group", String::new());
+    match name {
+        "diffuse" => {
+            let l = params.find_one_spectrum("L", Spectrum::from(1.0));
+            let sc = params.find_one_spectrum("scale", Spectrum::from(1.0));
+            let ns = params.find_one_int("nsamples", 1);
+            let mut light = DiffuseAreaLight::new(light_to_world.clone(), l * sc,
+                                                  ns.max(1) as usize, shape);
+
+            // L may also name a texture for the emitted radiance
+            match params.find_one_tex("L", String::new()).as_ref() {
+                "" => (),
+                tex => match spectrum_textures.get(tex) {
+                    Some(t) => light = light.with_texture(t.clone()),
+                    None => println!("WARNING: Couldn't find spectrum texture named \"{}\" \
+                                      for the area light", tex)
+                }
+            }
+            Arc::new(light.with_animated_scale(anim.clone()).with_group(&group))
+        },
+        _ => panic!("Unknown area light type: {}", name)
+    }
 }
 
+// Deforming shapes move between the positions they're given at the start
+// and end of the motion times
 fn make_shape(name: &str, obj_to_world: Transform, world_to_obj: Transform,
-              reverse_orientation: bool, params: &ParamSet) -> Shape {
+              reverse_orientation: bool, motion_times: (f32, f32), params: &ParamSet,
+              float_textures: &HashMap<String, Arc<dyn Texture<f32>>>) -> Shape {
     match name {
         "sphere" => {
             let radius = params.find_one_float("radius", 1.0);
@@ -324,12 +1067,417 @@ fn make_shape(name: &str, obj_to_world: Transform, world_to_obj: Transform,
             Shape::sphere(obj_to_world, world_to_obj, reverse_orientation,
                           radius, zmin, zmax, phimax)
         },
+        "cone" => {
+            let radius = params.find_one_float("radius", 1.0);
+            let height = params.find_one_float("height", 1.0);
+            let phimax = params.find_one_float("phimax", 360.0);
+            Shape::cone(obj_to_world, world_to_obj, reverse_orientation,
+                        radius, height, phimax)
+        },
+        "curve" => {
+            let p = params.find_point("P").unwrap_or(&[]);
+            if p.len() < 4 || !(p.len() - 1).is_multiple_of(3) {
+                panic!("Curves need 3n + 1 control points, but {} were given", p.len());
+            }
+
+            let ty = params.find_one_str("type", String::from("flat"));
+            let ty = CurveType::from_name(&ty).unwrap_or_else(|| {
+                println!("WARNING: Unknown curve type \"{}\". Using \"flat\".", ty);
+                CurveType::Flat
+            });
+
+            // Ribbons need a normal at the ends of every segment
+            let num_segments = (p.len() - 1) / 3;
+            let n = params.find_normal("N")
+                .filter(|n| ty == CurveType::Ribbon && n.len() == num_segments + 1);
+            let ty = if ty == CurveType::Ribbon && n.is_none() {
+                println!("WARNING: Ribbon curves need {} normals. Using \"flat\".",
+                         num_segments + 1);
+                CurveType::Flat
+            } else { ty };
+
+            let width = params.find_one_float("width", 1.0);
+            let width0 = params.find_one_float("width0", width);
+            let width1 = params.find_one_float("width1", width);
+            let split_depth = params.find_one_int("splitdepth", 3).max(0) as usize;
+            Shape::curves(obj_to_world, world_to_obj, reverse_orientation, ty, p,
+                          width0, width1, n, split_depth)
+        },
+        "sdf" => {
+            // Signed distances sampled on a grid over the bounds from p0 to
+            // p1, with x changing fastest
+            let res = params.find_int("resolution").unwrap_or(&[]);
+            if res.len() != 3 || res.iter().any(|&n| n < 2) {
+                panic!("Signed distance fields need at least two samples along each axis");
+            }
+
+            let res = [res[0] as usize, res[1] as usize, res[2] as usize];
+            let distances = params.find_float("distances").unwrap_or(&[]);
+            if distances.len() != res.iter().product::<usize>() {
+                panic!("Expected {} \"distances\" for signed distance field, but got {}",
+                       res.iter().product::<usize>(), distances.len());
+            }
+
+            let p0 = params.find_one_point("p0", Point::new_with(-1.0, -1.0, -1.0));
+            let p1 = params.find_one_point("p1", Point::new_with(1.0, 1.0, 1.0));
+            let bounds = BBox::from(p0).unioned_with(p1);
+            Shape::implicit_grid(obj_to_world, world_to_obj, reverse_orientation,
+                                 DistanceGrid::new(res, distances, bounds))
+        },
+        "loopsubdiv" => {
+            let nlevels = params.find_one_int("nlevels", 3).max(0) as usize;
+            let vi: Vec<usize> = params.find_int("indices").unwrap_or(&[])
+                .iter().map(|&i| i as usize).collect();
+            let p = params.find_point("P").unwrap_or(&[]);
+            if vi.is_empty() || p.is_empty() || vi.iter().any(|&i| i >= p.len()) {
+                panic!("Invalid vertices or indices for subdivision surface");
+            }
+
+            // Creases are pairs of vertices, which are infinitely sharp
+            // unless they're given one sharpness or one each
+            let creases: Vec<usize> = params.find_int("creases").unwrap_or(&[])
+                .iter().map(|&i| i as usize).collect();
+            if !creases.len().is_multiple_of(2) {
+                println!("WARNING: Odd number of \"creases\" indices. Ignoring the last one.");
+            }
+            let sharpness = match params.find_float("creasesharpness") {
+                Some(s) if s.len() == 1 || s.len() == creases.len() / 2 => s.to_vec(),
+                Some(_) => {
+                    println!("WARNING: Expected one \"creasesharpness\" or one per crease. \
+                              Making all creases sharp.");
+                    vec![f32::INFINITY]
+                },
+                None => vec![f32::INFINITY]
+            };
+            let corners: Vec<usize> = params.find_int("corners").unwrap_or(&[])
+                .iter().map(|&i| i as usize).collect();
+
+            Shape::loop_subdiv(obj_to_world, world_to_obj, reverse_orientation, &vi, p, nlevels)
+                .with_creases(&creases[..creases.len() / 2 * 2], &sharpness, &corners)
+        },
+        "trianglemesh" => {
+            let vi: Vec<usize> = params.find_int("indices").unwrap_or(&[])
+                .iter().map(|&i| i as usize).collect();
+            let p = params.find_point("P").unwrap_or(&[]);
+            if vi.is_empty() || p.is_empty() || vi.iter().any(|&i| i >= p.len()) {
+                panic!("Invalid vertices or indices for triangle mesh");
+            }
+
+            // Per-vertex attributes are dropped if there aren't as many of
+            // them as there are vertices
+            let uv = params.find_float("uv").or(params.find_float("st"))
+                .filter(|uv| {
+                    let valid = uv.len() == 2 * p.len();
+                    if !valid {
+                        println!("WARNING: Expected {} \"uv\" values for triangle mesh, \
+                                  but got {}. Discarding them.", 2 * p.len(), uv.len());
+                    }
+                    valid
+                });
+            let n = params.find_normal("N").filter(|n| {
+                let valid = n.len() == p.len();
+                if !valid {
+                    println!("WARNING: Number of \"N\"s for triangle mesh must match \"P\"s. \
+                              Discarding them.");
+                }
+                valid
+            });
+            let s = params.find_vec("S").filter(|s| {
+                let valid = s.len() == p.len();
+                if !valid {
+                    println!("WARNING: Number of \"S\"s for triangle mesh must match \"P\"s. \
+                              Discarding them.");
+                }
+                valid
+            });
+
+            // The alpha can be a texture, or a constant zero to make the
+            // mesh invisible
+            let alpha_tex = params.find_one_tex("alpha", String::new());
+            let alpha: Option<ScalarTextureReference> = if !alpha_tex.is_empty() {
+                let tex = float_textures.get(&alpha_tex).cloned();
+                if tex.is_none() {
+                    println!("WARNING: Couldn't find float texture \"{}\" for \"alpha\"",
+                             alpha_tex);
+                }
+                tex
+            } else if params.find_one_float("alpha", 1.0) == 0.0 {
+                Some(Arc::new(ConstantTexture::new(0.0)))
+            } else {
+                None
+            };
+
+            let culling = match params.find_one_str("cull", String::from("none")).as_str() {
+                "none" => BackfaceCulling::Never,
+                "camera" => BackfaceCulling::CameraRays,
+                "shadow" => BackfaceCulling::CameraAndShadowRays,
+                c => {
+                    println!("WARNING: Unknown backface culling mode \"{}\". Using \"none\".", c);
+                    BackfaceCulling::Never
+                }
+            };
+
+            let mesh = Shape::triangle_mesh(obj_to_world, world_to_obj, reverse_orientation,
+                                            &vi, p, n, s, uv, alpha)
+                .with_backface_culling(culling);
+
+            // The vertices of deforming meshes move to "Pend" by the end of
+            // the motion
+            match params.find_point("Pend") {
+                Some(p_end) if p_end.len() == p.len() =>
+                    mesh.with_vertex_motion(p_end, motion_times.0, motion_times.1),
+                Some(_) => {
+                    println!("WARNING: Number of \"Pend\"s for triangle mesh must match \
+                              \"P\"s. Discarding them.");
+                    mesh
+                },
+                None => mesh
+            }
+        },
         _ => panic!("Unknown shape type: {}", name)
     }
 }
 
-fn make_accelerator(name: &str, prims: &Vec<Primitive>, params: &ParamSet) -> Primitive {
-    unimplemented!()
+// Parses the "expression" parameter of an expression texture, which can
+// refer to any texture defined so far
+fn make_expr_texture(params: &ParamSet,
+                     float_textures: &HashMap<String, Arc<dyn Texture<f32>>>,
+                     spectrum_textures: &HashMap<String, Arc<dyn Texture<Spectrum>>>)
+                     -> Option<ExprTexture> {
+    let src = params.find_one_str("expression", String::new());
+    match ExprTexture::parse(&src, float_textures, spectrum_textures) {
+        Ok(tex) => Some(tex),
+        Err(e) => {
+            println!("WARNING: Invalid texture expression \"{}\": {}", src, e);
+            None
+        }
+    }
+}
+
+// The mapping of a 2D texture, chosen by the "mapping" parameter. The
+// (u, v) mapping is scaled by "uscale" and "vscale" and offset by "udelta"
+// and "vdelta", the spherical and cylindrical mappings are around the
+// origin of the space the texture was declared in, and the planar mapping
+// projects onto "v1" and "v2" before it's offset.
+fn make_texture_mapping(tex_to_world: &Transform, params: &TextureParams)
+                        -> Box<dyn TextureMapping2D> {
+    let uv = || -> Box<dyn TextureMapping2D> {
+        Box::new(UVMapping2D::new_with(params.find_float("uscale", 1.0),
+                                       params.find_float("vscale", 1.0),
+                                       params.find_float("udelta", 0.0),
+                                       params.find_float("vdelta", 0.0)))
+    };
+    match params.find_str("mapping", String::from("uv")).as_str() {
+        "uv" => uv(),
+        "spherical" => Box::new(SphericalMapping2D::new_with(tex_to_world.inverse())),
+        "cylindrical" => Box::new(CylindricalMapping2D::new_with(tex_to_world.inverse())),
+        "planar" => Box::new(PlanarMapping2D::new_with(
+            params.find_vec("v1", Vector::new_with(1.0, 0.0, 0.0)),
+            params.find_vec("v2", Vector::new_with(0.0, 1.0, 0.0)),
+            params.find_float("udelta", 0.0),
+            params.find_float("vdelta", 0.0))),
+        m => {
+            println!("WARNING: 2D texture mapping \"{}\" unknown. Using \"uv\".", m);
+            uv()
+        }
+    }
+}
+
+fn make_texture_mapping_3d(tex_to_world: &Transform) -> Box<dyn TextureMapping3D> {
+    Box::new(IdentityMapping3D::from_texture_space(tex_to_world))
+}
+
+// The noise that the octaves of noise textures are made of, given by
+// "basis" as "perlin" or "simplex"
+fn make_noise_basis(params: &TextureParams) -> NoiseBasis {
+    match params.find_str("basis", String::from("perlin")).as_str() {
+        "perlin" => NoiseBasis::Perlin,
+        "simplex" => NoiseBasis::Simplex,
+        b => {
+            println!("WARNING: Unknown noise basis \"{}\". Using \"perlin\".", b);
+            NoiseBasis::Perlin
+        }
+    }
+}
+
+// An fBm texture, which sums "octaves" octaves of noise whose amplitudes
+// fall off by "roughness" each octave
+fn make_fbm_texture(tex_to_world: &Transform, params: &TextureParams) -> FBmTexture {
+    FBmTexture::new(params.find_int("octaves", 8),
+                    params.find_float("roughness", 0.5),
+                    make_texture_mapping_3d(tex_to_world))
+        .with_basis(make_noise_basis(params))
+}
+
+// A wrinkled texture, which sums "octaves" octaves of turbulence whose
+// amplitudes fall off by "roughness" each octave
+fn make_wrinkled_texture(tex_to_world: &Transform, params: &TextureParams) -> WrinkledTexture {
+    WrinkledTexture::new(params.find_int("octaves", 8),
+                         params.find_float("roughness", 0.5),
+                         make_texture_mapping_3d(tex_to_world))
+        .with_basis(make_noise_basis(params))
+}
+
+fn make_windy_texture(tex_to_world: &Transform, params: &TextureParams) -> WindyTexture {
+    WindyTexture::new(make_texture_mapping_3d(tex_to_world))
+        .with_basis(make_noise_basis(params))
+}
+
+// A checkerboard of two textures, which is 2D when "dimension" is 2 and
+// solid when it's 3. 2D checkerboards are filtered in closed form unless
+// "aamode" is "none".
+fn make_checkerboard_texture<T>(tex_to_world: &Transform, params: &TextureParams,
+                                tex1: TextureReference<T>, tex2: TextureReference<T>)
+                                -> Option<Arc<dyn Texture<T>>>
+    where T: Lerp<f32> + Debug + 'static {
+    match params.find_int("dimension", 2) {
+        2 => {
+            let mapping = make_texture_mapping(tex_to_world, params);
+            match params.find_str("aamode", String::from("closedform")).as_str() {
+                "none" => Some(Arc::new(CheckerboardTexture::new(mapping, tex1, tex2))),
+                "closedform" => Some(Arc::new(
+                    CheckerboardTexture::new_antialiased(mapping, tex1, tex2))),
+                aa => {
+                    println!("WARNING: Antialiasing mode \"{}\" not understood by \
+                              checkerboard texture. Using \"closedform\".", aa);
+                    Some(Arc::new(CheckerboardTexture::new_antialiased(mapping, tex1, tex2)))
+                }
+            }
+        },
+        3 => Some(Arc::new(Checkerboard3DTexture::new(make_texture_mapping_3d(tex_to_world),
+                                                      tex1, tex2))),
+        dim => {
+            println!("WARNING: {} dimensional checkerboard texture not supported", dim);
+            None
+        }
+    }
+}
+
+// A marble texture, whose veins are perturbed along y by "octaves" octaves
+// of noise scaled by "variation"
+fn make_marble_texture(tex_to_world: &Transform, params: &TextureParams) -> MarbleTexture {
+    MarbleTexture::new(params.find_int("octaves", 8),
+                       params.find_float("roughness", 0.5),
+                       params.find_float("scale", 1.0),
+                       params.find_float("variation", 0.2),
+                       make_texture_mapping_3d(tex_to_world))
+        .with_basis(make_noise_basis(params))
+}
+
+// The parameters of an image texture, other than its mapping: the file to
+// read, how its texels are filtered and wrapped, how they're scaled and
+// decoded, and the bias of the level of detail they're filtered at. The
+// "encoding" is "linear", "sRGB" or "gamma" followed by the exponent, and
+// older scenes give just the exponent as "gamma". The anisotropy of every
+// texture can be limited by max_anisotropy.
+fn image_texture_params(params: &TextureParams, max_anisotropy: Option<f32>)
+                        -> (String, bool, f32, ImageWrap, f32, ImageEncoding, f32) {
+    let wrap = match params.find_str("wrap", String::from("repeat")).as_str() {
+        "repeat" => ImageWrap::Repeat,
+        "black" => ImageWrap::Black,
+        "clamp" => ImageWrap::Clamp,
+        w => {
+            println!("WARNING: Unknown wrap mode \"{}\" for image texture. Using \"repeat\".",
+                     w);
+            ImageWrap::Repeat
+        }
+    };
+
+    let gamma = params.find_floats("gamma").map_or(ImageEncoding::Linear,
+                                                   |g| ImageEncoding::Gamma(g[0]));
+    let encoding = match params.find_str("encoding", String::new()).as_str() {
+        "" => gamma,
+        "linear" => ImageEncoding::Linear,
+        "sRGB" => ImageEncoding::SRGB,
+        e => match e.strip_prefix("gamma ").map(|g| g.trim().parse::<f32>()) {
+            Some(Ok(g)) => ImageEncoding::Gamma(g),
+            _ => {
+                println!("WARNING: Unknown encoding \"{}\" for image texture. \
+                          Using \"linear\".", e);
+                ImageEncoding::Linear
+            }
+        }
+    };
+
+    let max_aniso = params.find_float("maxanisotropy", 8.0);
+    (params.find_str("filename", String::new()),
+     params.find_bool("trilinear", false),
+     max_anisotropy.map_or(max_aniso, |m| max_aniso.min(m)),
+     wrap,
+     params.find_float("scale", 1.0),
+     encoding,
+     params.find_float("lodbias", 0.0))
+}
+
+// Maps the materials of newer versions of pbrt onto the closest ones here
+fn translate_material(name: &str, params: &ParamSet) -> (String, ParamSet) {
+    let mut params = params.clone();
+    let name = match name {
+        "diffuse" => {
+            params.rename("reflectance", "Kd");
+            "matte"
+        },
+        "conductor" => {
+            if params.erase("reflectance") {
+                println!("WARNING: Conductors with a reflectance are unsupported. Using eta and k.");
+            }
+            "metal"
+        },
+        "dielectric" => {
+            if params.find_float("eta").is_some() {
+                params.rename("eta", "index");
+            } else if params.erase("eta") {
+                println!("WARNING: Dielectrics with a spectral eta are unsupported. \
+                          Use \"ior\" or Sellmeier coefficients.");
+            }
+            "glass"
+        },
+        "coateddiffuse" => {
+            params.rename("reflectance", "Kd");
+            if let Some(rough) = params.find_float("roughness").map(|r| r.to_vec()) {
+                params.erase("roughness");
+                params.add_float("uroughness", rough.clone());
+                params.add_float("vroughness", rough);
+            }
+            "substrate"
+        },
+        _ => name
+    };
+
+    // Newer versions only have GGX microfacets
+    if matches!(name, "metal" | "substrate") && params.find_str("distribution").is_none() {
+        params.add_str("distribution", vec![String::from("ggx")]);
+    }
+
+    (String::from(name), params)
+}
+
+// Builds the aggregate named name over prims. Unknown aggregates fall back
+// to a BVH with the default parameters.
+fn make_accelerator(name: &str, prims: &[Primitive], params: &ParamSet) -> Primitive {
+    let prims = prims.to_vec();
+    match name {
+        "grid" => Primitive::grid(prims, params.find_one_bool("refineimmediately", false)),
+        "bvh" => {
+            let split_method = params.find_one_str("splitmethod", String::from("sah"));
+            let max_prims = min(max(1, params.find_one_int("maxnodeprims", 4)), 255);
+            Primitive::bvh(prims, max_prims as usize, &split_method)
+        },
+        "kdtree" => {
+            let icost = params.find_one_int("intersectcost", 80);
+            let tcost = params.find_one_int("traversalcost", 1);
+            let ebonus = params.find_one_float("emptybonus", 0.5);
+            let max_prims = max(1, params.find_one_int("maxprims", 1));
+
+            // The depth is picked from the number of primitives if it's zero
+            let max_depth = max(0, params.find_one_int("maxdepth", -1));
+            Primitive::kdtree(prims, icost, tcost, ebonus, max_prims as usize, max_depth as usize)
+        },
+        _ => {
+            println!("WARNING: Accelerator \"{}\" unknown. Using \"bvh\".", name);
+            Primitive::bvh(prims, 4, "sah")
+        }
+    }
 }
 
 struct Pbrt {
@@ -342,7 +1490,15 @@ struct Pbrt {
   graphics_state: GraphicsState,
   pushed_graphics_states: Vec<GraphicsState>,
   pushed_transforms: Vec<TransformSet>,
-  pushed_active_transform_bits: Vec<usize>
+  pushed_active_transform_bits: Vec<usize>,
+  // The images written so far and how long each took to render
+  rendered_images: Vec<(String, Duration)>,
+  // The images loaded by image textures, which are shared by all of the
+  // textures that read the same file in the same way
+  float_texture_cache: TextureCache<f32>,
+  spectrum_texture_cache: TextureCache<Spectrum>,
+  // The caches used instead when image textures have a memory budget
+  lazy_texture_caches: Option<(Arc<LazyTextureCache<f32>>, Arc<LazyTextureCache<Spectrum>>)>
 }
 
 macro_rules! verify_initialized {
@@ -554,6 +1710,16 @@ impl Pbrt {
         self.render_options.renderer_params = params.clone();
     }
 
+    fn scene_units(&mut self, params: &ParamSet) {
+        verify_options!(self, "SceneUnits");
+        let m = params.find_one_float("metersperunit", 1.0);
+        if m > 0.0 {
+            self.render_options.meters_per_unit = m;
+        } else {
+            println!("WARNING: metersperunit must be positive, not {}. Ignoring.", m);
+        }
+    }
+
     fn camera(&mut self, name: &String, params: &ParamSet) {
         verify_options!(self, "Camera");
         self.render_options.camera_name = name.clone();
@@ -577,6 +1743,8 @@ impl Pbrt {
         } else {
             let mtl = make_material(&mat_name, &self.current_transforms[0], mp);
             self.graphics_state.named_materials.insert(name.clone(), Arc::new(mtl));
+            let le = params.find_one_spectrum("Le", Spectrum::from(0.0));
+            self.graphics_state.named_material_emission.insert(name.clone(), le);
         }
     }
 
@@ -587,33 +1755,141 @@ impl Pbrt {
         self.graphics_state.current_named_material = None;
     }
 
+    // Creates a float texture of the given type, or warns and returns None
+    // if there's no such type. Textures are mapped from the space that's
+    // current when they're declared.
+    fn make_float_texture(&self, texname: &str, params: &ParamSet)
+                          -> Option<Arc<dyn Texture<f32>>> {
+        let fts = self.graphics_state.float_textures();
+        let sts = self.graphics_state.spectrum_textures();
+        let tp = TextureParams::new(params, params, fts.clone(), sts.clone());
+        let tex_to_world = &self.current_transforms[0];
+        let tex: Arc<dyn Texture<f32>> = match texname {
+            "constant" => Arc::new(ConstantTexture::new(tp.find_float("value", 1.0))),
+            "scale" => Arc::new(ScaleTexture::new(tp.get_float_texture("tex1", 1.0),
+                                                  tp.get_float_texture("tex2", 1.0))),
+            "mix" => Arc::new(MixTexture::new(tp.get_float_texture("tex1", 0.0),
+                                              tp.get_float_texture("tex2", 1.0),
+                                              tp.get_float_texture("amount", 0.5))),
+            "bilerp" => Arc::new(BilerpTexture::new(make_texture_mapping(tex_to_world, &tp),
+                                                    tp.find_float("v00", 0.0),
+                                                    tp.find_float("v01", 1.0),
+                                                    tp.find_float("v10", 0.0),
+                                                    tp.find_float("v11", 1.0))),
+            "imagemap" => {
+                let (filename, trilinear, max_aniso, wrap, scale, encoding, lod_bias) =
+                    image_texture_params(&tp, self.options.max_anisotropy);
+                let mapping = make_texture_mapping(tex_to_world, &tp);
+                match self.lazy_texture_caches.as_ref() {
+                    Some((cache, _)) => Arc::new(cache.new_texture(
+                        mapping, &filename, trilinear, max_aniso, wrap, scale, encoding)
+                        .with_lod_bias(lod_bias)),
+                    None => Arc::new(self.float_texture_cache.new_texture(
+                        mapping, &filename, trilinear, max_aniso, wrap, scale, encoding)
+                        .with_lod_bias(lod_bias))
+                }
+            },
+            "checkerboard" => make_checkerboard_texture(tex_to_world, &tp,
+                                                        tp.get_float_texture("tex1", 1.0),
+                                                        tp.get_float_texture("tex2", 0.0))?,
+            "dots" => Arc::new(DotsTexture::new(make_texture_mapping(tex_to_world, &tp),
+                                                tp.get_float_texture("inside", 1.0),
+                                                tp.get_float_texture("outside", 0.0))),
+            "fbm" => Arc::new(make_fbm_texture(tex_to_world, &tp)),
+            "wrinkled" => Arc::new(make_wrinkled_texture(tex_to_world, &tp)),
+            "windy" => Arc::new(make_windy_texture(tex_to_world, &tp)),
+            "expr" => match make_expr_texture(params, &fts, &sts) {
+                Some(tex) => Arc::new(tex),
+                None => Arc::new(ConstantTexture::new(0.0))
+            },
+            _ => {
+                println!("WARNING: Float texture \"{}\" unknown.", texname);
+                return None;
+            }
+        };
+        Some(tex)
+    }
+
+    // Creates a spectrum texture of the given type, or warns and returns
+    // None if there's no such type
+    fn make_spectrum_texture(&self, texname: &str, params: &ParamSet)
+                             -> Option<Arc<dyn Texture<Spectrum>>> {
+        let fts = self.graphics_state.float_textures();
+        let sts = self.graphics_state.spectrum_textures();
+        let tp = TextureParams::new(params, params, fts.clone(), sts.clone());
+        let tex_to_world = &self.current_transforms[0];
+        let one = Spectrum::from(1.0);
+        let zero = Spectrum::from(0.0);
+        let tex: Arc<dyn Texture<Spectrum>> = match texname {
+            "constant" => Arc::new(ConstantTexture::new(tp.find_spectrum("value", one))),
+            "scale" => Arc::new(ScaleTexture::new(tp.get_spectrum_texture("tex1", &one),
+                                                  tp.get_spectrum_texture("tex2", &one))),
+            "mix" => Arc::new(MixTexture::new(tp.get_spectrum_texture("tex1", &zero),
+                                              tp.get_spectrum_texture("tex2", &one),
+                                              tp.get_float_texture("amount", 0.5))),
+            "bilerp" => Arc::new(BilerpTexture::new(make_texture_mapping(tex_to_world, &tp),
+                                                    tp.find_spectrum("v00", zero),
+                                                    tp.find_spectrum("v01", one),
+                                                    tp.find_spectrum("v10", zero),
+                                                    tp.find_spectrum("v11", one))),
+            "imagemap" => {
+                let (filename, trilinear, max_aniso, wrap, scale, encoding, lod_bias) =
+                    image_texture_params(&tp, self.options.max_anisotropy);
+                let mapping = make_texture_mapping(tex_to_world, &tp);
+                match self.lazy_texture_caches.as_ref() {
+                    Some((_, cache)) => Arc::new(cache.new_texture(
+                        mapping, &filename, trilinear, max_aniso, wrap, scale, encoding)
+                        .with_lod_bias(lod_bias)),
+                    None => Arc::new(self.spectrum_texture_cache.new_texture(
+                        mapping, &filename, trilinear, max_aniso, wrap, scale, encoding)
+                        .with_lod_bias(lod_bias))
+                }
+            },
+            "uv" => Arc::new(UVTexture::new(make_texture_mapping(tex_to_world, &tp))),
+            "checkerboard" => make_checkerboard_texture(tex_to_world, &tp,
+                                                        tp.get_spectrum_texture("tex1", &one),
+                                                        tp.get_spectrum_texture("tex2", &zero))?,
+            "dots" => Arc::new(DotsTexture::new(make_texture_mapping(tex_to_world, &tp),
+                                                tp.get_spectrum_texture("inside", &one),
+                                                tp.get_spectrum_texture("outside", &zero))),
+            "fbm" => Arc::new(make_fbm_texture(tex_to_world, &tp)),
+            "wrinkled" => Arc::new(make_wrinkled_texture(tex_to_world, &tp)),
+            "marble" => Arc::new(make_marble_texture(tex_to_world, &tp)),
+            "windy" => Arc::new(make_windy_texture(tex_to_world, &tp)),
+            "expr" => match make_expr_texture(params, &fts, &sts) {
+                Some(tex) => Arc::new(tex),
+                None => Arc::new(ConstantTexture::new(zero))
+            },
+            _ => {
+                println!("WARNING: Spectrum texture \"{}\" unknown.", texname);
+                return None;
+            }
+        };
+        Some(tex)
+    }
+
     fn texture(&mut self, name: &String, ty: &String, texname: &String, params: &ParamSet) {
         verify_world!(self, "Texture");
-        let mut fts = self.graphics_state.float_textures();
-        let mut sts = self.graphics_state.spectrum_textures();
-        let tp = TextureParams::new(params, params, fts.clone(), sts.clone());
+        warn_if_animated_xform!(self, "Texture");
         match ty.as_ref() {
             "float" => {
-                if fts.contains_key("float") {
-                    println!("Texture {} being redefined", texname);
+                if self.graphics_state.float_textures.contains_key(name) {
+                    println!("WARNING: Texture \"{}\" being redefined", name);
+                }
+                if let Some(ft) = self.make_float_texture(texname, params) {
+                    Arc::make_mut(&mut self.graphics_state.float_textures)
+                        .insert(name.clone(), ft);
                 }
-                warn_if_animated_xform!(self, "Texture");
-                let ft = match texname.as_str() {
-                    "constant" => ConstantTexture::new(params.find_one_float(&("value".to_string()), 0.0)),
-                    _ => panic!("Unknown float texture type: {}", texname),
-                };
-                (*Arc::get_mut(&mut fts).unwrap()).insert(texname.clone(), Arc::new(ft));
             },
-            "color" => {
-                if fts.contains_key("color") {
-                    println!("Texture {} being redefined", texname);
+            // Newer versions of pbrt call color textures spectrum textures
+            "color" | "spectrum" => {
+                if self.graphics_state.spectrum_textures.contains_key(name) {
+                    println!("WARNING: Texture \"{}\" being redefined", name);
+                }
+                if let Some(st) = self.make_spectrum_texture(texname, params) {
+                    Arc::make_mut(&mut self.graphics_state.spectrum_textures)
+                        .insert(name.clone(), st);
                 }
-                warn_if_animated_xform!(self, "Texture");
-                let st = match texname.as_ref() {
-                    "definitely isn't this" => panic!("Oops, it is."),
-                    _ => panic!("Unknown color texture type: {}", texname),
-                };
-                (*Arc::get_mut(&mut sts).unwrap()).insert(name.clone(), st);
             },
             _ => panic!("Texture type {} unknown!", ty),
         }
@@ -622,7 +1898,20 @@ impl Pbrt {
     fn light_source(&mut self, name: &String, params: &ParamSet) {
         verify_world!(self, "LightSource");
         warn_if_animated_xform!(self, "LightSource");
-        let lt = make_light(name, &self.current_transforms[0], params);
+        let anim = make_animated_scale(params, self.render_options.transform_start_time,
+                                       self.render_options.transform_end_time);
+
+        // The sun and sky come as a pair of lights
+        if name == "sunsky" {
+            let (sky, sun) = make_sun_sky(&self.current_transforms[0], params, &anim);
+            self.render_options.lights.push(sky);
+            self.render_options.lights.push(sun);
+            return;
+        }
+
+        let lt = make_light(name, &self.current_transforms[0], params,
+                            self.render_options.meters_per_unit, &anim,
+                            &self.graphics_state.spectrum_textures());
         self.render_options.lights.push(lt);
     }
 
@@ -635,16 +1924,20 @@ impl Pbrt {
     fn shape(&mut self, name: &String, params: &ParamSet) {
         verify_world!(self, "Shape");
         let ro = self.graphics_state.reverse_orientation;
+        let motion_times = (self.render_options.transform_start_time,
+                            self.render_options.transform_end_time);
         let prim =
             // Create primitive for animated shape
             if self.current_transforms.is_animated() {
                 // Create initial shape for animated shape
-                if !self.graphics_state.area_light.is_empty() {
+                if !self.graphics_state.area_light.is_empty() ||
+                    !self.graphics_state.emission(params).is_black() {
                     println!("Warning: ignoring currently set area light when creating animated shape");
                 }
     
                 let id = Transform::new();
-                let shape = make_shape(name, id.clone(), id.clone(), ro, params);
+                let shape = make_shape(name, id.clone(), id.clone(), ro, motion_times, params,
+                                       &self.graphics_state.float_textures);
                 let mtl = self.graphics_state.create_material(&self.current_transforms[0], params);
     
                 // Get animated world_to_object transform for shape
@@ -682,18 +1975,34 @@ impl Pbrt {
                     (t, t_inv)
                 };
                 let shape =
-                    make_shape(name, obj_to_world.clone(), world_to_obj, ro, params);
+                    make_shape(name, obj_to_world.clone(), world_to_obj, ro, motion_times,
+                               params, &self.graphics_state.float_textures);
                 let mtl = self.graphics_state.create_material(&self.current_transforms[0], params);
     
                 // Possibly create area light for shape
-                if !self.graphics_state.area_light.is_empty() {
+                let le = self.graphics_state.emission(params);
+                let emissive = !self.graphics_state.area_light.is_empty() || !le.is_black();
+                if emissive && !shape.can_sample() {
+                    println!("WARNING: \"{}\" shapes can't be area lights. Creating it \
+                              without emission.", name);
+                    Primitive::geometric(shape, mtl)
+                } else if !self.graphics_state.area_light.is_empty() {
+                    let anim = make_animated_scale(
+                        &self.graphics_state.area_light_params,
+                        self.render_options.transform_start_time,
+                        self.render_options.transform_end_time);
                     let area_light =
                         make_area_light(
                             &self.graphics_state.area_light,
                             &obj_to_world,
                             &self.graphics_state.area_light_params,
-                            shape.clone());
+                            shape.clone(), &anim,
+                            &self.graphics_state.spectrum_textures);
     
+                    Primitive::geometric_area_light(shape, mtl, area_light)
+                } else if !le.is_black() {
+                    let area_light =
+                        DiffuseAreaLight::new(obj_to_world, le, 1, shape.clone());
                     Primitive::geometric_area_light(shape, mtl, Arc::new(area_light))
                 } else {
                     Primitive::geometric(shape, mtl)
@@ -724,7 +2033,7 @@ impl Pbrt {
 
     fn object_end(&mut self) {
         verify_world!(self, "ObjectEnd");
-        if self.render_options.current_instance.is_some() {
+        if self.render_options.current_instance.is_none() {
             panic!("ObjectEnd called outside of instance definition!");
         }
     
@@ -732,10 +2041,13 @@ impl Pbrt {
         self.attribute_end();
     }
 
-    fn object_instance(&mut self, name: &String) {
+    // Instances may be shaded with one of the named materials in place of
+    // their own, given by the "material" parameter
+    fn object_instance(&mut self, name: &String, params: &ParamSet) {
         verify_world!(self, "ObjectInstance");
         if !self.render_options.instances.contains_key(name) {
             println!("Can't find object named {}", name);
+            return;
         }
     
         if self.render_options.instances.get(name).unwrap().is_empty() {
@@ -758,15 +2070,54 @@ impl Pbrt {
         let xf_end = self.render_options.transform_end_time;
         let animated_world_to_instance =
             AnimatedTransform::new(w2i0, xf_start, w2i1, xf_end);
-        let prim = self.render_options.instances.get(name).unwrap()[0].clone();
-        self.render_options.primitives.push(prim);    
+        let prim = Arc::new(self.render_options.instances.get(name).unwrap()[0].clone());
+
+        let mtl_name = params.find_one_str("material", String::new());
+        let mtl = if mtl_name.is_empty() { None } else {
+            let mtl = self.graphics_state.named_materials.get(&mtl_name).cloned();
+            if mtl.is_none() {
+                println!("WARNING: Named material \"{}\" not defined for instance \"{}\"",
+                         mtl_name, name);
+            }
+            mtl
+        };
+
+        let instance = match mtl {
+            Some(mtl) =>
+                Primitive::transformed_with_material(prim, animated_world_to_instance, mtl),
+            None => Primitive::transformed(prim, animated_world_to_instance)
+        };
+        self.render_options.primitives.push(instance);
     }
 
     fn world_begin(&mut self) {
         verify_options!(self, "WorldBegin");
         self.set_current_api_state(STATE_WORLD_BLOCK);
         self.active_transform_all();
-        self.for_active_transforms(|t| { *t = Transform::new(); });
+
+        // Everything in the world block is given in scene units, including
+        // the camera that was placed before it
+        let to_meters = self.render_options.units_to_meters();
+        for i in 0..MAX_TRANSFORMS {
+            let c2w = &mut self.render_options.camera_to_world[i];
+            *c2w = to_meters.clone() * c2w.clone();
+        }
+
+        // Everything, including the camera, is then moved to the space
+        // that the scene is rendered in. The world transforms that shapes
+        // and lights are built with include the move, so that their
+        // coordinates are small near the camera rather than far from the
+        // world's origin.
+        let render_from_world =
+            self.render_options.render_from_world(self.options.render_space);
+        let render_from_units = render_from_world.clone() * to_meters;
+        self.for_active_transforms(|t| { *t = render_from_units.clone(); });
+        for i in 0..MAX_TRANSFORMS {
+            let c2w = &mut self.render_options.camera_to_world[i];
+            *c2w = render_from_world.clone() * c2w.clone();
+        }
+        self.named_coordinate_systems.insert(
+            String::from("camera"), self.render_options.camera_to_world.clone());
         self.named_coordinate_systems.insert(
             String::from("world"), self.current_transforms.clone());
     }
@@ -786,9 +2137,29 @@ impl Pbrt {
         }
     
         // Create scene and render
-        let mut renderer = self.render_options.make_renderer();
+        if self.options.quick_render {
+            self.render_options.apply_quick_render();
+        }
+
+        let start = Instant::now();
+        let renderer = self.render_options.make_renderer(&self.options);
         let scene = self.render_options.make_scene();
-        Arc::get_mut(&mut renderer).unwrap().render(&scene);
+        match renderer {
+            Some(mut renderer) => {
+                let renderer = Arc::get_mut(&mut renderer).unwrap();
+                if let Some((x, y)) = self.options.debug_pixel {
+                    match renderer.debug_pixel(&scene, x, y) {
+                        Some(log) => println!("{}", log),
+                        None => println!("WARNING: Unable to debug pixel ({}, {})", x, y)
+                    }
+                } else {
+                    renderer.render(&scene);
+                    let filename = self.render_options.image_filename(&self.options);
+                    self.rendered_images.push((filename, start.elapsed()));
+                }
+            },
+            None => println!("WARNING: Unable to create the renderer. Skipping the render.")
+        }
     
         // Clean up after rendering
         self.set_current_api_state(STATE_OPTIONS_BLOCK);
@@ -797,11 +2168,97 @@ impl Pbrt {
         }
         self.active_transform_all();
         self.named_coordinate_systems.clear();
+        self.float_texture_cache.clear();
+        self.spectrum_texture_cache.clear();
+        if let Some((floats, spectra)) = self.lazy_texture_caches.as_ref() {
+            floats.clear();
+            spectra.clear();
+        }
     }
 
+    // pbrt-v3 replaced SurfaceIntegrator and VolumeIntegrator with a
+    // single Integrator statement, and renamed some of the integrators
+    fn integrator(&mut self, name: &String, params: &ParamSet) {
+        let surf = match name.as_str() {
+            "whitted" | "path" => name.as_str(),
+            "simplepath" | "randomwalk" => "path",
+            "directlighting" => {
+                println!("WARNING: Integrator \"directlighting\" is unsupported. Using \
+                          \"whitted\", which also only follows specular bounces.");
+                "whitted"
+            },
+            "ambientocclusion" => {
+                println!("WARNING: Integrator \"ambientocclusion\" is unsupported. \
+                          Using \"path\".");
+                "path"
+            },
+            "volpath" | "simplevolpath" => {
+                // The volume integrator picks out the parameters it knows,
+                // such as stepsize, and ignores the rest
+                self.vol_integrator(&String::from("single"), params);
+                "path"
+            },
+            _ => {
+                println!("WARNING: Integrator \"{}\" is unsupported. Using \"path\".", name);
+                "path"
+            }
+        };
+
+        self.surf_integrator(&String::from(surf), params);
+    }
+
+    // Calls the API for a statement that takes a name and a parameter list,
+    // accepting the statements of newer versions of pbrt too. Statements
+    // for features that aren't supported are ignored with a warning.
+    // Returns false if the statement isn't known at all.
+    fn named_statement(&mut self, statement: &str, name: &String, params: &ParamSet) -> bool {
+        match statement {
+            "PixelFilter" => self.pixel_filter(name, params),
+            "Sampler" => self.sampler(name, params),
+            "Accelerator" => self.accelerator(name, params),
+            "SurfaceIntegrator" => self.surf_integrator(name, params),
+            "VolumeIntegrator" => self.vol_integrator(name, params),
+            "Integrator" => self.integrator(name, params),
+            "Renderer" => self.renderer(name, params),
+            "Camera" => self.camera(name, params),
+            "Material" => {
+                let (mat, mat_params) = translate_material(name, params);
+                self.material(&mat, &mat_params);
+            },
+            "MakeNamedMaterial" => {
+                let ty = params.find_one_str("type", String::new());
+                let (mat, mut mat_params) = translate_material(&ty, params);
+                mat_params.erase("type");
+                mat_params.add_str("type", vec![mat]);
+                self.make_named_material(name, &mat_params);
+            },
+            "LightSource" => self.light_source(name, params),
+            "AreaLightSource" => self.area_light_source(name, params),
+            "Shape" => self.shape(name, params),
+            "ObjectInstance" => self.object_instance(name, params),
+            "MakeNamedMedium" | "MediumInterface" | "ColorSpace" | "Option" | "Attribute" =>
+                println!("WARNING: {} statements are unsupported. Ignoring \"{}\".",
+                         statement, name),
+            _ => return false
+        }
+
+        true
+    }
+
+    // !FIXME! There is no scene file tokenizer/parser (or PLY reader) yet.
+    // When they land, they should report malformed input by returning None
+    // with a warning instead of panicking, and get fuzz targets next to the
+    // ones in fuzz/ for the parsers of parameters, IES profiles and texture
+    // expressions. Statements with parameter lists should go through
+    // named_statement and add_param.
     fn parse_file(&mut self, _ : &str) -> Option<Scene> { None }
 
     fn init(opts: Options) -> Pbrt {
+        // Float and color textures split the budget between them
+        let lazy_texture_caches = opts.texture_budget.map(|budget| {
+            (Arc::new(LazyTextureCache::<f32>::new(budget / 2)),
+             Arc::new(LazyTextureCache::<Spectrum>::new(budget / 2)))
+        });
         Pbrt {
             options: opts,
             current_api_state: STATE_OPTIONS_BLOCK,
@@ -812,7 +2269,11 @@ impl Pbrt {
             graphics_state: GraphicsState::new(),
             pushed_graphics_states: Vec::new(),
             pushed_active_transform_bits: Vec::new(),
-            pushed_transforms: Vec::new()
+            pushed_transforms: Vec::new(),
+            rendered_images: Vec::new(),
+            float_texture_cache: TextureCache::new(),
+            spectrum_texture_cache: TextureCache::new(),
+            lazy_texture_caches
         }
     }
     
@@ -837,13 +2298,1012 @@ impl Pbrt {
                 }
             }
         }
+        if let Some(sheet_file) = pbrt.options.contact_sheet.clone() {
+            pbrt.write_contact_sheet(&sheet_file);
+        }
         pbrt.cleanup();
     }
+
+    fn write_contact_sheet(&self, filename: &str) {
+        let mut sheet = ContactSheet::new(CONTACT_SHEET_THUMB_SIZE, CONTACT_SHEET_COLUMNS);
+        for (image, time) in self.rendered_images.iter() {
+            sheet.add_image_file(image, *time);
+        }
+
+        if sheet.num_images() > 0 {
+            sheet.write(filename);
+        } else {
+            println!("WARNING: No images were rendered for the contact sheet \"{}\"", filename);
+        }
+    }
 }
 
 fn main() {
-    let options = Options::new();
-    let filenames : Vec<String> = vec![];
+    let mut options = Options::new();
+    let mut filenames : Vec<String> = vec![];
     // Process command line arguments
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--ncores" => {
+                options.num_cores = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--ncores requires a number of cores");
+            },
+            "--outfile" => {
+                options.image_file = args.next()
+                    .expect("--outfile requires an image filename");
+            },
+            "--debug-pixel" => {
+                let mut coord = || args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--debug-pixel requires the x and y coordinates of a pixel");
+                let x = coord();
+                options.debug_pixel = Some((x, coord()));
+            },
+            "--render-space" => {
+                options.render_space = match args.next().as_deref() {
+                    Some("world") => RenderSpace::World,
+                    Some("camera") => RenderSpace::Camera,
+                    Some("cameraworld") => RenderSpace::CameraWorld,
+                    _ => panic!("--render-space requires one of world, camera or cameraworld")
+                };
+            },
+            "--contact-sheet" => {
+                options.contact_sheet = Some(args.next()
+                    .expect("--contact-sheet requires an image filename"));
+            },
+            "--texture-budget" => {
+                let megabytes: usize = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--texture-budget requires a number of megabytes");
+                options.texture_budget = Some(megabytes << 20);
+            },
+            "--max-anisotropy" => {
+                options.max_anisotropy = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--max-anisotropy requires a number"));
+            },
+            "--quick" => options.quick_render = true,
+            "--quiet" => options.quiet = true,
+            "--verbose" => options.verbose = true,
+            "--window" => options.open_window = true,
+            _ => filenames.push(arg)
+        }
+    }
+
     Pbrt::run(options, filenames);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pbrt_rust::intersection::Intersectable;
+    use pbrt_rust::light::LightSample;
+    use pbrt_rust::params::{add_param, ParamValues};
+    use pbrt_rust::ray::Ray;
+    use pbrt_rust::rng::RNG;
+    use pbrt_rust::sampler::sample::Sample;
+
+    fn still() -> AnimatedScale { AnimatedScale::constant(Spectrum::from(1.0)) }
+
+    #[test]
+    fn quick_render_reduces_resolution_samples_and_depth() {
+        let mut ro = RenderOptions::new();
+        ro.film_params.add_int("xresolution", vec![800]);
+        ro.sampler_params.add_int("pixelsamples", vec![16]);
+        ro.surf_integrator_params.add_int("maxdepth", vec![8]);
+        ro.apply_quick_render();
+
+        assert_eq!(ro.film_params.find_one_int("xresolution", 0), 200);
+        assert_eq!(ro.film_params.find_one_int("yresolution", 0), 120);
+        assert_eq!(ro.sampler_params.find_one_int("pixelsamples", 0), 4);
+        assert_eq!(ro.surf_integrator_params.find_one_int("maxdepth", 0),
+                   QUICK_RENDER_MAX_DEPTH);
+        assert_eq!(ro.surf_integrator_params.find_one_int("maxindirectdepth", 0),
+                   QUICK_RENDER_MAX_DEPTH);
+    }
+
+    #[test]
+    fn quick_render_never_drops_below_one_sample() {
+        let mut ro = RenderOptions::new();
+        ro.sampler_name = String::from("stratified");
+        ro.sampler_params.add_int("xsamples", vec![1]);
+        ro.film_params.add_int("yresolution", vec![2]);
+        ro.apply_quick_render();
+
+        assert_eq!(ro.sampler_params.find_one_int("xsamples", 0), 1);
+        assert_eq!(ro.sampler_params.find_one_int("ysamples", 0), 1);
+        assert_eq!(ro.film_params.find_one_int("yresolution", 0), 1);
+    }
+
+    #[test]
+    fn scene_units_scale_the_world_into_meters() {
+        let mut pbrt = Pbrt::init(Options::new());
+        let mut params = ParamSet::new();
+        params.add_float("metersperunit", vec![0.01]);
+        pbrt.scene_units(&params);
+        pbrt.camera(&String::from("perspective"), &ParamSet::new());
+        pbrt.world_begin();
+
+        let cm = Transform::scale(0.01, 0.01, 0.01);
+        assert_eq!(pbrt.current_transforms[0], cm);
+        assert_eq!(pbrt.current_transforms[1], cm);
+        assert_eq!(pbrt.render_options.camera_to_world[0], cm);
+
+        // Nonsensical units are ignored
+        let mut pbrt = Pbrt::init(Options::new());
+        let mut params = ParamSet::new();
+        params.add_float("metersperunit", vec![-2.0]);
+        pbrt.scene_units(&params);
+        assert_eq!(pbrt.render_options.meters_per_unit, 1.0);
+    }
+
+    #[test]
+    fn scenes_far_from_the_origin_can_render_around_the_camera() {
+        use pbrt_rust::intersection::Intersectable;
+        use pbrt_rust::ray::Ray;
+
+        // A tiny sphere one unit in front of a camera that's far away from
+        // the origin. Returns how far from the surface of the sphere a ray
+        // from the camera hits it.
+        let hit_error = |space: RenderSpace| {
+            let mut options = Options::new();
+            options.render_space = space;
+            let mut pbrt = Pbrt::init(options);
+            pbrt.lookat(1e5, 1e5, 1e5, 1e5, 1e5, 1e5 + 1.0, 0.0, 1.0, 0.0);
+            pbrt.camera(&String::from("perspective"), &ParamSet::new());
+            pbrt.world_begin();
+
+            pbrt.translate(1e5, 1e5, 1e5 + 1.0);
+            let mut params = ParamSet::new();
+            params.add_float("radius", vec![1e-3]);
+            pbrt.shape(&String::from("sphere"), &params);
+
+            let c2w = pbrt.render_options.camera_to_world[0].clone();
+            let center = pbrt.current_transforms[0].t(&Point::new());
+            let ray = Ray::new_with(c2w.t(&Point::new()),
+                                    c2w.t(&Vector::new_with(0.0, 0.0, 1.0)), 0.0);
+            let isect = pbrt.render_options.primitives[0].intersect(&ray).unwrap();
+            (isect.dg.p.distance(&center) - 1e-3).abs()
+        };
+
+        // Around the camera, the hit is as precise as it is near the origin,
+        // which world space coordinates can't come close to
+        assert!(hit_error(RenderSpace::Camera) < 2e-5);
+        assert!(hit_error(RenderSpace::CameraWorld) < 2e-5);
+        assert!(hit_error(RenderSpace::World) > 1e-4);
+    }
+
+    #[test]
+    fn rendering_from_the_camera_moves_it_to_the_origin() {
+        let mut options = Options::new();
+        options.render_space = RenderSpace::CameraWorld;
+        let mut pbrt = Pbrt::init(options);
+        pbrt.lookat(10.0, 0.0, 0.0, 10.0, 1.0, 0.0, 0.0, 0.0, 1.0);
+        pbrt.camera(&String::from("perspective"), &ParamSet::new());
+        pbrt.world_begin();
+
+        // The camera keeps looking along +y, but from the origin
+        let c2w = &pbrt.render_options.camera_to_world[0];
+        assert!(c2w.t(&Point::new()).distance(&Point::new()) < 1e-6);
+        let dir = c2w.t(&Vector::new_with(0.0, 0.0, 1.0));
+        assert!((dir - Vector::new_with(0.0, 1.0, 0.0)).length() < 1e-6);
+
+        // while the world moves the other way
+        let world = &pbrt.named_coordinate_systems["world"][0];
+        assert_eq!(world.t(&Point::new_with(10.0, 0.0, 0.0)), Point::new());
+    }
+
+    #[test]
+    fn spot_lights_shine_from_and_to_the_given_points() {
+        use pbrt_rust::light::LightSample;
+        use pbrt_rust::rng::RNG;
+
+        let mut params = ParamSet::new();
+        params.add_point("from", vec![Point::new_with(0.0, 0.0, 1.0)]);
+        params.add_point("to", vec![Point::new_with(0.0, 0.0, -1.0)]);
+        params.add_float("coneangle", vec![20.0]);
+        params.add_float("conedeltaangle", vec![0.0]);
+        params.add_tex("gobo", vec![String::from("half")]);
+
+        let mut textures: HashMap<String, Arc<dyn Texture<Spectrum>>> = HashMap::new();
+        textures.insert(String::from("half"), Arc::new(ConstantTexture::new(Spectrum::from(0.5))));
+        let light = make_light("spot", &Transform::new(), &params, 1.0, &still(), &textures);
+
+        let mut rng = RNG::new(0);
+        let (l, ..) = light.sample_l(&Point::new_with(0.0, 0.0, -1.0),
+                                     LightSample::new(&mut rng), 0.0);
+        assert_eq!(l, Spectrum::from(0.125));
+
+        // 30 degrees off of the axis is outside of the cone
+        let (l, ..) = light.sample_l(&Point::new_with(1.0, 0.0, 1.0 - 3f32.sqrt()),
+                                     LightSample::new(&mut rng), 0.0);
+        assert!(l.is_black());
+    }
+
+    #[test]
+    fn expression_textures_can_use_earlier_textures() {
+        use pbrt_rust::diff_geom::DifferentialGeometry;
+
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let mut kd = ParamSet::new();
+        kd.add_float("value", vec![0.25]);
+        pbrt.texture(&String::from("kd"), &String::from("float"),
+                     &String::from("constant"), &kd);
+
+        let mut expr = ParamSet::new();
+        expr.add_str("expression", vec![String::from("0.5*checker(u,v) + kd")]);
+        pbrt.texture(&String::from("tint"), &String::from("color"),
+                     &String::from("expr"), &expr);
+
+        let tint = pbrt.graphics_state.spectrum_textures().get("tint").unwrap().clone();
+        assert_eq!(tint.evaluate(&DifferentialGeometry::new()), Spectrum::from(0.75));
+    }
+
+    #[test]
+    fn image_textures_read_their_files() {
+        use pbrt_rust::diff_geom::DifferentialGeometry;
+
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let filename = concat!(env!("CARGO_MANIFEST_DIR"),
+                               "/src/texture/testdata/checkerboard_square.png");
+        let mut params = ParamSet::new();
+        params.add_str("filename", vec![String::from(filename)]);
+        params.add_float("scale", vec![0.5]);
+        pbrt.texture(&String::from("bw"), &String::from("float"),
+                     &String::from("imagemap"), &params);
+
+        // The mapping can stretch the image
+        params.add_float("uscale", vec![2.0]);
+        params.add_float("gamma", vec![2.2]);
+        pbrt.texture(&String::from("tint"), &String::from("color"),
+                     &String::from("imagemap"), &params);
+
+        let mut dg = DifferentialGeometry::new();
+        dg.u = 0.75;
+        dg.v = 0.25;
+        let bw = pbrt.graphics_state.float_textures().get("bw").unwrap().clone();
+        assert_eq!(bw.evaluate(&dg), 0.5);
+
+        dg.u = 0.375;
+        let tint = pbrt.graphics_state.spectrum_textures().get("tint").unwrap().clone();
+        assert_eq!(tint.evaluate(&dg), Spectrum::from(0.5f32.powf(2.2)));
+
+        // Newer scenes spell out the encoding instead
+        params.erase("gamma");
+        params.add_str("encoding", vec![String::from("gamma 2.2")]);
+        pbrt.texture(&String::from("encoded"), &String::from("color"),
+                     &String::from("imagemap"), &params);
+        let encoded = pbrt.graphics_state.spectrum_textures().get("encoded").unwrap().clone();
+        assert_eq!(encoded.evaluate(&dg), tint.evaluate(&dg));
+    }
+
+    #[test]
+    fn every_texture_type_can_be_declared() {
+        use pbrt_rust::diff_geom::DifferentialGeometry;
+
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let filename = concat!(env!("CARGO_MANIFEST_DIR"),
+                               "/src/texture/testdata/checkerboard_square.png");
+        let mut params = ParamSet::new();
+        params.add_str("filename", vec![String::from(filename)]);
+        params.add_str("expression", vec![String::from("0.5")]);
+
+        let float_types = ["constant", "scale", "mix", "bilerp", "imagemap", "checkerboard",
+                           "dots", "fbm", "wrinkled", "windy", "expr"];
+        let spectrum_types = ["constant", "scale", "mix", "bilerp", "imagemap", "uv",
+                              "checkerboard", "dots", "fbm", "wrinkled", "marble", "windy",
+                              "expr"];
+        for ty in float_types.iter() {
+            pbrt.texture(&format!("float {}", ty), &String::from("float"),
+                         &String::from(*ty), &params);
+        }
+        for ty in spectrum_types.iter() {
+            pbrt.texture(&format!("color {}", ty), &String::from("color"),
+                         &String::from(*ty), &params);
+        }
+
+        // Solid checkerboards are their own kind of texture
+        params.add_int("dimension", vec![3]);
+        pbrt.texture(&String::from("solid"), &String::from("float"),
+                     &String::from("checkerboard"), &params);
+
+        // Unknown types warn instead of defining anything
+        pbrt.texture(&String::from("unknown"), &String::from("color"),
+                     &String::from("nonsense"), &params);
+
+        let fts = pbrt.graphics_state.float_textures();
+        let sts = pbrt.graphics_state.spectrum_textures();
+        assert_eq!(fts.len(), float_types.len() + 1);
+        assert_eq!(sts.len(), spectrum_types.len());
+        assert!(!sts.contains_key("unknown"));
+
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.5, 1.5, 2.5);
+        assert_eq!(fts["float constant"].evaluate(&dg), 1.0);
+        assert_eq!(fts["float mix"].evaluate(&dg), 0.5);
+        assert_eq!(sts["color scale"].evaluate(&dg), Spectrum::from(1.0));
+        assert_eq!(fts["solid"].evaluate(&dg), 0.0);
+    }
+
+    #[test]
+    fn noise_textures_can_use_simplex_noise() {
+        use pbrt_rust::diff_geom::DifferentialGeometry;
+
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let mut params = ParamSet::new();
+        pbrt.texture(&String::from("perlin"), &String::from("float"),
+                     &String::from("fbm"), &params);
+        params.add_str("basis", vec![String::from("simplex")]);
+        pbrt.texture(&String::from("simplex"), &String::from("float"),
+                     &String::from("fbm"), &params);
+
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.3, 0.4, 0.5);
+        dg.dpdx = Vector::new_with(0.01, 0.0, 0.0);
+        dg.dpdy = Vector::new_with(0.0, 0.01, 0.0);
+        let fts = pbrt.graphics_state.float_textures();
+        assert_ne!(fts["perlin"].evaluate(&dg), fts["simplex"].evaluate(&dg));
+    }
+
+    #[test]
+    fn image_textures_can_choose_their_mapping() {
+        use pbrt_rust::diff_geom::DifferentialGeometry;
+
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let filename = concat!(env!("CARGO_MANIFEST_DIR"),
+                               "/src/texture/testdata/checkerboard_square.png");
+        let mut params = ParamSet::new();
+        params.add_str("filename", vec![String::from(filename)]);
+        params.add_str("mapping", vec![String::from("planar")]);
+        params.add_vec("v1", vec![Vector::new_with(0.0, 0.0, 1.0)]);
+        pbrt.texture(&String::from("planar"), &String::from("float"),
+                     &String::from("imagemap"), &params);
+
+        // The planar mapping ignores (u, v) and projects the point instead
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.25, 0.25, 0.75);
+        let planar = pbrt.graphics_state.float_textures().get("planar").unwrap().clone();
+        assert_eq!(planar.evaluate(&dg), 1.0);
+        dg.p = Point::new_with(0.75, 0.25, 0.25);
+        assert_eq!(planar.evaluate(&dg), 0.0);
+    }
+
+    #[test]
+    fn image_textures_can_bias_their_level_of_detail() {
+        use pbrt_rust::diff_geom::DifferentialGeometry;
+
+        let mut opts = Options::new();
+        opts.max_anisotropy = Some(4.0);
+        let mut pbrt = Pbrt::init(opts);
+        pbrt.world_begin();
+
+        let filename = concat!(env!("CARGO_MANIFEST_DIR"),
+                               "/src/texture/testdata/checkerboard_square.png");
+        let mut params = ParamSet::new();
+        params.add_str("filename", vec![String::from(filename)]);
+        params.add_float("lodbias", vec![40.0]);
+        pbrt.texture(&String::from("blurry"), &String::from("float"),
+                     &String::from("imagemap"), &params);
+
+        // A large enough bias filters from the coarsest level, which is the
+        // average of the checks
+        let mut dg = DifferentialGeometry::new();
+        dg.u = 0.75;
+        dg.v = 0.25;
+        let blurry = pbrt.graphics_state.float_textures().get("blurry").unwrap().clone();
+        assert!((blurry.evaluate(&dg) - 0.5).abs() < 0.05);
+
+        // The global limit on anisotropy overrides larger ones
+        params.add_float("maxanisotropy", vec![16.0]);
+        let tp = TextureParams::new(&params, &params, Arc::new(HashMap::new()),
+                                    Arc::new(HashMap::new()));
+        assert_eq!(image_texture_params(&tp, pbrt.options.max_anisotropy).2, 4.0);
+        assert_eq!(image_texture_params(&tp, None).2, 16.0);
+    }
+
+    #[test]
+    fn image_textures_can_be_loaded_lazily() {
+        use pbrt_rust::diff_geom::DifferentialGeometry;
+
+        let mut opts = Options::new();
+        opts.texture_budget = Some(1 << 20);
+        let mut pbrt = Pbrt::init(opts);
+        pbrt.world_begin();
+
+        let filename = concat!(env!("CARGO_MANIFEST_DIR"),
+                               "/src/texture/testdata/checkerboard_square.png");
+        let mut params = ParamSet::new();
+        params.add_str("filename", vec![String::from(filename)]);
+        pbrt.texture(&String::from("bw"), &String::from("float"),
+                     &String::from("imagemap"), &params);
+
+        let (cache, _) = pbrt.lazy_texture_caches.clone().unwrap();
+        assert_eq!(cache.resident_bytes(), 0);
+
+        let mut dg = DifferentialGeometry::new();
+        dg.u = 0.75;
+        dg.v = 0.25;
+        let bw = pbrt.graphics_state.float_textures().get("bw").unwrap().clone();
+        assert_eq!(bw.evaluate(&dg), 1.0);
+        assert!(cache.resident_bytes() > 0);
+    }
+
+    #[test]
+    fn wrinkled_textures_are_looked_up_in_the_space_they_were_declared_in() {
+        use pbrt_rust::diff_geom::DifferentialGeometry;
+
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let mut params = ParamSet::new();
+        params.add_int("octaves", vec![4]);
+        pbrt.texture(&String::from("w"), &String::from("float"),
+                     &String::from("wrinkled"), &params);
+        pbrt.translate(1.0, 2.0, 3.0);
+        pbrt.texture(&String::from("moved"), &String::from("color"),
+                     &String::from("wrinkled"), &params);
+
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.3, 0.4, 0.5);
+        dg.dpdx = Vector::new_with(0.01, 0.0, 0.0);
+        dg.dpdy = Vector::new_with(0.0, 0.01, 0.0);
+        let w = pbrt.graphics_state.float_textures().get("w").unwrap().clone();
+        let expected = w.evaluate(&dg);
+        assert!(expected > 0.0);
+
+        dg.p = Point::new_with(1.3, 2.4, 3.5);
+        let moved = pbrt.graphics_state.spectrum_textures().get("moved").unwrap().clone();
+        let moved = moved.evaluate(&dg);
+        assert!((moved.y() - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn windy_textures_make_the_same_waves_for_floats_and_colors() {
+        use pbrt_rust::diff_geom::DifferentialGeometry;
+
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let params = ParamSet::new();
+        pbrt.texture(&String::from("height"), &String::from("float"),
+                     &String::from("windy"), &params);
+        pbrt.texture(&String::from("tint"), &String::from("color"),
+                     &String::from("windy"), &params);
+        let height = pbrt.graphics_state.float_textures().get("height").unwrap().clone();
+        let tint = pbrt.graphics_state.spectrum_textures().get("tint").unwrap().clone();
+
+        let mut dg = DifferentialGeometry::new();
+        dg.dpdx = Vector::new_with(0.01, 0.0, 0.0);
+        dg.dpdy = Vector::new_with(0.0, 0.01, 0.0);
+        let heights: Vec<f32> = (0..20).map(|i| {
+            dg.p = Point::new_with(1.7 * i as f32, 0.9 * i as f32, 0.3);
+            let h = height.evaluate(&dg);
+            assert!((tint.evaluate(&dg).y() - h).abs() < 1e-4);
+            h
+        }).collect();
+        assert!(heights.iter().any(|&h| h != 0.0));
+        assert!(heights.iter().any(|&h| h != heights[0]));
+    }
+
+    #[test]
+    fn sun_sky_lights_add_the_sun_and_the_sky() {
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let mut params = ParamSet::new();
+        params.add_vec("sundir", vec![Vector::new_with(1.0, 0.0, 1.0)]);
+        params.add_float("turbidity", vec![4.0]);
+        pbrt.light_source(&String::from("sunsky"), &params);
+
+        let lights = &pbrt.render_options.lights;
+        assert_eq!(lights.len(), 2);
+        assert!(!lights[0].is_delta_light());
+        assert!(lights[1].is_delta_light());
+    }
+
+    #[test]
+    fn sun_sky_portals_restrict_the_sky() {
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let mut params = ParamSet::new();
+        params.add_point("portal", vec![
+            Point::new_with(-1.0, -1.0, 2.0), Point::new_with(1.0, -1.0, 2.0),
+            Point::new_with(1.0, 1.0, 2.0), Point::new_with(-1.0, 1.0, 2.0)]);
+        pbrt.light_source(&String::from("sunsky"), &params);
+
+        let sky = &pbrt.render_options.lights[0];
+        let up = Vector::new_with(0.0, 0.0, 1.0);
+        assert!((sky.pdf(&Point::new(), &up) - 1.0).abs() < 1e-4);
+        assert_eq!(sky.pdf(&Point::new(), &Vector::new_with(0.0, 1.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn lights_can_be_put_in_output_groups() {
+        let ungrouped = make_light("distant", &Transform::new(), &ParamSet::new(), 1.0,
+                                   &still(), &HashMap::new());
+        assert_eq!(ungrouped.group(), None);
+
+        let mut params = ParamSet::new();
+        params.add_str("group", vec![String::from("key")]);
+        for name in ["point", "distant", "spot"].iter() {
+            let light = make_light(name, &Transform::new(), &params, 1.0, &still(),
+                                   &HashMap::new());
+            assert_eq!(light.group(), Some("key"));
+        }
+
+        // Both halves of a sun and sky go in the same group
+        let (sky, sun) = make_sun_sky(&Transform::new(), &params, &still());
+        assert_eq!(sky.group(), Some("key"));
+        assert_eq!(sun.group(), Some("key"));
+    }
+
+    #[test]
+    fn point_lights_keep_their_brightness_in_other_units() {
+        let mut params = ParamSet::new();
+        params.add_point("from", vec![Point::new_with(0.0, 0.0, 50.0)]);
+
+        let cm = Transform::scale(0.01, 0.01, 0.01);
+        let light = make_light("point", &cm, &params, 0.01, &still(), &HashMap::new());
+        let p = light.delta_position().unwrap();
+        assert!((p.z - 0.5).abs() < 1e-6);
+
+        // Everything it lights is 100 times closer in meters, so it has to
+        // be 10000 times dimmer to light it the same
+        let power = light.power(&Scene::new());
+        let expected = make_light("point", &Transform::new(), &params, 1.0, &still(),
+                                  &HashMap::new())
+            .power(&Scene::new()) * 1e-4;
+        assert!((power.y() - expected.y()).abs() < 1e-4 * expected.y());
+    }
+
+    #[test]
+    fn cameras_can_shape_their_aperture() {
+        assert_eq!(make_aperture(&ParamSet::new()), Aperture::round());
+
+        let mut params = ParamSet::new();
+        params.add_int("apertureblades", vec![6]);
+        params.add_float("aperturerotation", vec![15.0]);
+        params.add_float("cateye", vec![0.3]);
+        assert_eq!(make_aperture(&params), Aperture::new(6, 15.0, 0.3));
+    }
+
+    #[test]
+    fn cameras_can_tilt_their_focus() {
+        assert_eq!(make_focus_tilt(&ParamSet::new()), (0.0, 0.0));
+
+        let mut params = ParamSet::new();
+        params.add_float("tiltx", vec![-10.0]);
+        params.add_float("tilty", vec![5.0]);
+        assert_eq!(make_focus_tilt(&params), (-10.0, 5.0));
+    }
+
+    #[test]
+    fn volume_integrators_can_adapt_their_steps() {
+        let mut params = ParamSet::new();
+        params.add_float("stepsize", vec![0.1]);
+        let fixed = format!("{:?}", make_volume_integrator("single", &params));
+        assert!(fixed.contains("step_size: 0.1") && fixed.contains("cfl: None"));
+
+        params.add_float("cfl", vec![0.5]);
+        let adaptive = format!("{:?}", make_volume_integrator("single", &params));
+        assert!(adaptive.contains("cfl: Some(0.5)"));
+
+        params.add_int("majorantres", vec![8]);
+        let coarse = format!("{:?}", make_volume_integrator("single", &params));
+        assert!(coarse.contains("majorant_res: 8"));
+    }
+
+    #[test]
+    fn surface_integrators_read_their_limits() {
+        let mut params = ParamSet::new();
+        params.add_int("maxdepth", vec![7]);
+        params.add_int("rrstartdepth", vec![2]);
+        let path = make_surface_integrator("path", &params);
+        let expected = SurfaceIntegrator::path(7, 2).with_bounce_depths(BounceDepths::unlimited());
+        assert_eq!(format!("{:?}", path), format!("{:?}", expected));
+        assert!(format!("{:?}", path).contains("max_indirect: inf"));
+
+        params.add_float("maxindirect", vec![10.0]);
+        params.add_int("maxdiffusedepth", vec![2]);
+        params.add_int("maxspeculardepth", vec![10]);
+        params.add_str("bouncetypes", vec![String::from("reflection"),
+                                           String::from("diffuse"),
+                                           String::from("specular")]);
+        let depths = BounceDepths::new(2, usize::MAX, 10).with_mask(
+            BxDFType::BSDF_REFLECTION | BxDFType::BSDF_DIFFUSE | BxDFType::BSDF_SPECULAR);
+        for name in ["whitted", "irradiancecache", "path"].iter() {
+            let surf = format!("{:?}", make_surface_integrator(name, &params));
+            assert!(surf.contains("max_indirect: 10.0"), "{}", surf);
+            assert!(surf.contains(&format!("depths: {:?}", depths)), "{}", surf);
+        }
+
+        // Unknown integrators fall back to whitted
+        assert_eq!(format!("{:?}", make_surface_integrator("photonmap", &ParamSet::new())),
+                   format!("{:?}", make_surface_integrator("whitted", &ParamSet::new())));
+    }
+
+    #[test]
+    fn renderers_are_made_from_the_render_options() {
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.render_options.film_params.add_int("xresolution", vec![8]);
+        pbrt.render_options.film_params.add_int("yresolution", vec![4]);
+        let mut params = ParamSet::new();
+        params.add_int("pixelsamples", vec![2]);
+        pbrt.sampler(&String::from("halton"), &params);
+        pbrt.camera(&String::from("perspective"), &ParamSet::new());
+        pbrt.surf_integrator(&String::from("path"), &ParamSet::new());
+
+        // The camera sits inside of a sphere lit from its center
+        pbrt.world_begin();
+        pbrt.light_source(&String::from("point"), &ParamSet::new());
+        pbrt.shape(&String::from("sphere"), &ParamSet::new());
+
+        let mut renderer = pbrt.render_options.make_renderer(&pbrt.options).unwrap();
+        let scene = pbrt.render_options.make_scene();
+        let log = Arc::get_mut(&mut renderer).unwrap().debug_pixel(&scene, 4, 2).unwrap();
+        assert_eq!(log.matches("{\"event\":\"sample\"").count(), 2);
+        assert!(!log.contains("\"escaped\""));
+
+        // Renderers can't be made without a sampler
+        pbrt.render_options.sampler_name = String::from("unknown");
+        assert!(pbrt.render_options.make_renderer(&pbrt.options).is_none());
+    }
+
+    #[test]
+    fn accelerators_can_be_made_by_name() {
+        let spheres: Vec<Primitive> = (0..4).map(|i| {
+            let v = Vector::new_with(3.0 * (i as f32), 0.0, 0.0);
+            Primitive::simple(Shape::sphere(Transform::translate(&v),
+                                            Transform::translate(&(-v)),
+                                            false, 1.0, -1.0, 1.0, 360.0))
+        }).collect();
+        let ray = Ray::new_with(Point::new_with(6.0, 0.0, -5.0),
+                                Vector::new_with(0.0, 0.0, 1.0), 0.0);
+
+        let mut params = ParamSet::new();
+        params.add_str("splitmethod", vec![String::from("middle")]);
+        params.add_int("maxprims", vec![2]);
+        params.add_int("maxdepth", vec![3]);
+        params.add_float("emptybonus", vec![0.25]);
+        for (name, kind) in [("grid", "Grid("), ("bvh", "BVH("), ("kdtree", "KDT("),
+                             ("octree", "BVH(")].iter() {
+            let accel = make_accelerator(name, &spheres, &params);
+            assert!(format!("{:?}", accel).contains(kind), "{}", name);
+            assert!(accel.intersect_p(&ray), "{}", name);
+            let hit = accel.intersect(&ray).unwrap().primitive.unwrap();
+            assert_eq!(hit.get_id(), spheres[2].get_id(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn samplers_can_be_made_by_name() {
+        let mut params = ParamSet::new();
+        params.add_int("pixelsamples", vec![12]);
+        let extent = (-1, 9, -1, 5);
+
+        let sampler = make_sampler("maxmindist", &params, extent, 0.0, 1.0).unwrap();
+        assert!(matches!(sampler, Sampler::MaxMinDist(_)));
+        assert_eq!(sampler.sample_extent(), extent);
+        assert_eq!(sampler.maximum_sample_count(), 16);
+        assert_eq!(sampler.round_size(3), 4);
+
+        for name in ["lowdiscrepancy", "halton", "stratified", "adaptive"].iter() {
+            let sampler = make_sampler(name, &params, extent, 0.0, 1.0).unwrap();
+            assert_eq!(sampler.sample_extent(), extent);
+        }
+
+        let halton = format!("{:?}", make_sampler("halton", &params, extent, 0.0, 1.0));
+        assert!(halton.contains("scramble: Owen"));
+        params.add_str("scramble", vec![String::from("faure")]);
+        let halton = format!("{:?}", make_sampler("halton", &params, extent, 0.0, 1.0));
+        assert!(halton.contains("scramble: Faure"));
+
+        params.add_str("method", vec![String::from("variance")]);
+        params.add_float("maxerror", vec![0.02]);
+        let adaptive = format!("{:?}", make_sampler("adaptive", &params, extent, 0.0, 1.0));
+        assert!(adaptive.contains("method: Variance(0.02)"));
+
+        assert!(make_sampler("bestcandidate", &params, extent, 0.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn samplers_can_dither_their_samples_with_blue_noise() {
+        let mut params = ParamSet::new();
+        params.add_int("pixelsamples", vec![4]);
+        let first_lens_pos = |params: &ParamSet| {
+            let mut sampler = make_sampler("maxmindist", params, (0, 2, 0, 1), 0.0, 1.0)
+                .unwrap();
+            let mut samples = vec![Sample::empty(); 4];
+            let mut rng = RNG::new(0);
+            let mut pixels = Vec::new();
+            while sampler.get_more_samples(&mut samples, &mut rng) > 0 {
+                pixels.push(samples[0].camera_sample.lens_pos());
+            }
+            pixels
+        };
+
+        let plain = first_lens_pos(&params);
+        params.add_bool("bluenoise", vec![true]);
+        let dithered = first_lens_pos(&params);
+        assert_eq!(plain.len(), 2);
+        assert_eq!(dithered.len(), 2);
+        assert_ne!(plain, dithered);
+
+        // Samplers for parts of the image dither the same way
+        let sampler = make_sampler("lowdiscrepancy", &params, (0, 8, 0, 8), 0.0, 1.0).unwrap();
+        let sub = sampler.get_sub_sampler(0, 4).unwrap();
+        assert!(format!("{:?}", sub).contains("blue_noise: true"));
+        assert!(format!("{:?}", sampler.get_pixel_sampler(3, 3).unwrap())
+                .contains("blue_noise: true"));
+    }
+
+    #[test]
+    fn batch_renders_can_make_a_contact_sheet() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        let mut opts = Options::new();
+        let mut ro = RenderOptions::new();
+        ro.film_params.add_str("filename", vec![path("pbrt_sheet_frame0.png")]);
+        assert_eq!(ro.image_filename(&opts), path("pbrt_sheet_frame0.png"));
+        opts.image_file = path("pbrt_sheet_frame1.png");
+        assert_eq!(ro.image_filename(&opts), path("pbrt_sheet_frame1.png"));
+
+        let mut pbrt = Pbrt::init(opts);
+        for (i, color) in [[255, 0, 0], [0, 255, 0]].iter().enumerate() {
+            let filename = path(&format!("pbrt_sheet_frame{}.png", i));
+            image::RgbImage::from_pixel(40, 30, image::Rgb(*color)).save(&filename).unwrap();
+            pbrt.rendered_images.push((filename, Duration::from_millis(250)));
+        }
+
+        // Images that went missing are skipped
+        pbrt.rendered_images.push((path("pbrt_sheet_missing.png"), Duration::from_secs(1)));
+
+        let sheet_file = path("pbrt_sheet.png");
+        pbrt.write_contact_sheet(&sheet_file);
+        let sheet = image::open(&sheet_file).unwrap().to_rgb8();
+        assert!(sheet.width() > 2 * CONTACT_SHEET_THUMB_SIZE);
+        assert!(sheet.height() > CONTACT_SHEET_THUMB_SIZE);
+        assert!(sheet.height() < 2 * CONTACT_SHEET_THUMB_SIZE);
+    }
+
+    #[test]
+    fn newer_scene_statements_are_translated() {
+        let mut pbrt = Pbrt::init(Options::new());
+
+        // Newer parameter type names mean the same as the old ones
+        let mut params = ParamSet::new();
+        assert!(add_param(&mut params, "rgb reflectance", ParamValues::Numbers(vec![0.1, 0.2, 0.3])));
+        assert!(add_param(&mut params, "point3 P", ParamValues::Numbers(vec![1.0, 2.0, 3.0])));
+        assert!(add_param(&mut params, "integer maxdepth", ParamValues::Numbers(vec![3.0])));
+        assert!(add_param(&mut params, "spectrum eta",
+                          ParamValues::Strings(vec![String::from("metal-Ag-eta")])));
+        assert_eq!(params.find_one_spectrum("eta", Spectrum::from(0.0)), Conductor::Silver.eta());
+        assert!(add_param(&mut params, "spectrum Kd",
+                          ParamValues::Numbers(vec![400.0, 0.5, 700.0, 0.5])));
+        assert!(!add_param(&mut params, "point2 uv", ParamValues::Numbers(vec![0.0, 0.0])));
+        assert!(!add_param(&mut params, "float", ParamValues::Numbers(vec![0.0])));
+        assert!(!add_param(&mut params, "bool missing", ParamValues::Numbers(vec![1.0])));
+        assert_eq!(params.find_one_point("P", Point::new()), Point::new_with(1.0, 2.0, 3.0));
+        assert!(params.find_point("uv").is_none());
+
+        // Integrator sets both the surface and the volume integrators
+        let volpath = String::from("volpath");
+        assert!(pbrt.named_statement("Integrator", &volpath, &params));
+        assert_eq!(pbrt.render_options.surf_integrator_name, "path");
+        assert_eq!(pbrt.render_options.vol_integrator_name, "single");
+        assert_eq!(pbrt.render_options.surf_integrator_params.find_one_int("maxdepth", 5), 3);
+        assert_eq!(pbrt.render_options.vol_integrator_params, params);
+        assert!(!pbrt.named_statement("Unknown", &volpath, &params));
+
+        // Integrators that don't exist here fall back to ones that do
+        for (name, surf) in [("directlighting", "whitted"), ("ambientocclusion", "path")] {
+            assert!(pbrt.named_statement("Integrator", &String::from(name), &params));
+            assert_eq!(pbrt.render_options.surf_integrator_name, surf);
+        }
+
+        // Materials are renamed along with their parameters
+        pbrt.world_begin();
+        assert!(pbrt.named_statement("Material", &String::from("diffuse"), &params));
+        assert_eq!(pbrt.graphics_state.material, "matte");
+        assert_eq!(pbrt.graphics_state.material_params.find_one_spectrum("Kd", Spectrum::from(0.0)),
+                   Spectrum::from_rgb([0.1, 0.2, 0.3]));
+
+        let mut params = ParamSet::new();
+        params.add_str("type", vec![String::from("coateddiffuse")]);
+        params.add_float("roughness", vec![0.2]);
+        assert!(pbrt.named_statement("MakeNamedMaterial", &String::from("coated"), &params));
+        let coated = format!("{:?}", pbrt.graphics_state.named_materials["coated"]);
+        assert!(coated.starts_with("Substrate"));
+        assert!(coated.contains("distribution: GGX"));
+
+        let mut params = ParamSet::new();
+        params.add_str("type", vec![String::from("glass")]);
+        params.add_float("sellmeierb", vec![1.0, 0.2, 1.0]);
+        params.add_float("sellmeierc", vec![0.006, 0.02, 100.0]);
+        assert!(pbrt.named_statement("MakeNamedMaterial", &String::from("prism"), &params));
+        let prism = format!("{:?}", pbrt.graphics_state.named_materials["prism"]);
+        assert!(prism.starts_with("Glass") && prism.contains("Sellmeier"));
+
+        let mut params = ParamSet::new();
+        params.add_str("type", vec![String::from("dielectric")]);
+        params.add_float("eta", vec![1.33]);
+        assert!(pbrt.named_statement("MakeNamedMaterial", &String::from("water"), &params));
+        let water = format!("{:?}", pbrt.graphics_state.named_materials["water"]);
+        assert!(water.contains("Constant(1.33)"));
+
+        let mut params = ParamSet::new();
+        params.add_str("type", vec![String::from("clearcoat")]);
+        params.add_str("basematerial", vec![String::from("metal")]);
+        assert!(pbrt.named_statement("MakeNamedMaterial", &String::from("paint"), &params));
+        let paint = format!("{:?}", pbrt.graphics_state.named_materials["paint"]);
+        assert!(paint.starts_with("ClearCoat") && paint.contains("base: Metal"));
+
+        let mut params = ParamSet::new();
+        params.add_str("type", vec![String::from("disney")]);
+        params.add_float("metallic", vec![1.0]);
+        assert!(pbrt.named_statement("MakeNamedMaterial", &String::from("chrome"), &params));
+        let chrome = format!("{:?}", pbrt.graphics_state.named_materials["chrome"]);
+        assert!(chrome.starts_with("Disney"));
+
+        // Media are ignored rather than failing the whole scene
+        assert!(pbrt.named_statement("MakeNamedMedium", &String::from("fog"), &ParamSet::new()));
+    }
+
+    #[test]
+    fn lights_can_fade_over_the_shutter() {
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.transform_times(0.0, 2.0);
+        pbrt.world_begin();
+
+        let mut params = ParamSet::new();
+        params.add_point("from", vec![Point::new_with(0.0, 0.0, 1.0)]);
+        params.add_rgb_spectrum("scaleend", vec![0.0, 0.0, 0.0]);
+        pbrt.light_source(&String::from("point"), &params);
+        let light = pbrt.render_options.lights[0].clone();
+
+        let brightness = |time: f32| {
+            let mut rng = RNG::new(0);
+            light.sample_l(&Point::new(), LightSample::new(&mut rng), time).0.y()
+        };
+        assert!((brightness(0.0) - 1.0).abs() < 1e-5);
+        assert!((brightness(1.0) - 0.5).abs() < 1e-5);
+        assert_eq!(brightness(2.0), 0.0);
+        assert_eq!(brightness(3.0), 0.0);
+
+        // On average it's only half as bright
+        let power = light.power(&Scene::new()).y();
+        assert!((power - 2.0 * ::std::f32::consts::PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn instances_can_override_their_material() {
+        use pbrt_rust::intersection::Intersectable;
+        use pbrt_rust::ray::Ray;
+
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let mut named = ParamSet::new();
+        named.add_str("type", vec![String::from("matte")]);
+        pbrt.make_named_material(&String::from("autumnleaves"), &named);
+
+        let tree = String::from("tree");
+        pbrt.object_begin(tree.clone());
+        pbrt.shape(&String::from("sphere"), &ParamSet::new());
+        pbrt.object_end();
+        assert!(pbrt.render_options.primitives.is_empty());
+
+        let mut params = ParamSet::new();
+        params.add_str("material", vec![String::from("autumnleaves")]);
+        pbrt.object_instance(&tree, &params);
+        pbrt.object_instance(&tree, &ParamSet::new());
+        assert_eq!(pbrt.render_options.primitives.len(), 2);
+
+        let ray = || Ray::new_with(Point::new_with(0.0, 0.0, -5.0),
+                                   Vector::new_with(0.0, 0.0, 1.0), 0.0);
+        let leaves = &pbrt.graphics_state.named_materials["autumnleaves"];
+        let isect = pbrt.render_options.primitives[0].intersect(&ray()).unwrap();
+        assert!(Arc::ptr_eq(isect.material.as_ref().unwrap(), leaves));
+
+        let isect = pbrt.render_options.primitives[1].intersect(&ray()).unwrap();
+        assert!(isect.material.is_none());
+    }
+
+    #[test]
+    fn triangle_meshes_can_be_area_lights() {
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+        pbrt.area_light_source(&String::from("diffuse"), &ParamSet::new());
+
+        let mut params = ParamSet::new();
+        params.add_int("indices", vec![0, 2, 1, 0, 3, 2]);
+        params.add_point("P", vec![
+            Point::new_with(-1.0, -1.0, 5.0), Point::new_with(1.0, -1.0, 5.0),
+            Point::new_with(1.0, 1.0, 5.0), Point::new_with(-1.0, 1.0, 5.0)]);
+        pbrt.shape(&String::from("trianglemesh"), &params);
+        pbrt.shape(&String::from("loopsubdiv"), &params);
+
+        let p = Point::new();
+        let mut rng = RNG::new(0);
+        assert_eq!(pbrt.render_options.lights.len(), 2);
+        for light in pbrt.render_options.lights.iter() {
+            let (l, wi, pdf, _) = light.sample_l(&p, LightSample::new(&mut rng), 0.0);
+            assert!(!l.is_black());
+            assert!(pdf > 0.0 && pdf.is_finite());
+            assert!((pdf - light.pdf(&p, &wi)).abs() < 1e-3 * pdf);
+        }
+    }
+
+    #[test]
+    fn only_shapes_that_can_be_sampled_are_area_lights() {
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+        pbrt.area_light_source(&String::from("diffuse"), &ParamSet::new());
+
+        let mut curve = ParamSet::new();
+        curve.add_point("P", vec![
+            Point::new_with(0.0, 0.0, 5.0), Point::new_with(1.0, 0.0, 5.0),
+            Point::new_with(2.0, 0.0, 5.0), Point::new_with(3.0, 0.0, 5.0)]);
+        pbrt.shape(&String::from("curve"), &curve);
+        assert!(pbrt.render_options.lights.is_empty());
+        assert_eq!(pbrt.render_options.primitives.len(), 1);
+
+        pbrt.shape(&String::from("cone"), &ParamSet::new());
+        assert_eq!(pbrt.render_options.lights.len(), 1);
+
+        let p = Point::new_with(0.0, 0.0, 5.0);
+        let light = &pbrt.render_options.lights[0];
+        let (l, wi, pdf, _) = light.sample_l(&p, LightSample::new(&mut RNG::new(0)), 0.0);
+        assert!(!l.is_black());
+        assert!(pdf > 0.0 && pdf.is_finite());
+        assert!((pdf - light.pdf(&p, &wi)).abs() < 1e-3 * pdf);
+    }
+
+    #[test]
+    fn emissive_materials_make_area_lights() {
+        let mut pbrt = Pbrt::init(Options::new());
+        pbrt.world_begin();
+
+        let sphere = String::from("sphere");
+        pbrt.shape(&sphere, &ParamSet::new());
+        assert!(pbrt.render_options.lights.is_empty());
+
+        let mut glow = ParamSet::new();
+        glow.add_rgb_spectrum("Le", vec![1.0, 2.0, 3.0]);
+        pbrt.attribute_begin();
+        pbrt.material(&String::from("matte"), &glow);
+        pbrt.shape(&sphere, &ParamSet::new());
+        pbrt.attribute_end();
+        assert_eq!(pbrt.render_options.lights.len(), 1);
+        assert!(!pbrt.render_options.lights[0].is_delta_light());
+
+        // Named materials remember their emission, and shapes can set their own
+        let mut named = ParamSet::new();
+        named.add_str("type", vec![String::from("matte")]);
+        named.add_rgb_spectrum("Le", vec![1.0, 1.0, 1.0]);
+        pbrt.make_named_material(&String::from("lamp"), &named);
+        pbrt.graphics_state.current_named_material = Some(String::from("lamp"));
+        pbrt.shape(&sphere, &ParamSet::new());
+        assert_eq!(pbrt.render_options.lights.len(), 2);
+
+        let mut dark = ParamSet::new();
+        dark.add_rgb_spectrum("Le", vec![0.0, 0.0, 0.0]);
+        pbrt.shape(&sphere, &dark);
+        assert_eq!(pbrt.render_options.lights.len(), 2);
+        assert_eq!(pbrt.render_options.primitives.len(), 4);
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use crate::bsdf::BSDF;
+use crate::diff_geom::DifferentialGeometry;
+use crate::texture::{ScalarTextureReference, ColorTextureReference};
+
+use crate::material::Material;
+
+// Another material under a smooth, clear coating, like car paint or
+// lacquered wood. Thicker coatings absorb more of the light that crosses
+// them, giving it more of their tint.
+#[derive(Clone, Debug)]
+pub struct ClearCoatMaterial {
+    base: Arc<Material>,
+    eta: ScalarTextureReference,
+    tint: ColorTextureReference,
+    thickness: ScalarTextureReference
+}
+
+impl ClearCoatMaterial {
+    pub fn new(base: Arc<Material>, eta: ScalarTextureReference,
+               tint: ColorTextureReference,
+               thickness: ScalarTextureReference) -> ClearCoatMaterial {
+        ClearCoatMaterial { base, eta, tint, thickness }
+    }
+
+    pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
+                    dg_shading: DifferentialGeometry) -> Option<BSDF> {
+        let eta = self.eta.evaluate(&dg_shading).max(1.0);
+        let thickness = self.thickness.evaluate(&dg_shading).max(0.0);
+        let tint = self.tint.evaluate(&dg_shading).clamp(0.0, 1.0).powf(thickness);
+
+        let base = self.base.get_bsdf(dg_geom, dg_shading)?;
+        Some(base.with_clear_coat(eta, tint))
+    }
+}
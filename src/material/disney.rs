@@ -0,0 +1,152 @@
+use std::sync::Arc;
+
+use crate::bsdf::BSDF;
+use crate::bsdf::disney::{DisneyClearcoat, DisneyDiffuse, DisneySheen};
+use crate::bsdf::fresnel::Fresnel;
+use crate::bsdf::microfacet::{Microfacet, MicrofacetDistribution, MicrofacetTransmission};
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::{Texture, ConstantTexture, ScalarTextureReference, ColorTextureReference};
+use crate::utils::Lerp;
+
+use crate::material::bump;
+
+// Burley's principled material, which is how most interchange formats
+// describe surfaces. Every parameter besides the color and the index of
+// refraction is a weight between zero and one, and metals, dielectrics and
+// everything in between come from blending its lobes.
+#[derive(Clone, Debug)]
+pub struct DisneyMaterial {
+    color: ColorTextureReference,
+    metallic: ScalarTextureReference,
+    roughness: ScalarTextureReference,
+    eta: ScalarTextureReference,
+    specular_tint: ScalarTextureReference,
+    anisotropic: ScalarTextureReference,
+    sheen: ScalarTextureReference,
+    sheen_tint: ScalarTextureReference,
+    clearcoat: ScalarTextureReference,
+    clearcoat_gloss: ScalarTextureReference,
+    spec_trans: ScalarTextureReference,
+    bump_map: Option<ScalarTextureReference>
+}
+
+fn constant(value: f32) -> ScalarTextureReference { Arc::new(ConstantTexture::new(value)) }
+
+impl DisneyMaterial {
+    // An opaque, isotropic material without sheen or a clear coat, whose
+    // specular reflection is that of a dielectric with eta = 1.5 when it
+    // isn't metallic
+    pub fn new(color: ColorTextureReference, metallic: ScalarTextureReference,
+               roughness: ScalarTextureReference,
+               bm: Option<ScalarTextureReference>) -> DisneyMaterial {
+        DisneyMaterial {
+            color, metallic, roughness,
+            eta: constant(1.5),
+            specular_tint: constant(0.0),
+            anisotropic: constant(0.0),
+            sheen: constant(0.0),
+            sheen_tint: constant(0.5),
+            clearcoat: constant(0.0),
+            clearcoat_gloss: constant(1.0),
+            spec_trans: constant(0.0),
+            bump_map: bm
+        }
+    }
+
+    // The specular tint gives dielectric reflections the hue of the color
+    pub fn with_specular(self, eta: ScalarTextureReference,
+                         specular_tint: ScalarTextureReference) -> DisneyMaterial {
+        DisneyMaterial { eta, specular_tint, ..self }
+    }
+
+    pub fn with_anisotropic(self, anisotropic: ScalarTextureReference) -> DisneyMaterial {
+        DisneyMaterial { anisotropic, ..self }
+    }
+
+    // The sheen tint gives the sheen the hue of the color
+    pub fn with_sheen(self, sheen: ScalarTextureReference,
+                      sheen_tint: ScalarTextureReference) -> DisneyMaterial {
+        DisneyMaterial { sheen, sheen_tint, ..self }
+    }
+
+    pub fn with_clearcoat(self, clearcoat: ScalarTextureReference,
+                          clearcoat_gloss: ScalarTextureReference) -> DisneyMaterial {
+        DisneyMaterial { clearcoat, clearcoat_gloss, ..self }
+    }
+
+    // How much of the light that a dielectric doesn't reflect it transmits
+    // rather than scatters diffusely
+    pub fn with_transmission(self, spec_trans: ScalarTextureReference) -> DisneyMaterial {
+        DisneyMaterial { spec_trans, ..self }
+    }
+
+    pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
+                    dg_shading: DifferentialGeometry) -> Option<BSDF> {
+        // Allocate bsdf possibly doing bump mapping with bump map
+        let dgs = if let Some(ref tex) = self.bump_map {
+            bump(tex, &dg_geom, &dg_shading)
+        } else {
+            dg_shading
+        };
+
+        let color = self.color.evaluate(&dgs).clamp(0.0, f32::MAX);
+        let metallic = self.metallic.evaluate(&dgs).clamp(0.0, 1.0);
+        let eta = self.eta.evaluate(&dgs).max(1.0);
+        let roughness = self.roughness.evaluate(&dgs).clamp(0.0, 1.0);
+        let spec_trans = self.spec_trans.evaluate(&dgs).clamp(0.0, 1.0);
+
+        let trans_weight = (1.0 - metallic) * spec_trans;
+        let diffuse_weight = (1.0 - metallic) * (1.0 - spec_trans);
+        let mut bsdf = if trans_weight > 0.0 {
+            BSDF::new_with_eta(dgs.clone(), dg_geom.nn, eta)
+        } else {
+            BSDF::new(dgs.clone(), dg_geom.nn)
+        };
+
+        // The hue of the color, which tints the sheen and specular highlights
+        let luminance = color.y();
+        let tint = if luminance > 0.0 { color / luminance } else { Spectrum::from(1.0) };
+
+        if diffuse_weight > 0.0 {
+            bsdf.add_bxdf(DisneyDiffuse::new(diffuse_weight * color, roughness));
+
+            let sheen = self.sheen.evaluate(&dgs).clamp(0.0, 1.0);
+            if sheen > 0.0 {
+                let sheen_tint = self.sheen_tint.evaluate(&dgs).clamp(0.0, 1.0);
+                let sheen_color = Spectrum::from(1.0).lerp_with(tint, sheen_tint);
+                bsdf.add_bxdf(DisneySheen::new(diffuse_weight * sheen * sheen_color));
+            }
+        }
+
+        // Both specular lobes share a GGX distribution that is stretched
+        // along the shading frame's x axis by the anisotropy
+        let anisotropic = self.anisotropic.evaluate(&dgs).clamp(0.0, 1.0);
+        let aspect = (1.0 - anisotropic * 0.9).sqrt();
+        let alpha = roughness * roughness;
+        let distribution = MicrofacetDistribution::GGX((alpha / aspect).max(1e-3),
+                                                       (alpha * aspect).max(1e-3));
+
+        // Metals reflect their color at normal incidence, and dielectrics the
+        // reflectance of their index of refraction, possibly tinted
+        let specular_tint = self.specular_tint.evaluate(&dgs).clamp(0.0, 1.0);
+        let r0 = ((eta - 1.0) / (eta + 1.0)).powi(2);
+        let spec0 = (r0 * Spectrum::from(1.0).lerp_with(tint, specular_tint))
+            .lerp_with(color, metallic);
+        bsdf.add_bxdf(Microfacet::new(Spectrum::from(1.0),
+                                      Fresnel::disney(spec0, metallic, eta), distribution));
+
+        let clearcoat = self.clearcoat.evaluate(&dgs).clamp(0.0, 1.0);
+        if clearcoat > 0.0 {
+            let gloss = self.clearcoat_gloss.evaluate(&dgs);
+            bsdf.add_bxdf(DisneyClearcoat::new(clearcoat, gloss));
+        }
+
+        if trans_weight > 0.0 {
+            bsdf.add_bxdf(MicrofacetTransmission::new(
+                trans_weight * color.sqrt(), distribution, 1.0, eta));
+        }
+
+        Some(bsdf)
+    }
+}
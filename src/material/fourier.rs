@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use crate::bsdf::BSDF;
+use crate::bsdf::fourier::{FourierBSDF, FourierBSDFTable};
+use crate::diff_geom::DifferentialGeometry;
+use crate::texture::ScalarTextureReference;
+
+use crate::material::bump;
+
+// Materials whose scattering was tabulated ahead of time, e.g. layered
+// coatings. Like measured materials, the table is shared between every
+// BSDF made from it.
+#[derive(Clone, Debug)]
+pub struct FourierMaterial {
+    table: Arc<FourierBSDFTable>,
+    bump_map: Option<ScalarTextureReference>
+}
+
+impl FourierMaterial {
+    pub fn new(table: Arc<FourierBSDFTable>,
+               bm: Option<ScalarTextureReference>) -> FourierMaterial {
+        FourierMaterial { table, bump_map: bm }
+    }
+
+    pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
+                    dg_shading: DifferentialGeometry) -> Option<BSDF> {
+        // Allocate bsdf possibly doing bump mapping with bump map
+        let dgs = if let Some(ref tex) = self.bump_map {
+            bump(tex, &dg_geom, &dg_shading)
+        } else {
+            dg_shading
+        };
+
+        let mut bsdf = BSDF::new_with_eta(dgs, dg_geom.nn, self.table.eta());
+        bsdf.add_bxdf(FourierBSDF::new(self.table.clone()));
+        Some(bsdf)
+    }
+}
@@ -0,0 +1,50 @@
+use crate::bsdf::BSDF;
+use crate::bsdf::fresnel::{Fresnel, IndexOfRefraction};
+use crate::bsdf::specular::{SpecularReflection, SpecularTransmission};
+use crate::diff_geom::DifferentialGeometry;
+use crate::texture::{ScalarTextureReference, ColorTextureReference};
+
+use crate::material::bump;
+
+// Smooth dielectrics, which reflect and refract light perfectly. Glasses
+// whose index of refraction varies with wavelength split white light up
+// into its colors, see BSDF::set_wavelength.
+#[derive(Clone, Debug)]
+pub struct GlassMaterial {
+    k_r: ColorTextureReference,
+    k_t: ColorTextureReference,
+    index: IndexOfRefraction,
+    bump_map: Option<ScalarTextureReference>
+}
+
+impl GlassMaterial {
+    pub fn new(k_r: ColorTextureReference, k_t: ColorTextureReference,
+               index: IndexOfRefraction,
+               bump_map: Option<ScalarTextureReference>) -> GlassMaterial {
+        GlassMaterial { k_r, k_t, index, bump_map }
+    }
+
+    pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
+                    dg_shading: DifferentialGeometry) -> Option<BSDF> {
+        // Allocate bsdf possibly doing bump mapping with bump map
+        let dgs = if let Some(ref tex) = self.bump_map {
+            bump(tex, &dg_geom, &dg_shading)
+        } else {
+            dg_shading
+        };
+
+        let eta = self.index.nominal_eta();
+        let r = self.k_r.evaluate(&dgs).clamp(0.0, f32::MAX);
+        let t = self.k_t.evaluate(&dgs).clamp(0.0, f32::MAX);
+
+        let mut bsdf = BSDF::new_with_eta(dgs, dg_geom.nn, eta).with_dispersion(self.index);
+        if !r.is_black() {
+            bsdf.add_bxdf(SpecularReflection::new(r, Fresnel::dielectric(1.0, eta)));
+        }
+        if !t.is_black() {
+            bsdf.add_bxdf(SpecularTransmission::new(t, 1.0, eta));
+        }
+
+        Some(bsdf)
+    }
+}
@@ -0,0 +1,60 @@
+use crate::bsdf::BSDF;
+use crate::bsdf::hair::HairBSDF;
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::{Texture, ScalarTextureReference, ColorTextureReference};
+
+// The ways of specifying how much light the inside of a hair absorbs
+#[derive(Clone, Debug)]
+pub enum HairAbsorption {
+    SigmaA(ColorTextureReference),
+    // The color that the hair should roughly end up looking
+    Color(ColorTextureReference),
+    // Concentrations of the eumelanin and pheomelanin pigments
+    Melanin(ScalarTextureReference, ScalarTextureReference)
+}
+
+// Hair is meant to be put on curves, which have v running across their
+// width, so that it knows where on the fiber each ray hits.
+#[derive(Clone, Debug)]
+pub struct HairMaterial {
+    absorption: HairAbsorption,
+    eta: ScalarTextureReference,
+    beta_m: ScalarTextureReference,
+    beta_n: ScalarTextureReference,
+    alpha: ScalarTextureReference
+}
+
+impl HairMaterial {
+    pub fn new(absorption: HairAbsorption, eta: ScalarTextureReference,
+               beta_m: ScalarTextureReference, beta_n: ScalarTextureReference,
+               alpha: ScalarTextureReference) -> HairMaterial {
+        HairMaterial { absorption, eta, beta_m, beta_n, alpha }
+    }
+
+    pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
+                    dg_shading: DifferentialGeometry) -> Option<BSDF> {
+        let beta_m = self.beta_m.evaluate(&dg_shading).clamp(0.0, 1.0);
+        let beta_n = self.beta_n.evaluate(&dg_shading).clamp(0.0, 1.0);
+        let alpha = self.alpha.evaluate(&dg_shading);
+        let eta = self.eta.evaluate(&dg_shading);
+
+        let sigma_a = match &self.absorption {
+            HairAbsorption::SigmaA(s) => s.evaluate(&dg_shading).clamp(0.0, f32::MAX),
+            HairAbsorption::Color(c) => {
+                let color = c.evaluate(&dg_shading).clamp(0.0, 1.0);
+                HairBSDF::sigma_a_from_reflectance(&color, beta_n)
+            },
+            HairAbsorption::Melanin(ce, cp) =>
+                HairBSDF::sigma_a_from_concentration(ce.evaluate(&dg_shading).max(0.0),
+                                                     cp.evaluate(&dg_shading).max(0.0))
+        };
+
+        // Find where across the width of the curve the hair was hit
+        let h = (-1.0 + 2.0 * dg_shading.v).clamp(-1.0, 1.0);
+
+        let mut bsdf = BSDF::new(dg_shading, dg_geom.nn);
+        bsdf.add_bxdf(HairBSDF::new(h, eta, sigma_a, beta_m, beta_n, alpha));
+        Some(bsdf)
+    }
+}
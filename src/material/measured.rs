@@ -1,32 +1,53 @@
+use std::path::Path;
 use std::sync::Arc;
 
-use bsdf::BSDF;
-use bsdf::measured::IrregIsotropic;
-use bsdf::measured::IrregIsotropicSample;
-use bsdf::measured::RegularHalfangle;
-use diff_geom::DifferentialGeometry;
-use spectrum::Spectrum;
-use texture::Texture;
-use utils::kdtree::KdTree;
+use crate::bsdf::BSDF;
+use crate::bsdf::measured::IrregIsotropic;
+use crate::bsdf::measured::IrregIsotropicSample;
+use crate::bsdf::measured::RegularHalfangle;
+use crate::bsdf::measured::RegularHalfangleTable;
+use crate::bsdf::measured::read_irregular_isotropic;
+use crate::diff_geom::DifferentialGeometry;
+use crate::texture::Texture;
+use crate::utils::kdtree::KdTree;
 
-use material::bump;
+use crate::material::bump;
 
+// The measured data is read-only once loaded, so it is shared between
+// every BSDF created by this material (and every render thread) rather
+// than being copied for each intersection.
 #[derive(Clone, Debug)]
 pub struct MeasuredMaterial {
-    theta_phi_data: KdTree<IrregIsotropicSample>,
-    regular_halfangle_data: Vec<f32>,
-    num_theta_h: usize,
-    num_theta_d: usize,
-    num_phi_d: usize,
+    theta_phi_data: Option<Arc<KdTree<IrregIsotropicSample>>>,
+    regular_halfangle_data: Option<Arc<RegularHalfangleTable>>,
     bump_map: Option<Arc<dyn Texture<f32>>>
 }
 
 impl MeasuredMaterial {
     pub fn new(filename: String, b: Option<Arc<dyn Texture<f32>>>) -> MeasuredMaterial {
-        // If we want to follow the datatype for the measured brdf data
-        // used in PBRT-v2, then we need to follow the code given in
-        // materials/measured.cpp...
-        unimplemented!()
+        // Files from the MERL database are tabulated in half angle
+        // coordinates, anything else is taken to be irregularly sampled
+        // as in pbrt-v2.
+        let mut mat = MeasuredMaterial {
+            theta_phi_data: None,
+            regular_halfangle_data: None,
+            bump_map: b
+        };
+
+        let is_merl = Path::new(&filename).extension().is_some_and(|ext| ext == "merl");
+        let result = if is_merl {
+            RegularHalfangleTable::read_merl(&filename)
+                .map(|table| mat.regular_halfangle_data = Some(Arc::new(table)))
+        } else {
+            read_irregular_isotropic(&filename)
+                .map(|data| mat.theta_phi_data = Some(Arc::new(data)))
+        };
+
+        if let Err(e) = result {
+            println!("WARNING: Unable to read measured BRDF data in {}: {}", filename, e);
+        }
+
+        mat
     }
 
     pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
@@ -40,13 +61,10 @@ impl MeasuredMaterial {
 
         let mut bsdf = BSDF::new(dgs.clone(), dg_geom.nn);
 
-        if self.regular_halfangle_data.len() > 0 {
-            let data = self.regular_halfangle_data.clone();
-            bsdf.add_bxdf(RegularHalfangle::new(self.num_theta_h,
-                                                self.num_theta_d,
-                                                self.num_phi_d, data));
-        } else {
-            bsdf.add_bxdf(IrregIsotropic::new(self.theta_phi_data.clone()));
+        if let Some(ref table) = self.regular_halfangle_data {
+            bsdf.add_bxdf(RegularHalfangle::new(table.clone()));
+        } else if let Some(ref data) = self.theta_phi_data {
+            bsdf.add_bxdf(IrregIsotropic::new(data.clone()));
         }
 
         Some(bsdf)
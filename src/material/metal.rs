@@ -0,0 +1,63 @@
+use crate::bsdf::BSDF;
+use crate::bsdf::TwoSided;
+use crate::bsdf::fresnel::Fresnel;
+use crate::bsdf::microfacet::DistributionKind;
+use crate::bsdf::microfacet::Microfacet;
+use crate::bsdf::microfacet::MicrofacetDistribution;
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::{Texture, ScalarTextureReference, ColorTextureReference};
+
+use crate::material::bump;
+
+#[derive(Clone, Debug)]
+pub struct MetalMaterial {
+    eta: ColorTextureReference,
+    k: ColorTextureReference,
+    u_roughness: ScalarTextureReference,
+    v_roughness: ScalarTextureReference,
+    distribution: DistributionKind,
+    bump_map: Option<ScalarTextureReference>
+}
+
+impl MetalMaterial {
+    pub fn new(eta: ColorTextureReference,
+               k: ColorTextureReference,
+               u_rough: ScalarTextureReference,
+               v_rough: ScalarTextureReference,
+               bm: Option<ScalarTextureReference>) -> MetalMaterial {
+        MetalMaterial {
+            eta, k, u_roughness: u_rough, v_roughness: v_rough,
+            distribution: DistributionKind::Blinn, bump_map: bm
+        }
+    }
+
+    pub fn with_distribution(self, distribution: DistributionKind) -> MetalMaterial {
+        MetalMaterial { distribution, ..self }
+    }
+
+    pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
+                    dg_shading: DifferentialGeometry) -> Option<BSDF> {
+        // Allocate bsdf possibly doing bump mapping with bump map
+        let dgs = if let Some(ref tex) = self.bump_map {
+            bump(tex, &dg_geom, &dg_shading)
+        } else {
+            dg_shading
+        };
+
+        let mut bsdf = BSDF::new(dgs.clone(), dg_geom.nn);
+
+        let eta = self.eta.evaluate(&dgs);
+        let k = self.k.evaluate(&dgs);
+        let fresnel = Fresnel::conductor(&eta, &k);
+
+        let u_rough = self.u_roughness.evaluate(&dgs);
+        let v_rough = self.v_roughness.evaluate(&dgs);
+        let spec = Microfacet::new(Spectrum::from(1.0), fresnel,
+                                   MicrofacetDistribution::from_roughness(
+                                       self.distribution, u_rough, v_rough));
+        bsdf.add_bxdf(TwoSided::new(spec));
+
+        Some(bsdf)
+    }
+}
@@ -1,13 +1,13 @@
 use std::sync::Arc;
 
-use bsdf::BSDF;
-use bsdf::lambertian::Lambertian;
-use bsdf::orennayar::OrenNayar;
-use diff_geom::DifferentialGeometry;
-use spectrum::Spectrum;
-use texture::Texture;
-
-use material::Material;
+use crate::bsdf::BSDF;
+use crate::bsdf::lambertian::Lambertian;
+use crate::bsdf::orennayar::OrenNayar;
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::Texture;
+
+use crate::material::Material;
 
 #[derive(Clone, Debug)]
 pub struct MixMaterial {
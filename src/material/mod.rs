@@ -1,24 +1,43 @@
+mod clearcoat;
+mod disney;
+mod fourier;
+mod glass;
+mod hair;
 mod matte;
 mod measured;
+mod metal;
 mod mix;
 mod plastic;
+mod substrate;
 mod subsurface;
 
 use std::sync::Arc;
 
-use bsdf::BSDF;
-use bsdf::bssrdf::BSSRDF;
-use diff_geom::DifferentialGeometry;
-use geometry::vector::*;
-use geometry::normal::*;
-use spectrum::Spectrum;
-use texture::{Texture, ScalarTextureReference, ColorTextureReference};
+use crate::bsdf::BSDF;
+use crate::bsdf::bssrdf::BSSRDF;
+use crate::bsdf::fourier::FourierBSDFTable;
+use crate::bsdf::fresnel::IndexOfRefraction;
+use crate::bsdf::microfacet::DistributionKind;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::vector::*;
+use crate::geometry::normal::*;
+use crate::spectrum::Spectrum;
+use crate::texture::{Texture, ScalarTextureReference, ColorTextureReference};
 
-use material::matte::MatteMaterial;
-use material::plastic::PlasticMaterial;
-use material::measured::MeasuredMaterial;
-use material::mix::MixMaterial;
-use material::subsurface::SubsurfaceMaterial;
+use crate::material::clearcoat::ClearCoatMaterial;
+use crate::material::fourier::FourierMaterial;
+use crate::material::glass::GlassMaterial;
+use crate::material::hair::HairMaterial;
+use crate::material::matte::MatteMaterial;
+use crate::material::plastic::PlasticMaterial;
+use crate::material::measured::MeasuredMaterial;
+use crate::material::metal::MetalMaterial;
+use crate::material::mix::MixMaterial;
+use crate::material::substrate::SubstrateMaterial;
+use crate::material::subsurface::SubsurfaceMaterial;
+
+pub use crate::material::disney::DisneyMaterial;
+pub use crate::material::hair::HairAbsorption;
 
 pub fn bump<Tex: Texture<f32>>(
     d: &Tex, dg_geom: &DifferentialGeometry,
@@ -34,6 +53,13 @@ pub fn bump<Tex: Texture<f32>>(
 
     dg_eval.p = &dg_shading.p + du * &dg_shading.dpdu;
     dg_eval.u = dg_shading.u + du;
+
+    // Shapes whose u wraps around, like full spheres, have a seam at u = 1,
+    // and offsetting past it should find the texture on the other side of
+    // the seam rather than whatever lies beyond u = 1
+    if dg_shading.shape.as_ref().is_some_and(|s| s.periodic_u) {
+        dg_eval.u = dg_eval.u.rem_euclid(1.0);
+    }
     dg_eval.nn = Normal::from(
         dg_shading.dpdu.cross_with(&dg_shading.dpdv) + du * &dg_shading.dndu)
         .normalize();
@@ -76,60 +102,194 @@ pub fn bump<Tex: Texture<f32>>(
     dg_bump
 }
 
+// Perturbs the shading frame with a normal map, whose colors hold normals
+// in the tangent space of the surface: red along dpdu, green along the
+// normal crossed with dpdu, and blue along the normal itself. The maps
+// should be read without any gamma correction.
+pub fn normal_map<Tex: Texture<Spectrum> + ?Sized>(
+    map: &Tex, dg_geom: &DifferentialGeometry,
+    dg_shading: &DifferentialGeometry) -> DifferentialGeometry {
+    let rgb = map.evaluate(dg_shading).to_rgb();
+    let local = Vector::new_with(2.0 * rgb[0] - 1.0, 2.0 * rgb[1] - 1.0, 2.0 * rgb[2] - 1.0);
+    if local.length_squared() == 0.0 {
+        return dg_shading.clone();
+    }
+    let local = local.normalize();
+
+    // Transform the normal out of tangent space
+    let n = Vector::from(dg_shading.nn.clone());
+    let x = dg_shading.dpdu.clone().normalize();
+    let y = n.cross_with(&x);
+    let ns = (local.x * x + local.y * y + local.z * n).normalize();
+
+    // Keep the lengths of the partial derivatives but make them
+    // perpendicular to the new normal
+    let u_len = dg_shading.dpdu.length();
+    let v_len = dg_shading.dpdv.length();
+    let dpdu = (&dg_shading.dpdu - ns.dot(&dg_shading.dpdu) * &ns).normalize() * u_len;
+    let dpdv = ns.cross_with(&dpdu).normalize() * v_len;
+
+    let mut dg_mapped = dg_shading.clone();
+    dg_mapped.dpdu = dpdu;
+    dg_mapped.dpdv = dpdv;
+    dg_mapped.nn = Normal::from(ns).face_forward(Vector::from(dg_geom.nn.clone()));
+    dg_mapped
+}
+
 #[derive(Clone, Debug)]
 pub enum Material {
+    ClearCoat(ClearCoatMaterial),
+    Disney(DisneyMaterial),
+    Fourier(FourierMaterial),
+    Glass(GlassMaterial),
+    Hair(HairMaterial),
     Matte(MatteMaterial),
     Plastic(PlasticMaterial),
     Measured(MeasuredMaterial),
+    Metal(MetalMaterial),
     Mixed(MixMaterial),
+    // Any material whose shading frame is perturbed by a normal map first
+    NormalMapped(Arc<Material>, ColorTextureReference),
+    Substrate(SubstrateMaterial),
     Subsurface(SubsurfaceMaterial),
     Broken
 }
 
 impl Material {
+    // The base material under a smooth coating with the index of refraction
+    // eta, which tints light by the given color for each unit of thickness
+    // that it crosses
+    pub fn clear_coat(base: Arc<Material>, eta: ScalarTextureReference,
+                      tint: ColorTextureReference,
+                      thickness: ScalarTextureReference) -> Material {
+        Material::ClearCoat(ClearCoatMaterial::new(base, eta, tint, thickness))
+    }
+
+    // Burley's principled material, whose optional lobes are added with
+    // DisneyMaterial's builders
+    pub fn disney(mat: DisneyMaterial) -> Material { Material::Disney(mat) }
+
+    // A BSDF tabulated by external tools, see FourierBSDFTable::read
+    pub fn fourier(table: Arc<FourierBSDFTable>,
+                   bm: Option<ScalarTextureReference>) -> Material {
+        Material::Fourier(FourierMaterial::new(table, bm))
+    }
+
+    // Smooth glass that reflects with kr and refracts with kt, see
+    // IndexOfRefraction for glasses that disperse light
+    pub fn glass(kr: ColorTextureReference, kt: ColorTextureReference,
+                 index: IndexOfRefraction,
+                 bm: Option<ScalarTextureReference>) -> Material {
+        Material::Glass(GlassMaterial::new(kr, kt, index, bm))
+    }
+
+    // Scattering from hair fibers, which should be put on curves. The
+    // roughness along and around the fiber are from zero to one, and the
+    // scales on its surface are tilted by alpha degrees.
+    pub fn hair(absorption: HairAbsorption, eta: ScalarTextureReference,
+                beta_m: ScalarTextureReference, beta_n: ScalarTextureReference,
+                alpha: ScalarTextureReference) -> Material {
+        Material::Hair(HairMaterial::new(absorption, eta, beta_m, beta_n, alpha))
+    }
+
     pub fn matte(kd: ColorTextureReference,
                  sig: ScalarTextureReference,
                  bump_map: Option<ScalarTextureReference>) -> Material {
         Material::Matte(MatteMaterial::new(kd, sig, bump_map))
     }
 
+    // Like substrates, the roughness of the highlights can differ along u
+    // and v
     pub fn plastic(kd: ColorTextureReference,
                    ks: ColorTextureReference,
-                   rough: ScalarTextureReference,
+                   u_rough: ScalarTextureReference,
+                   v_rough: ScalarTextureReference,
                    bm: Option<ScalarTextureReference>) -> Material {
-        Material::Plastic(PlasticMaterial::new(kd, ks, rough, bm))
+        Material::Plastic(PlasticMaterial::new(kd, ks, u_rough, v_rough, bm))
     }
 
     pub fn measured(filename: String, b: Option<ScalarTextureReference>) -> Material {
         Material::Measured(MeasuredMaterial::new(filename, b))
     }
 
+    // Conductors with the complex index of refraction eta + ik, see
+    // fresnel::Conductor for some measured ones. Brushed metals are rougher
+    // across their grooves than along them.
+    pub fn metal(eta: ColorTextureReference,
+                 k: ColorTextureReference,
+                 u_rough: ScalarTextureReference,
+                 v_rough: ScalarTextureReference,
+                 bm: Option<ScalarTextureReference>) -> Material {
+        Material::Metal(MetalMaterial::new(eta, k, u_rough, v_rough, bm))
+    }
+
     pub fn mixed(m1: Arc<Material>, m2: Arc<Material>,
                  sc: ColorTextureReference) -> Material {
         Material::Mixed(MixMaterial::new(m1, m2, sc))
     }
 
+    // The roughness of the glossy coating can differ along u and v
+    pub fn substrate(kd: ColorTextureReference,
+                     ks: ColorTextureReference,
+                     u_rough: ScalarTextureReference,
+                     v_rough: ScalarTextureReference,
+                     bm: Option<ScalarTextureReference>) -> Material {
+        Material::Substrate(SubstrateMaterial::new(kd, ks, u_rough, v_rough, bm))
+    }
+
     pub fn subsurface(scale: f32, k_r: ColorTextureReference,
                       sigma_a: ColorTextureReference,
                       sigma_prime_s: ColorTextureReference,
-                      eta: ScalarTextureReference,
+                      eta: f32,
                       bm: Option<ScalarTextureReference>) -> Material {
         Material::Subsurface(
             SubsurfaceMaterial::new(scale, k_r, sigma_a, sigma_prime_s, eta, bm))
     }
 
-    // !FIXME!
+    // The material of primitives that aren't shaded at all, such as those
+    // that only emit light. Rays that hit them don't scatter.
     pub fn broken() -> Material { Material::Broken }
 
+    pub fn with_normal_map(self, map: ColorTextureReference) -> Material {
+        Material::NormalMapped(Arc::new(self), map)
+    }
+
+    // Only materials with glossy microfacet reflection can choose their
+    // distribution of microfacets
+    pub fn with_distribution(self, distribution: DistributionKind) -> Material {
+        match self {
+            Material::Metal(mat) => Material::Metal(mat.with_distribution(distribution)),
+            Material::Plastic(mat) => Material::Plastic(mat.with_distribution(distribution)),
+            Material::Substrate(mat) =>
+                Material::Substrate(mat.with_distribution(distribution)),
+            _ => {
+                println!("WARNING: Only metal, plastic and substrate materials have a \
+                          microfacet distribution");
+                self
+            }
+        }
+    }
+
     pub fn get_bsdf(&self, dg: DifferentialGeometry,
                     dgs: DifferentialGeometry) -> Option<BSDF> {
         match self {
+            Material::ClearCoat(mat) => mat.get_bsdf(dg, dgs),
+            Material::Disney(mat) => mat.get_bsdf(dg, dgs),
+            Material::Fourier(mat) => mat.get_bsdf(dg, dgs),
+            Material::Glass(mat) => mat.get_bsdf(dg, dgs),
+            Material::Hair(mat) => mat.get_bsdf(dg, dgs),
             &Material::Matte(ref mat) => mat.get_bsdf(dg, dgs),
             &Material::Plastic(ref mat) => mat.get_bsdf(dg, dgs),
             &Material::Measured(ref mat) => mat.get_bsdf(dg, dgs),
+            Material::Metal(mat) => mat.get_bsdf(dg, dgs),
             &Material::Mixed(ref mat) => mat.get_bsdf(dg, dgs),
+            Material::NormalMapped(mat, map) => {
+                let dgs = normal_map(map, &dg, &dgs);
+                mat.get_bsdf(dg, dgs)
+            },
+            Material::Substrate(mat) => mat.get_bsdf(dg, dgs),
             &Material::Subsurface(ref mat) => mat.get_bsdf(dg, dgs),
-            _ => unimplemented!()
+            Material::Broken => None
         }
     }
 
@@ -137,6 +297,10 @@ impl Material {
                       dgs: DifferentialGeometry) -> Option<BSSRDF> {
         match self {
             &Material::Subsurface(ref mat) => Some(mat.get_bssrdf(dg, dgs)),
+            Material::NormalMapped(mat, map) => {
+                let dgs = normal_map(map, &dg, &dgs);
+                mat.get_bssrdf(dg, dgs)
+            },
             _ => None
         }
     }
@@ -146,11 +310,303 @@ impl Material {
 mod tests {
     use super::*;
 
-    #[ignore]
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::ray::Ray;
+    use crate::shape::Shape;
+    use crate::texture::ConstantTexture;
+    use crate::texture::expr::ExprTexture;
+    use crate::transform::transform::Transform;
+
+    use std::collections::HashMap;
+
+    // Bumps the point on the unit sphere at the equator, phi radians around
+    fn bumped_sphere_normal<Tex: Texture<f32>>(sphere: &Shape, tex: &Tex, phi: f32) -> Normal {
+        let dir = Vector::new_with(phi.cos(), phi.sin(), 0.0);
+        let ray = Ray::new_with(Point::new() + 2.0 * &dir, -dir, 0.0);
+        let dg = sphere.intersect(&ray).unwrap().dg;
+        bump(tex, &dg, &dg).nn
+    }
+
     #[test]
     fn it_can_generate_normals_from_bump_maps() {
-        // We need to try with some dummy bump maps here
-        // once we get textures implemented
-        unimplemented!()
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+
+        // Constant displacement just makes the sphere bigger
+        let flat = ConstantTexture::new(0.1f32);
+        let n = bumped_sphere_normal(&sphere, &flat, 1.0);
+        assert!((Vector::from(n) - Vector::new_with(1f32.cos(), 1f32.sin(), 0.0)).length() < 1e-4);
+
+        // Displacement that increases with u tilts the normal towards -u
+        let ramp = ExprTexture::parse("0.1 * u", &HashMap::new(), &HashMap::new()).unwrap();
+        let n = bumped_sphere_normal(&sphere, &ramp, 1.0);
+        assert!(Vector::from(n).dot(&Vector::new_with(-(1f32.sin()), 1f32.cos(), 0.0)) < -1e-3);
+    }
+
+    #[test]
+    fn metals_reflect_their_color() {
+        use crate::bsdf::BxDFType;
+        use crate::bsdf::fresnel::Conductor;
+
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+        let ray = Ray::new_with(Point::new_with(2.0, 0.0, 0.0),
+                                Vector::new_with(-1.0, 0.0, 0.0), 0.0);
+        let dg = sphere.intersect(&ray).unwrap().dg;
+        let n = Vector::new_with(1.0, 0.0, 0.0);
+        let highlight = |metal: Conductor, rough: f32| {
+            let rough: ScalarTextureReference = Arc::new(ConstantTexture::new(rough));
+            let mat = Material::metal(Arc::new(ConstantTexture::new(metal.eta())),
+                                      Arc::new(ConstantTexture::new(metal.k())),
+                                      rough.clone(), rough, None);
+            let bsdf = mat.get_bsdf(dg.clone(), dg.clone()).unwrap();
+            bsdf.f(n.clone(), n.clone(), BxDFType::BSDF_ALL).to_rgb()
+        };
+
+        // Gold and copper reflect more red than blue, aluminum and silver
+        // about as much of each
+        let gold = highlight(Conductor::Gold, 0.1);
+        let copper = highlight(Conductor::Copper, 0.1);
+        let aluminum = highlight(Conductor::Aluminum, 0.1);
+        let silver = highlight(Conductor::Silver, 0.1);
+        assert!(gold[0] > 1.2 * gold[2], "Gold: {:?}", gold);
+        assert!(copper[0] > 1.2 * copper[2], "Copper: {:?}", copper);
+        assert!((aluminum[0] - aluminum[2]).abs() < 0.1 * aluminum[0], "Aluminum: {:?}", aluminum);
+        assert!((silver[0] - silver[2]).abs() < 0.1 * silver[0], "Silver: {:?}", silver);
+
+        // Smoother metals have sharper highlights
+        assert!(highlight(Conductor::Gold, 0.01)[0] > gold[0]);
+    }
+
+    #[test]
+    fn darker_hair_reflects_less() {
+        use crate::bsdf::BxDFType;
+
+        // A curve facing +x with the hair running along y
+        let dg = DifferentialGeometry::new_with(
+            Point::new(), Vector::new_with(0.0, 1.0, 0.0), Vector::new_with(0.0, 0.0, 1.0),
+            Normal::new(), Normal::new(), 0.5, 0.4, None);
+        let hair = |ce: f32| {
+            let mat = Material::hair(
+                HairAbsorption::Melanin(Arc::new(ConstantTexture::new(ce)),
+                                        Arc::new(ConstantTexture::new(0.0))),
+                Arc::new(ConstantTexture::new(1.55)), Arc::new(ConstantTexture::new(0.3)),
+                Arc::new(ConstantTexture::new(0.3)), Arc::new(ConstantTexture::new(2.0)));
+            mat.get_bsdf(dg.clone(), dg.clone()).unwrap()
+        };
+
+        // Light passing through the hair is absorbed more by darker hair,
+        // but it still scatters back off of the surface
+        let wo = Vector::new_with(1.0, 0.1, 0.0).normalize();
+        let back = Vector::new_with(-1.0, 0.1, 0.0).normalize();
+        let f = |bsdf: &BSDF, wi: &Vector| bsdf.f(wo.clone(), wi.clone(), BxDFType::BSDF_ALL).y();
+        assert!(f(&hair(0.1), &back) > 2.0 * f(&hair(4.0), &back));
+        assert!(f(&hair(4.0), &wo) > 0.0);
+    }
+
+    #[test]
+    fn substrates_can_have_brushed_highlights() {
+        use crate::bsdf::BxDFType;
+
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+        let ray = Ray::new_with(Point::new_with(2.0, 0.0, 0.0),
+                                Vector::new_with(-1.0, 0.0, 0.0), 0.0);
+        let dg = sphere.intersect(&ray).unwrap().dg;
+        let substrate = |ks: f32, u_rough: f32, v_rough: f32| {
+            let mat = Material::substrate(Arc::new(ConstantTexture::new(Spectrum::from(0.5))),
+                                          Arc::new(ConstantTexture::new(Spectrum::from(ks))),
+                                          Arc::new(ConstantTexture::new(u_rough)),
+                                          Arc::new(ConstantTexture::new(v_rough)), None);
+            mat.get_bsdf(dg.clone(), dg.clone()).unwrap()
+        };
+
+        // Looking straight down, tilting the light along u or v
+        let wo = Vector::new_with(1.0, 0.0, 0.0);
+        let along_u = (Vector::new_with(1.0, 0.0, 0.0) + &dg.dpdu.clone().normalize()).normalize();
+        let along_v = (Vector::new_with(1.0, 0.0, 0.0) + &dg.dpdv.clone().normalize()).normalize();
+        let f = |bsdf: &BSDF, wi: &Vector| bsdf.f(wo.clone(), wi.clone(), BxDFType::BSDF_ALL).y();
+
+        // The same roughness in both directions gives round highlights,
+        // while a rougher v smears them out along it
+        let round = substrate(0.5, 0.05, 0.05);
+        assert!((f(&round, &along_u) - f(&round, &along_v)).abs() < 1e-3 * f(&round, &along_u));
+        let brushed = substrate(0.5, 0.05, 0.5);
+        assert!(f(&brushed, &along_v) > 2.0 * f(&brushed, &along_u));
+
+        // Without a coating the base looks diffuse
+        let bare = substrate(0.0, 0.05, 0.05);
+        assert!((f(&bare, &along_u) - f(&bare, &along_v)).abs() < 1e-5);
+        assert!(f(&bare, &wo) > 0.0);
+    }
+
+    #[test]
+    fn metals_can_be_brushed() {
+        use crate::bsdf::BxDFType;
+        use crate::bsdf::fresnel::Conductor;
+
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+        let ray = Ray::new_with(Point::new_with(2.0, 0.0, 0.0),
+                                Vector::new_with(-1.0, 0.0, 0.0), 0.0);
+        let dg = sphere.intersect(&ray).unwrap().dg;
+        let metal = |u_rough: f32, v_rough: f32, distribution: DistributionKind| {
+            let mat = Material::metal(Arc::new(ConstantTexture::new(Conductor::Aluminum.eta())),
+                                      Arc::new(ConstantTexture::new(Conductor::Aluminum.k())),
+                                      Arc::new(ConstantTexture::new(u_rough)),
+                                      Arc::new(ConstantTexture::new(v_rough)), None)
+                .with_distribution(distribution);
+            mat.get_bsdf(dg.clone(), dg.clone()).unwrap()
+        };
+
+        let wo = Vector::new_with(1.0, 0.0, 0.0);
+        let along_u = (Vector::new_with(1.0, 0.0, 0.0) + &dg.dpdu.clone().normalize()).normalize();
+        let along_v = (Vector::new_with(1.0, 0.0, 0.0) + &dg.dpdv.clone().normalize()).normalize();
+        let f = |bsdf: &BSDF, wi: &Vector| bsdf.f(wo.clone(), wi.clone(), BxDFType::BSDF_ALL).y();
+
+        // Grooves running along u make the highlight spread out along v
+        for &distribution in [DistributionKind::Blinn, DistributionKind::GGX].iter() {
+            let round = metal(0.1, 0.1, distribution);
+            assert!((f(&round, &along_u) - f(&round, &along_v)).abs() < 1e-3 * f(&round, &along_u));
+            let brushed = metal(0.05, 0.5, distribution);
+            assert!(f(&brushed, &along_v) > 2.0 * f(&brushed, &along_u), "{:?}", distribution);
+        }
+    }
+
+    #[test]
+    fn normal_maps_tilt_the_shading_frame() {
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+        let ray = Ray::new_with(Point::new_with(2.0, 0.0, 0.0),
+                                Vector::new_with(-1.0, 0.0, 0.0), 0.0);
+        let dg = sphere.intersect(&ray).unwrap().dg;
+        let mapped = |rgb: [f32; 3]| {
+            let mat = Material::matte(Arc::new(ConstantTexture::new(Spectrum::from(0.5))),
+                                      Arc::new(ConstantTexture::new(0.0)), None)
+                .with_normal_map(Arc::new(ConstantTexture::new(Spectrum::from_rgb(rgb))));
+            mat.get_bsdf(dg.clone(), dg.clone()).unwrap().dg_shading
+        };
+
+        // A flat normal map leaves the shading frame alone
+        let flat = mapped([0.5, 0.5, 1.0]);
+        assert!((Vector::from(flat.nn.clone()) - Vector::from(dg.nn.clone())).length() < 1e-5);
+
+        // Reddish normals lean towards dpdu, and the frame stays orthogonal
+        let tilted = mapped([1.0, 0.5, 1.0]);
+        let n = Vector::from(tilted.nn.clone());
+        let dpdu = dg.dpdu.clone().normalize();
+        assert!((n.dot(&dpdu) - 0.5f32.sqrt()).abs() < 1e-4);
+        assert!(n.dot(&tilted.dpdu).abs() < 1e-4 && n.dot(&tilted.dpdv).abs() < 1e-4);
+        assert!((tilted.dpdu.length() - dg.dpdu.length()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn glass_can_disperse_light() {
+        use crate::bsdf::BSDFSample;
+        use crate::bsdf::BxDFType;
+
+        let bk7 = IndexOfRefraction::from_name("bk7").unwrap();
+        assert!((bk7.nominal_eta() - 1.5168).abs() < 1e-3);
+        let cauchy = IndexOfRefraction::Cauchy { a: 1.5, b: 0.0042 };
+        assert!((cauchy.eta(500.0) - 1.5168).abs() < 1e-4);
+        assert!(!IndexOfRefraction::Constant(1.5).is_dispersive());
+
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+        let ray = Ray::new_with(Point::new_with(2.0, 0.0, 0.0),
+                                Vector::new_with(-1.0, 0.0, 0.0), 0.0);
+        let dg = sphere.intersect(&ray).unwrap().dg;
+        let wo = Vector::new_with(0.6, 0.8, 0.0);
+        let refract = |index: IndexOfRefraction, lambda: Option<f32>| {
+            let white: ColorTextureReference = Arc::new(ConstantTexture::new(Spectrum::from(1.0)));
+            let mat = Material::glass(white.clone(), white, index, None);
+            let mut bsdf = mat.get_bsdf(dg.clone(), dg.clone()).unwrap();
+            if let Some(lambda) = lambda {
+                bsdf.set_wavelength(lambda);
+            }
+
+            let sample = BSDFSample { u_dir: (0.5, 0.5), u_component: 0.5 };
+            let flags = BxDFType::BSDF_TRANSMISSION | BxDFType::BSDF_SPECULAR;
+            (bsdf.is_dispersive(), bsdf.sample_f(&wo, sample, flags).0)
+        };
+
+        // Blue light bends further towards the normal than red light
+        let (dispersive, blue) = refract(bk7, Some(450.0));
+        let (_, red) = refract(bk7, Some(650.0));
+        assert!(dispersive);
+        assert!(blue.x < red.x && blue.x < 0.0);
+        assert!((blue.y + 0.8 / bk7.eta(450.0)).abs() < 1e-4);
+
+        // Without dispersion every wavelength refracts the same way
+        let (dispersive, plain) = refract(IndexOfRefraction::Constant(1.5), Some(450.0));
+        assert!(!dispersive);
+        assert!((plain.y + 0.8 / 1.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn disney_materials_blend_between_lobes() {
+        use crate::bsdf::BxDFType;
+        use crate::rng::RNG;
+
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+        let ray = Ray::new_with(Point::new_with(2.0, 0.0, 0.0),
+                                Vector::new_with(-1.0, 0.0, 0.0), 0.0);
+        let dg = sphere.intersect(&ray).unwrap().dg;
+        let scalar = |v: f32| -> ScalarTextureReference { Arc::new(ConstantTexture::new(v)) };
+        let red: ColorTextureReference =
+            Arc::new(ConstantTexture::new(Spectrum::from_rgb([0.8, 0.1, 0.1])));
+        let disney = |mat: DisneyMaterial| {
+            Material::disney(mat).get_bsdf(dg.clone(), dg.clone()).unwrap()
+        };
+        let wo = Vector::new_with(0.8, 0.6, 0.0);
+        let rho = |bsdf: &BSDF, flags: BxDFType| {
+            bsdf.rho_hd(&wo, &mut RNG::new(0), flags, 32).to_rgb()
+        };
+
+        // Dielectrics scatter their color diffusely, and reflect some white
+        // light specularly
+        let plastic = disney(DisneyMaterial::new(red.clone(), scalar(0.0), scalar(0.3), None));
+        let diffuse = rho(&plastic, BxDFType::BSDF_ALL_REFLECTION & !BxDFType::BSDF_GLOSSY);
+        let glossy = rho(&plastic, BxDFType::BSDF_REFLECTION | BxDFType::BSDF_GLOSSY);
+        assert!(diffuse[0] > 4.0 * diffuse[2], "Diffuse: {:?}", diffuse);
+        assert!((glossy[0] - glossy[2]).abs() < 0.1 * glossy[0], "Glossy: {:?}", glossy);
+
+        // Metals have no diffuse lobe and reflect their color
+        let metal = disney(DisneyMaterial::new(red.clone(), scalar(1.0), scalar(0.3), None));
+        let reflected = rho(&metal, BxDFType::BSDF_ALL);
+        assert!(reflected[0] > 4.0 * reflected[2], "Metal: {:?}", reflected);
+        assert!(rho(&metal, BxDFType::BSDF_DIFFUSE | BxDFType::BSDF_REFLECTION)[0] == 0.0);
+
+        // Transmissive dielectrics refract instead of scattering diffusely
+        let glass = disney(DisneyMaterial::new(red.clone(), scalar(0.0), scalar(0.1), None)
+                           .with_transmission(scalar(1.0)));
+        assert!(glass.eta > 1.0);
+        let transmitted = rho(&glass, BxDFType::BSDF_ALL_TRANSMISSION);
+        assert!(transmitted[0] > 0.5, "Transmitted: {:?}", transmitted);
+        assert!(rho(&glass, BxDFType::BSDF_DIFFUSE | BxDFType::BSDF_REFLECTION)[0] == 0.0);
+
+        // Sheen and clear coats only add light
+        let coated = disney(DisneyMaterial::new(red, scalar(0.0), scalar(0.3), None)
+                            .with_sheen(scalar(1.0), scalar(0.0))
+                            .with_clearcoat(scalar(1.0), scalar(1.0)));
+        let more = rho(&coated, BxDFType::BSDF_ALL);
+        let plain = rho(&plastic, BxDFType::BSDF_ALL);
+        assert!(more.iter().zip(plain.iter()).all(|(m, p)| m > p));
+    }
+
+    #[test]
+    fn it_bumps_smoothly_across_the_seam() {
+        let sphere = Shape::sphere(Transform::new(), Transform::new(), false,
+                                   1.0, -1.0, 1.0, 360.0);
+
+        // Continuous around the sphere, but nothing past u = 1
+        let tex = ExprTexture::parse("0.1 * cos(6.2831853 * u) * (1 - floor(u))",
+                                     &HashMap::new(), &HashMap::new()).unwrap();
+        let before = bumped_sphere_normal(&sphere, &tex, -0.01);
+        let after = bumped_sphere_normal(&sphere, &tex, 0.01);
+        assert!((Vector::from(before) - Vector::from(after)).length() < 0.03);
     }
 }
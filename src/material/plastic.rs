@@ -1,34 +1,44 @@
 use std::sync::Arc;
 
-use bsdf::BSDF;
-use bsdf::fresnel::Fresnel;
-use bsdf::lambertian::Lambertian;
-use bsdf::microfacet::Microfacet;
-use bsdf::microfacet::MicrofacetDistribution;
-use diff_geom::DifferentialGeometry;
-use spectrum::Spectrum;
-use texture::{Texture, ScalarTextureReference, ColorTextureReference};
+use crate::bsdf::BSDF;
+use crate::bsdf::TwoSided;
+use crate::bsdf::fresnel::Fresnel;
+use crate::bsdf::lambertian::Lambertian;
+use crate::bsdf::microfacet::DistributionKind;
+use crate::bsdf::microfacet::Microfacet;
+use crate::bsdf::microfacet::MicrofacetDistribution;
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::{Texture, ScalarTextureReference, ColorTextureReference};
 
-use material::bump;
+use crate::material::bump;
 
 #[derive(Clone, Debug)]
 pub struct PlasticMaterial {
     k_d: ColorTextureReference,
     k_s: ColorTextureReference,
-    roughness: ScalarTextureReference,
+    u_roughness: ScalarTextureReference,
+    v_roughness: ScalarTextureReference,
+    distribution: DistributionKind,
     bump_map: Option<ScalarTextureReference>
 }
 
 impl PlasticMaterial {
     pub fn new(kd: ColorTextureReference,
                ks: ColorTextureReference,
-               rough: ScalarTextureReference,
+               u_rough: ScalarTextureReference,
+               v_rough: ScalarTextureReference,
                bm: Option<ScalarTextureReference>) -> PlasticMaterial {
         PlasticMaterial {
-            k_d: kd, k_s: ks, roughness: rough, bump_map: bm
+            k_d: kd, k_s: ks, u_roughness: u_rough, v_roughness: v_rough,
+            distribution: DistributionKind::Blinn, bump_map: bm
         }
     }
 
+    pub fn with_distribution(self, distribution: DistributionKind) -> PlasticMaterial {
+        PlasticMaterial { distribution, ..self }
+    }
+
     pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
                     dg_shading: DifferentialGeometry) -> Option<BSDF> {
         // Allocate bsdf possibly doing bump mapping with bump map
@@ -45,11 +55,14 @@ impl PlasticMaterial {
         let fresnel = Fresnel::dielectric(1.5, 1.0);
 
         let ks = self.k_s.evaluate(&dgs).clamp(0.0, ::std::f32::MAX);
-        let rough = self.roughness.evaluate(&dgs);
-        let spec = Microfacet::new(ks, fresnel, MicrofacetDistribution::blinn(1.0 / rough));
+        let u_rough = self.u_roughness.evaluate(&dgs);
+        let v_rough = self.v_roughness.evaluate(&dgs);
+        let distribution =
+            MicrofacetDistribution::from_roughness(self.distribution, u_rough, v_rough);
+        let spec = Microfacet::new(ks, fresnel, distribution);
 
         bsdf.add_bxdf(diff);
-        bsdf.add_bxdf(spec);
+        bsdf.add_bxdf(TwoSided::new(spec));
 
         Some(bsdf)
     }
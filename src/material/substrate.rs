@@ -0,0 +1,60 @@
+use crate::bsdf::BSDF;
+use crate::bsdf::microfacet::DistributionKind;
+use crate::bsdf::microfacet::FresnelBlend;
+use crate::bsdf::microfacet::MicrofacetDistribution;
+use crate::diff_geom::DifferentialGeometry;
+use crate::texture::{Texture, ScalarTextureReference, ColorTextureReference};
+
+use crate::material::bump;
+
+// A diffuse base under a glossy coating, like varnished wood. Light is
+// reflected more by the coating at grazing angles, so less of it reaches
+// the base.
+#[derive(Clone, Debug)]
+pub struct SubstrateMaterial {
+    k_d: ColorTextureReference,
+    k_s: ColorTextureReference,
+    u_roughness: ScalarTextureReference,
+    v_roughness: ScalarTextureReference,
+    distribution: DistributionKind,
+    bump_map: Option<ScalarTextureReference>
+}
+
+impl SubstrateMaterial {
+    pub fn new(kd: ColorTextureReference,
+               ks: ColorTextureReference,
+               u_rough: ScalarTextureReference,
+               v_rough: ScalarTextureReference,
+               bm: Option<ScalarTextureReference>) -> SubstrateMaterial {
+        SubstrateMaterial {
+            k_d: kd, k_s: ks, u_roughness: u_rough, v_roughness: v_rough,
+            distribution: DistributionKind::Blinn, bump_map: bm
+        }
+    }
+
+    pub fn with_distribution(self, distribution: DistributionKind) -> SubstrateMaterial {
+        SubstrateMaterial { distribution, ..self }
+    }
+
+    pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
+                    dg_shading: DifferentialGeometry) -> Option<BSDF> {
+        // Allocate bsdf possibly doing bump mapping with bump map
+        let dgs = if let Some(ref tex) = self.bump_map {
+            bump(tex, &dg_geom, &dg_shading)
+        } else {
+            dg_shading
+        };
+
+        let mut bsdf = BSDF::new(dgs.clone(), dg_geom.nn);
+
+        let kd = self.k_d.evaluate(&dgs).clamp(0.0, f32::MAX);
+        let ks = self.k_s.evaluate(&dgs).clamp(0.0, f32::MAX);
+        let u_rough = self.u_roughness.evaluate(&dgs);
+        let v_rough = self.v_roughness.evaluate(&dgs);
+        let distribution =
+            MicrofacetDistribution::from_roughness(self.distribution, u_rough, v_rough);
+        bsdf.add_bxdf(FresnelBlend::new(kd, ks, distribution));
+
+        Some(bsdf)
+    }
+}
@@ -1,14 +1,14 @@
 use std::sync::Arc;
 
-use bsdf::BSDF;
-use bsdf::bssrdf::BSSRDF;
-use bsdf::fresnel::Fresnel;
-use bsdf::specular::SpecularReflection;
-use diff_geom::DifferentialGeometry;
-use spectrum::Spectrum;
-use texture::{Texture, ScalarTextureReference, ColorTextureReference};
+use crate::bsdf::BSDF;
+use crate::bsdf::bssrdf::{BSSRDF, BSSRDFTable};
+use crate::bsdf::fresnel::Fresnel;
+use crate::bsdf::specular::{SpecularReflection, SpecularTransmission};
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::{Texture, ScalarTextureReference, ColorTextureReference};
 
-use material::bump;
+use crate::material::bump;
 
 #[derive(Clone, Debug)]
 pub struct SubsurfaceMaterial {
@@ -16,7 +16,8 @@ pub struct SubsurfaceMaterial {
     k_r: ColorTextureReference,
     sigma_a: ColorTextureReference,
     sigma_prime_s: ColorTextureReference,
-    eta: ScalarTextureReference,
+    eta: f32,
+    table: Arc<BSSRDFTable>,
     bump_map: Option<ScalarTextureReference>
 }
 
@@ -24,17 +25,17 @@ impl SubsurfaceMaterial {
     pub fn new(scale: f32, k_r: ColorTextureReference,
                sigma_a: ColorTextureReference,
                sigma_prime_s: ColorTextureReference,
-               eta: ScalarTextureReference,
+               eta: f32,
                bump_map: Option<ScalarTextureReference>) -> SubsurfaceMaterial {
-        SubsurfaceMaterial { scale, k_r, sigma_a, sigma_prime_s, eta, bump_map }
+        // The scattering coefficient is already reduced, so the profiles
+        // are those of an isotropic medium
+        let table = Arc::new(BSSRDFTable::beam_diffusion(0.0, eta));
+        SubsurfaceMaterial { scale, k_r, sigma_a, sigma_prime_s, eta, table, bump_map }
     }
 
     pub fn get_bsdf(&self, dg_geom: DifferentialGeometry,
                     dg_shading: DifferentialGeometry) -> Option<BSDF> {
         let r = self.k_r.evaluate(&dg_geom).clamp(0.0, 1.0);
-        if r.is_black() {
-            return None;
-        }
 
         // Allocate bsdf possibly doing bump mapping with bump map
         let dgs = if let Some(ref tex) = self.bump_map {
@@ -43,9 +44,14 @@ impl SubsurfaceMaterial {
             dg_shading
         };
 
-        let fresnel = Fresnel::dielectric(1.0, self.eta.evaluate(&dg_geom));
-        let mut bsdf = BSDF::new(dgs.clone(), dg_geom.nn);
-        bsdf.add_bxdf(SpecularReflection::new(r, fresnel));
+        // Light that isn't reflected refracts into the surface, where the
+        // integrator hands it over to the BSSRDF
+        let fresnel = Fresnel::dielectric(1.0, self.eta);
+        let mut bsdf = BSDF::new_with_eta(dgs.clone(), dg_geom.nn, self.eta);
+        if !r.is_black() {
+            bsdf.add_bxdf(SpecularReflection::new(r, fresnel));
+        }
+        bsdf.add_bxdf(SpecularTransmission::new(Spectrum::from(1.0), 1.0, self.eta));
         Some(bsdf)
     }
 
@@ -53,7 +59,6 @@ impl SubsurfaceMaterial {
                       dgs: DifferentialGeometry) -> BSSRDF {
         BSSRDF::new(self.scale * self.sigma_a.evaluate(&dgs),
                     self.scale * self.sigma_prime_s.evaluate(&dgs),
-                    self.eta.evaluate(&dgs))
+                    self.eta, self.table.clone())
     }
-
 }
@@ -1,7 +1,7 @@
-extern crate primal;
-
-use rng::RNG;
+use crate::geometry::vector::Vector;
+use crate::rng::RNG;
 
+use std::f32::consts::PI;
 use std::ops::Deref;
 use std::ops::DerefMut;
 
@@ -127,10 +127,183 @@ pub fn stratified_sample_2d(samples: &mut [f32], nx: usize, ny: usize,
     }
 }
 
+// Maps a point in the unit square to the unit disk, preserving relative
+// areas so that stratified samples stay well distributed.
+pub fn concentric_sample_disk(u1: f32, u2: f32) -> (f32, f32) {
+    // Map uniform random numbers to [-1, 1]^2
+    let sx = 2.0 * u1 - 1.0;
+    let sy = 2.0 * u2 - 1.0;
+
+    // Handle degeneracy at the origin
+    if sx == 0.0 && sy == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    // Map square to (r, theta)
+    let (r, theta) =
+        if sx >= -sy {
+            if sx > sy {
+                // Handle first region of disk
+                (sx, if sy > 0.0 { sy / sx } else { 8.0 + sy / sx })
+            } else {
+                // Handle second region of disk
+                (sy, 2.0 - sx / sy)
+            }
+        } else if sx <= sy {
+            // Handle third region of disk
+            (-sx, 4.0 + sy / sx)
+        } else {
+            // Handle fourth region of disk
+            (-sy, 6.0 - sx / sy)
+        };
+
+    let theta = theta * PI / 4.0;
+    (r * theta.cos(), r * theta.sin())
+}
+
+pub fn cosine_sample_hemisphere(u1: f32, u2: f32) -> Vector {
+    let (x, y) = concentric_sample_disk(u1, u2);
+    let z = 0f32.max(1.0 - x * x - y * y).sqrt();
+    Vector::new_with(x, y, z)
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f32, _phi: f32) -> f32 {
+    cos_theta / PI
+}
+
+pub fn uniform_sample_hemisphere(u1: f32, u2: f32) -> Vector {
+    let z = u1;
+    let r = 0f32.max(1.0 - z * z).sqrt();
+    let phi = 2.0 * PI * u2;
+    Vector::new_with(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_hemisphere_pdf() -> f32 {
+    1.0 / (2.0 * PI)
+}
+
+pub fn uniform_sample_sphere(u1: f32, u2: f32) -> Vector {
+    let z = 1.0 - 2.0 * u1;
+    let r = 0f32.max(1.0 - z * z).sqrt();
+    let phi = 2.0 * PI * u2;
+    Vector::new_with(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_sphere_pdf() -> f32 {
+    1.0 / (4.0 * PI)
+}
+
+// Returns the barycentric coordinates of the first two vertices for a point
+// chosen uniformly over the area of a triangle
+pub fn uniform_sample_triangle(u1: f32, u2: f32) -> (f32, f32) {
+    let su1 = u1.sqrt();
+    (1.0 - su1, u2 * su1)
+}
+
+// Samples a direction uniformly within the cone of directions around +z
+// whose angle to it has cosine at least cos_theta_max
+pub fn uniform_sample_cone(u1: f32, u2: f32, cos_theta_max: f32) -> Vector {
+    let cos_theta = (1.0 - u1) + u1 * cos_theta_max;
+    let sin_theta = 0f32.max(1.0 - cos_theta * cos_theta).sqrt();
+    let phi = 2.0 * PI * u2;
+    Vector::new_with(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta)
+}
+
+pub fn uniform_cone_pdf(cos_theta_max: f32) -> f32 {
+    1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+}
+
+pub fn balance_heuristic(nf: usize, f_pdf: f32, ng: usize, g_pdf: f32) -> f32 {
+    let f = (nf as f32) * f_pdf;
+    let g = (ng as f32) * g_pdf;
+    f / (f + g)
+}
+
+pub fn power_heuristic(nf: usize, f_pdf: f32, ng: usize, g_pdf: f32) -> f32 {
+    let f = (nf as f32) * f_pdf;
+    let g = (ng as f32) * g_pdf;
+    (f * f) / (f * f + g * g)
+}
+
+// A piecewise constant distribution over [0, 1) built from n function
+// values, used to pick samples in proportion to e.g. the area of each
+// triangle in a mesh.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    func_int: f32
+}
+
+impl Distribution1D {
+    pub fn new(f: &[f32]) -> Distribution1D {
+        assert!(!f.is_empty());
+        let n = f.len();
+
+        // Compute integral of step function at x_i
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..(n + 1) {
+            cdf[i] = cdf[i - 1] + f[i - 1] / (n as f32);
+        }
+
+        // Transform step function integral into CDF
+        let func_int = cdf[n];
+        if func_int == 0.0 {
+            for (i, c) in cdf.iter_mut().enumerate().skip(1) {
+                *c = (i as f32) / (n as f32);
+            }
+        } else {
+            for c in cdf.iter_mut().skip(1) {
+                *c /= func_int;
+            }
+        }
+
+        Distribution1D { func: f.to_vec(), cdf, func_int }
+    }
+
+    pub fn count(&self) -> usize { self.func.len() }
+
+    // The integral of the function over [0, 1)
+    pub fn func_int(&self) -> f32 { self.func_int }
+
+    // Returns the index of the segment that u falls into in the CDF
+    fn find_segment(&self, u: f32) -> usize {
+        let idx = self.cdf.partition_point(|&c| c <= u);
+        idx.saturating_sub(1).min(self.count() - 1)
+    }
+
+    // Returns a sample in [0, 1), its pdf and the segment it came from
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        let offset = self.find_segment(u);
+
+        // Compute offset along CDF segment
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        let du = if width > 0.0 { (u - self.cdf[offset]) / width } else { 0.0 };
+
+        let pdf = if self.func_int > 0.0 { self.func[offset] / self.func_int } else { 0.0 };
+        (((offset as f32) + du) / (self.count() as f32), pdf, offset)
+    }
+
+    // Returns one of the segments, picked in proportion to its value, along
+    // with the probability of picking it
+    pub fn sample_discrete(&self, u: f32) -> (usize, f32) {
+        let offset = self.find_segment(u);
+        (offset, self.discrete_pdf(offset))
+    }
+
+    pub fn discrete_pdf(&self, offset: usize) -> f32 {
+        if self.func_int > 0.0 {
+            self.func[offset] / (self.func_int * (self.count() as f32))
+        } else {
+            1.0 / (self.count() as f32)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rng::RNG;
+    use crate::rng::RNG;
 
     #[test]
     fn it_can_compute_radical_inverses() {
@@ -310,4 +483,97 @@ mod tests {
     #[test]
     fn it_can_generate_latin_hypercube() {
     }
+
+    #[test]
+    fn it_can_weight_samples_with_the_power_heuristic() {
+        assert_eq!(power_heuristic(1, 1.0, 1, 1.0), 0.5);
+        assert_eq!(power_heuristic(1, 2.0, 1, 0.0), 1.0);
+        assert_eq!(power_heuristic(1, 0.0, 1, 2.0), 0.0);
+        assert!((power_heuristic(1, 3.0, 1, 1.0) - 0.9).abs() < 1e-6);
+        assert!((balance_heuristic(1, 3.0, 1, 1.0) - 0.75).abs() < 1e-6);
+
+        // Weights from both strategies should sum to one
+        let w1 = power_heuristic(2, 0.3, 3, 0.7);
+        let w2 = power_heuristic(3, 0.7, 2, 0.3);
+        assert!((w1 + w2 - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn it_can_cosine_sample_the_hemisphere() {
+        let mut rng = RNG::new(0);
+        for _ in 0..1000 {
+            let (u1, u2) = (rng.random_float(), rng.random_float());
+            let (x, y) = concentric_sample_disk(u1, u2);
+            assert!(x * x + y * y <= 1.0 + 1e-6);
+
+            let w = cosine_sample_hemisphere(u1, u2);
+            assert!(w.z >= 0.0);
+            assert!((w.length_squared() - 1.0).abs() < 1e-4);
+        }
+
+        assert_eq!(concentric_sample_disk(0.5, 0.5), (0.0, 0.0));
+        let (x, y) = concentric_sample_disk(1.0, 0.5);
+        assert!((x - 1.0).abs() < 1e-6 && y.abs() < 1e-6);
+    }
+
+    #[test]
+    fn it_can_uniformly_sample_the_sphere() {
+        let mut rng = RNG::new(0);
+        let mut mean = Vector::new();
+        for _ in 0..4096 {
+            let w = uniform_sample_sphere(rng.random_float(), rng.random_float());
+            assert!((w.length_squared() - 1.0).abs() < 1e-4);
+            mean = mean + w / 4096.0;
+        }
+
+        // Uniform directions average out to nothing
+        assert!(mean.length() < 0.05);
+        assert!((uniform_sphere_pdf() * 4.0 * PI - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn it_can_uniformly_sample_cones() {
+        let mut rng = RNG::new(0);
+        let cos_max = 30f32.to_radians().cos();
+        let mut mean = Vector::new();
+        for _ in 0..4096 {
+            let w = uniform_sample_cone(rng.random_float(), rng.random_float(), cos_max);
+            assert!((w.length_squared() - 1.0).abs() < 1e-4);
+            assert!(w.z >= cos_max - 1e-6);
+            mean = mean + w / 4096.0;
+        }
+
+        // The mean is on the axis, at the cone's average cosine
+        assert!(mean.x.abs() < 0.01 && mean.y.abs() < 0.01);
+        assert!((mean.z - 0.5 * (1.0 + cos_max)).abs() < 0.01);
+        assert!((uniform_cone_pdf(-1.0) - uniform_sphere_pdf()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn it_samples_piecewise_constant_distributions() {
+        let d = Distribution1D::new(&[1.0, 3.0, 0.0, 4.0]);
+        assert_eq!(d.count(), 4);
+        assert!((d.func_int() - 2.0).abs() < 1e-6);
+
+        assert_eq!(d.sample_discrete(0.0), (0, 0.125));
+        assert_eq!(d.sample_discrete(0.2).0, 1);
+        assert_eq!(d.sample_discrete(0.6).0, 3);
+        assert_eq!(d.sample_discrete(0.99).0, 3);
+        assert_eq!(d.discrete_pdf(2), 0.0);
+
+        let (x, pdf, offset) = d.sample_continuous(0.25);
+        assert_eq!(offset, 1);
+        assert!((x - 0.3333333).abs() < 1e-5);
+        assert!((pdf - 1.5).abs() < 1e-6);
+
+        // Segments with no weight are never sampled
+        let mut rng = RNG::new(0);
+        for _ in 0..1000 {
+            assert!(d.sample_discrete(rng.random_float()).0 != 2);
+        }
+
+        // ... unless they all have no weight
+        let flat = Distribution1D::new(&[0.0, 0.0]);
+        assert_eq!(flat.sample_discrete(0.75), (1, 0.5));
+    }
 }
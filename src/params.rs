@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use geometry::normal::Normal;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use spectrum::Spectrum;
-use texture::ConstantTexture;
-use texture::Texture;
+use crate::bsdf::fresnel::Conductor;
+use crate::geometry::normal::Normal;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::spectrum::Spectrum;
+use crate::texture::ConstantTexture;
+use crate::texture::Texture;
 
 #[derive(Clone, Debug, PartialEq)]
 enum ParamTy {
@@ -34,6 +35,23 @@ impl ParamSet {
         }
     }
 
+    // Removes the parameter with the given name, returning whether or not
+    // it was present
+    pub fn erase(&mut self, name: &str) -> bool {
+        let &mut ParamSet(ref mut map) = self;
+        map.remove(name).is_some()
+    }
+
+    // Renames a parameter, keeping its type and values. Returns whether or
+    // not it was present.
+    pub fn rename(&mut self, from: &str, to: &str) -> bool {
+        let &mut ParamSet(ref mut map) = self;
+        match map.remove(from) {
+            Some(data) => { self.add_param(to, data); true },
+            None => false
+        }
+    }
+
     pub fn add_float(&mut self, name: &str, data: Vec<f32>) {
         self.add_param(name, ParamTy::Float(data))
     }
@@ -220,12 +238,17 @@ impl ParamSet {
         })
     }
 
+    // Spectra given as RGB or XYZ take three values each
     pub fn add_rgb_spectrum(&mut self, name: &str, data: Vec<f32>) {
-        unimplemented!()
+        assert_eq!(data.len() % 3, 0);
+        let s = data.chunks(3).map(|c| Spectrum::from_rgb([c[0], c[1], c[2]])).collect();
+        self.add_param(name, ParamTy::Spec(s))
     }
 
     pub fn add_xyz_spectrum(&mut self, name: &str, data: Vec<f32>) {
-        unimplemented!()
+        assert_eq!(data.len() % 3, 0);
+        let s = data.chunks(3).map(|c| Spectrum::from_xyz([c[0], c[1], c[2]])).collect();
+        self.add_param(name, ParamTy::Spec(s))
     }
 
     pub fn add_blackbody_spectrum(&mut self, name: &str, data: Vec<f32>) {
@@ -250,16 +273,96 @@ impl ParamSet {
         })
     }
 
+    // Reads a spectrum from each SPD file, or uses the built in spectrum of
+    // a metal if it's named like "metal-Cu-eta". Files that can't be read
+    // are skipped with a warning.
     pub fn add_sampled_spectrum_files(&mut self, name: &str,
                                       data: Vec<String>) {
-        unimplemented!()
+        let s = data.iter().filter_map(|filename| {
+            if let Some(s) = Conductor::named_spectrum(filename) {
+                return Some(s);
+            }
+
+            match Spectrum::read_spd(filename) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    println!("WARNING: Unable to read SPD file \"{}\": {}", filename, e);
+                    None
+                }
+            }
+        }).collect();
+        self.add_param(name, ParamTy::Spec(s))
     }
 
+    // A single spectrum given as pairs of wavelengths and values
     pub fn add_sampled_spectrum(&mut self, name: &str, data: Vec<f32>) {
-        unimplemented!()
+        assert_eq!(data.len() % 2, 0);
+        let samples: Vec<(f32, f32)> = data.chunks(2).map(|c| (c[0], c[1])).collect();
+        let s = Spectrum::from_samples(&samples).into_rgb_spectrum();
+        self.add_param(name, ParamTy::Spec(vec![s]))
     }
 }
 
+// The values of a parameter as they're read from a scene file, before
+// they're checked against the parameter's declared type
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParamValues {
+    Numbers(Vec<f32>),
+    Strings(Vec<String>),
+    Bools(Vec<bool>)
+}
+
+// Adds a parameter declared as "type name" to the set. Newer versions of
+// pbrt spell some of the types differently, such as "rgb" for "color" and
+// "point3" for "point", so both spellings are accepted. Returns false with
+// a warning if the parameter couldn't be added.
+pub fn add_param(params: &mut ParamSet, decl: &str, values: ParamValues) -> bool {
+    let words: Vec<&str> = decl.split_whitespace().collect();
+    if words.len() != 2 {
+        println!("WARNING: Bad parameter declaration \"{}\". Ignoring it.", decl);
+        return false;
+    }
+
+    let (ty, name) = (words[0], words[1]);
+    match (ty, values) {
+        ("float", ParamValues::Numbers(v)) => params.add_float(name, v),
+        ("integer", ParamValues::Numbers(v)) => {
+            if v.iter().any(|x| x.fract() != 0.0) {
+                println!("WARNING: Integer parameter \"{}\" has non-integral values. \
+                          Truncating them.", name);
+            }
+            params.add_int(name, v.iter().map(|&x| x as i32).collect())
+        },
+        ("bool", ParamValues::Bools(v)) => params.add_bool(name, v),
+        ("string", ParamValues::Strings(v)) => params.add_str(name, v),
+        ("texture", ParamValues::Strings(v)) => params.add_tex(name, v),
+        ("point" | "point3", ParamValues::Numbers(v)) if v.len() % 3 == 0 =>
+            params.add_point(name, v.chunks(3).map(|c| Point::new_with(c[0], c[1], c[2])).collect()),
+        ("vector" | "vector3", ParamValues::Numbers(v)) if v.len() % 3 == 0 =>
+            params.add_vec(name, v.chunks(3).map(|c| Vector::new_with(c[0], c[1], c[2])).collect()),
+        ("normal" | "normal3", ParamValues::Numbers(v)) if v.len() % 3 == 0 =>
+            params.add_normal(name, v.chunks(3).map(|c| Normal::new_with(c[0], c[1], c[2])).collect()),
+        ("color" | "rgb", ParamValues::Numbers(v)) if v.len() % 3 == 0 =>
+            params.add_rgb_spectrum(name, v),
+        ("xyz", ParamValues::Numbers(v)) if v.len() % 3 == 0 => params.add_xyz_spectrum(name, v),
+        ("spectrum", ParamValues::Numbers(v)) if v.len() % 2 == 0 =>
+            params.add_sampled_spectrum(name, v),
+        ("spectrum", ParamValues::Strings(v)) => params.add_sampled_spectrum_files(name, v),
+        ("point2" | "vector2", _) => {
+            println!("WARNING: Two dimensional {} parameters are unsupported. Ignoring \"{}\".",
+                     ty, name);
+            return false;
+        },
+        _ => {
+            println!("WARNING: Unsupported type or values for {} parameter \"{}\". Ignoring it.",
+                     ty, name);
+            return false;
+        }
+    }
+
+    true
+}
+
 pub struct TextureParams<'a> {
     float_textures: Arc<HashMap<String, Arc<dyn Texture<f32>>>>,
     spectrum_textures: Arc<HashMap<String, Arc<dyn Texture<Spectrum>>>>,
@@ -323,6 +426,12 @@ impl<'a> TextureParams<'a> {
             name, self.material_params.find_one_float(name, def))
     }
 
+    // Every value of a float parameter, e.g. for coefficients that come in
+    // groups
+    pub fn find_floats(&self, name: &str) -> Option<&'a [f32]> {
+        self.geom_params.find_float(name).or(self.material_params.find_float(name))
+    }
+
     pub fn find_bool(&self, name: &str, def: bool) -> bool {
         self.geom_params.find_one_bool(
             name, self.material_params.find_one_bool(name, def))
@@ -387,4 +496,33 @@ mod tests {
     fn it_can_lookup_multiple_params() {
         unimplemented!()
     }
+
+    #[test]
+    fn it_can_add_rgb_spectra() {
+        let mut ps = ParamSet::new();
+        ps.add_rgb_spectrum("Kd", vec![0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+        assert_eq!(ps.find_one_spectrum("Kd", Spectrum::from(0.0)),
+                   Spectrum::from_rgb([0.25, 0.5, 0.75]));
+        assert_eq!(ps.find_spectrum("Kd").unwrap().len(), 2);
+        assert_eq!(ps.find_one_spectrum("Ks", Spectrum::from(0.5)), Spectrum::from(0.5));
+    }
+
+    #[test]
+    fn it_can_add_sampled_spectra() {
+        let mut ps = ParamSet::new();
+        ps.add_sampled_spectrum("Kd", vec![400.0, 0.5, 700.0, 0.5]);
+        let kd = ps.find_one_spectrum("Kd", Spectrum::from(0.0));
+        assert_eq!(kd, Spectrum::parse_spd("400 0.5 700 0.5").unwrap());
+
+        ps.add_sampled_spectrum_files("eta", vec![String::from("metal-Au-eta"),
+                                                  String::from("no_such_file.spd")]);
+        assert_eq!(ps.find_spectrum("eta").unwrap(), &[Conductor::Gold.eta()]);
+    }
+
+    #[test]
+    fn it_truncates_non_integral_integers() {
+        let mut ps = ParamSet::new();
+        assert!(add_param(&mut ps, "integer maxdepth", ParamValues::Numbers(vec![2.5, -1.5])));
+        assert_eq!(ps.find_int("maxdepth").unwrap(), &[2, -1]);
+    }
 }
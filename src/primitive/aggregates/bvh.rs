@@ -1,15 +1,15 @@
-use bbox::BBox;
-use bbox::HasBounds;
-use bbox::Union;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use intersection::Intersection;
-use primitive::Primitive;
-use primitive::FullyRefinable;
-use ray::Ray;
-
-use utils::partition_by;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bbox::Union;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::primitive::Primitive;
+use crate::primitive::FullyRefinable;
+use crate::ray::Ray;
+
+use crate::utils::partition_by;
 
 #[derive(Clone, Debug, PartialEq, Copy)]
 enum SplitMethod {
@@ -83,6 +83,25 @@ impl BVHPrimitiveInfo {
     fn centroid<'a>(&'a self) -> &'a Point { &self.centroid }
 }
 
+// Leaf primitives are stored in order of descending surface area. A ray
+// is more likely to hit a larger primitive, so shadow rays that only need
+// to find any hit in the leaf tend to terminate sooner.
+fn make_leaf(bounds: BBox, prims: Vec<BVHPrimitiveInfo>) -> (BVHNode, Vec<Primitive>) {
+    let mut sorted = prims;
+    sorted.sort_by(|a, b| {
+        b.bounds.surface_area().partial_cmp(&a.bounds.surface_area())
+            .unwrap_or(::std::cmp::Ordering::Equal)
+    });
+
+    let node = BVHNode::Leaf {
+        bounds,
+        first_prim_offset: 0,
+        num_primitives: sorted.len()
+    };
+
+    (node, sorted.into_iter().map(|BVHPrimitiveInfo { primitive, .. }| primitive).collect())
+}
+
 fn split_middle(centroid_bounds: BBox, dim: usize, prims: Vec<BVHPrimitiveInfo>)
                 -> (Vec<BVHPrimitiveInfo>, Vec<BVHPrimitiveInfo>) {
     let p_mid = 0.5 * (centroid_bounds.p_min[dim] + centroid_bounds.p_max[dim]);
@@ -169,19 +188,7 @@ fn split_surface_area_heuristic(total_bounds: BBox, centroid_bounds: BBox, mp: u
     if mp < num_prims || (min_cost as usize) < num_prims {
         Ok(prims.into_iter().partition(|p| { bucket_for_prim(p) <= min_cost_split }))
     } else {
-        // Make leaf node
-        let node = BVHNode::Leaf {
-            bounds: total_bounds,
-            first_prim_offset: 0,
-            num_primitives: num_prims
-        };
-
-        let new_prims = prims
-            .into_iter()
-            .map(|BVHPrimitiveInfo { primitive, .. }| primitive)
-            .collect();
-
-        Err((node, new_prims))
+        Err(make_leaf(total_bounds, prims))
     }
 }
 
@@ -195,14 +202,7 @@ fn recursive_build(prims: Vec<BVHPrimitiveInfo>,
     let num_prims = prims.len();
 
     if num_prims == 1 {
-        // Make leaf node
-        let node = BVHNode::Leaf {
-            bounds: bbox,
-            first_prim_offset: 0,
-            num_primitives: num_prims
-        };
-
-        (node, prims.into_iter().map(|BVHPrimitiveInfo { primitive, .. }| primitive).collect())
+        make_leaf(bbox, prims)
     } else {
         let centroid_bounds = prims.iter().fold(BBox::new(), |b, p| {
             b.unioned_with_ref(p.centroid())
@@ -210,14 +210,7 @@ fn recursive_build(prims: Vec<BVHPrimitiveInfo>,
         let dim = centroid_bounds.max_extent();
 
         if centroid_bounds.p_min[dim] == centroid_bounds.p_max[dim] {
-            // Make leaf node
-            let node = BVHNode::Leaf {
-                bounds: bbox,
-                first_prim_offset: 0,
-                num_primitives: num_prims
-            };
-
-            (node, prims.into_iter().map(|BVHPrimitiveInfo { primitive, .. }| primitive).collect())
+            make_leaf(bbox, prims)
         } else {
             // Partition primitives based on split method
             let (p1, p2) = {
@@ -332,7 +325,7 @@ pub struct BVHAccelerator {
 }
 
 impl BVHAccelerator {
-    pub fn new(p: Vec<Primitive>, mp: usize, sm: &'static str) -> BVHAccelerator {
+    pub fn new(p: Vec<Primitive>, mp: usize, sm: &str) -> BVHAccelerator {
         let prims = p.into_iter().fold(Vec::new(), |mut ps, prim| {
             ps.append(&mut prim.fully_refine());
             ps
@@ -373,7 +366,6 @@ impl HasBounds for BVHAccelerator {
 }
 
 impl Intersectable for BVHAccelerator {
-    // !SPEED! A custom intersect_p algorithm would be a lot faster
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         if self.nodes.len() == 0 { return None; }
 
@@ -419,21 +411,58 @@ impl Intersectable for BVHAccelerator {
 
         isect
     }
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        if self.nodes.is_empty() { return false; }
+
+        let inv_dir = Vector::new_with(1f32 / ray.d.x, 1f32 / ray.d.y, 1f32 / ray.d.z);
+        let dir_is_neg = [ inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0 ];
+
+        let mut todo = Vec::with_capacity(64);
+        todo.push(0);
+
+        while let Some(node_num) = todo.pop() {
+            if !self.nodes[node_num].bounds().intersect_p(ray) {
+                continue;
+            }
+
+            match self.nodes[node_num] {
+                PackedBVHNode::Leaf { prim_offset, num_prims, ..} => {
+                    // Any hit will do, and the largest primitives come first
+                    let leaf = &self.primitives[prim_offset..(prim_offset + num_prims)];
+                    if leaf.iter().any(|p| p.intersect_p(ray)) {
+                        return true;
+                    }
+                },
+                PackedBVHNode::Inner { second_child_offset, axis, .. } => {
+                    if dir_is_neg[axis] {
+                        todo.push(node_num + 1);
+                        todo.push(second_child_offset);
+                    } else {
+                        todo.push(second_child_offset);
+                        todo.push(node_num + 1);
+                    }
+                }
+            }
+        }
+
+        false
+    }
 }
 
 #[cfg(test)]
 mod tests  {
     use super::*;
-    use bbox::BBox;
-    use primitive::Primitive;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
-    use intersection::Intersectable;
-    use ray::Ray;
-    use shape::Shape;
-    use transform::transform::Transform;
-    use primitive::aggregates::tests::get_spheres;
-    use primitive::aggregates::tests::sphere_at;
+    use crate::bbox::BBox;
+    use crate::primitive::Primitive;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::ray::Ray;
+    use crate::shape::Shape;
+    use crate::transform::transform::Transform;
+    use crate::primitive::aggregates::tests::get_spheres;
+    use crate::primitive::aggregates::tests::sphere_at;
 
     #[test]
     fn it_can_be_created() {
@@ -557,4 +586,60 @@ mod tests  {
                    BBox::new_with(Point::new_with(3.0, -1.0, -4.0),
                                   Point::new_with(5.0, 3.0, 4.0)));
     }
+
+    #[test]
+    fn it_orders_leaf_primitives_by_surface_area() {
+        let sized_sphere = |v: Vector, r: f32| {
+            Primitive::simple(Shape::sphere(
+                Transform::translate(&v), Transform::translate(&(-v)),
+                false, r, -r, r, 360.0))
+        };
+
+        // Concentric spheres share a centroid, so no split method can
+        // separate them and they have to end up in the same leaf
+        let spheres = vec![
+            sized_sphere(Vector::new_with(0.0, 0.0, 0.0), 0.5),
+            sized_sphere(Vector::new_with(0.0, 0.0, 0.0), 2.0),
+            sized_sphere(Vector::new_with(0.0, 0.0, 0.0), 0.25),
+            sized_sphere(Vector::new_with(3.0, 0.0, 0.0), 1.0)];
+
+        for sm in ["sah", "middle", "equal"].iter() {
+            let bvh = BVHAccelerator::new(spheres.clone(), 4, sm);
+            let mut num_shared_leaves = 0;
+            for n in bvh.nodes.iter() {
+                if let &PackedBVHNode::Leaf { prim_offset, num_prims, .. } = n {
+                    let areas: Vec<_> = bvh.primitives[prim_offset..(prim_offset + num_prims)]
+                        .iter().map(|p| p.world_bound().surface_area()).collect();
+                    for w in areas.windows(2) {
+                        assert!(w[0] >= w[1]);
+                    }
+
+                    if num_prims > 1 { num_shared_leaves += 1; }
+                }
+            }
+
+            // Make sure that there was something to order
+            assert!(num_shared_leaves > 0, "No leaf with more than one primitive for {}", sm);
+        }
+    }
+
+    #[test]
+    fn it_agrees_on_shadow_rays() {
+        let bvh = BVHAccelerator::new(get_spheres(), 4, "sah");
+        let hit = Ray::new_with(Point::new_with(-5.0, 0.0, 0.0),
+                                Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        let miss = Ray::new_with(Point::new_with(-5.0, 10.0, 0.0),
+                                 Vector::new_with(1.0, 0.0, 0.0), 0.0);
+
+        assert!(bvh.intersect_p(&hit));
+        assert!(bvh.intersect(&hit.clone()).is_some());
+        assert!(!bvh.intersect_p(&miss));
+        assert!(bvh.intersect(&miss.clone()).is_none());
+
+        // Shadow rays that stop short of every primitive don't hit
+        let short = Ray::new_with(Point::new_with(-5.0, 0.0, 0.0),
+                                  Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        short.set_maxt(1.0);
+        assert!(!bvh.intersect_p(&short));
+    }
 }
@@ -1,16 +1,16 @@
 use std::sync::{Arc, Weak, RwLock};
 
-use bbox::BBox;
-use bbox::HasBounds;
-use bbox::Union;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use intersection::Intersection;
-use primitive::FullyRefinable;
-use primitive::Primitive;
-use primitive::Refinable;
-use ray::Ray;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bbox::Union;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::primitive::FullyRefinable;
+use crate::primitive::Primitive;
+use crate::primitive::Refinable;
+use crate::ray::Ray;
 
 #[derive(Debug, Clone)]
 struct Voxel {
@@ -290,16 +290,16 @@ impl Intersectable for GridAccelerator {
 mod tests {
     use super::*;
 
-    use bbox::BBox;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
-    use intersection::Intersectable;
-    use primitive::Primitive;
-    use ray::Ray;
-    use shape::Shape;
-    use transform::transform::Transform;
+    use crate::bbox::BBox;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::primitive::Primitive;
+    use crate::ray::Ray;
+    use crate::shape::Shape;
+    use crate::transform::transform::Transform;
 
-    use primitive::aggregates::tests::get_spheres;
+    use crate::primitive::aggregates::tests::get_spheres;
 
     #[test]
     fn it_can_be_created() {
@@ -1,12 +1,12 @@
-use bbox::BBox;
-use bbox::HasBounds;
-use bbox::Union;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use intersection::Intersection;
-use primitive::Primitive;
-use primitive::FullyRefinable;
-use ray::Ray;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bbox::Union;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::primitive::Primitive;
+use crate::primitive::FullyRefinable;
+use crate::ray::Ray;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum SplitAxis { X, Y, Z }
@@ -85,13 +85,6 @@ impl KDAccelNode {
         }
     }
 
-    fn is_leaf(&self) -> bool {
-        match self {
-            &KDAccelNode::Leaf(_) => true,
-            _ => false
-        }
-    }
-
     fn num_prims(&self) -> usize {
         match self {
             &KDAccelNode::Leaf(ref prim_ids) => prim_ids.len(),
@@ -368,8 +361,46 @@ impl HasBounds for KDTreeAccelerator {
     fn world_bound(&self) -> BBox { self.bounds.clone() }
 }
 
+impl KDTreeAccelerator {
+    // Pushes the children of the interior node node_idx that the ray
+    // passes through between tmin and tmax onto todo, so that the one the
+    // ray reaches first is popped first.
+    fn push_children(&self, ray: &Ray, inv_dir: &Vector, node_idx: usize,
+                     tmin: f32, tmax: f32, todo: &mut Vec<(usize, f32, f32)>) {
+        let node = &self.nodes[node_idx];
+
+        // Compute parametric distance along ray to split plane
+        let axis = match node.split_axis() {
+            SplitAxis::X => 0,
+            SplitAxis::Y => 1,
+            SplitAxis::Z => 2
+        };
+        let tplane = (node.split_pos() - ray.o[axis]) * inv_dir[axis];
+
+        // Get node children pointers for ray
+        let below_first =
+            (ray.o[axis] < node.split_pos()) ||
+            (ray.o[axis] == node.split_pos() && ray.d[axis] <= 0.0);
+
+        let (first, second) = if below_first {
+            (node_idx + 1, node.above_child())
+        } else {
+            (node.above_child(), node_idx + 1)
+        };
+
+        // Advance to next child node, possibly enqueue other child
+        if tplane > tmax || tplane <= 0.0 {
+            todo.push((first, tmin, tmax));
+        } else if tplane < tmin {
+            todo.push((second, tmin, tmax));
+        } else {
+            todo.push((second, tplane, tmax));
+            todo.push((first, tmin, tplane));
+        }
+    }
+}
+
 impl Intersectable for KDTreeAccelerator {
-    // !SPEED! A custom intersect_p algorithm would be a lot faster
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         // Compute initial parametric range of ray inside kd-tree extent
         let (gmin, gmax) = match self.world_bound().intersect(ray) {
@@ -390,65 +421,66 @@ impl Intersectable for KDTreeAccelerator {
                 break;
             }
 
-            let node = &self.nodes[node_idx];
-            if node.is_leaf() {
+            match self.nodes[node_idx] {
                 // Check for intersections inside leaf node
-                match node {
-                    &KDAccelNode::Leaf(ref prim_ids) => {
-                        isect = prim_ids.iter().fold(isect, |isec, &p| {
-                            self.primitives[p].intersect(ray).or(isec)
-                        });
-                    },
-                    _ => panic!("So, is it a leaf or not??")
-                }
-            } else {
-                // Compute parametric distance along ray to split plane
-                let axis = match node.split_axis() {
-                    SplitAxis::X => 0,
-                    SplitAxis::Y => 1,
-                    SplitAxis::Z => 2
-                };
-                let tplane = (node.split_pos() - ray.o[axis]) * inv_dir[axis];
-
-                // Get node children pointers for ray
-                let below_first =
-                    (ray.o[axis] < node.split_pos()) ||
-                    (ray.o[axis] == node.split_pos() && ray.d[axis] <= 0.0);
-
-                let (first, second) = if below_first {
-                    (node_idx + 1, node.above_child())
-                } else {
-                    (node.above_child(), node_idx + 1)
-                };
-
-                // Advance to next child node, possibly enqueue other child
-                if tplane > tmax || tplane <= 0.0 {
-                    todo.push((first, tmin, tmax));
-                } else if tplane < tmin {
-                    todo.push((second, tmin, tmax));
-                } else {
-                    todo.push((second, tplane, tmax));
-                    todo.push((first, tmin, tplane));
-                }
+                KDAccelNode::Leaf(ref prim_ids) => {
+                    isect = prim_ids.iter().fold(isect, |isec, &p| {
+                        self.primitives[p].intersect(ray).or(isec)
+                    });
+                },
+                _ => self.push_children(ray, &inv_dir, node_idx, tmin, tmax, &mut todo)
             }
         }
 
         isect
     }
+
+    // Shadow rays only need to know if anything is in the way, so this
+    // stops at the first primitive that the ray hits, without building its
+    // intersection or shortening the ray.
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        let (gmin, gmax) = match self.world_bound().intersect(ray) {
+            None => return false,
+            Some((x, y)) => (x, y)
+        };
+
+        let inv_dir = Vector::new_with(1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z);
+        let mut todo = Vec::with_capacity(64);
+        todo.push((0, gmin, gmax));
+
+        while let Some((node_idx, tmin, tmax)) = todo.pop() {
+            // Nodes are visited front to back, so the rest of them are all
+            // past the end of the ray
+            if ray.maxt() < tmin {
+                break;
+            }
+
+            match self.nodes[node_idx] {
+                KDAccelNode::Leaf(ref prim_ids) => {
+                    if prim_ids.iter().any(|&p| self.primitives[p].intersect_p(ray)) {
+                        return true;
+                    }
+                },
+                _ => self.push_children(ray, &inv_dir, node_idx, tmin, tmax, &mut todo)
+            }
+        }
+
+        false
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
-    use intersection::Intersectable;
-    use primitive::Primitive;
-    use primitive::aggregates::tests::sphere_at;
-    use primitive::aggregates::tests::get_spheres;
-    use ray::Ray;
-    use shape::Shape;
-    use transform::transform::Transform;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::primitive::Primitive;
+    use crate::primitive::aggregates::tests::sphere_at;
+    use crate::primitive::aggregates::tests::get_spheres;
+    use crate::ray::Ray;
+    use crate::shape::Shape;
+    use crate::transform::transform::Transform;
 
     #[test]
     fn it_can_be_created() {
@@ -540,6 +572,31 @@ mod tests {
         assert_eq!(kdt.nodes[2], KDAccelNode::leaf(vec![4, 5, 6, 7]));
     }
 
+    #[test]
+    fn it_finds_occluders_without_shortening_rays() {
+        let kdt = KDTreeAccelerator::new(get_spheres(), 80, 1, 1.0, 1, 10);
+
+        // Rays from a grid of points on one side of the spheres towards
+        // points on the other side, some of which pass between them
+        for i in 0..16 {
+            for j in 0..16 {
+                let o = Point::new_with(-2.0 + 0.4 * (i as f32), -2.0 + 0.4 * (j as f32), -3.0);
+                let d = Vector::new_with(0.1 * (j as f32) - 0.5, 0.5 - 0.1 * (i as f32), 1.0);
+                for &maxt in [1.5, 3.0, 10.0].iter() {
+                    let r = Ray::new_with(o.clone(), d.clone(), 0.0);
+                    r.set_maxt(maxt);
+                    let occluded = kdt.intersect_p(&r);
+                    assert_eq!(r.maxt(), maxt);
+                    assert_eq!(occluded, kdt.intersect(&r).is_some(), "{} {} {}", i, j, maxt);
+                }
+            }
+        }
+
+        let r = Ray::new_with(Point::new_with(-5.0, 5.0, 0.0),
+                              Vector::new_with(0.0, 0.0, 1.0), 0.0);
+        assert!(!kdt.intersect_p(&r));
+    }
+
     #[test]
     fn it_fully_refines_all_primitives() {
         // Tetrahedron
@@ -2,15 +2,15 @@ mod grid;
 mod bvh;
 mod kdt;
 
-use bbox::BBox;
-use bbox::HasBounds;
-use intersection::Intersectable;
-use intersection::Intersection;
-use primitive::Primitive;
-use primitive::aggregates::grid::GridAccelerator;
-use primitive::aggregates::bvh::BVHAccelerator;
-use primitive::aggregates::kdt::KDTreeAccelerator;
-use ray::Ray;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::primitive::Primitive;
+use crate::primitive::aggregates::grid::GridAccelerator;
+use crate::primitive::aggregates::bvh::BVHAccelerator;
+use crate::primitive::aggregates::kdt::KDTreeAccelerator;
+use crate::ray::Ray;
 
 #[derive(Clone, Debug)]
 pub enum Aggregate {
@@ -24,7 +24,7 @@ impl Aggregate {
         Aggregate::Grid(GridAccelerator::new(p, refine_immediately))
     }
 
-    pub fn bvh(p: Vec<Primitive>, max_prims: usize, sm: &'static str) -> Aggregate {
+    pub fn bvh(p: Vec<Primitive>, max_prims: usize, sm: &str) -> Aggregate {
         Aggregate::BVH(BVHAccelerator::new(p, max_prims, sm))
     }
 
@@ -65,13 +65,13 @@ impl Intersectable for Aggregate {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
-    use intersection::Intersectable;
-    use primitive::Primitive;
-    use ray::Ray;
-    use shape::Shape;
-    use transform::transform::Transform;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::primitive::Primitive;
+    use crate::ray::Ray;
+    use crate::shape::Shape;
+    use crate::transform::transform::Transform;
 
     pub fn sphere_at(v: Vector) -> Primitive {
         Primitive::simple(Shape::sphere(
@@ -1,25 +1,25 @@
 use std::sync::Arc;
 
-use area_light::AreaLight;
-use bbox::BBox;
-use bbox::HasBounds;
-use bsdf::BSDF;
-use bsdf::bssrdf::BSSRDF;
-use diff_geom::DifferentialGeometry;
-use intersection::Intersectable;
-use intersection::Intersection;
-use material::Material;
-use primitive::FullyRefinable;
-use primitive::Refinable;
-use ray::Ray;
-use shape::Shape;
-use transform::transform::Transform;
+use crate::area_light::AreaLight;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bsdf::BSDF;
+use crate::bsdf::bssrdf::BSSRDF;
+use crate::diff_geom::DifferentialGeometry;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::material::Material;
+use crate::primitive::FullyRefinable;
+use crate::primitive::Refinable;
+use crate::ray::Ray;
+use crate::shape::Shape;
+use crate::transform::transform::Transform;
 
 #[derive(Clone, Debug)]
 pub struct GeometricPrimitive {
     s: Shape,
     m: Arc<Material>,
-    area_light: Option<Arc<AreaLight>>
+    area_light: Option<Arc<dyn AreaLight>>
 }
 
 impl GeometricPrimitive {
@@ -31,7 +31,7 @@ impl GeometricPrimitive {
         }
     }
 
-    pub fn new_lit(_s: Shape, _m: Arc<Material>, al: Arc<AreaLight>) -> GeometricPrimitive {
+    pub fn new_lit(_s: Shape, _m: Arc<Material>, al: Arc<dyn AreaLight>) -> GeometricPrimitive {
         GeometricPrimitive {
             s: _s,
             m: _m,
@@ -39,20 +39,22 @@ impl GeometricPrimitive {
         }
     }
 
-    pub fn area_light(&self) -> Option<Arc<AreaLight>> {
+    pub fn area_light(&self) -> Option<Arc<dyn AreaLight>> {
         self.area_light.clone()
     }
 
-    pub fn get_bsdf(&self, dg: DifferentialGeometry,
-                    o2w: &Transform) -> Option<BSDF> {
+    pub fn material(&self) -> &Material { &self.m }
+
+    pub fn get_bsdf(&self, dg: DifferentialGeometry, o2w: &Transform,
+                    mtl: Option<&Material>) -> Option<BSDF> {
         let dgs = self.s.get_shading_geometry(o2w, dg.clone());
-        self.m.get_bsdf(dg, dgs)
+        mtl.unwrap_or(&self.m).get_bsdf(dg, dgs)
     }
 
-    pub fn get_bssrdf(&self, dg: DifferentialGeometry,
-                      o2w: &Transform) -> Option<BSSRDF> {
+    pub fn get_bssrdf(&self, dg: DifferentialGeometry, o2w: &Transform,
+                      mtl: Option<&Material>) -> Option<BSSRDF> {
         let dgs = self.s.get_shading_geometry(o2w, dg.clone());
-        self.m.get_bssrdf(dg, dgs)
+        mtl.unwrap_or(&self.m).get_bssrdf(dg, dgs)
     }
 
     pub fn can_intersect(&self) -> bool { self.s.can_intersect() }
@@ -64,11 +66,11 @@ impl Intersectable for GeometricPrimitive {
             ray.set_maxt(si.t_hit);
             Intersection::new_with(
                 si.dg,
-                self.s.base().world2object.clone(),
-                self.s.base().object2world.clone(),
+                (*self.s.base().world2object).clone(),
+                (*self.s.base().object2world).clone(),
                 self.s.base().shape_id,
                 0,
-                si.ray_epsilon)
+                si.p_error)
         })
     }
 
@@ -2,23 +2,23 @@ mod aggregates;
 mod geometric;
 mod transformed;
 
-use area_light::AreaLight;
-use bbox::BBox;
-use bbox::HasBounds;
-use bsdf::BSDF;
-use bsdf::bssrdf::BSSRDF;
-use diff_geom::DifferentialGeometry;
-use intersection::Intersectable;
-use intersection::Intersection;
-use material::Material;
-use ray::Ray;
-use shape::Shape;
-use transform::animated::AnimatedTransform;
-use transform::transform::Transform;
-
-use primitive::geometric::GeometricPrimitive;
-use primitive::transformed::TransformedPrimitive;
-use primitive::aggregates::Aggregate;
+use crate::area_light::AreaLight;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bsdf::BSDF;
+use crate::bsdf::bssrdf::BSSRDF;
+use crate::diff_geom::DifferentialGeometry;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::shape::Shape;
+use crate::transform::animated::AnimatedTransform;
+use crate::transform::transform::Transform;
+
+use crate::primitive::geometric::GeometricPrimitive;
+use crate::primitive::transformed::TransformedPrimitive;
+use crate::primitive::aggregates::Aggregate;
 
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
@@ -35,8 +35,10 @@ impl PrimitiveBase {
         prim_id: NEXT_PRIM_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed) } }
 }
 
+// Like shapes, primitives are identified by their id: clones share it, and
+// refinement creates primitives with new ids.
 impl ::std::cmp::PartialEq for PrimitiveBase {
-    fn eq(&self, _: &PrimitiveBase) -> bool { true }
+    fn eq(&self, other: &PrimitiveBase) -> bool { self.prim_id == other.prim_id }
 }
 
 pub trait Refinable<T = Self> {
@@ -65,7 +67,9 @@ pub trait FullyRefinable : Refinable<Self>+Sized {
 #[derive(Clone, Debug)] // , PartialEq)]
 enum Prim {
     Geometric(GeometricPrimitive),
-    Transformed(TransformedPrimitive),
+    // Instances are rare next to the shapes they're made of, so keep them
+    // from making every primitive bigger
+    Transformed(Box<TransformedPrimitive>),
     Aggregate(Aggregate)
 }
 
@@ -91,7 +95,7 @@ impl Primitive {
         }
     }
 
-    pub fn geometric_area_light(s: Shape, mtl: Arc<Material>, al: Arc<AreaLight>) -> Primitive {
+    pub fn geometric_area_light(s: Shape, mtl: Arc<Material>, al: Arc<dyn AreaLight>) -> Primitive {
         Primitive {
             base: PrimitiveBase::new(),
             prim: Arc::new(Prim::Geometric(GeometricPrimitive::new_lit(s, mtl, al)))
@@ -101,7 +105,17 @@ impl Primitive {
     pub fn transformed(p: Arc<Primitive>, xf: AnimatedTransform) -> Primitive {
         Primitive {
             base: PrimitiveBase::new(),
-            prim: Arc::new(Prim::Transformed(TransformedPrimitive::new(p, xf)))
+            prim: Arc::new(Prim::Transformed(Box::new(TransformedPrimitive::new(p, xf))))
+        }
+    }
+
+    // An instance of p that is shaded with mtl instead of its own materials
+    pub fn transformed_with_material(p: Arc<Primitive>, xf: AnimatedTransform,
+                                     mtl: Arc<Material>) -> Primitive {
+        Primitive {
+            base: PrimitiveBase::new(),
+            prim: Arc::new(Prim::Transformed(
+                Box::new(TransformedPrimitive::new(p, xf).with_material(mtl))))
         }
     }
 
@@ -112,13 +126,22 @@ impl Primitive {
         }
     }
 
-    pub fn bvh(p: Vec<Primitive>, max_prims: usize, sm: &'static str) -> Primitive {
+    pub fn bvh(p: Vec<Primitive>, max_prims: usize, sm: &str) -> Primitive {
         Primitive {
             base: PrimitiveBase::new(),
             prim: Arc::new(Prim::Aggregate(Aggregate::bvh(p, max_prims, sm))),
         }
     }
 
+    pub fn kdtree(p: Vec<Primitive>, icost: i32, tcost: i32, ebonus: f32,
+                  max_prims: usize, max_depth: usize) -> Primitive {
+        Primitive {
+            base: PrimitiveBase::new(),
+            prim: Arc::new(Prim::Aggregate(
+                Aggregate::kdt(p, icost, tcost, ebonus, max_prims, max_depth))),
+        }
+    }
+
     pub fn can_intersect(&self) -> bool {
         match self.prim.as_ref() {
             &Prim::Geometric(ref p) => p.can_intersect(),
@@ -129,30 +152,42 @@ impl Primitive {
 
     pub fn get_id(&self) -> usize { self.base.prim_id }
 
-    pub fn area_light(&self) -> Option<Arc<AreaLight>> {
+    pub fn area_light(&self) -> Option<Arc<dyn AreaLight>> {
         match self.prim.as_ref() {
             &Prim::Geometric(ref p) => p.area_light(),
             _ => panic!("Only geometric primitives may have area lights")
         }
     }
 
-    pub fn get_bsdf(&self, dg: DifferentialGeometry,
-                    o2w: &Transform) -> Option<BSDF> {
+    pub fn material(&self) -> &Material {
+        match self.prim.as_ref() {
+            Prim::Geometric(p) => p.material(),
+            _ => panic!("Only geometric primitives may have materials")
+        }
+    }
+
+    // Shades the primitive with mtl if given, otherwise with its own material
+    pub fn get_bsdf(&self, dg: DifferentialGeometry, o2w: &Transform,
+                    mtl: Option<&Material>) -> Option<BSDF> {
         match self.prim.as_ref() {
-            &Prim::Geometric(ref p) => p.get_bsdf(dg, o2w),
+            &Prim::Geometric(ref p) => p.get_bsdf(dg, o2w, mtl),
             _ => panic!("Only geometric primitives may have bsdfs")
         }
     }
 
-    pub fn get_bssrdf(&self, dg: DifferentialGeometry,
-                      o2w: &Transform) -> Option<BSSRDF> {
+    pub fn get_bssrdf(&self, dg: DifferentialGeometry, o2w: &Transform,
+                      mtl: Option<&Material>) -> Option<BSSRDF> {
         match self.prim.as_ref() {
-            &Prim::Geometric(ref p) => p.get_bssrdf(dg, o2w),
+            &Prim::Geometric(ref p) => p.get_bssrdf(dg, o2w, mtl),
             _ => panic!("Only geometric primitives may have bssrdfs")
         }
     }
 }
 
+impl ::std::cmp::PartialEq for Primitive {
+    fn eq(&self, other: &Primitive) -> bool { self.base == other.base }
+}
+
 impl HasBounds for Primitive {
     fn world_bound(&self) -> BBox {
         match self.prim.as_ref() {
@@ -229,3 +264,85 @@ impl Refinable for Primitive {
 }
 
 impl FullyRefinable for Primitive { }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+
+    fn unit_quad() -> Shape {
+        let pts = [Point::new_with(0.0, 0.0, 0.0), Point::new_with(1.0, 0.0, 0.0),
+                   Point::new_with(1.0, 1.0, 0.0), Point::new_with(0.0, 1.0, 0.0)];
+        Shape::triangle_mesh(Transform::new(), Transform::new(), false,
+                             &[0, 1, 2, 0, 2, 3], &pts, None, None, None, None)
+    }
+
+    #[test]
+    fn clones_share_their_id() {
+        let prim = Primitive::simple(unit_quad());
+        let copy = prim.clone();
+        assert_eq!(copy.get_id(), prim.get_id());
+        assert!(copy == prim);
+        assert!(Primitive::simple(unit_quad()) != prim);
+    }
+
+    #[test]
+    fn refinement_creates_new_ids() {
+        let prim = Primitive::simple(unit_quad());
+        let id = prim.get_id();
+        let refined = prim.fully_refine();
+        assert_eq!(refined.len(), 2);
+        assert!(refined.iter().all(|p| p.get_id() != id));
+        assert!(refined[0] != refined[1]);
+
+        // Already refined primitives are returned as is
+        let tri = refined[0].clone();
+        let again = tri.clone().refine();
+        assert_eq!(again.len(), 1);
+        assert!(again[0] == tri);
+    }
+
+    #[test]
+    fn intersections_report_the_primitive_that_was_hit() {
+        let prim = Primitive::simple(unit_quad());
+        let tris = prim.fully_refine();
+        let ray = Ray::new_with(Point::new_with(0.75, 0.25, 1.0),
+                                Vector::new_with(0.0, 0.0, -1.0), 0.0);
+        let hits: Vec<_> = tris.iter().filter_map(|t| t.intersect(&ray)).collect();
+        assert_eq!(hits.len(), 1);
+
+        let hit = tris.iter().find(|t| t.intersect(&ray).is_some()).unwrap();
+        assert_eq!(hits[0].primitive_id, hit.get_id());
+        assert!(hits[0].primitive.as_ref().is_some_and(|p| **p == *hit));
+    }
+
+    #[test]
+    fn intersections_carry_the_material_and_emission_of_the_primitive() {
+        use crate::light::diffuse::DiffuseAreaLight;
+        use crate::ray::RayDifferential;
+        use crate::spectrum::Spectrum;
+        use crate::texture::ConstantTexture;
+
+        let matte = Arc::new(Material::matte(
+            Arc::new(ConstantTexture::new(Spectrum::from(0.5))),
+            Arc::new(ConstantTexture::new(0.0)), None));
+        let sphere = || Shape::sphere(Transform::new(), Transform::new(), false,
+                                      1.0, -1.0, 1.0, 360.0);
+        let light = Arc::new(DiffuseAreaLight::new(
+            Transform::new(), Spectrum::from(3.0), 1, sphere()));
+        let prim = Primitive::geometric_area_light(sphere(), matte.clone(), light);
+
+        let ray = RayDifferential::new_with(Point::new_with(0.0, 0.0, 5.0),
+                                            Vector::new_with(0.0, 0.0, -1.0), 0.0);
+        let isect = prim.intersect(&ray.ray).unwrap();
+        assert!(isect.material().is_some_and(|m| ::std::ptr::eq(m, matte.as_ref())));
+        assert!(isect.get_bsdf(&ray).is_some());
+        assert_eq!(isect.le(&Vector::new_with(0.0, 0.0, 1.0), 0.0), Spectrum::from(3.0));
+
+        // Primitives without a material don't scatter light
+        let isect = Primitive::simple(sphere()).intersect(&ray.ray).unwrap();
+        assert!(isect.get_bsdf(&ray).is_none());
+        assert!(isect.le(&Vector::new_with(0.0, 0.0, 1.0), 0.0).is_black());
+    }
+}
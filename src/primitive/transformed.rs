@@ -1,20 +1,22 @@
 use std::sync::Arc;
 
-use bbox::BBox;
-use bbox::HasBounds;
-use geometry::normal::Normalize;
-use intersection::Intersectable;
-use intersection::Intersection;
-use primitive::Primitive;
-use primitive::Refinable;
-use ray::Ray;
-use transform::animated::AnimatedTransform;
-use transform::transform::ApplyTransform;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::geometry::normal::Normalize;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::material::Material;
+use crate::primitive::Primitive;
+use crate::primitive::Refinable;
+use crate::ray::Ray;
+use crate::transform::animated::AnimatedTransform;
+use crate::transform::transform::ApplyTransform;
 
 #[derive(Clone, Debug)]  // , PartialEq)]
 pub struct TransformedPrimitive {
     prim: Arc<Primitive>,
-    xf: AnimatedTransform
+    xf: AnimatedTransform,
+    material: Option<Arc<Material>>
 }
 
 impl TransformedPrimitive {
@@ -22,10 +24,18 @@ impl TransformedPrimitive {
         assert!(p.is_refined());
         TransformedPrimitive {
             prim: p.clone(),
-            xf: xform
+            xf: xform,
+            material: None
         }
     }
 
+    // Shades everything that the instanced primitive is made of with mtl
+    // instead of its own materials
+    pub fn with_material(mut self, mtl: Arc<Material>) -> TransformedPrimitive {
+        self.material = Some(mtl);
+        self
+    }
+
     pub fn primitive(&self) -> &Primitive {
         self.prim.as_ref()
     }
@@ -42,6 +52,7 @@ impl Intersectable for TransformedPrimitive {
             isect.object_to_world = isect.world_to_object.inverse();
 
             let prim2world = w2p.invert();
+            isect.p_error = prim2world.xf_point_error(&isect.dg.p, &isect.p_error);
             isect.dg.p = prim2world.t(&isect.dg.p);
             isect.dg.nn = prim2world.t(&isect.dg.nn).normalize();
             isect.dg.dpdu = prim2world.t(&isect.dg.dpdu);
@@ -49,6 +60,10 @@ impl Intersectable for TransformedPrimitive {
             isect.dg.dndu = prim2world.t(&isect.dg.dndu);
             isect.dg.dndv = prim2world.t(&isect.dg.dndv);
 
+            if let Some(mtl) = self.material.as_ref() {
+                isect.material = Some(mtl.clone());
+            }
+
             Some(isect)
         })
     }
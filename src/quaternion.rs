@@ -1,8 +1,8 @@
-use geometry::normal::Normalize;
-use geometry::vector::Dot;
-use geometry::vector::Vector;
+use crate::geometry::normal::Normalize;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
 use std::f32;
-use utils::Lerp;
+use crate::utils::Lerp;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Quaternion {
@@ -173,11 +173,11 @@ impl ::std::ops::Neg for Quaternion {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use geometry::normal::Normalize;
-    use geometry::vector::Vector;
-    use geometry::vector::Dot;
+    use crate::geometry::normal::Normalize;
+    use crate::geometry::vector::Vector;
+    use crate::geometry::vector::Dot;
     use std::f32;
-    use utils::Lerp;
+    use crate::utils::Lerp;
 
     #[test]
     fn it_can_be_created() {
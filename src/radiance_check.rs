@@ -0,0 +1,98 @@
+// Checks for NaN and infinite radiance values in debug builds. A single bad
+// sample turns a whole pixel into NaN, or black once it's written out, so
+// instead each value that comes out of an integrator, BSDF or light is
+// checked where it's computed, and bad values are reported along with
+// where they came from and then dropped. Release builds skip the checks.
+
+use std::cell::Cell;
+
+use crate::spectrum::Spectrum;
+use crate::trace;
+
+thread_local! {
+    static CURRENT_PIXEL: Cell<Option<(i32, i32)>> = const { Cell::new(None) };
+    static NUM_REPORTS: Cell<usize> = const { Cell::new(0) };
+}
+
+// Sets the pixel that is being rendered on the calling thread, so that it
+// can be included in reports.
+pub fn set_pixel(pixel: Option<(i32, i32)>) {
+    CURRENT_PIXEL.with(|p| p.set(pixel));
+}
+
+// Returns the number of invalid values that have been reported on the
+// calling thread.
+pub fn num_reports() -> usize {
+    NUM_REPORTS.with(|n| n.get())
+}
+
+pub fn is_valid(l: &Spectrum) -> bool {
+    !l.has_nans() && !l.has_infs()
+}
+
+// Returns l, unless this is a debug build and l has NaN or infinite
+// components, in which case the value is reported as coming from the given
+// module and primitive, and black is returned instead.
+pub fn check(module: &str, primitive_id: Option<usize>, l: Spectrum) -> Spectrum {
+    if !cfg!(debug_assertions) || is_valid(&l) {
+        return l;
+    }
+
+    NUM_REPORTS.with(|n| n.set(n.get() + 1));
+    let pixel = CURRENT_PIXEL.with(|p| p.get());
+
+    let mut msg = format!("WARNING: {} returned invalid radiance {:?}", module, l.to_rgb());
+    if let Some(id) = primitive_id {
+        msg.push_str(&format!(" for primitive {}", id));
+    }
+    if let Some((x, y)) = pixel {
+        msg.push_str(&format!(" at pixel ({}, {})", x, y));
+    }
+    println!("{}", msg);
+
+    trace::record("invalid_radiance", |e| {
+        let e = e.str("module", module).spectrum("L", &l);
+        match primitive_id {
+            Some(id) => e.int("primitive_id", id as i64),
+            None => e
+        }
+    });
+
+    Spectrum::from(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_passes_valid_radiance_through() {
+        let n = num_reports();
+        let l = Spectrum::from_rgb([0.5, 2.0, 0.0]);
+        assert_eq!(check("test", Some(3), l), l);
+        assert_eq!(check("test", None, Spectrum::from(0.0)), Spectrum::from(0.0));
+        assert_eq!(num_reports(), n);
+    }
+
+    #[test]
+    fn it_reports_and_drops_invalid_radiance() {
+        set_pixel(Some((3, 4)));
+        let n = num_reports();
+        for x in [f32::NAN, f32::INFINITY, -f32::INFINITY] {
+            let l = Spectrum::from_rgb([0.5, x, 0.0]);
+            assert!(!is_valid(&l));
+            assert_eq!(check("test", Some(7), l), Spectrum::from(0.0));
+        }
+        assert_eq!(num_reports(), n + 3);
+        set_pixel(None);
+    }
+
+    #[test]
+    fn it_records_reports_when_tracing() {
+        trace::begin();
+        check("light", None, Spectrum::from(f32::NAN));
+        let events = trace::end();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("{\"event\":\"invalid_radiance\",\"module\":\"light\""));
+    }
+}
@@ -1,15 +1,55 @@
 use std::cell::RefCell;
 
-use geometry::point::Point;
-use geometry::vector::Vector;
+use crate::bsdf::BxDFType;
+use crate::geometry::normal::Normal;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
 use std::f32;
 
+// The number of times a path has scattered off of each type of BxDF before
+// spawning a ray
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct BounceCounts {
+    pub diffuse: usize,
+    pub glossy: usize,
+    pub specular: usize
+}
+
+impl BounceCounts {
+    // Records a scattering event off of a BxDF with the given type. Types
+    // with more than one of diffuse, glossy or specular count towards each.
+    pub fn add(&mut self, ty: BxDFType) {
+        if ty.contains(BxDFType::BSDF_DIFFUSE) { self.diffuse += 1; }
+        if ty.contains(BxDFType::BSDF_GLOSSY) { self.glossy += 1; }
+        if ty.contains(BxDFType::BSDF_SPECULAR) { self.specular += 1; }
+    }
+}
+
+// What a ray was traced for. Shapes can use this to treat the rays that
+// decide what is seen directly differently from the rest of the path.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum RayKind {
+    // Leaves the camera towards the first visible surface
+    Camera,
+    // Continues a path after scattering, or starts one from a light
+    #[default]
+    Scattered,
+    // Only checks whether two points can see each other
+    Shadow
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Ray {
     pub o: Point,
     pub d: Vector,
     pub time: f32,
     pub depth: usize,
+    pub bounces: BounceCounts,
+    pub kind: RayKind,
+    // The wavelength in nanometers of the light that the ray carries, once a
+    // dispersive surface has split its path up by wavelength
+    pub wavelength: Option<f32>,
     mint: RefCell<f32>,
     maxt: RefCell<f32>
 }
@@ -21,6 +61,9 @@ impl Ray {
             d: Vector::new(),
             time: 0.0,
             depth: 0,
+            bounces: BounceCounts::default(),
+            kind: RayKind::Scattered,
+            wavelength: None,
             mint: RefCell::new(0.0),
             maxt: RefCell::new(f32::MAX)
         }
@@ -32,6 +75,9 @@ impl Ray {
             d: dir,
             time: 0.0,
             depth: 0,
+            bounces: BounceCounts::default(),
+            kind: RayKind::Scattered,
+            wavelength: None,
             mint: RefCell::new(start),
             maxt: RefCell::new(f32::MAX)
         }
@@ -43,6 +89,9 @@ impl Ray {
             d: dir,
             time: self.time,
             depth: self.depth + 1,
+            bounces: self.bounces,
+            kind: RayKind::Scattered,
+            wavelength: self.wavelength,
             mint: RefCell::new(start),
             maxt: self.maxt.clone()
         }
@@ -54,12 +103,35 @@ impl Ray {
     pub fn set_maxt(&self, t: f32) { *(self.maxt.borrow_mut()) = t; }
     pub fn maxt(&self) -> f32 { *(self.maxt.borrow()) }
 
+    pub fn with_kind(mut self, kind: RayKind) -> Ray {
+        self.kind = kind;
+        self
+    }
+
     pub fn set_time(&mut self, t: f32) { self.time = t }
     pub fn set_depth(&mut self, d: usize) { self.depth = d }
 
     pub fn point_at(&self, t: f32) -> Point { &self.o + (&self.d * t) }
 }
 
+// Returns the origin for a ray leaving the surface at p in direction w. The
+// surface passes somewhere inside of the box of size p_error around p, so p
+// is pushed along the surface normal n until it's past the box on the side
+// that w points to. The ray then can't hit the surface that it's leaving.
+pub fn offset_ray_origin(p: &Point, p_error: &Vector, n: &Normal, w: &Vector) -> Point {
+    let d = n.x.abs() * p_error.x + n.y.abs() * p_error.y + n.z.abs() * p_error.z;
+    let offset = d * Vector::from(n.clone());
+    let offset = if n.dot(w) < 0.0 { -offset } else { offset };
+    let po = p + &offset;
+
+    // Round the offset point away from p so that it doesn't land back
+    // inside of the box
+    let round = |po: f32, off: f32| {
+        if off > 0.0 { po.next_up() } else if off < 0.0 { po.next_down() } else { po }
+    };
+    Point::new_with(round(po.x, offset.x), round(po.y, offset.y), round(po.z, offset.z))
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct RayDifferential {
     pub ray: Ray,
@@ -131,8 +203,8 @@ impl ::std::convert::From<Ray> for RayDifferential {
 mod tests {
     use super::*;
     use std::cell::RefCell;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
 
     #[test]
     fn rays_can_be_created() {
@@ -141,6 +213,9 @@ mod tests {
             d: Vector::new(),
             time: 0.0,
             depth: 0,
+            bounces: BounceCounts::default(),
+            kind: RayKind::Scattered,
+            wavelength: None,
             mint: RefCell::new(0.0),
             maxt: RefCell::new(::std::f32::MAX)
         });
@@ -153,6 +228,9 @@ mod tests {
             d: d.clone(),
             time: 0.0,
             depth: 0,
+            bounces: BounceCounts::default(),
+            kind: RayKind::Scattered,
+            wavelength: None,
             mint: RefCell::new(2.0),
             maxt: RefCell::new(::std::f32::MAX)
         });
@@ -164,6 +242,9 @@ mod tests {
                        d: Vector::new_with(1.0, 1.0, 1.0),
                        time: 0.0,
                        depth: 1,
+                       bounces: BounceCounts::default(),
+                       kind: RayKind::Scattered,
+                       wavelength: None,
                        mint: RefCell::new(1.0),
                        maxt: RefCell::new(::std::f32::MAX)
                    });
@@ -207,6 +288,18 @@ mod tests {
                    });
     }
 
+    #[test]
+    fn rays_carry_their_bounce_counts() {
+        let mut r = Ray::new();
+        r.bounces.add(BxDFType::BSDF_REFLECTION | BxDFType::BSDF_SPECULAR);
+        r.bounces.add(BxDFType::BSDF_TRANSMISSION | BxDFType::BSDF_SPECULAR);
+        r.bounces.add(BxDFType::BSDF_REFLECTION | BxDFType::BSDF_DIFFUSE);
+
+        let child = r.into(Point::new(), Vector::new_with(0.0, 0.0, 1.0), 0.0);
+        assert_eq!(child.bounces,
+                   BounceCounts { diffuse: 1, glossy: 0, specular: 2 });
+    }
+
     #[test]
     fn ray_differentials_can_be_scaled() {
         // !FIXME! I'm not totally sure what this function is supposed
@@ -230,4 +323,28 @@ mod tests {
         assert_eq!(rd.point_at(0.5), Point::new_with(1.0, 1.0, 3.0));
         assert_eq!(r.point_at(0.5), Point::new_with(1.0, 1.0, 3.0));
     }
+
+    #[test]
+    fn ray_origins_are_offset_off_of_surfaces() {
+        let p = Point::new_with(1.0, 2.0, 3.0);
+        let p_error = Vector::new_with(1e-3, 2e-3, 4e-3);
+        let n = Normal::new_with(0.0, 0.0, 1.0);
+
+        // Rays leave from past the error bounds, on the side of the surface
+        // that they point to
+        let up = offset_ray_origin(&p, &p_error, &n, &Vector::new_with(0.0, 1.0, 1.0));
+        assert!(up.z > 3.004);
+        assert_eq!((up.x, up.y), (1.0, 2.0));
+
+        let down = offset_ray_origin(&p, &p_error, &n, &Vector::new_with(0.0, 1.0, -1.0));
+        assert!(down.z < 2.996);
+        assert_eq!((down.x, down.y), (1.0, 2.0));
+
+        // Slanted normals move it along every axis
+        let n = Normal::new_with(0.6, 0.0, 0.8);
+        let slanted = offset_ray_origin(&p, &p_error, &n, &Vector::new_with(1.0, 0.0, 0.0));
+        let d = 0.6 * 1e-3 + 0.8 * 4e-3;
+        assert!(slanted.x > 1.0 + 0.6 * d && slanted.z > 3.0 + 0.8 * d);
+        assert_eq!(slanted.y, 2.0);
+    }
 }
@@ -1,9 +1,10 @@
-use ray;
-use rng::RNG;
-use sampler::sample::Sample;
-use spectrum::Spectrum;
-use scene;
-use intersection::Intersection;
+use crate::integrator::LightGroupRadiance;
+use crate::ray;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::spectrum::Spectrum;
+use crate::scene;
+use crate::intersection::Intersection;
 
 pub trait Renderer {
     fn render(&mut self, scene: &scene::Scene);
@@ -18,9 +19,24 @@ pub trait Renderer {
         self.li(scene, ray, sample, rng).0
     }
 
+    // Computes the same radiance as li_simple, and if groups is given, adds
+    // the part of it that came from lights in an output group. Renderers
+    // that don't track groups leave them untouched.
+    fn li_grouped(
+        &self, scene: &scene::Scene, ray: &ray::RayDifferential,
+        sample: &Sample, rng: &mut RNG, _: Option<&mut LightGroupRadiance>) -> Spectrum {
+        self.li_simple(scene, ray, sample, rng)
+    }
+
     fn transmittance(
         &self, scene: &scene::Scene, ray: &ray::RayDifferential,
         sample: &Sample, rng: &mut RNG) -> Spectrum;
 
+    // Renders only pixel (x, y) and returns a JSON log of everything that
+    // went into it, or None if the renderer can't do that for the pixel.
+    fn debug_pixel(&mut self, _: &scene::Scene, _: i32, _: i32) -> Option<String> {
+        None
+    }
+
     // Rnderer Interface
 }
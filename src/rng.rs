@@ -1,7 +1,5 @@
-extern crate rand;
-
-use self::rand::{Rng, SeedableRng};
-use self::rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 
 pub struct RNG {
     rng: StdRng
@@ -1,16 +1,37 @@
-use intersection::Intersection;
-use ray::RayDifferential;
-use rng::RNG;
-use sampler::sample::Sample;
-use sampler::base::SamplerBase;
-use spectrum::Spectrum;
+use crate::intersection::Intersection;
+use crate::ray::RayDifferential;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::sampler::base::SamplerBase;
+use crate::spectrum::Spectrum;
 
-use sampler::utils::*;
+use crate::sampler::utils::*;
 
-#[derive(Copy, PartialOrd, Ord, PartialEq, Eq, Debug, Clone)]
+#[derive(Copy, PartialEq, Debug, Clone)]
 pub enum AdaptiveTest {
     CompreShapeID,
-    ContrastThreshold
+    ContrastThreshold,
+
+    // Supersamples pixels where the standard error of the mean luminance of
+    // the samples, relative to that mean, is larger than the given error.
+    Variance(f32)
+}
+
+// Returns the standard error of the mean luminance of ls relative to the
+// mean, or zero if the mean is zero.
+fn relative_standard_error(ls: &[Spectrum]) -> f32 {
+    if ls.len() < 2 {
+        return 0.0;
+    }
+
+    let n = ls.len() as f32;
+    let mean = ls.iter().map(|l| l.y()).sum::<f32>() / n;
+    if mean <= 0.0 {
+        return 0.0;
+    }
+
+    let variance = ls.iter().map(|l| (l.y() - mean) * (l.y() - mean)).sum::<f32>() / (n - 1.0);
+    (variance / n).sqrt() / mean
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -43,6 +64,7 @@ impl AdaptiveSampler {
     }
     
     pub fn base(&self) -> &SamplerBase { &self.base }
+    pub fn base_mut(&mut self) -> &mut SamplerBase { &mut self.base }
 
     pub fn maximum_sample_count(&self) -> usize {
         self.max_samples
@@ -50,6 +72,11 @@ impl AdaptiveSampler {
 
     pub fn get_sub_sampler(&self, num: usize, count:usize) -> Option<AdaptiveSampler> {
         let (x0, x1, y0, y1) = self.base.compute_sub_window(num, count);
+        self.get_window_sampler(x0, x1, y0, y1)
+    }
+
+    pub fn get_window_sampler(&self, x0: i32, x1: i32,
+                              y0: i32, y1: i32) -> Option<AdaptiveSampler> {
         if x0 == x1 || y0 == y1 {
             None
         } else {
@@ -66,10 +93,6 @@ impl AdaptiveSampler {
         assert!(samples.len() >= 1);
         if self.y_pos == self.base.y_pixel_end { return 0 }
 
-        let ns = ld_pixel_sample_floats_needed(samples.get(0).unwrap(),
-                                               self.base.samples_per_pixel);
-        self.sample_buf.resize(ns, 0f32);
-
         let num_samples =
             if self.supersample_pixel {
                 self.max_samples
@@ -77,20 +100,18 @@ impl AdaptiveSampler {
                 self.min_samples
             };
 
+        let ns = ld_pixel_sample_floats_needed(samples.get(0).unwrap(), num_samples);
+        self.sample_buf.resize(ns, 0f32);
+
         ld_pixel_sample(self.x_pos, self.y_pos, self.base.shutter_open,
                         self.base.shutter_close, num_samples, samples,
                         &mut self.sample_buf, rng);
 
-        self.supersample_pixel = false;
-
         num_samples
     }
 
-    pub fn report_results(&mut self, samples: &Vec<Sample>,
-                          rays: &Vec<RayDifferential>,
-                          ls: &Vec<Spectrum>, isects: &Vec<Intersection>,
-                          sample_count: usize) -> bool {
-        let needs_supersample = !self.supersample_pixel && match self.method {
+    fn needs_supersample(&self, ls: &[Spectrum], isects: &[Intersection]) -> bool {
+        match self.method {
             AdaptiveTest::CompreShapeID => {
                 let tail = isects.iter().skip(1);
                 isects.iter().zip(tail).fold(false, |acc, (i1, i2)| {
@@ -101,18 +122,32 @@ impl AdaptiveSampler {
             },
 
             AdaptiveTest::ContrastThreshold => {
+                if ls.is_empty() {
+                    return false;
+                }
+
                 let lavg = ls.iter().fold(0.0, |acc, l| {
                     acc + l.y()
-                });
+                }) / (ls.len() as f32);
 
                 let contrast_ratio = 0.5;
                 ls.iter().fold(false, |acc, l| {
                     acc || ((l.y() - lavg).abs() / lavg) > contrast_ratio
                 })
-            }
-        };
+            },
 
-        if needs_supersample {
+            AdaptiveTest::Variance(max_error) => relative_standard_error(ls) > max_error
+        }
+    }
+
+    pub fn report_results(&mut self, _samples: &Vec<Sample>,
+                          _rays: &Vec<RayDifferential>,
+                          ls: &Vec<Spectrum>, isects: &Vec<Intersection>,
+                          _sample_count: usize) -> bool {
+        // Pixels are only supersampled once, and then the sampler moves on
+        if self.supersample_pixel {
+            self.supersample_pixel = false;
+        } else if self.needs_supersample(ls, isects) {
             self.supersample_pixel = true;
             return false;
         }
@@ -126,3 +161,70 @@ impl AdaptiveSampler {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(sampler: &mut AdaptiveSampler, ls: &[f32]) -> bool {
+        let ls: Vec<Spectrum> = ls.iter().map(|&l| Spectrum::from(l)).collect();
+        sampler.report_results(&Vec::new(), &Vec::new(), &ls, &Vec::new(), ls.len())
+    }
+
+    #[test]
+    fn it_measures_the_relative_error_of_the_mean() {
+        let ls = |vs: &[f32]| vs.iter().map(|&v| Spectrum::from(v)).collect::<Vec<_>>();
+        assert_eq!(relative_standard_error(&ls(&[0.5, 0.5, 0.5, 0.5])), 0.0);
+        assert_eq!(relative_standard_error(&ls(&[0.0, 0.0])), 0.0);
+
+        // A variance of 1/3 over four samples with a mean of 1/2
+        let err = relative_standard_error(&ls(&[0.0, 1.0, 0.0, 1.0]));
+        assert!((err - 2.0 * (1.0f32 / 12.0).sqrt()).abs() < 1e-5, "{}", err);
+    }
+
+    #[test]
+    fn it_supersamples_noisy_pixels_once() {
+        let mut sampler = AdaptiveSampler::new(0, 2, 0, 1, 4, 32, AdaptiveTest::Variance(0.1),
+                                               false, 0.0, 1.0);
+        let mut samples = vec![Sample::empty(); 32];
+        let mut rng = RNG::new(0);
+
+        // A pixel that's the same everywhere is done after its first samples
+        assert_eq!(sampler.get_more_samples(&mut samples, &mut rng), 4);
+        assert!(report(&mut sampler, &[0.5, 0.5, 0.5, 0.5]));
+
+        // A noisy pixel is taken again with more samples, and then the
+        // sampler moves on even if it's still noisy.
+        assert_eq!(sampler.get_more_samples(&mut samples, &mut rng), 4);
+        assert_eq!(samples[0].camera_sample.image_pos().0.floor(), 1.0);
+        assert!(!report(&mut sampler, &[0.0, 1.0, 0.0, 1.0]));
+        assert_eq!(sampler.get_more_samples(&mut samples, &mut rng), 32);
+        assert_eq!(samples[31].camera_sample.image_pos().0.floor(), 1.0);
+        assert!(report(&mut sampler, &[0.0, 1.0, 0.0, 1.0]));
+        assert_eq!(sampler.get_more_samples(&mut samples, &mut rng), 0);
+    }
+
+    #[test]
+    fn its_error_threshold_decides_what_is_noisy() {
+        let mut lenient = AdaptiveSampler::new(0, 1, 0, 1, 4, 32, AdaptiveTest::Variance(1.0),
+                                               false, 0.0, 1.0);
+        assert!(report(&mut lenient, &[0.0, 1.0, 0.0, 1.0]));
+
+        let mut strict = AdaptiveSampler::new(0, 1, 0, 1, 4, 32, AdaptiveTest::Variance(0.1),
+                                              false, 0.0, 1.0);
+        assert!(!report(&mut strict, &[0.0, 1.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn its_contrast_test_compares_against_the_average() {
+        let mut sampler = AdaptiveSampler::new(0, 1, 0, 1, 4, 32,
+                                               AdaptiveTest::ContrastThreshold,
+                                               false, 0.0, 1.0);
+        assert!(report(&mut sampler, &[1.0, 1.1, 0.9, 1.0]));
+
+        let mut sampler = AdaptiveSampler::new(0, 1, 0, 1, 4, 32,
+                                               AdaptiveTest::ContrastThreshold,
+                                               false, 0.0, 1.0);
+        assert!(!report(&mut sampler, &[1.0, 3.0, 1.0, 1.0]));
+    }
+}
@@ -1,5 +1,5 @@
-use utils::Lerp;
-use utils::get_crop_window;
+use crate::utils::Lerp;
+use crate::utils::get_crop_window;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct SamplerBase {
@@ -9,7 +9,10 @@ pub struct SamplerBase {
     pub y_pixel_end: i32,
     pub samples_per_pixel: usize,
     pub shutter_open: f32,
-    pub shutter_close: f32
+    pub shutter_close: f32,
+
+    // Whether the samples of each pixel are shifted by a blue noise mask
+    pub blue_noise: bool
 }
 
 impl SamplerBase {
@@ -22,7 +25,8 @@ impl SamplerBase {
             y_pixel_end: y_end,
             samples_per_pixel: spp,
             shutter_open: sopen,
-            shutter_close: sclose
+            shutter_close: sclose,
+            blue_noise: false
         }
     }
 
@@ -0,0 +1,223 @@
+use std::sync::OnceLock;
+
+use crate::camera::CameraSample;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::utils::Lerp;
+
+// The mask is a MASK_SIZE x MASK_SIZE tile that repeats over the image
+const MASK_SIZE: usize = 64;
+
+// The standard deviation, in pixels, of the gaussian that the void and
+// cluster method uses to measure how crowded each pixel's neighborhood is,
+// and how far out the gaussian is evaluated.
+const SIGMA: f32 = 1.5;
+const KERNEL_RADIUS: i32 = 6;
+
+static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+
+// The energy of the pixels near (x, y) is changed by sign times the
+// gaussian centered at (x, y), wrapping around the tile.
+fn splat(energy: &mut [f32], x: usize, y: usize, sign: f32) {
+    let n = MASK_SIZE as i32;
+    for dy in -KERNEL_RADIUS..(KERNEL_RADIUS + 1) {
+        for dx in -KERNEL_RADIUS..(KERNEL_RADIUS + 1) {
+            let px = (x as i32 + dx).rem_euclid(n) as usize;
+            let py = (y as i32 + dy).rem_euclid(n) as usize;
+            let d2 = (dx * dx + dy * dy) as f32;
+            energy[py * MASK_SIZE + px] += sign * (-d2 / (2.0 * SIGMA * SIGMA)).exp();
+        }
+    }
+}
+
+// The pixel that's set to on with the highest energy is the tightest
+// cluster, and the one that's off with the lowest energy is the largest
+// void.
+fn tightest_cluster(on: &[bool], energy: &[f32]) -> usize {
+    (0..on.len()).filter(|&i| on[i])
+        .max_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+        .unwrap()
+}
+
+fn largest_void(on: &[bool], energy: &[f32]) -> usize {
+    (0..on.len()).filter(|&i| !on[i])
+        .min_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+        .unwrap()
+}
+
+fn toggle(on: &mut [bool], energy: &mut [f32], i: usize) {
+    on[i] = !on[i];
+    splat(energy, i % MASK_SIZE, i / MASK_SIZE, if on[i] { 1.0 } else { -1.0 });
+}
+
+// Ulichney's void and cluster method: pixels are ranked by the order in
+// which they'd be turned on so that the ones that are on are always as
+// evenly spread out as possible. Ranks are returned as values in [0, 1).
+fn void_and_cluster() -> Vec<f32> {
+    let n = MASK_SIZE * MASK_SIZE;
+    let mut on = vec![false; n];
+    let mut energy = vec![0.0; n];
+
+    // Start with a tenth of the pixels turned on at random, and then spread
+    // them out by moving the tightest cluster to the largest void until
+    // that doesn't change anything.
+    let mut rng = RNG::new(0);
+    let num_initial = n / 10;
+    let mut num_on = 0;
+    while num_on < num_initial {
+        let i = rng.random_uint() % n;
+        if !on[i] {
+            toggle(&mut on, &mut energy, i);
+            num_on += 1;
+        }
+    }
+
+    loop {
+        let cluster = tightest_cluster(&on, &energy);
+        toggle(&mut on, &mut energy, cluster);
+        let void = largest_void(&on, &energy);
+        toggle(&mut on, &mut energy, void);
+        if void == cluster { break; }
+    }
+
+    let mut ranks = vec![0; n];
+
+    // Rank the initial pixels by taking away the tightest clusters...
+    let (prototype, prototype_energy) = (on.clone(), energy.clone());
+    for rank in (0..num_initial).rev() {
+        let cluster = tightest_cluster(&on, &energy);
+        toggle(&mut on, &mut energy, cluster);
+        ranks[cluster] = rank;
+    }
+
+    // ... and the rest by filling in the largest voids. Once more than half
+    // of the pixels are on, the largest void of the ones that are on is the
+    // tightest cluster of the ones that are off, so this also ranks them.
+    on = prototype;
+    energy = prototype_energy;
+    for rank in num_initial..n {
+        let void = largest_void(&on, &energy);
+        toggle(&mut on, &mut energy, void);
+        ranks[void] = rank;
+    }
+
+    ranks.into_iter().map(|r| (r as f32 + 0.5) / (n as f32)).collect()
+}
+
+// Each dimension looks the mask up at a different offset, following the
+// R2 sequence, so that the offsets of different dimensions of the same
+// pixel aren't correlated.
+fn mask_offset(x: i32, y: i32, dim: usize) -> f32 {
+    let mask = MASK.get_or_init(void_and_cluster);
+    let n = MASK_SIZE as f32;
+    let ox = (n * (dim as f32 * 0.754_877_7).fract()) as i32;
+    let oy = (n * (dim as f32 * 0.569_840_3).fract()) as i32;
+    let px = (x + ox).rem_euclid(MASK_SIZE as i32) as usize;
+    let py = (y + oy).rem_euclid(MASK_SIZE as i32) as usize;
+    mask[py * MASK_SIZE + px]
+}
+
+fn shift(u: f32, offset: f32) -> f32 {
+    let s = u + offset;
+    if s >= 1.0 { s - 1.0 } else { s }
+}
+
+// Shifts all of the dimensions of the samples other than their position on
+// the image by an offset that's the same for every sample of a pixel, but
+// is blue noise across the image, wrapping the values around [0, 1). This
+// keeps each pixel's samples stratified, while the error of neighboring
+// pixels is decorrelated into high frequency noise instead of structured
+// artifacts.
+pub fn dither_samples(samples: &mut [Sample], shutter_open: f32, shutter_close: f32) {
+    for sample in samples.iter_mut() {
+        let (image_x, image_y) = sample.camera_sample.image_pos();
+        let (x, y) = (image_x.floor() as i32, image_y.floor() as i32);
+        let (lens_u, lens_v) = sample.camera_sample.lens_pos();
+
+        // Take the time back to [0, 1) before shifting it
+        let time = if shutter_close > shutter_open {
+            let t = (sample.camera_sample.time() - shutter_open) /
+                (shutter_close - shutter_open);
+            shutter_open.lerp(&shutter_close, shift(t, mask_offset(x, y, 2)))
+        } else {
+            sample.camera_sample.time()
+        };
+
+        sample.camera_sample =
+            CameraSample::new(image_x, image_y,
+                              shift(lens_u, mask_offset(x, y, 0)),
+                              shift(lens_v, mask_offset(x, y, 1)),
+                              time);
+
+        for (i, u) in sample.samples.iter_mut().enumerate() {
+            *u = shift(*u, mask_offset(x, y, 3 + i));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn its_mask_ranks_every_pixel_once() {
+        let mask = MASK.get_or_init(void_and_cluster);
+        let n = MASK_SIZE * MASK_SIZE;
+        let mut ranks: Vec<usize> = mask.iter().map(|&v| (v * n as f32) as usize).collect();
+        ranks.sort();
+        assert_eq!(ranks, (0..n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn its_lowest_ranked_pixels_are_evenly_spread_out() {
+        // The pixels in the first sixteenth of the ranks are about four
+        // pixels apart, and none of them should be right next to another.
+        let mask = MASK.get_or_init(void_and_cluster);
+        let low: Vec<(i32, i32)> = (0..mask.len())
+            .filter(|&i| mask[i] < 1.0 / 16.0)
+            .map(|i| ((i % MASK_SIZE) as i32, (i / MASK_SIZE) as i32))
+            .collect();
+
+        let n = MASK_SIZE as i32;
+        for (i, &(x0, y0)) in low.iter().enumerate() {
+            for &(x1, y1) in low[(i + 1)..].iter() {
+                let dx = (x0 - x1).abs().min(n - (x0 - x1).abs());
+                let dy = (y0 - y1).abs().min(n - (y0 - y1).abs());
+                assert!(dx * dx + dy * dy >= 4, "{:?} {:?}", (x0, y0), (x1, y1));
+            }
+        }
+    }
+
+    #[test]
+    fn it_shifts_every_sample_of_a_pixel_the_same_way() {
+        let sample = |x: f32, y: f32, u: f32| {
+            let mut s = Sample::empty();
+            s.camera_sample = CameraSample::new(x, y, u, u, 1.0 + u);
+            s.samples = vec![u; 4];
+            s
+        };
+
+        let mut samples = vec![sample(3.25, 7.5, 0.0), sample(3.75, 7.25, 0.5),
+                               sample(4.5, 7.5, 0.0)];
+        dither_samples(&mut samples, 1.0, 2.0);
+
+        let values = |s: &Sample| {
+            let (u, v) = s.camera_sample.lens_pos();
+            let mut vs = vec![u, v, s.camera_sample.time() - 1.0];
+            vs.extend(s.samples.iter().cloned());
+            vs
+        };
+
+        // Samples half a period apart in the same pixel stay that way
+        let (a, b) = (values(&samples[0]), values(&samples[1]));
+        for (&a, &b) in a.iter().zip(b.iter()) {
+            assert!(a >= 0.0 && a < 1.0 && b >= 0.0 && b < 1.0);
+            assert!(((a - b).abs() - 0.5).abs() < 1e-5, "{} {}", a, b);
+        }
+
+        // The image position isn't moved, and the next pixel over is
+        // shifted by different amounts
+        assert_eq!(samples[1].camera_sample.image_pos(), (3.75, 7.25));
+        assert_ne!(values(&samples[0]), values(&samples[2]));
+    }
+}
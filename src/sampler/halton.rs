@@ -1,96 +1,274 @@
-use camera::CameraSample;
-use rng::RNG;
-use sampler::base::SamplerBase;
-use sampler::sample::Sample;
-use utils::Lerp;
+use std::sync::OnceLock;
 
-use montecarlo::radical_inverse;
-use montecarlo::latin_hypercube;
+use crate::camera::CameraSample;
+use crate::rng::RNG;
+use crate::sampler::base::SamplerBase;
+use crate::sampler::sample::Sample;
+use crate::utils::Lerp;
 
+use crate::montecarlo::radical_inverse;
+use crate::montecarlo::permuted_radical_inverse;
+use crate::montecarlo::latin_hypercube;
+
+// The bases of the dimensions of a camera sample: the image position, the
+// lens position and the time.
+const PRIMES: [usize; 5] = [2, 3, 5, 7, 11];
+
+// The image is covered with blocks of at most this many pixels on a side,
+// and the sequence visits every pixel of a block before it returns to any
+// of them.
+const MAX_RESOLUTION: i32 = 128;
+
+static FAURE_PERMUTATIONS: OnceLock<Vec<Vec<usize>>> = OnceLock::new();
+
+// How the digits of the radical inverses of the dimensions after the image
+// position are scrambled. Plain radical inverses in large bases are
+// strongly correlated with each other for the first few hundred points,
+// which shows up as structured artifacts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HaltonScramble {
+    None,
+
+    // Faure's deterministic permutation of the digits in each base
+    Faure,
+
+    // Owen's nested scrambling, where each digit is permuted differently
+    // depending on the digits before it
+    Owen
+}
+
+// Faure's permutation of the digits in base b, which is built from the
+// permutations of smaller bases so that it spreads out the digits that
+// are next to each other.
+fn faure_permutation(b: usize) -> Vec<usize> {
+    if b == 2 {
+        return vec![0, 1];
+    }
+
+    let c = b / 2;
+    if b.is_multiple_of(2) {
+        // Double the permutation for b / 2 into the even and odd digits
+        let half = faure_permutation(c);
+        half.iter().map(|&x| 2 * x).chain(half.iter().map(|&x| 2 * x + 1)).collect()
+    } else {
+        // Make room for c in the permutation for b - 1, and put it in the middle
+        let mut p: Vec<usize> = faure_permutation(b - 1).into_iter()
+            .map(|x| if x >= c { x + 1 } else { x })
+            .collect();
+        p.insert(c, c);
+        p
+    }
+}
+
+fn mix_bits(v: u64) -> u64 {
+    let mut v = v;
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5_d329_728e_a185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81da_def4_bc2d_d44d);
+    v ^= v >> 33;
+    v
+}
+
+// The i-th element of the permutation of b digits that hash picks
+fn permutation_element(i: usize, b: usize, hash: u64) -> usize {
+    let mut p = [0; 16];
+    for (j, x) in p.iter_mut().enumerate().take(b) { *x = j; }
+
+    let mut h = hash;
+    for j in (1..b).rev() {
+        h = mix_bits(h.wrapping_add(j as u64));
+        p.swap(j, (h % (j as u64 + 1)) as usize);
+    }
+    p[i]
+}
+
+fn owen_scrambled_radical_inverse(n: usize, b: usize, seed: u64) -> f64 {
+    let inv_base = 1.0 / (b as f64);
+    let mut v = 0.0;
+    let mut num = n;
+    let mut aib = inv_base;
+
+    // The digits that came before, with a leading one so that the same
+    // digits at different depths are told apart. The zeros past the last
+    // digit of n are scrambled too, until they're too small to matter.
+    let mut prefix = 1u64;
+    while aib > (f32::EPSILON as f64) / 4.0 {
+        let d = num % b;
+        num /= b;
+        let hash = mix_bits(seed ^ mix_bits(prefix));
+        v += (permutation_element(d, b, hash) as f64) * aib;
+        prefix = prefix.wrapping_mul(b as u64).wrapping_add(d as u64);
+        aib *= inv_base;
+    }
+
+    v
+}
+
+// The index of the point in the first base^digits points of the radical
+// inverse in base whose first digits are the digits of inverse reversed.
+fn inverse_radical_inverse(inverse: usize, base: usize, digits: u32) -> usize {
+    let mut inverse = inverse;
+    let mut index = 0;
+    for _ in 0..digits {
+        index = index * base + inverse % base;
+        inverse /= base;
+    }
+    index
+}
+
+fn extended_gcd(a: i64, b: i64) -> (i64, i64) {
+    if b == 0 {
+        return (1, 0);
+    }
+
+    let (x, y) = extended_gcd(b, a % b);
+    (y, x - (a / b) * y)
+}
+
+// The x such that a x = 1 mod n
+fn multiplicative_inverse(a: usize, n: usize) -> usize {
+    let (x, _) = extended_gcd(a as i64, n as i64);
+    x.rem_euclid(n as i64) as usize
+}
+
+// The number of digits of base needed to tell the pixels of res apart,
+// and base to that power.
+fn base_exponent(base: usize, res: i32) -> (u32, usize) {
+    let (mut exp, mut scale) = (0, 1);
+    while (scale as i32) < res.min(MAX_RESOLUTION) {
+        exp += 1;
+        scale *= base;
+    }
+    (exp, scale)
+}
+
+// Generates the samples of each pixel from a single Halton sequence over the
+// whole image, following pbrt-v3: the first two dimensions, in bases 2 and
+// 3, are scaled so that the image is tiled by blocks of pixels that each
+// have sample_stride samples, and each pixel gets every sample_stride-th
+// point of the sequence starting at an offset that's found by inverting
+// the radical inverse of its position in the block. The dimensions after
+// that are scrambled so that the pixels' samples aren't correlated.
 #[derive(Debug, Clone, PartialEq)]
 pub struct HaltonSampler {
     base: SamplerBase,
-    wanted_samples: usize,
-    current_sample: usize
+    scramble: HaltonScramble,
+    base_exponents: (u32, u32),
+    base_scales: (usize, usize),
+    mult_inverse: (usize, usize),
+    sample_stride: usize,
+    x_pos: i32,
+    y_pos: i32
 }
 
 impl HaltonSampler {
     pub fn new(x_start: i32, x_end: i32, y_start: i32, y_end: i32,
-               samples_per_pixel: usize, sopen: f32, sclose: f32) -> HaltonSampler {
-        let dx = x_end - x_start;
-        let dy = y_end - y_start;
-        let num_samples = (|x| x * x)(if dx > dy { dx } else { dy }) as usize;
+               samples_per_pixel: usize, scramble: HaltonScramble,
+               sopen: f32, sclose: f32) -> HaltonSampler {
+        let (exp_x, scale_x) = base_exponent(PRIMES[0], x_end - x_start);
+        let (exp_y, scale_y) = base_exponent(PRIMES[1], y_end - y_start);
         HaltonSampler {
             base: SamplerBase::new(x_start, x_end, y_start, y_end,
                                    samples_per_pixel, sopen, sclose),
-            wanted_samples: num_samples * samples_per_pixel,
-            current_sample: 0
+            scramble,
+            base_exponents: (exp_x, exp_y),
+            base_scales: (scale_x, scale_y),
+            mult_inverse: (multiplicative_inverse(scale_y, scale_x),
+                           multiplicative_inverse(scale_x, scale_y)),
+            sample_stride: scale_x * scale_y,
+            x_pos: x_start,
+            y_pos: y_start
         }
     }
 
     pub fn base(&self) -> &SamplerBase { &self.base }
+    pub fn base_mut(&mut self) -> &mut SamplerBase { &mut self.base }
 
-    pub fn maximum_sample_count(&self) -> usize { 1 }
+    pub fn maximum_sample_count(&self) -> usize { self.base.samples_per_pixel }
 
     pub fn get_sub_sampler(&self, num: usize,
                            count: usize) -> Option<HaltonSampler> {
         let (x0, x1, y0, y1) = self.base.compute_sub_window(num, count);
+        self.get_window_sampler(x0, x1, y0, y1)
+    }
+
+    // Samplers for part of the image keep the blocks of the whole image, so
+    // that they generate the same samples for each pixel.
+    pub fn get_window_sampler(&self, x0: i32, x1: i32,
+                              y0: i32, y1: i32) -> Option<HaltonSampler> {
         if x0 == x1 || y0 == y1 {
             None
         } else {
-            Some(HaltonSampler::new(x0, x1, y0, y1,
-                                    self.base.samples_per_pixel,
-                                    self.base.shutter_open,
-                                    self.base.shutter_close))
-        }        
-    }    
-
-    pub fn get_more_samples(&mut self, samples: &mut Vec<Sample>,
-                            rng: &mut RNG) -> usize {
-        loop {
-            let mut sample: &mut Sample = samples.get_mut(0).unwrap();
+            Some(HaltonSampler {
+                base: SamplerBase::new(x0, x1, y0, y1,
+                                       self.base.samples_per_pixel,
+                                       self.base.shutter_open,
+                                       self.base.shutter_close),
+                x_pos: x0,
+                y_pos: y0,
+                ..self.clone()
+            })
+        }
+    }
 
-            if self.current_sample == self.wanted_samples {
-                return 0;
-            }
+    // The index into the sequence of the num-th sample of pixel (x, y)
+    fn index_for_sample(&self, x: i32, y: i32, num: usize) -> usize {
+        let (scale_x, scale_y) = self.base_scales;
+        let px = x.rem_euclid(scale_x as i32) as usize;
+        let py = y.rem_euclid(scale_y as i32) as usize;
 
-            // Generate sample with halton sequence and reject if outside image extent
-            let u = radical_inverse(self.current_sample, 3) as f32;
-            let v = radical_inverse(self.current_sample, 2) as f32;
-
-            let lerp_delta = {
-                let dx = (self.base.x_pixel_end - self.base.x_pixel_start) as f32;
-                let dy = (self.base.y_pixel_end - self.base.y_pixel_start) as f32;
-                dy.max(dx)
-            };
-
-            let image_x = (self.base.x_pixel_start as f32)
-                .lerp(&((self.base.x_pixel_start as f32) + lerp_delta), u);
-            let image_y = (self.base.y_pixel_start as f32)
-                .lerp(&((self.base.y_pixel_start as f32) + lerp_delta), v);
-            self.current_sample += 1;
-
-            if image_x >= (self.base.x_pixel_end as f32) ||
-               image_y >= (self.base.y_pixel_end as f32)
-            {
-                continue;
-            }
+        let offset_x = inverse_radical_inverse(px, PRIMES[0], self.base_exponents.0);
+        let offset_y = inverse_radical_inverse(py, PRIMES[1], self.base_exponents.1);
+        let offset = (offset_x * (self.sample_stride / scale_x) * self.mult_inverse.0 +
+                      offset_y * (self.sample_stride / scale_y) * self.mult_inverse.1) %
+            self.sample_stride;
+        offset + num * self.sample_stride
+    }
 
-            // Generate lens time and integrator samples for HaltonSampler
-            let lens_u = radical_inverse(self.current_sample, 5) as f32;
-            let lens_v = radical_inverse(self.current_sample, 7) as f32;
-            let t = self.base.shutter_open.lerp(
-                &self.base.shutter_close,
-                radical_inverse(self.current_sample, 11) as f32);
+    fn sample_dimension(&self, index: usize, dim: usize) -> f32 {
+        (self.radical_inverse(index, dim) as f32).min(1.0 - f32::EPSILON)
+    }
 
+    fn radical_inverse(&self, index: usize, dim: usize) -> f64 {
+        let b = PRIMES[dim];
+        match (dim, self.scramble) {
+            // The digits that pick the pixel are shifted out of the image
+            // position, leaving its position in the pixel
+            (0, _) => radical_inverse(index >> self.base_exponents.0, b),
+            (1, _) => radical_inverse(index / self.base_scales.1, b),
+            (_, HaltonScramble::None) => radical_inverse(index, b),
+            (_, HaltonScramble::Faure) => {
+                let perms = FAURE_PERMUTATIONS.get_or_init(|| {
+                    PRIMES.iter().map(|&p| faure_permutation(p)).collect()
+                });
+                permuted_radical_inverse(index, b, &perms[dim])
+            },
+            (_, HaltonScramble::Owen) =>
+                owen_scrambled_radical_inverse(index, b, mix_bits(dim as u64))
+        }
+    }
+
+    pub fn get_more_samples(&mut self, samples: &mut [Sample],
+                            rng: &mut RNG) -> usize {
+        if self.y_pos == self.base.y_pixel_end { return 0 }
+
+        let (x, y) = (self.x_pos, self.y_pos);
+        for (i, sample) in samples.iter_mut().take(self.base.samples_per_pixel).enumerate() {
+            let index = self.index_for_sample(x, y, i);
+            let t = self.base.shutter_open.lerp(&self.base.shutter_close,
+                                                self.sample_dimension(index, 4));
             sample.camera_sample = CameraSample::new(
-                image_x, image_y, lens_u, lens_v, t);
+                x as f32 + self.sample_dimension(index, 0),
+                y as f32 + self.sample_dimension(index, 1),
+                self.sample_dimension(index, 2),
+                self.sample_dimension(index, 3), t);
 
             let sz_and_off_1d: Vec<(usize, usize)> = sample.num_1d.iter().zip(
                 sample.offset_1d.iter()).map(|(x, y)| (*x, *y)).collect();
 
             for (num, off) in sz_and_off_1d {
-                let (oned, _) = sample.samples.split_at_mut(off);
+                let (_, oned) = sample.samples.split_at_mut(off);
                 latin_hypercube(oned, num, 1, rng);
             }
 
@@ -98,15 +276,108 @@ impl HaltonSampler {
                 sample.offset_2d.iter()).map(|(x, y)| (*x, *y)).collect();
 
             for (num, off) in sz_and_off_2d {
-                let (twod, _) = sample.samples.split_at_mut(off);
+                let (_, twod) = sample.samples.split_at_mut(off);
                 latin_hypercube(twod, num, 2, rng);
             }
+        }
 
-            return 1;
+        self.x_pos += 1;
+        if self.x_pos == self.base.x_pixel_end {
+            self.x_pos = self.base.x_pixel_start;
+            self.y_pos += 1;
         }
+
+        self.base.samples_per_pixel.min(samples.len())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn image_positions(sampler: &mut HaltonSampler) -> Vec<(f32, f32)> {
+        let mut samples = vec![Sample::empty(); sampler.maximum_sample_count()];
+        let mut rng = RNG::new(0);
+        let mut pos = Vec::new();
+        loop {
+            let n = sampler.get_more_samples(&mut samples, &mut rng);
+            if n == 0 { return pos; }
+            pos.extend(samples[..n].iter().map(|s| s.camera_sample.image_pos()));
+        }
+    }
+
+    #[test]
+    fn it_computes_faure_permutations() {
+        assert_eq!(faure_permutation(3), vec![0, 1, 2]);
+        assert_eq!(faure_permutation(4), vec![0, 2, 1, 3]);
+        assert_eq!(faure_permutation(5), vec![0, 3, 2, 1, 4]);
+        assert_eq!(faure_permutation(7), vec![0, 2, 5, 3, 1, 4, 6]);
+    }
+
+    #[test]
+    fn it_gives_every_pixel_its_own_samples() {
+        let mut sampler = HaltonSampler::new(-2, 5, 1, 4, 4, HaltonScramble::Owen, 0.0, 1.0);
+        let pos = image_positions(&mut sampler);
+        assert_eq!(pos.len(), 7 * 3 * 4);
+
+        for (i, pixel) in pos.chunks(4).enumerate() {
+            let (x, y) = ((i % 7) as f32 - 2.0, (i / 7) as f32 + 1.0);
+            assert!(pixel.iter().all(|p| p.0.floor() == x && p.1.floor() == y));
+        }
+
+        // The samples of each pixel are the ones it gets as part of the
+        // sequence over the whole image, and aren't the same in every pixel
+        let mut pixel_sampler = sampler.get_window_sampler(3, 4, 2, 3).unwrap();
+        assert_eq!(image_positions(&mut pixel_sampler), pos[(4 * 12)..(4 * 13)].to_vec());
+
+        let offsets = |p: &[(f32, f32)]| p.iter().map(|q| q.0.fract()).collect::<Vec<_>>();
+        assert_ne!(offsets(&pos[0..4]), offsets(&pos[4..8]));
+    }
+
+    #[test]
+    fn its_pixels_together_cover_the_sequence_once() {
+        // A 2x3 block of pixels has one of every six points of the sequence
+        let sampler = HaltonSampler::new(0, 2, 0, 3, 2, HaltonScramble::None, 0.0, 1.0);
+        assert_eq!(sampler.sample_stride, 6);
+        let mut indices: Vec<usize> = (0..2).flat_map(|x| (0..3).flat_map(move |y| {
+            (0..2).map(move |n| (x, y, n))
+        })).map(|(x, y, n)| sampler.index_for_sample(x, y, n)).collect();
+        indices.sort();
+        assert_eq!(indices, (0..12).collect::<Vec<_>>());
+
+        for x in 0..2 {
+            for y in 0..3 {
+                let i = sampler.index_for_sample(x, y, 1);
+                assert_eq!((2.0 * radical_inverse(i, 2)) as i32, x);
+                assert_eq!((3.0 * radical_inverse(i, 3)) as i32, y);
+            }
+        }
+    }
+
+    #[test]
+    fn its_scrambled_dimensions_stay_stratified() {
+        // The first b^2 points of every dimension fall in different
+        // intervals of width 1 / b^2, however they are scrambled.
+        for scramble in [HaltonScramble::None, HaltonScramble::Faure,
+                         HaltonScramble::Owen].iter() {
+            let sampler = HaltonSampler::new(0, 1, 0, 1, 1, *scramble, 0.0, 1.0);
+            for dim in 2..5 {
+                let n = PRIMES[dim] * PRIMES[dim];
+                let mut cells: Vec<usize> = (0..n)
+                    .map(|i| (sampler.radical_inverse(i, dim) * n as f64 + 1e-9) as usize)
+                    .collect();
+                cells.sort();
+                assert_eq!(cells, (0..n).collect::<Vec<_>>(), "{:?} {}", scramble, dim);
+            }
+        }
+
+        // Scrambling changes the points
+        let plain = HaltonSampler::new(0, 1, 0, 1, 1, HaltonScramble::None, 0.0, 1.0);
+        let owen = HaltonSampler::new(0, 1, 0, 1, 1, HaltonScramble::Owen, 0.0, 1.0);
+        let faure = HaltonSampler::new(0, 1, 0, 1, 1, HaltonScramble::Faure, 0.0, 1.0);
+        let points = |s: &HaltonSampler| (1..20).map(|i| s.sample_dimension(i, 3))
+            .collect::<Vec<_>>();
+        assert_ne!(points(&plain), points(&owen));
+        assert_ne!(points(&plain), points(&faure));
+    }
 }
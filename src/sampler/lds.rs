@@ -1,8 +1,8 @@
-use rng::RNG;
-use sampler::sample::Sample;
-use sampler::base::SamplerBase;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::sampler::base::SamplerBase;
 
-use sampler::utils::*;
+use crate::sampler::utils::*;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct LDSampler {
@@ -30,6 +30,7 @@ impl LDSampler {
     }
 
     pub fn base(&self) -> &SamplerBase { &self.base }
+    pub fn base_mut(&mut self) -> &mut SamplerBase { &mut self.base }
 
     pub fn maximum_sample_count(&self) -> usize {
         self.base.samples_per_pixel
@@ -37,6 +38,11 @@ impl LDSampler {
 
     pub fn get_sub_sampler(&self, num: usize, count:usize) -> Option<LDSampler> {
         let (x0, x1, y0, y1) = self.base.compute_sub_window(num, count);
+        self.get_window_sampler(x0, x1, y0, y1)
+    }
+
+    pub fn get_window_sampler(&self, x0: i32, x1: i32,
+                              y0: i32, y1: i32) -> Option<LDSampler> {
         if x0 == x1 || y0 == y1 {
             None
         } else {
@@ -0,0 +1,236 @@
+use crate::rng::RNG;
+use crate::sampler::base::SamplerBase;
+use crate::sampler::sample::Sample;
+
+use crate::sampler::utils::*;
+
+// Generator matrices C for n = 2^m image samples, indexed by m, in the
+// spirit of Gruenschloss et al.'s "(t, m, s)-Nets and Maximized Minimum
+// Distance". C has m columns, each a u32 whose most significant bit is
+// the first row, and the points (i / n, C i) form a (0, m, 2)-net. These
+// aren't the matrices from the paper: they were found with a local search
+// over such nets for the one whose points are the farthest apart on the
+// torus.
+const MAX_MIN_DIST: [&[u32]; 17] = [
+    &[],
+    &[0x80000000],
+    &[0x80000000, 0x40000000],
+    &[0xa0000000, 0x40000000, 0x80000000],
+    &[0xc0000000, 0x50000000, 0xe0000000, 0xf0000000],
+    &[0x88000000, 0x50000000, 0x20000000, 0x30000000, 0x78000000],
+    &[0xbc000000, 0x64000000, 0x2c000000, 0x78000000, 0x70000000, 0x7c000000],
+    &[0xd6000000, 0x96000000, 0x24000000, 0xe2000000, 0xfe000000, 0x06000000,
+      0xfc000000],
+    &[0xf3000000, 0x80000000, 0xa6000000, 0xe5000000, 0x1f000000, 0xeb000000,
+      0x84000000, 0xf6000000],
+    &[0xe2000000, 0x64000000, 0xa7000000, 0x97000000, 0x7d800000, 0x7b800000,
+      0x67000000, 0x65800000, 0xe2800000],
+    &[0x86800000, 0xc0000000, 0x20000000, 0x5c000000, 0x75400000, 0xb9400000,
+      0x3cc00000, 0xc1c00000, 0x3bc00000, 0x41c00000],
+    &[0xcb400000, 0x9b400000, 0xeb400000, 0x30000000, 0x5c000000, 0x07400000,
+      0x63800000, 0x62800000, 0x33000000, 0xd6200000, 0x2e200000],
+    &[0xcee00000, 0x5fa00000, 0x38b00000, 0x26b00000, 0x71400000, 0x7f400000,
+      0x15200000, 0x5ec00000, 0xe9f00000, 0xb7500000, 0xb7700000, 0x37600000],
+    &[0xf0880000, 0x44080000, 0x21380000, 0xec800000, 0x16d80000, 0xc1f80000,
+      0x76600000, 0x53e80000, 0x65d80000, 0x9aa00000, 0x86700000, 0x40480000,
+      0x8da00000],
+    &[0x8adc0000, 0xe4700000, 0xcaa40000, 0x7e1c0000, 0x20580000, 0x15fc0000,
+      0xdc480000, 0x7a080000, 0xa5c80000, 0x04d00000, 0x36980000, 0x5c180000,
+      0xea840000, 0x1a480000],
+    &[0xec1a0000, 0xbb060000, 0x7bbc0000, 0xa39e0000, 0x9bb80000, 0x26d00000,
+      0xef140000, 0xe1ca0000, 0x3ed20000, 0x12100000, 0x34760000, 0x83ae0000,
+      0x894c0000, 0xd24a0000, 0xf8d40000],
+    &[0xd4dc0000, 0x45850000, 0xb1bc0000, 0x1f940000, 0xa57e0000, 0x49c40000,
+      0x99040000, 0x6b8a0000, 0x4be00000, 0xb1550000, 0xde640000, 0xd0180000,
+      0xfb020000, 0x2b350000, 0x75950000, 0xfbcf0000]
+];
+
+// The largest number of samples per pixel that there's a matrix for
+const MAX_SAMPLES: usize = 1 << (MAX_MIN_DIST.len() - 1);
+
+fn multiply_generator(c: &[u32], a: u32) -> u32 {
+    let mut v = 0;
+    let mut a = a;
+    for col in c.iter() {
+        if a == 0 { break; }
+        if (a & 0x1) != 0 { v ^= col; }
+        a >>= 1;
+    }
+    v
+}
+
+fn sample_generator_matrix(c: &[u32], a: u32) -> f32 {
+    (((multiply_generator(c, a) >> 8) & 0xffffff) as f64 / ((1 << 24) as f64)) as f32
+}
+
+// The image samples of a pixel are the net for its number of samples, in
+// a random order so that they aren't correlated with the samples of the
+// other dimensions.
+fn max_min_dist_image_samples(num_samples: usize, samples: &mut [f32], rng: &mut RNG) {
+    let c = MAX_MIN_DIST[num_samples.trailing_zeros() as usize];
+    let inv_spp = 1.0 / (num_samples as f32);
+    for i in 0..num_samples {
+        samples[2 * i] = (i as f32) * inv_spp;
+        samples[2 * i + 1] = sample_generator_matrix(c, i as u32);
+    }
+
+    rng.shuffle(&mut samples[..(2 * num_samples)], 2);
+}
+
+// A sampler whose image samples maximize the smallest distance between
+// any two of them, while still being stratified like a (0, m, 2)-net.
+// The samples of the other dimensions are the same as LDSampler's.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaxMinDistSampler {
+    base: SamplerBase,
+    x_pos: i32,
+    y_pos: i32,
+    sample_buf: Vec<f32>
+}
+
+impl MaxMinDistSampler {
+    pub fn new(x_start: i32, x_end: i32, y_start: i32, y_end: i32,
+               samples_per_pixel: usize, sopen: f32, sclose: f32) -> MaxMinDistSampler {
+        let mut ps = samples_per_pixel.next_power_of_two();
+        if !samples_per_pixel.is_power_of_two() {
+            print!("Warning -- ");
+            println!("MaxMinDistSampler using next power of two ({:?}) samples per pixel",
+                     ps);
+        }
+
+        if ps > MAX_SAMPLES {
+            print!("Warning -- ");
+            println!("MaxMinDistSampler only supports up to {:?} samples per pixel",
+                     MAX_SAMPLES);
+            ps = MAX_SAMPLES;
+        }
+
+        MaxMinDistSampler {
+            base: SamplerBase::new(x_start, x_end, y_start, y_end, ps, sopen, sclose),
+            x_pos: x_start,
+            y_pos: y_start,
+            sample_buf: Vec::new()
+        }
+    }
+
+    pub fn base(&self) -> &SamplerBase { &self.base }
+    pub fn base_mut(&mut self) -> &mut SamplerBase { &mut self.base }
+
+    pub fn maximum_sample_count(&self) -> usize {
+        self.base.samples_per_pixel
+    }
+
+    pub fn get_sub_sampler(&self, num: usize, count: usize) -> Option<MaxMinDistSampler> {
+        let (x0, x1, y0, y1) = self.base.compute_sub_window(num, count);
+        self.get_window_sampler(x0, x1, y0, y1)
+    }
+
+    pub fn get_window_sampler(&self, x0: i32, x1: i32,
+                              y0: i32, y1: i32) -> Option<MaxMinDistSampler> {
+        if x0 == x1 || y0 == y1 {
+            None
+        } else {
+            Some(MaxMinDistSampler::new(x0, x1, y0, y1,
+                                        self.base.samples_per_pixel,
+                                        self.base.shutter_open,
+                                        self.base.shutter_close))
+        }
+    }
+
+    pub fn get_more_samples(&mut self, samples: &mut [Sample],
+                            rng: &mut RNG) -> usize {
+        assert!(!samples.is_empty());
+        if self.y_pos == self.base.y_pixel_end { return 0 }
+
+        let ns = ld_pixel_sample_floats_needed(&samples[0], self.base.samples_per_pixel);
+        self.sample_buf.resize(ns, 0f32);
+
+        pixel_sample_with_image_samples(self.x_pos, self.y_pos, self.base.shutter_open,
+                                        self.base.shutter_close,
+                                        self.base.samples_per_pixel, samples,
+                                        &mut self.sample_buf, rng,
+                                        max_min_dist_image_samples);
+
+        self.x_pos += 1;
+        if self.x_pos == self.base.x_pixel_end {
+            self.x_pos = self.base.x_pixel_start;
+            self.y_pos += 1;
+        }
+
+        self.base.samples_per_pixel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_samples(m: usize) -> Vec<(f32, f32)> {
+        let n = 1 << m;
+        let mut buf = vec![0.0; 2 * n];
+        max_min_dist_image_samples(n, &mut buf, &mut RNG::new(0));
+        buf.chunks(2).map(|p| (p[0], p[1])).collect()
+    }
+
+    #[test]
+    fn its_image_samples_are_stratified_in_every_elementary_interval() {
+        for m in 0..11 {
+            let pts = image_samples(m);
+            let n = pts.len();
+            for k in 0..(m + 1) {
+                // Intervals that are 2^-k wide and 2^(k - m) tall
+                let (nx, ny) = (1 << k, 1 << (m - k));
+                let mut counts = vec![0; n];
+                for &(x, y) in pts.iter() {
+                    assert!(x >= 0.0 && x < 1.0 && y >= 0.0 && y < 1.0);
+                    let cell = ((x * nx as f32) as usize) * ny + (y * ny as f32) as usize;
+                    counts[cell] += 1;
+                }
+                assert!(counts.iter().all(|&c| c == 1), "m = {}, k = {}", m, k);
+            }
+        }
+    }
+
+    #[test]
+    fn its_image_samples_are_farther_apart_than_the_hammersley_points() {
+        fn min_dist(pts: &[(f32, f32)]) -> f32 {
+            let mut d = 1.0f32;
+            for (i, p) in pts.iter().enumerate() {
+                for q in pts[(i + 1)..].iter() {
+                    let dx = (p.0 - q.0).abs().min(1.0 - (p.0 - q.0).abs());
+                    let dy = (p.1 - q.1).abs().min(1.0 - (p.1 - q.1).abs());
+                    d = d.min((dx * dx + dy * dy).sqrt());
+                }
+            }
+            d
+        }
+
+        for m in 2..9 {
+            let n = 1 << m;
+            let hammersley: Vec<(f32, f32)> = (0..n)
+                .map(|i| (i as f32 / n as f32, van_der_corput(i as u32, 0)))
+                .collect();
+            assert!(min_dist(&image_samples(m)) >= min_dist(&hammersley), "m = {}", m);
+        }
+    }
+
+    #[test]
+    fn it_generates_every_sample_of_every_pixel() {
+        let mut sampler = MaxMinDistSampler::new(0, 2, 0, 3, 6, 0.0, 1.0);
+        assert_eq!(sampler.maximum_sample_count(), 8);
+
+        let mut samples = vec![Sample::empty(); 8];
+        let mut rng = RNG::new(0);
+        let mut pixels = Vec::new();
+        while sampler.get_more_samples(&mut samples, &mut rng) > 0 {
+            let (x, y) = samples[0].camera_sample.image_pos();
+            let (px, py) = (x.floor(), y.floor());
+            assert!(samples.iter().all(|s| {
+                let (sx, sy) = s.camera_sample.image_pos();
+                sx.floor() == px && sy.floor() == py
+            }));
+            pixels.push((px as i32, py as i32));
+        }
+        assert_eq!(pixels, vec![(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)]);
+    }
+}
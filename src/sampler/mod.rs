@@ -1,29 +1,36 @@
 mod adaptive;
 mod base;
+mod bluenoise;
 mod halton;
 mod lds;
+mod maxmin;
 pub mod sample;
 mod stratified;
 mod utils;
 
-use intersection::Intersection;
-use ray::RayDifferential;
-use rng::RNG;
-use sampler::base::SamplerBase;
-use sampler::adaptive::AdaptiveTest;
-use sampler::adaptive::AdaptiveSampler;
-use sampler::halton::HaltonSampler;
-use sampler::lds::LDSampler;
-use sampler::sample::Sample;
-use sampler::stratified::StratifiedSampler;
-use spectrum::Spectrum;
+use crate::intersection::Intersection;
+use crate::ray::RayDifferential;
+use crate::rng::RNG;
+use crate::sampler::base::SamplerBase;
+use crate::sampler::bluenoise::dither_samples;
+use crate::sampler::adaptive::AdaptiveSampler;
+use crate::sampler::halton::HaltonSampler;
+use crate::sampler::lds::LDSampler;
+use crate::sampler::maxmin::MaxMinDistSampler;
+use crate::sampler::sample::Sample;
+use crate::sampler::stratified::StratifiedSampler;
+use crate::spectrum::Spectrum;
+
+pub use self::adaptive::AdaptiveTest;
+pub use self::halton::HaltonScramble;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Sampler {
     Stratified(StratifiedSampler),
     Halton(HaltonSampler),
     LowDiscrepancy(LDSampler),
-    Adaptive(AdaptiveSampler)
+    Adaptive(AdaptiveSampler),
+    MaxMinDist(MaxMinDistSampler)
 }
 
 impl Sampler {
@@ -35,9 +42,10 @@ impl Sampler {
     }
 
     pub fn halton(x_start: i32, x_end: i32, y_start: i32, y_end: i32,
-                  samples_per_pixel: usize, sopen: f32, sclose: f32) -> Sampler {
+                  samples_per_pixel: usize, scramble: HaltonScramble,
+                  sopen: f32, sclose: f32) -> Sampler {
         Sampler::Halton(HaltonSampler::new(x_start, x_end, y_start, y_end,
-                                           samples_per_pixel, sopen, sclose))
+                                           samples_per_pixel, scramble, sopen, sclose))
     }
 
     pub fn low_discrepancy(x_start: i32, x_end: i32, y_start: i32, y_end: i32,
@@ -47,6 +55,12 @@ impl Sampler {
                                                samples_per_pixel, sopen, sclose))
     }
 
+    pub fn max_min_dist(x_start: i32, x_end: i32, y_start: i32, y_end: i32,
+                        samples_per_pixel: usize, sopen: f32, sclose: f32) -> Sampler {
+        Sampler::MaxMinDist(MaxMinDistSampler::new(x_start, x_end, y_start, y_end,
+                                                   samples_per_pixel, sopen, sclose))
+    }
+
     pub fn adaptive(x_start: i32, x_end: i32, y_start: i32, y_end: i32,
                     min_samples: usize, max_samples: usize, method: AdaptiveTest,
                     supersample: bool, sopen: f32, sclose: f32) -> Sampler {
@@ -55,18 +69,36 @@ impl Sampler {
                                                supersample, sopen, sclose))
     }
 
+    // Returns this sampler with the samples of each pixel decorrelated from
+    // those of its neighbors by a tiled blue noise mask, or not.
+    pub fn with_blue_noise(mut self, blue_noise: bool) -> Sampler {
+        self.base_mut().blue_noise = blue_noise;
+        self
+    }
+
     fn base(&self) -> &SamplerBase {
         match self {
             &Sampler::Stratified(ref sampler) => sampler.base(),
             &Sampler::Halton(ref sampler) => sampler.base(),
             &Sampler::LowDiscrepancy(ref sampler) => sampler.base(),
-            &Sampler::Adaptive(ref sampler) => sampler.base()
+            &Sampler::Adaptive(ref sampler) => sampler.base(),
+            &Sampler::MaxMinDist(ref sampler) => sampler.base()
+        }
+    }
+
+    fn base_mut(&mut self) -> &mut SamplerBase {
+        match self {
+            &mut Sampler::Stratified(ref mut sampler) => sampler.base_mut(),
+            &mut Sampler::Halton(ref mut sampler) => sampler.base_mut(),
+            &mut Sampler::LowDiscrepancy(ref mut sampler) => sampler.base_mut(),
+            &mut Sampler::Adaptive(ref mut sampler) => sampler.base_mut(),
+            &mut Sampler::MaxMinDist(ref mut sampler) => sampler.base_mut()
         }
     }
 
     pub fn get_sub_sampler(&self, task_idx: usize, num_tasks: usize)
                            -> Option<Sampler> {
-        match self {
+        let sub_sampler = match self {
             &Sampler::Stratified(ref sampler) =>
                 sampler
                 .get_sub_sampler(task_idx, num_tasks)
@@ -83,7 +115,46 @@ impl Sampler {
                 sampler
                 .get_sub_sampler(task_idx, num_tasks)
                 .map(Sampler::Adaptive),
+            &Sampler::MaxMinDist(ref sampler) =>
+                sampler
+                .get_sub_sampler(task_idx, num_tasks)
+                .map(Sampler::MaxMinDist),
+        };
+        sub_sampler.map(|s| s.with_blue_noise(self.base().blue_noise))
+    }
+
+    // Returns a sampler with the same settings that only generates the
+    // samples for pixel (x, y), or None if the pixel is outside of this
+    // sampler's extent.
+    pub fn get_pixel_sampler(&self, x: i32, y: i32) -> Option<Sampler> {
+        let (x0, x1, y0, y1) = self.sample_extent();
+        if x < x0 || x >= x1 || y < y0 || y >= y1 {
+            return None;
         }
+
+        let pixel_sampler = match self {
+            &Sampler::Stratified(ref sampler) =>
+                sampler
+                .get_window_sampler(x, x + 1, y, y + 1)
+                .map(Sampler::Stratified),
+            &Sampler::Halton(ref sampler) =>
+                sampler
+                .get_window_sampler(x, x + 1, y, y + 1)
+                .map(Sampler::Halton),
+            &Sampler::LowDiscrepancy(ref sampler) =>
+                sampler
+                .get_window_sampler(x, x + 1, y, y + 1)
+                .map(Sampler::LowDiscrepancy),
+            &Sampler::Adaptive(ref sampler) =>
+                sampler
+                .get_window_sampler(x, x + 1, y, y + 1)
+                .map(Sampler::Adaptive),
+            &Sampler::MaxMinDist(ref sampler) =>
+                sampler
+                .get_window_sampler(x, x + 1, y, y + 1)
+                .map(Sampler::MaxMinDist),
+        };
+        pixel_sampler.map(|s| s.with_blue_noise(self.base().blue_noise))
     }
 
     pub fn maximum_sample_count(&self) -> usize {
@@ -91,13 +162,14 @@ impl Sampler {
             &Sampler::Stratified(ref sampler) => sampler.maximum_sample_count(),
             &Sampler::Halton(ref sampler) => sampler.maximum_sample_count(),
             &Sampler::LowDiscrepancy(ref sampler) => sampler.maximum_sample_count(),
-            &Sampler::Adaptive(ref sampler) => sampler.maximum_sample_count()
+            &Sampler::Adaptive(ref sampler) => sampler.maximum_sample_count(),
+            &Sampler::MaxMinDist(ref sampler) => sampler.maximum_sample_count()
         }
     }
 
     pub fn get_more_samples(&mut self, samples: &mut Vec<Sample>,
                             rng: &mut RNG) -> usize {
-        match self {
+        let count = match self {
             &mut Sampler::Stratified(ref mut sampler) =>
                 sampler.get_more_samples(samples, rng),
             &mut Sampler::Halton(ref mut sampler) =>
@@ -105,18 +177,35 @@ impl Sampler {
             &mut Sampler::LowDiscrepancy(ref mut sampler) =>
                 sampler.get_more_samples(samples, rng),
             &mut Sampler::Adaptive(ref mut sampler) =>
+                sampler.get_more_samples(samples, rng),
+            &mut Sampler::MaxMinDist(ref mut sampler) =>
                 sampler.get_more_samples(samples, rng)
+        };
+
+        let base = self.base();
+        if base.blue_noise {
+            dither_samples(&mut samples[..count], base.shutter_open, base.shutter_close);
         }
+        count
     }
 
     pub fn samples_per_pixel(&self) -> f32 {
         self.base().samples_per_pixel as f32
     }
 
+    // Returns the half-open window (x_start, x_end, y_start, y_end) in
+    // image space that this sampler generates samples in.
+    pub fn sample_extent(&self) -> (i32, i32, i32, i32) {
+        let base = self.base();
+        (base.x_pixel_start, base.x_pixel_end,
+         base.y_pixel_start, base.y_pixel_end)
+    }
+
     pub fn round_size(&self, sz: usize) -> usize {
         match self {
             &Sampler::LowDiscrepancy(_) => sz.next_power_of_two(),
             &Sampler::Adaptive(_) => sz.next_power_of_two(),
+            &Sampler::MaxMinDist(_) => sz.next_power_of_two(),
             _ => sz
         }
     }
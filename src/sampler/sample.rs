@@ -1,8 +1,8 @@
-use camera::CameraSample;
-use integrator::SurfaceIntegrator;
-use integrator::VolumeIntegrator;
-use sampler::Sampler;
-use scene::Scene;
+use crate::camera::CameraSample;
+use crate::integrator::SurfaceIntegrator;
+use crate::integrator::VolumeIntegrator;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
 
 #[derive(Debug, Clone)]
 pub struct Sample {
@@ -26,102 +26,160 @@ impl Sample {
         }
     }
 
-    pub fn new(sampler: &Sampler, _surf: Option<&SurfaceIntegrator>,
-               _vol: Option<&VolumeIntegrator>, scene: &Scene) -> Sample {
+    // Makes a sample with room for the arrays of samples that the
+    // integrators request. Offsets into the arrays are made relative to
+    // the start of all of the sample values, with the 1D arrays first.
+    pub fn new(sampler: &Sampler, surf: Option<&mut SurfaceIntegrator>,
+               vol: Option<&VolumeIntegrator>, scene: &Scene) -> Sample {
         let mut s = Sample::empty();
-        if let Some(vol) = _vol {
+        if let Some(vol) = vol {
             vol.request_samples(sampler, &mut s, scene);
         }
 
-        if let Some(surf) = _surf {
+        if let Some(surf) = surf {
             surf.request_samples(sampler, &mut s, scene);
         }
 
         // Allocate sample memory
-        let num_1d_samples = match s.offset_1d.last() {
-            None => 0,
-            Some(x) => {
-                assert!(s.num_1d.len() > 0);
-                x + *(s.num_1d.last().unwrap())
-            }
-        };
-
+        let num_1d_samples: usize = s.num_1d.iter().sum();
         for x in s.offset_2d.iter_mut() {
             *x += num_1d_samples
         }
 
-        let total_num_samples = match s.offset_2d.last() {
-            None => num_1d_samples,
-            Some(x) => {
-                assert!(s.num_2d.len() > 0);
-                x + num_1d_samples + *(s.num_2d.last().unwrap()) * 2
-            }
-        };
-
-        s.samples = vec![0.0; total_num_samples];
+        let num_2d_samples: usize = s.num_2d.iter().sum();
+        s.samples = vec![0.0; num_1d_samples + 2 * num_2d_samples];
         s
     }
 
+    // Reserves an array of num 1D samples, and returns its index
     pub fn add_1d(&mut self, num: usize) -> usize {
-        let first = self.num_1d.is_empty();
+        let offset = match (self.offset_1d.last(), self.num_1d.last()) {
+            (Some(off), Some(n)) => off + n,
+            _ => 0
+        };
 
         self.num_1d.push(num);
-        if first {
-            self.offset_1d.push(0)
-        } else {
-            assert!(self.offset_1d.len() > 0);
-            let offset_to_last = *(self.offset_1d.last().unwrap());
-            self.offset_1d.push(offset_to_last + num);
-        }
-
+        self.offset_1d.push(offset);
         self.num_1d.len() - 1
     }
 
+    // Reserves an array of num 2D samples, and returns its index
     pub fn add_2d(&mut self, num: usize) -> usize {
-        let first = self.num_2d.is_empty();
+        let offset = match (self.offset_2d.last(), self.num_2d.last()) {
+            (Some(off), Some(n)) => off + 2 * n,
+            _ => 0
+        };
 
         self.num_2d.push(num);
-        if first {
-            self.offset_2d.push(0)
-        } else {
-            assert!(self.offset_2d.len() > 0);
-            let offset_to_last = *(self.offset_2d.last().unwrap());
-            self.offset_2d.push(offset_to_last + (2 * num));
-        }
-
+        self.offset_2d.push(offset);
         self.num_2d.len() - 1
     }
 
+    // The values of the idx-th array of 1D samples, or None if this sample
+    // doesn't have room for it.
+    pub fn one_d(&self, idx: usize) -> Option<&[f32]> {
+        let (num, off) = (*self.num_1d.get(idx)?, self.offset_1d[idx]);
+        self.samples.get(off..(off + num))
+    }
+
+    // The values of the idx-th array of 2D samples, with the two
+    // coordinates of each sample next to each other, or None if this
+    // sample doesn't have room for it.
+    pub fn two_d(&self, idx: usize) -> Option<&[f32]> {
+        let (num, off) = (*self.num_2d.get(idx)?, self.offset_2d[idx]);
+        self.samples.get(off..(off + 2 * num))
+    }
+
     pub fn to_camera_sample(self) -> CameraSample { self.camera_sample }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    use crate::integrator::SurfaceIntegrator;
+    use crate::rng::RNG;
+    use crate::sampler::AdaptiveTest;
+    use crate::sampler::HaltonScramble;
 
-    // !FIXME! These should all also make sure that the proper amount
-    // of data was allocated to store the resulting samples. These
-    // can't be properly tested until we get integrators working...
+    fn sampler() -> Sampler { Sampler::low_discrepancy(0, 1, 0, 1, 4, 0.0, 1.0) }
 
-    #[ignore]
     #[test]
     fn it_can_be_created() {
+        let s = Sample::new(&sampler(), None, None, &Scene::new());
+        assert!(s.num_1d.is_empty() && s.num_2d.is_empty());
+        assert!(s.samples.is_empty());
+        assert_eq!(s.one_d(0), None);
+        assert_eq!(s.two_d(0), None);
     }
 
-    #[ignore]
     #[test]
     fn it_can_add_1d_samples() {
-        unimplemented!()
+        let mut s = Sample::empty();
+        assert_eq!(s.add_1d(3), 0);
+        assert_eq!(s.add_1d(1), 1);
+        assert_eq!(s.add_1d(4), 2);
+        assert_eq!(s.offset_1d, vec![0, 3, 4]);
+
+        // Arrays can't be looked up until there's room for them
+        assert_eq!(s.one_d(1), None);
+        s.samples = (0..8).map(|x| x as f32).collect();
+        assert_eq!(s.one_d(1), Some(&[3.0][..]));
+        assert_eq!(s.one_d(2), Some(&[4.0, 5.0, 6.0, 7.0][..]));
     }
 
-    #[ignore]
     #[test]
     fn it_can_add_2d_samples() {
-        unimplemented!()
+        let mut s = Sample::empty();
+        assert_eq!(s.add_2d(2), 0);
+        assert_eq!(s.add_2d(3), 1);
+        assert_eq!(s.add_2d(1), 2);
+        assert_eq!(s.offset_2d, vec![0, 4, 10]);
+
+        s.samples = (0..12).map(|x| x as f32).collect();
+        assert_eq!(s.two_d(0), Some(&[0.0, 1.0, 2.0, 3.0][..]));
+        assert_eq!(s.two_d(2), Some(&[10.0, 11.0][..]));
+        assert_eq!(s.two_d(3), None);
     }
 
-    #[ignore]
     #[test]
     fn it_can_add_both_1d_and_2d_samples() {
-        unimplemented!()
+        // The path integrator asks for a light sample, a choice of light, a
+        // BSDF sample and a direction to continue in for its first bounces
+        let mut surf = SurfaceIntegrator::path(5, 3);
+        let mut s = Sample::new(&sampler(), Some(&mut surf), None, &Scene::new());
+        assert_eq!(s.num_1d.len(), 12);
+        assert_eq!(s.num_2d.len(), 9);
+        assert_eq!(s.samples.len(), 12 + 2 * 9);
+
+        // The 2D arrays come after all of the 1D ones, and every value
+        // belongs to exactly one array
+        let total_1d: usize = s.num_1d.iter().sum();
+        assert_eq!(s.offset_2d[0], total_1d);
+        let mut owners = vec![0; s.samples.len()];
+        for (&n, &off) in s.num_1d.iter().zip(s.offset_1d.iter()) {
+            for k in 0..n { owners[off + k] += 1; }
+        }
+        for (&n, &off) in s.num_2d.iter().zip(s.offset_2d.iter()) {
+            for k in 0..(2 * n) { owners[off + k] += 1; }
+        }
+        assert!(owners.iter().all(|&o| o == 1));
+
+        // Every kind of sampler fills in every array
+        let samplers = vec![
+            sampler(),
+            Sampler::stratified(0, 1, 0, 1, 2, 2, true, 0.0, 1.0),
+            Sampler::halton(0, 1, 0, 1, 4, HaltonScramble::Owen, 0.0, 1.0),
+            Sampler::max_min_dist(0, 1, 0, 1, 4, 0.0, 1.0),
+            Sampler::adaptive(0, 1, 0, 1, 4, 8, AdaptiveTest::ContrastThreshold, false, 0.0, 1.0)];
+        for mut sampler in samplers.into_iter() {
+            let mut samples = vec![s.clone(); sampler.maximum_sample_count()];
+            let n = sampler.get_more_samples(&mut samples, &mut RNG::new(0));
+            assert!(n > 0);
+            for s in samples[..n].iter() {
+                assert!(s.samples.iter().all(|&u| (0.0..1.0).contains(&u)));
+                assert!(s.samples.iter().any(|&u| u != 0.0));
+            }
+        }
     }
 }
@@ -1,12 +1,12 @@
-use camera::CameraSample;
-use rng::RNG;
-use sampler::base::SamplerBase;
-use sampler::sample::Sample;
-use utils::Lerp;
+use crate::camera::CameraSample;
+use crate::rng::RNG;
+use crate::sampler::base::SamplerBase;
+use crate::sampler::sample::Sample;
+use crate::utils::Lerp;
 
-use montecarlo::latin_hypercube;
-use montecarlo::stratified_sample_1d;
-use montecarlo::stratified_sample_2d;
+use crate::montecarlo::latin_hypercube;
+use crate::montecarlo::stratified_sample_1d;
+use crate::montecarlo::stratified_sample_2d;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct StratifiedSampler {
@@ -37,6 +37,7 @@ impl StratifiedSampler {
     }
 
     pub fn base(&self) -> &SamplerBase { &self.base }
+    pub fn base_mut(&mut self) -> &mut SamplerBase { &mut self.base }
 
     pub fn maximum_sample_count(&self) -> usize {
         self.x_pixel_samples * self.y_pixel_samples
@@ -45,6 +46,11 @@ impl StratifiedSampler {
     pub fn get_sub_sampler(&self, num: usize,
                            count: usize) -> Option<StratifiedSampler> {
         let (x0, x1, y0, y1) = self.base.compute_sub_window(num, count);
+        self.get_window_sampler(x0, x1, y0, y1)
+    }
+
+    pub fn get_window_sampler(&self, x0: i32, x1: i32,
+                              y0: i32, y1: i32) -> Option<StratifiedSampler> {
         if x0 == x1 || y0 == y1 {
             None
         } else {
@@ -103,7 +109,7 @@ impl StratifiedSampler {
                 samples[i].offset_1d.iter()).map(|(x, y)| (*x, *y)).collect();
 
             for (num, off) in sz_and_off_1d {
-                let (oned, _) = samples[i].samples.split_at_mut(off);
+                let (_, oned) = samples[i].samples.split_at_mut(off);
                 latin_hypercube(oned, num, 1, rng);
             }
 
@@ -111,7 +117,7 @@ impl StratifiedSampler {
                 samples[i].offset_2d.iter()).map(|(x, y)| (*x, *y)).collect();
 
             for (num, off) in sz_and_off_2d {
-                let (twod, _) = samples[i].samples.split_at_mut(off);
+                let (_, twod) = samples[i].samples.split_at_mut(off);
                 latin_hypercube(twod, num, 2, rng);
             }
         }
@@ -130,7 +136,7 @@ impl StratifiedSampler {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use sampler::base::SamplerBase;
+    use crate::sampler::base::SamplerBase;
 
     #[test]
     fn it_can_be_created() {
@@ -1,7 +1,7 @@
-use camera::CameraSample;
-use rng::RNG;
-use sampler::sample::Sample;
-use utils::Lerp;
+use crate::camera::CameraSample;
+use crate::rng::RNG;
+use crate::sampler::sample::Sample;
+use crate::utils::Lerp;
 
 pub fn van_der_corput(_n: u32, scramble: u32) -> f32 {
     let mut n = _n;
@@ -73,22 +73,37 @@ fn ld_shuffle_scrambled_2d(num_samples: usize, num_pixel_samples: usize,
     }
 
     for win in samples.chunks_mut(2 * num_samples) {
-        debug_assert_eq!(win.len(), num_samples);
+        debug_assert_eq!(win.len(), 2 * num_samples);
         rng.shuffle(win, 2);
     }
 
     rng.shuffle(samples, 2 * num_samples);
 }
 
+fn ld_image_samples(num_samples: usize, samples: &mut [f32], rng: &mut RNG) {
+    ld_shuffle_scrambled_2d(1, num_samples, samples, rng);
+}
+
 pub fn ld_pixel_sample(x_pos: i32, y_pos: i32, shutter_open: f32, shutter_close: f32,
                        num_samples: usize, samples: &mut [Sample],
                        buf: &mut [f32], rng: &mut RNG) {
+    pixel_sample_with_image_samples(x_pos, y_pos, shutter_open, shutter_close,
+                                    num_samples, samples, buf, rng, ld_image_samples);
+}
+
+// Like ld_pixel_sample, but the num_samples image samples of the pixel, in
+// [0, 1)^2, are generated by image_samples instead.
+pub fn pixel_sample_with_image_samples(x_pos: i32, y_pos: i32, shutter_open: f32,
+                                       shutter_close: f32, num_samples: usize,
+                                       samples: &mut [Sample], buf: &mut [f32],
+                                       rng: &mut RNG,
+                                       image_samples: fn(usize, &mut [f32], &mut RNG)) {
     if samples.is_empty() {
         return;
     }
 
     // Prepare temporary array pointers for low-discrepancy camera samples
-    let (mut image_samples, mut not_image_samples) =
+    let (image_sample_buf, mut not_image_samples) =
         buf.split_at_mut(2 * num_samples);
     let (mut lens_samples, mut not_lens_samples) =
         not_image_samples.split_at_mut(2 * num_samples);
@@ -107,20 +122,20 @@ pub fn ld_pixel_sample(x_pos: i32, y_pos: i32, shutter_open: f32, shutter_close:
     // !SPEED! These are allocated on the heap. :(
     let mut oned_samples = samples[0].num_1d.iter()
         .fold((Vec::new(), oned_sample_buf), |(mut ss, rest), &split| {
-            let (oned, the_rest) = rest.split_at_mut(split);
+            let (oned, the_rest) = rest.split_at_mut(split * num_samples);
             ss.push(oned);
             (ss, the_rest)
         }).0;
 
     let mut twod_samples = samples[0].num_2d.iter()
         .fold((Vec::new(), twod_sample_buf), |(mut ss, rest), &split| {
-            let (twod, the_rest) = rest.split_at_mut(2 * split);
+            let (twod, the_rest) = rest.split_at_mut(2 * split * num_samples);
             ss.push(twod);
             (ss, the_rest)
         }).0;
 
     // Generate low-discrepancy pixel samples
-    ld_shuffle_scrambled_2d(1, num_samples, &mut image_samples, rng);
+    image_samples(num_samples, image_sample_buf, rng);
     ld_shuffle_scrambled_2d(1, num_samples, &mut lens_samples, rng);
     ld_shuffle_scrambled_1d(1, num_samples, &mut time_samples, rng);
 
@@ -136,8 +151,8 @@ pub fn ld_pixel_sample(x_pos: i32, y_pos: i32, shutter_open: f32, shutter_close:
     for i in 0..num_samples {
         let t = shutter_open.lerp(&shutter_close, time_samples[i]);
         samples[i].camera_sample =
-            CameraSample::new(x_pos as f32 + image_samples[2 * i],
-                              y_pos as f32 + image_samples[2 * i + 1],
+            CameraSample::new(x_pos as f32 + image_sample_buf[2 * i],
+                              y_pos as f32 + image_sample_buf[2 * i + 1],
                               lens_samples[2 * i],
                               lens_samples[2 * i + 1],
                               t);
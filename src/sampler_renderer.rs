@@ -1,26 +1,30 @@
-extern crate scoped_threadpool;
-extern crate num_cpus;
-
-use camera::Camera;
-use camera::film::Film;
-use integrator::VolumeIntegrator;
-use integrator::SurfaceIntegrator;
-use intersection::Intersection;
-use intersection::Intersectable;
-use light::Light;
-use ray::RayDifferential;
-use rng::RNG;
-use renderer::Renderer;
-use sampler::sample::Sample;
-use sampler::Sampler;
-use scene::Scene;
+use crate::camera::Camera;
+use crate::camera::CameraSample;
+use crate::camera::film::Film;
+use crate::integrator::VolumeIntegrator;
+use crate::integrator::escaped_radiance;
+use crate::integrator::LightGroupRadiance;
+use crate::integrator::SurfaceIntegrator;
+use crate::intersection::Intersection;
+use crate::intersection::Intersectable;
+use crate::radiance_check;
+use crate::ray::RayDifferential;
+use crate::rng::RNG;
+use crate::renderer::Renderer;
+use crate::sampler::sample::Sample;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+#[cfg(feature = "threads")]
 use scoped_threadpool::Pool;
-use spectrum::Spectrum;
+use crate::spectrum::Spectrum;
+use crate::trace;
 
 use std::cmp::max;
 use std::ops::BitAnd;
 use std::iter::Iterator;
-use std::sync::{RwLock, Arc};
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 #[derive(Debug, Clone)]
 pub struct SamplerRenderer {
@@ -29,81 +33,307 @@ pub struct SamplerRenderer {
     surface_integrator: SurfaceIntegrator,
     volume_integrator: VolumeIntegrator,
 
+    // Every sample is a copy of this one, which has room for the sample
+    // arrays that the integrators requested when they were preprocessed
+    sample: Sample,
+
     num_tasks: usize,
+    num_threads: usize,
     // SamplerRenderer Private Data
 }
 
 impl SamplerRenderer {
     pub fn new(sampler: Sampler, cam: Camera,
                surf: SurfaceIntegrator, vol: VolumeIntegrator) -> Self {
-        let num_cpus = num_cpus::get() as u32;
+        // The tasks only depend on the size of the image and not on how many
+        // threads render them, so that their overlapping sub-films are summed
+        // the same way on every machine
         let num_pixels = (cam.film().x_res() * cam.film().y_res()) as u32;
         let tasks_fn = |x: u32| {
             31 - x.leading_zeros() + (if 0 == x.bitand(x - 1) { 0 } else { 1 })
         };
-        let tasks = tasks_fn(max(32 * num_cpus, num_pixels / 256));
+        let tasks = tasks_fn(max(256, num_pixels / 256));
 
         SamplerRenderer {
             sampler: sampler,
             camera: cam,
             surface_integrator: surf,
             volume_integrator: vol,
+            sample: Sample::empty(),
 
-            num_tasks: tasks as usize
+            num_tasks: tasks as usize,
+            num_threads: cpu_count()
         }
     }
 
+    // Sets how many threads threaded renders use, which defaults to one per
+    // CPU. It changes which thread renders each task but not the image.
+    pub fn with_num_threads(self, num_threads: usize) -> SamplerRenderer {
+        SamplerRenderer { num_threads: max(num_threads, 1), ..self }
+    }
+
     pub fn empty() -> SamplerRenderer {
         unimplemented!()
     }
+
+    // Allow integrators to do preprocessing for the scene, and then to
+    // request the sample arrays they need
+    fn preprocess(&mut self, scene: &Scene) {
+        self.surface_integrator.preprocess(scene, &(self.camera));
+        self.volume_integrator.preprocess(scene, &(self.camera));
+        self.sample = Sample::new(&self.sampler, Some(&mut self.surface_integrator),
+                                  Some(&self.volume_integrator), scene);
+    }
+
+    // Renders the scene and returns the film holding the result. If
+    // threaded is false, every task runs on the calling thread, which is
+    // needed on targets without threads (e.g. wasm32-unknown-unknown). The
+    // tasks are merged in the same order either way, so both produce the
+    // same image.
+    fn render_film(&mut self, scene: &Scene, threaded: bool) -> Film {
+        self.preprocess(scene);
+
+        // Allocate and initialize sample
+        let num_tasks = self.num_tasks;
+
+        // Create and launch SampleRendererTasks for rendering image
+        let mut film_clone = self.camera.film().clone();
+        {
+            let rend: &SamplerRenderer = self;
+            let film: &Film = &film_clone;
+            let mut task_films: Vec<Option<Film>> =
+                (0..num_tasks).map(|_| None).collect();
+
+            let num_threads = if threaded { rend.num_threads } else { 1 };
+            if num_threads > 1 {
+                println!("Running {:?} tasks on pool with {} threads",
+                         num_tasks, num_threads);
+            }
+
+            run_all(task_films.iter_mut().enumerate(), num_threads, |(i, task_film)| {
+                *task_film = run_task(scene, rend, film, i, num_tasks);
+            });
+
+            // Merge the results in task order so that the sums of
+            // overlapping pixels don't depend on which tasks finished first
+            for task_film in task_films.into_iter().flatten() {
+                film_clone.add_sub_film(task_film);
+            }
+        }
+
+        film_clone
+    }
+
+    // Computes the radiance along a camera ray. If groups is given, the part
+    // of it that came from lights in an output group is added to it.
+    fn li_with_groups(&self, scene: &Scene, ray: &RayDifferential,
+                      sample: &Sample, rng: &mut RNG,
+                      mut groups: Option<&mut LightGroupRadiance>)
+                      -> (Spectrum, Option<Intersection>, Spectrum) {
+        // Allocate variables for isect and T if needed
+        let (isect, li) =
+            if let Some(mut scene_isect) = scene.intersect(&ray.ray) {
+                trace::record("intersection", |e| {
+                    e.int("depth", ray.ray.depth as i64)
+                        .point("p", &scene_isect.dg.p)
+                        .normal("n", &scene_isect.dg.nn)
+                        .int("shape_id", scene_isect.shape_id as i64)
+                        .int("primitive_id", scene_isect.primitive_id as i64)
+                });
+                let l = self.surface_integrator.li_with_groups(
+                    scene, self, ray, &mut scene_isect, sample, rng,
+                    groups.as_deref_mut());
+                let l = radiance_check::check(
+                    "surface integrator", Some(scene_isect.primitive_id), l);
+                (Some(scene_isect), l)
+            } else {
+                // Handle ray that doesn't intersect any geometry
+                let le = escaped_radiance(scene, ray, groups.as_deref_mut());
+                trace::record("escaped", |e| {
+                    e.int("depth", ray.ray.depth as i64)
+                        .vector("d", &ray.ray.d)
+                        .spectrum("Le", &le)
+                });
+                (None, le)
+            };
+
+        let mut local_trans = Spectrum::from(0f32);
+        let lvi = self.volume_integrator.li(scene, self, ray, sample,
+                                            rng, &mut local_trans);
+        let lvi = radiance_check::check("volume integrator", None, lvi);
+
+        if let Some(g) = groups {
+            g.scale(local_trans);
+        }
+
+        (local_trans * li + lvi, isect, local_trans)
+    }
+
+    // Starts an interactive render of the scene in which each tile spends at
+    // most tile_budget on every pass. Nothing is rendered until the first
+    // call to render_pass.
+    pub fn start_progressive(&mut self, scene: &Scene,
+                             tile_budget: Duration) -> ProgressiveRender {
+        self.preprocess(scene);
+
+        let tiles = (0..self.num_tasks).filter_map(|i| {
+            self.sampler.get_sub_sampler(i, self.num_tasks)
+        }).map(|sampler| TileProgress { sampler, next_pixel: 0, round: 0 }).collect();
+
+        ProgressiveRender {
+            film: self.camera.film().clone(),
+            tiles,
+            tile_budget,
+            passes: 0
+        }
+    }
+
+    // Runs one pass of a progressive render, adding its samples to the
+    // render's film, and returns the number of pixels that were rendered.
+    // As with render_film, tiles are merged in order so that threading
+    // doesn't change the result.
+    pub fn render_pass(&self, scene: &Scene, progress: &mut ProgressiveRender,
+                       threaded: bool) -> usize {
+        let track_groups = scene.lights().iter().any(|l| l.group().is_some());
+        let budget = progress.tile_budget;
+        let film: &Film = &progress.film;
+        let mut results: Vec<Option<(Film, usize)>> =
+            progress.tiles.iter().map(|_| None).collect();
+
+        let num_threads = if threaded { self.num_threads } else { 1 };
+        run_all(progress.tiles.iter_mut().zip(results.iter_mut()), num_threads, |(tile, result)| {
+            *result = Some(run_tile_pass(scene, self, film, tile, budget, track_groups));
+        });
+
+        let mut rendered = 0;
+        for (tile_film, n) in results.into_iter().flatten() {
+            progress.film.add_sub_film(tile_film);
+            rendered += n;
+        }
+
+        progress.passes += 1;
+        rendered
+    }
+
+    // Renders the scene on the calling thread and returns the image as 8-bit
+    // RGBA without writing anything to disk.
+    pub fn render_to_rgba(&mut self, scene: &Scene) -> Vec<u8> {
+        self.render_film(scene, false).get_rgba8(1.0)
+    }
 }
 
-fn run_task<'a>(scene: &'a Scene,
-                renderer: &'a SamplerRenderer,
-                film: Arc<RwLock<&'a mut Film>>,
-                task_idx: usize, num_tasks: usize) {
-    // Get sub-sampler for SamplerRendererTask
-    let mut sampler = {
-        if let Some(s) = renderer.sampler.get_sub_sampler(task_idx, num_tasks)
-        { s } else { return }
-    };
+#[cfg(feature = "threads")]
+fn cpu_count() -> usize { num_cpus::get() }
 
-    let mut task_film = film.read().unwrap().get_sub_film(task_idx, num_tasks);
+#[cfg(not(feature = "threads"))]
+fn cpu_count() -> usize { 1 }
 
-    // Declare local variables used for rendering loop
-    let mut rng = RNG::new(task_idx);
+// Calls f with each of the items, spread over a pool of num_threads
+// threads. Builds without the threads feature always call it on the calling
+// thread.
+#[cfg(feature = "threads")]
+fn run_all<T: Send>(items: impl Iterator<Item = T>, num_threads: usize, f: impl Fn(T) + Sync) {
+    if num_threads <= 1 {
+        items.for_each(f);
+        return;
+    }
+
+    let f = &f;
+    Pool::new(num_threads as u32).scoped(|scope| {
+        for item in items {
+            scope.execute(move || f(item));
+        }
+    });
+}
+
+#[cfg(not(feature = "threads"))]
+fn run_all<T: Send>(items: impl Iterator<Item = T>, _: usize, f: impl Fn(T) + Sync) {
+    items.for_each(f);
+}
+
+// Returns the random number generator for pixel (x, y). Seeding it by the
+// pixel rather than by the task makes each pixel come out the same no
+// matter how the image is split into tasks, which is what allows a single
+// pixel to be re-rendered on its own for debugging. Progressive renders
+// cover each pixel once per round, and need different samples every time.
+fn pixel_rng(x: i32, y: i32, round: usize) -> RNG {
+    RNG::new((x as usize).wrapping_mul(73856093) ^ (y as usize).wrapping_mul(19349663)
+             ^ round.wrapping_mul(83492791))
+}
+
+// A camera sample along with the radiance that it carries
+struct PixelSample {
+    camera_sample: CameraSample,
+    l: Spectrum,
+    groups: LightGroupRadiance
+}
+
+// Takes all of the samples that the sampler generates, which should be
+// restricted to a single pixel, and returns the ones that should be added
+// to the image.
+fn render_pixel(scene: &Scene, renderer: &SamplerRenderer, sampler: &mut Sampler,
+                pixel: (i32, i32), round: usize, track_groups: bool) -> Vec<PixelSample> {
+    let mut rng = pixel_rng(pixel.0, pixel.1, round);
+    radiance_check::set_pixel(Some(pixel));
 
     // Allocate space for samples and intersections
-    let max_samples = sampler.maximum_sample_count() as usize;
-    let mut samples : Vec<Sample> = vec![Sample::empty(); max_samples];
-    let mut rays : Vec<RayDifferential> = Vec::with_capacity(max_samples);
+    let max_samples = sampler.maximum_sample_count();
+    let mut samples : Vec<Sample> = vec![renderer.sample.clone(); max_samples];
+    let rays : Vec<RayDifferential> = Vec::with_capacity(max_samples);
     let mut l_s : Vec<Spectrum> = Vec::with_capacity(max_samples);
     let mut t_s : Vec<Spectrum> = Vec::with_capacity(max_samples);
     let mut isects : Vec<Intersection> = Vec::with_capacity(max_samples);
+    let mut g_s : Vec<LightGroupRadiance> = Vec::with_capacity(max_samples);
+    let mut c_s : Vec<CameraSample> = Vec::with_capacity(max_samples);
+    let mut result = Vec::new();
 
     // Get samples from Sampler and update image
     loop {
-        sampler.get_more_samples(&mut samples, &mut rng);
-        let sample_count = samples.len();
+        let sample_count = sampler.get_more_samples(&mut samples, &mut rng);
         if sample_count == 0 { break; }
 
+        // Results are reported to the sampler one batch at a time
+        l_s.clear();
+        t_s.clear();
+        isects.clear();
+        g_s.clear();
+        c_s.clear();
+
         // Generate camera rays and compute radiance along rays
-        for i in 0..sample_count {
-            // Find camera ray for sample[i]
-            let cs = samples[i].clone().to_camera_sample();
+        for sample in samples.iter().take(sample_count) {
+            // Find camera ray for sample
+            let cs = sample.camera_sample.clone();
+            trace::record("sample", |e| {
+                let (x, y) = cs.image_pos();
+                let (u, v) = cs.lens_pos();
+                e.floats("image", &[x, y])
+                    .floats("lens", &[u, v])
+                    .float("time", cs.time())
+                    .floats("integrator", &sample.samples)
+            });
             let (ray_weight, mut ray) = renderer.camera.generate_ray_differential(&cs);
 
             ray.scale_differentials(1.0f32 / sampler.samples_per_pixel().sqrt());
+            trace::record("camera_ray", |e| {
+                e.point("o", &ray.ray.o).vector("d", &ray.ray.d).float("weight", ray_weight)
+            });
 
             // Evaluate radiance along camera ray
             if ray_weight > 0f32 {
                 // !FIXME! I think this synchronization is a bit too coarse grained
-                let (mut ls, isect, ts) = renderer.li(scene, &ray, &samples[i],
-                                                      &mut rng);
-                ls = ls * ray_weight;
+                let mut groups = LightGroupRadiance::new();
+                let (mut ls, isect, ts) = renderer.li_with_groups(
+                    scene, &ray, sample, &mut rng,
+                    if track_groups { Some(&mut groups) } else { None });
+                ls = radiance_check::check("camera", None, ls * ray_weight);
+                groups.scale(Spectrum::from(ray_weight));
+                g_s.push(groups);
+                trace::record("radiance", |e| {
+                    e.spectrum("L", &ls).boolean("has_nans", ls.has_nans())
+                });
 
-                if !ls.has_nans() { panic!("Invalid radiance value!"); }
                 l_s.push(ls);
+                c_s.push(cs);
 
                 // !FIXME! I think there are times when we don't generate
                 // transmissive values, and these times we shouldn't add them
@@ -126,90 +356,387 @@ fn run_task<'a>(scene: &'a Scene,
         }
 
         // Report sample results to Sampler, add contributions to image
-        if sampler.report_results(&mut samples, &rays, &l_s, &isects, sample_count) {
-            for i in 0..sample_count {
-                // !FIXME! This synchronization is still a bit coarse grained, but
-                // we may be able to move the lock within a few levels to get finer
-                // synchronization. Writing the computed sample is significantly
-                // cheaper than the render step, though. Once we figure out a good
-                // way to do the synchronization here, we should fix the atomicity of
-                // adding samples to pixels in src/camera/film.rs
-                let cs = samples[i].clone().to_camera_sample();
-                task_film.add_sample(&cs, &l_s[i]);
+        if sampler.report_results(&samples, &rays, &l_s, &isects, sample_count) {
+            for ((camera_sample, &l), groups) in c_s.drain(..).zip(l_s.iter()).zip(g_s.drain(..)) {
+                result.push(PixelSample { camera_sample, l, groups });
             }
         }
     }
 
-    film.write().unwrap().add_sub_film(task_film);
+    radiance_check::set_pixel(None);
+    result
 }
 
-impl Renderer for SamplerRenderer {
-    fn render(&mut self, scene : &Scene) {
-        // Allow integrators to do preprocessing for the scene
-        self.surface_integrator.preprocess(scene, &(self.camera));
-        self.volume_integrator.preprocess(scene, &(self.camera));
+// Renders a single task and returns the sub-film holding its contributions,
+// or None if the task had no samples to generate. The sub-films are not
+// merged here: they overlap along their borders, so the caller merges them
+// in task order to keep the image identical regardless of scheduling.
+fn run_task(scene: &Scene, renderer: &SamplerRenderer, film: &Film,
+            task_idx: usize, num_tasks: usize) -> Option<Film> {
+    // Get sub-sampler for SamplerRendererTask
+    let sampler = renderer.sampler.get_sub_sampler(task_idx, num_tasks)?;
+
+    let (x0, x1, y0, y1) = sampler.sample_extent();
+    let mut task_film = film.get_sub_film(x0, x1, y0, y1);
+
+    // Only split radiance up by light group if some light asks for it
+    let track_groups = scene.lights().iter().any(|l| l.group().is_some());
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            // !FIXME! This synchronization is still a bit coarse grained, but
+            // we may be able to move the lock within a few levels to get finer
+            // synchronization. Writing the computed sample is significantly
+            // cheaper than the render step, though. Once we figure out a good
+            // way to do the synchronization here, we should fix the atomicity of
+            // adding samples to pixels in src/camera/film.rs
+            add_pixel(scene, renderer, &sampler, &mut task_film, (x, y), 0, track_groups);
+        }
+    }
 
-        // Allocate and initialize sample
-        let num_tasks = self.num_tasks;
+    Some(task_film)
+}
 
-        // Create and launch SampleRendererTasks for rendering image
-        let mut film_clone = self.camera.film().clone();
-        {
-            let num_cpus = num_cpus::get();
-            let num_pixels = film_clone.x_res() * film_clone.y_res();
+// Renders pixel (x, y) for the given round and adds its samples to film
+fn add_pixel(scene: &Scene, renderer: &SamplerRenderer, sampler: &Sampler, film: &mut Film,
+             pixel: (i32, i32), round: usize, track_groups: bool) {
+    let mut pixel_sampler = sampler.get_pixel_sampler(pixel.0, pixel.1).unwrap();
+    for ps in render_pixel(scene, renderer, &mut pixel_sampler, pixel, round, track_groups) {
+        film.add_sample(&ps.camera_sample, &ps.l);
+        for (name, lg) in ps.groups.iter() {
+            film.add_group_sample(name, &ps.camera_sample, lg);
+        }
+    }
+}
 
-            let task_data_shared = Arc::new(RwLock::new(&mut film_clone));
+// How far a tile of a progressive render has gotten: the pixel that it
+// renders next, in scanline order, and how many times it has covered every
+// one of its pixels.
+#[derive(Debug, Clone)]
+struct TileProgress {
+    sampler: Sampler,
+    next_pixel: usize,
+    round: usize
+}
 
-            println!("Running {:?} tasks on pool with {} cpus",
-                     num_tasks, num_cpus);
+impl TileProgress {
+    fn num_pixels(&self) -> usize {
+        let (x0, x1, y0, y1) = self.sampler.sample_extent();
+        ((x1 - x0) * (y1 - y0)) as usize
+    }
 
-            let rend: &SamplerRenderer = self;
+    fn pixel(&self) -> (i32, i32) {
+        let (x0, x1, y0, _) = self.sampler.sample_extent();
+        let w = (x1 - x0) as usize;
+        (x0 + (self.next_pixel % w) as i32, y0 + (self.next_pixel / w) as i32)
+    }
+}
 
-            Pool::new(num_cpus as u32).scoped(|scope| {
-                for i in 0..num_tasks {
-                    let film = task_data_shared.clone();
-                    scope.execute(move || run_task(scene, rend, film, i, num_tasks));
-                }
-            });
+// An interactive render that refines the image over a series of passes. In
+// each pass every tile covers its pixels at most once more, but stops as
+// soon as it has spent its time budget. Tiles that run out of time pick up
+// where they left off in the next pass, so that a few expensive tiles can't
+// hold up the preview of the whole image.
+#[derive(Debug, Clone)]
+pub struct ProgressiveRender {
+    film: Film,
+    tiles: Vec<TileProgress>,
+    tile_budget: Duration,
+    passes: usize
+}
+
+impl ProgressiveRender {
+    // The image accumulated over all of the passes so far
+    pub fn film(&self) -> &Film { &self.film }
+
+    pub fn passes(&self) -> usize { self.passes }
+
+    // The number of times that every pixel of the image has been covered
+    pub fn completed_rounds(&self) -> usize {
+        self.tiles.iter().map(|t| t.round).min().unwrap_or(0)
+    }
+}
+
+// Renders the next pixels of a tile for one pass of a progressive render,
+// and returns the sub-film holding them and the number of pixels rendered.
+// At least one pixel is rendered so that every tile makes progress.
+fn run_tile_pass(scene: &Scene, renderer: &SamplerRenderer, film: &Film,
+                 tile: &mut TileProgress, budget: Duration,
+                 track_groups: bool) -> (Film, usize) {
+    let (x0, x1, y0, y1) = tile.sampler.sample_extent();
+    let mut tile_film = film.get_sub_film(x0, x1, y0, y1);
+    let num_pixels = tile.num_pixels();
+    if num_pixels == 0 {
+        return (tile_film, 0);
+    }
+
+    let start = Instant::now();
+    let mut rendered = 0;
+    loop {
+        add_pixel(scene, renderer, &tile.sampler, &mut tile_film, tile.pixel(),
+                  tile.round, track_groups);
+        rendered += 1;
+
+        tile.next_pixel += 1;
+        if tile.next_pixel == num_pixels {
+            tile.next_pixel = 0;
+            tile.round += 1;
+            break;
+        }
+
+        if start.elapsed() >= budget {
+            break;
         }
+    }
+
+    (tile_film, rendered)
+}
+
+impl Renderer for SamplerRenderer {
+    fn render(&mut self, scene : &Scene) {
+        let film = self.render_film(scene, true);
 
         // Clean up after rendering and store final image
 
         // !FIXME! This doesn't work... :(
-        // *(self.camera.film_mut()) = film_clone;
+        // *(self.camera.film_mut()) = film;
 
-        film_clone.write_image(1.0);
+        film.write_image(1.0);
     }
 
     fn li<'a>(&self, scene: &'a Scene, ray: &RayDifferential,
               sample: &Sample,
               rng: &mut RNG) -> (Spectrum, Option<Intersection>, Spectrum) {
+        self.li_with_groups(scene, ray, sample, rng, None)
+    }
 
-        // Allocate variables for isect and T if needed
-        let (isect, li) =
-            if let Some(mut scene_isect) = scene.intersect(&ray.ray) {
-                let l = self.surface_integrator.li(scene, self, ray,
-                                                   &mut scene_isect, sample, rng);
-                (Some(scene_isect), l)
-            } else {
-                // Handle ray that doesn't intersect any geometry
-                let zero_spect = Spectrum::from(0f32);
-                let accum = |acc, light: &Arc<dyn Light>| acc + light.le(ray);
-                (None, scene.lights().iter().fold(zero_spect, accum))
-            };
-
-        let mut local_trans = Spectrum::from(0f32);
-        let lvi = self.volume_integrator.li(scene, self, ray, sample,
-                                            rng, &mut local_trans);
+    // Re-renders pixel (x, y) exactly as it is rendered as part of the
+    // whole image and returns a JSON log of the sampler values, rays,
+    // intersections, BSDF samples and light samples that went into it, or
+    // None if the pixel isn't part of the image.
+    fn debug_pixel(&mut self, scene: &Scene, x: i32, y: i32) -> Option<String> {
+        let mut sampler = self.sampler.get_pixel_sampler(x, y)?;
+        self.preprocess(scene);
+
+        let track_groups = scene.lights().iter().any(|l| l.group().is_some());
+        trace::begin();
+        let samples = render_pixel(scene, self, &mut sampler, (x, y), 0, track_groups);
+        let events = trace::end();
+
+        let l = samples.iter().fold(Spectrum::from(0.0), |acc, ps| acc + ps.l);
+        let summary = trace::TraceEvent::new("pixel")
+            .floats("pixel", &[x as f32, y as f32])
+            .int("num_samples", samples.len() as i64)
+            .spectrum("L_sum", &l)
+            .raw("events", &format!("[{}]", events.join(",")));
+        Some(summary.to_json())
+    }
 
-        (local_trans * li + lvi, isect, local_trans)
+    fn li_grouped(&self, scene: &Scene, ray: &RayDifferential, sample: &Sample,
+                  rng: &mut RNG, groups: Option<&mut LightGroupRadiance>) -> Spectrum {
+        self.li_with_groups(scene, ray, sample, rng, groups).0
     }
 
     fn transmittance(&self, scene: &Scene, ray: &RayDifferential,
                      sample: &Sample, rng: &mut RNG) -> Spectrum {
-        let mut local_trans = Spectrum::from(0f32);
-        self.volume_integrator.li(scene, self, ray, sample, rng, &mut local_trans)
+        self.volume_integrator.transmittance(scene, self, ray, sample, rng)
     }
 
     // Rnderer Interface
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::primitive::Primitive;
+    use crate::shape::Shape;
+    use crate::transform::transform::Transform;
+    use crate::transform::animated::AnimatedTransform;
+
+    fn renderer(sampler: Sampler) -> SamplerRenderer {
+        let film = Film::image(4, 4, Filter::mean(0.5, 0.5), [0.0, 1.0, 0.0, 1.0],
+                               String::from("debug.png"), false);
+        let camera = Camera::perspective(AnimatedTransform::identity(),
+                                         [-1.0, 1.0, -1.0, 1.0], 0.0, 1.0,
+                                         0.0, 1e6, 90.0, film);
+        SamplerRenderer::new(sampler, camera, SurfaceIntegrator::whitted(5),
+                             VolumeIntegrator::single_scattering(1.0, false))
+    }
+
+    #[test]
+    fn it_samples_pixels_the_same_way_every_time() {
+        let sampler = Sampler::stratified(0, 4, 0, 4, 2, 2, true, 0.0, 1.0);
+        let gen = |x, y| {
+            let mut pixel_sampler = sampler.get_pixel_sampler(x, y).unwrap();
+            let mut samples = vec![Sample::empty(); pixel_sampler.maximum_sample_count()];
+            let n = pixel_sampler.get_more_samples(&mut samples, &mut pixel_rng(x, y, 0));
+            assert_eq!(pixel_sampler.get_more_samples(&mut samples.clone(),
+                                                      &mut pixel_rng(x, y, 0)), 0);
+            samples.truncate(n);
+            samples.into_iter().map(|s| s.camera_sample.image_pos()).collect::<Vec<_>>()
+        };
+
+        let samples = gen(2, 1);
+        assert_eq!(samples.len(), 4);
+        for &(x, y) in samples.iter() {
+            assert!((2.0..3.0).contains(&x) && (1.0..2.0).contains(&y));
+        }
+        assert_eq!(samples, gen(2, 1));
+        assert_ne!(samples, gen(1, 2));
+    }
+
+    #[test]
+    fn it_logs_how_pixels_are_rendered() {
+        let mut r = renderer(Sampler::stratified(0, 4, 0, 4, 2, 1, false, 0.0, 1.0));
+
+        // Put a sphere behind the camera so that every camera ray escapes
+        let xf = Transform::translate(&Vector::new_with(0.0, 0.0, -5.0));
+        let sphere = Shape::sphere(xf.clone(), xf.inverse(), false, 1.0, -1.0, 1.0, 360.0);
+        let scene = Scene::new_with(Arc::new(Primitive::simple(sphere)), vec![], None);
+
+        let log = r.debug_pixel(&scene, 1, 2).unwrap();
+        assert!(log.starts_with("{\"event\":\"pixel\",\"pixel\":[1,2],\"num_samples\":2"));
+        assert_eq!(log.matches("{\"event\":\"sample\"").count(), 2);
+        assert_eq!(log.matches("{\"event\":\"camera_ray\"").count(), 2);
+        assert_eq!(log.matches("{\"event\":\"escaped\"").count(), 2);
+        assert_eq!(log.matches("{\"event\":\"radiance\"").count(), 2);
+        assert_eq!(log, r.debug_pixel(&scene, 1, 2).unwrap());
+        assert!(!trace::is_enabled());
+    }
+
+    #[test]
+    fn escaped_rays_see_the_lights_in_the_background() {
+        use crate::light::Light;
+        use crate::light::LightBuilder;
+        use crate::light::sunsky::SunSkyLight;
+
+        let r = renderer(Sampler::stratified(0, 4, 0, 4, 1, 1, false, 0.0, 1.0));
+        let sky = SunSkyLight::new(Transform::new(), Vector::new_with(0.0, 0.0, 1.0), 3.0,
+                                   Spectrum::from(0.0), Spectrum::from(1.0))
+            .with_group("sky");
+        let expected = sky.le(&RayDifferential::new_with(
+            Point::new(), Vector::new_with(1.0, 0.0, 1.0), 0.0));
+        assert!(!expected.is_black());
+
+        // The only geometry is behind the ray
+        let xf = Transform::translate(&Vector::new_with(0.0, 0.0, -5.0));
+        let sphere = Shape::sphere(xf.clone(), xf.inverse(), false, 1.0, -1.0, 1.0, 360.0);
+        let light: Arc<dyn Light> = Arc::new(sky);
+        let scene = Scene::new_with(Arc::new(Primitive::simple(sphere)), vec![light], None);
+
+        let ray = RayDifferential::new_with(Point::new(), Vector::new_with(1.0, 0.0, 1.0), 0.0);
+        let mut groups = LightGroupRadiance::new();
+        let (l, isect, _) = r.li_with_groups(&scene, &ray, &Sample::empty(), &mut RNG::new(0),
+                                             Some(&mut groups));
+        assert!(isect.is_none());
+        assert_eq!(l, expected);
+        assert_eq!(groups.iter().collect::<Vec<_>>(), vec![&(String::from("sky"), expected)]);
+    }
+
+    #[test]
+    fn light_groups_follow_specular_bounces() {
+        use crate::bsdf::fresnel::IndexOfRefraction;
+        use crate::light::Light;
+        use crate::light::LightBuilder;
+        use crate::light::sunsky::SunSkyLight;
+        use crate::material::Material;
+        use crate::texture::ConstantTexture;
+
+        let r = renderer(Sampler::stratified(0, 4, 0, 4, 1, 1, false, 0.0, 1.0));
+        let sky: Arc<dyn Light> = Arc::new(
+            SunSkyLight::new(Transform::new(), Vector::new_with(0.0, 0.0, 1.0), 3.0,
+                             Spectrum::from(0.0), Spectrum::from(1.0))
+                .with_group("sky"));
+
+        // The sky is only seen through a clear glass ball
+        let xf = Transform::translate(&Vector::new_with(3.0, 0.0, 3.0));
+        let sphere = Shape::sphere(xf.clone(), xf.inverse(), false, 1.0, -1.0, 1.0, 360.0);
+        let glass = Material::glass(Arc::new(ConstantTexture::new(Spectrum::from(0.0))),
+                                    Arc::new(ConstantTexture::new(Spectrum::from(1.0))),
+                                    IndexOfRefraction::Constant(1.5), None);
+        let scene = Scene::new_with(
+            Arc::new(Primitive::geometric(sphere, Arc::new(glass))), vec![sky], None);
+
+        let ray = RayDifferential::new_with(Point::new(), Vector::new_with(1.0, 0.0, 1.0), 0.0);
+        let mut groups = LightGroupRadiance::new();
+        let (l, isect, _) = r.li_with_groups(&scene, &ray, &Sample::empty(), &mut RNG::new(0),
+                                             Some(&mut groups));
+        assert!(isect.is_some());
+        assert!(!l.is_black());
+
+        let split: Vec<_> = groups.iter().collect();
+        assert_eq!(split.len(), 1);
+        assert_eq!(split[0].0, "sky");
+        assert!((split[0].1.y() - l.y()).abs() < 1e-4 * l.y());
+    }
+
+    #[test]
+    fn progressive_passes_stay_within_their_time_budget() {
+        use crate::light::Light;
+        use crate::light::sunsky::SunSkyLight;
+
+        let mut r = renderer(Sampler::stratified(0, 4, 0, 4, 1, 1, false, 0.0, 1.0));
+        let sky: Arc<dyn Light> = Arc::new(SunSkyLight::new(
+            Transform::new(), Vector::new_with(0.0, 1.0, 1.0), 3.0,
+            Spectrum::from(0.0), Spectrum::from(0.01)));
+        let xf = Transform::translate(&Vector::new_with(0.0, 0.0, -5.0));
+        let sphere = Shape::sphere(xf.clone(), xf.inverse(), false, 1.0, -1.0, 1.0, 360.0);
+        let scene = Scene::new_with(Arc::new(Primitive::simple(sphere)), vec![sky], None);
+
+        // Without any time to spare, tiles only get one pixel further
+        // every pass, and so the image fills in over several passes
+        let mut progress = r.start_progressive(&scene, Duration::from_secs(0));
+        let num_tiles = progress.tiles.len();
+        assert!(num_tiles > 0);
+        assert_eq!(r.render_pass(&scene, &mut progress, false), num_tiles);
+        assert_eq!(progress.passes(), 1);
+        while progress.completed_rounds() == 0 {
+            r.render_pass(&scene, &mut progress, false);
+        }
+        assert!(progress.passes() > 1);
+
+        // A full pass renders the same image as rendering all at once
+        let mut progress = r.start_progressive(&scene, Duration::from_secs(60));
+        assert_eq!(r.render_pass(&scene, &mut progress, true), 16);
+        assert_eq!(progress.completed_rounds(), 1);
+        let image = r.render_to_rgba(&scene);
+        assert!(image.iter().any(|&c| c > 0 && c < 255));
+        assert_eq!(progress.film().get_rgba8(1.0), image);
+    }
+
+    #[test]
+    fn images_dont_depend_on_the_number_of_threads() {
+        use crate::light::Light;
+        use crate::light::sunsky::SunSkyLight;
+
+        let sky: Arc<dyn Light> = Arc::new(SunSkyLight::new(
+            Transform::new(), Vector::new_with(0.0, 1.0, 1.0), 3.0,
+            Spectrum::from(0.0), Spectrum::from(0.01)));
+        let xf = Transform::translate(&Vector::new_with(0.0, 0.0, 3.0));
+        let sphere = Shape::sphere(xf.clone(), xf.inverse(), false, 1.0, -1.0, 1.0, 360.0);
+        let scene = Scene::new_with(Arc::new(Primitive::simple(sphere)), vec![sky], None);
+
+        let render = |num_threads| {
+            let mut r = renderer(Sampler::stratified(0, 4, 0, 4, 2, 2, true, 0.0, 1.0))
+                .with_num_threads(num_threads);
+            r.render_film(&scene, true).get_rgb(1.0)
+        };
+
+        let image = render(1);
+        assert!(image.iter().any(|&c| c > 0.0));
+        assert_eq!(image, render(3));
+        assert_eq!(image, render(16));
+    }
+
+    #[test]
+    fn it_cant_debug_pixels_outside_of_the_image() {
+        let mut r = renderer(Sampler::stratified(0, 4, 0, 4, 1, 1, false, 0.0, 1.0));
+        let scene = Scene::new();
+        assert_eq!(r.debug_pixel(&scene, 4, 0), None);
+        assert_eq!(r.debug_pixel(&scene, 0, -1), None);
+        assert!(!trace::is_enabled());
+    }
+}
@@ -1,14 +1,18 @@
-use bbox::BBox;
-use bbox::Union;
-use bbox::HasBounds;
-use intersection::Intersectable;
-use intersection::Intersection;
-use light::Light;
-use primitive::Primitive;
-use ray::Ray;
-use shape::Shape;
-use transform::transform::Transform;
-use volume::VolumeRegion;
+use crate::bbox::BBox;
+use crate::bbox::Union;
+use crate::bbox::HasBounds;
+use crate::intersection::Intersectable;
+use crate::intersection::Intersection;
+use crate::geometry::normal::Normal;
+use crate::geometry::point::Point;
+use crate::light::Light;
+use crate::light::bvh::LightBVH;
+use crate::montecarlo::Distribution1D;
+use crate::primitive::Primitive;
+use crate::ray::Ray;
+use crate::shape::Shape;
+use crate::transform::transform::Transform;
+use crate::volume::VolumeRegion;
 
 use std::sync::Arc;
 
@@ -16,6 +20,10 @@ pub struct Scene {
     aggregate : Arc<Primitive>,
     lights : Vec<Arc<dyn Light>>,
     volume_region : Option<Arc<dyn VolumeRegion>>,
+    // Picks lights in proportion to their power, if there are any lights
+    light_distribution : Option<Distribution1D>,
+    // Picks lights by how much they contribute to each point, if enabled
+    light_bvh : Option<LightBVH>,
     // Scene Public data 23
 }
 
@@ -26,23 +34,104 @@ impl Scene {
                 Transform::new(), Transform::new(), false, 1.0, -1.0, 1.0, 360.0))),
             lights: vec![],
             volume_region: None,
+            light_distribution: None,
+            light_bvh: None,
         }
     }
 
     pub fn new_with(aggregate: Arc<Primitive>,
                     lights: Vec<Arc<dyn Light>>,
                     volume_region: Option<Arc<dyn VolumeRegion>>) -> Scene {
-        Scene {
+        let mut scene = Scene {
             aggregate: aggregate.clone(),
             lights: lights.clone(),
-            volume_region: volume_region
+            volume_region: volume_region,
+            light_distribution: None,
+            light_bvh: None
+        };
+
+        scene.light_distribution = scene.power_distribution();
+        scene
+    }
+
+    // Lights need the finished scene to work out how much power they emit
+    // into it, e.g. for distant lights that cover the whole scene
+    fn power_distribution(&self) -> Option<Distribution1D> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let powers: Vec<f32> = self.lights.iter().map(|l| l.power(self).y()).collect();
+        Some(Distribution1D::new(&powers))
+    }
+
+    // Replaces the scene's lights, e.g. between the frames of an animation
+    // where they move or change their intensity, without rebuilding the
+    // rest of the scene. If the lights match the old ones one for one, the
+    // light BVH is refit to them rather than built again.
+    pub fn update_lights(&mut self, lights: Vec<Arc<dyn Light>>) {
+        self.lights = lights;
+        self.light_distribution = self.power_distribution();
+
+        if self.lights.is_empty() {
+            self.light_bvh = None;
+        } else if let Some(ref mut bvh) = self.light_bvh {
+            bvh.refit(&self.lights);
+        }
+    }
+
+    // Builds a light BVH so that sample_light_at picks lights by how much
+    // they are likely to contribute at each point instead of by power.
+    pub fn with_light_bvh(mut self) -> Scene {
+        if !self.lights.is_empty() {
+            self.light_bvh = Some(LightBVH::new(&self.lights));
         }
+        self
     }
 
     pub fn lights(&self) -> Vec<Arc<dyn Light>> {
         self.lights.clone()
     }
 
+    pub fn volume_region(&self) -> Option<&Arc<dyn VolumeRegion>> {
+        self.volume_region.as_ref()
+    }
+
+    // Picks a light in proportion to its power using u in [0, 1) and
+    // returns it along with the probability of picking it, or None if the
+    // scene has no lights.
+    pub fn sample_light(&self, u: f32) -> Option<(&Arc<dyn Light>, f32)> {
+        let distrib = self.light_distribution.as_ref()?;
+        let (idx, pdf) = distrib.sample_discrete(u);
+        Some((&self.lights[idx], pdf))
+    }
+
+    // Returns the probability that sample_light picks the idx-th light
+    pub fn light_pdf(&self, idx: usize) -> f32 {
+        self.light_distribution.as_ref().map_or(0.0, |d| d.discrete_pdf(idx))
+    }
+
+    // Picks a light to sample for the point p with surface normal n, using
+    // the light BVH if the scene has one and falling back to sample_light
+    // otherwise. Returns the light along with the probability of picking
+    // it, or None if no light contributes.
+    pub fn sample_light_at(&self, p: &Point, n: &Normal, u: f32)
+                           -> Option<(&Arc<dyn Light>, f32)> {
+        match self.light_bvh {
+            Some(ref bvh) => bvh.sample(p, n, u).map(|(idx, pmf)| (&self.lights[idx], pmf)),
+            None => self.sample_light(u)
+        }
+    }
+
+    // Returns the probability that sample_light_at picks the idx-th light
+    // for the point p with surface normal n
+    pub fn light_pdf_at(&self, p: &Point, n: &Normal, idx: usize) -> f32 {
+        match self.light_bvh {
+            Some(ref bvh) => bvh.pmf(p, n, idx),
+            None => self.light_pdf(idx)
+        }
+    }
+
     // Scene Public methods 23
 }
 
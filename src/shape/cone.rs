@@ -0,0 +1,308 @@
+use std::f32::consts::PI;
+
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::shape::ShapeBase;
+use crate::shape::ShapeIntersection;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Degrees;
+use crate::utils::gamma;
+
+use crate::shape::helpers::compute_dg;
+
+// A cone with its base on the xy plane, centered at the origin, and its
+// apex on the z axis at the given height
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cone {
+    base: ShapeBase,
+    radius: f32,
+    height: f32,
+    phi_max: f32
+}
+
+impl Cone {
+    pub fn new(o2w: Transform, w2o: Transform, ro: bool,
+               rad: f32, ht: f32, pm: f32) -> Cone {
+        let phi_max = pm.clamp(0.0, 360.0).as_radians();
+        let periodic_u = pm >= 360.0;
+        Cone {
+            base: ShapeBase::new(o2w, w2o, ro).with_periodic_u(periodic_u),
+            radius: rad,
+            height: ht,
+            phi_max
+        }
+    }
+
+    fn get_intersection_point(&self, r: &Ray) -> Option<(f32, f32)> {
+        if self.height == 0.0 {
+            return None;
+        }
+
+        // Compute quadratic cone coefficients
+        let k = (self.radius / self.height) * (self.radius / self.height);
+        let dz = r.o.z - self.height;
+        let a = r.d.x * r.d.x + r.d.y * r.d.y - k * r.d.z * r.d.z;
+        let b = 2.0 * (r.d.x * r.o.x + r.d.y * r.o.y - k * r.d.z * dz);
+        let c = r.o.x * r.o.x + r.o.y * r.o.y - k * dz * dz;
+
+        // Solve quadratic equation for t values
+        let (t0, t1) = crate::utils::quadratic(a, b, c)?;
+
+        // Compute intersection distance along ray
+        if t0 > r.maxt() || t1 < r.mint() {
+            return None
+        }
+
+        let mut t_hit = t0;
+        if t0 < r.mint() {
+            t_hit = t1;
+            if t_hit > r.maxt() {
+                return None;
+            }
+        }
+
+        // Compute cone hit point and Phi
+        let get_hit = |t: f32| {
+            let hit = r.point_at(t);
+            let mut angle = hit.y.atan2(hit.x);
+            if angle < 0.0 {
+                angle += 2.0 * PI;
+            }
+            (hit, angle)
+        };
+
+        // Both nappes of the cone solve the quadratic, but only the one
+        // between the base and the apex is part of the shape
+        let (z_min, z_max) = (self.height.min(0.0), self.height.max(0.0));
+        let invalid_hit = |hit: &(Point, f32)| {
+            hit.0.z < z_min || hit.0.z > z_max || hit.1 > self.phi_max
+        };
+
+        // Test cone intersection against clipping parameters
+        let mut p_hit = get_hit(t_hit);
+        if invalid_hit(&p_hit) {
+            if t_hit == t1 { return None; }
+            if t1 > r.maxt() { return None; }
+            t_hit = t1;
+            p_hit = get_hit(t_hit);
+            if invalid_hit(&p_hit) { return None; }
+        }
+
+        Some((t_hit, p_hit.1))
+    }
+
+    pub fn base(&self) -> &ShapeBase { &self.base }
+
+    pub fn object_bound(&self) -> BBox {
+        BBox::new_with(
+            Point::new_with(-self.radius, -self.radius, self.height.min(0.0)),
+            Point::new_with(self.radius, self.radius, self.height.max(0.0)))
+    }
+
+    pub fn area(&self) -> f32 {
+        // Unroll the sector of a disk whose radius is the slant height
+        self.radius * (self.height * self.height + self.radius * self.radius).sqrt() *
+            self.phi_max / 2.0
+    }
+
+    // Uniformly samples a point on the cone, returning it along with the
+    // surface normal there in world space. The circles around the axis grow
+    // linearly with their distance from the apex, and so does the density
+    // of that distance.
+    pub fn sample(&self, u1: f32, u2: f32) -> (Point, Normal) {
+        let s = u1.sqrt();
+        let phi = u2 * self.phi_max;
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let p = Point::new_with(self.radius * s * cos_phi, self.radius * s * sin_phi,
+                                self.height * (1.0 - s));
+        let o2w = &self.base().object2world;
+        let n = Normal::new_with(self.height * cos_phi, self.height * sin_phi, self.radius);
+        let ns = o2w.xf(n).normalize();
+        let ns = if self.base().reverse_orientation { -ns } else { ns };
+        (o2w.xf(p), ns)
+    }
+
+    // The density of sample choosing p with respect to surface area
+    pub fn pdf(&self, _p: &Point) -> f32 { 1.0 / self.area() }
+}
+
+impl HasBounds for Cone {
+    fn world_bound(&self) -> BBox {
+        self.base().object2world.xf(self.object_bound())
+    }
+}
+
+impl Intersectable<ShapeIntersection> for Cone {
+    fn intersect_p(&self, r: &Ray) -> bool {
+        // Transform ray to object space
+        let ray = self.base().world2object.t(r);
+        self.get_intersection_point(&ray).is_some()
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+        // Transform ray to object space
+        let ray = self.base().world2object.t(r);
+        let (t_hit, phi) = self.get_intersection_point(&ray)?;
+        // Refine the hit point by projecting it back onto the cone at its
+        // height, which leaves only the error of the projection
+        let mut p_hit = ray.point_at(t_hit);
+        let hit_rad = (p_hit.x * p_hit.x + p_hit.y * p_hit.y).sqrt();
+        if hit_rad > 0.0 {
+            let cone_rad = self.radius * (1.0 - p_hit.z / self.height);
+            p_hit.x *= cone_rad / hit_rad;
+            p_hit.y *= cone_rad / hit_rad;
+        }
+        let p_error = self.base().object2world.xf_point_error(
+            &p_hit, &(gamma(5) * Vector::from(p_hit.clone()).abs()));
+
+        // Find parametric representation of cone hit. The apex is
+        // degenerate, so nudge v away from it.
+        let u = phi / self.phi_max;
+        let v = (p_hit.z / self.height).min(1.0 - 1e-5);
+
+        // Compute cone dpdu and dpdv
+        let dpdu = self.phi_max * Vector::new_with(-p_hit.y, p_hit.x, 0.0);
+        let dpdv = Vector::new_with(-p_hit.x / (1.0 - v), -p_hit.y / (1.0 - v), self.height);
+
+        // Compute cone dndu and dndv
+        let d2pduu = -self.phi_max * self.phi_max *
+            Vector::new_with(p_hit.x, p_hit.y, 0.0);
+        let d2pduv = self.phi_max / (1.0 - v) * Vector::new_with(p_hit.y, -p_hit.x, 0.0);
+        let d2pdvv = Vector::new();
+
+        // Initialize DifferentialGeometry from parametric information
+        let dg = compute_dg(self.base(), u, v, p_hit,
+                            dpdu, dpdv, d2pduu, d2pduv, d2pdvv);
+
+        Some(ShapeIntersection::new(t_hit, p_error, dg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::geometry::normal::Normalize;
+    use crate::geometry::vector::Dot;
+
+    #[test]
+    fn it_has_bounds() {
+        assert_eq!(Cone::new(Transform::new(), Transform::new(), false,
+                             0.5, 2.0, 360.0).object_bound(),
+                   BBox::new_with(
+                       Point::new_with(-0.5, -0.5, 0.0),
+                       Point::new_with(0.5, 0.5, 2.0)));
+
+        // Cones can point down too
+        assert_eq!(Cone::new(Transform::new(), Transform::new(), false,
+                             0.5, -2.0, 360.0).object_bound(),
+                   BBox::new_with(
+                       Point::new_with(-0.5, -0.5, -2.0),
+                       Point::new_with(0.5, 0.5, 0.0)));
+    }
+
+    #[test]
+    fn it_can_be_intersected() {
+        let cone = Cone::new(Transform::new(), Transform::new(), false, 1.0, 2.0, 360.0);
+
+        // Halfway up, the cone has half of its radius
+        assert!(cone.intersect_p(
+            &Ray::new_with(Point::new_with(0.49, 0.0, 3.0),
+                           Vector::new_with(0.0, 0.0, -1.0), 0.0)));
+        assert!(!cone.intersect_p(
+            &Ray::new_with(Point::new_with(1.01, 0.0, 3.0),
+                           Vector::new_with(0.0, 0.0, -1.0), 0.0)));
+
+        // The other nappe of the cone, above the apex, isn't part of it
+        assert!(!cone.intersect_p(
+            &Ray::new_with(Point::new_with(-5.0, 0.0, 3.0),
+                           Vector::new_with(1.0, 0.0, 0.0), 0.0)));
+
+        // Nor is the one below its base
+        assert!(!cone.intersect_p(
+            &Ray::new_with(Point::new_with(-5.0, 0.0, -0.5),
+                           Vector::new_with(1.0, 0.0, 0.0), 0.0)));
+
+        // Rays hit the inside of the cone from within
+        let from_inside = cone.intersect(
+            &Ray::new_with(Point::new_with(0.0, 0.0, 0.5),
+                           Vector::new_with(1.0, 0.0, 0.0), 0.0)).unwrap();
+        assert!((from_inside.t_hit - 0.75).abs() < 1e-5);
+
+        // Partial cones only cover some angles
+        let partial = Cone::new(Transform::new(), Transform::new(), false, 1.0, 2.0, 90.0);
+        assert!(partial.intersect_p(
+            &Ray::new_with(Point::new_with(3.0, 3.0, 0.5),
+                           Vector::new_with(-1.0, -1.0, 0.0), 0.0)));
+        assert!(!partial.intersect_p(
+            &Ray::new_with(Point::new_with(-3.0, -0.2, 0.5),
+                           Vector::new_with(1.0, 0.0, 0.0), 0.0)));
+    }
+
+    #[test]
+    fn it_has_intersection_information() {
+        let xf = Transform::translate(&Vector::new_with(0.0, 0.0, 1.0));
+        let cone = Cone::new(xf.clone(), xf.inverse(), false, 1.0, 1.0, 360.0);
+
+        // Shoot at the cone's side halfway up, at phi = pi / 2
+        let r = Ray::new_with(Point::new_with(0.0, 5.0, 1.5),
+                              Vector::new_with(0.0, -1.0, 0.0), 0.0);
+        let si = cone.intersect(&r).unwrap();
+        assert!((si.t_hit - 4.5).abs() < 1e-5);
+        assert!((si.dg.p.clone() - Point::new_with(0.0, 0.5, 1.5)).length_squared() < 1e-8);
+        assert!((si.dg.u - 0.25).abs() < 1e-5);
+        assert!((si.dg.v - 0.5).abs() < 1e-5);
+
+        // The side slopes at 45 degrees
+        let n = Vector::from(si.dg.nn.clone());
+        assert!((n.dot(&Vector::new_with(0.0, 1.0, 1.0).normalize()).abs() - 1.0).abs() < 1e-5);
+
+        // v runs from the base to the apex
+        assert!((si.dg.dpdv - Vector::new_with(0.0, -1.0, 1.0)).length_squared() < 1e-8);
+        assert!((si.dg.dpdu - Vector::new_with(-PI, 0.0, 0.0)).length_squared() < 1e-8);
+    }
+
+    #[test]
+    fn it_has_a_surface_area() {
+        // Its lateral surface is a sector of a disk with the slant height
+        let cone = Cone::new(Transform::new(), Transform::new(), false, 3.0, 4.0, 360.0);
+        assert!((cone.area() - PI * 3.0 * 5.0).abs() < 1e-4);
+
+        let half = Cone::new(Transform::new(), Transform::new(), false, 3.0, 4.0, 180.0);
+        assert!((half.area() - PI * 3.0 * 5.0 / 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn it_samples_points_uniformly_over_its_surface() {
+        let xf = Transform::translate(&Vector::new_with(0.0, 0.0, 1.0));
+        let cone = Cone::new(xf.clone(), xf.inverse(), false, 1.0, 1.0, 360.0);
+
+        let n = 64;
+        let mut z_sum = 0.0;
+        for i in 0..(n * n) {
+            let u1 = ((i % n) as f32 + 0.5) / (n as f32);
+            let u2 = ((i / n) as f32 + 0.5) / (n as f32);
+            let (p, ns) = cone.sample(u1, u2);
+
+            // Points are on the side of the cone, and their normals match
+            // the ones found by intersecting it there
+            let r = (p.x * p.x + p.y * p.y).sqrt();
+            assert!((r - (2.0 - p.z)).abs() < 1e-5);
+            let origin = Point::new_with(0.0, 0.0, p.z);
+            let si = cone.intersect(&Ray::new_with(origin.clone(), &p - &origin, 0.0)).unwrap();
+            assert!((Vector::from(si.dg.nn).dot(&Vector::from(ns)).abs() - 1.0).abs() < 1e-4);
+            z_sum += p.z - 1.0;
+        }
+
+        // Most of the area is close to the base
+        assert!((z_sum / ((n * n) as f32) - 1.0 / 3.0).abs() < 1e-3);
+        assert!((cone.pdf(&Point::new()) * cone.area() - 1.0).abs() < 1e-6);
+    }
+}
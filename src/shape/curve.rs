@@ -0,0 +1,492 @@
+use std::sync::Arc;
+
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bbox::Union;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::{Cross, Dot, Vector, coordinate_system};
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::shape::ShapeBase;
+use crate::shape::ShapeIntersection;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Lerp;
+
+// How the width of a curve is oriented. Flat curves always face the ray
+// that hits them, cylinders also do but shade as if they were round, and
+// ribbons are oriented by normals given at either end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CurveType {
+    Flat,
+    Cylinder,
+    Ribbon
+}
+
+impl CurveType {
+    pub fn from_name(name: &str) -> Option<CurveType> {
+        match name {
+            "flat" => Some(CurveType::Flat),
+            "cylinder" => Some(CurveType::Cylinder),
+            "ribbon" => Some(CurveType::Ribbon),
+            _ => None
+        }
+    }
+}
+
+// The point at the blossom p(u0, u1, u2) of a cubic Bezier curve. Giving
+// it the ends of a subinterval three ways gives the control points of that
+// part of the curve.
+fn blossom_bezier(p: &[Point; 4], u0: f32, u1: f32, u2: f32) -> Point {
+    let a = [p[0].lerp(&p[1], u0), p[1].lerp(&p[2], u0), p[2].lerp(&p[3], u0)];
+    let b = [a[0].lerp(&a[1], u1), a[1].lerp(&a[2], u1)];
+    b[0].lerp(&b[1], u2)
+}
+
+fn segment_control_points(p: &[Point; 4], u_min: f32, u_max: f32) -> [Point; 4] {
+    [blossom_bezier(p, u_min, u_min, u_min), blossom_bezier(p, u_min, u_min, u_max),
+     blossom_bezier(p, u_min, u_max, u_max), blossom_bezier(p, u_max, u_max, u_max)]
+}
+
+// Splits a cubic Bezier curve in half, returning the control points of both
+// halves, which share the middle one
+fn subdivide_bezier(cp: &[Point; 4]) -> [Point; 7] {
+    let mid = |a: &Point, b: &Point| a.lerp(b, 0.5);
+    let c01 = mid(&cp[0], &cp[1]);
+    let c12 = mid(&cp[1], &cp[2]);
+    let c23 = mid(&cp[2], &cp[3]);
+    let c012 = mid(&c01, &c12);
+    let c123 = mid(&c12, &c23);
+    [cp[0].clone(), c01, c012.clone(), mid(&c012, &c123), c123, c23, cp[3].clone()]
+}
+
+// The point on a cubic Bezier curve at u, along with its derivative
+fn eval_bezier(cp: &[Point; 4], u: f32) -> (Point, Vector) {
+    let cp1 = [cp[0].lerp(&cp[1], u), cp[1].lerp(&cp[2], u), cp[2].lerp(&cp[3], u)];
+    let cp2 = [cp1[0].lerp(&cp1[1], u), cp1[1].lerp(&cp1[2], u)];
+    let deriv = if (&cp2[1] - &cp2[0]).length_squared() > 0.0 {
+        3.0 * (&cp2[1] - &cp2[0])
+    } else {
+        // The derivative vanishes where control points coincide, so use the
+        // direction between the first and last ones instead
+        &cp[3] - &cp[0]
+    };
+    (cp2[0].lerp(&cp2[1], u), deriv)
+}
+
+// The bounds of the control points in each axis
+fn bounds(cp: &[Point]) -> (Point, Point) {
+    cp.iter().fold((cp[0].clone(), cp[0].clone()), |(lo, hi), p| {
+        (Point::new_with(lo.x.min(p.x), lo.y.min(p.y), lo.z.min(p.z)),
+         Point::new_with(hi.x.max(p.x), hi.y.max(p.y), hi.z.max(p.z)))
+    })
+}
+
+// What a cubic Bezier segment of a curve shares with each of the pieces it
+// is split into
+#[derive(Debug, PartialEq, Clone)]
+struct CurveSegment {
+    cp: [Point; 4],
+    width: [f32; 2],
+    // The normals at either end of ribbons, and the angle between them
+    n: [Vector; 2],
+    normal_angle: f32,
+    inv_sin_normal_angle: f32
+}
+
+impl CurveSegment {
+    fn width_at(&self, u: f32) -> f32 { self.width[0].lerp(&self.width[1], u) }
+
+    // Spherically interpolates the ribbon's normals
+    fn normal_at(&self, u: f32) -> Vector {
+        if self.normal_angle == 0.0 {
+            return self.n[0].clone();
+        }
+
+        let sin0 = ((1.0 - u) * self.normal_angle).sin() * self.inv_sin_normal_angle;
+        let sin1 = (u * self.normal_angle).sin() * self.inv_sin_normal_angle;
+        sin0 * &self.n[0] + sin1 * &self.n[1]
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct CurveCommon {
+    ty: CurveType,
+    segments: Vec<CurveSegment>
+}
+
+// A piecewise cubic Bezier curve, whose width is interpolated from one end
+// to the other. It has to be refined into pieces before it can be
+// intersected, which is done by recursively splitting each piece.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Curves {
+    base: ShapeBase,
+    common: Arc<CurveCommon>,
+    split_depth: usize
+}
+
+impl Curves {
+    // Every three control points after the first add a segment. Ribbons
+    // need a normal at the start of every segment and at the end of the
+    // last one. Each segment is split into 2^split_depth pieces.
+    pub fn new(o2w: Transform, w2o: Transform, ro: bool, ty: CurveType,
+               cp: &[Point], width0: f32, width1: f32, normals: Option<&[Normal]>,
+               split_depth: usize) -> Curves {
+        assert!(cp.len() >= 4 && (cp.len() - 1).is_multiple_of(3));
+        let num_segments = (cp.len() - 1) / 3;
+        let normals: Vec<Vector> = match normals {
+            Some(n) if ty == CurveType::Ribbon => {
+                assert_eq!(n.len(), num_segments + 1);
+                n.iter().map(|n| Vector::from(n).normalize()).collect()
+            },
+            _ => vec![Vector::new(); num_segments + 1]
+        };
+
+        let segments = (0..num_segments).map(|seg| {
+            let t0 = seg as f32 / num_segments as f32;
+            let t1 = (seg + 1) as f32 / num_segments as f32;
+            let n = [normals[seg].clone(), normals[seg + 1].clone()];
+            let normal_angle = n[0].dot(&n[1]).clamp(0.0, 1.0).acos();
+            CurveSegment {
+                cp: [cp[3 * seg].clone(), cp[3 * seg + 1].clone(),
+                     cp[3 * seg + 2].clone(), cp[3 * seg + 3].clone()],
+                width: [width0.lerp(&width1, t0), width0.lerp(&width1, t1)],
+                n,
+                normal_angle,
+                inv_sin_normal_angle: 1.0 / normal_angle.sin()
+            }
+        }).collect();
+
+        Curves {
+            base: ShapeBase::new(o2w, w2o, ro),
+            common: Arc::new(CurveCommon { ty, segments }),
+            split_depth
+        }
+    }
+
+    pub fn base(&self) -> &ShapeBase { &self.base }
+
+    pub fn object_bound(&self) -> BBox {
+        self.refine().iter().fold(BBox::new(), |b, c| b.unioned_with(c.object_bound()))
+    }
+
+    pub fn area(&self) -> f32 { self.refine().iter().map(|c| c.area()).sum() }
+
+    pub fn refine(&self) -> Vec<Curve> {
+        let num_pieces = 1 << self.split_depth;
+        (0..self.common.segments.len()).flat_map(|segment| {
+            (0..num_pieces).map(move |i| Curve {
+                base: self.base.refined(),
+                common: self.common.clone(),
+                segment,
+                u_min: i as f32 / num_pieces as f32,
+                u_max: (i + 1) as f32 / num_pieces as f32
+            })
+        }).collect()
+    }
+}
+
+impl HasBounds for Curves {
+    fn world_bound(&self) -> BBox {
+        self.refine().iter().fold(BBox::new(), |b, c| b.unioned_with(c.world_bound()))
+    }
+}
+
+// Where a ray hit a curve, in the curve's object space
+struct CurveHit {
+    t: f32,
+    u: f32,
+    v: f32,
+    dpdu: Vector,
+    dpdv: Vector,
+    width: f32
+}
+
+// The part of one segment of a curve between u_min and u_max
+#[derive(Debug, PartialEq, Clone)]
+pub struct Curve {
+    base: ShapeBase,
+    common: Arc<CurveCommon>,
+    segment: usize,
+    u_min: f32,
+    u_max: f32
+}
+
+impl Curve {
+    fn segment(&self) -> &CurveSegment { &self.common.segments[self.segment] }
+
+    pub fn base(&self) -> &ShapeBase { &self.base }
+
+    pub fn object_bound(&self) -> BBox {
+        let seg = self.segment();
+        let cp = segment_control_points(&seg.cp, self.u_min, self.u_max);
+        let (lo, hi) = bounds(&cp);
+        let half_width = 0.5 * seg.width_at(self.u_min).max(seg.width_at(self.u_max));
+        let pad = Vector::new_with(half_width, half_width, half_width);
+        BBox::new_with(lo - &pad, hi + &pad)
+    }
+
+    pub fn area(&self) -> f32 {
+        // Approximate the length of the curve by that of its control polygon
+        let seg = self.segment();
+        let cp = segment_control_points(&seg.cp, self.u_min, self.u_max);
+        let length: f32 = cp.windows(2).map(|p| (&p[1] - &p[0]).length()).sum();
+        let avg_width = 0.5 * (seg.width_at(self.u_min) + seg.width_at(self.u_max));
+        length * avg_width
+    }
+
+    // Finds where the ray, which lies along the z axis of ray space, hits
+    // the curve with control points cp in ray space. The curve is split in
+    // half until depth reaches zero, skipping halves whose bounds the ray
+    // misses, and then treated as a line segment.
+    fn recursive_intersect(&self, ray: &Ray, cp: &[Point; 4], object_to_ray: &Transform,
+                           u0: f32, u1: f32, depth: usize, z_max: f32) -> Option<CurveHit> {
+        let ray_length = ray.d.length();
+        let seg = self.segment();
+        if depth > 0 {
+            let cp_split = subdivide_bezier(cp);
+            let u = [u0, 0.5 * (u0 + u1), u1];
+            let mut closest: Option<CurveHit> = None;
+            for half in 0..2 {
+                let cps = [cp_split[3 * half].clone(), cp_split[3 * half + 1].clone(),
+                           cp_split[3 * half + 2].clone(), cp_split[3 * half + 3].clone()];
+                let half_width = 0.5 * seg.width_at(u[half]).max(seg.width_at(u[half + 1]));
+                let z_max = closest.as_ref().map_or(z_max, |h| h.t * ray_length);
+                let (lo, hi) = bounds(&cps);
+                if hi.x + half_width < 0.0 || lo.x - half_width > 0.0 ||
+                    hi.y + half_width < 0.0 || lo.y - half_width > 0.0 ||
+                    hi.z + half_width < 0.0 || lo.z - half_width > z_max {
+                    continue;
+                }
+
+                if let Some(hit) = self.recursive_intersect(ray, &cps, object_to_ray,
+                                                            u[half], u[half + 1],
+                                                            depth - 1, z_max) {
+                    closest = Some(hit);
+                }
+            }
+            return closest;
+        }
+
+        // Test the ray against the planes through the ends of the segment,
+        // perpendicular to the curve there
+        let edge = (cp[1].y - cp[0].y) * -cp[0].y + cp[0].x * (cp[0].x - cp[1].x);
+        if edge < 0.0 {
+            return None;
+        }
+        let edge = (cp[2].y - cp[3].y) * -cp[3].y + cp[3].x * (cp[3].x - cp[2].x);
+        if edge < 0.0 {
+            return None;
+        }
+
+        // Find the closest point on the line between the ends to the ray
+        let (sx, sy) = (cp[3].x - cp[0].x, cp[3].y - cp[0].y);
+        let denom = sx * sx + sy * sy;
+        if denom == 0.0 {
+            return None;
+        }
+        let w = (-cp[0].x * sx - cp[0].y * sy) / denom;
+
+        // Find the u coordinate of the hit and the curve's width there
+        let u = u0.lerp(&u1, w).clamp(u0, u1);
+        let mut hit_width = seg.width_at(u);
+        let n_hit = if self.common.ty == CurveType::Ribbon {
+            // Ribbons seen at an angle look narrower
+            let n = seg.normal_at(u);
+            hit_width *= n.abs_dot(&ray.d) / ray_length;
+            n
+        } else { Vector::new() };
+
+        // Test the hit against the curve's width
+        let (pc, dpcdw) = eval_bezier(cp, w.clamp(0.0, 1.0));
+        let dist2 = pc.x * pc.x + pc.y * pc.y;
+        if dist2 > hit_width * hit_width * 0.25 || pc.z < 0.0 || pc.z > z_max {
+            return None;
+        }
+
+        // Find how far across the curve's width the hit is
+        let dist = dist2.sqrt();
+        let edge_func = dpcdw.x * -pc.y + pc.x * dpcdw.y;
+        let v = if edge_func > 0.0 { 0.5 + dist / hit_width } else { 0.5 - dist / hit_width };
+
+        let (_, dpdu) = eval_bezier(&seg.cp, u);
+        let dpdv = if self.common.ty == CurveType::Ribbon {
+            n_hit.cross_with(&dpdu).normalize() * hit_width
+        } else {
+            // Flat curves face the ray, and cylinders turn away from it
+            // towards their sides
+            let dpdu_plane = object_to_ray.xf(dpdu.clone());
+            let dpdv_plane =
+                Vector::new_with(-dpdu_plane.y, dpdu_plane.x, 0.0).normalize() * hit_width;
+            let dpdv_plane = if self.common.ty == CurveType::Cylinder {
+                let theta = (-90f32).lerp(&90.0, v);
+                Transform::rotate(-theta, &dpdu_plane).xf(dpdv_plane)
+            } else { dpdv_plane };
+            object_to_ray.inverse().xf(dpdv_plane)
+        };
+
+        Some(CurveHit { t: pc.z / ray_length, u, v, dpdu, dpdv, width: hit_width })
+    }
+
+    fn intersect_object(&self, ray: &Ray) -> Option<CurveHit> {
+        let seg = self.segment();
+        let cp_obj = segment_control_points(&seg.cp, self.u_min, self.u_max);
+
+        // Project the curve into a space where the ray runs along the z axis
+        let dx = ray.d.cross_with(&(&cp_obj[3] - &cp_obj[0]));
+        let dx = if dx.length_squared() == 0.0 { coordinate_system(&ray.d).0 } else { dx };
+        let object_to_ray = Transform::look_at(&ray.o, &(&ray.o + &ray.d), &dx);
+        let cp = [object_to_ray.xf(cp_obj[0].clone()), object_to_ray.xf(cp_obj[1].clone()),
+                  object_to_ray.xf(cp_obj[2].clone()), object_to_ray.xf(cp_obj[3].clone())];
+
+        // Split the curve until its pieces are nearly flat, as measured by
+        // the second differences of the control points
+        let l0 = (0..2).map(|i| {
+            let d = |a: f32, b: f32, c: f32| (a - 2.0 * b + c).abs();
+            d(cp[i].x, cp[i + 1].x, cp[i + 2].x)
+                .max(d(cp[i].y, cp[i + 1].y, cp[i + 2].y))
+                .max(d(cp[i].z, cp[i + 1].z, cp[i + 2].z))
+        }).fold(0.0, f32::max);
+        let eps = seg.width[0].max(seg.width[1]) * 0.05;
+        let r0 = (std::f32::consts::SQRT_2 * 6.0 * l0 / (8.0 * eps)).log2().floor() / 2.0;
+        let max_depth = if r0.is_finite() { r0.clamp(0.0, 10.0) as usize } else { 0 };
+
+        let z_max = ray.d.length() * ray.maxt();
+        let hit = self.recursive_intersect(ray, &cp, &object_to_ray, self.u_min, self.u_max,
+                                           max_depth, z_max)?;
+        if hit.t < ray.mint() || hit.t > ray.maxt() { None } else { Some(hit) }
+    }
+}
+
+impl HasBounds for Curve {
+    fn world_bound(&self) -> BBox {
+        self.base().object2world.xf(self.object_bound())
+    }
+}
+
+impl Intersectable<ShapeIntersection> for Curve {
+    fn intersect_p(&self, r: &Ray) -> bool {
+        let ray = self.base().world2object.t(r);
+        self.intersect_object(&ray).is_some()
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+        let ray = self.base().world2object.t(r);
+        let hit = self.intersect_object(&ray)?;
+
+        // The hit point is only as close to the curve as the curve is wide,
+        // since its surface is approximated when it's intersected
+        let o2w = &self.base().object2world;
+        let p_hit = ray.point_at(hit.t);
+        let p_error = o2w.xf_point_error(&p_hit, &Vector::new_with(
+            2.0 * hit.width, 2.0 * hit.width, 2.0 * hit.width));
+
+        // Curves are too thin to bend their normals across their width
+        let dg = DifferentialGeometry::new_with(
+            o2w.xf(p_hit), o2w.xf(hit.dpdu), o2w.xf(hit.dpdv),
+            Normal::new(), Normal::new(), hit.u, hit.v, Some(self.base().clone()));
+        Some(ShapeIntersection::new(hit.t, p_error, dg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn straight(ty: CurveType, normals: Option<&[Normal]>) -> Curves {
+        // A straight curve along the x axis, narrowing from 0.2 to 0.1
+        let cp = [Point::new_with(0.0, 0.0, 0.0), Point::new_with(1.0, 0.0, 0.0),
+                  Point::new_with(2.0, 0.0, 0.0), Point::new_with(3.0, 0.0, 0.0)];
+        Curves::new(Transform::new(), Transform::new(), false, ty, &cp, 0.2, 0.1, normals, 2)
+    }
+
+    fn hit(curves: &Curves, r: &Ray) -> Option<ShapeIntersection> {
+        curves.refine().iter()
+            .filter_map(|c| c.intersect(r))
+            .min_by(|a, b| a.t_hit.partial_cmp(&b.t_hit).unwrap())
+    }
+
+    #[test]
+    fn it_splits_bezier_curves() {
+        let cp = [Point::new_with(0.0, 0.0, 0.0), Point::new_with(1.0, 2.0, 0.0),
+                  Point::new_with(2.0, 2.0, 1.0), Point::new_with(3.0, 0.0, 1.0)];
+        let split = subdivide_bezier(&cp);
+        let (mid, _) = eval_bezier(&cp, 0.5);
+        assert!((&split[3] - &mid).length() < 1e-6);
+
+        // Both halves trace the same curve as the whole
+        let (p, _) = eval_bezier(&[split[3].clone(), split[4].clone(),
+                                   split[5].clone(), split[6].clone()], 0.5);
+        assert!((&p - &eval_bezier(&cp, 0.75).0).length() < 1e-6);
+        let part = segment_control_points(&cp, 0.5, 1.0);
+        assert!(part.iter().zip(split[3..].iter()).all(|(a, b)| (a - b).length() < 1e-6));
+    }
+
+    #[test]
+    fn it_refines_into_pieces() {
+        let curves = straight(CurveType::Flat, None);
+        let pieces = curves.refine();
+        assert_eq!(pieces.len(), 4);
+        assert!(pieces.iter().all(|p| p.base() != curves.base()));
+
+        // The pieces cover the curve, each padded by its width at its wider end
+        let bound = curves.object_bound();
+        assert!((bound.p_min.x + 0.1).abs() < 1e-5);
+        assert!((bound.p_max.x - 3.0625).abs() < 1e-5);
+        assert!((curves.area() - 3.0 * 0.15).abs() < 1e-4);
+    }
+
+    #[test]
+    fn flat_curves_face_the_ray() {
+        let curves = straight(CurveType::Flat, None);
+
+        // The curve narrows along its length
+        let down = Vector::new_with(0.0, 0.0, -1.0);
+        assert!(hit(&curves, &Ray::new_with(Point::new_with(0.5, 0.09, 1.0), down.clone(), 0.0))
+                .is_some());
+        assert!(hit(&curves, &Ray::new_with(Point::new_with(2.5, 0.09, 1.0), down.clone(), 0.0))
+                .is_none());
+
+        let si = hit(&curves, &Ray::new_with(Point::new_with(1.5, 0.0, 1.0), down.clone(), 0.0))
+            .unwrap();
+        assert!((si.t_hit - 1.0).abs() < 1e-4);
+        assert!((si.dg.u - 0.5).abs() < 1e-4);
+        assert!((si.dg.v - 0.5).abs() < 1e-4);
+        assert!(Vector::from(si.dg.nn.clone()).dot(&down).abs() > 0.999);
+
+        // Rays from the side see it facing them too
+        let side = Vector::new_with(0.0, -1.0, 0.0);
+        let si = hit(&curves, &Ray::new_with(Point::new_with(1.5, 1.0, 0.0), side.clone(), 0.0))
+            .unwrap();
+        assert!(Vector::from(si.dg.nn).dot(&side).abs() > 0.999);
+    }
+
+    #[test]
+    fn cylinders_turn_towards_their_sides() {
+        let curves = straight(CurveType::Cylinder, None);
+        let down = Vector::new_with(0.0, 0.0, -1.0);
+        let center = hit(&curves, &Ray::new_with(Point::new_with(1.5, 0.0, 1.0),
+                                                 down.clone(), 0.0)).unwrap();
+        let edge = hit(&curves, &Ray::new_with(Point::new_with(1.5, 0.07, 1.0),
+                                               down.clone(), 0.0)).unwrap();
+        assert!(Vector::from(center.dg.nn).dot(&down).abs() > 0.999);
+        assert!(Vector::from(edge.dg.nn).dot(&down).abs() < 0.9);
+    }
+
+    #[test]
+    fn ribbons_follow_their_normals() {
+        let up = Normal::new_with(0.0, 0.0, 1.0);
+        let curves = straight(CurveType::Ribbon, Some(&[up.clone(), up]));
+
+        // Ribbons can be seen face on, but not edge on
+        let down = Vector::new_with(0.0, 0.0, -1.0);
+        let si = hit(&curves, &Ray::new_with(Point::new_with(1.5, 0.05, 1.0), down, 0.0))
+            .unwrap();
+        assert!(Vector::from(si.dg.nn).z.abs() > 0.999);
+        assert!(hit(&curves, &Ray::new_with(Point::new_with(1.5, 0.0, 1.0),
+                                            Vector::new_with(0.0, -1.0, 0.0), 0.0)).is_none());
+    }
+}
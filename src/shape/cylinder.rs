@@ -1,18 +1,22 @@
 use std::f32::consts::PI;
 
-use bbox::BBox;
-use bbox::HasBounds;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use ray::Ray;
-use shape::ShapeBase;
-use shape::ShapeIntersection;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use utils::Degrees;
-
-use shape::helpers::compute_dg;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::shape::ShapeBase;
+use crate::shape::ShapeIntersection;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Degrees;
+use crate::utils::gamma;
+use crate::utils::Lerp;
+
+use crate::shape::helpers::compute_dg;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Cylinder {
@@ -26,12 +30,14 @@ pub struct Cylinder {
 impl Cylinder {
     pub fn new(o2w: Transform, w2o: Transform, ro: bool,
                rad: f32, z0: f32, z1: f32, pm: f32) -> Cylinder {
+        let phi_max = pm.clamp(0.0, 360.0).as_radians();
+        let periodic_u = pm >= 360.0;
         Cylinder {
-            base: ShapeBase::new(o2w, w2o, ro),
+            base: ShapeBase::new(o2w, w2o, ro).with_periodic_u(periodic_u),
             radius: rad,
             z_min: z0.min(z1),
             z_max: z0.max(z1),
-            phi_max: pm.clamp(0.0, 360.0).as_radians()
+            phi_max
         }
     }
 
@@ -43,7 +49,7 @@ impl Cylinder {
 
         // Solve quadratic equation for t values
         let (t0, t1) = {
-            match ::utils::quadratic(a, b, c) {
+            match crate::utils::quadratic(a, b, c) {
                 None => return None,
                 Some((x, y)) => (x, y)
             }
@@ -105,6 +111,21 @@ impl Cylinder {
         // Unroll the rectangle
         (self.z_max - self.z_min) * self.phi_max * self.radius
     }
+
+    // Uniformly samples a point on the cylinder, returning it along with the
+    // surface normal there in world space.
+    pub fn sample(&self, u1: f32, u2: f32) -> (Point, Normal) {
+        let z = self.z_min.lerp_with(self.z_max, u1);
+        let t = u2 * self.phi_max;
+        let p = Point::new_with(self.radius * t.cos(), self.radius * t.sin(), z);
+        let o2w = &self.base().object2world;
+        let ns = o2w.xf(Normal::new_with(p.x, p.y, 0.0)).normalize();
+        let ns = if self.base().reverse_orientation { -ns } else { ns };
+        (o2w.xf(p), ns)
+    }
+
+    // The density of sample choosing p with respect to surface area
+    pub fn pdf(&self, _p: &Point) -> f32 { 1.0 / self.area() }
 }
 
 impl HasBounds for Cylinder {
@@ -129,7 +150,14 @@ impl Intersectable<ShapeIntersection> for Cylinder {
             if hit.is_some() { hit.unwrap() } else { return None; }
         };
 
-        let p_hit = ray.point_at(t_hit);
+        // Refine the hit point by projecting it back onto the cylinder, which
+        // leaves only the error of the projection
+        let mut p_hit = ray.point_at(t_hit);
+        let hit_rad = (p_hit.x * p_hit.x + p_hit.y * p_hit.y).sqrt();
+        p_hit.x *= self.radius / hit_rad;
+        p_hit.y *= self.radius / hit_rad;
+        let p_error = self.base().object2world.xf_point_error(
+            &p_hit, &(gamma(3) * Vector::new_with(p_hit.x, p_hit.y, 0.0).abs()));
 
         // Find parametric representation of cylinder hit
         let u = phi / self.phi_max;
@@ -149,7 +177,7 @@ impl Intersectable<ShapeIntersection> for Cylinder {
         let dg = compute_dg(self.base(), u, v, p_hit,
                             dpdu, dpdv, d2pduu, d2pduv, d2pdvv);
 
-        Some(ShapeIntersection::new(t_hit, t_hit * 5e-4, dg))
+        Some(ShapeIntersection::new(t_hit, p_error, dg))
     }
 }
 
@@ -158,23 +186,27 @@ mod tests {
     use super::*;
     use ::std::f32::consts::PI;
 
-    use bbox::BBox;
-    use geometry::point::Point;
-    use geometry::normal::Normalize;
-    use geometry::vector::Vector;
-    use intersection::Intersectable;
-    use ray::Ray;
-    use shape::ShapeBase;
-    use transform::transform::Transform;
-    use utils::Degrees;
+    use crate::bbox::BBox;
+    use crate::geometry::point::Point;
+    use crate::geometry::normal::Normalize;
+    use crate::geometry::vector::Dot;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::ray::Ray;
+    use crate::shape::ShapeBase;
+    use crate::transform::transform::Transform;
+    use crate::utils::Degrees;
 
     #[test]
     fn it_can_be_created() {
         let xf = Transform::translate(&Vector::new_with(1.0, 2.0, 3.0));
-        assert_eq!(Cylinder::new(xf.clone(), xf.inverse(), false,
-                                 3.2, 14.0, -3.0, 16.0),
+        // Shapes are only equal to themselves, so borrow the new cylinder's id
+        let cylinder = Cylinder::new(xf.clone(), xf.inverse(), false,
+                                     3.2, 14.0, -3.0, 16.0);
+        assert_eq!(*cylinder.base.object2world, xf);
+        assert_eq!(cylinder,
                    Cylinder {
-                       base: ShapeBase::new(xf.clone(), xf.inverse(), false),
+                       base: cylinder.base.clone(),
                        radius: 3.2,
                        z_min: -3.0,
                        z_max: 14.0,
@@ -312,7 +344,7 @@ mod tests {
         let shape_int = c.intersect(&r).unwrap();
 
         assert!((shape_int.t_hit - (2f32.sqrt() - 1.0)).abs() < 1e-6);
-        assert!((shape_int.ray_epsilon - ((2f32.sqrt() - 1.0) * 5e-4)).abs() < 1e-6);
+        assert!(shape_int.p_error.length() > 0.0 && shape_int.p_error.length() < 1e-6);
 
         let sqrt2_2 = 2f32.sqrt() * 0.5;
         assert!((shape_int.dg.p -
@@ -350,4 +382,29 @@ mod tests {
         assert_eq!(Cylinder::new(xf.clone(), xf.inverse(), false,
                                  1.0, 0.0, 0.0, 360.0).area(), 0.0);
     }
+
+    #[test]
+    fn it_can_be_sampled() {
+        let xf = Transform::translate(&Vector::new_with(1.0, 2.0, 3.0));
+        let cylinder = Cylinder::new(xf.clone(), xf.inverse(), false, 2.0, -1.0, 1.0, 180.0);
+        for (u1, u2) in [(0.0, 0.0), (0.25, 0.5), (0.5, 0.75), (0.9, 0.1)] {
+            let (p, n) = cylinder.sample(u1, u2);
+            let p_obj = xf.inverse().xf(p.clone());
+            assert!(((p_obj.x * p_obj.x + p_obj.y * p_obj.y).sqrt() - 2.0).abs() < 1e-4);
+            assert!(p_obj.z >= -1.0 && p_obj.z <= 1.0 && p_obj.y >= -1e-4);
+
+            // The normal is the same as the one found by intersecting it
+            let r = Ray::new_with(&p + Vector::from(n.clone()), -Vector::from(n.clone()), 0.0);
+            let si = cylinder.intersect(&r).unwrap();
+            assert!((Vector::from(si.dg.nn) - Vector::from(n)).length() < 1e-4);
+        }
+
+        assert_eq!(cylinder.pdf(&Point::new()), 1.0 / cylinder.area());
+
+        // Reversed cylinders have inward facing normals
+        let cylinder = Cylinder::new(xf.clone(), xf.inverse(), true, 2.0, -1.0, 1.0, 360.0);
+        let (p, n) = cylinder.sample(0.25, 0.5);
+        let p_obj = xf.inverse().xf(p);
+        assert!(Vector::from(n).dot(&Vector::new_with(p_obj.x, p_obj.y, 0.0)) < 0.0);
+    }
 }
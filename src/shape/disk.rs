@@ -1,16 +1,18 @@
-use bbox::BBox;
-use bbox::HasBounds;
-use diff_geom::DifferentialGeometry;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use geometry::normal::Normal;
-use intersection::Intersectable;
-use ray::Ray;
-use shape::ShapeBase;
-use shape::ShapeIntersection;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use utils::Degrees;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::intersection::Intersectable;
+use crate::montecarlo::concentric_sample_disk;
+use crate::ray::Ray;
+use crate::shape::ShapeBase;
+use crate::shape::ShapeIntersection;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Degrees;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Disk {
@@ -24,12 +26,14 @@ pub struct Disk {
 impl Disk {
     pub fn new(o2w: Transform, w2o: Transform, ro: bool,
                ht: f32, r: f32, ri: f32, t_max: f32) -> Disk {
+        let phi_max = t_max.clamp(0.0, 360.0).as_radians();
+        let periodic_u = t_max >= 360.0;
         Disk {
-            base: ShapeBase::new(o2w, w2o, ro),
+            base: ShapeBase::new(o2w, w2o, ro).with_periodic_u(periodic_u),
             height: ht,
             radius: r,
             inner_radius: ri,
-            phi_max: t_max.clamp(0.0, 360.0).as_radians()
+            phi_max
         }
     }
 
@@ -81,6 +85,21 @@ impl Disk {
         let ir2 = self.inner_radius * self.inner_radius;
         0.5 * self.phi_max * (r2 - ir2)
     }
+
+    // Uniformly samples a point on the full disk, returning it along with
+    // the surface normal there in world space.
+    // !FIXME! This ignores the inner radius and phi limit
+    pub fn sample(&self, u1: f32, u2: f32) -> (Point, Normal) {
+        let (x, y) = concentric_sample_disk(u1, u2);
+        let p = Point::new_with(x * self.radius, y * self.radius, self.height);
+        let o2w = &self.base().object2world;
+        let ns = o2w.xf(Normal::new_with(0.0, 0.0, 1.0)).normalize();
+        let ns = if self.base().reverse_orientation { -ns } else { ns };
+        (o2w.xf(p), ns)
+    }
+
+    // The density of sample choosing p with respect to surface area
+    pub fn pdf(&self, _p: &Point) -> f32 { 1.0 / self.area() }
 }
 
 impl HasBounds for Disk {
@@ -105,7 +124,11 @@ impl Intersectable<ShapeIntersection> for Disk {
             if hit.is_some() { hit.unwrap() } else { return None; }
         };
 
-        let p_hit = ray.point_at(t_hit);
+        // The hit point is exactly on the plane of the disk once its height
+        // is set, so it has no error before it's transformed
+        let mut p_hit = ray.point_at(t_hit);
+        p_hit.z = self.height;
+        let p_error = self.base().object2world.xf_point_error(&p_hit, &Vector::new());
         let u = phi / self.phi_max;
         let dist = (p_hit.x * p_hit.x + p_hit.y * p_hit.y).sqrt();
         let v = 1.0 - (dist - self.inner_radius) /
@@ -128,7 +151,7 @@ impl Intersectable<ShapeIntersection> for Disk {
             dg.nn = o2w.xf(Normal::new_with(0.0, 0.0, -1.0));
         }
 
-        Some(ShapeIntersection::new(t_hit, t_hit * 5e-4, dg))
+        Some(ShapeIntersection::new(t_hit, p_error, dg))
     }
 }
 
@@ -137,21 +160,23 @@ mod tests {
     use super::*;
     use ::std::f32::consts::PI;
 
-    use bbox::BBox;
-    use geometry::point::Point;
-    use geometry::normal::Normal;
-    use geometry::vector::Vector;
-    use intersection::Intersectable;
-    use ray::Ray;
-    use shape::ShapeBase;
-    use transform::transform::Transform;
+    use crate::bbox::BBox;
+    use crate::geometry::point::Point;
+    use crate::geometry::normal::Normal;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::ray::Ray;
+    use crate::shape::ShapeBase;
+    use crate::transform::transform::Transform;
 
     #[test]
     fn it_can_be_created() {
-        assert_eq!(Disk::new(Transform::new(), Transform::new(), false,
-                             0.0, 1.0, 0.5, 360.0),
+        // Shapes are only equal to themselves, so borrow each new disk's id
+        let disk = Disk::new(Transform::new(), Transform::new(), false,
+                             0.0, 1.0, 0.5, 360.0);
+        assert_eq!(disk,
                    Disk {
-                       base: ShapeBase::new(Transform::new(), Transform::new(), false),
+                       base: disk.base.clone(),
                        height: 0.0,
                        radius: 1.0,
                        inner_radius: 0.5,
@@ -159,9 +184,11 @@ mod tests {
                    });
 
         let xf = Transform::scale(1.0, 2.0, 3.0);
-        assert_eq!(Disk::new(xf.clone(), xf.inverse(), false, 2.0, 0.0, 1.0, 90.0),
+        let disk = Disk::new(xf.clone(), xf.inverse(), false, 2.0, 0.0, 1.0, 90.0);
+        assert_eq!(*disk.base.object2world, xf);
+        assert_eq!(disk,
                    Disk {
-                       base: ShapeBase::new(xf.clone(), xf.inverse(), false),
+                       base: disk.base.clone(),
                        height: 2.0,
                        radius: 0.0,
                        inner_radius: 1.0,
@@ -257,7 +284,7 @@ mod tests {
                 Vector::new_with(0.0, 0.0, -1.0), 0.0)).unwrap();
 
         assert_eq!(info.t_hit, 1.0);
-        assert_eq!(info.ray_epsilon, 5e-4);
+        assert_eq!(info.p_error, Vector::new());
         assert_eq!(info.dg.p, Point::new());
         assert_eq!(info.dg.nn, Normal::new_with(0.0, 0.0, 1.0));
 
@@ -271,7 +298,8 @@ mod tests {
                 Vector::new_with(0.0, 0.0, -1.0), 0.0)).unwrap();
 
         assert_eq!(half_pipe_int.t_hit, 1.0);
-        assert_eq!(half_pipe_int.ray_epsilon, 5e-4);
+        assert_eq!(half_pipe_int.p_error.z, 0.0);
+        assert!(half_pipe_int.p_error.length() < 1e-6);
         assert_eq!(half_pipe_int.dg.p, Point::new_with(0.0, 0.5, 0.0));
         assert_eq!(half_pipe_int.dg.nn, Normal::new_with(0.0, 0.0, 1.0));
         assert_eq!(half_pipe_int.dg.u, 0.5);
@@ -303,4 +331,21 @@ mod tests {
                              0.0, 1.0, 0.5f32.sqrt(), 360.0).area(),
                    0.5 * ::std::f32::consts::PI);
     }
+
+    #[test]
+    fn it_can_be_sampled() {
+        let xf = Transform::rotate_x(90.0);
+        let disk = Disk::new(xf.clone(), xf.inverse(), false, 1.0, 2.0, 0.0, 360.0);
+        for (u1, u2) in [(0.0, 0.0), (0.25, 0.5), (0.5, 0.75), (0.9, 0.1)] {
+            let (p, n) = disk.sample(u1, u2);
+            let p_obj = xf.inverse().xf(p);
+            assert!((p_obj.z - 1.0).abs() < 1e-4);
+            assert!(p_obj.x * p_obj.x + p_obj.y * p_obj.y <= 4.0 + 1e-4);
+            assert!((Vector::from(n) - xf.xf(Vector::new_with(0.0, 0.0, 1.0))).length() < 1e-4);
+        }
+
+        let disk = Disk::new(xf.clone(), xf.inverse(), true, 1.0, 2.0, 0.0, 360.0);
+        let (_, n) = disk.sample(0.25, 0.5);
+        assert!((Vector::from(n) + xf.xf(Vector::new_with(0.0, 0.0, 1.0))).length() < 1e-4);
+    }
 }
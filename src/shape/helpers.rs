@@ -1,12 +1,15 @@
-use diff_geom::DifferentialGeometry;
-use shape::ShapeBase;
-use geometry::point::Point;
-use geometry::vector::Cross;
-use geometry::vector::Dot;
-use geometry::vector::Vector;
-use geometry::normal::Normal;
-use geometry::normal::Normalize;
-use transform::transform::ApplyTransform;
+use crate::diff_geom::DifferentialGeometry;
+use crate::shape::ShapeBase;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::shape::ShapeIntersection;
+use crate::transform::transform::ApplyTransform;
 
 // Note: This is the part where the math kind of escapes me as I haven't
 // actually taken a course on differential geometry. For that, the book recommends
@@ -41,3 +44,17 @@ pub fn compute_dg(shape: &ShapeBase, u: f32, v: f32, p_hit: Point,
         o2w.xf(p_hit), o2w.xf(dpdu), o2w.xf(dpdv), o2w.xf(dndu),
         o2w.xf(dndv), u, v, Some(shape.clone()))
 }
+
+// The density, with respect to solid angle, of choosing the direction wi
+// from p by sampling a point uniformly over the given area of the shape.
+pub fn area_pdf_wi<S: Intersectable<ShapeIntersection>>(shape: &S, area: f32,
+                                                        p: &Point, wi: &Vector) -> f32 {
+    let ray = Ray::new_with(p.clone(), wi.clone(), 1e-3);
+    match shape.intersect(&ray) {
+        None => 0.0,
+        Some(si) => {
+            let cos_theta = si.dg.nn.dot(&(-wi)).abs();
+            p.distance_squared(&si.dg.p) / (cos_theta * area)
+        }
+    }
+}
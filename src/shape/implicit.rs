@@ -0,0 +1,349 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::geometry::vector::coordinate_system;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::shape::ShapeBase;
+use crate::shape::ShapeIntersection;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+
+// The signed distance from a point in object space to a surface, which is
+// negative inside of it. It must never be more than the distance to the
+// closest point of the surface, or sphere tracing can step through it.
+pub type DistanceFunction = Arc<dyn Fn(&Point) -> f32 + Send + Sync>;
+
+// Signed distances sampled at the corners of a regular grid of cells over
+// some bounds, such as those computed from a mesh by another program
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistanceGrid {
+    resolution: [usize; 3],
+    distances: Vec<f32>,
+    bounds: BBox
+}
+
+impl DistanceGrid {
+    // The distances are given with x changing fastest, then y, then z, and
+    // there must be at least two samples along each axis
+    pub fn new(resolution: [usize; 3], distances: &[f32], bounds: BBox) -> DistanceGrid {
+        assert!(resolution.iter().all(|&n| n >= 2));
+        assert_eq!(distances.len(), resolution.iter().product::<usize>());
+        DistanceGrid { resolution, distances: distances.to_vec(), bounds }
+    }
+
+    fn lookup(&self, x: usize, y: usize, z: usize) -> f32 {
+        let [nx, ny, _] = self.resolution;
+        self.distances[(z * ny + y) * nx + x]
+    }
+
+    // Trilinearly interpolates the samples around p, which is clamped to
+    // the bounds of the grid
+    pub fn distance(&self, p: &Point) -> f32 {
+        let offset = self.bounds.offset(p);
+        let mut cell = [0; 3];
+        let mut d = [0.0; 3];
+        for i in 0..3 {
+            let n = self.resolution[i] - 1;
+            let x = (offset[i] * (n as f32)).clamp(0.0, n as f32);
+            cell[i] = (x as usize).min(n - 1);
+            d[i] = x - (cell[i] as f32);
+        }
+
+        let [x, y, z] = cell;
+        let lerp = |a: f32, b: f32, t: f32| (1.0 - t) * a + t * b;
+        let face = |z: usize| {
+            lerp(lerp(self.lookup(x, y, z), self.lookup(x + 1, y, z), d[0]),
+                 lerp(self.lookup(x, y + 1, z), self.lookup(x + 1, y + 1, z), d[0]),
+                 d[1])
+        };
+        lerp(face(z), face(z + 1), d[2])
+    }
+}
+
+// How many steps a ray takes before giving up on hitting the surface, which
+// it may never do if it grazes it
+const MAX_STEPS: usize = 256;
+
+// A surface given implicitly by where its signed distance function is
+// zero, which rays find by sphere tracing (Hart 1996): every step along the
+// ray can go as far as the distance to the surface without passing it.
+// This makes fractals and other procedural geometry easy to describe, but
+// there's no natural parameterization, so (u, v) are the spherical
+// coordinates of the normal, as they would be for a sphere.
+#[derive(Clone)]
+pub struct ImplicitSurface {
+    base: ShapeBase,
+    sdf: DistanceFunction,
+    bounds: BBox,
+
+    // How close to the surface a point must be to be on it, which is also
+    // the step used to find normals from the gradient of the distance
+    epsilon: f32,
+
+    // Computed the first time it's needed, since it takes many evaluations
+    // of the distance function to estimate
+    area: OnceLock<f32>
+}
+
+impl ImplicitSurface {
+    // The surface must lie within the bounds, which are in object space
+    pub fn new(o2w: Transform, w2o: Transform, ro: bool,
+               sdf: DistanceFunction, bounds: BBox) -> ImplicitSurface {
+        let epsilon = 1e-4 * bounds.p_min.distance(&bounds.p_max);
+        ImplicitSurface {
+            base: ShapeBase::new(o2w, w2o, ro),
+            sdf, bounds, epsilon,
+            area: OnceLock::new()
+        }
+    }
+
+    pub fn from_grid(o2w: Transform, w2o: Transform, ro: bool,
+                     grid: DistanceGrid) -> ImplicitSurface {
+        let bounds = grid.bounds.clone();
+        ImplicitSurface::new(o2w, w2o, ro, Arc::new(move |p| grid.distance(p)), bounds)
+    }
+
+    pub fn base(&self) -> &ShapeBase { &self.base }
+
+    pub fn object_bound(&self) -> BBox { self.bounds.clone() }
+
+    fn distance(&self, p: &Point) -> f32 { (self.sdf)(p) }
+
+    // Steps along the object space ray from where it enters the bounds
+    // until it's within epsilon of the surface. Rays that start inside of
+    // the surface find it from the inside, since the steps go by the
+    // magnitude of the distance.
+    fn get_intersection_point(&self, r: &Ray) -> Option<f32> {
+        let (t0, t1) = self.bounds.intersect(r)?;
+        let inv_len = 1.0 / r.d.length();
+        let mut t = t0;
+        for _ in 0..MAX_STEPS {
+            let d = self.distance(&r.point_at(t)).abs();
+            if d < self.epsilon {
+                return Some(t);
+            }
+
+            t += d * inv_len;
+            if t > t1 {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    // The gradient of the distance, found by central differences, which
+    // points away from the inside of the surface
+    fn gradient(&self, p: &Point) -> Vector {
+        let h = self.epsilon;
+        let axis = |v: Vector| self.distance(&(p + &v)) - self.distance(&(p - &v));
+        Vector::new_with(axis(Vector::new_with(h, 0.0, 0.0)),
+                         axis(Vector::new_with(0.0, h, 0.0)),
+                         axis(Vector::new_with(0.0, 0.0, h)))
+    }
+
+    // Estimates the area from how much of the bounds lies within a thin
+    // shell around the surface, whose volume is its area times its
+    // thickness when the distance is exact
+    pub fn area(&self) -> f32 {
+        *self.area.get_or_init(|| {
+            const N: usize = 64;
+            let cell = (&self.bounds.p_max - &self.bounds.p_min) / (N as f32);
+            let half_width = cell.x.max(cell.y).max(cell.z);
+
+            let mut in_shell = 0;
+            for i in 0..(N * N * N) {
+                let (x, y, z) = (i % N, (i / N) % N, i / (N * N));
+                let p = self.bounds.lerp_point((x as f32 + 0.5) / (N as f32),
+                                               (y as f32 + 0.5) / (N as f32),
+                                               (z as f32 + 0.5) / (N as f32));
+                if self.distance(&p).abs() < half_width {
+                    in_shell += 1;
+                }
+            }
+
+            let shell_volume = (in_shell as f32) * cell.x * cell.y * cell.z;
+            shell_volume / (2.0 * half_width)
+        })
+    }
+}
+
+impl fmt::Debug for ImplicitSurface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ImplicitSurface")
+            .field("base", &self.base)
+            .field("bounds", &self.bounds)
+            .field("epsilon", &self.epsilon)
+            .finish()
+    }
+}
+
+// The distance function can't be compared, so surfaces are only equal if
+// they're the same shape
+impl PartialEq for ImplicitSurface {
+    fn eq(&self, other: &ImplicitSurface) -> bool { self.base == other.base }
+}
+
+impl HasBounds for ImplicitSurface {
+    fn world_bound(&self) -> BBox {
+        self.base().object2world.xf(self.object_bound())
+    }
+}
+
+impl Intersectable<ShapeIntersection> for ImplicitSurface {
+    fn intersect_p(&self, r: &Ray) -> bool {
+        // Transform ray to object space
+        let ray = self.base().world2object.t(r);
+        self.get_intersection_point(&ray).is_some()
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+        // Transform ray to object space
+        let ray = self.base().world2object.t(r);
+        let t_hit = self.get_intersection_point(&ray)?;
+        let p_hit = ray.point_at(t_hit);
+
+        // The hit is only known to be within epsilon of the surface, which
+        // is also far enough to offset rays leaving it so that they don't
+        // find it again right away
+        let o2w = &self.base().object2world;
+        let p_error = o2w.xf_point_error(&p_hit, &Vector::new_with(
+            2.0 * self.epsilon, 2.0 * self.epsilon, 2.0 * self.epsilon));
+
+        let gradient = self.gradient(&p_hit);
+        let n = if gradient.length_squared() > 0.0 {
+            gradient.normalize()
+        } else {
+            -ray.d.clone().normalize()
+        };
+
+        let phi = n.y.atan2(n.x);
+        let u = if phi < 0.0 { phi + 2.0 * PI } else { phi } / (2.0 * PI);
+        let v = n.z.clamp(-1.0, 1.0).acos() / PI;
+
+        // Any tangents will do, as long as their cross product is the normal
+        let (dpdu, dpdv) = coordinate_system(&n);
+        let dg = DifferentialGeometry::new_with(
+            o2w.xf(p_hit), o2w.xf(dpdu), o2w.xf(dpdv), Normal::new(), Normal::new(),
+            u, v, Some(self.base().clone()));
+
+        Some(ShapeIntersection::new(t_hit, p_error, dg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::geometry::vector::Dot;
+    use crate::ray::offset_ray_origin;
+
+    fn unit_sphere() -> DistanceFunction {
+        Arc::new(|p: &Point| Vector::from(p.clone()).length() - 1.0)
+    }
+
+    fn sphere_bounds() -> BBox {
+        BBox::new_with(Point::new_with(-1.0, -1.0, -1.0), Point::new_with(1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn it_can_be_intersected() {
+        let xf = Transform::translate(&Vector::new_with(0.0, 0.0, 2.0));
+        let sphere = ImplicitSurface::new(xf.clone(), xf.inverse(), false,
+                                          unit_sphere(), sphere_bounds());
+        assert_eq!(sphere.world_bound(),
+                   BBox::new_with(Point::new_with(-1.0, -1.0, 1.0),
+                                  Point::new_with(1.0, 1.0, 3.0)));
+
+        // Shoot at the sphere from the side
+        let r = Ray::new_with(Point::new_with(-5.0, 0.0, 2.0),
+                              Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        let si = sphere.intersect(&r).unwrap();
+        assert!((si.t_hit - 4.0).abs() < 1e-3);
+        assert!((si.dg.p.clone() - Point::new_with(-1.0, 0.0, 2.0)).length() < 1e-3);
+
+        // The normal faces out of the surface, as do its tangents' cross
+        // product, and it's where (u, v) come from
+        let n = Vector::from(si.dg.nn.clone());
+        assert!((n.dot(&Vector::new_with(-1.0, 0.0, 0.0)) - 1.0).abs() < 1e-3);
+        assert!((si.dg.u - 0.5).abs() < 1e-3);
+        assert!((si.dg.v - 0.5).abs() < 1e-3);
+
+        // Rays that pass by it, or are too short, miss
+        assert!(!sphere.intersect_p(&Ray::new_with(Point::new_with(-5.0, 1.1, 2.0),
+                                                   Vector::new_with(1.0, 0.0, 0.0), 0.0)));
+        let short = Ray::new_with(Point::new_with(-5.0, 0.0, 2.0),
+                                  Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        short.set_maxt(3.5);
+        assert!(!sphere.intersect_p(&short));
+
+        // Rays from inside find it from the inside, and steps are measured
+        // in object space even if the ray isn't of unit length
+        let inside = Ray::new_with(Point::new_with(0.0, 0.0, 2.0),
+                                   Vector::new_with(0.0, 2.0, 0.0), 0.0);
+        let si = sphere.intersect(&inside).unwrap();
+        assert!((si.t_hit - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rays_leaving_it_dont_hit_it_again() {
+        let sphere = ImplicitSurface::new(Transform::new(), Transform::new(), false,
+                                          unit_sphere(), sphere_bounds());
+        let r = Ray::new_with(Point::new_with(0.3, 0.2, 5.0),
+                              Vector::new_with(0.0, 0.0, -1.0), 0.0);
+        let si = sphere.intersect(&r).unwrap();
+
+        // Leaving the outside of the surface doesn't hit it again...
+        let n = si.dg.nn.clone();
+        let out = Vector::from(n.clone());
+        let o = offset_ray_origin(&si.dg.p, &si.p_error, &n, &out);
+        assert!(!sphere.intersect_p(&Ray::new_with(o, out, 0.0)));
+
+        // ... and going into it hits the far side
+        let o = offset_ray_origin(&si.dg.p, &si.p_error, &n, &r.d);
+        let si2 = sphere.intersect(&Ray::new_with(o, r.d.clone(), 0.0)).unwrap();
+        assert!(si2.dg.p.z < -0.5);
+    }
+
+    #[test]
+    fn it_can_be_given_by_a_grid() {
+        // Sample the distance to the plane z = 0.25, which trilinear
+        // interpolation reproduces exactly
+        let n = 5;
+        let distances: Vec<f32> = (0..(n * n * n)).map(|i| {
+            let z = (i / (n * n)) as f32 / ((n - 1) as f32);
+            2.0 * z - 1.0 - 0.25
+        }).collect();
+        let grid = DistanceGrid::new([n, n, n], &distances, sphere_bounds());
+        assert!((grid.distance(&Point::new_with(0.1, 0.7, 0.6)) - 0.35).abs() < 1e-5);
+
+        // Points outside of the grid take the distance at its boundary
+        assert!((grid.distance(&Point::new_with(0.0, 0.0, 3.0)) - 0.75).abs() < 1e-5);
+
+        let plane = ImplicitSurface::from_grid(Transform::new(), Transform::new(), false, grid);
+        let si = plane.intersect(&Ray::new_with(Point::new_with(0.2, -0.3, 4.0),
+                                                Vector::new_with(0.0, 0.0, -1.0), 0.0))
+            .unwrap();
+        assert!((si.dg.p.z - 0.25).abs() < 1e-3);
+        assert!((Vector::from(si.dg.nn.clone()).z - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn it_estimates_its_surface_area() {
+        let sphere = ImplicitSurface::new(Transform::new(), Transform::new(), false,
+                                          unit_sphere(), sphere_bounds());
+        let area = sphere.area();
+        assert!((area - 4.0 * PI).abs() < 0.05 * 4.0 * PI, "Area: {}", area);
+        assert_eq!(sphere.clone().area(), area);
+    }
+}
@@ -1,21 +1,19 @@
-use std::borrow::Borrow;
-use std::sync::{Arc, Weak};
-use std::hash::{Hash, Hasher};
 use std::collections::HashMap;
 
-use bbox::BBox;
-use bbox::HasBounds;
-use bbox::Union;
-use geometry::normal::Normal;
-use geometry::normal::Normalize;
-use geometry::point::Point;
-use geometry::vector::Cross;
-use geometry::vector::Vector;
-use primitive::Refinable;
-use shape::mesh::Mesh;
-use shape::ShapeBase;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bbox::Union;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Vector;
+use crate::primitive::Refinable;
+use crate::shape::mesh::Mesh;
+use crate::shape::ShapeBase;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Lerp;
 
 fn next(i: usize) -> usize { (i + 1) % 3 }
 fn prev(i: usize) -> usize { (i + 2) % 3 }
@@ -28,672 +26,631 @@ fn beta(valence: usize) -> f32 {
     }
 }
 
-// !SPEED! This is a really poor approximation to the C++ code that was provided
-// in the book. Right now we have vectors of reference counted vertices and faces.
-// This means that we need to allocate a new vertex/face every time we add one to
-// the array. It would be significantly faster (and increase cache coherence) if
-// we had something like a RcVec that lets us create a whole reference counted
-// vector and then give out Weak references to the internal elements.
+// The weight of the one-ring that moves a vertex to the limit surface
+fn loop_gamma(valence: usize) -> f32 {
+    1.0 / ((valence as f32) + 3.0 / (8.0 * beta(valence)))
+}
+
+// Edges are identified by their vertices, whichever way around they're given
+fn edge_key(v0: usize, v1: usize) -> (usize, usize) {
+    if v0 < v1 { (v0, v1) } else { (v1, v0) }
+}
 
-#[derive(Debug, Clone)]
+// Vertices and faces refer to each other by their index in the mesh rather
+// than through reference counted pointers, so that a whole level of the
+// mesh can be built at once. Subdividing a mesh keeps the indices of its
+// vertices, and the children of face i are faces 4i to 4i + 3.
+#[derive(Debug, Clone, PartialEq)]
 struct SDVertex {
     p: Point,
-    id: usize,  // We need this for ordering edges properly
-    start_face: Option<Weak<SDFace>>,
-    child: Option<Weak<SDVertex>>,
-    regular: bool,
-    boundary: bool
+    start_face: Option<usize>,
+    boundary: bool,
+    // Corners stay where they are when the mesh is subdivided
+    corner: bool
 }
 
-impl ::std::cmp::PartialEq for SDVertex {
-    fn eq(&self, other: &SDVertex) -> bool {
-        return self.id.eq(&other.id);
+impl SDVertex {
+    fn new(p: &Point) -> SDVertex {
+        SDVertex { p: p.clone(), start_face: None, boundary: false, corner: false }
     }
 }
 
-impl ::std::cmp::Eq for SDVertex { }
+#[derive(Debug, Clone, PartialEq)]
+struct SDFace {
+    v: [usize; 3],
+    // The faces across the edges from each vertex to the next one
+    f: [Option<usize>; 3]
+}
 
-impl Hash for SDVertex {
-    fn hash<H>(&self, state: &mut H) where H : Hasher {
-        self.id.hash(state);
-        self.regular.hash(state);
-        self.boundary.hash(state);
-    }
+#[derive(Debug, Clone, PartialEq)]
+struct SDMesh {
+    vertices: Vec<SDVertex>,
+    faces: Vec<SDFace>,
+    // The sharpness of the creases along edges. Each level of subdivision
+    // takes one away from the sharpness of a crease, so creases that are
+    // less sharp than the number of levels blend back into the surface.
+    creases: HashMap<(usize, usize), f32>
 }
 
-impl SDVertex {
-    fn new(_id: usize, _p: &Point) -> SDVertex {
-        SDVertex {
-            p: _p.clone(),
-            id: _id,
-            start_face: None,
-            child: None,
-            regular: false,
-            boundary: false
+impl SDMesh {
+    fn vnum(&self, f: usize, v: usize) -> usize {
+        match self.faces[f].v.iter().position(|&fv| fv == v) {
+            Some(i) => i,
+            None => panic!("Basic logic error in SDMesh::vnum()")
         }
     }
 
-    fn fold_faces<F, T>(&self, start: T, closure: F)
-                        -> T where F: Fn(T, &SDFace) -> T {
-        let sf = match self.start_face.clone() {
-            None => return start,
-            Some(_f) => _f.upgrade().unwrap()
-        };
+    fn next_face(&self, f: usize, v: usize) -> Option<usize> {
+        self.faces[f].f[self.vnum(f, v)]
+    }
 
-        if self.boundary {
-            // Compute valence of boundary vertex
-            let mut first_face : Arc<SDFace> = sf;
-            let mut f : Option<Arc<SDFace>> = first_face.prev_face(self);
+    fn prev_face(&self, f: usize, v: usize) -> Option<usize> {
+        self.faces[f].f[prev(self.vnum(f, v))]
+    }
 
-            while f != None {
-                let face : Arc<SDFace> = f.unwrap();
-                first_face = face.clone();
-                f = face.prev_face(self);
-            }
+    fn next_vert(&self, f: usize, v: usize) -> usize {
+        self.faces[f].v[next(self.vnum(f, v))]
+    }
 
-            f = Some(first_face);
-            let mut t = start;
+    fn prev_vert(&self, f: usize, v: usize) -> usize {
+        self.faces[f].v[prev(self.vnum(f, v))]
+    }
 
-            while f != None {
-                let face = f.unwrap();
-                t = closure(t, face.as_ref());
-                f = face.next_face(self);
+    fn other_vert(&self, f: usize, v0: usize, v1: usize) -> usize {
+        match self.faces[f].v.iter().find(|&&v| v != v0 && v != v1) {
+            Some(&v) => v,
+            None => panic!("Basic logic error in SDMesh::other_vert()")
+        }
+    }
+
+    // The faces around a vertex in order. Boundary vertices start from the
+    // face on one side of the boundary, and interior ones from any face.
+    fn faces_around(&self, v: usize) -> Vec<usize> {
+        let start = match self.vertices[v].start_face {
+            None => return Vec::new(),
+            Some(f) => f
+        };
+
+        // Walking around a vertex only ends if the faces around it agree on
+        // which way around their vertices go
+        let check_walk = |faces: &Vec<usize>| {
+            assert!(faces.len() <= self.faces.len(),
+                    "Subdivision surfaces need consistently oriented faces");
+        };
+
+        let mut faces = Vec::new();
+        if self.vertices[v].boundary {
+            let mut f = start;
+            while let Some(f2) = self.next_face(f, v) {
+                f = f2;
+                faces.push(f);
+                check_walk(&faces);
             }
 
-            t
+            faces.clear();
+            let mut f = Some(f);
+            while let Some(face) = f {
+                faces.push(face);
+                check_walk(&faces);
+                f = self.prev_face(face, v);
+            }
         } else {
-            // Compute valence of interior vertex
-            let mut t = closure(start, sf.as_ref());
-            let mut f = sf.next_face(self).unwrap();
-            while f != sf {
-                t = closure(t, f.as_ref());
-                f = f.next_face(self).unwrap();
+            let mut f = start;
+            loop {
+                faces.push(f);
+                check_walk(&faces);
+                f = self.next_face(f, v).unwrap();
+                if f == start {
+                    break;
+                }
             }
-
-            t
         }
-    }
 
-    fn valence(&self) -> usize {
-        self.fold_faces(1, |nf, _| { nf + 1 })
+        faces
     }
 
-    // !SPEED! Ideally we shouldn't be allocating a vec here -- better
-    // to figure out how to do something like alloca in C99...
-    fn one_ring(&self) -> Vec<Point> {
-        self.fold_faces(Vec::new(), |ps, nf| {
-            let mut v = ps;
-            if v.len() == 0 {
-                v.push(nf.prev_vert(self).upgrade().unwrap().p.clone());
-                v.push(nf.next_vert(self).upgrade().unwrap().p.clone());
-            } else {
-                v.push(nf.next_vert(self).upgrade().unwrap().p.clone());
-            }
-            v
-        })
+    // The vertices around a vertex, in order, which for boundary vertices
+    // start and end with its neighbors along the boundary
+    fn one_ring(&self, v: usize) -> Vec<usize> {
+        let faces = self.faces_around(v);
+        if faces.is_empty() {
+            return Vec::new();
+        }
+
+        if self.vertices[v].boundary {
+            let mut ring = vec![self.next_vert(faces[0], v)];
+            ring.extend(faces.iter().map(|&f| self.prev_vert(f, v)));
+            ring
+        } else {
+            faces.iter().map(|&f| self.next_vert(f, v)).collect()
+        }
     }
 
-    fn weight_boundary(&self, beta: f32) -> Point {
-        let p_ring = self.one_ring();
-        assert!(p_ring.len() > 0);
-        (1.0 - 2.0 * beta) * &self.p + beta * &p_ring[0] + beta * &p_ring[p_ring.len() - 1]
+    fn sharpness(&self, v0: usize, v1: usize) -> f32 {
+        self.creases.get(&edge_key(v0, v1)).cloned().unwrap_or(0.0)
     }
 
-    fn weight_one_ring(&self, beta: f32) -> Point {
-        let p_ring = self.one_ring();
-        let mut p = (1.0 - (p_ring.len() as f32) * beta) * &self.p;
-        for vi in p_ring.iter() {
-            p = p + beta * vi;
-        }
-        p
+    fn weight_one_ring(&self, v: usize, ring: &[usize], beta: f32) -> Point {
+        let p = (1.0 - (ring.len() as f32) * beta) * &self.vertices[v].p;
+        ring.iter().fold(p, |p, &r| p + beta * &self.vertices[r].p)
     }
-}
 
-#[derive(Debug)]
-struct SDFace {
-    v: [Weak<SDVertex>; 3],
-    f: [Option<Weak<SDFace>>; 3],
-    children: [Option<Weak<SDFace>>; 4],
-}
+    // Where a vertex on creases moves to, along with how sharp it is, or
+    // None if it's smooth. Vertices on two creases move along them, giving
+    // the vertex itself the center weight, and corners and vertices where
+    // more creases meet stay put. Boundaries are infinitely sharp creases.
+    fn crease_rule(&self, v: usize, ring: &[usize], center_weight: f32) -> Option<(Point, f32)> {
+        let vtx = &self.vertices[v];
+        let last = ring.len().saturating_sub(1);
+        let sharp: Vec<(usize, f32)> = ring.iter().enumerate().filter_map(|(i, &r)| {
+            let s = if vtx.boundary && (i == 0 || i == last) {
+                f32::INFINITY
+            } else {
+                self.sharpness(v, r)
+            };
+            if s > 0.0 { Some((r, s)) } else { None }
+        }).collect();
 
-impl ::std::clone::Clone for SDFace {
-    fn clone(&self) -> SDFace {
-        SDFace {
-            v: [self.v[0].clone(), self.v[1].clone(), self.v[2].clone()],
-            f: [self.f[0].clone(), self.f[1].clone(), self.f[2].clone()],
-            children: [
-                self.children[0].clone(),
-                self.children[1].clone(),
-                self.children[2].clone(),
-                self.children[3].clone()]
+        if vtx.corner {
+            return Some((vtx.p.clone(), f32::INFINITY));
         }
-    }
-}
 
-impl ::std::cmp::PartialEq for SDFace {
-    fn eq(&self, other: &SDFace) -> bool {
-        self.v.iter().zip(other.v.iter()).fold(true, |r, (p, q)| {
-            r && p.upgrade().unwrap() == q.upgrade().unwrap()
-        })
-    }
-}
-
-impl SDFace {
-    fn new(v1: Weak<SDVertex>, v2: Weak<SDVertex>, v3: Weak<SDVertex>) -> SDFace {
-        SDFace {
-            v: [v1.clone(), v2.clone(), v3.clone()],
-            f: [None, None, None],
-            children: [None, None, None, None]
+        let sharpness = sharp.iter().map(|&(_, s)| s).sum::<f32>() / (sharp.len() as f32);
+        match sharp.len() {
+            0 | 1 => None,
+            2 => {
+                let end_weight = 0.5 * (1.0 - center_weight);
+                let (a, b) = (&self.vertices[sharp[0].0].p, &self.vertices[sharp[1].0].p);
+                Some((center_weight * &vtx.p + end_weight * a + end_weight * b, sharpness))
+            },
+            _ => Some((vtx.p.clone(), sharpness))
         }
     }
 
-    fn vnum(&self, v: &SDVertex) -> usize {
-        for i in 0..3 {
-            if (*self.v[i].upgrade().unwrap()).borrow() == v {
-                return i;
-            }
+    // Applies the rules for creases, blending between them and the smooth
+    // rule for creases that are less than one unit sharp
+    fn vertex_rule(&self, v: usize, center_weight: f32,
+                   smooth_weight: fn(usize) -> f32) -> Point {
+        let ring = self.one_ring(v);
+        if ring.is_empty() {
+            return self.vertices[v].p.clone();
         }
 
-        panic!("Basic logic error in SDFace::vnum()");
+        let smooth = || self.weight_one_ring(v, &ring, smooth_weight(ring.len()));
+        match self.crease_rule(v, &ring, center_weight) {
+            Some((p, s)) if s >= 1.0 => p,
+            Some((p, s)) => smooth().lerp_with(p, s),
+            None => smooth()
+        }
     }
 
-    fn next_face(&self, v: &SDVertex) -> Option<Arc<SDFace>> {
-        self.f[self.vnum(v)].clone().map(|fr| fr.upgrade().unwrap())
-    }
+    // The new position of the vertex at the end of edge k of face f
+    fn edge_rule(&self, f: usize, k: usize) -> Point {
+        let face = &self.faces[f];
+        let (v0, v1) = (face.v[k], face.v[next(k)]);
+        let (p0, p1) = (&self.vertices[v0].p, &self.vertices[v1].p);
+        let crease = 0.5 * p0 + 0.5 * p1;
+        let sharpness = match face.f[k] {
+            None => return crease,
+            Some(_) => self.sharpness(v0, v1)
+        };
 
-    fn prev_face(&self, v: &SDVertex) -> Option<Arc<SDFace>> {
-        self.f[prev(self.vnum(v))].clone().map(|fr| fr.upgrade().unwrap())
-    }
+        if sharpness >= 1.0 {
+            return crease;
+        }
 
-    fn next_vert(&self, v: &SDVertex) -> Weak<SDVertex> {
-        self.v[next(self.vnum(v))].clone()
+        let p2 = &self.vertices[self.other_vert(f, v0, v1)].p;
+        let p3 = &self.vertices[self.other_vert(face.f[k].unwrap(), v0, v1)].p;
+        let smooth = (3f32 / 8f32) * p0 + (3f32 / 8f32) * p1 +
+            (1f32 / 8f32) * p2 + (1f32 / 8f32) * p3;
+        smooth.lerp_with(crease, sharpness)
     }
 
-    fn prev_vert(&self, v: &SDVertex) -> Weak<SDVertex> {
-        self.v[prev(self.vnum(v))].clone()
-    }
+    // Splits every face into four, adding a vertex at the middle of every
+    // edge and moving the existing ones
+    fn subdivide(&self) -> SDMesh {
+        // Update the positions of the existing vertices, which become the
+        // corners of the children of the faces around them
+        let mut vertices: Vec<SDVertex> = self.vertices.iter().enumerate().map(|(v, vtx)| {
+            SDVertex {
+                p: self.vertex_rule(v, 3f32 / 4f32, beta),
+                start_face: vtx.start_face.map(|f| 4 * f + self.vnum(f, v)),
+                ..vtx.clone()
+            }
+        }).collect();
 
-    fn other_vert(&self, v1: &SDVertex, v2: &SDVertex) -> Weak<SDVertex> {
-        for vtx in self.v.iter() {
-            let real_vtx = vtx.upgrade().unwrap();
-            if real_vtx.as_ref() != v1 && real_vtx.as_ref() != v2 {
-                return vtx.clone();
+        // Compute new odd edge vertices
+        let mut edge_verts: HashMap<(usize, usize), usize> = HashMap::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            for k in 0..3 {
+                let key = edge_key(face.v[k], face.v[next(k)]);
+                if edge_verts.contains_key(&key) {
+                    continue;
+                }
+
+                edge_verts.insert(key, vertices.len());
+                vertices.push(SDVertex {
+                    p: self.edge_rule(f, k),
+                    start_face: Some(4 * f + 3),
+                    boundary: face.f[k].is_none(),
+                    corner: false
+                });
             }
         }
 
-        panic!("Basic logic error in SDFace::other_vert()");
-    }
-}
-
-#[derive(Debug)]
-struct SDEdge {
-    v: [Weak<SDVertex>; 2],
-    f: [Option<Weak<SDFace>>; 2],
-    f0_edge_num: usize
-}
+        // Create the child faces. The k'th child is at the k'th vertex, and
+        // the last one is in the middle, between the new edge vertices.
+        let mut faces = Vec::with_capacity(4 * self.faces.len());
+        for (f, face) in self.faces.iter().enumerate() {
+            let edge_vert = |k: usize| edge_verts[&edge_key(face.v[k], face.v[next(k)])];
+            let neighbor_child =
+                |f2: Option<usize>, v: usize| f2.map(|f2| 4 * f2 + self.vnum(f2, v));
+            for k in 0..3 {
+                let mut v = [0; 3];
+                v[k] = face.v[k];
+                v[next(k)] = edge_vert(k);
+                v[prev(k)] = edge_vert(prev(k));
+
+                let mut neighbors = [None; 3];
+                neighbors[k] = neighbor_child(face.f[k], face.v[k]);
+                neighbors[next(k)] = Some(4 * f + 3);
+                neighbors[prev(k)] = neighbor_child(face.f[prev(k)], face.v[k]);
+                faces.push(SDFace { v, f: neighbors });
+            }
 
-impl ::std::cmp::PartialEq for SDEdge {
-    fn eq(&self, other: &SDEdge) -> bool {
-        self.v[0].upgrade() == other.v[0].upgrade() &&
-        self.v[1].upgrade() == other.v[1].upgrade()
-    }
-}
+            faces.push(SDFace {
+                v: [edge_vert(0), edge_vert(1), edge_vert(2)],
+                f: [Some(4 * f + 1), Some(4 * f + 2), Some(4 * f)]
+            });
+        }
 
-impl ::std::cmp::Eq for SDEdge { }
+        // Both halves of a crease are one unit less sharp than it was
+        let creases = self.creases.iter()
+            .filter(|&(_, &s)| s > 1.0)
+            .flat_map(|(&(v0, v1), &s)| {
+                let mid = edge_verts[&(v0, v1)];
+                [(edge_key(v0, mid), s - 1.0), (edge_key(mid, v1), s - 1.0)]
+            }).collect();
 
-impl Hash for SDEdge {
-    fn hash<H>(&self, state: &mut H) where H : Hasher {
-        self.v[0].upgrade().unwrap().hash(state);
-        self.v[1].upgrade().unwrap().hash(state);
+        SDMesh { vertices, faces, creases }
     }
-}
 
-impl SDEdge {
-    fn new(v1: Weak<SDVertex>, v2: Weak<SDVertex>) -> SDEdge {
-        let (min_v, max_v) =
-            if v1.upgrade().unwrap().id < v2.upgrade().unwrap().id {
-                (v1.clone(), v2.clone())
-            } else {
-                (v2.clone(), v1.clone())
+    // The normal of the limit surface at a vertex, whose one-ring must
+    // already be on the limit surface. Creases aren't smooth, so vertices
+    // on them average the normals of the faces around them instead.
+    fn limit_normal(&self, v: usize) -> Normal {
+        let ring = self.one_ring(v);
+        let valence = ring.len();
+        if valence == 0 {
+            return Normal::new();
+        }
+
+        let p = &self.vertices[v].p;
+        let p_ring: Vec<&Point> = ring.iter().map(|&r| &self.vertices[r].p).collect();
+        let creased = self.vertices[v].corner || ring.iter().any(|&r| self.sharpness(v, r) > 0.0);
+        let n = if creased {
+            self.faces_around(v).iter().fold(Vector::new(), |n, &f| {
+                let fv = self.faces[f].v;
+                let (p0, p1, p2) = (&self.vertices[fv[0]].p,
+                                    &self.vertices[fv[1]].p,
+                                    &self.vertices[fv[2]].p);
+                n + (p1 - p0).into_cross(p2 - p0)
+            })
+        } else if !self.vertices[v].boundary {
+            // Compute tangents of interior vertex
+            let (s, t) = p_ring.iter().enumerate().fold(
+                (Vector::new(), Vector::new()), |(s, t), (k, &pk)| {
+                    let angle = 2f32 * ::std::f32::consts::PI * (k as f32) / (valence as f32);
+                    (s + angle.cos() * Vector::from(pk.clone()),
+                     t + angle.sin() * Vector::from(pk.clone()))
+                });
+            t.into_cross(s)
+        } else {
+            // Compute tangents of boundary vertex
+            let s = p_ring[valence - 1] - p_ring[0];
+            let t = match valence {
+                2 => Vector::from(p_ring[0] + p_ring[1] - 2.0 * p),
+                3 => p_ring[1] - p,
+                4 => Vector::from(-1.0 * p_ring[0] + 2.0 * p_ring[1] + 2.0 * p_ring[2] +
+                                  -1.0 * p_ring[3] - 2.0 * p),
+                _ => {
+                    let theta = ::std::f32::consts::PI / ((valence - 1) as f32);
+                    let r = Vector::from(
+                        theta.sin() * (p_ring[0] + p_ring[valence - 1]));
+                    let r = p_ring[1..valence - 1].iter().enumerate().fold(r, |r, (k, &pk)| {
+                        let wt = (2.0 * theta.cos() - 2.0) * (((k + 1) as f32) * theta).sin();
+                        r + Vector::from(wt * pk)
+                    });
+                    -r
+                }
             };
+            t.into_cross(s)
+        };
 
-        SDEdge {
-            v: [min_v, max_v],
-            f: [None, None],
-            f0_edge_num: 4
-        }
+        Normal::from(n.normalize())
     }
 }
 
+// A smooth surface that's the limit of repeatedly subdividing a triangle
+// mesh with Loop's rules. Edges can be given a sharpness to crease the
+// surface along them, and vertices made into corners, without needing any
+// more geometry. The surface is approximated by a triangle mesh after the
+// given number of subdivisions.
 #[derive(Debug, Clone, PartialEq)]
 pub struct LoopSubdiv {
     base: ShapeBase,
     n_levels: usize,
-    vertices: Vec<Arc<SDVertex>>,
-    faces: Vec<Arc<SDFace>>,
-    max_vert_id: usize
+    mesh: SDMesh
 }
 
 impl LoopSubdiv {
+    // The faces must be consistently oriented, and every edge can be
+    // shared by at most two of them
     pub fn new(o2w: Transform, w2o: Transform, ro: bool,
                vertex_indices: &[usize], points: &[Point], nl: usize)
                -> LoopSubdiv {
-        // Allocate vertices
-        let mut vert_id = 0;
-        let mut verts = {
-            let mut vs = Vec::new();
-
-            for p in points {
-                vs.push(Arc::new(SDVertex::new(vert_id, p)));
-                vert_id += 1;
-            }
-
-            vs
-        };
-
-        // Allocate faces
+        // Allocate vertices and faces
         debug_assert_eq!((vertex_indices.len() % 3), 0);
-        let num_faces = vertex_indices.len() / 3;
-        let mut faces: Vec<Arc<SDFace>> = {
-            let mut vert_idxs = vertex_indices.iter();
-
-            (0..num_faces).map(|_| {
-                let v0 = *vert_idxs.next().unwrap();
-                let v1 = *vert_idxs.next().unwrap();
-                let v2 = *vert_idxs.next().unwrap();
-                let f = Arc::new(SDFace::new(
-                    Arc::downgrade(&verts[v0]),
-                    Arc::downgrade(&verts[v1]),
-                    Arc::downgrade(&verts[v2])));
-
-                Arc::get_mut(&mut verts[v0]).unwrap().start_face = Some(Arc::downgrade(&f));
-                Arc::get_mut(&mut verts[v1]).unwrap().start_face = Some(Arc::downgrade(&f));
-                Arc::get_mut(&mut verts[v2]).unwrap().start_face = Some(Arc::downgrade(&f));
-
-                f
-            }).collect()
-        };
+        let mut vertices: Vec<SDVertex> = points.iter().map(SDVertex::new).collect();
+        let mut faces: Vec<SDFace> = vertex_indices.chunks(3).enumerate().map(|(f, v)| {
+            for &vi in v {
+                vertices[vi].start_face = Some(f);
+            }
+            SDFace { v: [v[0], v[1], v[2]], f: [None; 3] }
+        }).collect();
 
         // Set neighbor pointers in faces
-        let mut edges: HashMap<(usize, usize), SDEdge> = HashMap::new();
-        for f in faces.iter_mut() {
+        let mut edges: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+        for f in 0..faces.len() {
             for edge_num in 0..3 {
-                let v0 = edge_num;
-                let v1 = next(edge_num);
-
-                // Update neighbor pointer for edge_num
-                let key = {
-                    let id1 = f.v[v0].clone().upgrade().unwrap().id;
-                    let id2 = f.v[v1].clone().upgrade().unwrap().id;
-                    if id1 < id2 { (id1, id2) } else { (id2, id1) }
-                };
+                let key = edge_key(faces[f].v[edge_num], faces[f].v[next(edge_num)]);
+                match edges.remove(&key) {
+                    // Handle previously seen edge
+                    Some((f0, f0_edge_num)) => {
+                        faces[f0].f[f0_edge_num] = Some(f);
+                        faces[f].f[edge_num] = Some(f0);
+                    },
 
-                if edges.contains_key(&key) {
-                    {
-                        let e = edges.get_mut(&key).unwrap();
-                        assert!(e.f0_edge_num < 4);
-
-                        // Handle previously seen edge
-                        Arc::get_mut(&mut e.f[0].as_mut().unwrap().upgrade().unwrap())
-                            .unwrap().f[e.f0_edge_num] = Some(Arc::downgrade(f));
-                        Arc::get_mut(f).as_mut().unwrap().f[edge_num] = e.f[0].clone();
-                    }
-                    edges.remove(&key);
-                } else {
                     // Handle new edge
-                    let mut e = SDEdge::new(f.v[v0].clone(), f.v[v1].clone());
-                    e.f[0] = Some(Arc::downgrade(f));
-                    e.f0_edge_num = edge_num;
-                    edges.insert(key, e);
+                    None => { edges.insert(key, (f, edge_num)); }
                 }
             }
         }
 
-        // Finish vertex initialization
-        for v in verts.iter_mut() {
-            let boundary = {
-                let sf = {
-                    match v.start_face.clone() {
-                        None => continue,
-                        Some(_f) => _f.upgrade().unwrap()
-                    }
-                };
-
-                let mut f = sf.clone();
-                let mut is_boundary = false;
-                loop {
-                    f = match f.next_face((*v).borrow()) {
-                        None => {
-                            is_boundary = true;
-                            break;
-                        },
+        // Finish vertex initialization. Vertices are on the boundary if the
+        // faces around them don't close up.
+        let mut mesh = SDMesh { vertices, faces, creases: HashMap::new() };
+        for v in 0..mesh.vertices.len() {
+            let start = match mesh.vertices[v].start_face {
+                None => continue,
+                Some(f) => f
+            };
 
-                        Some(_f) => _f
-                    };
+            let mut f = start;
+            let boundary = loop {
+                f = match mesh.next_face(f, v) {
+                    None => break true,
+                    Some(f) => f
+                };
 
-                    if f == sf { break };
+                if f == start {
+                    break false;
                 }
-
-                is_boundary
             };
-
-            Arc::get_mut(v).unwrap().boundary = boundary;
-            Arc::get_mut(v).unwrap().regular =
-                (!v.boundary && v.valence() == 6) || (v.boundary && v.valence() == 4);
+            mesh.vertices[v].boundary = boundary;
         }
 
         LoopSubdiv {
             base: ShapeBase::new(o2w, w2o, ro),
             n_levels: nl,
-            vertices: verts,
-            faces: faces,
-            max_vert_id: vert_id
+            mesh
         }
     }
 
+    // Creases the surface along the edges between each pair of vertices.
+    // Creases with a sharpness of at least the number of levels are hard
+    // edges, and ones with less blend back into the surface. There can be
+    // one sharpness for all of the creases, or one for each.
+    pub fn with_creases(mut self, edges: &[usize], sharpness: &[f32]) -> LoopSubdiv {
+        let num_creases = edges.len() / 2;
+        assert!(sharpness.len() == 1 || sharpness.len() == num_creases);
+        let edge_exists = |v0: usize, v1: usize| self.mesh.faces.iter().any(|f| {
+            (0..3).any(|k| edge_key(f.v[k], f.v[next(k)]) == edge_key(v0, v1))
+        });
+
+        for (i, e) in edges.chunks_exact(2).enumerate() {
+            if !edge_exists(e[0], e[1]) {
+                println!("WARNING: Ignoring crease between unconnected vertices {} and {}",
+                         e[0], e[1]);
+                continue;
+            }
+
+            let s = sharpness[if sharpness.len() == 1 { 0 } else { i }];
+            self.mesh.creases.insert(edge_key(e[0], e[1]), s);
+        }
+        self
+    }
+
+    pub fn with_corners(mut self, corners: &[usize]) -> LoopSubdiv {
+        for &v in corners {
+            match self.mesh.vertices.get_mut(v) {
+                Some(vtx) => vtx.corner = true,
+                None => println!("WARNING: Ignoring corner at nonexistent vertex {}", v)
+            }
+        }
+        self
+    }
+
     pub fn base<'a>(&'a self) -> &'a ShapeBase { &self.base }
 
+    // The area of the limit surface can only be approximated by subdividing
+    // a copy of the control mesh. The meshes that this creates are thrown
+    // away, so their new ids are never seen.
+    pub fn area(&self) -> f32 {
+        self.clone().refine().iter().fold(0f32, |a, m| a + m.area())
+    }
+
     pub fn object_bound(&self) -> BBox {
-        self.vertices.iter().fold(BBox::new(), |b, v| b.unioned_with_ref(&v.p))
+        self.mesh.vertices.iter().fold(BBox::new(), |b, v| b.unioned_with_ref(&v.p))
     }
 }
 
 impl Refinable<Mesh> for LoopSubdiv {
     fn is_refined(&self) -> bool { false }
     fn refine(self) -> Vec<Mesh> {
-        let mut f = self.faces.clone();
-        let mut v = self.vertices.clone();
-        
-        let mut vtx_id = self.max_vert_id;
+        let mut mesh = self.mesh.clone();
         for _ in 0..self.n_levels {
-            // Update f and v for next level of subdivision
-            let mut new_vertices = Vec::new();
-
-            // Allocate next level of children in mesh tree
-            for vtx in v.iter_mut() {
-                // Determine new vertex position
-                let p = if vtx.boundary {
-                    // Apply boundary rule for even vertex
-                    vtx.weight_boundary(1f32 / 8f32)
-                } else {
-                    // Apply one-ring rule for even vertex
-                    if vtx.regular {
-                        vtx.weight_one_ring(1f32 / 16f32)
-                    } else {
-                        vtx.weight_one_ring(beta(vtx.valence()))
-                    }
-                };
-
-                let mut new_vtx = Arc::new(SDVertex::new(vtx_id, &p));
-                vtx_id += 1;
-
-                Arc::get_mut(&mut new_vtx).unwrap().boundary = vtx.boundary;
-                Arc::get_mut(&mut new_vtx).unwrap().regular = vtx.regular;
-
-                Arc::get_mut(vtx).unwrap().child = Some(Arc::downgrade(&new_vtx));
-                new_vertices.push(new_vtx);
-            }
-
-            // Compute new odd edge vertices
-            let mut edge_verts: HashMap<SDEdge, Weak<SDVertex>> = HashMap::new();
-            for face in f.iter_mut() {
-                for k in 0..3 {
-                    // Compute odd vertex on k'th edge
-                    let edge = SDEdge::new(face.v[k].clone(), face.v[next(k)].clone());
-                    if !edge_verts.contains_key(&edge) {
-                        // Apply edge rules to compute new vertex position
-                        let boundary = match face.f[k] {
-                            None => false,
-                            Some(_) => true
-                        };
-
-                        let pos = {
-                            let v1 = edge.v[0].upgrade().unwrap();
-                            let v2 = edge.v[1].upgrade().unwrap();
-                            if boundary {
-                                0.5 * &v1.p + 0.5 * &v2.p
-                            } else {
-                                let v3 = face
-                                    .other_vert(v1.as_ref(), v2.as_ref())
-                                    .upgrade().unwrap();
-
-                                let v4 =
-                                    face.f[k].clone().unwrap()
-                                    .upgrade().unwrap()
-                                    .other_vert(v1.as_ref(), v2.as_ref())
-                                    .upgrade().unwrap();
-
-                                let mut p = (3f32 / 8f32) * &v1.p;
-                                p = p + (3f32 / 8f32) * &v2.p;
-                                p = p + (1f32 / 8f32) * &v3.p;
-                                p = p + (1f32 / 8f32) * &v4.p;
-                                p
-                            }
-                        };
-
-                        // Create and initialize new odd vertex
-                        let mut vert = Arc::new(SDVertex::new(vtx_id, &pos));
-                        vtx_id += 1;
-
-                        Arc::get_mut(&mut vert).unwrap().boundary = boundary;
-                        Arc::get_mut(&mut vert).unwrap().regular = true;
-
-                        edge_verts.insert(edge, Arc::downgrade(&vert));
-                        new_vertices.push(vert);
-                    }
-                }
-            }
-
-            // Create child faces, set verts based on edges, and set
-            // start face for intermediate edge verts to center face...
-            let mut new_faces = Vec::new();
-            for f in f.iter_mut() {
-                let mut cvs : Vec<Weak<SDVertex>> = (0..3).map(|k| {
-                    let edge = SDEdge::new(f.v[k].clone(), f.v[next(k)].clone());
-                    assert!(edge_verts.contains_key(&edge));
-                    edge_verts.get(&edge).unwrap().clone()
-                }).collect();
-
-                // Allocate new faces...
-                let f1 = Arc::new(SDFace::new(f.v[0].clone(), cvs[0].clone(), cvs[2].clone()));
-                let f2 = Arc::new(SDFace::new(cvs[0].clone(), f.v[1].clone(), cvs[1].clone()));
-                let f3 = Arc::new(SDFace::new(cvs[2].clone(), cvs[1].clone(), f.v[2].clone()));
-                let f4 = Arc::new(SDFace::new(cvs[0].clone(), cvs[1].clone(), cvs[2].clone()));
-
-                // Set f4 as the start face for all of the child vertices...
-                for cv in cvs.iter_mut() {
-                    Arc::get_mut(&mut cv.upgrade().unwrap()).unwrap().start_face = Some(Arc::downgrade(&f4))
-                }
-
-                // Set f1-f4 as the children for this face...
-                Arc::get_mut(f).unwrap().children = [
-                    Some(Arc::downgrade(&f1)),
-                    Some(Arc::downgrade(&f2)),
-                    Some(Arc::downgrade(&f3)),
-                    Some(Arc::downgrade(&f4))];
-
-                // Add each face to the list of new faces..
-                new_faces.push(f1);
-                new_faces.push(f2);
-                new_faces.push(f3);
-                new_faces.push(f4);
-            }
-
-            // Update new mesh topology
-            /* Update even vertex face pointers */
-            for vert in v.iter_mut() {
-                let vert_num = vert.start_face.clone().unwrap().upgrade().unwrap().vnum(vert);
-                Arc::get_mut(&mut vert.child.clone().unwrap().upgrade().unwrap()).unwrap().start_face =
-                    vert.start_face.clone().unwrap().upgrade().unwrap().children[vert_num].clone()
-            }
-
-            /* Update face neighbor pointers */
-            for face in f.iter_mut() {
-                for k in 0..3 {
-                    // Update f pointers for siblings
-                    Arc::get_mut(&mut face.children[3].clone().unwrap().upgrade().unwrap()).unwrap().f[k] =
-                        face.children[next(k)].clone();
-                    Arc::get_mut(&mut face.children[k].clone().unwrap().upgrade().unwrap()).unwrap().f[next(k)] =
-                        face.children[3].clone();
-                    
-                    // Update children f pointers for neighbor children
-                    let mut child_ref = face.children[k].clone().unwrap().upgrade().unwrap();
-                    let mut f2 = face.f[k].clone();
-                    if let Some(f2_wref) = f2 {
-                        let f2_ref = f2_wref.upgrade().unwrap();
-                        Arc::get_mut(&mut child_ref).unwrap().f[k] =
-                            f2_ref.children[f2_ref.vnum(face.v[k].upgrade().unwrap().as_ref())].clone();
-                    } else {
-                        Arc::get_mut(&mut child_ref).unwrap().f[k] = None;
-                    }
-
-                    f2 = face.f[prev(k)].clone();
-                    if let Some(f2_wref) = f2 {
-                        let f2_ref = f2_wref.upgrade().unwrap();
-                        Arc::get_mut(&mut child_ref).unwrap().f[prev(k)] =
-                            f2_ref.children[f2_ref.vnum(face.v[k].upgrade().unwrap().as_ref())].clone();
-                    } else {
-                        Arc::get_mut(&mut child_ref).unwrap().f[prev(k)] = None;
-                    }
-                }
-            }
-
-            // Prepare for next level of subdivision
-            f = new_faces;
-            v = new_vertices;
+            mesh = mesh.subdivide();
         }
 
         // Push vertices to limit surface
-        let p_limit : Vec<_> = v.iter().map(|vert| {
-            if vert.boundary {
-                vert.weight_boundary(1f32 / 5f32)
-            } else {
-                let valence = vert.valence();
-                let gamma = 1.0 / ((valence as f32) + 3.0 / (8.0 * beta(valence)));
-                vert.weight_one_ring(gamma)
-            }
-        }).collect();
-
-        for (k, vert) in v.iter_mut().enumerate() {
-            Arc::get_mut(vert).unwrap().p = p_limit[k].clone();
+        let p_limit: Vec<Point> = (0..mesh.vertices.len())
+            .map(|v| mesh.vertex_rule(v, 3f32 / 5f32, loop_gamma))
+            .collect();
+        for (vert, p) in mesh.vertices.iter_mut().zip(p_limit.iter()) {
+            vert.p = p.clone();
         }
 
-        // Compute vertex tangents on limit surface
-        let ns : Vec<_> = v.iter().map(|vert| {
-            let mut s = Vector::new();
-            let mut t = Vector::new();
-
-            let valence = vert.valence();
-
-            let p_ring = vert.one_ring();
-            if vert.boundary {
-                // Compute tangents of interior vertex
-                for (k, p) in p_ring.iter().enumerate() {
-                    let factor = 2f32 * ::std::f32::consts::PI * (k as f32) / (valence as f32);
-                    s = s + factor.cos() * Vector::from(p.clone());
-                    t = t + factor.sin() * Vector::from(p.clone());
-                }
-            } else {
-                // Compute tangents of boundary vertex
-                s = &p_ring[valence - 1] - &p_ring[0];
-                t = match valence {
-                    2 => Vector::from(&p_ring[0] + &p_ring[1] - 2.0 * &vert.p),
-                    3 => &p_ring[1] - &vert.p,
-                    4 => Vector::from(-1.0 * &p_ring[0] + 2.0 * &p_ring[1] + 2.0 * &p_ring[2] +
-                                      -1.0 * &p_ring[3] - 2.0 * &vert.p),
-                    _ => {
-                        let theta = ::std::f32::consts::PI / ((valence - 1) as f32);
-                        let mut r = Vector::from(theta.sin() * (&p_ring[0] + &p_ring[valence - 1]));
-                        for (k, p) in p_ring.iter().enumerate() {
-                            let wt = (2.0 * theta.cos() - 2.0) * ((k as f32) * theta).sin();
-                            r = r + Vector::from(wt * p);
-                        }
-                        -r
-                    }
-                }
-            }
-
-            Normal::from(s.into_cross(t).normalize())
-        }).collect();
+        // Compute vertex normals on limit surface
+        let ns: Vec<Normal> = (0..mesh.vertices.len()).map(|v| mesh.limit_normal(v)).collect();
 
         // Create TriangleMesh from subdivision mesh
-        let mut used_verts: HashMap<Arc<SDVertex>, usize> = HashMap::new();
-        let mut used_vert_id = 0;
-        for vert in v.iter() {
-            used_verts.insert(vert.clone(), used_vert_id);
-            used_vert_id = used_vert_id + 1;
-        }
-
-        let mut indices = Vec::with_capacity(f.len() * 3);
-        for face in f.iter() {
-            for j in 0..3 {
-                indices.push(*(used_verts.get(&face.v[j].upgrade().unwrap()).unwrap()));
-            }
-        }
-
-        vec![Mesh::new(self.base.object2world.clone(),
-                       self.base.world2object.clone(),
+        let indices: Vec<usize> = mesh.faces.iter().flat_map(|f| f.v).collect();
+        vec![Mesh::new((*self.base.object2world).clone(),
+                       (*self.base.world2object).clone(),
                        self.base.reverse_orientation,
-                       &*indices.into_boxed_slice(),
-                       &*p_limit.into_boxed_slice(),
-                       Some(&*ns.into_boxed_slice()),
-                       None, None, None)]
+                       &indices, &p_limit, Some(&ns), None, None, None)]
     }
 }
 
 impl HasBounds for LoopSubdiv {
     fn world_bound(&self) -> BBox {
         let o2w = &self.base().object2world;
-        self.vertices.iter().fold(BBox::new(), |b, v| b.unioned_with(o2w.t(&v.p)))
+        self.mesh.vertices.iter().fold(BBox::new(), |b, v| b.unioned_with(o2w.t(&v.p)))
     }
 }
 
-////////////////////////////////////////////////////////////////////////////////
-//
-// !FIXME! We have to wait for https://github.com/rust-lang/rust/issues/30658
-// to be fixed before we can implement these fixes...
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use geometry::point::Point;
-    use transform::transform::Transform;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Dot;
+    use crate::transform::transform::Transform;
 
-    // Tetrahedron
+    // Tetrahedron, with its faces wound the same way around
     static TET_PTS : [Point; 4] =
         [Point { x: 0.0, y: 0.0, z: 0.0 },
          Point { x: 1.0, y: 0.0, z: 0.0 },
          Point { x: 0.0, y: 1.0, z: 0.0 },
          Point { x: 0.0, y: 0.0, z: 1.0 }];
     static TET_TRIS : [usize; 12] =
-        [ 0, 3, 2, 0, 1, 2, 0, 3, 1, 1, 2, 3 ];
+        [ 0, 2, 1, 0, 1, 3, 0, 3, 2, 1, 2, 3 ];
+
+    fn tetrahedron(nl: usize) -> LoopSubdiv {
+        LoopSubdiv::new(Transform::new(), Transform::new(), false, &TET_TRIS, &TET_PTS, nl)
+    }
 
-    #[ignore]
     #[test]
     fn it_can_be_created() {
-        let subdiv = LoopSubdiv::new(Transform::new(), Transform::new(), false,
-                                     &TET_TRIS, &TET_PTS, 1);
+        let subdiv = tetrahedron(1);
         assert_eq!(subdiv.n_levels, 1);
-        assert_eq!(subdiv.vertices.len(), 4);
-        assert_eq!(subdiv.faces.len(), 4);
+        assert_eq!(subdiv.mesh.vertices.len(), 4);
+        assert_eq!(subdiv.mesh.faces.len(), 4);
+
+        // Every vertex of a closed mesh is surrounded by faces
+        assert!(subdiv.mesh.vertices.iter().all(|v| !v.boundary));
+        assert!((0..4).all(|v| subdiv.mesh.one_ring(v).len() == 3));
+
+        // But a single triangle is all boundary
+        let tri = LoopSubdiv::new(Transform::new(), Transform::new(), false,
+                                  &[0, 1, 2], &TET_PTS[0..3], 1);
+        assert!(tri.mesh.vertices.iter().all(|v| v.boundary));
+        assert_eq!(tri.mesh.one_ring(0), vec![1, 2]);
     }
 
-    #[ignore]
     #[test]
     fn it_has_object_space_bounds() {
-        unimplemented!();
+        let xf = Transform::translate(&Vector::new_with(1.0, 2.0, 3.0));
+        let subdiv = LoopSubdiv::new(xf.clone(), xf.inverse(), false, &TET_TRIS, &TET_PTS, 1);
+        assert_eq!(subdiv.object_bound(),
+                   BBox::new_with(Point::new(), Point::new_with(1.0, 1.0, 1.0)));
     }
 
-    #[ignore]
     #[test]
     fn it_has_world_space_bounds() {
-        unimplemented!();
+        let xf = Transform::translate(&Vector::new_with(1.0, 2.0, 3.0));
+        let subdiv = LoopSubdiv::new(xf.clone(), xf.inverse(), false, &TET_TRIS, &TET_PTS, 1);
+        assert_eq!(subdiv.world_bound(),
+                   BBox::new_with(Point::new_with(1.0, 2.0, 3.0),
+                                  Point::new_with(2.0, 3.0, 4.0)));
     }
 
-    #[ignore]
     #[test]
     fn it_can_be_refined() {
-        unimplemented!();
+        // Each level splits every face into four, and adds a vertex to every
+        // edge
+        let mut mesh = tetrahedron(0).mesh;
+        for (faces, verts) in [(16, 10), (64, 34), (256, 130)] {
+            mesh = mesh.subdivide();
+            assert_eq!(mesh.faces.len(), faces);
+            assert_eq!(mesh.vertices.len(), verts);
+            assert!(mesh.vertices.iter().all(|v| !v.boundary));
+        }
+
+        // The limit surface shrinks inside the control mesh, and its normals
+        // point outwards
+        let meshes = tetrahedron(3).refine();
+        assert_eq!(meshes.len(), 1);
+        let bound = meshes[0].object_bound();
+        assert!(bound.p_min.x > 0.0 && bound.p_max.x < 1.0);
+
+        let center = Point::new_with(0.25, 0.25, 0.25);
+        let mut mesh = tetrahedron(0).mesh;
+        for _ in 0..2 {
+            mesh = mesh.subdivide();
+        }
+        for v in 0..mesh.vertices.len() {
+            let n = Vector::from(mesh.limit_normal(v));
+            assert!(n.dot(&(&mesh.vertices[v].p - &center)) > 0.0);
+        }
+
+        // Including on the boundary of open meshes
+        let mut open = LoopSubdiv::new(Transform::new(), Transform::new(), false,
+                                       &TET_TRIS[3..], &TET_PTS, 0).mesh;
+        for _ in 0..2 {
+            open = open.subdivide();
+        }
+        assert!(open.vertices.iter().any(|v| v.boundary));
+        for v in 0..open.vertices.len() {
+            let n = Vector::from(open.limit_normal(v));
+            assert!(n.dot(&(&open.vertices[v].p - &center)) > 0.0);
+        }
+
+        // Boundaries are smoothed too, unless their vertices are corners
+        let tri = LoopSubdiv::new(Transform::new(), Transform::new(), false,
+                                  &[0, 1, 2], &TET_PTS[0..3], 2);
+        assert!(tri.area() < 0.45);
+        assert!((tri.with_corners(&[0, 1, 2]).area() - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn it_keeps_creases_sharp() {
+        // Crease the bottom of the tetrahedron, so that it stays flat
+        let bottom = [0, 1, 1, 2, 2, 0];
+        let min_z = |subdiv: LoopSubdiv| subdiv.refine()[0].object_bound().p_min.z;
+        let smooth = min_z(tetrahedron(3));
+        let hard = min_z(tetrahedron(3).with_creases(&bottom, &[f32::INFINITY]));
+        assert!(smooth > 0.01);
+        assert!(hard.abs() < 1e-5);
+
+        // Creases that are less sharp than the number of levels are softer
+        let soft = min_z(tetrahedron(3).with_creases(&bottom, &[1.5]));
+        assert!(soft > hard && soft < smooth);
+
+        // Corners don't move at all
+        let corners = tetrahedron(3).with_corners(&[0, 1, 2, 3]).refine();
+        assert_eq!(corners[0].object_bound(),
+                   BBox::new_with(Point::new(), Point::new_with(1.0, 1.0, 1.0)));
     }
 }
@@ -1,31 +1,62 @@
 use std::sync::Arc;
+use std::sync::OnceLock;
 use std::convert::AsRef;
 
-use bbox::BBox;
-use bbox::HasBounds;
-use bbox::Union;
-use diff_geom::DifferentialGeometry;
-use geometry::normal::Normal;
-use geometry::normal::Normalize;
-use geometry::point::Point;
-use geometry::vector::Cross;
-use geometry::vector::Dot;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use primitive::Refinable;
-use ray::Ray;
-use shape::ShapeBase;
-use shape::ShapeIntersection;
-use texture::{Texture, ScalarTextureReference};
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-
-use geometry::vector::coordinate_system;
-use utils::solve_linear_system_2x2;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bbox::Union;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::montecarlo::Distribution1D;
+use crate::montecarlo::uniform_sample_triangle;
+use crate::primitive::Refinable;
+use crate::ray::Ray;
+use crate::ray::RayKind;
+use crate::shape::ShapeBase;
+use crate::shape::helpers::area_pdf_wi;
+use crate::shape::ShapeIntersection;
+use crate::texture::{Texture, ScalarTextureReference};
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+
+use crate::geometry::vector::coordinate_system;
+use crate::utils::Lerp;
+use crate::utils::gamma;
+use crate::utils::solve_linear_system_2x2;
+
+// Which rays pass through the back of a mesh's triangles, for meshes that
+// were only modelled to be seen from one side. Culling shadow rays too
+// stops such meshes from casting shadows onto what's behind them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum BackfaceCulling {
+    #[default]
+    Never,
+    CameraRays,
+    CameraAndShadowRays
+}
+
+impl BackfaceCulling {
+    pub fn culls(&self, kind: RayKind) -> bool {
+        match self {
+            BackfaceCulling::Never => false,
+            BackfaceCulling::CameraRays => kind == RayKind::Camera,
+            BackfaceCulling::CameraAndShadowRays =>
+                kind == RayKind::Camera || kind == RayKind::Shadow
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Triangle {
-    mesh: Arc<Mesh>,
+    // Triangles share the transforms of their mesh, but each has its own id
+    base: ShapeBase,
+    mesh: Arc<MeshData>,
     v: [usize; 3]
 }
 
@@ -38,37 +69,94 @@ impl Triangle {
         (p1.clone(), p2.clone(), p3.clone())
     }
 
-    fn get_intersection_point(&self, r: &Ray) -> Option<(f32, f32, f32)> {
-        // Compute s1
+    // The vertices at the given time. Those of deforming meshes move in a
+    // straight line from their positions at the start of the motion to
+    // those at its end, and stay put outside of it.
+    fn get_vertices_at(&self, time: f32) -> (Point, Point, Point) {
         let (p1, p2, p3) = self.get_vertices();
+        let Some(p_end) = self.mesh.p_end.as_ref() else {
+            return (p1, p2, p3);
+        };
+
+        let t = self.mesh.motion_fraction(time);
+        (p1.lerp_with(p_end[self.v[0]].clone(), t),
+         p2.lerp_with(p_end[self.v[1]].clone(), t),
+         p3.lerp_with(p_end[self.v[2]].clone(), t))
+    }
+
+    // Finds where the ray hits the triangle, and the barycentric coordinates
+    // of the hit, using the watertight test of Woop et al. (2013). The
+    // vertices are moved into a space where the ray starts at the origin and
+    // runs along the z axis, and the hit is found from the signed areas of
+    // the edges as seen from the origin. Since adjacent triangles compute
+    // the same areas for their shared edges, rays can't slip between them.
+    fn get_intersection_point(&self, r: &Ray) -> Option<(f32, f32, f32)> {
+        let (p1, p2, p3) = self.get_vertices_at(r.time);
+
+        // The ray hits the front of the triangle, i.e. the side its normal
+        // points to, when it runs against the normal
+        if self.mesh.culling.culls(r.kind) {
+            let n = (&p2 - &p1).into_cross(&p3 - &p1);
+            let base = self.base();
+            let flipped = base.reverse_orientation ^ base.transform_swaps_handedness;
+            if (r.d.dot(&n) > 0.0) != flipped {
+                return None;
+            }
+        }
 
-        let e1 = &p2 - &p1;
-        let e2 = &p3 - &p1;
-        let s1 = r.d.cross_with(&e2);
-        let divisor = s1.dot(&e1);
-        if divisor == 0f32 {
+        // Permute the axes so that the ray points furthest along z
+        let kz = (0..3).fold(0, |k, i| if r.d[i].abs() > r.d[k].abs() { i } else { k });
+        let kx = (kz + 1) % 3;
+        let ky = (kx + 1) % 3;
+        let permute = |v: Vector| [v[kx], v[ky], v[kz]];
+        let d = permute(r.d.clone());
+        if d[2] == 0.0 {
             return None;
         }
 
-        // Compute first barycentric coordinate
-        let inv_divisor = 1.0 / divisor;
-        let s = &(r.o) - p1;
-        let b1 = s1.dot(&s) * inv_divisor;
-        if b1 < 0.0 || b1 > 1.0 {
+        // Translate the vertices to the ray origin and shear them so that
+        // the ray runs along z. Only x and y are sheared until they're
+        // needed to find t.
+        let (sx, sy, sz) = (-d[0] / d[2], -d[1] / d[2], 1.0 / d[2]);
+        let shear = |p: &Point| {
+            let v = permute(p - &r.o);
+            [v[0] + sx * v[2], v[1] + sy * v[2], v[2]]
+        };
+        let (p1t, p2t, p3t) = (shear(&p1), shear(&p2), shear(&p3));
+
+        // Compute the edge functions, falling back to double precision when
+        // one is too close to zero to tell which side of the edge the ray is
+        let edge = |a: &[f32; 3], b: &[f32; 3]| {
+            let e = a[0] * b[1] - a[1] * b[0];
+            if e == 0.0 {
+                ((a[0] as f64) * (b[1] as f64) - (a[1] as f64) * (b[0] as f64)) as f32
+            } else {
+                e
+            }
+        };
+        let e1 = edge(&p2t, &p3t);
+        let e2 = edge(&p3t, &p1t);
+        let e3 = edge(&p1t, &p2t);
+
+        // The ray hits the triangle if it's on the same side of every edge
+        if (e1 < 0.0 || e2 < 0.0 || e3 < 0.0) && (e1 > 0.0 || e2 > 0.0 || e3 > 0.0) {
             return None;
         }
 
-        // Compute second barycentric coordinate
-        let s2 = s.cross_with(&e1);
-        let b2 = r.d.dot(&s2) * inv_divisor;
-        if b2 < 0.0 || (b1 + b2) > 1.0 {
+        let det = e1 + e2 + e3;
+        if det == 0.0 {
             return None;
         }
 
-        // Compute t to intersection point
-        let t = e2.dot(&s2) * inv_divisor;
+        // Compute t from the sheared z coordinates, weighted by the edges
+        let inv_det = 1.0 / det;
+        let t = (e1 * p1t[2] + e2 * p2t[2] + e3 * p3t[2]) * sz * inv_det;
+        if t < r.mint() || t > r.maxt() {
+            return None;
+        }
 
-        if t < r.mint() || t > r.maxt() { None } else { Some((t, b1, b2)) }
+        // Each edge's share of the area weights the vertex opposite it
+        Some((t, e2 * inv_det, e3 * inv_det))
     }
 
     fn get_uvs(&self) -> [[f32; 2]; 3] {
@@ -86,22 +174,105 @@ impl Triangle {
         }
     }
 
-    pub fn base<'a>(&'a self) -> &'a ShapeBase { self.mesh.base() }
+    // The point of the triangle with barycentric coordinates b1 and b2 at
+    // the given time, and the bound on its error from interpolating the
+    // vertices
+    fn get_point(&self, b1: f32, b2: f32, time: f32) -> (Point, Vector) {
+        let (p1, p2, p3) = self.get_vertices_at(time);
+        let b0 = 1.0 - b1 - b2;
+        let (v0, v1, v2) = (b0 * Vector::from(p1), b1 * Vector::from(p2), b2 * Vector::from(p3));
+        let p_error = gamma(7) * (v0.abs() + v1.abs() + v2.abs());
+        (Point::new() + v0 + v1 + v2, p_error)
+    }
 
-    pub fn object_bound(&self) -> BBox {
-        let (p1, p2, p3) = self.get_vertices();
+    // The geometry at the point of the triangle with barycentric coordinates
+    // b1 and b2 at the given time, unless the alpha texture cuts a hole there
+    fn get_differential_geometry(&self, b1: f32, b2: f32, time: f32)
+                                 -> Option<DifferentialGeometry> {
+        let (p1, p2, p3) = self.get_vertices_at(time);
+        let uvs = self.get_uvs();
 
+        // Compute deltas for triangle partial derivatives
+        let du1 = uvs[0][0] - uvs[2][0];
+        let du2 = uvs[1][0] - uvs[2][0];
+        let dv1 = uvs[0][1] - uvs[2][1];
+        let dv2 = uvs[1][1] - uvs[2][1];
+
+        let dp1 = &p1 - &p3;
+        let dp2 = &p2 - &p3;
+
+        // Compute triangle partial derivatives
+        let (dpdu, dpdv) = {
+            let determinant = du1 * dv2 - dv1 * du2;
+            if determinant == 0.0 {
+                // Handle zero determinant for triangle partial
+                // derivatives matrix
+                coordinate_system(&(&p3 - &p1).into_cross(&p2 - &p1).normalize())
+            } else {
+                let inv_det = 1.0 / determinant;
+                (( dv2 * &dp1 - dv1 * &dp2) * inv_det,
+                 (-du2 * &dp1 + du1 * &dp2) * inv_det)
+            }
+        };
+
+        // Interpolate (u, v) triangle parametric coordinates
+        let b0 = 1.0 - b1 - b2;
+        let tu = b0 * uvs[0][0] + b1 * uvs[1][0] + b2 * uvs[2][0];
+        let tv = b0 * uvs[0][1] + b1 * uvs[1][1] + b2 * uvs[2][1];
+
+        // Test intersection against alpha texture, if present
+        let dg = DifferentialGeometry::new_with(
+            self.get_point(b1, b2, time).0, dpdu, dpdv, Normal::new(), Normal::new(), tu, tv,
+            Some(self.base().clone()));
+
+        if let Some(tex_ref) = self.mesh.atex.as_ref().map(|t| t.clone()) {
+            if (*tex_ref).evaluate(&dg) == 0.0 {
+                return None
+            }
+        }
+
+        Some(dg)
+    }
+
+    pub fn base<'a>(&'a self) -> &'a ShapeBase { &self.base }
+
+    pub fn object_bound(&self) -> BBox {
         let w2o = &(self.base().world2object);
-        BBox::from(w2o.xf(p1))
-            .unioned_with(w2o.xf(p2))
-            .unioned_with(w2o.xf(p3))
+        self.world_positions().fold(BBox::new(), |b, p| b.unioned_with(w2o.t(p)))
+    }
+
+    // The vertices at both ends of the motion of deforming meshes, which
+    // bound the triangle at every time in between
+    fn world_positions(&self) -> impl Iterator<Item = &Point> {
+        let data = &self.mesh;
+        let ends = data.p_end.iter().flat_map(move |p_end| self.v.iter().map(|&i| &p_end[i]));
+        self.v.iter().map(|&i| &data.p[i]).chain(ends)
     }
 
+    // Deforming triangles are measured and sampled where they are at the
+    // start of their motion
     pub fn area(&self) -> f32 {
         let (p1, p2, p3) = self.get_vertices();
         0.5 * (&p2 - &p1).into_cross(&p3 - &p1).length()
     }
 
+    // Uniformly samples a point on the triangle, returning it along with the
+    // geometric normal there, which faces the same way as the normals of
+    // intersections with it
+    pub fn sample(&self, u1: f32, u2: f32) -> (Point, Normal) {
+        let (p1, p2, p3) = self.get_vertices();
+        let (b1, b2) = uniform_sample_triangle(u1, u2);
+        let p = b1 * &p1 + b2 * &p2 + (1.0 - b1 - b2) * &p3;
+
+        let n = Normal::from((&p2 - &p1).into_cross(&p3 - &p1).normalize());
+        let base = self.base();
+        let n = if base.reverse_orientation ^ base.transform_swaps_handedness { -n } else { n };
+        (p, n)
+    }
+
+    // The density of sample choosing p with respect to surface area
+    pub fn pdf(&self, _p: &Point) -> f32 { 1.0 / self.area() }
+
     pub fn get_shading_geometry(&self, o2w: &Transform,
                                 dg: DifferentialGeometry)
                                 -> DifferentialGeometry {
@@ -149,10 +320,12 @@ impl Triangle {
                 }
             };
 
-            let ts = ss.cross(Vector::from(ns.clone()));
+            // Make ss perpendicular to the shading normal, keeping it in the
+            // plane that it spans with the interpolated tangent
+            let ts = Vector::from(ns.clone()).cross(ss);
             if ts.length_squared() > 0f32 {
-                (ts.clone().normalize(),
-                 Vector::from(ns).cross(Vector::from(ts)))
+                let ts = ts.normalize();
+                (ts.cross(Vector::from(ns)), ts)
             } else {
                 coordinate_system(&Vector::from(ns))
             }
@@ -195,71 +368,29 @@ impl Triangle {
 
 impl HasBounds for Triangle {
     fn world_bound(&self) -> BBox {
-        let (p1, p2, p3) = self.get_vertices();
-
-        BBox::new()
-            .unioned_with(p1)
-            .unioned_with(p2)
-            .unioned_with(p3)
+        self.world_positions().fold(BBox::new(), |b, p| b.unioned_with_ref(p))
     }
 }
 
 impl Intersectable<ShapeIntersection> for Triangle {
     fn intersect_p(&self, r: &Ray) -> bool {
-        self.get_intersection_point(r).is_some()
+        match self.get_intersection_point(r) {
+            None => false,
+            // Only look up the hit's texture coordinates if they're needed
+            // for the alpha texture
+            Some((_, b1, b2)) => self.mesh.atex.is_none() ||
+                self.get_differential_geometry(b1, b2, r.time).is_some()
+        }
     }
 
     fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
-        let (t, b1, b2) = {
-            match self.get_intersection_point(r) {
-                None => return None,
-                Some(t) => t
-            }
-        };
-
-        let (p1, p2, p3) = self.get_vertices();
-        let uvs = self.get_uvs();
-
-        // Compute deltas for triangle partial derivatives
-        let du1 = uvs[0][0] - uvs[2][0];
-        let du2 = uvs[1][0] - uvs[2][0];
-        let dv1 = uvs[0][1] - uvs[2][1];
-        let dv2 = uvs[1][1] - uvs[2][1];
-
-        let dp1 = &p1 - &p3;
-        let dp2 = &p2 - &p3;
-
-        // Compute triangle partial derivatives
-        let (dpdu, dpdv) = {
-            let determinant = du1 * dv2 - dv1 * du2;
-            if determinant == 0.0 {
-                // Handle zero determinant for triangle partial
-                // derivatives matrix
-                coordinate_system(&(&p3 - &p1).into_cross(&p2 - &p1).normalize())
-            } else {
-                let inv_det = 1.0 / determinant;
-                (( dv2 * &dp1 - dv1 * &dp2) * inv_det,
-                 (-du2 * &dp1 + du1 * &dp2) * inv_det)
-            }
-        };
-
-        // Interpolate (u, v) triangle parametric coordinates
-        let b0 = 1.0 - b1 - b2;
-        let tu = b0 * uvs[0][0] + b1 * uvs[1][0] + b2 * uvs[2][0];
-        let tv = b0 * uvs[0][1] + b1 * uvs[1][1] + b2 * uvs[2][1];
-
-        // Test intersection against alpha texture, if present
-        let dg = DifferentialGeometry::new_with(
-            r.point_at(t), dpdu, dpdv, Normal::new(), Normal::new(), tu, tv,
-            Some(self.base().clone()));
+        let (t, b1, b2) = self.get_intersection_point(r)?;
+        let dg = self.get_differential_geometry(b1, b2, r.time)?;
 
-        if let Some(tex_ref) = self.mesh.atex.as_ref().map(|t| t.clone()) {
-            if (*tex_ref).evaluate(&dg) == 0.0 {
-                return None
-            }
-        }
-
-        Some(ShapeIntersection::new(t, t * 5e-4, dg))
+        // The hit point is interpolated from the vertices rather than found
+        // along the ray, since its error is much easier to bound that way
+        let (_, p_error) = self.get_point(b1, b2, r.time);
+        Some(ShapeIntersection::new(t, p_error, dg))
     }
 }
 
@@ -273,65 +404,185 @@ impl ::std::ops::Index<usize> for Triangle {
     }
 }
 
+// The vertices and their attributes, which a mesh shares with all of the
+// triangles that it's refined into rather than each having a copy
 #[derive(Clone, Debug)]
-pub struct Mesh {
-    base: ShapeBase,
+struct MeshData {
     vertex_index: Vec<usize>,
     p: Vec<Point>,
     n: Option<Vec<Normal>>,
     s: Option<Vec<Vector>>,
     uvs: Option<Vec<f32>>,
-    atex: Option<ScalarTextureReference>
+    atex: Option<ScalarTextureReference>,
+    culling: BackfaceCulling,
+
+    // The positions of the vertices at the end of the motion of a deforming
+    // mesh, which p holds the positions of at its start, and the times
+    // that the motion runs between
+    p_end: Option<Vec<Point>>,
+    motion_times: (f32, f32),
+
+    // The area of each triangle, computed the first time it's needed. A
+    // mesh's geometry never changes after it's created, so the cache never
+    // has to be invalidated and clones can share what's already computed.
+    // Anything that moves the vertices must build a new Mesh instead.
+    triangle_areas: OnceLock<Distribution1D>
+}
+
+impl MeshData {
+    // How far along its motion a deforming mesh is at the given time
+    fn motion_fraction(&self, time: f32) -> f32 {
+        let (start, end) = self.motion_times;
+        if end > start { ((time - start) / (end - start)).clamp(0.0, 1.0) } else { 0.0 }
+    }
 }
 
-impl PartialEq for Mesh {
-    fn eq(&self, rhs: &Mesh) -> bool {
-        self.base == rhs.base &&
-            self.vertex_index == rhs.vertex_index &&
+impl PartialEq for MeshData {
+    fn eq(&self, rhs: &MeshData) -> bool {
+        self.vertex_index == rhs.vertex_index &&
             self.p == rhs.p &&
             self.n == rhs.n &&
             self.s == rhs.s &&
-            self.uvs == rhs.uvs
+            self.uvs == rhs.uvs &&
+            self.culling == rhs.culling &&
+            self.p_end == rhs.p_end &&
+            self.motion_times == rhs.motion_times
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mesh {
+    base: ShapeBase,
+    data: Arc<MeshData>
+}
+
 impl Mesh {
+    // The points are moved into world space, but the normals and tangents,
+    // which there must be one of for each point, stay in object space and
+    // are only transformed when shading. Hits where the alpha texture is
+    // zero pass through the mesh.
     pub fn new(o2w: Transform, w2o: Transform, ro: bool, vi: &[usize],
-               _p: &[Point], _n: Option<&[Normal]>, _s: Option<&[Vector]>,
-               uv: Option<&[f32]>, _atex: Option<ScalarTextureReference>)
+               p: &[Point], n: Option<&[Normal]>, s: Option<&[Vector]>,
+               uv: Option<&[f32]>, atex: Option<ScalarTextureReference>)
                -> Mesh {
         assert!(vi.len() % 3 == 0);
-        let xf = o2w.clone();
-        Mesh {
-            base: ShapeBase::new(o2w, w2o, ro),
+        assert!(n.is_none_or(|n| n.len() == p.len()));
+        assert!(s.is_none_or(|s| s.len() == p.len()));
+        assert!(uv.is_none_or(|uv| uv.len() == 2 * p.len()));
+        let data = MeshData {
             vertex_index: vi.to_vec(),
-            p: _p.iter().map(|x| xf.t(x)).collect(),
-            n: _n.map(|v| v.to_vec()),
-            s: _s.map(|v| v.to_vec()),
+            p: p.iter().map(|x| o2w.t(x)).collect(),
+            n: n.map(|v| v.to_vec()),
+            s: s.map(|v| v.to_vec()),
             uvs: uv.map(|v| v.to_vec()),
-            atex: _atex.clone()
+            atex,
+            culling: BackfaceCulling::Never,
+            p_end: None,
+            motion_times: (0.0, 1.0),
+            triangle_areas: OnceLock::new()
+        };
+
+        Mesh {
+            base: ShapeBase::new(o2w, w2o, ro),
+            data: Arc::new(data)
         }
     }
 
+    pub fn with_backface_culling(mut self, culling: BackfaceCulling) -> Mesh {
+        Arc::make_mut(&mut self.data).culling = culling;
+        self
+    }
+
+    // Makes the mesh deform over the given times, with its vertices moving
+    // from their positions at the start time to those in p_end, which are
+    // in object space too, at the end time. Rigid motion is better left to
+    // animated transforms, which don't need a second copy of the vertices.
+    pub fn with_vertex_motion(mut self, p_end: &[Point], start_time: f32,
+                              end_time: f32) -> Mesh {
+        let o2w = &self.base.object2world;
+        let data = Arc::make_mut(&mut self.data);
+        assert_eq!(p_end.len(), data.p.len());
+        data.p_end = Some(p_end.iter().map(|x| o2w.t(x)).collect());
+        data.motion_times = (start_time, end_time);
+        self
+    }
+
     pub fn base<'a>(&'a self) -> &'a ShapeBase { &self.base }
 
+    // Returns a distribution over the triangles of the mesh, in the order
+    // that they appear in the vertex indices, proportional to their area.
+    pub fn triangle_area_distribution(&self) -> &Distribution1D {
+        let data = &self.data;
+        data.triangle_areas.get_or_init(|| {
+            let areas: Vec<f32> = data.vertex_index.chunks(3).map(|v| {
+                let (p1, p2, p3) = (&data.p[v[0]], &data.p[v[1]], &data.p[v[2]]);
+                0.5 * (p2 - p1).into_cross(p3 - p1).length()
+            }).collect();
+            Distribution1D::new(&areas)
+        })
+    }
+
+    pub fn area(&self) -> f32 {
+        if self.data.vertex_index.is_empty() {
+            return 0.0;
+        }
+
+        let areas = self.triangle_area_distribution();
+        areas.func_int() * (areas.count() as f32)
+    }
+
+    // Uniformly samples a point on the mesh by picking a triangle in
+    // proportion to its area and reusing u1 to sample inside of it
+    pub fn sample(&self, u1: f32, u2: f32) -> (Point, Normal) {
+        let areas = self.triangle_area_distribution();
+        let (u, _, tri) = areas.sample_continuous(u1);
+        let u1 = u * (areas.count() as f32) - (tri as f32);
+        self.triangle(tri).sample(u1.clamp(0.0, 1.0), u2)
+    }
+
+    // The density of sample choosing p with respect to surface area
+    pub fn pdf(&self, _p: &Point) -> f32 { 1.0 / self.area() }
+
+    // The density, with respect to solid angle, of sample choosing the
+    // direction wi from p, which adds up the densities of every triangle
+    // along it. This tests the ray against each triangle in turn.
+    pub fn pdf_wi(&self, p: &Point, wi: &Vector) -> f32 {
+        let area = self.area();
+        (0..(self.data.vertex_index.len() / 3)).map(|i| {
+            area_pdf_wi(&self.triangle(i), area, p, wi)
+        }).sum()
+    }
+
+    // The i-th triangle of the mesh. Unlike the triangles from refine, it
+    // keeps the mesh's id, so it's only meant for sampling the mesh.
+    fn triangle(&self, i: usize) -> Triangle {
+        let v = &self.data.vertex_index[(3 * i)..(3 * i + 3)];
+        Triangle { base: self.base.clone(), mesh: self.data.clone(), v: [v[0], v[1], v[2]] }
+    }
+
     pub fn object_bound(&self) -> BBox {
         let w2o = &self.base.world2object;
-        self.p.iter().fold(BBox::new(), |b, p| b.unioned_with(w2o.t(p)))
+        self.world_positions().fold(BBox::new(), |b, p| b.unioned_with(w2o.t(p)))
+    }
+
+    // The positions of the vertices at both ends of the motion of deforming
+    // meshes
+    fn world_positions(&self) -> impl Iterator<Item = &Point> {
+        self.data.p.iter().chain(self.data.p_end.iter().flatten())
     }
 }
 
 impl Refinable<Triangle> for Mesh {
+    // Every triangle gets its own id, but shares the mesh's transforms and
+    // vertex data, so only the indices of its vertices are its own
     fn refine(self) -> Vec<Triangle> {
-        let m = Arc::new(self);
-        let mut indices = m.vertex_index.clone();
-        let mut tris = Vec::new();
-        while let (Some(v1), Some(v2), Some(v3)) =
-            (indices.pop(), indices.pop(), indices.pop()) {
-                tris.push( Triangle { mesh: m.clone(), v: [v1, v2, v3] });
+        self.data.vertex_index.chunks_exact(3).rev().map(|v| {
+            // Keep the winding of the vertices, which decides which side of
+            // the triangle is the front
+            Triangle {
+                base: self.base.refined(), mesh: self.data.clone(), v: [v[0], v[1], v[2]]
             }
-
-        tris
+        }).collect()
     }
 
     fn is_refined(&self) -> bool { false }
@@ -339,7 +590,7 @@ impl Refinable<Triangle> for Mesh {
 
 impl HasBounds for Mesh {
     fn world_bound(&self) -> BBox {
-        self.p.iter().fold(BBox::new(), |b, p| b.unioned_with_ref(p))
+        self.world_positions().fold(BBox::new(), |b, p| b.unioned_with_ref(p))
     }
 }
 
@@ -347,15 +598,16 @@ impl HasBounds for Mesh {
 mod tests {
     use super::*;
 
-    use bbox::BBox;
-    use bbox::HasBounds;
-    use diff_geom::DifferentialGeometry;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
-    use intersection::Intersectable;
-    use primitive::Refinable;
-    use ray::Ray;
-    use transform::transform::Transform;
+    use crate::bbox::BBox;
+    use crate::bbox::HasBounds;
+    use crate::diff_geom::DifferentialGeometry;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::primitive::Refinable;
+    use crate::ray::Ray;
+    use crate::ray::offset_ray_origin;
+    use crate::transform::transform::Transform;
 
     // Tetrahedron
     static TET_PTS : [Point; 4] =
@@ -371,16 +623,16 @@ mod tests {
         let mesh = Mesh::new(Transform::new(), Transform::new(), false,
                              &TET_TRIS, &TET_PTS, None, None, None, None);
         // Make sure that all of the indices and points remained untransformed...
-        assert_eq!(mesh.vertex_index, TET_TRIS.to_vec());
-        assert_eq!(mesh.p, TET_PTS.to_vec());
+        assert_eq!(mesh.data.vertex_index, TET_TRIS.to_vec());
+        assert_eq!(mesh.data.p, TET_PTS.to_vec());
 
         // If we rotate it about y by 90 degrees then it should be OK as well
         let xf = Transform::rotate_y(90.0);
         let mesh2 = Mesh::new(xf.clone(), xf.inverse(), false,
                               &TET_TRIS, &TET_PTS, None, None, None, None);
 
-        assert_eq!(mesh2.vertex_index, TET_TRIS.to_vec());
-        assert!(mesh2.p.iter().zip(vec![
+        assert_eq!(mesh2.data.vertex_index, TET_TRIS.to_vec());
+        assert!(mesh2.data.p.iter().zip(vec![
             Point::new_with(0.0, 0.0, 0.0),
             Point::new_with(0.0, 0.0, -1.0),
             Point::new_with(0.0, 1.0, 0.0),
@@ -390,6 +642,33 @@ mod tests {
             }));
     }
 
+    #[test]
+    fn it_caches_its_area() {
+        let mesh = Mesh::new(Transform::new(), Transform::new(), false,
+                             &TET_TRIS, &TET_PTS, None, None, None, None);
+        let expected = 1.5 + 0.75f32.sqrt();
+        assert!((mesh.area() - expected).abs() < 1e-5);
+
+        // The distribution is only built once and is kept by clones
+        let areas: *const Distribution1D = mesh.triangle_area_distribution();
+        assert!(::std::ptr::eq(areas, mesh.triangle_area_distribution()));
+        assert_eq!(mesh.clone().triangle_area_distribution(),
+                   mesh.triangle_area_distribution());
+
+        // The equilateral face is the most likely to be picked
+        let dist = mesh.triangle_area_distribution();
+        assert_eq!(dist.count(), 4);
+        assert!((dist.discrete_pdf(3) - 0.75f32.sqrt() / expected).abs() < 1e-5);
+
+        // The triangles add up to the same area
+        let tri_area = mesh.refine().iter().fold(0.0, |a, t| a + t.area());
+        assert!((tri_area - expected).abs() < 1e-5);
+
+        let empty = Mesh::new(Transform::new(), Transform::new(), false,
+                              &[], &[], None, None, None, None);
+        assert_eq!(empty.area(), 0.0);
+    }
+
     #[test]
     fn it_has_object_space_bounds() {
         let xf = Transform::rotate_y(90.0);
@@ -429,10 +708,17 @@ mod tests {
         let tris = mesh.refine();
 
         assert_eq!(tris.len(), 4);
-        assert_eq!(tris[3].v, [2, 3, 0]);
-        assert_eq!(tris[2].v, [2, 1, 0]);
-        assert_eq!(tris[1].v, [1, 3, 0]);
-        assert_eq!(tris[0].v, [3, 2, 1]);
+        assert_eq!(tris[3].v, [0, 3, 2]);
+        assert_eq!(tris[2].v, [0, 1, 2]);
+        assert_eq!(tris[1].v, [0, 3, 1]);
+        assert_eq!(tris[0].v, [1, 2, 3]);
+
+        // The triangles share the mesh's vertices and transforms
+        assert!(tris.iter().all(|t| Arc::ptr_eq(&t.mesh, &tris[0].mesh)));
+        assert!(tris.iter().all(|t| {
+            Arc::ptr_eq(&t.base.object2world, &tris[0].base.object2world) &&
+                Arc::ptr_eq(&t.base.world2object, &tris[0].base.world2object)
+        }));
     }
 
     #[test]
@@ -496,6 +782,33 @@ mod tests {
             Vector::new_with(-1.0, -1.0, 1.0), 0.0)));
     }
 
+    #[test]
+    fn its_triangles_are_watertight() {
+        // A tilted quad split along its diagonal, which rays aimed right at
+        // the diagonal can't slip through
+        let xf = Transform::rotate(37.0, &Vector::new_with(1.0, 2.0, 3.0));
+        let pts = [Point::new_with(0.0, 0.0, 0.0), Point::new_with(1.0, 0.0, 0.0),
+                   Point::new_with(1.0, 1.0, 0.0), Point::new_with(0.0, 1.0, 0.0)];
+        let tris = Mesh::new(xf.clone(), xf.inverse(), false, &[0, 1, 2, 0, 2, 3], &pts,
+                             None, None, None, None).refine();
+
+        let origin = Point::new_with(0.3, 0.7, 2.0);
+        for i in 1..1000 {
+            let x = i as f32 / 1000.0;
+            let target = xf.xf(Point::new_with(x, x, 0.0));
+            let o = xf.xf(origin.clone());
+            let r = Ray::new_with(o.clone(), target - o, 0.0);
+            assert!(tris.iter().any(|t| t.intersect_p(&r)), "Ray leaked at {}", x);
+        }
+
+        // Rays in the plane of a triangle miss it
+        let r = Ray::new_with(Point::new_with(-1.0, 0.5, 0.0),
+                              Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        let flat = Mesh::new(Transform::new(), Transform::new(), false, &[0, 1, 2], &pts,
+                             None, None, None, None).refine();
+        assert!(!flat[0].intersect_p(&r));
+    }
+
     #[test]
     #[ignore]
     fn its_triangles_have_intersection_information() {
@@ -524,25 +837,218 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
-    fn its_triangles_have_shading_geometry() {
-        let mesh = Mesh::new(Transform::new(), Transform::new(), false,
+    fn rays_leaving_its_triangles_dont_hit_them_again() {
+        // A triangle far from the origin, where its points are imprecise
+        let xf = Transform::translate(&Vector::new_with(-3000.0, 2000.0, 1000.0)) *
+            Transform::rotate_x(30.0);
+        let mesh = Mesh::new(xf.clone(), xf.inverse(), false,
+                             &TET_TRIS[3..6], &TET_PTS, None, None, None, None);
+        let tri = &mesh.refine()[0];
+        let (p1, p2, p3) = tri.get_vertices();
+        let o = &p1 + Vector::new_with(0.3, -2.0, 5.0);
+        for i in 0..64 {
+            let (b1, b2) = uniform_sample_triangle((i % 8) as f32 / 8.0 + 0.0625,
+                                                   (i / 8) as f32 / 8.0 + 0.0625);
+            let target = b1 * &p1 + b2 * &p2 + (1.0 - b1 - b2) * &p3;
+            let r = Ray::new_with(o.clone(), (&target - &o).normalize(), 0.0);
+            let si = tri.intersect(&r).unwrap();
+
+            // Rays that graze the triangle on either side miss it
+            let n = si.dg.nn.clone();
+            let tangent = (&p2 - &p1).normalize();
+            for side in [1e-3, -1e-3] {
+                let w = &tangent + side * Vector::from(n.clone());
+                let po = offset_ray_origin(&si.dg.p, &si.p_error, &n, &w);
+                assert!(!tri.intersect_p(&Ray::new_with(po, w, 0.0)));
+            }
+        }
+    }
+
+    #[test]
+    fn its_triangles_can_be_sampled() {
+        let xf = Transform::translate(&Vector::new_with(1.0, 2.0, 3.0));
+        let mesh = Mesh::new(xf.clone(), xf.inverse(), false,
                              &TET_TRIS, &TET_PTS, None, None, None, None);
-        let tris = mesh.refine();
+        for tri in mesh.refine() {
+            let (p1, p2, p3) = tri.get_vertices();
+            let mut centroid = Vector::new();
+            for i in 0..16 {
+                let (u1, u2) = ((i % 4) as f32 / 4.0 + 0.125, (i / 4) as f32 / 4.0 + 0.125);
+                let (p, n) = tri.sample(u1, u2);
+
+                // The point is inside of the triangle...
+                let area = |a: &Point, b: &Point, c: &Point| {
+                    0.5 * (b - a).into_cross(c - a).length()
+                };
+                let total = area(&p, &p2, &p3) + area(&p1, &p, &p3) + area(&p1, &p2, &p);
+                assert!((total - tri.area()).abs() < 1e-4);
+                centroid = centroid + Vector::from(p.clone()) / 16.0;
+
+                // ... and faces the same way as intersections with it
+                let r = Ray::new_with(&p + Vector::from(n.clone()), -Vector::from(n.clone()), 0.0);
+                let si = tri.intersect(&r).unwrap();
+                assert!((Vector::from(si.dg.nn) - Vector::from(n)).length() < 1e-4);
+            }
+
+            // Stratified samples are spread evenly over it
+            let expected = (Vector::from(p1) + Vector::from(p2) + Vector::from(p3)) / 3.0;
+            assert!((centroid - expected).length() < 0.05);
+            assert_eq!(tri.pdf(&Point::new()), 1.0 / tri.area());
+        }
+    }
+
+    #[test]
+    fn its_triangles_have_shading_geometry() {
+        // A triangle whose normals lean out towards its corners, and whose
+        // tangents all point diagonally
+        let pts = [Point::new_with(0.0, 0.0, 0.0),
+                   Point::new_with(1.0, 0.0, 0.0),
+                   Point::new_with(0.0, 1.0, 0.0)];
+        let ns = [Normal::new_with(0.0, 0.0, 1.0),
+                  Normal::new_with(1.0, 0.0, 1.0).normalize(),
+                  Normal::new_with(0.0, 1.0, 1.0).normalize()];
+        let ss = [Vector::new_with(1.0, 1.0, 0.0),
+                  Vector::new_with(1.0, 1.0, 0.0),
+                  Vector::new_with(1.0, 1.0, 0.0)];
+        let uvs = [0.0, 0.0, 1.0, 0.0, 0.0, 0.5];
+        let tri = Mesh::new(Transform::new(), Transform::new(), false, &[0, 1, 2], &pts,
+                            Some(&ns), Some(&ss), Some(&uvs), None).refine().pop().unwrap();
+
+        // The texture coordinates are interpolated across the triangle
+        let r = Ray::new_with(Point::new_with(0.5, 0.25, 1.0),
+                              Vector::new_with(0.0, 0.0, -1.0), 0.0);
+        let dg = tri.intersect(&r).unwrap().dg;
+        assert!((dg.u - 0.5).abs() < 1e-5);
+        assert!((dg.v - 0.125).abs() < 1e-5);
+        assert!((Vector::from(dg.nn.clone()).z - 1.0).abs() < 1e-5);
+
+        // And so are the normals
+        let dgs = tri.get_shading_geometry(&Transform::new(), dg.clone());
+        let expected = (0.25 * Vector::from(ns[0].clone()) + 0.5 * Vector::from(ns[1].clone()) +
+                        0.25 * Vector::from(ns[2].clone())).normalize();
+        let n = Vector::from(dgs.nn.clone());
+        assert!((n.dot(&expected) - 1.0).abs() < 1e-5);
+
+        // The tangent is made perpendicular to the normal, but otherwise
+        // follows the one given
+        let ss = dgs.dpdu.clone().normalize();
+        assert!(ss.dot(&n).abs() < 1e-5);
+        assert!(ss.dot(&Vector::new_with(1.0, 1.0, 0.0).normalize()) > 0.9);
+        assert!(Vector::from(dgs.dndu.clone()).length_squared() > 0.0);
+
+        // Without normals or tangents, the shading geometry is the geometry
+        let plain = Mesh::new(Transform::new(), Transform::new(), false, &[0, 1, 2], &pts,
+                              None, None, Some(&uvs), None).refine().pop().unwrap();
+        let dg = plain.intersect(&r).unwrap().dg;
+        assert_eq!(plain.get_shading_geometry(&Transform::new(), dg.clone()), dg);
+    }
+
+    #[test]
+    fn its_triangles_have_holes_where_alpha_is_zero() {
+        use crate::texture::ConstantTexture;
 
-        assert_eq!(tris[0].get_shading_geometry(&Transform::new(),
-                                                DifferentialGeometry::new()),
-                   DifferentialGeometry::new());
-        assert_eq!(tris[1].get_shading_geometry(&Transform::new(),
-                                                DifferentialGeometry::new()),
-                   DifferentialGeometry::new());
-        assert_eq!(tris[2].get_shading_geometry(&Transform::new(),
-                                                DifferentialGeometry::new()),
-                   DifferentialGeometry::new());
-        assert_eq!(tris[3].get_shading_geometry(&Transform::new(),
-                                                DifferentialGeometry::new()),
-                   DifferentialGeometry::new());
-
-        panic!("Add more actual tests!");
+        let tri = |alpha: f32| {
+            let atex: ScalarTextureReference = Arc::new(ConstantTexture::new(alpha));
+            Mesh::new(Transform::new(), Transform::new(), false, &[0, 1, 2], &TET_PTS[0..3],
+                      None, None, None, Some(atex)).refine().pop().unwrap()
+        };
+        let r = Ray::new_with(Point::new_with(0.25, 0.25, 1.0),
+                              Vector::new_with(0.0, 0.0, -1.0), 0.0);
+
+        assert!(tri(1.0).intersect(&r).is_some());
+        assert!(tri(1.0).intersect_p(&r));
+
+        // Shadow rays pass through the holes too
+        assert!(tri(0.0).intersect(&r).is_none());
+        assert!(!tri(0.0).intersect_p(&r));
+    }
+
+    #[test]
+    fn it_can_cull_back_faces_for_camera_rays() {
+        use crate::ray::RayKind;
+
+        let pts = [Point::new_with(0.0, 0.0, 0.0),
+                   Point::new_with(1.0, 0.0, 0.0),
+                   Point::new_with(0.0, 1.0, 0.0)];
+        let tri = |culling, ro| {
+            Mesh::new(Transform::new(), Transform::new(), ro, &[0, 1, 2], &pts,
+                      None, None, None, None)
+                .with_backface_culling(culling).refine().pop().unwrap()
+        };
+        let front = Ray::new_with(Point::new_with(0.25, 0.25, 1.0),
+                                  Vector::new_with(0.0, 0.0, -1.0), 0.0);
+        let back = Ray::new_with(Point::new_with(0.25, 0.25, -1.0),
+                                 Vector::new_with(0.0, 0.0, 1.0), 0.0);
+
+        // The front is the side that the normal points to
+        let t = tri(BackfaceCulling::CameraRays, false);
+        let dg = t.intersect(&front).unwrap().dg;
+        assert!(Vector::from(dg.nn).dot(&front.d) < 0.0);
+
+        // Only camera rays pass through the back...
+        assert!(t.intersect(&front.clone().with_kind(RayKind::Camera)).is_some());
+        assert!(t.intersect(&back.clone().with_kind(RayKind::Camera)).is_none());
+        assert!(t.intersect(&back).is_some());
+        assert!(t.intersect_p(&back.clone().with_kind(RayKind::Shadow)));
+
+        // ... unless shadow rays are asked to as well
+        let t = tri(BackfaceCulling::CameraAndShadowRays, false);
+        assert!(!t.intersect_p(&back.clone().with_kind(RayKind::Shadow)));
+        assert!(t.intersect_p(&front.clone().with_kind(RayKind::Shadow)));
+
+        // Flipping the triangle around flips which side is culled
+        let t = tri(BackfaceCulling::CameraRays, true);
+        assert!(t.intersect(&front.clone().with_kind(RayKind::Camera)).is_none());
+        assert!(t.intersect(&back.clone().with_kind(RayKind::Camera)).is_some());
+
+        // Nothing is culled by default
+        let t = tri(BackfaceCulling::default(), false);
+        assert!(t.intersect(&back.clone().with_kind(RayKind::Camera)).is_some());
+    }
+
+    #[test]
+    fn its_triangles_can_deform_over_time() {
+        // The triangle slides two units along x while the shutter is open
+        let pts = [Point::new_with(0.0, 0.0, 0.0),
+                   Point::new_with(1.0, 0.0, 0.0),
+                   Point::new_with(0.0, 1.0, 0.0)];
+        let pts_end = [Point::new_with(2.0, 0.0, 0.0),
+                       Point::new_with(3.0, 0.0, 0.0),
+                       Point::new_with(2.0, 1.0, 1.0)];
+        let xf = Transform::translate(&Vector::new_with(0.0, 0.0, 1.0));
+        let mesh = Mesh::new(xf.clone(), xf.inverse(), false, &[0, 1, 2], &pts,
+                             None, None, None, None)
+            .with_vertex_motion(&pts_end, 0.0, 2.0);
+
+        // Its bounds cover where it is at every time
+        let expected = BBox::new_with(Point::new_with(0.0, 0.0, 0.0),
+                                      Point::new_with(3.0, 1.0, 1.0));
+        assert_eq!(mesh.object_bound(), expected);
+        assert_eq!(mesh.world_bound(), xf.t(&expected));
+
+        let tri = mesh.refine().pop().unwrap();
+        assert_eq!(tri.object_bound(), expected);
+        assert_eq!(tri.world_bound(), xf.t(&expected));
+
+        // Rays only hit it where it is at their time
+        let at = |x: f32, time: f32| {
+            let mut r = Ray::new_with(Point::new_with(x, 0.25, 5.0),
+                                      Vector::new_with(0.0, 0.0, -1.0), 0.0);
+            r.time = time;
+            r
+        };
+        assert!(tri.intersect_p(&at(0.25, 0.0)));
+        assert!(!tri.intersect_p(&at(0.25, 2.0)));
+        assert!(!tri.intersect_p(&at(2.25, 0.0)));
+        assert!(tri.intersect_p(&at(2.25, 2.0)));
+
+        // Halfway through, it has moved halfway, and is tilted halfway up
+        let si = tri.intersect(&at(1.25, 1.0)).unwrap();
+        assert!((si.dg.p.clone() - Point::new_with(1.25, 0.25, 1.125)).length_squared() < 1e-8);
+        assert!((si.t_hit - 3.875).abs() < 1e-5);
+
+        // Its vertices stop moving outside of the motion
+        assert!(tri.intersect_p(&at(2.25, 5.0)));
+        assert!(tri.intersect_p(&at(0.25, -1.0)));
     }
 }
@@ -1,7 +1,10 @@
 mod helpers;
 
+mod cone;
+mod curve;
 mod cylinder;
 mod disk;
+mod implicit;
 mod loopsubdiv;
 mod mesh;
 mod sphere;
@@ -9,73 +12,103 @@ mod sphere;
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
 
-use bbox::BBox;
-use bbox::HasBounds;
-use diff_geom::DifferentialGeometry;
-use geometry::normal::Normal;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use primitive::Refinable;
-use primitive::FullyRefinable;
-use ray::Ray;
-use texture::{Texture, ScalarTextureReference};
-use transform::transform::Transform;
-
-use shape::sphere::Sphere;
-use shape::cylinder::Cylinder;
-use shape::disk::Disk;
-use shape::mesh::Triangle;
-use shape::mesh::Mesh;
-use shape::loopsubdiv::LoopSubdiv;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::normal::Normal;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::primitive::Refinable;
+use crate::primitive::FullyRefinable;
+use crate::ray::Ray;
+use crate::texture::{Texture, ScalarTextureReference};
+use crate::transform::transform::Transform;
+
+use crate::shape::sphere::Sphere;
+use crate::shape::cone::Cone;
+use crate::shape::curve::{Curve, Curves};
+use crate::shape::cylinder::Cylinder;
+use crate::shape::disk::Disk;
+use crate::shape::implicit::ImplicitSurface;
+use crate::shape::mesh::Triangle;
+use crate::shape::mesh::Mesh;
+use crate::shape::loopsubdiv::LoopSubdiv;
+
+pub use crate::shape::curve::CurveType;
+pub use crate::shape::implicit::{DistanceFunction, DistanceGrid};
+pub use crate::shape::mesh::BackfaceCulling;
 
 #[derive(Debug, Clone, PartialOrd)]
 pub struct ShapeBase {
-    pub object2world: Transform,
-    pub world2object: Transform,
+    // Shapes refined from the same shape share its transforms
+    pub object2world: Arc<Transform>,
+    pub world2object: Arc<Transform>,
     pub reverse_orientation: bool,
     pub transform_swaps_handedness: bool,
+    // Whether u wraps around, i.e. u = 0 and u = 1 are the same points, as
+    // they are for full surfaces of revolution
+    pub periodic_u: bool,
     pub shape_id: usize
 }
 
 static NEXT_SHAPE_ID: AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
 
+fn next_shape_id() -> usize {
+    NEXT_SHAPE_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed)
+}
+
+// Every shape has a unique id that identifies it for the rest of the
+// render: clones of a shape share its id, since they're the same surface,
+// while each shape produced by refinement gets an id of its own. Two
+// shapes are equal only if they have the same id.
 impl ShapeBase {
     pub fn new(o2w: Transform, w2o: Transform, ro: bool) -> ShapeBase {
         let swap = o2w.swaps_handedness();
         ShapeBase {
-            object2world: o2w,
-            world2object: w2o,
+            object2world: Arc::new(o2w),
+            world2object: Arc::new(w2o),
             reverse_orientation: ro,
             transform_swaps_handedness: swap,
-            shape_id: NEXT_SHAPE_ID.fetch_add(
-                1, ::std::sync::atomic::Ordering::Relaxed)
+            periodic_u: false,
+            shape_id: next_shape_id()
         }
     }
+
+    pub fn with_periodic_u(mut self, periodic: bool) -> ShapeBase {
+        self.periodic_u = periodic;
+        self
+    }
+
+    // Returns a copy of this base with a new id, for shapes created by
+    // refining the shape that this base belongs to.
+    pub fn refined(&self) -> ShapeBase {
+        ShapeBase { shape_id: next_shape_id(), ..self.clone() }
+    }
 }
 
 impl ::std::cmp::PartialEq for ShapeBase {
     fn eq(&self, other: &ShapeBase) -> bool {
-        self.object2world == other.object2world
-            && self.world2object == other.world2object
-            && self.reverse_orientation == other.reverse_orientation
-            && self.transform_swaps_handedness == other.transform_swaps_handedness
+        self.shape_id == other.shape_id
     }
 }
 
+// Where a ray hit a shape. The hit point is only known to within p_error
+// in each dimension of world space.
 #[derive(Debug, PartialEq, Clone)]
 pub struct ShapeIntersection {
     pub t_hit: f32,
-    pub ray_epsilon: f32,
+    pub p_error: Vector,
     pub dg: DifferentialGeometry
 }
 
 impl ShapeIntersection {
-    pub fn new(t: f32, eps: f32, dgeom: DifferentialGeometry)
+    pub fn new(t: f32, p_error: Vector, dgeom: DifferentialGeometry)
            -> ShapeIntersection {
         ShapeIntersection {
             t_hit: t,
-            ray_epsilon: eps,
+            p_error,
             dg: dgeom
         }
     }
@@ -85,7 +118,11 @@ impl ShapeIntersection {
 pub enum Shape {
     Sphere(Sphere),
     Disk(Disk),
+    Cone(Cone),
+    Curve(Curve),
+    Curves(Curves),
     Cylinder(Cylinder),
+    Implicit(ImplicitSurface),
     Triangle(Triangle),
     TriangleMesh(Mesh),
     LoopSubdiv(LoopSubdiv)
@@ -96,7 +133,11 @@ impl HasBounds for Shape {
         match self {
             &Shape::Sphere(ref s) => s.world_bound(),
             &Shape::Disk(ref d) => d.world_bound(),
+            Shape::Cone(c) => c.world_bound(),
+            Shape::Curve(c) => c.world_bound(),
+            Shape::Curves(c) => c.world_bound(),
             &Shape::Cylinder(ref c) => c.world_bound(),
+            Shape::Implicit(s) => s.world_bound(),
             &Shape::Triangle(ref t) => t.world_bound(),
             &Shape::TriangleMesh(ref m) => m.world_bound(),
             &Shape::LoopSubdiv(ref m) => m.world_bound()
@@ -110,7 +151,11 @@ impl Refinable for Shape {
         match self {
             &Shape::Sphere(_) => true,
             &Shape::Disk(_) => true,
+            Shape::Cone(_) => true,
+            Shape::Curve(_) => true,
+            Shape::Curves(_) => false,
             &Shape::Cylinder(_) => true,
+            Shape::Implicit(_) => true,
             &Shape::Triangle(_) => true,
             &Shape::TriangleMesh(_) => false,
             &Shape::LoopSubdiv(_) => false
@@ -121,7 +166,11 @@ impl Refinable for Shape {
         match self {
             Shape::Sphere(s) => vec![Shape::Sphere(s)],
             Shape::Disk(d) => vec![Shape::Disk(d)],
+            Shape::Cone(c) => vec![Shape::Cone(c)],
+            Shape::Curve(c) => vec![Shape::Curve(c)],
+            Shape::Curves(c) => c.refine().into_iter().map(Shape::Curve).collect(),
             Shape::Cylinder(c) => vec![Shape::Cylinder(c)],
+            Shape::Implicit(s) => vec![Shape::Implicit(s)],
             Shape::Triangle(t) => vec![Shape::Triangle(t)],
             Shape::TriangleMesh(m) => m.refine().iter().cloned().map(Shape::Triangle).collect(),
             Shape::LoopSubdiv(m) => m.refine().iter().cloned().map(Shape::TriangleMesh).collect()
@@ -136,7 +185,11 @@ impl Intersectable<ShapeIntersection> for Shape {
         match self {
             &Shape::Sphere(ref s) => s.intersect(ray),
             &Shape::Disk(ref d) => d.intersect(ray),
+            Shape::Cone(c) => c.intersect(ray),
+            Shape::Curve(c) => c.intersect(ray),
+            Shape::Curves(_) => None,
             &Shape::Cylinder(ref c) => c.intersect(ray),
+            Shape::Implicit(s) => s.intersect(ray),
             &Shape::Triangle(ref t) => t.intersect(ray),
             &Shape::TriangleMesh(_) => None,
             &Shape::LoopSubdiv(_) => None
@@ -147,7 +200,11 @@ impl Intersectable<ShapeIntersection> for Shape {
         match self {
             &Shape::Sphere(ref s) => s.intersect_p(ray),
             &Shape::Disk(ref d) => d.intersect_p(ray),
+            Shape::Cone(c) => c.intersect_p(ray),
+            Shape::Curve(c) => c.intersect_p(ray),
+            Shape::Curves(_) => false,
             &Shape::Cylinder(ref c) => c.intersect_p(ray),
+            Shape::Implicit(s) => s.intersect_p(ray),
             &Shape::Triangle(ref t) => t.intersect_p(ray),
             &Shape::TriangleMesh(_) => false,
             &Shape::LoopSubdiv(_) => false
@@ -160,7 +217,11 @@ impl Shape {
         match self {
             &Shape::Sphere(ref s) => s.base(),
             &Shape::Disk(ref d) => d.base(),
+            Shape::Cone(c) => c.base(),
+            Shape::Curve(c) => c.base(),
+            Shape::Curves(c) => c.base(),
             &Shape::Cylinder(ref c) => c.base(),
+            Shape::Implicit(s) => s.base(),
             &Shape::Triangle(ref t) => t.base(),
             &Shape::TriangleMesh(ref m) => m.base(),
             &Shape::LoopSubdiv(ref m) => m.base()
@@ -172,6 +233,20 @@ impl Shape {
         Shape::Sphere( Sphere::new(o2w, w2o, ro, rad, z0, z1, pm) )
     }
 
+    pub fn cone(o2w: Transform, w2o: Transform, ro: bool,
+                rad: f32, ht: f32, pm: f32) -> Shape {
+        Shape::Cone( Cone::new(o2w, w2o, ro, rad, ht, pm) )
+    }
+
+    // A piecewise cubic Bezier curve through the control points, which are
+    // shared by consecutive segments, that is split into 2^split_depth pieces
+    // per segment. Ribbons need a normal at each end of every segment.
+    pub fn curves(o2w: Transform, w2o: Transform, ro: bool, ty: CurveType,
+                  cp: &[Point], width0: f32, width1: f32, normals: Option<&[Normal]>,
+                  split_depth: usize) -> Shape {
+        Shape::Curves( Curves::new(o2w, w2o, ro, ty, cp, width0, width1, normals, split_depth) )
+    }
+
     pub fn cylinder(o2w: Transform, w2o: Transform, ro: bool,
                     rad: f32, z0: f32, z1: f32, pm: f32) -> Shape {
         Shape::Cylinder( Cylinder::new(o2w, w2o, ro, rad, z0, z1, pm) )
@@ -182,11 +257,48 @@ impl Shape {
         Shape::Disk( Disk::new(o2w, w2o, ro, ht, r, ri, t_max) )
     }
 
+    // The surface where the signed distance function is zero, which must lie
+    // within the given object space bounds
+    pub fn implicit(o2w: Transform, w2o: Transform, ro: bool,
+                    sdf: DistanceFunction, bounds: BBox) -> Shape {
+        Shape::Implicit( ImplicitSurface::new(o2w, w2o, ro, sdf, bounds) )
+    }
+
+    pub fn implicit_grid(o2w: Transform, w2o: Transform, ro: bool,
+                         grid: DistanceGrid) -> Shape {
+        Shape::Implicit( ImplicitSurface::from_grid(o2w, w2o, ro, grid) )
+    }
+
     pub fn triangle_mesh(o2w: Transform, w2o: Transform, ro: bool, vi: &[usize],
-                         _p: &[Point], _n: Option<&[Normal]>,
-                         _s: Option<&[Vector]>, uv: Option<&[f32]>,
-                         _atex: Option<ScalarTextureReference>) -> Shape {
-        Shape::TriangleMesh( Mesh::new(o2w, w2o, ro, vi, _p, _n, _s, uv, _atex) )
+                         p: &[Point], n: Option<&[Normal]>,
+                         s: Option<&[Vector]>, uv: Option<&[f32]>,
+                         atex: Option<ScalarTextureReference>) -> Shape {
+        Shape::TriangleMesh( Mesh::new(o2w, w2o, ro, vi, p, n, s, uv, atex) )
+    }
+
+    // Only triangle meshes can skip their back faces
+    pub fn with_backface_culling(self, culling: BackfaceCulling) -> Shape {
+        match self {
+            Shape::TriangleMesh(m) => Shape::TriangleMesh(m.with_backface_culling(culling)),
+            _ => {
+                println!("WARNING: Backface culling is only supported for triangle meshes");
+                self
+            }
+        }
+    }
+
+    // Only triangle meshes can deform, since the other shapes have no
+    // vertices to move
+    pub fn with_vertex_motion(self, p_end: &[Point], start_time: f32,
+                              end_time: f32) -> Shape {
+        match self {
+            Shape::TriangleMesh(m) =>
+                Shape::TriangleMesh(m.with_vertex_motion(p_end, start_time, end_time)),
+            _ => {
+                println!("WARNING: Vertex motion is only supported for triangle meshes");
+                self
+            }
+        }
     }
 
     pub fn loop_subdiv(o2w: Transform, w2o: Transform, ro: bool,
@@ -194,6 +306,19 @@ impl Shape {
         Shape::LoopSubdiv( LoopSubdiv::new(o2w, w2o, ro, vertex_indices, points, nl) )
     }
 
+    // Only subdivision surfaces can have creases, which are given by pairs of
+    // vertex indices, and corners
+    pub fn with_creases(self, edges: &[usize], sharpness: &[f32], corners: &[usize]) -> Shape {
+        match self {
+            Shape::LoopSubdiv(m) =>
+                Shape::LoopSubdiv(m.with_creases(edges, sharpness).with_corners(corners)),
+            _ => {
+                println!("WARNING: Creases are only supported for subdivision surfaces");
+                self
+            }
+        }
+    }
+
     pub fn can_intersect(&self) -> bool {
         match self {
             &Shape::LoopSubdiv(_) => false,
@@ -205,7 +330,11 @@ impl Shape {
         match self {
             &Shape::Sphere(ref s) => s.object_bound(),
             &Shape::Disk(ref d) => d.object_bound(),
+            Shape::Cone(c) => c.object_bound(),
+            Shape::Curve(c) => c.object_bound(),
+            Shape::Curves(c) => c.object_bound(),
             &Shape::Cylinder(ref c) => c.object_bound(),
+            Shape::Implicit(s) => s.object_bound(),
             &Shape::Triangle(ref t) => t.object_bound(),
             &Shape::TriangleMesh(ref m) => m.object_bound(),
             &Shape::LoopSubdiv(ref m) => m.object_bound()
@@ -225,9 +354,74 @@ impl Shape {
         match self {
             &Shape::Sphere(ref s) => s.area(),
             &Shape::Disk(ref d) => d.area(),
+            Shape::Cone(c) => c.area(),
+            Shape::Curve(c) => c.area(),
+            Shape::Curves(c) => c.area(),
             &Shape::Cylinder(ref c) => c.area(),
+            Shape::Implicit(s) => s.area(),
             &Shape::Triangle(ref t) => t.area(),
-            _ => self.clone().refine().iter().fold(0f32, |a, t| a + t.area())
+            &Shape::TriangleMesh(ref m) => m.area(),
+            &Shape::LoopSubdiv(ref m) => m.area()
+        }
+    }
+
+    // Whether points can be sampled on the surface of the shape, which
+    // area lights need. Curves have no surface of their own, since they turn
+    // to face whichever ray hits them, and implicit surfaces are only known
+    // where rays hit them.
+    pub fn can_sample(&self) -> bool {
+        !matches!(self, Shape::Curve(_) | Shape::Curves(_) | Shape::Implicit(_))
+    }
+
+    // Samples a point on the surface of the shape with respect to area,
+    // returning it along with the surface normal at that point, or None if
+    // the shape can't be sampled.
+    pub fn sample(&self, u1: f32, u2: f32) -> Option<(Point, Normal)> {
+        match self {
+            &Shape::Sphere(ref s) => Some(s.sample(u1, u2)),
+            &Shape::Disk(ref d) => Some(d.sample(u1, u2)),
+            Shape::Cone(c) => Some(c.sample(u1, u2)),
+            Shape::Cylinder(c) => Some(c.sample(u1, u2)),
+            Shape::Triangle(t) => Some(t.sample(u1, u2)),
+            Shape::TriangleMesh(m) => Some(m.sample(u1, u2)),
+            // Subdivision surfaces are sampled on the mesh that they refine to
+            Shape::LoopSubdiv(_) => self.clone().refine().pop()?.sample(u1, u2),
+            Shape::Curve(_) | Shape::Curves(_) | Shape::Implicit(_) => None
+        }
+    }
+
+    // The density, with respect to surface area, of sample choosing p
+    pub fn pdf(&self, p: &Point) -> f32 {
+        match self {
+            Shape::Sphere(s) => s.pdf(p),
+            Shape::Disk(d) => d.pdf(p),
+            Shape::Cone(c) => c.pdf(p),
+            Shape::Cylinder(c) => c.pdf(p),
+            Shape::Triangle(t) => t.pdf(p),
+            Shape::TriangleMesh(m) => m.pdf(p),
+            Shape::LoopSubdiv(m) => 1.0 / m.area(),
+            Shape::Curve(_) | Shape::Curves(_) | Shape::Implicit(_) => 0.0
+        }
+    }
+
+    // Samples a point on the surface of the shape that is meant to light p,
+    // returning it along with the surface normal at that point. Shapes that
+    // can't do better than sampling by area fall back to sample.
+    pub fn sample_at_point(&self, p: &Point, u1: f32, u2: f32) -> Option<(Point, Normal)> {
+        match self {
+            Shape::Sphere(s) => Some(s.sample_at_point(p, u1, u2)),
+            _ => self.sample(u1, u2)
+        }
+    }
+
+    // The density, with respect to solid angle, of choosing the direction wi
+    // from p with sample_at_point.
+    pub fn pdf_wi(&self, p: &Point, wi: &Vector) -> f32 {
+        match self {
+            Shape::Sphere(s) => s.pdf_wi(p, wi),
+            Shape::TriangleMesh(m) => m.pdf_wi(p, wi),
+            _ if !self.can_sample() => 0.0,
+            _ => helpers::area_pdf_wi(self, self.area(), p, wi)
         }
     }
 }
@@ -236,24 +430,52 @@ impl Shape {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use transform::transform::Transform;
+    use crate::transform::transform::Transform;
 
     #[test]
     fn it_can_be_created() {
         let some_shape = ShapeBase::new(Transform::new(), Transform::new(), false);
-        assert_eq!(ShapeBase::new(Transform::new(), Transform::new(), false),
-                   ShapeBase {
-                       object2world: Transform::new(),
-                       world2object: Transform::new(),
-                       reverse_orientation: false,
-                       transform_swaps_handedness: false,
-                       shape_id: some_shape.shape_id + 1
-                   });
+        let shape = ShapeBase::new(Transform::new(), Transform::new(), false);
+        assert_eq!(*shape.object2world, Transform::new());
+        assert_eq!(*shape.world2object, Transform::new());
+        assert!(!shape.reverse_orientation);
+        assert!(!shape.transform_swaps_handedness);
+        assert!(shape.shape_id > some_shape.shape_id);
     }
 
     #[test]
-    fn two_shapes_can_be_equal() {
-        assert_eq!(ShapeBase::new(Transform::new(), Transform::new(), false),
-                   ShapeBase::new(Transform::new(), Transform::new(), false));
+    fn shapes_are_equal_if_they_have_the_same_id() {
+        let shape = ShapeBase::new(Transform::new(), Transform::new(), false);
+        assert_eq!(shape.clone(), shape);
+        assert!(ShapeBase::new(Transform::new(), Transform::new(), false) != shape);
+
+        let refined = shape.refined();
+        assert!(refined != shape);
+        assert_eq!(refined.object2world, shape.object2world);
+        assert_eq!(refined.reverse_orientation, shape.reverse_orientation);
+    }
+
+    #[test]
+    fn refined_shapes_get_new_ids() {
+        let pts = [Point::new_with(0.0, 0.0, 0.0), Point::new_with(1.0, 0.0, 0.0),
+                   Point::new_with(1.0, 1.0, 0.0), Point::new_with(0.0, 1.0, 0.0)];
+        let mesh = Shape::triangle_mesh(Transform::new(), Transform::new(), false,
+                                        &[0, 1, 2, 0, 2, 3], &pts,
+                                        None, None, None, None);
+        let mesh_id = mesh.base().shape_id;
+        assert_eq!(mesh.clone().base().shape_id, mesh_id);
+
+        // Computing the area doesn't refine the mesh
+        assert!((mesh.area() - 1.0).abs() < 1e-6);
+        let tris = mesh.refine();
+        let mut ids: Vec<_> = tris.iter().map(|t| t.base().shape_id).collect();
+        ids.push(mesh_id);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+
+        // ... and the triangles keep theirs when cloned
+        assert_eq!(tris[0].clone(), tris[0]);
+        assert!(tris[0] != tris[1]);
     }
 }
@@ -1,17 +1,23 @@
-use bbox::BBox;
-use bbox::HasBounds;
-use geometry::point::Point;
-use geometry::vector::Dot;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use ray::Ray;
-use shape::ShapeBase;
-use shape::ShapeIntersection;
-use transform::transform::Transform;
-use transform::transform::ApplyTransform;
-use utils::Degrees;
-
-use shape::helpers::compute_dg;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::geometry::vector::coordinate_system;
+use crate::montecarlo::{uniform_sample_cone, uniform_cone_pdf, uniform_sample_sphere};
+use crate::ray::Ray;
+use crate::shape::ShapeBase;
+use crate::shape::ShapeIntersection;
+use crate::transform::transform::Transform;
+use crate::transform::transform::ApplyTransform;
+use crate::utils::Degrees;
+use crate::utils::gamma;
+
+use crate::shape::helpers::area_pdf_wi;
+use crate::shape::helpers::compute_dg;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Sphere {
@@ -32,14 +38,16 @@ impl Sphere {
         let zmax = z0.max(z1).clamp(-rad, rad);
         let thetamin = (zmin / rad).acos();
         let thetamax = (zmax / rad).acos();
+        let phi_max = pm.clamp(0.0, 360.0).as_radians();
+        let periodic_u = pm >= 360.0;
         Sphere {
-            base: ShapeBase::new(o2w, w2o, ro),
+            base: ShapeBase::new(o2w, w2o, ro).with_periodic_u(periodic_u),
             radius: rad,
             z_min: zmin,
             z_max: zmax,
             theta_min: thetamin,
             theta_max: thetamax,
-            phi_max: pm.clamp(0.0, 360.0).as_radians()
+            phi_max
         }
     }
 
@@ -52,7 +60,7 @@ impl Sphere {
 
         // Solve quadratic equation for t values
         let (t0, t1) = {
-            match ::utils::quadratic(a, b, c) {
+            match crate::utils::quadratic(a, b, c) {
                 None => return None,
                 Some((x, y)) => (x, y)
             }
@@ -117,6 +125,79 @@ impl Sphere {
     pub fn area(&self) -> f32 {
         self.phi_max * self.radius * (self.z_max - self.z_min)
     }
+
+    // Uniformly samples a point on the full sphere, returning it along
+    // with the surface normal there in world space.
+    // !FIXME! This ignores the z and phi limits of partial spheres
+    pub fn sample(&self, u1: f32, u2: f32) -> (Point, Normal) {
+        let p = Point::new() + self.radius * uniform_sample_sphere(u1, u2);
+        let o2w = &self.base().object2world;
+        let ns = o2w.xf(Normal::new_with(p.x, p.y, p.z)).normalize();
+        let ns = if self.base().reverse_orientation { -ns } else { ns };
+        (o2w.xf(p), ns)
+    }
+
+    // The density of sample choosing p with respect to surface area
+    pub fn pdf(&self, _p: &Point) -> f32 { 1.0 / self.area() }
+
+    // Returns the center of the sphere in world space, and the cosine of the
+    // half angle of the cone of directions that it subtends as seen from p,
+    // unless p is inside of it
+    fn subtended_cone(&self, p: &Point) -> Option<(Point, f32)> {
+        let p_center = self.base().object2world.xf(Point::new());
+        let dist2 = p.distance_squared(&p_center);
+        if dist2 - self.radius * self.radius < 1e-4 {
+            return None;
+        }
+
+        let sin_theta_max2 = self.radius * self.radius / dist2;
+        Some((p_center, (1.0 - sin_theta_max2).max(0.0).sqrt()))
+    }
+
+    // Samples a point on the sphere that is visible from p by uniformly
+    // sampling the cone of directions that the sphere subtends, returning it
+    // along with the surface normal there in world space. Points inside of
+    // the sphere see all of it, so they sample it by area.
+    pub fn sample_at_point(&self, p: &Point, u1: f32, u2: f32) -> (Point, Normal) {
+        let (p_center, cos_theta_max) = match self.subtended_cone(p) {
+            None => return self.sample(u1, u2),
+            Some(cone) => cone
+        };
+
+        // Compute coordinate system for sphere sampling
+        let wc = (&p_center - p).normalize();
+        let (wc_x, wc_y) = coordinate_system(&wc);
+
+        // Sample sphere uniformly inside subtended cone
+        let v = uniform_sample_cone(u1, u2, cos_theta_max);
+        let dir = v.x * wc_x + v.y * wc_y + v.z * &wc;
+        let r = Ray::new_with(p.clone(), dir.clone(), 1e-3);
+        let ps = match self.intersect(&r) {
+            Some(si) => si.dg.p,
+            // The sample grazed the silhouette, so take the closest point
+            // along the ray to the center
+            None => r.point_at((&p_center - p).dot(&dir))
+        };
+
+        let ns = Normal::from((&ps - &p_center).normalize());
+        let ns = if self.base().reverse_orientation { -ns } else { ns };
+        (ps, ns)
+    }
+
+    // The density, with respect to solid angle, of choosing the direction wi
+    // from p with sample_at_point
+    pub fn pdf_wi(&self, p: &Point, wi: &Vector) -> f32 {
+        match self.subtended_cone(p) {
+            None => area_pdf_wi(self, self.area(), p, wi),
+            Some((p_center, cos_theta_max)) => {
+                if wi.clone().normalize().dot(&(&p_center - p).normalize()) < cos_theta_max {
+                    return 0.0;
+                }
+
+                uniform_cone_pdf(cos_theta_max)
+            }
+        }
+    }
 }
 
 impl HasBounds for Sphere {
@@ -141,7 +222,15 @@ impl Intersectable<ShapeIntersection> for Sphere {
             if hit.is_some() { hit.unwrap() } else { return None; }
         };
 
+        // Refine the hit point by projecting it back onto the sphere, which
+        // leaves only the error of the projection
         let p_hit = ray.point_at(t_hit);
+        let mut p_hit = &p_hit * (self.radius / p_hit.distance(&Point::new()));
+        if p_hit.x == 0.0 && p_hit.y == 0.0 {
+            p_hit.x = 1e-5 * self.radius;
+        }
+        let p_error = self.base().object2world.xf_point_error(
+            &p_hit, &(gamma(5) * Vector::from(p_hit.clone()).abs()));
 
         // Find parametric representation of sphere hit
         let u = phi / self.phi_max;
@@ -171,29 +260,32 @@ impl Intersectable<ShapeIntersection> for Sphere {
 
         let dg = compute_dg(self.base(), u, v, p_hit,
                             dpdu, dpdv, d2pduu, d2pduv, d2pdvv);
-        Some(ShapeIntersection::new(t_hit, t_hit * 5e-4, dg))
+        Some(ShapeIntersection::new(t_hit, p_error, dg))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use geometry::normal::Normal;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
-    use intersection::Intersectable;
-    use ray::Ray;
-    use shape::ShapeBase;
-    use transform::transform::Transform;
+    use crate::geometry::normal::Normal;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::intersection::Intersectable;
+    use crate::ray::Ray;
+    use crate::ray::offset_ray_origin;
+    use crate::shape::ShapeBase;
+    use crate::transform::transform::Transform;
 
     use std::f32::consts::PI;
 
     #[test]
     fn it_can_be_created() {
-        assert_eq!(Sphere::new(Transform::new(), Transform::new(),
-                               false, 1.0, -1.0, 1.0, 360.0),
+        // Shapes are only equal to themselves, so borrow the new sphere's id
+        let sphere = Sphere::new(Transform::new(), Transform::new(),
+                                 false, 1.0, -1.0, 1.0, 360.0);
+        assert_eq!(sphere,
                    Sphere {
-                       base: ShapeBase::new(Transform::new(), Transform::new(), false),
+                       base: sphere.base.clone(),
                        radius: 1.0,
                        z_min: -1.0,
                        z_max: 1.0,
@@ -274,7 +366,7 @@ mod tests {
         let shape_int = s.intersect(&r).unwrap();
 
         assert_eq!(shape_int.t_hit, 0.5);
-        assert_eq!(shape_int.ray_epsilon, 0.5 * 5e-4);
+        assert!(shape_int.p_error.length() < 1e-6);
         assert_eq!(shape_int.dg.p, Point::new_with(0.0, -0.5, 0.0));
         assert_eq!(shape_int.dg.shape.as_ref().unwrap(), s.base());
 
@@ -331,4 +423,77 @@ mod tests {
             xf2.clone(), xf2.inverse(), false,
             1.0, -1.0, 1.0, 360.0).area(), 4.0 * PI);
     }
+
+    #[test]
+    fn it_can_be_sampled() {
+        let xf = Transform::translate(&Vector::new_with(1.0, 2.0, 3.0));
+        let sphere = Sphere::new(xf.clone(), xf.inverse(), false, 2.0, -2.0, 2.0, 360.0);
+        let center = Point::new_with(1.0, 2.0, 3.0);
+        for (u1, u2) in [(0.0, 0.0), (0.25, 0.5), (0.5, 0.75), (0.9, 0.1)] {
+            let (p, n) = sphere.sample(u1, u2);
+            assert!((p.distance(&center) - 2.0).abs() < 1e-4);
+            assert!((Vector::from(n.clone()) - (&p - &center) / 2.0).length() < 1e-4);
+        }
+
+        // Reversed spheres have inward facing normals
+        let sphere = Sphere::new(xf.clone(), xf.inverse(), true, 2.0, -2.0, 2.0, 360.0);
+        let (p, n) = sphere.sample(0.25, 0.5);
+        assert!(n.dot(&(&p - &center)) < 0.0);
+    }
+
+    #[test]
+    fn rays_leaving_it_dont_hit_it_again() {
+        // Far from the origin, where points on the sphere are imprecise
+        let xf = Transform::translate(&Vector::new_with(1000.0, -2000.0, 3000.0));
+        let sphere = Sphere::new(xf.clone(), xf.inverse(), false, 1.0, -1.0, 1.0, 360.0);
+        let center = Point::new_with(1000.0, -2000.0, 3000.0);
+        let o = &center + Vector::new_with(3.0, 4.0, 5.0);
+        for i in 0..64 {
+            let (u1, u2) = ((i % 8) as f32 / 8.0 + 0.0625, (i / 8) as f32 / 8.0 + 0.0625);
+            let target = &center + 0.5 * uniform_sample_sphere(u1, u2);
+            let r = Ray::new_with(o.clone(), (&target - &o).normalize(), 0.0);
+            let si = sphere.intersect(&r).unwrap();
+
+            // Rays that just barely leave the sphere miss it...
+            let n = si.dg.nn.clone();
+            let w = si.dg.dpdu.clone().normalize() + 1e-3 * Vector::from(n.clone());
+            let po = offset_ray_origin(&si.dg.p, &si.p_error, &n, &w);
+            assert!(!sphere.intersect_p(&Ray::new_with(po, w, 0.0)));
+
+            // ... and rays that go into it hit its far side
+            let po = offset_ray_origin(&si.dg.p, &si.p_error, &n, &r.d);
+            let exit = sphere.intersect(&Ray::new_with(po, r.d.clone(), 0.0)).unwrap();
+            assert!(exit.t_hit > 1e-2);
+        }
+    }
+
+    #[test]
+    fn it_can_be_sampled_from_a_point() {
+        let xf = Transform::translate(&Vector::new_with(1.0, 2.0, 3.0));
+        let sphere = Sphere::new(xf.clone(), xf.inverse(), false, 2.0, -2.0, 2.0, 360.0);
+        let center = Point::new_with(1.0, 2.0, 3.0);
+        let p = Point::new_with(1.0, 2.0, -1.0);
+
+        // The sphere subtends a cone with a half angle of 30 degrees
+        let expected_pdf = 1.0 / (2.0 * PI * (1.0 - 0.75f32.sqrt()));
+        for (u1, u2) in [(0.0, 0.0), (0.25, 0.5), (0.5, 0.75), (0.99, 0.1)] {
+            let (ps, n) = sphere.sample_at_point(&p, u1, u2);
+            assert!((ps.distance(&center) - 2.0).abs() < 1e-3);
+            assert!((Vector::from(n.clone()) - (&ps - &center) / 2.0).length() < 1e-3);
+
+            // Only the near side of the sphere is sampled
+            let wi = (&ps - &p).normalize();
+            assert!(n.dot(&wi) <= 1e-3);
+            assert!((sphere.pdf_wi(&p, &wi) - expected_pdf).abs() < 1e-3 * expected_pdf);
+        }
+
+        // Directions outside of the cone can't be sampled
+        assert_eq!(sphere.pdf_wi(&p, &Vector::new_with(0.0, 1.0, 0.0)), 0.0);
+
+        // Points inside of the sphere sample all of it by area
+        let (ps, _) = sphere.sample_at_point(&center, 0.25, 0.5);
+        assert_eq!(ps, sphere.sample(0.25, 0.5).0);
+        let pdf = sphere.pdf_wi(&center, &Vector::new_with(0.6, 0.0, 0.8));
+        assert!((pdf - 4.0 / sphere.area()).abs() < 1e-4);
+    }
 }
@@ -1,10 +1,12 @@
+use std::fs;
+use std::path::Path;
 use std::ops::Add;
 use std::ops::Sub;
 use std::ops::Div;
 use std::ops::Mul;
 use std::ops::Neg;
 
-use utils::Lerp;
+use crate::utils::Lerp;
 
 const SAMPLED_LAMBDA_START: usize = 400;
 const SAMPLED_LAMBDA_END: usize = 700;
@@ -412,6 +414,29 @@ impl Spectrum {
         Spectrum::sampled(cs)
     }
 
+    // Reads a spectral power distribution given as pairs of wavelengths
+    // in nanometers and values, separated by whitespace. Anything after a
+    // '#' on a line is a comment.
+    pub fn read_spd<P: AsRef<Path>>(filename: &P) -> Result<Spectrum, String> {
+        let contents = fs::read_to_string(filename).map_err(|e| e.to_string())?;
+        Spectrum::parse_spd(&contents)
+    }
+
+    pub fn parse_spd(contents: &str) -> Result<Spectrum, String> {
+        let values = contents.lines()
+            .flat_map(|l| l.split('#').next().unwrap_or("").split_whitespace())
+            .map(|tok| tok.parse::<f32>()
+                 .map_err(|_| format!("Invalid number in SPD data: {}", tok)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if values.is_empty() || values.len() % 2 != 0 {
+            return Err(String::from("SPD data should be pairs of wavelengths and values"));
+        }
+
+        let samples: Vec<(f32, f32)> = values.chunks(2).map(|c| (c[0], c[1])).collect();
+        Ok(Spectrum::from_samples(&samples).into_rgb_spectrum())
+    }
+
     pub fn from_rgb(rgb: [f32; 3]) -> Spectrum { Spectrum::rgb(rgb) }
 
     pub fn from_xyz(xyz: [f32; 3]) -> Spectrum { Spectrum::rgb(xyz_to_rgb(xyz)) }
@@ -420,10 +445,18 @@ impl Spectrum {
         self.coeffs().iter().fold(false, |r, x| r || x.is_nan())
     }
 
+    pub fn has_infs(&self) -> bool {
+        self.coeffs().iter().any(|x| x.is_infinite())
+    }
+
     pub fn is_black(&self) -> bool {
         self.coeffs().iter().fold(true, |r, x| r && *x == 0.0)
     }
 
+    pub fn max_component_value(&self) -> f32 {
+        self.coeffs().iter().fold(f32::MIN, |m, &x| m.max(x))
+    }
+
     pub fn sqrt(self) -> Spectrum {
         self.transform(|x| x.sqrt())
     }
@@ -490,6 +523,30 @@ impl Spectrum {
     pub fn clamp(self, a: f32, b: f32) -> Spectrum {
         self.transform(|x| x.clamp(a, b))
     }
+
+    // Picks a wavelength in nanometers uniformly over the sampled range
+    pub fn sample_wavelength(u: f32) -> f32 {
+        (SAMPLED_LAMBDA_START as f32).lerp(&(SAMPLED_LAMBDA_END as f32), u)
+    }
+
+    // The color of light at a single wavelength, which lights the sampled
+    // spectrum's bin that it falls in. It's scaled so that averaging it over
+    // wavelengths from sample_wavelength gives white, which is how paths that
+    // follow one wavelength, e.g. through dispersive glass, are weighted.
+    pub fn from_wavelength(lambda: f32) -> Spectrum {
+        let range = (SAMPLED_LAMBDA_END - SAMPLED_LAMBDA_START) as f32;
+        let t = (lambda - SAMPLED_LAMBDA_START as f32) / range;
+        if !(0.0..=1.0).contains(&t) {
+            return Spectrum::from(0.0);
+        }
+
+        let bin = ((t * NUM_SPECTRUM_SAMPLES as f32) as usize).min(NUM_SPECTRUM_SAMPLES - 1);
+        let mut cs = [0.0; NUM_SPECTRUM_SAMPLES];
+        cs[bin] = NUM_SPECTRUM_SAMPLES as f32;
+
+        let white = Spectrum::sampled([1.0; NUM_SPECTRUM_SAMPLES]).into_rgb_spectrum();
+        Spectrum::sampled(cs).into_rgb_spectrum() / white
+    }
 }
 
 impl ::std::convert::From<f32> for Spectrum {
@@ -673,7 +730,7 @@ impl ::std::default::Default for Spectrum {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use utils::Lerp;
+    use crate::utils::Lerp;
 
     #[test]
     fn it_can_be_created() {
@@ -735,6 +792,12 @@ mod tests {
                    Spectrum::from(10.0 * 6.0));
     }
 
+    #[test]
+    fn it_has_a_largest_component() {
+        assert_eq!(Spectrum::from_rgb([0.1, 0.7, 0.3]).max_component_value(), 0.7);
+        assert_eq!(Spectrum::from(-2.0).max_component_value(), -2.0);
+    }
+
     #[test]
     fn it_can_be_divided_by_scalars() {
         let s1 = [(400.0, 3.0), (500.0, 3.0), (600.0, 3.0), (700.0, 3.0)];
@@ -817,5 +880,37 @@ mod tests {
         assert_eq!(s3.lerp(&s4, 0.0).coeffs(), [10.0; 3]);
         assert_eq!(s3.lerp(&s4, 1.0).coeffs(), [6.0; 3]);
     }
-}
 
+    #[test]
+    fn it_can_parse_spds() {
+        let flat = Spectrum::parse_spd("# A flat spectrum\n350 0.5\n550 0.5 # middle\n800 0.5")
+            .unwrap();
+        let expected = Spectrum::from_samples(&[(350.0, 0.5), (800.0, 0.5)]).into_rgb_spectrum();
+        assert_eq!(flat, expected);
+
+        // Reddish spectra stay reddish
+        let red = Spectrum::parse_spd("400 0.1 500 0.1 600 0.9 700 0.9").unwrap().to_rgb();
+        assert!(red[0] > 2.0 * red[2]);
+
+        assert!(Spectrum::parse_spd("400 0.1 500").is_err());
+        assert!(Spectrum::parse_spd("400 abc").is_err());
+        assert!(Spectrum::parse_spd("").is_err());
+    }
+
+    #[test]
+    fn single_wavelengths_average_to_white() {
+        let n = 300;
+        let sum = (0..n).map(|i| {
+            Spectrum::from_wavelength(Spectrum::sample_wavelength((i as f32 + 0.5) / n as f32))
+        }).fold(Spectrum::from(0.0), |acc, s| acc + s) / (n as f32);
+        for c in sum.to_rgb().iter() {
+            assert!((c - 1.0).abs() < 1e-3);
+        }
+
+        // Short wavelengths are blue and long ones red
+        let blue = Spectrum::from_wavelength(450.0).to_rgb();
+        let red = Spectrum::from_wavelength(650.0).to_rgb();
+        assert!(blue[2] > blue[0] && red[0] > red[2]);
+        assert!(Spectrum::from_wavelength(800.0).is_black());
+    }
+}
@@ -1,10 +1,10 @@
 use std::fmt::Debug;
 use std::sync::Arc;
 
-use diff_geom::DifferentialGeometry;
-use texture::internal::TextureBase;
-use texture::mapping2d::TextureMapping2D;
-use utils::Lerp;
+use crate::diff_geom::DifferentialGeometry;
+use crate::texture::internal::TextureBase;
+use crate::texture::mapping2d::TextureMapping2D;
+use crate::utils::Lerp;
 
 #[derive(Clone, Debug)]
 pub struct BilerpTexture<T> where T: Lerp<f32> {
@@ -37,10 +37,10 @@ impl<T> super::internal::TextureBase<T> for BilerpTexture<T> where T: Lerp<f32>
 #[cfg(test)]
 mod tests {
     use super::*;
-    use texture::ConstantTexture;
-    use texture::Texture;
-    use texture::mapping2d::UVMapping2D;
-    use geometry::vector::Vector;
+    use crate::texture::ConstantTexture;
+    use crate::texture::Texture;
+    use crate::texture::mapping2d::UVMapping2D;
+    use crate::geometry::vector::Vector;
 
     #[test]
     fn bilerp_texture_works() {
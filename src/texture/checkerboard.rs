@@ -1,11 +1,11 @@
 use std::sync::Arc;
 
-use diff_geom::DifferentialGeometry;
-use texture::TextureReference;
-use texture::internal::TextureBase;
-use texture::mapping2d::TextureMapping2D;
-use texture::mapping3d::TextureMapping3D;
-use utils::Lerp;
+use crate::diff_geom::DifferentialGeometry;
+use crate::texture::TextureReference;
+use crate::texture::internal::TextureBase;
+use crate::texture::mapping2d::TextureMapping2D;
+use crate::texture::mapping3d::TextureMapping3D;
+use crate::utils::Lerp;
 
 #[derive(Debug, PartialEq, PartialOrd, Eq, Ord, Clone, Copy)]
 enum CheckerboardAA {
@@ -57,24 +57,25 @@ impl<T> TextureBase<T> for CheckerboardTexture<T> where T: Lerp<f32> {
                 if s0.floor() == s1.floor() && t0.floor() == t1.floor() {
                     self.point_sample(dg, s, t)
                 } else {
-                    // Apply box filter to checkerboard region
+                    // Apply box filter to checkerboard region. The
+                    // filtered value along each axis is how much of the
+                    // footprint covers odd checks, which for a footprint
+                    // without any width is whether it's in one.
                     let bump_int = |x: f32| {
                         let half_x = x / 2.0;
                         half_x.floor() +
                             2.0 * (half_x - half_x.floor() - 0.5).max(0.0)
                     };
-
-                    let sint = if ds > 0.0 {
-                        (bump_int(s1) - bump_int(s0)) / (2.0 * ds)
-                    } else {
-                        0.0
+                    let filter = |x: f32, x0: f32, x1: f32, dx: f32| {
+                        if dx > 0.0 {
+                            (bump_int(x1) - bump_int(x0)) / (2.0 * dx)
+                        } else {
+                            (x.floor() as i32).rem_euclid(2) as f32
+                        }
                     };
 
-                    let tint = if dt > 0.0 {
-                        (bump_int(t1) - bump_int(t0)) / (2.0 * dt)
-                    } else {
-                        0.0
-                    };
+                    let sint = filter(s, s0, s1, ds);
+                    let tint = filter(t, t0, t1, dt);
 
                     let area_sq =
                         if ds > 1.0 || dt > 1.0 {
@@ -120,12 +121,12 @@ impl<T> TextureBase<T> for Checkerboard3DTexture<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use texture::Texture;
-    use texture::ConstantTexture;
-    use texture::mapping2d::PlanarMapping2D;
-    use texture::mapping3d::IdentityMapping3D;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
+    use crate::texture::Texture;
+    use crate::texture::ConstantTexture;
+    use crate::texture::mapping2d::PlanarMapping2D;
+    use crate::texture::mapping3d::IdentityMapping3D;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
 
     #[test]
     fn checkerboard_texture_works() {
@@ -187,4 +188,37 @@ mod tests {
         let expected: f32 = 2.0 * (0.25 * 0.75);
         assert!((checker.evaluate(&dg) - expected).abs() < 0.001);
     }
+
+    #[test]
+    fn closed_form_filtering_averages_the_checks_in_the_footprint() {
+        let checker = CheckerboardTexture::new_antialiased(
+            Box::new(PlanarMapping2D::new()), Arc::new(ConstantTexture::new(0.0f32)),
+            Arc::new(ConstantTexture::new(1.0f32)));
+
+        // Footprints that are only wide along one axis, including ones in
+        // odd rows, and ones that are wide along both
+        let footprints = [(1.1, 0.5, 0.2, 0.0), (1.1, 1.5, 0.2, 0.0), (-0.7, 2.9, 0.0, 0.3),
+                          (0.3, -1.2, 0.4, 0.35), (2.45, 3.6, 0.6, 0.1)];
+        for &(s, t, ds, dt) in footprints.iter() {
+            let mut dg = DifferentialGeometry::new();
+            dg.p = Point::new_with(s, t, 0.0);
+            dg.dpdx = Vector::new_with(ds, 0.0, 0.0);
+            dg.dpdy = Vector::new_with(0.0, dt, 0.0);
+
+            // Average point samples of the checks over the footprint
+            const N: usize = 200;
+            let sample = |x: f32, dx: f32, i: usize| {
+                x + dx * (2.0 * (i as f32 + 0.5) / (N as f32) - 1.0)
+            };
+            let odd = (0..(N * N)).filter(|i| {
+                let (ps, pt) = (sample(s, ds, i % N), sample(t, dt, i / N));
+                ((ps.floor() + pt.floor()) as i32).rem_euclid(2) == 1
+            }).count();
+            let expected = (odd as f32) / ((N * N) as f32);
+
+            let filtered = checker.evaluate(&dg);
+            assert!((filtered - expected).abs() < 0.01,
+                    "({}, {}): {} vs {}", s, t, filtered, expected);
+        }
+    }
 }
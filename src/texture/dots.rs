@@ -1,10 +1,10 @@
 use std::sync::Arc;
 
-use diff_geom::DifferentialGeometry;
-use texture::internal::TextureBase;
-use texture::mapping2d::TextureMapping2D;
-use texture::noise::noise;
-use texture::TextureReference;
+use crate::diff_geom::DifferentialGeometry;
+use crate::texture::internal::TextureBase;
+use crate::texture::mapping2d::TextureMapping2D;
+use crate::texture::noise::noise;
+use crate::texture::TextureReference;
 
 #[derive(Debug)]
 pub struct DotsTexture<T> {
@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::point::Point;
+use crate::spectrum::Spectrum;
+use crate::texture::internal::TextureBase;
+use crate::texture::noise::fbm;
+use crate::texture::noise::noise_at;
+use crate::texture::ColorTextureReference;
+use crate::texture::ScalarTextureReference;
+
+// Default number of octaves for fbm(p)
+const FBM_OCTAVES: i32 = 8;
+
+// Expressions evaluate to either a single value or a triple, which holds
+// points and RGB colors. Operations that mix the two apply the single
+// value to each element of the triple.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Float(f32),
+    Triple([f32; 3])
+}
+
+impl Value {
+    fn map<F>(self, f: F) -> Value where F: Fn(f32) -> f32 {
+        match self {
+            Value::Float(x) => Value::Float(f(x)),
+            Value::Triple(t) => Value::Triple([f(t[0]), f(t[1]), f(t[2])])
+        }
+    }
+
+    fn zip<F>(self, b: Value, f: F) -> Value where F: Fn(f32, f32) -> f32 {
+        match (self, b) {
+            (Value::Float(x), Value::Float(y)) => Value::Float(f(x, y)),
+            (Value::Float(x), Value::Triple(t)) =>
+                Value::Triple([f(x, t[0]), f(x, t[1]), f(x, t[2])]),
+            (Value::Triple(t), Value::Float(y)) =>
+                Value::Triple([f(t[0], y), f(t[1], y), f(t[2], y)]),
+            (Value::Triple(s), Value::Triple(t)) =>
+                Value::Triple([f(s[0], t[0]), f(s[1], t[1]), f(s[2], t[2])])
+        }
+    }
+
+    fn triple(self) -> [f32; 3] {
+        match self {
+            Value::Float(x) => [x, x, x],
+            Value::Triple(t) => t
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Float,
+    Triple
+}
+
+fn widest(a: Kind, b: Kind) -> Kind {
+    if a == Kind::Triple || b == Kind::Triple { Kind::Triple } else { Kind::Float }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Checker,
+    Noise,
+    FBm,
+    Sin,
+    Cos,
+    Abs,
+    Floor,
+    Sqrt,
+    Min,
+    Max,
+    Pow,
+    Mix,
+    Clamp,
+    Rgb
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "checker" => Some(Function::Checker),
+            "noise" => Some(Function::Noise),
+            "fbm" => Some(Function::FBm),
+            "sin" => Some(Function::Sin),
+            "cos" => Some(Function::Cos),
+            "abs" => Some(Function::Abs),
+            "floor" => Some(Function::Floor),
+            "sqrt" => Some(Function::Sqrt),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "pow" => Some(Function::Pow),
+            "mix" => Some(Function::Mix),
+            "clamp" => Some(Function::Clamp),
+            "rgb" => Some(Function::Rgb),
+            _ => None
+        }
+    }
+
+    // Checks the arguments and returns the kind of the result
+    fn check_args(&self, name: &str, args: &[Kind]) -> Result<Kind, String> {
+        let arity = |n: usize| if args.len() == n { Ok(()) } else {
+            Err(format!("{}() takes {} arguments, got {}", name, n, args.len()))
+        };
+
+        let all_floats = || if args.iter().all(|&k| k == Kind::Float) { Ok(()) } else {
+            Err(format!("{}() takes scalar arguments", name))
+        };
+
+        match *self {
+            Function::Checker => { arity(2)?; all_floats()?; Ok(Kind::Float) },
+            Function::Noise => {
+                arity(1)?;
+                if args[0] != Kind::Triple {
+                    return Err(format!("{}() takes a point", name));
+                }
+                Ok(Kind::Float)
+            },
+            Function::FBm => {
+                if args.is_empty() || args.len() > 2 {
+                    return Err(format!("{}() takes a point and optionally a number of octaves",
+                                       name));
+                }
+                if args[0] != Kind::Triple || args.get(1).is_some_and(|&k| k != Kind::Float) {
+                    return Err(format!("{}() takes a point and a scalar", name));
+                }
+                Ok(Kind::Float)
+            },
+            Function::Sin | Function::Cos | Function::Abs | Function::Floor | Function::Sqrt => {
+                arity(1)?;
+                Ok(args[0])
+            },
+            Function::Min | Function::Max | Function::Pow => {
+                arity(2)?;
+                Ok(widest(args[0], args[1]))
+            },
+            Function::Mix | Function::Clamp => {
+                arity(3)?;
+                Ok(widest(widest(args[0], args[1]), args[2]))
+            },
+            Function::Rgb => { arity(3)?; all_floats()?; Ok(Kind::Triple) }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Constant(Value),
+    U,
+    V,
+    P,
+    FloatTexture(ScalarTextureReference),
+    ColorTexture(ColorTextureReference),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>)
+}
+
+impl Expr {
+    fn eval(&self, dg: &DifferentialGeometry) -> Value {
+        match *self {
+            Expr::Constant(v) => v,
+            Expr::U => Value::Float(dg.u),
+            Expr::V => Value::Float(dg.v),
+            Expr::P => Value::Triple([dg.p.x, dg.p.y, dg.p.z]),
+            Expr::FloatTexture(ref tex) => Value::Float(tex.evaluate(dg)),
+            Expr::ColorTexture(ref tex) => Value::Triple(tex.evaluate(dg).to_rgb()),
+            Expr::Neg(ref e) => e.eval(dg).map(|x| -x),
+            Expr::Binary(op, ref a, ref b) => {
+                let (a, b) = (a.eval(dg), b.eval(dg));
+                match op {
+                    BinaryOp::Add => a.zip(b, |x, y| x + y),
+                    BinaryOp::Sub => a.zip(b, |x, y| x - y),
+                    BinaryOp::Mul => a.zip(b, |x, y| x * y),
+                    BinaryOp::Div => a.zip(b, |x, y| if y == 0.0 { 0.0 } else { x / y })
+                }
+            },
+            Expr::Call(f, ref args) => {
+                let args: Vec<Value> = args.iter().map(|a| a.eval(dg)).collect();
+                let scalar = |i: usize| match args[i] {
+                    Value::Float(x) => x,
+                    Value::Triple(_) => unreachable!()
+                };
+                let point = |i: usize| {
+                    let t = args[i].triple();
+                    Point::new_with(t[0], t[1], t[2])
+                };
+
+                match f {
+                    Function::Checker => {
+                        let sum = scalar(0).floor() + scalar(1).floor();
+                        Value::Float(if sum.rem_euclid(2.0) == 0.0 { 1.0 } else { 0.0 })
+                    },
+                    Function::Noise => Value::Float(noise_at(&point(0))),
+                    Function::FBm => {
+                        let octaves = if args.len() > 1 { scalar(1) as i32 } else { FBM_OCTAVES };
+                        Value::Float(fbm(&point(0), &dg.dpdx, &dg.dpdy, 0.5, octaves))
+                    },
+                    Function::Sin => args[0].map(f32::sin),
+                    Function::Cos => args[0].map(f32::cos),
+                    Function::Abs => args[0].map(f32::abs),
+                    Function::Floor => args[0].map(f32::floor),
+                    Function::Sqrt => args[0].map(|x| x.max(0.0).sqrt()),
+                    Function::Min => args[0].zip(args[1], f32::min),
+                    Function::Max => args[0].zip(args[1], f32::max),
+                    Function::Pow => args[0].zip(args[1], f32::powf),
+                    Function::Mix => {
+                        let t = args[2];
+                        args[0].zip(t, |a, t| (1.0 - t) * a)
+                            .zip(args[1].zip(t, |b, t| t * b), |a, b| a + b)
+                    },
+                    Function::Clamp =>
+                        args[0].zip(args[1], f32::max).zip(args[2], f32::min),
+                    Function::Rgb => Value::Triple([scalar(0), scalar(1), scalar(2)])
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f32),
+    Ident(String),
+    Op(char)
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars: Peekable<Chars> = src.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut num = String::new();
+            while let Some(&c) = chars.peek() {
+                let is_exponent_sign = (c == '-' || c == '+') &&
+                    num.ends_with(['e', 'E']);
+                if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || is_exponent_sign {
+                    num.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let x = num.parse::<f32>().map_err(|_| format!("Invalid number: {}", num))?;
+            tokens.push(Token::Number(x));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' {
+                    ident.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Ident(ident));
+        } else if "+-*/(),".contains(c) {
+            tokens.push(Token::Op(c));
+            chars.next();
+        } else {
+            return Err(format!("Unexpected character '{}'", c));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    float_textures: &'a HashMap<String, ScalarTextureReference>,
+    spectrum_textures: &'a HashMap<String, ColorTextureReference>
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> { self.tokens.get(self.pos) }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn expect(&mut self, op: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Op(c)) if c == op => Ok(()),
+            Some(t) => Err(format!("Expected '{}', found {:?}", op, t)),
+            None => Err(format!("Expected '{}' at end of expression", op))
+        }
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<(Expr, Kind), String> {
+        let (mut lhs, mut kind) = self.term()?;
+        while let Some(&Token::Op(c)) = self.peek() {
+            let op = match c { '+' => BinaryOp::Add, '-' => BinaryOp::Sub, _ => break };
+            self.next();
+            let (rhs, rhs_kind) = self.term()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+            kind = widest(kind, rhs_kind);
+        }
+        Ok((lhs, kind))
+    }
+
+    // term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<(Expr, Kind), String> {
+        let (mut lhs, mut kind) = self.unary()?;
+        while let Some(&Token::Op(c)) = self.peek() {
+            let op = match c { '*' => BinaryOp::Mul, '/' => BinaryOp::Div, _ => break };
+            self.next();
+            let (rhs, rhs_kind) = self.unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+            kind = widest(kind, rhs_kind);
+        }
+        Ok((lhs, kind))
+    }
+
+    // unary := '-' unary | primary
+    fn unary(&mut self) -> Result<(Expr, Kind), String> {
+        if self.peek() == Some(&Token::Op('-')) {
+            self.next();
+            let (e, kind) = self.unary()?;
+            return Ok((Expr::Neg(Box::new(e)), kind));
+        }
+        self.primary()
+    }
+
+    // primary := number | name | name '(' args ')' | '(' expr ')'
+    fn primary(&mut self) -> Result<(Expr, Kind), String> {
+        match self.next() {
+            Some(Token::Number(x)) => Ok((Expr::Constant(Value::Float(x)), Kind::Float)),
+            Some(Token::Op('(')) => {
+                let e = self.expr()?;
+                self.expect(')')?;
+                Ok(e)
+            },
+            Some(Token::Ident(name)) => {
+                if self.peek() == Some(&Token::Op('(')) {
+                    self.next();
+                    return self.call(&name);
+                }
+                self.variable(&name)
+            },
+            Some(t) => Err(format!("Unexpected {:?}", t)),
+            None => Err(String::from("Unexpected end of expression"))
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<(Expr, Kind), String> {
+        let f = Function::from_name(name)
+            .ok_or_else(|| format!("Unknown function {}()", name))?;
+        let mut args = Vec::new();
+        let mut kinds = Vec::new();
+        if self.peek() != Some(&Token::Op(')')) {
+            loop {
+                let (arg, kind) = self.expr()?;
+                args.push(arg);
+                kinds.push(kind);
+                if self.peek() != Some(&Token::Op(',')) { break; }
+                self.next();
+            }
+        }
+        self.expect(')')?;
+        let kind = f.check_args(name, &kinds)?;
+        Ok((Expr::Call(f, args), kind))
+    }
+
+    // Names are the surface coordinates u and v, the point p, or the name
+    // of a texture, with float textures taking precedence
+    fn variable(&mut self, name: &str) -> Result<(Expr, Kind), String> {
+        match name {
+            "u" => Ok((Expr::U, Kind::Float)),
+            "v" => Ok((Expr::V, Kind::Float)),
+            "p" => Ok((Expr::P, Kind::Triple)),
+            _ => {
+                if let Some(tex) = self.float_textures.get(name) {
+                    Ok((Expr::FloatTexture(tex.clone()), Kind::Float))
+                } else if let Some(tex) = self.spectrum_textures.get(name) {
+                    Ok((Expr::ColorTexture(tex.clone()), Kind::Triple))
+                } else {
+                    Err(format!("Unknown name {}", name))
+                }
+            }
+        }
+    }
+}
+
+// A texture given by an arithmetic expression over the surface coordinates,
+// previously defined textures and a handful of built-in functions, e.g.
+// "0.5*checker(8*u, 8*v) + noise(p)*kd". Expressions support + - * /,
+// parentheses and the functions checker, noise, fbm, sin, cos, abs, floor,
+// sqrt, min, max, pow, mix, clamp and rgb. The point p is in world space.
+#[derive(Clone, Debug)]
+pub struct ExprTexture {
+    source: String,
+    expr: Expr
+}
+
+impl ExprTexture {
+    pub fn parse(source: &str,
+                 float_textures: &HashMap<String, ScalarTextureReference>,
+                 spectrum_textures: &HashMap<String, ColorTextureReference>)
+                 -> Result<ExprTexture, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            float_textures,
+            spectrum_textures
+        };
+
+        let (expr, _) = parser.expr()?;
+        if let Some(t) = parser.peek() {
+            return Err(format!("Unexpected {:?} after expression", t));
+        }
+
+        Ok(ExprTexture { source: String::from(source), expr })
+    }
+
+    pub fn source(&self) -> &str { &self.source }
+}
+
+// Colors are converted to floats by their luminance
+impl TextureBase<f32> for ExprTexture {
+    fn eval(&self, dg: &DifferentialGeometry) -> f32 {
+        match self.expr.eval(dg) {
+            Value::Float(x) => x,
+            Value::Triple(t) => Spectrum::from_rgb(t).y()
+        }
+    }
+}
+
+impl TextureBase<Spectrum> for ExprTexture {
+    fn eval(&self, dg: &DifferentialGeometry) -> Spectrum {
+        match self.expr.eval(dg) {
+            Value::Float(x) => Spectrum::from(x),
+            Value::Triple(t) => Spectrum::from_rgb(t)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::ConstantTexture;
+    use crate::texture::Texture;
+
+    use std::sync::Arc;
+
+    fn parse(src: &str) -> Result<ExprTexture, String> {
+        let mut fts: HashMap<String, ScalarTextureReference> = HashMap::new();
+        fts.insert(String::from("kd"), Arc::new(ConstantTexture::new(0.25f32)));
+        let mut sts: HashMap<String, ColorTextureReference> = HashMap::new();
+        sts.insert(String::from("base"),
+                   Arc::new(ConstantTexture::new(Spectrum::from_rgb([1.0, 0.5, 0.0]))));
+        ExprTexture::parse(src, &fts, &sts)
+    }
+
+    fn eval_at(src: &str, u: f32, v: f32) -> f32 {
+        let mut dg = DifferentialGeometry::new();
+        dg.u = u;
+        dg.v = v;
+        let tex: &dyn Texture<f32> = &parse(src).unwrap();
+        tex.evaluate(&dg)
+    }
+
+    #[test]
+    fn it_evaluates_arithmetic() {
+        assert_eq!(eval_at("1 + 2 * 3", 0.0, 0.0), 7.0);
+        assert_eq!(eval_at("(1 + 2) * 3", 0.0, 0.0), 9.0);
+        assert_eq!(eval_at("-u / 2 - -v", 3.0, 1.0), -0.5);
+        assert_eq!(eval_at("2.5e-1 * 4", 0.0, 0.0), 1.0);
+        assert_eq!(eval_at("kd * 2", 0.0, 0.0), 0.5);
+        assert_eq!(eval_at("clamp(u, 0, 1) + max(v, 2)", 1.5, 0.0), 3.0);
+        assert_eq!(eval_at("1 / 0", 0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn it_evaluates_built_in_textures() {
+        assert_eq!(eval_at("checker(u, v)", 0.5, 0.5), 1.0);
+        assert_eq!(eval_at("checker(u, v)", 1.5, 0.5), 0.0);
+        assert_eq!(eval_at("checker(u, v)", -0.5, 0.5), 0.0);
+        assert_eq!(eval_at("0.5*checker(u,v)+noise(p)*kd", 0.5, 0.5),
+                   0.5 + noise_at(&Point::new()) * 0.25);
+    }
+
+    #[test]
+    fn it_mixes_colors_and_floats() {
+        let tex: &dyn Texture<Spectrum> = &parse("mix(base, rgb(0, 0, 1), u)").unwrap();
+        let mut dg = DifferentialGeometry::new();
+        dg.u = 0.5;
+        assert_eq!(tex.evaluate(&dg).to_rgb(), Spectrum::from_rgb([0.5, 0.25, 0.5]).to_rgb());
+
+        let tex: &dyn Texture<Spectrum> = &parse("kd * 2").unwrap();
+        assert_eq!(tex.evaluate(&dg), Spectrum::from(0.5));
+    }
+
+    #[test]
+    fn it_reports_errors() {
+        assert!(parse("1 +").is_err());
+        assert!(parse("(u").is_err());
+        assert!(parse("u v").is_err());
+        assert!(parse("nope(u)").is_err());
+        assert!(parse("missing * 2").is_err());
+        assert!(parse("noise(u)").is_err());
+        assert!(parse("checker(u)").is_err());
+        assert!(parse("u $ v").is_err());
+        assert_eq!(parse("u").unwrap().source(), "u");
+    }
+}
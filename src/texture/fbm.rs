@@ -1,27 +1,38 @@
-use diff_geom::DifferentialGeometry;
-use texture::internal::TextureBase;
-use texture::mapping3d::TextureMapping3D;
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::internal::TextureBase;
+use crate::texture::mapping3d::TextureMapping3D;
 
-use texture::noise::fbm;
-use texture::noise::turbulence;
+use crate::texture::noise::NoiseBasis;
 
 #[derive(Debug)]
 pub struct FBmTexture {
     omega: f32,
     octaves: i32,
+    basis: NoiseBasis,
     mapping: Box<dyn TextureMapping3D>
 }
 
 impl FBmTexture {
     pub fn new(oct: i32, roughness: f32, map: Box<dyn TextureMapping3D>) -> FBmTexture {
-        FBmTexture { omega: roughness, octaves: oct, mapping: map }
+        FBmTexture { omega: roughness, octaves: oct, basis: NoiseBasis::Perlin, mapping: map }
+    }
+
+    pub fn with_basis(self, basis: NoiseBasis) -> FBmTexture {
+        FBmTexture { basis, ..self }
     }
 }
 
 impl TextureBase<f32> for FBmTexture {
     fn eval(&self, dg: &DifferentialGeometry) -> f32 {
         let (p, dpdx, dpdy) = self.mapping.map(dg);
-        fbm(&p, &dpdx, &dpdy, self.omega, self.octaves)
+        self.basis.fbm(&p, &dpdx, &dpdy, self.omega, self.octaves)
+    }
+}
+
+impl TextureBase<Spectrum> for FBmTexture {
+    fn eval(&self, dg: &DifferentialGeometry) -> Spectrum {
+        Spectrum::from(TextureBase::<f32>::eval(self, dg))
     }
 }
 
@@ -29,19 +40,66 @@ impl TextureBase<f32> for FBmTexture {
 pub struct WrinkledTexture {
     omega: f32,
     octaves: i32,
+    basis: NoiseBasis,
     mapping: Box<dyn TextureMapping3D>
 }
 
 impl WrinkledTexture {
     pub fn new(oct: i32, roughness: f32, map: Box<dyn TextureMapping3D>)
                -> WrinkledTexture {
-        WrinkledTexture { omega: roughness, octaves: oct, mapping: map }
+        WrinkledTexture {
+            omega: roughness, octaves: oct, basis: NoiseBasis::Perlin, mapping: map
+        }
+    }
+
+    pub fn with_basis(self, basis: NoiseBasis) -> WrinkledTexture {
+        WrinkledTexture { basis, ..self }
     }
 }
 
 impl TextureBase<f32> for WrinkledTexture {
     fn eval(&self, dg: &DifferentialGeometry) -> f32 {
         let (p, dpdx, dpdy) = self.mapping.map(dg);
-        turbulence(&p, &dpdx, &dpdy, self.omega, self.octaves)
+        self.basis.turbulence(&p, &dpdx, &dpdy, self.omega, self.octaves)
+    }
+}
+
+impl TextureBase<Spectrum> for WrinkledTexture {
+    fn eval(&self, dg: &DifferentialGeometry) -> Spectrum {
+        Spectrum::from(TextureBase::<f32>::eval(self, dg))
+    }
+}
+
+// The height of waves on water, which are made up of small waves whose
+// amplitude is scaled by a lower frequency fBm for the strength of the wind
+#[derive(Debug)]
+pub struct WindyTexture {
+    basis: NoiseBasis,
+    mapping: Box<dyn TextureMapping3D>
+}
+
+impl WindyTexture {
+    pub fn new(map: Box<dyn TextureMapping3D>) -> WindyTexture {
+        WindyTexture { basis: NoiseBasis::Perlin, mapping: map }
+    }
+
+    pub fn with_basis(self, basis: NoiseBasis) -> WindyTexture {
+        WindyTexture { basis, ..self }
+    }
+}
+
+impl TextureBase<f32> for WindyTexture {
+    fn eval(&self, dg: &DifferentialGeometry) -> f32 {
+        let (p, dpdx, dpdy) = self.mapping.map(dg);
+        let wind_strength =
+            self.basis.fbm(&(0.1 * &p), &(0.1 * &dpdx), &(0.1 * &dpdy), 0.5, 3);
+        let wave_height = self.basis.fbm(&p, &dpdx, &dpdy, 0.5, 6);
+        wind_strength.abs() * wave_height
+    }
+}
+
+impl TextureBase<Spectrum> for WindyTexture {
+    fn eval(&self, dg: &DifferentialGeometry) -> Spectrum {
+        Spectrum::from(TextureBase::<f32>::eval(self, dg))
     }
 }
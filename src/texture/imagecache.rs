@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::iter::Sum;
+use std::ops::Add;
+use std::ops::Div;
+use std::ops::Mul;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::imagemap::ImageEncoding;
+use crate::texture::imagemap::TexInfo;
+use crate::texture::imagemap::read_texels;
+use crate::texture::imagewrap::ImageWrap;
+use crate::texture::mapping2d::TextureMapping2D;
+use crate::texture::mipmap::MIPMap;
+use crate::utils::Lerp;
+
+// An image that the cache holds, and the value of the cache's clock when
+// it was last looked up
+#[derive(Debug)]
+struct CachedImage<T: Default + Clone> {
+    mipmap: Arc<MIPMap<T>>,
+    bytes: usize,
+    last_used: AtomicUsize
+}
+
+// A cache for scenes with more image textures than fit in memory. Unlike
+// TextureCache, images aren't read when their textures are created but the
+// first time that they're looked up, and once the MIPMaps of the images
+// take up more than the budget, the ones used least recently are dropped
+// until they're needed again. The image formats that can be read can only
+// be decoded whole, so images are loaded and evicted whole rather than in
+// tiles.
+//
+// Lookups of images that are already loaded only take the read lock, and
+// images are read without holding any lock. Textures keep an image that
+// they're filtering alive even if it's evicted in the meantime.
+#[derive(Debug)]
+pub struct LazyTextureCache<T: Default + Clone> {
+    budget: usize,
+    convert: fn(Spectrum) -> T,
+    images: RwLock<BTreeMap<TexInfo, CachedImage<T>>>,
+    clock: AtomicUsize
+}
+
+impl LazyTextureCache<f32> {
+    // The budget is in bytes. Float textures are the luminance of their
+    // images.
+    pub fn new(budget: usize) -> LazyTextureCache<f32> {
+        LazyTextureCache::with_conversion(budget, |s| s.y())
+    }
+}
+
+impl LazyTextureCache<Spectrum> {
+    pub fn new(budget: usize) -> LazyTextureCache<Spectrum> {
+        LazyTextureCache::with_conversion(budget, |s| s)
+    }
+}
+
+impl<T: Default + Clone + ::std::fmt::Debug +
+     Mul<f32, Output = T> +
+     Div<f32, Output = T> +
+     Sum<<T as Mul<f32>>::Output> +
+     Add<Output = T> +
+     Lerp<f32>> LazyTextureCache<T> {
+    fn with_conversion(budget: usize, convert: fn(Spectrum) -> T) -> LazyTextureCache<T> {
+        LazyTextureCache {
+            budget, convert,
+            images: RwLock::new(BTreeMap::new()),
+            clock: AtomicUsize::new(0)
+        }
+    }
+
+    // How much memory the images that are loaded take up
+    pub fn resident_bytes(&self) -> usize {
+        self.images.read().unwrap().values().map(|img| img.bytes).sum()
+    }
+
+    pub fn clear(&self) {
+        self.images.write().unwrap().clear();
+    }
+
+    fn get(&self, tex_info: &TexInfo) -> Arc<MIPMap<T>> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        if let Some(img) = self.images.read().unwrap().get(tex_info) {
+            img.last_used.store(now, Ordering::Relaxed);
+            return img.mipmap.clone();
+        }
+
+        let (width, height, texels) =
+            read_texels(&tex_info.filename, tex_info.scale, tex_info.encoding);
+        let pixels = texels.into_iter().map(self.convert).collect();
+        let mipmap = Arc::new(MIPMap::new(width as usize, height as usize, pixels,
+                                          tex_info.do_trilinear, tex_info.max_aniso,
+                                          tex_info.wrap));
+
+        // If another thread loaded the image first, its MIPMap wins
+        let mut images = self.images.write().unwrap();
+        let img = images.entry(tex_info.clone()).or_insert_with(|| CachedImage {
+            bytes: mipmap.size_in_bytes(),
+            mipmap,
+            last_used: AtomicUsize::new(now)
+        });
+        let mipmap = img.mipmap.clone();
+        LazyTextureCache::evict(&mut images, self.budget, tex_info);
+        mipmap
+    }
+
+    // Drops the least recently used images until the rest fit in the
+    // budget. The image that was just looked up always stays, even if it's
+    // larger than the budget on its own.
+    fn evict(images: &mut BTreeMap<TexInfo, CachedImage<T>>, budget: usize, keep: &TexInfo) {
+        let mut total: usize = images.values().map(|img| img.bytes).sum();
+        while total > budget {
+            let lru = images.iter()
+                .filter(|(info, _)| *info != keep)
+                .min_by_key(|(_, img)| img.last_used.load(Ordering::Relaxed))
+                .map(|(info, _)| info.clone());
+            match lru {
+                Some(info) => total -= images.remove(&info).unwrap().bytes,
+                None => break
+            }
+        }
+    }
+
+    pub fn new_texture<P>(
+        self: &Arc<Self>, m: Box<dyn TextureMapping2D>, filename: &P, do_trilinear: bool,
+        max_aniso: f32, wrap_mode: ImageWrap, scale: f32, encoding: ImageEncoding)
+        -> LazyImageTexture<T> where P: AsRef<Path> + AsRef<OsStr> {
+        LazyImageTexture {
+            cache: self.clone(),
+            tex_info: TexInfo {
+                filename: PathBuf::from(filename),
+                do_trilinear,
+                max_aniso,
+                wrap: wrap_mode,
+                scale,
+                encoding
+            },
+            mapping: m,
+            lod_bias: 0.0
+        }
+    }
+}
+
+// An image texture whose image is loaded by a LazyTextureCache
+#[derive(Debug)]
+pub struct LazyImageTexture<T: Default + Clone> {
+    cache: Arc<LazyTextureCache<T>>,
+    tex_info: TexInfo,
+    mapping: Box<dyn TextureMapping2D>,
+    lod_bias: f32
+}
+
+impl<T: Default + Clone> LazyImageTexture<T> {
+    pub fn with_lod_bias(self, lod_bias: f32) -> LazyImageTexture<T> {
+        LazyImageTexture { lod_bias, ..self }
+    }
+}
+
+impl<T: Default + Clone + ::std::fmt::Debug +
+     Mul<f32, Output = T> +
+     Div<f32, Output = T> +
+     Sum<<T as Mul<f32>>::Output> +
+     Add<Output = T> +
+     Lerp<f32>>
+super::internal::TextureBase<T> for LazyImageTexture<T> {
+    fn eval(&self, dg: &DifferentialGeometry) -> T {
+        let (s, t, dsdx, dtdx, dsdy, dtdy) = self.mapping.map(dg);
+        self.cache.get(&self.tex_info)
+            .biased_lookup(self.lod_bias, s, t, dsdx, dtdx, dsdy, dtdy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::geometry::point::Point;
+    use crate::texture::Texture;
+    use crate::texture::mapping2d::PlanarMapping2D;
+
+    fn test_file(name: &str) -> PathBuf {
+        Path::new(file!()).parent().unwrap().join("testdata").join(name)
+    }
+
+    fn texture(cache: &Arc<LazyTextureCache<f32>>, name: &str) -> LazyImageTexture<f32> {
+        cache.new_texture(Box::new(PlanarMapping2D::new()), &test_file(name), false, 1.0,
+                          ImageWrap::Repeat, 1.0, ImageEncoding::Gamma(1.0))
+    }
+
+    #[test]
+    fn it_loads_images_when_theyre_first_looked_up() {
+        let cache = Arc::new(LazyTextureCache::<f32>::new(1 << 30));
+        let tex = texture(&cache, "checkerboard_square.png");
+        assert_eq!(cache.resident_bytes(), 0);
+
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.75, 0.25, 0.0);
+        assert_eq!(tex.evaluate(&dg), 1.0);
+        let bytes = cache.resident_bytes();
+        assert!(bytes > 0);
+
+        // Textures of the same image share it
+        let other = texture(&cache, "checkerboard_square.png");
+        assert_eq!(other.evaluate(&dg), 1.0);
+        assert_eq!(cache.resident_bytes(), bytes);
+
+        cache.clear();
+        assert_eq!(cache.resident_bytes(), 0);
+    }
+
+    #[test]
+    fn it_evicts_the_least_recently_used_images() {
+        // Find out how large each image is, and leave room for just two
+        let sizes: Vec<usize> = ["checkerboard_square.png", "checkerboard_stretched.png"]
+            .iter().map(|name| {
+                let cache = Arc::new(LazyTextureCache::<f32>::new(1 << 30));
+                texture(&cache, name).evaluate(&DifferentialGeometry::new());
+                cache.resident_bytes()
+            }).collect();
+        let cache = Arc::new(LazyTextureCache::<f32>::new(sizes[0] + sizes[1]));
+
+        // The same image read with two scales is two different MIPMaps
+        let square = texture(&cache, "checkerboard_square.png");
+        let stretched = texture(&cache, "checkerboard_stretched.png");
+        let bright = cache.new_texture(
+            Box::new(PlanarMapping2D::new()), &test_file("checkerboard_square.png"), false,
+            1.0, ImageWrap::Repeat, 2.0, ImageEncoding::Gamma(1.0));
+
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.75, 0.25, 0.0);
+        square.evaluate(&dg);
+        stretched.evaluate(&dg);
+        assert_eq!(cache.resident_bytes(), sizes[0] + sizes[1]);
+
+        // Using the square image again makes the stretched one the least
+        // recently used, so it's the one that makes room for the bright one
+        square.evaluate(&dg);
+        assert_eq!(bright.evaluate(&dg), 2.0);
+        assert_eq!(cache.resident_bytes(), 2 * sizes[0]);
+        let images = cache.images.read().unwrap();
+        assert!(images.keys().all(|info| info.filename == test_file("checkerboard_square.png")));
+        drop(images);
+
+        // Evicted images are loaded again when they're needed
+        assert_eq!(stretched.evaluate(&dg), 1.0);
+        assert_eq!(cache.resident_bytes(), sizes[0] + sizes[1]);
+    }
+
+    #[test]
+    fn images_larger_than_the_budget_are_still_used() {
+        let cache = Arc::new(LazyTextureCache::<Spectrum>::new(0));
+        let tex = cache.new_texture(
+            Box::new(PlanarMapping2D::new()), &test_file("checkerboard_square.png"), false,
+            1.0, ImageWrap::Repeat, 1.0, ImageEncoding::Gamma(1.0));
+
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.75, 0.25, 0.0);
+        assert_eq!(tex.evaluate(&dg), Spectrum::from(1.0));
+        assert!(cache.resident_bytes() > 0);
+    }
+}
@@ -1,6 +1,3 @@
-extern crate lazy_static;
-extern crate image;
-
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
@@ -11,28 +8,62 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::iter::Sum;
 use std::marker::PhantomData;
-use std::sync::Mutex;
 use std::sync::Arc;
+use std::sync::RwLock;
+
+#[cfg(feature = "image")]
+use image::open;
+#[cfg(feature = "image")]
+use image::ColorType;
+
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::mapping2d::TextureMapping2D;
+use crate::texture::internal::TextureBase;
+use crate::texture::Texture;
+use crate::texture::imagewrap::ImageWrap;
+use crate::texture::mipmap::MIPMap;
+use crate::utils::Lerp;
+
+// How the texels of low dynamic range images are decoded to linear values:
+// either left as they are, raised to a power, or decoded with the sRGB
+// transfer curve that most 8-bit images are stored with
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum ImageEncoding {
+    Linear,
+    Gamma(f32),
+    SRGB
+}
 
-use self::image::open;
-use self::image::ImageResult;
+impl ImageEncoding {
+    fn decode(&self, v: f32) -> f32 {
+        match self {
+            ImageEncoding::Linear => v,
+            ImageEncoding::Gamma(gamma) => v.powf(*gamma),
+            ImageEncoding::SRGB => if v <= 0.04045 {
+                v / 12.92
+            } else {
+                ((v + 0.055) / 1.055).powf(2.4)
+            }
+        }
+    }
 
-use diff_geom::DifferentialGeometry;
-use spectrum::Spectrum;
-use texture::mapping2d::TextureMapping2D;
-use texture::internal::TextureBase;
-use texture::Texture;
-use texture::imagewrap::ImageWrap;
-use texture::mipmap::MIPMap;
-use utils::Lerp;
+    fn decode_spectrum(&self, s: Spectrum) -> Spectrum {
+        let [r, g, b] = s.to_rgb();
+        Spectrum::from_rgb([self.decode(r), self.decode(g), self.decode(b)])
+    }
+}
 
+// Everything that goes into the MIPMap of an image texture, which textures
+// with the same info can share
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
-struct TexInfo {
-    filename: PathBuf,
-    do_trilinear: bool,
-    max_aniso: f32,
-    wrap: ImageWrap,
-    gamma: f32
+pub(crate) struct TexInfo {
+    pub(crate) filename: PathBuf,
+    pub(crate) do_trilinear: bool,
+    pub(crate) max_aniso: f32,
+    pub(crate) wrap: ImageWrap,
+    pub(crate) scale: f32,
+    pub(crate) encoding: ImageEncoding
 }
 
 impl Eq for TexInfo {}
@@ -54,144 +85,238 @@ impl Ord for TexInfo {
             return Ordering::Greater;
         }
 
-        if self.gamma < other.gamma {
+        if self.scale < other.scale {
+            return Ordering::Less;
+        } else if self.scale > other.scale {
+            return Ordering::Greater;
+        }
+
+        if self.encoding < other.encoding {
             return Ordering::Less;
-        } else if self.gamma > other.gamma {
+        } else if self.encoding > other.encoding {
             return Ordering::Greater;
-        }        
+        }
 
         self.wrap.cmp(&other.wrap)
     }
 }
 
-struct TextureCache<T: std::default::Default + std::clone::Clone>(BTreeMap<TexInfo, Arc<MIPMap<T>>>);
+// The texture cache is shared by everything that creates image textures,
+// potentially from multiple threads, so the map lives behind a RwLock and
+// all of the methods take &self. Lookups of textures that have already
+// been loaded only need the read lock. Images are read without holding any
+// lock, so if two threads race to load the same file, whichever inserts
+// first wins and both get back the same MIPMap.
+//
+// MIPMaps themselves are immutable once built, and ImageTextures only hold
+// an Arc to them, so they are safe to evaluate from any render thread.
+#[derive(Default)]
+pub struct TextureCache<T: Default + Clone>(RwLock<BTreeMap<TexInfo, Arc<MIPMap<T>>>>);
+
+impl<T: Default + Clone> TextureCache<T> {
+    pub fn new() -> TextureCache<T> { TextureCache(RwLock::new(BTreeMap::new())) }
+
+    fn lookup(&self, tex_info: &TexInfo) -> Option<Arc<MIPMap<T>>> {
+        self.0.read().unwrap().get(tex_info).cloned()
+    }
+
+    fn insert(&self, tex_info: TexInfo, mipmap: Arc<MIPMap<T>>) -> Arc<MIPMap<T>> {
+        self.0.write().unwrap().entry(tex_info).or_insert(mipmap).clone()
+    }
+
+    pub fn clear(&self) {
+        self.0.write().unwrap().clear();
+    }
+}
 
 #[derive(Debug)]
 pub struct ImageTexture<Tmemory: Default + Clone> {
     mipmap: Arc<MIPMap<Tmemory>>,
-    mapping: Box<dyn TextureMapping2D>
+    mapping: Box<dyn TextureMapping2D>,
+    lod_bias: f32
 }
 
-fn read_image<P>(filename: &P)
-                 -> ImageResult<(u32, u32, Vec<Spectrum>)> where P: AsRef<Path> {
-    open(filename)
-        .and_then(|raw_img| Ok(raw_img.into_rgb8()))
-        .and_then(|rgb_img| {
-            let mut spec_vec = Vec::new();
-            for &pixel in rgb_img.pixels() {
-                let r = (pixel[0] as f32) / 255f32;
-                let g = (pixel[1] as f32) / 255f32;
-                let b = (pixel[2] as f32) / 255f32;
-                spec_vec.push(Spectrum::from_rgb([r, g, b]));
-            }
-            Ok((rgb_img.width(), rgb_img.height(), spec_vec))
+impl<T: Default + Clone> ImageTexture<T> {
+    // Offsets the levels of the MIPMap that the texture is filtered from.
+    // The bias only applies to this texture, so textures with different
+    // biases still share their MIPMap.
+    pub fn with_lod_bias(self, lod_bias: f32) -> ImageTexture<T> {
+        ImageTexture { lod_bias, ..self }
+    }
+}
+
+// An image as RGB texels. High dynamic range images store floating point
+// values, as OpenEXR and Radiance HDR images do, which are linear and can be
+// brighter than one. The texels of the rest are normalized to [0, 1] and
+// may still be gamma encoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub texels: Vec<Spectrum>,
+    pub high_dynamic_range: bool
+}
+
+// Reads the images that image textures and image based lights refer to.
+// By default they're decoded from the file system by FileImageReader, but
+// targets without a file system or the image crate, such as wasm32, can
+// install a reader of their own with set_image_reader.
+pub trait ImageReader : Send + Sync {
+    fn read(&self, filename: &Path) -> Result<DecodedImage, String>;
+}
+
+// Reads any image format that the image crate knows, such as PNG, TGA,
+// OpenEXR and Radiance HDR
+#[cfg(feature = "image")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileImageReader;
+
+#[cfg(feature = "image")]
+impl ImageReader for FileImageReader {
+    fn read(&self, filename: &Path) -> Result<DecodedImage, String> {
+        let img = open(filename).map_err(|e| e.to_string())?;
+        let high_dynamic_range = matches!(img.color(), ColorType::Rgb32F | ColorType::Rgba32F);
+        let rgb_img = img.into_rgb32f();
+        Ok(DecodedImage {
+            width: rgb_img.width(),
+            height: rgb_img.height(),
+            texels: rgb_img.pixels().map(|p| Spectrum::from_rgb([p[0], p[1], p[2]])).collect(),
+            high_dynamic_range
         })
+    }
 }
 
-impl TextureCache<f32> {
-    pub fn new() -> TextureCache<f32> { TextureCache(BTreeMap::new()) }
+static IMAGE_READER: RwLock<Option<Arc<dyn ImageReader>>> = RwLock::new(None);
+
+// Replaces the reader that images are read with from now on. Textures and
+// lights that have already been created keep the images they read, and so
+// do the texture caches.
+pub fn set_image_reader(reader: Arc<dyn ImageReader>) {
+    *IMAGE_READER.write().unwrap() = Some(reader);
+}
 
+fn decode_image(filename: &Path) -> Result<DecodedImage, String> {
+    match IMAGE_READER.read().unwrap().clone() {
+        Some(reader) => reader.read(filename),
+        #[cfg(feature = "image")]
+        None => FileImageReader.read(filename),
+        #[cfg(not(feature = "image"))]
+        None => Err(String::from("built without image support and no image reader was set"))
+    }
+}
+
+// Reads an image as RGB texels with the current ImageReader
+pub(crate) fn read_image<P>(filename: &P)
+                 -> Result<(u32, u32, Vec<Spectrum>), String> where P: AsRef<Path> {
+    decode_image(filename.as_ref()).map(|img| (img.width, img.height, img.texels))
+}
+
+// Reads the texels of an image texture, scaled and decoded. Power law
+// gammas apply to the scaled texels, while sRGB is decoded before the
+// texels are scaled. High dynamic
+// range images are already linear, so only their scale applies. Images
+// that can't be read are a single texel, as if they were white.
+pub(crate) fn read_texels<P>(filename: &P, scale: f32, encoding: ImageEncoding)
+                  -> (u32, u32, Vec<Spectrum>) where P: AsRef<Path> {
+    match decode_image(filename.as_ref()) {
+        Ok(DecodedImage { width, height, texels, high_dynamic_range }) => {
+            let texels = texels.into_iter().map(|s| {
+                match encoding {
+                    _ if high_dynamic_range => s * scale,
+                    ImageEncoding::SRGB => encoding.decode_spectrum(s) * scale,
+                    _ => encoding.decode_spectrum(s * scale)
+                }
+            }).collect();
+            (width, height, texels)
+        },
+        Err(e) => {
+            println!("WARNING: Unable to read image texture {}: {}",
+                     filename.as_ref().display(), e);
+            let white = match encoding {
+                ImageEncoding::SRGB => scale,
+                _ => encoding.decode(scale)
+            };
+            (1, 1, vec![Spectrum::from(white)])
+        }
+    }
+}
+
+impl TextureCache<f32> {
     fn get_texture<P>(
-        &mut self, filename: &P, do_trilinear: bool, max_aniso: f32, wrap_mode: ImageWrap,
-        scale: f32, gamma: f32)
+        &self, filename: &P, do_trilinear: bool, max_aniso: f32, wrap_mode: ImageWrap,
+        scale: f32, encoding: ImageEncoding)
         -> Arc<MIPMap<f32>> where P: AsRef<Path> + AsRef<OsStr> {
         let tex_info = TexInfo {
             filename: PathBuf::from(filename),
             do_trilinear: do_trilinear,
             max_aniso: max_aniso,
             wrap: wrap_mode,
-            gamma: gamma
+            scale,
+            encoding
         };
 
-        if let Some(tex) = self.0.get(&tex_info) {
-            return tex.clone();
+        if let Some(tex) = self.lookup(&tex_info) {
+            return tex;
         }
 
-        let read_img_result = read_image(filename);
-        let ret = if let Ok((width, height, texels)) = read_img_result {
-            // Convert texels to f32 and create MIPMap
-            let pixels = texels.into_iter()
-                .map(|s| (s.y() * scale).powf(gamma))
-                .collect::<Vec<_>>();
-            Arc::new(MIPMap::new(width as usize, height as usize, pixels,
-                                do_trilinear, max_aniso, wrap_mode))
-        } else {
-            // Create one-values mipmap
-            Arc::new(MIPMap::new(1, 1, vec![scale.powf(gamma); 1],
-                                do_trilinear, max_aniso, wrap_mode))
-        };
+        // Convert texels to f32 and create MIPMap
+        let (width, height, texels) = read_texels(filename, scale, encoding);
+        let pixels = texels.into_iter().map(|s| s.y()).collect::<Vec<_>>();
+        let ret = Arc::new(MIPMap::new(width as usize, height as usize, pixels,
+                                       do_trilinear, max_aniso, wrap_mode));
 
-        self.0.insert(tex_info.clone(), ret);
-        self.0.get(&tex_info).unwrap().clone()
+        self.insert(tex_info, ret)
     }
 
     pub fn new_texture<P>(
-        &mut self, m: Box<dyn TextureMapping2D>, filename: &P, do_trilinear: bool, max_aniso: f32,
-        wrap_mode: ImageWrap, scale: f32, gamma: f32)
+        &self, m: Box<dyn TextureMapping2D>, filename: &P, do_trilinear: bool, max_aniso: f32,
+        wrap_mode: ImageWrap, scale: f32, encoding: ImageEncoding)
         -> ImageTexture<f32> where P: AsRef<Path> + AsRef<OsStr> {
         ImageTexture {
             mipmap: self.get_texture(
-                filename, do_trilinear, max_aniso, wrap_mode, scale, gamma),
-            mapping: m
+                filename, do_trilinear, max_aniso, wrap_mode, scale, encoding),
+            mapping: m,
+            lod_bias: 0.0
         }
     }
-
-    pub fn clear(&mut self) {
-        self.0.clear();
-    }
 }
 
 impl TextureCache<Spectrum> {
-    pub fn new() -> TextureCache<Spectrum> { TextureCache(BTreeMap::new()) }
-
     fn get_texture<P>(
-        &mut self, filename: &P, do_trilinear: bool, max_aniso: f32, wrap_mode: ImageWrap,
-        scale: f32, gamma: f32)
+        &self, filename: &P, do_trilinear: bool, max_aniso: f32, wrap_mode: ImageWrap,
+        scale: f32, encoding: ImageEncoding)
         -> Arc<MIPMap<Spectrum>> where P: AsRef<Path> + AsRef<OsStr> {
         let tex_info = TexInfo {
             filename: PathBuf::from(filename),
             do_trilinear: do_trilinear,
             max_aniso: max_aniso,
             wrap: wrap_mode,
-            gamma: gamma
+            scale,
+            encoding
         };
     
-        if let Some(tex) = self.0.get(&tex_info) {
-            return tex.clone();
+        if let Some(tex) = self.lookup(&tex_info) {
+            return tex;
         }
     
-        let read_img_result = read_image(filename);
-        let ret = if let Ok((width, height, texels)) = read_img_result {
-            // Convert texels to Spectrum and create MIPMap
-            let pixels = texels.into_iter()
-                .map(|s| (s * scale).powf(gamma))
-                .collect();
-            Arc::new(MIPMap::new(width as usize, height as usize, pixels,
-                                    do_trilinear, max_aniso, wrap_mode))
-        } else {
-            // Create one-values mipmap
-            Arc::new(MIPMap::new(1, 1, vec![Spectrum::from(scale.powf(gamma)); 1],
-                                    do_trilinear, max_aniso, wrap_mode))
-        };
+        let (width, height, texels) = read_texels(filename, scale, encoding);
+        let ret = Arc::new(MIPMap::new(width as usize, height as usize, texels,
+                                       do_trilinear, max_aniso, wrap_mode));
     
-        self.0.insert(tex_info.clone(), ret);
-        self.0.get(&tex_info).unwrap().clone()
+        self.insert(tex_info, ret)
     }
 
     pub fn new_texture<P>(
-        &mut self, m: Box<dyn TextureMapping2D>, filename: &P, do_trilinear: bool, max_aniso: f32,
-        wrap_mode: ImageWrap, scale: f32, gamma: f32)
+        &self, m: Box<dyn TextureMapping2D>, filename: &P, do_trilinear: bool, max_aniso: f32,
+        wrap_mode: ImageWrap, scale: f32, encoding: ImageEncoding)
         -> ImageTexture<Spectrum> where P: AsRef<Path> + AsRef<OsStr> {
         ImageTexture {
             mipmap: self.get_texture(
-                filename, do_trilinear, max_aniso, wrap_mode, scale, gamma),
-            mapping: m
+                filename, do_trilinear, max_aniso, wrap_mode, scale, encoding),
+            mapping: m,
+            lod_bias: 0.0
         }
     }
-
-    pub fn clear(&mut self) {
-        self.0.clear();
-    }
 }
 
 impl<T: Default + Clone + ::std::fmt::Debug +
@@ -203,20 +328,22 @@ impl<T: Default + Clone + ::std::fmt::Debug +
 super::internal::TextureBase<T> for ImageTexture<T> {
     fn eval(&self, dg: &DifferentialGeometry) -> T {
         let (s, t, dsdx, dtdx, dsdy, dtdy) = self.mapping.map(dg);
-        self.mipmap.lookup(s, t, dsdx, dtdx, dsdy, dtdy)
+        self.mipmap.biased_lookup(self.lod_bias, s, t, dsdx, dtdx, dsdy, dtdy)
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "image"))]
 mod tests {
     use super::*;
 
     use std::path::Path;
 
-    use texture::mapping2d::PlanarMapping2D;
-    use texture::mapping2d::TextureMapping2D;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
+    use image::DynamicImage;
+
+    use crate::texture::mapping2d::PlanarMapping2D;
+    use crate::texture::mapping2d::TextureMapping2D;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
 
     #[test]
     fn it_can_create_rgb_textures() {
@@ -224,9 +351,9 @@ mod tests {
         let this_file = Path::new(file!());
         let test_file = Path::join(this_file.parent().unwrap(),
                                    "testdata/checkerboard_square.png");
-        let mut tex_cache = TextureCache::<Spectrum>::new();
+        let tex_cache = TextureCache::<Spectrum>::new();
         let tex = tex_cache.new_texture(
-            mapping, &test_file, false, 1.0, ImageWrap::Repeat, 1.0, 2.2);
+            mapping, &test_file, false, 1.0, ImageWrap::Repeat, 1.0, ImageEncoding::Gamma(2.2));
 
         let mut dg = DifferentialGeometry::new();
         dg.p = Point::new_with(0.25, 0.25, 0.0);
@@ -248,9 +375,9 @@ mod tests {
         let this_file = Path::new(file!());
         let test_file = Path::join(this_file.parent().unwrap(),
                                    "testdata/checkerboard_stretched.png");
-        let mut tex_cache = TextureCache::<f32>::new();
+        let tex_cache = TextureCache::<f32>::new();
         let tex = tex_cache.new_texture(
-            mapping, &test_file, false, 1.0, ImageWrap::Repeat, 1.0, 2.2);
+            mapping, &test_file, false, 1.0, ImageWrap::Repeat, 1.0, ImageEncoding::Gamma(2.2));
 
         let mut dg = DifferentialGeometry::new();
         dg.p = Point::new_with(0.25, 0.25, 0.0);
@@ -272,9 +399,9 @@ mod tests {
         let this_file = Path::new(file!());
         let test_file = Path::join(this_file.parent().unwrap(),
                                    "testdata/checkerboard_square.png");
-        let mut tex_cache = TextureCache::<Spectrum>::new();
+        let tex_cache = TextureCache::<Spectrum>::new();
         let tex = tex_cache.new_texture(
-            mapping, &test_file, false, 1.0, ImageWrap::Repeat, 1.0, 2.2);
+            mapping, &test_file, false, 1.0, ImageWrap::Repeat, 1.0, ImageEncoding::Gamma(2.2));
 
         let mut dg = DifferentialGeometry::new();
         dg.p = Point::new_with(0.25, 0.25, 0.0);
@@ -310,9 +437,9 @@ mod tests {
         let this_file = Path::new(file!());
         let test_file = Path::join(this_file.parent().unwrap(),
                                    "testdata/checkerboard_square.png");
-        let mut tex_cache = TextureCache::<Spectrum>::new();
+        let tex_cache = TextureCache::<Spectrum>::new();
         let tex = tex_cache.new_texture(
-            mapping, &test_file, false, 1.0, ImageWrap::Black, 1.0, 2.2);
+            mapping, &test_file, false, 1.0, ImageWrap::Black, 1.0, ImageEncoding::Gamma(2.2));
 
         let mut dg = DifferentialGeometry::new();
         let black = Spectrum::from_rgb([0.0, 0.0, 0.0]);
@@ -348,9 +475,9 @@ mod tests {
         let this_file = Path::new(file!());
         let test_file = Path::join(this_file.parent().unwrap(),
                                    "testdata/checkerboard_square.png");
-        let mut tex_cache = TextureCache::<f32>::new();
+        let tex_cache = TextureCache::<f32>::new();
         let tex = tex_cache.new_texture(
-            mapping, &test_file, false, 1.0, ImageWrap::Clamp, 1.0, 2.2);
+            mapping, &test_file, false, 1.0, ImageWrap::Clamp, 1.0, ImageEncoding::Gamma(2.2));
 
         let mut dg = DifferentialGeometry::new();
         for i in 0..10 {
@@ -382,16 +509,16 @@ mod tests {
         let this_file = Path::new(file!());
         let test_file = Path::join(this_file.parent().unwrap(),
                                    "testdata/checkerboard_stretched.png");
-        let mut tex_cache = TextureCache::<f32>::new();
+        let tex_cache = TextureCache::<f32>::new();
         let tex = tex_cache.new_texture(
-            mapping, &test_file, true, 1.0, ImageWrap::Clamp, 1.0, 2.2);
+            mapping, &test_file, true, 1.0, ImageWrap::Clamp, 1.0, ImageEncoding::Gamma(2.2));
 
         let mut dg = DifferentialGeometry::new();
         dg.p = Point::new_with(0.51, 0.25, 0.0);
         dg.dpdx = Vector::new_with(0.02, 0.0, 0.0);
         dg.dpdy = Vector::new_with(0.0, 0.02, 0.0);
 
-        assert!((tex.evaluate(&dg) - 0.7).abs() < 0.01);
+        assert!((tex.evaluate(&dg) - 0.747).abs() < 0.01);
     }
 
     #[test]
@@ -400,9 +527,9 @@ mod tests {
         let this_file = Path::new(file!());
         let test_file = Path::join(this_file.parent().unwrap(),
                                    "testdata/checkerboard_stretched.png");
-        let mut tex_cache = TextureCache::<f32>::new();
+        let tex_cache = TextureCache::<f32>::new();
         let tex = tex_cache.new_texture(
-            mapping, &test_file, false, 100.0, ImageWrap::Clamp, 1.0, 2.2);
+            mapping, &test_file, false, 100.0, ImageWrap::Clamp, 1.0, ImageEncoding::Gamma(2.2));
 
         let mut dg = DifferentialGeometry::new();
         dg.p = Point::new_with(0.51, 0.48, 0.0);
@@ -415,4 +542,172 @@ mod tests {
         dg.dpdy.y = 0.002;
         assert!((tex.evaluate(&dg) - 0.88).abs() < 0.01);
     }
+
+    #[test]
+    fn it_loads_high_dynamic_range_images_without_gamma() {
+        // Float images can be brighter than one, and are already linear
+        let mut img = image::Rgb32FImage::new(2, 2);
+        for p in img.pixels_mut() {
+            *p = image::Rgb([4.0, 2.0, 0.5]);
+        }
+
+        let dir = ::std::env::temp_dir();
+        for ext in ["exr", "hdr"] {
+            let filename = dir.join(format!("pbrt_rust_imagemap_test.{}", ext));
+            DynamicImage::ImageRgb32F(img.clone()).save(&filename).unwrap();
+
+            let tex_cache = TextureCache::<Spectrum>::new();
+            let tex = tex_cache.new_texture(Box::new(PlanarMapping2D::new()), &filename,
+                                            false, 1.0, ImageWrap::Repeat, 0.5, ImageEncoding::Gamma(2.2));
+            let mut dg = DifferentialGeometry::new();
+            dg.p = Point::new_with(0.25, 0.25, 0.0);
+            let rgb = tex.evaluate(&dg).to_rgb();
+            for (c, expected) in rgb.iter().zip([2.0, 1.0, 0.25].iter()) {
+                assert!((c - expected).abs() < 1e-2, "{}: {:?}", ext, rgb);
+            }
+            ::std::fs::remove_file(&filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_removes_the_gamma_of_low_dynamic_range_images() {
+        let filename = ::std::env::temp_dir().join("pbrt_rust_imagemap_test.tga");
+        image::RgbImage::from_pixel(2, 2, image::Rgb([128, 128, 128]))
+            .save(&filename).unwrap();
+
+        let tex_cache = TextureCache::<f32>::new();
+        let tex = tex_cache.new_texture(Box::new(PlanarMapping2D::new()), &filename,
+                                        false, 1.0, ImageWrap::Repeat, 2.0, ImageEncoding::Gamma(2.2));
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.25, 0.25, 0.0);
+        let expected = (2.0 * 128.0 / 255.0f32).powf(2.2);
+        assert!((tex.evaluate(&dg) - expected).abs() < 1e-4);
+        ::std::fs::remove_file(&filename).unwrap();
+
+        // Missing images are white, so they only leave their scale
+        let tex = tex_cache.new_texture(Box::new(PlanarMapping2D::new()), &"no/such/image.png",
+                                        false, 1.0, ImageWrap::Repeat, 0.5, ImageEncoding::Gamma(1.0));
+        assert_eq!(tex.evaluate(&dg), 0.5);
+    }
+
+    #[test]
+    fn it_can_decode_srgb_images() {
+        let filename = ::std::env::temp_dir().join("pbrt_rust_imagemap_srgb_test.tga");
+        image::RgbImage::from_pixel(2, 2, image::Rgb([128, 5, 255])).save(&filename).unwrap();
+
+        let tex_cache = TextureCache::<Spectrum>::new();
+        let tex = tex_cache.new_texture(Box::new(PlanarMapping2D::new()), &filename,
+                                        false, 1.0, ImageWrap::Repeat, 2.0, ImageEncoding::SRGB);
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.25, 0.25, 0.0);
+        let rgb = tex.evaluate(&dg).to_rgb();
+
+        // The curve is linear near black and a power law elsewhere, and the
+        // texels are scaled after they're decoded
+        let expected = [2.0 * 0.2158605, 2.0 * 5.0 / 255.0 / 12.92, 2.0];
+        for (c, e) in rgb.iter().zip(expected.iter()) {
+            assert!((c - e).abs() < 1e-4, "{:?}", rgb);
+        }
+
+        // Linear images are only scaled
+        let tex = tex_cache.new_texture(Box::new(PlanarMapping2D::new()), &filename,
+                                        false, 1.0, ImageWrap::Repeat, 2.0,
+                                        ImageEncoding::Linear);
+        let rgb = tex.evaluate(&dg).to_rgb();
+        assert!((rgb[0] - 2.0 * 128.0 / 255.0).abs() < 1e-4);
+        ::std::fs::remove_file(&filename).unwrap();
+    }
+
+    // Serves one image from memory, and reads the rest from files so that
+    // the other tests aren't affected while it's installed
+    struct MemoryImageReader;
+
+    impl ImageReader for MemoryImageReader {
+        fn read(&self, filename: &Path) -> Result<DecodedImage, String> {
+            if filename != Path::new("memory/orange") {
+                return FileImageReader.read(filename);
+            }
+
+            Ok(DecodedImage {
+                width: 1,
+                height: 1,
+                texels: vec![Spectrum::from_rgb([1.0, 0.5, 0.0])],
+                high_dynamic_range: true
+            })
+        }
+    }
+
+    #[test]
+    fn images_can_be_read_through_a_custom_reader() {
+        set_image_reader(Arc::new(MemoryImageReader));
+
+        let tex_cache = TextureCache::<Spectrum>::new();
+        let tex = tex_cache.new_texture(Box::new(PlanarMapping2D::new()), &"memory/orange",
+                                        false, 1.0, ImageWrap::Repeat, 2.0,
+                                        ImageEncoding::Gamma(2.2));
+        let mut dg = DifferentialGeometry::new();
+        dg.p = Point::new_with(0.25, 0.25, 0.0);
+        let rgb = tex.evaluate(&dg).to_rgb();
+        for (c, e) in rgb.iter().zip([2.0, 1.0, 0.0].iter()) {
+            assert!((c - e).abs() < 1e-4, "{:?}", rgb);
+        }
+    }
+
+    fn assert_send_sync<T: Send + Sync>() { }
+
+    #[test]
+    fn caches_and_textures_can_be_shared_between_threads() {
+        assert_send_sync::<TextureCache<f32>>();
+        assert_send_sync::<TextureCache<Spectrum>>();
+        assert_send_sync::<ImageTexture<f32>>();
+        assert_send_sync::<ImageTexture<Spectrum>>();
+    }
+
+    #[test]
+    fn it_loads_each_texture_once_when_used_from_many_threads() {
+        let this_file = Path::new(file!());
+        let test_file = Path::join(this_file.parent().unwrap(),
+                                   "testdata/checkerboard_square.png");
+        let tex_cache = Arc::new(TextureCache::<Spectrum>::new());
+
+        let handles: Vec<_> = (0..16).map(|i| {
+            let cache = tex_cache.clone();
+            let file = test_file.clone();
+            ::std::thread::spawn(move || {
+                let mut results = Vec::new();
+                for j in 0..32 {
+                    // Alternate between two different wrap modes so that
+                    // there are a few distinct entries in the cache.
+                    let wrap = if (i + j) % 2 == 0 {
+                        ImageWrap::Repeat
+                    } else {
+                        ImageWrap::Clamp
+                    };
+
+                    let tex = cache.new_texture(
+                        Box::new(PlanarMapping2D::new()), &file, false, 1.0,
+                        wrap, 1.0, ImageEncoding::Gamma(2.2));
+
+                    let mut dg = DifferentialGeometry::new();
+                    dg.p = Point::new_with(0.75, 0.25, 0.0);
+                    assert_eq!(tex.evaluate(&dg), Spectrum::from_rgb([1.0, 1.0, 1.0]));
+                    results.push((wrap, tex.mipmap.clone()));
+                }
+                results
+            })
+        }).collect();
+
+        let results: Vec<_> = handles.into_iter()
+            .flat_map(|h| h.join().unwrap().into_iter())
+            .collect();
+
+        assert_eq!(tex_cache.0.read().unwrap().len(), 2);
+        for &(wrap, ref mipmap) in results.iter() {
+            let first = results.iter().find(|r| r.0 == wrap).unwrap();
+            assert!(Arc::ptr_eq(mipmap, &first.1));
+        }
+
+        tex_cache.clear();
+        assert!(tex_cache.0.read().unwrap().is_empty());
+    }
 }
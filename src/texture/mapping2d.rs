@@ -2,16 +2,16 @@ use std::f32::consts;
 use std::fmt::Debug;
 use std::ops::Deref;
 
-use diff_geom::DifferentialGeometry;
-use geometry::normal::Normalize;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use geometry::vector::Dot;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::geometry::vector::Dot;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
 
-use geometry::vector::spherical_theta;
-use geometry::vector::spherical_phi;
+use crate::geometry::vector::spherical_theta;
+use crate::geometry::vector::spherical_phi;
 
 mod internal {
     use super::*;
@@ -75,30 +75,36 @@ impl internal::TextureMapping2DBase for UVMapping2D {
     }
 }
 
-fn get_circular_differentials<F>(dg: &DifferentialGeometry, mapping: F) ->
-    (f32, f32, f32, f32, f32, f32) where F: Fn(&Point) -> (f32, f32) {
+// The difference between two coordinates that wrap around at one, taken
+// the short way around so that lookups straddling the seam aren't filtered
+// across the whole texture
+fn wrapped_difference(a: f32, b: f32) -> f32 {
+    let d = a - b;
+    d - d.round()
+}
+
+// Computes the differentials of a mapping of the surface point by finite
+// differences. The coordinates of the mapping that wrap around are flagged
+// in `periodic`.
+fn get_circular_differentials<F>(dg: &DifferentialGeometry, mapping: F, periodic: (bool, bool))
+                                 -> (f32, f32, f32, f32, f32, f32)
+    where F: Fn(&Point) -> (f32, f32) {
         let (s, t) = mapping(&dg.p);
 
         let delta : f32 = 0.1;
-        let deal_with_singularity = |res: f32| {
-            if res > 0.5 {
-                1.0 - res
-            } else if res < -0.5 {
-                -(res + 1.0)
-            } else {
-                res
-            }
+        let difference = |a: f32, b: f32, wraps: bool| {
+            if wraps { wrapped_difference(a, b) } else { a - b }
         };
 
         let px = &dg.p + delta * &dg.dpdx;
         let (sx, tx) = mapping(&px);
-        let dsdx = (sx - s) / delta;
-        let dtdx = deal_with_singularity((tx - t) / delta);
+        let dsdx = difference(sx, s, periodic.0) / delta;
+        let dtdx = difference(tx, t, periodic.1) / delta;
 
         let py = &dg.p + delta * &dg.dpdy;
         let (sy, ty) = mapping(&py);
-        let dsdy = (sy - s) / delta;
-        let dtdy = deal_with_singularity((ty - t) / delta);
+        let dsdy = difference(sy, s, periodic.0) / delta;
+        let dtdy = difference(ty, t, periodic.1) / delta;
 
         (s, t, dsdx, dtdx, dsdy, dtdy)
     }
@@ -134,7 +140,7 @@ impl SphericalMapping2D {
 
 impl internal::TextureMapping2DBase for SphericalMapping2D {
     fn map_dg(&self, dg: &DifferentialGeometry) -> (f32, f32, f32, f32, f32, f32) {
-        get_circular_differentials(dg, |p| { self.sphere(p) })
+        get_circular_differentials(dg, |p| { self.sphere(p) }, (false, true))
     }
 }
 
@@ -167,7 +173,7 @@ impl CylindricalMapping2D {
 
 impl internal::TextureMapping2DBase for CylindricalMapping2D {
     fn map_dg(&self, dg: &DifferentialGeometry) -> (f32, f32, f32, f32, f32, f32) {
-        get_circular_differentials(dg, |p| { self.cylinder(p) })
+        get_circular_differentials(dg, |p| { self.cylinder(p) }, (true, false))
     }
 }
 
@@ -211,7 +217,7 @@ impl internal::TextureMapping2DBase for PlanarMapping2D {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use diff_geom::DifferentialGeometry;
+    use crate::diff_geom::DifferentialGeometry;
 
     fn test_uv_mapping_deriv(mapping: UVMapping2D) {
         let mut dg = DifferentialGeometry::new();
@@ -446,6 +452,25 @@ mod tests {
         test_positional_differentials(transformed_mapping);
     }
 
+    #[test]
+    fn circular_mappings_have_small_differentials_across_their_seams() {
+        let mut dg = DifferentialGeometry::new();
+        dg.dpdx = Vector::new_with(0.0, 0.02, 0.0);
+        dg.dpdy = Vector::new_with(0.0, 0.0, 0.02);
+
+        // The spherical mapping's t coordinate wraps around at +x...
+        dg.p = Point::new_with(1.0, -0.001, 0.0);
+        let (_, t, _, dtdx, _, _) = SphericalMapping2D::new().map(&dg);
+        assert!(t > 0.99);
+        assert!(dtdx > 0.0 && dtdx < 0.01);
+
+        // ... and the cylindrical mapping's s coordinate wraps around at -x
+        dg.p = Point::new_with(-1.0, -0.001, 0.0);
+        let (s, _, dsdx, _, _, _) = CylindricalMapping2D::new().map(&dg);
+        assert!(s < 0.01);
+        assert!(dsdx < 0.0 && dsdx > -0.01);
+    }
+
     #[test]
     fn identity_planar_mapping_can_produce_differentials() {
         let planar_mapping = PlanarMapping2D::new();
@@ -1,11 +1,11 @@
 use std::ops::Deref;
 use std::fmt::Debug;
 
-use diff_geom::DifferentialGeometry;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
+use crate::diff_geom::DifferentialGeometry;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
 
 mod internal {
     use super::*;
@@ -52,6 +52,13 @@ impl IdentityMapping3D {
     pub fn new() -> IdentityMapping3D {
         IdentityMapping3D::new_with(Transform::new())
     }
+
+    // Solid textures are defined in the space that was current when they
+    // were declared, so that they move along with the objects that were
+    // declared in the same space rather than sliding through them.
+    pub fn from_texture_space(tex_to_world: &Transform) -> IdentityMapping3D {
+        IdentityMapping3D::new_with(tex_to_world.inverse())
+    }
 }
 
 impl internal::TextureMapping3DBase for IdentityMapping3D {
@@ -65,7 +72,7 @@ impl internal::TextureMapping3DBase for IdentityMapping3D {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use diff_geom::DifferentialGeometry;
+    use crate::diff_geom::DifferentialGeometry;
 
     fn test_positional_differentials<Mapping : TextureMapping3D>(m: Mapping) {
         let mut dg = DifferentialGeometry::new();
@@ -114,4 +121,19 @@ mod tests {
 
         test_positional_differentials(identity_mapping);
     }
+
+    #[test]
+    fn texture_space_mappings_follow_the_texture_space() {
+        let tex_to_world = Transform::translate(&Vector::new_with(1.0, 2.0, 3.0))
+            * Transform::scale(2.0, 2.0, 2.0);
+        let mapping = IdentityMapping3D::from_texture_space(&tex_to_world);
+
+        // Points in texture space come back to where they started
+        let mut dg = DifferentialGeometry::new();
+        dg.p = tex_to_world.xf(Point::new_with(0.5, -0.25, 1.0));
+        dg.dpdx = Vector::new_with(2.0, 0.0, 0.0);
+        let (p, dpdx, _) = mapping.map(&dg);
+        assert!(Vector::from(p - Point::new_with(0.5, -0.25, 1.0)).length_squared() < 1e-10);
+        assert_eq!(dpdx, Vector::new_with(1.0, 0.0, 0.0));
+    }
 }
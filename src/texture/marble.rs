@@ -0,0 +1,99 @@
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::internal::TextureBase;
+use crate::texture::mapping3d::TextureMapping3D;
+use crate::texture::noise::NoiseBasis;
+use crate::utils::Lerp;
+
+// The colors of the veins of the marble, which are the control points of
+// a cubic Bezier spline that's indexed by the perturbed sine wave
+const MARBLE_COLORS: [[f32; 3]; 9] = [
+    [0.58, 0.58, 0.6], [0.58, 0.58, 0.6], [0.58, 0.58, 0.6],
+    [0.5, 0.5, 0.5], [0.6, 0.59, 0.58], [0.58, 0.58, 0.6],
+    [0.58, 0.58, 0.6], [0.2, 0.2, 0.33], [0.58, 0.58, 0.6],
+];
+
+#[derive(Debug)]
+pub struct MarbleTexture {
+    octaves: i32,
+    omega: f32,
+    scale: f32,
+    variation: f32,
+    basis: NoiseBasis,
+    mapping: Box<dyn TextureMapping3D>
+}
+
+impl MarbleTexture {
+    pub fn new(oct: i32, roughness: f32, scale: f32, variation: f32,
+               map: Box<dyn TextureMapping3D>) -> MarbleTexture {
+        MarbleTexture {
+            octaves: oct, omega: roughness, scale, variation, basis: NoiseBasis::Perlin,
+            mapping: map
+        }
+    }
+
+    pub fn with_basis(self, basis: NoiseBasis) -> MarbleTexture {
+        MarbleTexture { basis, ..self }
+    }
+}
+
+impl TextureBase<Spectrum> for MarbleTexture {
+    fn eval(&self, dg: &DifferentialGeometry) -> Spectrum {
+        let (p, dpdx, dpdy) = self.mapping.map(dg);
+        let p = self.scale * &p;
+        let marble = p.y + self.variation * self.basis.fbm(&p, &(self.scale * &dpdx),
+                                                           &(self.scale * &dpdy),
+                                                           self.omega, self.octaves);
+        let t = 0.5 + 0.5 * marble.sin();
+
+        // Evaluate the segment of the spline that t falls in
+        let num_segments = MARBLE_COLORS.len() - 3;
+        let first = ((t * num_segments as f32).floor() as usize).min(num_segments - 1);
+        let t = t * num_segments as f32 - first as f32;
+        let c: Vec<Spectrum> = MARBLE_COLORS[first..(first + 4)].iter()
+            .map(|&rgb| Spectrum::from_rgb(rgb))
+            .collect();
+
+        // de Casteljau's algorithm
+        let s0 = c[0].lerp(&c[1], t);
+        let s1 = c[1].lerp(&c[2], t);
+        let s2 = c[2].lerp(&c[3], t);
+        let s0 = s0.lerp_with(s1, t);
+        let s1 = s1.lerp_with(s2, t);
+
+        // Extra scale of 1.5 to increase variation among colors
+        s0.lerp_with(s1, t) * 1.5
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::texture::Texture;
+    use crate::texture::mapping3d::IdentityMapping3D;
+
+    #[test]
+    fn its_colors_stay_within_the_spline() {
+        let tex = MarbleTexture::new(8, 0.5, 1.0, 0.2, Box::new(IdentityMapping3D::new()));
+        let mut dg = DifferentialGeometry::new();
+        dg.dpdx = Vector::new_with(0.01, 0.0, 0.0);
+        dg.dpdy = Vector::new_with(0.0, 0.01, 0.0);
+
+        // The spline lies in the convex hull of its control points
+        let (lo, hi) = (0.2 * 1.5, 0.6 * 1.5);
+        let mut ys = Vec::new();
+        for i in 0..50 {
+            dg.p = Point::new_with(0.1 * i as f32, 0.13 * i as f32, 0.07 * i as f32);
+            let s = tex.evaluate(&dg);
+            let rgb = s.to_rgb();
+            assert!(rgb.iter().all(|&c| c >= lo - 1e-4 && c <= hi + 1e-4), "{:?}", rgb);
+            ys.push(s.y());
+        }
+
+        // The veins run along y, so the color changes as we move along it
+        assert!(ys.iter().any(|&y| (y - ys[0]).abs() > 1e-2));
+    }
+}
@@ -3,13 +3,13 @@ use std::ops::Mul;
 use std::ops::Div;
 use std::iter::Sum;
 
-use texture::imagewrap::ImageWrap;
-use utils::blocked_vec::BlockedVec;
-use utils::Lerp;
+use crate::texture::imagewrap::ImageWrap;
+use crate::utils::blocked_vec::BlockedVec;
+use crate::utils::Lerp;
 
 use std::cmp;
-use utils::modulo;
-use utils::sinc_1d;
+use crate::utils::modulo;
+use crate::utils::sinc_1d;
 
 const INV_EXP_2: f32 = 0.13533528323;
 
@@ -89,9 +89,11 @@ fn resize_to_power_of_two_dims<T>
         }
     }
 
-    // Resample image in t direction
+    // Resample image in t direction. Each column is copied out first, since
+    // the texels that are resampled replace the ones that they're made from.
     let t_weights = resample_weights(h as usize, hpot);
     for s in 0..wpot {
+        let column: Vec<T> = (0..h).map(|t| new_pixels[t * wpot + s].clone()).collect();
         for t in 0..hpot {
             let new_pixel =
                 t_weights[t].weights.iter()
@@ -99,7 +101,7 @@ fn resize_to_power_of_two_dims<T>
                 .filter_map(
                     |(j, &weight)|
                     get_orig(&(t_weights[t]), j, h).map(|orig_t| {
-                        new_pixels[(orig_t as usize)*wpot + s].clone() * weight
+                        column[orig_t as usize].clone() * weight
                     }))
                 .sum();
             new_pixels[t * wpot + s] = new_pixel;
@@ -210,6 +212,13 @@ impl<T: Default + Clone + ::std::fmt::Debug +
 
     pub fn levels(&self) -> usize { self.pyramid.len() }
 
+    // The memory taken up by the texels of every level of the pyramid
+    pub fn size_in_bytes(&self) -> usize {
+        self.pyramid.iter()
+            .map(|level| level.width() * level.height() * ::std::mem::size_of::<T>())
+            .sum()
+    }
+
     fn triangle(&self, _level: usize, _s: f32, _t: f32) -> T {
         let level = _level.clamp(0, self.levels() - 1);
         let s = _s * (self.pyramid[level].width() as f32) - 0.5;
@@ -225,8 +234,8 @@ impl<T: Default + Clone + ::std::fmt::Debug +
         texel_at(&self.pyramid[level], s0 + 1, t0 + 1, wm) * ds * dt
     }
 
-    fn pyramid_lookup(&self, s: f32, t: f32, width: f32) -> T {
-        let level = (self.levels() as f32) - 1.0 + width.max(1e-8).log2();
+    fn pyramid_lookup(&self, s: f32, t: f32, width: f32, lod_bias: f32) -> T {
+        let level = (self.levels() as f32) - 1.0 + width.max(1e-8).log2() + lod_bias;
         if level < 0.0 {
             self.triangle(0, s, t)
         } else if level >= ((self.levels() - 1) as f32) {
@@ -234,8 +243,8 @@ impl<T: Default + Clone + ::std::fmt::Debug +
         } else {
             let ilevel = level as usize;
             let delta = level - (ilevel as f32);
-            let t0 = self.triangle(ilevel + 1, s, t);
-            let t1 = self.triangle(ilevel, s, t);
+            let t0 = self.triangle(ilevel, s, t);
+            let t1 = self.triangle(ilevel + 1, s, t);
             t0.lerp_with(t1, delta)
         }
     }
@@ -300,10 +309,18 @@ impl<T: Default + Clone + ::std::fmt::Debug +
 
     pub fn lookup(&self, s: f32, t: f32,
                   dsdx: f32, dtdx: f32, dsdy: f32, dtdy: f32) -> T {
+        self.biased_lookup(0.0, s, t, dsdx, dtdx, dsdy, dtdy)
+    }
+
+    // Looks up the filtered texture like lookup does, but from levels of
+    // the pyramid that are offset by the bias. Positive biases blur the
+    // texture, and negative ones sharpen it at the cost of aliasing.
+    pub fn biased_lookup(&self, lod_bias: f32, s: f32, t: f32,
+                         dsdx: f32, dtdx: f32, dsdy: f32, dtdy: f32) -> T {
         if self.do_trilinear {
             let width =
                 dsdx.abs().max(dtdx.abs()).max(dsdy.abs()).max(dtdy.abs());
-            return self.pyramid_lookup(s, t, 2.0 * width);
+            return self.pyramid_lookup(s, t, 2.0 * width, lod_bias);
         }
 
         // Compute ellipse minor and major axes
@@ -327,11 +344,14 @@ impl<T: Default + Clone + ::std::fmt::Debug +
             };
 
         if scaled_minor_length == 0.0 {
-            return self.triangle(0, s, t);
+            return self.pyramid_lookup(s, t, 0.0, lod_bias);
         }
 
         // Choose level of detail for EWA lookup and perform EWA filtering.
-        let lod = ((self.levels() as f32) - 1.0 + minor_length.log2()).max(0.0);
+        // Clamped ellipses are filtered from the level that fits their
+        // lengthened minor axis, so they don't cover too many texels.
+        let lod = ((self.levels() as f32) - 1.0 + scaled_minor_length.log2() + lod_bias)
+            .max(0.0);
         let ilod = lod.floor() as usize;
         let d = lod - (ilod as f32);
         let t0 = self.ewa(ilod + 0, s, t, ds0, dt0, scaled_ds1, scaled_dt1);
@@ -339,3 +359,100 @@ impl<T: Default + Clone + ::std::fmt::Debug +
         t0.lerp_with(t1, d)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Vertical stripes that are one texel wide
+    fn stripes(w: usize, h: usize) -> Vec<f32> {
+        (0..(w * h)).map(|i| if (i % w) % 2 == 0 { 0.0 } else { 1.0 }).collect()
+    }
+
+    #[test]
+    fn it_builds_a_pyramid_of_averages() {
+        let mipmap = MIPMap::new(8, 4, stripes(8, 4), true, 8.0, ImageWrap::Repeat);
+        assert_eq!(mipmap.levels(), 4);
+        assert_eq!(mipmap.pyramid[1].width(), 4);
+        assert_eq!(mipmap.pyramid[1].height(), 2);
+        assert_eq!(*mipmap.pyramid[1].get(1, 1).unwrap(), 0.5);
+        assert_eq!(*mipmap.pyramid[3].get(0, 0).unwrap(), 0.5);
+    }
+
+    #[test]
+    fn it_resamples_images_that_arent_powers_of_two() {
+        // A ramp from top to bottom stays a ramp
+        let ramp: Vec<f32> = (0..9).map(|i| (i / 3) as f32 / 2.0).collect();
+        let mipmap = MIPMap::new(3, 3, ramp, true, 8.0, ImageWrap::Clamp);
+        assert_eq!((mipmap.width(), mipmap.height()), (4, 4));
+
+        let level = &mipmap.pyramid[0];
+        for s in 0..4 {
+            let column: Vec<f32> = (0..4).map(|t| *level.get(s, t).unwrap()).collect();
+            assert!(column.windows(2).all(|c| c[0] < c[1]), "{:?}", column);
+            assert!((column[0] + column[3] - 1.0).abs() < 1e-4, "{:?}", column);
+        }
+
+        // And a constant image stays constant
+        let mipmap = MIPMap::new(5, 3, vec![0.25; 15], false, 8.0, ImageWrap::Repeat);
+        assert_eq!((mipmap.width(), mipmap.height()), (8, 4));
+        for t in 0..4 {
+            for s in 0..8 {
+                assert!((mipmap.pyramid[0].get(s, t).unwrap() - 0.25).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn trilinear_lookups_blur_with_their_footprint() {
+        let mipmap = MIPMap::new(8, 8, stripes(8, 8), true, 8.0, ImageWrap::Repeat);
+
+        // Without a footprint, the lookup interpolates the finest texels
+        assert_eq!(mipmap.lookup(1.0 / 16.0, 0.5, 0.0, 0.0, 0.0, 0.0), 0.0);
+        assert_eq!(mipmap.lookup(3.0 / 16.0, 0.5, 0.0, 0.0, 0.0, 0.0), 1.0);
+
+        // Footprints that cover several stripes average them out
+        let blurred = mipmap.lookup(1.0 / 16.0, 0.5, 0.25, 0.0, 0.0, 0.25);
+        assert!((blurred - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn trilinear_lookups_blend_the_closest_levels() {
+        let mipmap = MIPMap::new(8, 8, stripes(8, 8), true, 8.0, ImageWrap::Repeat);
+
+        // A footprint a quarter of the way from the finest level to the
+        // next, where the stripes are averaged out, is mostly the former
+        let d = 2.0f32.powf(-3.75);
+        let v = mipmap.lookup(3.0 / 16.0, 9.0 / 16.0, d, 0.0, 0.0, d);
+        assert!((v - 0.875).abs() < 1e-4, "{}", v);
+
+        // Biasing the level of detail moves the lookup to coarser levels
+        let v = mipmap.biased_lookup(0.5, 3.0 / 16.0, 9.0 / 16.0, d, 0.0, 0.0, d);
+        assert!((v - 0.625).abs() < 1e-4, "{}", v);
+        let v = mipmap.biased_lookup(-1.0, 3.0 / 16.0, 9.0 / 16.0, d, 0.0, 0.0, d);
+        assert!((v - 1.0).abs() < 1e-4, "{}", v);
+    }
+
+    #[test]
+    fn ewa_lookups_filter_along_their_footprint() {
+        let mipmap = MIPMap::new(16, 16, stripes(16, 16), false, 8.0, ImageWrap::Repeat);
+
+        // Footprints across the stripes average them out...
+        let across = mipmap.lookup(0.5, 0.5, 0.25, 0.0, 0.0, 0.01);
+        assert!((across - 0.5).abs() < 0.05, "{}", across);
+
+        // ... but those along a stripe keep it
+        let along = mipmap.lookup(1.0 / 32.0, 0.5, 0.0, 0.25, 0.01, 0.0);
+        assert!(along < 0.1, "{}", along);
+
+        // Footprints that are too eccentric are made rounder, which picks a
+        // coarser level to filter from
+        let round = MIPMap::new(16, 16, stripes(16, 16), false, 1.0, ImageWrap::Repeat);
+        let along = round.lookup(1.0 / 32.0, 0.5, 0.0, 0.25, 0.01, 0.0);
+        assert!((along - 0.5).abs() < 0.05, "{}", along);
+
+        // Biased lookups filter from coarser levels too
+        let along = mipmap.biased_lookup(3.0, 1.0 / 32.0, 0.5, 0.0, 0.25, 0.01, 0.0);
+        assert!((along - 0.5).abs() < 0.05, "{}", along);
+    }
+}
@@ -1,9 +1,9 @@
 use std::sync::Arc;
 
-use diff_geom::DifferentialGeometry;
-use texture::internal::TextureBase;
-use texture::Texture;
-use utils::Lerp;
+use crate::diff_geom::DifferentialGeometry;
+use crate::texture::internal::TextureBase;
+use crate::texture::Texture;
+use crate::utils::Lerp;
 
 #[derive(Clone, Debug)]
 pub struct MixTexture<T> where T: Lerp<f32> {
@@ -29,8 +29,8 @@ impl<T> super::internal::TextureBase<T> for MixTexture<T> where T: Lerp<f32> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use texture::ConstantTexture;
-    use geometry::vector::Vector;
+    use crate::texture::ConstantTexture;
+    use crate::geometry::vector::Vector;
 
     #[test]
     fn mix_texture_works() {
@@ -1,23 +1,28 @@
 pub mod bilerp;
 pub mod checkerboard;
 pub mod dots;
+pub mod expr;
 pub mod fbm;
+pub mod imagecache;
 pub mod imagemap;
 pub mod imagewrap;
 pub mod mapping2d;
 pub mod mapping3d;
+pub mod marble;
 pub mod mipmap;
 pub mod mix;
 pub mod uv;
 
 mod noise;
 
+pub use self::noise::NoiseBasis;
+
 use std::sync::Arc;
 use std::ops::Deref;
 use std::ops::Mul;
 use std::fmt::Debug;
-use diff_geom::DifferentialGeometry;
-use spectrum::Spectrum;
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
 
 mod internal {
     use super::*;
@@ -33,6 +38,12 @@ mod internal {
     }
 }
 
+// Textures are created once during scene construction and then evaluated
+// concurrently by every render thread, so they must be Send + Sync and
+// evaluation only gets &self. Any state a texture wants to memoize (such as
+// the MIPMaps loaded by the image texture cache) must be immutable once
+// built or live behind a lock. Per-thread scratch state (samplers, RNGs,
+// film tiles) is never stored in a texture.
 pub trait Texture<T>: Debug + Send + Sync + internal::TextureBase<T> {
     fn evaluate(&self, _: &DifferentialGeometry) -> T;
 }
@@ -87,7 +98,7 @@ Mul<U, Output=V> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use geometry::vector::Vector;
+    use crate::geometry::vector::Vector;
 
     #[test]
     fn const_texture_works() {
@@ -1,6 +1,6 @@
-use geometry::point::Point;
-use geometry::vector::Vector;
-use utils::Lerp;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::utils::Lerp;
 
 const NOISE_PERM_SIZE: usize = 256;
 const NOISE_PERM: [usize; 2 * NOISE_PERM_SIZE] = [
@@ -104,49 +104,120 @@ pub fn noise(x: f32, y: f32, z: f32) -> f32 {
 
 pub fn noise_at(p: &Point) -> f32 { noise(p.x, p.y, p.z) }
 
+// Simplex noise, which sums the gradients of the four corners of the
+// tetrahedron of a skewed grid that the point falls in. Since the
+// tetrahedra aren't lined up with the axes, it doesn't have the grid
+// artifacts along them that Perlin noise has.
+pub fn simplex_noise(x: f32, y: f32, z: f32) -> f32 {
+    const SKEW: f32 = 1.0 / 3.0;
+    const UNSKEW: f32 = 1.0 / 6.0;
+
+    // Find the cell of the skewed grid and the offset from its origin
+    let s = (x + y + z) * SKEW;
+    let (i, j, k) = ((x + s).floor(), (y + s).floor(), (z + s).floor());
+    let t = (i + j + k) * UNSKEW;
+    let (dx, dy, dz) = (x - (i - t), y - (j - t), z - (k - t));
+
+    // Find which of the cell's six tetrahedra the point is in, which is
+    // given by the order of the offsets
+    let (second, third) = if dx >= dy {
+        if dy >= dz { ((1, 0, 0), (1, 1, 0)) }
+        else if dx >= dz { ((1, 0, 0), (1, 0, 1)) }
+        else { ((0, 0, 1), (1, 0, 1)) }
+    } else if dy < dz { ((0, 0, 1), (0, 1, 1)) }
+    else if dx < dz { ((0, 1, 0), (0, 1, 1)) }
+    else { ((0, 1, 0), (1, 1, 0)) };
+
+    let noise_mask = (NOISE_PERM_SIZE - 1) as i32;
+    let ix = ((i as i32) & noise_mask) as usize;
+    let iy = ((j as i32) & noise_mask) as usize;
+    let iz = ((k as i32) & noise_mask) as usize;
+    [(0, 0, 0), second, third, (1, 1, 1)].iter().enumerate()
+        .map(|(c, &(oi, oj, ok))| {
+            let cx = dx - oi as f32 + c as f32 * UNSKEW;
+            let cy = dy - oj as f32 + c as f32 * UNSKEW;
+            let cz = dz - ok as f32 + c as f32 * UNSKEW;
+            let falloff = 0.6 - cx * cx - cy * cy - cz * cz;
+            if falloff <= 0.0 {
+                0.0
+            } else {
+                let falloff2 = falloff * falloff;
+                falloff2 * falloff2 * grad(ix + oi, iy + oj, iz + ok, cx, cy, cz)
+            }
+        })
+        .sum::<f32>() * 32.0
+}
+
 fn smoothstep(min: f32, max: f32, value: f32) -> f32 {
     let v = ((value - min) / (max - min)).clamp(0.0, 1.0);
     v * v * (-2.0 * v + 3.0)
 }
 
+// The noise that fBm and turbulence sum octaves of
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub enum NoiseBasis {
+    #[default]
+    Perlin,
+    Simplex
+}
+
+impl NoiseBasis {
+    pub fn noise_at(&self, p: &Point) -> f32 {
+        match self {
+            NoiseBasis::Perlin => noise(p.x, p.y, p.z),
+            NoiseBasis::Simplex => simplex_noise(p.x, p.y, p.z)
+        }
+    }
+
+    pub fn fbm(&self, p: &Point, dpdx: &Vector, dpdy: &Vector,
+               omega: f32, max_octaves: i32) -> f32 {
+        // Compute number of octaves for antialiased FBm
+        let s2 = dpdx.length_squared().max(dpdy.length_squared());
+        let foctaves = (-1.0 - 0.5 * s2.log2()).clamp(0.0, max_octaves as f32);
+        let octaves = foctaves.floor() as i32;
+
+        // Compute sum of octaves of noise for FBm
+        let (sum, lambda, o) = (0..octaves).fold(
+            (0.0, 1.0, 1.0), |(acc, lambda, o), _| {
+                let v = self.noise_at(&(lambda * p));
+                (acc + o * v, lambda * 1.99, o * omega)
+            });
+        let partial_octave = foctaves - foctaves.floor();
+        sum + o * smoothstep(0.3, 0.7, partial_octave) * self.noise_at(&(lambda * p))
+    }
+
+    pub fn turbulence(&self, p: &Point, dpdx: &Vector, dpdy: &Vector,
+                      omega: f32, max_octaves: i32) -> f32 {
+        // Compute number of octaves for antialiased FBm
+        let s2 = dpdx.length_squared().max(dpdy.length_squared());
+        let foctaves = (-1.0 - 0.5 * s2.log2()).clamp(0.0, max_octaves as f32);
+        let octaves = foctaves.floor() as i32;
+
+        // Compute sum of octaves of noise for turbulence
+        let (sum, lambda, o) = (0..octaves).fold(
+            (0.0, 1.0, 1.0), |(acc, lambda, o), _| {
+                (acc + o * self.noise_at(&(lambda * p)).abs(), lambda * 1.99, o * omega)
+            });
+        let partial_octave = foctaves - foctaves.floor();
+        sum + o * smoothstep(0.3, 0.7, partial_octave) * self.noise_at(&(lambda * p)).abs()
+    }
+}
+
 pub fn fbm(p: &Point, dpdx: &Vector, dpdy: &Vector,
            omega: f32, max_octaves: i32) -> f32 {
-    // Compute number of octaves for antialiased FBm
-    let s2 = dpdx.length_squared().max(dpdy.length_squared());
-    let foctaves = (-1.0 - 0.5 * s2.log2()).clamp(0.0, max_octaves as f32);
-    let octaves = foctaves.floor() as i32;
-
-    // Compute sum of octaves of noise for FBm
-    let (sum, lambda, o) = (0..octaves).fold(
-        (0.0, 1.0, 1.0), |(acc, lambda, o), i| {
-            let v = noise_at(&(lambda * p));
-            (acc + o * v, lambda * 1.99, o * omega)
-        });
-    let partial_octave = foctaves - foctaves.floor();
-    sum + o * smoothstep(0.3, 0.7, partial_octave) * noise_at(&(lambda * p))
+    NoiseBasis::Perlin.fbm(p, dpdx, dpdy, omega, max_octaves)
 }
 
 pub fn turbulence(p: &Point, dpdx: &Vector, dpdy: &Vector,
                   omega: f32, max_octaves: i32) -> f32 {
-    // Compute number of octaves for antialiased FBm
-    let s2 = dpdx.length_squared().max(dpdy.length_squared());
-    let foctaves = (-1.0 - 0.5 * s2.log2()).clamp(0.0, max_octaves as f32);
-    let octaves = foctaves.floor() as i32;
-
-    // Compute sum of octaves of noise for turbulence
-    let (sum, lambda, o) = (0..octaves).fold(
-        (0.0, 1.0, 1.0), |(acc, lambda, o), i| {
-            (acc + o * noise_at(&(lambda * p)), lambda * 1.99, o * omega)
-        });
-    let partial_octave = foctaves - foctaves.floor();
-    sum + o * smoothstep(0.3, 0.7, partial_octave) * noise_at(&(lambda * p)).abs()
+    NoiseBasis::Perlin.turbulence(p, dpdx, dpdy, omega, max_octaves)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
 
     #[test]
     fn noise_is_zero_at_integers() {
@@ -211,4 +282,51 @@ mod tests {
         assert!(v != v2);
         assert!((v - v2).abs() < 0.06);
     }
+
+    #[test]
+    fn turbulence_is_never_negative() {
+        let dpdx = Vector::new_with(0.01, 0.0, 0.0);
+        let dpdy = Vector::new_with(0.0, 0.01, 0.0);
+        for i in 0..100 {
+            let p = Point::new_with(0.37 * i as f32, -0.13 * i as f32, 0.05 * i as f32);
+            assert!(turbulence(&p, &dpdx, &dpdy, 0.5, 8) >= 0.0);
+        }
+    }
+
+    #[test]
+    fn simplex_noise_is_bounded_and_more_or_less_continuous() {
+        let mut prev = simplex_noise(0.0, 0.25, -3.1);
+        let mut nonzero = 0;
+        for i in 1..2000 {
+            let t = i as f32 * 0.005;
+            let v = simplex_noise(t, 0.25 + 0.5 * t, -3.1 + 0.3 * t);
+            assert!(v.abs() <= 1.0);
+            assert!((v - prev).abs() < 0.1);
+            if v != 0.0 { nonzero += 1; }
+            prev = v;
+        }
+        assert!(nonzero > 1900);
+    }
+
+    #[test]
+    fn simplex_noise_is_not_zero_on_the_grid() {
+        // Unlike Perlin noise, the lattice of integers doesn't show through
+        let vs: Vec<f32> = (0..10).map(|i| simplex_noise(i as f32, 2.0 * i as f32, 1.0))
+            .collect();
+        assert!(vs.iter().filter(|v| v.abs() > 1e-3).count() > 5);
+    }
+
+    #[test]
+    fn fbm_can_use_either_basis() {
+        let p = Point::new_with(0.3, -0.4, 10.2);
+        let dpdx = Vector::new_with(0.01, 0.0, 0.0);
+        let dpdy = Vector::new_with(0.0, 0.01, 0.0);
+        assert_eq!(NoiseBasis::default().fbm(&p, &dpdx, &dpdy, 0.5, 8),
+                   fbm(&p, &dpdx, &dpdy, 0.5, 8));
+
+        let v = NoiseBasis::Simplex.fbm(&p, &dpdx, &dpdy, 0.5, 8);
+        assert!(v.abs() > 0.0);
+        assert!(v != fbm(&p, &dpdx, &dpdy, 0.5, 8));
+        assert!(NoiseBasis::Simplex.turbulence(&p, &dpdx, &dpdy, 0.5, 8) >= 0.0);
+    }
 }
@@ -1,10 +1,10 @@
 use std::sync::Arc;
 
-use diff_geom::DifferentialGeometry;
-use spectrum::Spectrum;
-use texture::internal::TextureBase;
-use texture::mapping2d::TextureMapping2D;
-use texture::Texture;
+use crate::diff_geom::DifferentialGeometry;
+use crate::spectrum::Spectrum;
+use crate::texture::internal::TextureBase;
+use crate::texture::mapping2d::TextureMapping2D;
+use crate::texture::Texture;
 
 #[derive(Debug)]
 pub struct UVTexture {
@@ -28,10 +28,10 @@ impl super::internal::TextureBase<Spectrum> for UVTexture {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use diff_geom::DifferentialGeometry;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
-    use texture::mapping2d::PlanarMapping2D;
+    use crate::diff_geom::DifferentialGeometry;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::texture::mapping2d::PlanarMapping2D;
 
     #[test]
     fn uv_texture_works() {
@@ -0,0 +1,180 @@
+// Structured logging of everything that goes into the radiance of a single
+// pixel, used to track down fireflies and NaNs. Tracing is switched on per
+// thread, so a pixel can be re-rendered with tracing on while the rest of
+// the renderer pays only for a thread local check. Events are recorded as
+// JSON objects, e.g. {"event":"light_sample","pdf":0.5,...}.
+
+use std::cell::RefCell;
+
+use crate::geometry::normal::Normal;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::spectrum::Spectrum;
+
+thread_local! {
+    static EVENTS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+// Writes a float as JSON, which has no representation for NaN or infinity,
+// so those are written as strings to keep them visible in the log.
+fn write_float(out: &mut String, x: f32) {
+    if x.is_finite() {
+        out.push_str(&format!("{}", x));
+    } else {
+        out.push_str(&format!("\"{}\"", x));
+    }
+}
+
+fn write_floats(out: &mut String, xs: &[f32]) {
+    out.push('[');
+    for (i, &x) in xs.iter().enumerate() {
+        if i > 0 { out.push(','); }
+        write_float(out, x);
+    }
+    out.push(']');
+}
+
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+// A JSON object under construction
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent(String);
+
+impl TraceEvent {
+    pub fn new(name: &str) -> TraceEvent {
+        let mut json = String::from("{\"event\":");
+        write_str(&mut json, name);
+        TraceEvent(json)
+    }
+
+    fn key(&mut self, key: &str) -> &mut String {
+        self.0.push(',');
+        write_str(&mut self.0, key);
+        self.0.push(':');
+        &mut self.0
+    }
+
+    pub fn float(mut self, key: &str, x: f32) -> TraceEvent {
+        write_float(self.key(key), x);
+        self
+    }
+
+    pub fn floats(mut self, key: &str, xs: &[f32]) -> TraceEvent {
+        write_floats(self.key(key), xs);
+        self
+    }
+
+    pub fn int(mut self, key: &str, x: i64) -> TraceEvent {
+        let out = self.key(key);
+        out.push_str(&format!("{}", x));
+        self
+    }
+
+    pub fn boolean(mut self, key: &str, b: bool) -> TraceEvent {
+        self.key(key).push_str(if b { "true" } else { "false" });
+        self
+    }
+
+    pub fn str(mut self, key: &str, s: &str) -> TraceEvent {
+        write_str(self.key(key), s);
+        self
+    }
+
+    pub fn point(self, key: &str, p: &Point) -> TraceEvent {
+        self.floats(key, &[p.x, p.y, p.z])
+    }
+
+    pub fn vector(self, key: &str, v: &Vector) -> TraceEvent {
+        self.floats(key, &[v.x, v.y, v.z])
+    }
+
+    pub fn normal(self, key: &str, n: &Normal) -> TraceEvent {
+        self.floats(key, &[n.x, n.y, n.z])
+    }
+
+    // Spectra are logged as linear RGB
+    pub fn spectrum(self, key: &str, s: &Spectrum) -> TraceEvent {
+        self.floats(key, &s.to_rgb())
+    }
+
+    // Adds a value that is already valid JSON
+    pub fn raw(mut self, key: &str, json: &str) -> TraceEvent {
+        self.key(key).push_str(json);
+        self
+    }
+
+    pub fn to_json(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+// Starts recording events on the calling thread, dropping anything that
+// was recorded before.
+pub fn begin() {
+    EVENTS.with(|events| *events.borrow_mut() = Some(Vec::new()));
+}
+
+// Stops recording events on the calling thread and returns the recorded
+// events as JSON objects.
+pub fn end() -> Vec<String> {
+    EVENTS.with(|events| events.borrow_mut().take().unwrap_or_default())
+}
+
+pub fn is_enabled() -> bool {
+    EVENTS.with(|events| events.borrow().is_some())
+}
+
+// Records an event if tracing is enabled on the calling thread. The event
+// is only built when it's going to be recorded.
+pub fn record<F>(name: &str, f: F) where F: FnOnce(TraceEvent) -> TraceEvent {
+    EVENTS.with(|events| {
+        if let Some(ref mut events) = *events.borrow_mut() {
+            events.push(f(TraceEvent::new(name)).to_json());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_only_records_while_enabled() {
+        record("ignored", |e| e.int("x", 1));
+        assert!(!is_enabled());
+
+        begin();
+        assert!(is_enabled());
+        record("light_sample", |e| e.float("pdf", 0.5).boolean("unoccluded", true));
+        record("miss", |e| e.vector("d", &Vector::new_with(0.0, 0.0, 1.0)));
+        assert_eq!(end(), vec![
+            String::from("{\"event\":\"light_sample\",\"pdf\":0.5,\"unoccluded\":true}"),
+            String::from("{\"event\":\"miss\",\"d\":[0,0,1]}")]);
+
+        assert!(!is_enabled());
+        assert!(end().is_empty());
+    }
+
+    #[test]
+    fn it_writes_valid_json_for_odd_values() {
+        let json = TraceEvent::new("odd")
+            .floats("l", &[f32::NAN, f32::INFINITY, -1.5])
+            .str("name", "a \"quoted\"\\name\n")
+            .to_json();
+        assert_eq!(json, "{\"event\":\"odd\",\"l\":[\"NaN\",\"inf\",-1.5],\
+                          \"name\":\"a \\\"quoted\\\"\\\\name\\n\"}");
+    }
+}
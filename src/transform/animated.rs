@@ -1,14 +1,14 @@
-use bbox::BBox;
-use bbox::Union;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use quaternion::Quaternion;
-use ray::Ray;
-use ray::RayDifferential;
-use transform::matrix4x4::Matrix4x4;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use utils::Lerp;
+use crate::bbox::BBox;
+use crate::bbox::Union;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::quaternion::Quaternion;
+use crate::ray::Ray;
+use crate::ray::RayDifferential;
+use crate::transform::matrix4x4::Matrix4x4;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Lerp;
 
 macro_rules! check_mat {
     ($m1: expr, $m2: expr) => {{
@@ -285,16 +285,16 @@ impl ApplyTransform<RayDifferential> for AnimatedTransform {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bbox::BBox;
-    use geometry::point::Point;
-    use geometry::vector::Dot;
-    use geometry::vector::Vector;
-    use quaternion::Quaternion;
-    use ray::Ray;
-    use ray::RayDifferential;
-    use transform::matrix4x4::Matrix4x4;
-    use transform::transform::Transform;
-    use transform::transform::ApplyTransform;
+    use crate::bbox::BBox;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Dot;
+    use crate::geometry::vector::Vector;
+    use crate::quaternion::Quaternion;
+    use crate::ray::Ray;
+    use crate::ray::RayDifferential;
+    use crate::transform::matrix4x4::Matrix4x4;
+    use crate::transform::transform::Transform;
+    use crate::transform::transform::ApplyTransform;
 
     #[test]
     fn it_can_be_created() {
@@ -1,6 +1,6 @@
-use geometry::vector::Dot;
-use quaternion::Quaternion;
-use utils::Lerp;
+use crate::geometry::vector::Dot;
+use crate::quaternion::Quaternion;
+use crate::utils::Lerp;
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub struct Matrix4x4 {
@@ -341,10 +341,10 @@ impl ::std::convert::From<Matrix4x4> for Quaternion {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use utils::Lerp;
-    use geometry::normal::Normalize;
-    use geometry::vector::Dot;
-    use quaternion::Quaternion;
+    use crate::utils::Lerp;
+    use crate::geometry::normal::Normalize;
+    use crate::geometry::vector::Dot;
+    use crate::quaternion::Quaternion;
 
     macro_rules! check_mat {
         ($m1: expr, $m2: expr) => {{
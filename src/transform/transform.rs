@@ -1,15 +1,16 @@
-use bbox::BBox;
-use bbox::Union;
-use geometry::normal::Normal;
-use geometry::normal::Normalize;
-use geometry::point::Point;
-use geometry::vector::Cross;
-use geometry::vector::Vector;
-use quaternion::Quaternion;
-use ray::Ray;
-use ray::RayDifferential;
-use transform::matrix4x4::Matrix4x4;
-use utils::Degrees;
+use crate::bbox::BBox;
+use crate::bbox::Union;
+use crate::geometry::normal::Normal;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Cross;
+use crate::geometry::vector::Vector;
+use crate::quaternion::Quaternion;
+use crate::ray::Ray;
+use crate::ray::RayDifferential;
+use crate::transform::matrix4x4::Matrix4x4;
+use crate::utils::Degrees;
+use crate::utils::gamma;
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub struct Transform {
@@ -183,6 +184,21 @@ impl Transform {
     }
 
     pub fn get_matrix<'a>(&'a self) -> &'a Matrix4x4 { &(self.m) }
+
+    // Bounds the absolute error of transforming p, which is itself only
+    // known to within p_error, by this affine transform. The result is the
+    // error of xf(p) in each dimension.
+    pub fn xf_point_error(&self, p: &Point, p_error: &Vector) -> Vector {
+        let m = &self.m;
+        let row_error = |i: usize| {
+            (gamma(3) + 1.0) * (m[i][0].abs() * p_error.x + m[i][1].abs() * p_error.y +
+                                m[i][2].abs() * p_error.z) +
+                gamma(3) * ((m[i][0] * p.x).abs() + (m[i][1] * p.y).abs() +
+                            (m[i][2] * p.z).abs() + m[i][3].abs())
+        };
+
+        Vector::new_with(row_error(0), row_error(1), row_error(2))
+    }
 }
 
 pub trait ApplyTransform<T : Clone> {
@@ -332,16 +348,16 @@ impl ::std::ops::IndexMut<usize> for Transform {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bbox::BBox;
-    use geometry::point::Point;
-    use geometry::vector::Vector;
-    use geometry::normal::Normal;
-    use geometry::normal::Normalize;
-    use quaternion::Quaternion;
-    use ray::Ray;
-    use ray::RayDifferential;
-    use transform::matrix4x4::Matrix4x4;
-    use utils::Degrees;
+    use crate::bbox::BBox;
+    use crate::geometry::point::Point;
+    use crate::geometry::vector::Vector;
+    use crate::geometry::normal::Normal;
+    use crate::geometry::normal::Normalize;
+    use crate::quaternion::Quaternion;
+    use crate::ray::Ray;
+    use crate::ray::RayDifferential;
+    use crate::transform::matrix4x4::Matrix4x4;
+    use crate::utils::Degrees;
 
     #[test]
     fn it_can_be_created() {
@@ -665,4 +681,19 @@ mod tests {
         let q = Quaternion::new_with(1.0, 4.0, 16.0, 2.0).normalize();
         assert_eq!(q, Quaternion::from(Transform::from(q.clone())));
     }
+
+    #[test]
+    fn it_bounds_the_error_of_transformed_points() {
+        let xf = Transform::translate(&Vector::new_with(100.0, 0.0, 0.0)) *
+            Transform::scale(2.0, 2.0, 2.0);
+        let p = Point::new_with(1.0, 2.0, 3.0);
+
+        // Errors that the point already had get scaled by the transform...
+        let err = xf.xf_point_error(&p, &Vector::new_with(1e-3, 0.0, 0.0));
+        assert!(err.x >= 2e-3 && err.x < 2e-3 + 1e-4);
+
+        // ... and rounding adds error relative to the size of the result
+        assert!(err.y > 0.0 && err.y < 1e-5);
+        assert!(err.x - 2e-3 > err.y);
+    }
 }
@@ -0,0 +1,279 @@
+use std::f64::consts::PI;
+
+// Returns the largest index i in [0, size - 2] such that pred(i) holds,
+// assuming that pred is true up to some index and false after it.
+pub fn find_interval<F: Fn(usize) -> bool>(size: usize, pred: F) -> usize {
+    let (mut first, mut len) = (0, size);
+    while len > 0 {
+        let half = len >> 1;
+        let middle = first + half;
+        if pred(middle) {
+            first = middle + 1;
+            len -= half + 1;
+        } else {
+            len = half;
+        }
+    }
+
+    first.saturating_sub(1).min(size.saturating_sub(2))
+}
+
+// The weights of the four nodes around x in a Catmull-Rom spline through
+// the given nodes. Returns the index of the first of them, which may be
+// -1 at the start of the nodes where that one's weight is zero, or None
+// if x lies outside of the nodes.
+pub fn catmull_rom_weights(nodes: &[f32], x: f32) -> Option<(i32, [f32; 4])> {
+    let size = nodes.len();
+    if size < 2 || !(x >= nodes[0] && x <= nodes[size - 1]) {
+        return None;
+    }
+
+    // Search for the interval containing x and compute the weights of its
+    // end points
+    let idx = find_interval(size, |i| nodes[i] <= x);
+    let (x0, x1) = (nodes[idx], nodes[idx + 1]);
+    let t = (x - x0) / (x1 - x0);
+    let (t2, t3) = (t * t, t * t * t);
+
+    let mut weights = [0.0, 2.0 * t3 - 3.0 * t2 + 1.0, -2.0 * t3 + 3.0 * t2, 0.0];
+
+    // The derivatives at the ends of the nodes are found from the interval
+    // itself rather than its missing neighbors
+    if idx > 0 {
+        let w0 = (t3 - 2.0 * t2 + t) * (x1 - x0) / (x1 - nodes[idx - 1]);
+        weights[0] = -w0;
+        weights[2] += w0;
+    } else {
+        let w0 = t3 - 2.0 * t2 + t;
+        weights[1] -= w0;
+        weights[2] += w0;
+    }
+
+    if idx + 2 < size {
+        let w3 = (t3 - t2) * (x1 - x0) / (nodes[idx + 2] - x0);
+        weights[1] -= w3;
+        weights[3] = w3;
+    } else {
+        let w3 = t3 - t2;
+        weights[1] -= w3;
+        weights[2] += w3;
+    }
+
+    Some((idx as i32 - 1, weights))
+}
+
+// Integrates the Catmull-Rom spline through the given values, returning
+// the total along with its running integral at each node.
+pub fn integrate_catmull_rom(nodes: &[f32], values: &[f32]) -> (f32, Vec<f32>) {
+    assert_eq!(nodes.len(), values.len());
+    let n = nodes.len();
+    let mut sum = 0.0;
+    let mut cdf = vec![0.0; n];
+    for i in 0..(n.max(1) - 1) {
+        let (x0, x1) = (nodes[i], nodes[i + 1]);
+        let (f0, f1) = (values[i], values[i + 1]);
+        let width = x1 - x0;
+
+        let d0 = if i > 0 {
+            width * (f1 - values[i - 1]) / (x1 - nodes[i - 1])
+        } else {
+            f1 - f0
+        };
+
+        let d1 = if i + 2 < n {
+            width * (values[i + 2] - f0) / (nodes[i + 2] - x0)
+        } else {
+            f1 - f0
+        };
+
+        sum += ((d0 - d1) * (1.0 / 12.0) + (f0 + f1) * 0.5) * width;
+        cdf[i + 1] = sum;
+    }
+
+    (sum, cdf)
+}
+
+// Samples the second dimension of a table of Catmull-Rom splines, which
+// is stored one row for each of nodes1, after interpolating between its
+// rows at alpha. The cdf holds the running integral of each row, as found
+// by integrate_catmull_rom. Returns the sample with the interpolated value
+// there and its density, or None if alpha lies outside of nodes1.
+pub fn sample_catmull_rom_2d(nodes1: &[f32], nodes2: &[f32], values: &[f32], cdf: &[f32],
+                             alpha: f32, u: f32) -> Option<(f32, f32, f32)> {
+    let size2 = nodes2.len();
+    let (offset, weights) = catmull_rom_weights(nodes1, alpha)?;
+    let interpolate = |array: &[f32], idx: usize| {
+        (0..4).filter(|&i| weights[i] != 0.0).fold(0.0, |value, i| {
+            value + array[(offset + i as i32) as usize * size2 + idx] * weights[i]
+        })
+    };
+
+    // Map u onto the spline's integral and find the interval it lies in
+    let maximum = interpolate(cdf, size2 - 1);
+    let u = u * maximum;
+    let idx = find_interval(size2, |i| interpolate(cdf, i) <= u);
+
+    // Look up the values and derivatives at the ends of the interval
+    let (f0, f1) = (interpolate(values, idx), interpolate(values, idx + 1));
+    let (x0, x1) = (nodes2[idx], nodes2[idx + 1]);
+    let width = x1 - x0;
+    let u = (u - interpolate(cdf, idx)) / width;
+
+    let d0 = if idx > 0 {
+        width * (f1 - interpolate(values, idx - 1)) / (x1 - nodes2[idx - 1])
+    } else {
+        f1 - f0
+    };
+
+    let d1 = if idx + 2 < size2 {
+        width * (interpolate(values, idx + 2) - f0) / (nodes2[idx + 2] - x0)
+    } else {
+        f1 - f0
+    };
+
+    // Invert the integral over the interval with a mix of Newton steps and
+    // bisection, starting from the inverse of the linear interpolant
+    let mut t = if f0 != f1 {
+        (f0 - (f0 * f0 + 2.0 * u * (f1 - f0)).max(0.0).sqrt()) / (f0 - f1)
+    } else {
+        u / f0
+    };
+
+    let (mut a, mut b) = (0.0, 1.0);
+    let fhat = loop {
+        if !(t >= a && t <= b) {
+            t = 0.5 * (a + b);
+        }
+
+        let big_fhat = t * (f0 + t * (0.5 * d0 + t * ((1.0 / 3.0) * (-2.0 * d0 - d1) + f1 - f0 +
+                                                      t * (0.25 * (d0 + d1) + 0.5 * (f0 - f1)))));
+        let fhat = f0 + t * (d0 + t * (-2.0 * d0 - d1 + 3.0 * (f1 - f0) +
+                                       t * (d0 + d1 + 2.0 * (f0 - f1))));
+
+        if (big_fhat - u).abs() < 1e-6 || b - a < 1e-6 {
+            break fhat;
+        }
+
+        if big_fhat - u < 0.0 { a = t; } else { b = t; }
+        t -= (big_fhat - u) / fhat;
+    };
+
+    Some((x0 + width * t, fhat, fhat / maximum))
+}
+
+// Evaluates the cosine series with the coefficients a at the angle phi
+pub fn fourier(a: &[f32], cos_phi: f32) -> f32 {
+    let cos_phi = cos_phi as f64;
+    let (mut cos_k_minus_one_phi, mut cos_k_phi) = (cos_phi, 1.0);
+    let mut value = 0.0;
+    for &ak in a.iter() {
+        value += ak as f64 * cos_k_phi;
+        let cos_k_plus_one_phi = 2.0 * cos_phi * cos_k_phi - cos_k_minus_one_phi;
+        cos_k_minus_one_phi = cos_k_phi;
+        cos_k_phi = cos_k_plus_one_phi;
+    }
+
+    value as f32
+}
+
+// Samples an angle in [0, 2pi) proportionally to the cosine series with
+// the coefficients a, where recip holds 1/k for each k. Returns the angle
+// along with the series' value there and its density.
+pub fn sample_fourier(a: &[f32], recip: &[f32], u: f32) -> (f32, f32, f32) {
+    // The series is symmetric, so sample half of it and then mirror it
+    let flip = u >= 0.5;
+    let u = if flip { 1.0 - 2.0 * (u as f64 - 0.5) } else { u as f64 * 2.0 };
+
+    // Find the angle where the integral of the series reaches u with a mix
+    // of Newton steps and bisection
+    let (mut lo, mut hi, mut phi) = (0.0, PI, 0.5 * PI);
+    let a0 = a[0] as f64;
+    let f = loop {
+        let cos_phi = phi.cos();
+        let sin_phi = (1.0 - cos_phi * cos_phi).max(0.0).sqrt();
+        let (mut cos_phi_prev, mut cos_phi_cur) = (cos_phi, 1.0);
+        let (mut sin_phi_prev, mut sin_phi_cur) = (-sin_phi, 0.0);
+
+        let mut big_f = a0 * phi;
+        let mut f = a0;
+        for k in 1..a.len() {
+            let sin_phi_next = 2.0 * cos_phi * sin_phi_cur - sin_phi_prev;
+            let cos_phi_next = 2.0 * cos_phi * cos_phi_cur - cos_phi_prev;
+            sin_phi_prev = sin_phi_cur;
+            sin_phi_cur = sin_phi_next;
+            cos_phi_prev = cos_phi_cur;
+            cos_phi_cur = cos_phi_next;
+
+            big_f += (a[k] * recip[k]) as f64 * sin_phi_next;
+            f += a[k] as f64 * cos_phi_next;
+        }
+        big_f -= u * a0 * PI;
+
+        if big_f > 0.0 { hi = phi; } else { lo = phi; }
+        if big_f.abs() < 1e-6 || hi - lo < 1e-6 {
+            break f;
+        }
+
+        phi -= big_f / f;
+        if !(phi > lo && phi < hi) {
+            phi = 0.5 * (lo + hi);
+        }
+    };
+
+    let phi = if flip { 2.0 * PI - phi } else { phi };
+    (phi as f32, f as f32, (f / (2.0 * PI * a0)) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catmull_rom_splines_reproduce_lines() {
+        let nodes = [0.0, 0.5, 1.5, 2.0, 3.0];
+        let values: Vec<f32> = nodes.iter().map(|x| 2.0 * x + 1.0).collect();
+        for &x in [0.0, 0.25, 0.7, 1.5, 2.9, 3.0].iter() {
+            let (offset, weights) = catmull_rom_weights(&nodes, x).unwrap();
+            let y = (0..4).filter(|&i| weights[i] != 0.0)
+                .fold(0.0, |y, i| y + weights[i] * values[(offset + i as i32) as usize]);
+            assert!((y - (2.0 * x + 1.0)).abs() < 1e-5);
+        }
+        assert!(catmull_rom_weights(&nodes, 3.1).is_none());
+
+        let (total, cdf) = integrate_catmull_rom(&nodes, &values);
+        assert!((total - 12.0).abs() < 1e-5);
+        assert!((cdf[1] - 0.75).abs() < 1e-5);
+
+        // Sampling a line and its mirror image, interpolated half way,
+        // gives a constant
+        let rows = [0.0, 1.0];
+        let mirrored: Vec<f32> = nodes.iter().map(|x| 7.0 - 2.0 * x).collect();
+        let table = [values.clone(), mirrored.clone()].concat();
+        let table_cdf = [cdf, integrate_catmull_rom(&nodes, &mirrored).1].concat();
+        let (x, f, pdf) = sample_catmull_rom_2d(&rows, &nodes, &table, &table_cdf, 0.5, 0.5)
+            .unwrap();
+        assert!((x - 1.5).abs() < 1e-3);
+        assert!((f - 4.0).abs() < 1e-3 && (pdf - 1.0 / 3.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn fourier_series_can_be_sampled() {
+        let a = [1.0, 0.5, 0.25];
+        let recip = [0.0, 1.0, 0.5];
+        let cos_phi = 0.3f32;
+        let phi = cos_phi.acos();
+        let expected = 1.0 + 0.5 * cos_phi + 0.25 * (2.0 * phi).cos();
+        assert!((fourier(&a, cos_phi) - expected).abs() < 1e-5);
+
+        // The density integrates to one and the samples cover every angle
+        let n = 1000;
+        let mut total = 0.0;
+        for i in 0..n {
+            let (phi, f, pdf) = sample_fourier(&a, &recip, (i as f32 + 0.5) / (n as f32));
+            assert!((0.0..2.0 * std::f32::consts::PI).contains(&phi));
+            assert!((f - fourier(&a, phi.cos())).abs() < 1e-4);
+            total += 1.0 / pdf;
+        }
+        assert!((total / (n as f32) - 2.0 * std::f32::consts::PI).abs() < 1e-2);
+    }
+}
@@ -1,7 +1,8 @@
-use bbox::*;
-use geometry::point::Point;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
 
-use utils::partition_by;
+use crate::bbox::*;
+use crate::geometry::point::Point;
 
 #[derive(Debug, PartialEq, Clone)]
 struct KdNode {
@@ -59,7 +60,13 @@ fn recursive_build<NodeData: HasPoint+Clone+::std::fmt::Debug>(build_nodes: &mut
 
     let split_axis = bound.max_extent();
     let split_pos = (build_nodes.len() / 2) - 1;
-    partition_by(build_nodes, |bn| bn.p()[split_axis]);
+
+    // The node at split_pos has to be the median along the split axis for
+    // lookups to be able to skip the far side of it, so a split into halves
+    // isn't enough here.
+    build_nodes.select_nth_unstable_by(split_pos, |a, b| {
+        a.p()[split_axis].partial_cmp(&b.p()[split_axis]).unwrap_or(Ordering::Equal)
+    });
 
     // Allocate kd-tree node and continue recursively
     let node_num = nodes.len();
@@ -108,52 +115,148 @@ impl<NodeData: HasPoint+Clone+::std::fmt::Debug> KdTree<NodeData> {
 
     pub fn size(&self) -> usize { self.nodes.len() }
 
-    fn private_lookup<U: KdTreeProc<NodeData>>(&self, node_num: usize, m: &Point,
-                                               p: &mut U, max_dist_sq: &mut f32) {
+    pub fn node(&self, i: usize) -> &NodeData { &self.node_data[i] }
+
+    // Visits the nodes that lie within max_dist_sq of m, nearest side of
+    // each split first, handing each one's index to visit. visit may shrink
+    // max_dist_sq to cut off the rest of the search.
+    fn private_lookup<F>(&self, node_num: usize, m: &Point, visit: &mut F,
+                         max_dist_sq: &mut f32)
+        where F: FnMut(usize, f32, &mut f32) {
         let node = &self.nodes[node_num];
 
         // Process kd-tree node's children
         let axis = node.split_axis;
         if axis != 3 {
             let dist_sq = (m[axis] - node.split_pos) * (m[axis] - node.split_pos);
-            let look_both = dist_sq <= *max_dist_sq;
-
-            let on_left = m[axis] <= node.split_pos;
             let can_left = node.has_left_child;
-
-            let on_right = m[axis] >= node.split_pos;
             let can_right = node.right_child < self.size();
 
-            let look_left = (look_both || on_left) && can_left;
-            let look_right = (look_both || on_right) && can_right;
-
-            if look_left {
-                self.private_lookup(node_num + 1, m, p, max_dist_sq);
-            }
-
-            if look_right {
-                self.private_lookup(node.right_child, m, p, max_dist_sq);
+            if m[axis] <= node.split_pos {
+                if can_left {
+                    self.private_lookup(node_num + 1, m, visit, max_dist_sq);
+                }
+                if dist_sq <= *max_dist_sq && can_right {
+                    self.private_lookup(node.right_child, m, visit, max_dist_sq);
+                }
+            } else {
+                if can_right {
+                    self.private_lookup(node.right_child, m, visit, max_dist_sq);
+                }
+                if dist_sq <= *max_dist_sq && can_left {
+                    self.private_lookup(node_num + 1, m, visit, max_dist_sq);
+                }
             }
         }
 
         // Hand kd-tree node to processing function
         let dist_sq = (self.node_data[node_num].p() - m).length_squared();
         if dist_sq <= *max_dist_sq {
-            p.run(m, &self.node_data[node_num], dist_sq, max_dist_sq);
+            visit(node_num, dist_sq, max_dist_sq);
         }
     }
 
     pub fn lookup<U: KdTreeProc<NodeData>>(&self, m: &Point, p: &mut U,
                                            max_dist_sq: f32) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut mdsq = max_dist_sq;
+        let mut visit = |i: usize, dist_sq: f32, mdsq: &mut f32| {
+            p.run(m, &self.node_data[i], dist_sq, mdsq)
+        };
+        self.private_lookup(0, m, &mut visit, &mut mdsq);
+    }
+
+    // Finds the nodes nearest to m, up to as many as nearest holds and no
+    // further away than the square root of max_dist_sq. Once enough nodes
+    // have been found the search radius shrinks to the furthest of them,
+    // and the hard limit keeps lookups in empty regions from scanning the
+    // whole tree. The result is left in nearest.
+    pub fn k_nearest(&self, m: &Point, max_dist_sq: f32, nearest: &mut KNearest) {
+        nearest.heap.clear();
+        if self.nodes.is_empty() || nearest.k == 0 {
+            return;
+        }
+
         let mut mdsq = max_dist_sq;
-        self.private_lookup(0, m, p, &mut mdsq);
+        let mut visit = |i: usize, dist_sq: f32, mdsq: &mut f32| {
+            nearest.push(i, dist_sq, mdsq)
+        };
+        self.private_lookup(0, m, &mut visit, &mut mdsq);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct HeapEntry {
+    dist_sq: f32,
+    idx: usize
+}
+
+impl Eq for HeapEntry { }
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &HeapEntry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &HeapEntry) -> Ordering {
+        self.dist_sq.partial_cmp(&other.dist_sq).unwrap_or(Ordering::Equal)
+    }
+}
+
+// A max-heap of the closest nodes found by KdTree::k_nearest, bounded to k
+// of them. Its storage is allocated once up front and reused by every
+// lookup, so photon gathers should keep one per thread instead of creating
+// one each time.
+#[derive(Clone, Debug)]
+pub struct KNearest {
+    k: usize,
+    heap: BinaryHeap<HeapEntry>
+}
+
+impl KNearest {
+    pub fn new(k: usize) -> KNearest {
+        KNearest { k, heap: BinaryHeap::with_capacity(k) }
+    }
+
+    fn push(&mut self, idx: usize, dist_sq: f32, max_dist_sq: &mut f32) {
+        let entry = HeapEntry { dist_sq, idx };
+        if self.heap.len() < self.k {
+            self.heap.push(entry);
+        } else if let Some(mut furthest) = self.heap.peek_mut() {
+            if entry < *furthest {
+                *furthest = entry;
+            }
+        }
+
+        // Only nodes closer than the furthest one found so far matter now
+        if self.heap.len() == self.k {
+            *max_dist_sq = self.heap.peek().map_or(*max_dist_sq, |e| e.dist_sq);
+        }
+    }
+
+    pub fn len(&self) -> usize { self.heap.len() }
+
+    pub fn is_empty(&self) -> bool { self.heap.is_empty() }
+
+    // Squared distance to the furthest node found, if any
+    pub fn max_dist_sq(&self) -> Option<f32> { self.heap.peek().map(|e| e.dist_sq) }
+
+    // The kd-tree indices of the nodes found, along with their squared
+    // distances, in no particular order. Use KdTree::node to get at them.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+        self.heap.iter().map(|e| (e.idx, e.dist_sq))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use geometry::point::Point;
+    use crate::geometry::point::Point;
 
     struct PointCounter {
         counter: usize
@@ -269,4 +372,55 @@ mod tests {
             assert_eq!(ctr.counter, 1);
         }
     }
+
+    #[test]
+    fn it_can_find_the_nearest_points() {
+        use crate::rng::RNG;
+
+        let mut rng = RNG::new(0);
+        let points: Vec<Point> = (0..500).map(|_| {
+            Point::new_with(rng.random_float(), rng.random_float(), rng.random_float())
+        }).collect();
+        let kdtree = KdTree::new(&points);
+
+        // The same gather is reused across lookups
+        let mut nearest = KNearest::new(8);
+        for _ in 0..20 {
+            let m = Point::new_with(rng.random_float(), rng.random_float(), rng.random_float());
+            kdtree.k_nearest(&m, f32::INFINITY, &mut nearest);
+            assert_eq!(nearest.len(), 8);
+
+            let mut expected: Vec<f32> =
+                points.iter().map(|p| (p - &m).length_squared()).collect();
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            let mut found: Vec<f32> = nearest.iter().map(|(i, d)| {
+                assert_eq!((kdtree.node(i) - &m).length_squared(), d);
+                d
+            }).collect();
+            found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(found, expected[..8].to_vec());
+            assert_eq!(nearest.max_dist_sq(), Some(expected[7]));
+        }
+    }
+
+    #[test]
+    fn it_respects_the_max_radius_when_gathering() {
+        let mut points = box_at(1.0);
+        points.append(&mut box_at(2.0));
+        let kdtree = KdTree::new(&points);
+
+        let mut nearest = KNearest::new(4);
+        kdtree.k_nearest(&Point::new_with(1.5, 1.5, 1.5), 0.76, &mut nearest);
+        assert_eq!(nearest.len(), 2);
+
+        // Nothing is around the origin within the limit
+        kdtree.k_nearest(&Point::new(), 3f32 - 1e-6, &mut nearest);
+        assert!(nearest.is_empty());
+        assert_eq!(nearest.max_dist_sq(), None);
+
+        kdtree.k_nearest(&Point::new(), 12f32 + 1e-6, &mut nearest);
+        assert_eq!(nearest.len(), 4);
+        assert!(nearest.iter().all(|(_, d)| (d - 3.0).abs() < 1e-5));
+    }
 }
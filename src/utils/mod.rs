@@ -1,5 +1,7 @@
 pub mod kdtree;
 pub mod blocked_vec;
+pub mod interpolation;
+pub mod octree;
 
 use std::ops::Add;
 use std::ops::Mul;
@@ -53,6 +55,17 @@ impl Degrees for f64 {
     }
 }
 
+// Half of the gap between one and the next float, which bounds the relative
+// error of a single correctly rounded floating-point operation
+pub const MACHINE_EPSILON: f32 = f32::EPSILON * 0.5;
+
+// A conservative bound on the relative error that accumulates over n
+// floating-point operations, as in Higham (2002)
+pub fn gamma(n: i32) -> f32 {
+    let n_eps = n as f32 * MACHINE_EPSILON;
+    n_eps / (1.0 - n_eps)
+}
+
 pub fn quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
     // Find quadratic discriminant
     let descrim = b * b - 4f32 * a * c;
@@ -509,9 +522,9 @@ mod tests {
     #[test]
     fn it_can_partition_points() {
         let mut pts = vec![
-            ::geometry::point::Point::new_with(1.0, 1.0, -1.0),
-            ::geometry::point::Point::new_with(-2.0, 2.0, -2.0),
-            ::geometry::point::Point::new_with(2.0, 2.0, -2.0)];
+            crate::geometry::point::Point::new_with(1.0, 1.0, -1.0),
+            crate::geometry::point::Point::new_with(-2.0, 2.0, -2.0),
+            crate::geometry::point::Point::new_with(2.0, 2.0, -2.0)];
         partition_by(&mut pts, |p| p[0]);
         assert_eq!(pts[0][0], -2.0);
     }
@@ -0,0 +1,205 @@
+use crate::bbox::BBox;
+use crate::geometry::point::Point;
+
+const OCTREE_MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone)]
+struct OctNode<NodeData> {
+    children: [Option<Box<OctNode<NodeData>>>; 8],
+    data: Vec<NodeData>
+}
+
+impl<NodeData> OctNode<NodeData> {
+    fn new() -> OctNode<NodeData> {
+        OctNode {
+            children: [None, None, None, None, None, None, None, None],
+            data: Vec::new()
+        }
+    }
+}
+
+fn child_bound(child: usize, node_bound: &BBox, p_mid: &Point) -> BBox {
+    let mut child_bound = BBox::new();
+    for axis in 0..3 {
+        let bit = 4 >> axis;
+        if (child & bit) != 0 {
+            child_bound.p_min[axis] = p_mid[axis];
+            child_bound.p_max[axis] = node_bound.p_max[axis];
+        } else {
+            child_bound.p_min[axis] = node_bound.p_min[axis];
+            child_bound.p_max[axis] = p_mid[axis];
+        }
+    }
+    child_bound
+}
+
+fn midpoint(b: &BBox) -> Point {
+    0.5 * &b.p_min + 0.5 * &b.p_max
+}
+
+fn add_private<NodeData: Clone>(node: &mut OctNode<NodeData>, node_bound: &BBox,
+                                item: NodeData, data_bound: &BBox,
+                                diag2: f32, depth: usize) {
+    // Possibly add data item to current octree node
+    if depth == OCTREE_MAX_DEPTH ||
+        node_bound.p_min.distance_squared(&node_bound.p_max) < diag2 {
+        node.data.push(item);
+        return;
+    }
+
+    // Otherwise add data item to octree children
+    let p_mid = midpoint(node_bound);
+
+    // Determine which children the item overlaps
+    let overlaps = |child: usize, axis: usize| {
+        if (child & (4 >> axis)) != 0 {
+            data_bound.p_max[axis] > p_mid[axis]
+        } else {
+            data_bound.p_min[axis] <= p_mid[axis]
+        }
+    };
+
+    // Items that straddle several children are stored in each of them
+    for child in 0..8 {
+        if !(0..3).all(|axis| overlaps(child, axis)) {
+            continue;
+        }
+
+        let cb = child_bound(child, node_bound, &p_mid);
+        let node_child = node.children[child]
+            .get_or_insert_with(|| Box::new(OctNode::new()));
+        add_private(node_child, &cb, item.clone(), data_bound, diag2, depth + 1);
+    }
+}
+
+fn lookup_private<NodeData, F>(node: &OctNode<NodeData>, node_bound: &BBox,
+                               p: &Point, process: &mut F) -> bool
+    where F: FnMut(&NodeData) -> bool {
+    for data in node.data.iter() {
+        if !process(data) {
+            return false;
+        }
+    }
+
+    // Determine which octree child node p is inside
+    let p_mid = midpoint(node_bound);
+    let child =
+        (if p.x > p_mid.x { 4 } else { 0 }) +
+        (if p.y > p_mid.y { 2 } else { 0 }) +
+        (if p.z > p_mid.z { 1 } else { 0 });
+
+    match node.children[child] {
+        Some(ref node_child) => {
+            let cb = child_bound(child, node_bound, &p_mid);
+            lookup_private(node_child, &cb, p, process)
+        },
+        None => true
+    }
+}
+
+// An octree that stores items with spatial extent. Each item is stored in
+// every node that it overlaps whose size is on the same order as the item
+// itself, so a point lookup only needs to visit the nodes along the path
+// from the root to the leaf that contains the point.
+#[derive(Debug, Clone)]
+pub struct Octree<NodeData> {
+    bound: BBox,
+    root: OctNode<NodeData>
+}
+
+impl<NodeData: Clone> Octree<NodeData> {
+    pub fn new(b: BBox) -> Octree<NodeData> {
+        Octree { bound: b, root: OctNode::new() }
+    }
+
+    pub fn bound(&self) -> &BBox { &self.bound }
+
+    pub fn add(&mut self, item: NodeData, data_bound: &BBox) {
+        if !self.bound.overlaps(data_bound) {
+            return;
+        }
+
+        let diag2 = data_bound.p_min.distance_squared(&data_bound.p_max);
+        let bound = self.bound.clone();
+        add_private(&mut self.root, &bound, item, data_bound, diag2, 0);
+    }
+
+    // Calls process with every item stored in a node that contains p. The
+    // lookup stops early if process returns false.
+    pub fn lookup<F>(&self, p: &Point, mut process: F)
+        where F: FnMut(&NodeData) -> bool {
+        if !self.bound.inside(p) {
+            return;
+        }
+
+        lookup_private(&self.root, &self.bound, p, &mut process);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_octree() -> Octree<usize> {
+        Octree::new(BBox::new_with(Point::new_with(0.0, 0.0, 0.0),
+                                   Point::new_with(1.0, 1.0, 1.0)))
+    }
+
+    fn bound_around(p: Point, r: f32) -> BBox {
+        BBox::new_with(Point::new_with(p.x - r, p.y - r, p.z - r),
+                       Point::new_with(p.x + r, p.y + r, p.z + r))
+    }
+
+    fn found_at(tree: &Octree<usize>, p: Point) -> Vec<usize> {
+        let mut found = Vec::new();
+        tree.lookup(&p, |&x| { found.push(x); true });
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    #[test]
+    fn it_can_find_items_containing_a_point() {
+        let mut tree = unit_octree();
+        tree.add(0, &bound_around(Point::new_with(0.1, 0.1, 0.1), 0.05));
+        tree.add(1, &bound_around(Point::new_with(0.9, 0.9, 0.9), 0.05));
+        tree.add(2, &bound_around(Point::new_with(0.5, 0.5, 0.5), 0.5));
+
+        assert_eq!(found_at(&tree, Point::new_with(0.1, 0.1, 0.1)), vec![0, 2]);
+        assert_eq!(found_at(&tree, Point::new_with(0.9, 0.9, 0.9)), vec![1, 2]);
+        assert_eq!(found_at(&tree, Point::new_with(0.5, 0.1, 0.9)), vec![2]);
+    }
+
+    #[test]
+    fn it_stores_items_that_straddle_children_in_each_child() {
+        let mut tree = unit_octree();
+        tree.add(7, &bound_around(Point::new_with(0.5, 0.5, 0.5), 0.01));
+
+        assert_eq!(found_at(&tree, Point::new_with(0.495, 0.495, 0.495)), vec![7]);
+        assert_eq!(found_at(&tree, Point::new_with(0.505, 0.505, 0.505)), vec![7]);
+        assert_eq!(found_at(&tree, Point::new_with(0.495, 0.505, 0.495)), vec![7]);
+        assert!(found_at(&tree, Point::new_with(0.25, 0.25, 0.25)).is_empty());
+    }
+
+    #[test]
+    fn it_ignores_items_and_lookups_outside_its_bounds() {
+        let mut tree = unit_octree();
+        tree.add(3, &bound_around(Point::new_with(2.0, 2.0, 2.0), 0.1));
+        assert!(found_at(&tree, Point::new_with(2.0, 2.0, 2.0)).is_empty());
+
+        tree.add(4, &bound_around(Point::new_with(0.2, 0.2, 0.2), 0.1));
+        assert!(found_at(&tree, Point::new_with(-0.2, 0.2, 0.2)).is_empty());
+    }
+
+    #[test]
+    fn it_can_stop_lookups_early() {
+        let mut tree = unit_octree();
+        for i in 0..10 {
+            tree.add(i, &bound_around(Point::new_with(0.5, 0.5, 0.5), 0.5));
+        }
+
+        let mut count = 0;
+        tree.lookup(&Point::new_with(0.5, 0.5, 0.5), |_| { count += 1; count < 3 });
+        assert_eq!(count, 3);
+    }
+}
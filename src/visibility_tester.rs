@@ -1,34 +1,50 @@
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use sampler::sample::Sample;
-use scene::Scene;
-use spectrum::Spectrum;
-use ray::Ray;
-use ray::RayDifferential;
-use renderer::Renderer;
-use rng::RNG;
+use crate::geometry::normal::Normal;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::sampler::sample::Sample;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::ray::Ray;
+use crate::ray::RayDifferential;
+use crate::ray::RayKind;
+use crate::ray::offset_ray_origin;
+use crate::renderer::Renderer;
+use crate::rng::RNG;
+
+// How much shorter than the distance between its ends a segment is, so that
+// it doesn't hit the surface of a light that it ends on
+const SHADOW_EPSILON: f32 = 1e-4;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct VisibilityTester(Ray);
 
 impl VisibilityTester {
-    pub fn segment(p1: Point, eps1: f32, p2: Point, eps2: f32, time: f32)
-                   -> VisibilityTester {
+    pub fn segment(p1: Point, p2: Point, time: f32) -> VisibilityTester {
         let dist = p1.distance(&p2);
         let dir = (p2 - &p1) / dist;
-        let mut r = Ray::new_with(p1, dir, eps1);
-        r.set_maxt((1.0 - eps2) * dist);
+        let mut r = Ray::new_with(p1, dir, 0.0).with_kind(RayKind::Shadow);
+        r.set_maxt((1.0 - SHADOW_EPSILON) * dist);
         r.set_time(time);
         VisibilityTester(r)
     }
 
-    pub fn ray(p: Point, eps: f32, w: Vector, time: f32) -> VisibilityTester {
-        let mut r = Ray::new_with(p, w, eps);
+    pub fn ray(p: Point, w: Vector, time: f32) -> VisibilityTester {
+        let mut r = Ray::new_with(p, w, 0.0).with_kind(RayKind::Shadow);
         r.set_time(time);
         VisibilityTester(r)
     }
 
+    // Moves the start of the test off of the surface that it leaves, whose
+    // normal there is n and whose point is only known to within p_error.
+    // The offset is much smaller than SHADOW_EPSILON of any segment, so the
+    // end of the test stays put.
+    pub fn leaving_surface(self, p_error: &Vector, n: &Normal) -> VisibilityTester {
+        let VisibilityTester(mut r) = self;
+        r.o = offset_ray_origin(&r.o, p_error, n, &r.d);
+        VisibilityTester(r)
+    }
+
     pub fn unoccluded(&self, scene: &Scene) -> bool {
         ! scene.intersect_p(&self.0)
     }
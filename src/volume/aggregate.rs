@@ -1,14 +1,14 @@
-use bbox::BBox;
-use bbox::HasBounds;
-use bbox::Union;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use ray::Ray;
-use spectrum::Spectrum;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use volume::VolumeRegion;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::bbox::Union;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::volume::VolumeRegion;
 
 use std::f32;
 use std::sync::Arc;
@@ -74,9 +74,9 @@ impl VolumeRegion for AggregateVolumeRegion {
         ph / sum
     }
 
-    fn tau(&self, ray: &Ray, t0: f32, t1: f32) -> Spectrum {
+    fn tau(&self, ray: &Ray, step_size: f32, offset: f32) -> Spectrum {
         self.regions.iter().fold(Spectrum::from(0.0), |old_s, r| {
-            old_s + r.tau(ray, t0, t1)
+            old_s + r.tau(ray, step_size, offset)
         })
     }
 }
@@ -1,17 +1,17 @@
-use bbox::BBox;
-use bbox::HasBounds;
-use geometry::normal::Normalize;
-use geometry::point::Point;
-use geometry::vector::Dot;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use ray::Ray;
-use spectrum::Spectrum;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use utils::Lerp;
-use volume::density::internal::DensityRegion;
-use volume::VolumeRegion;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::geometry::normal::Normalize;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Lerp;
+use crate::volume::density::internal::DensityRegion;
+use crate::volume::VolumeRegion;
 
 #[derive(Clone, Debug, PartialEq)]
 struct ExponentialDensity {
@@ -1,22 +1,23 @@
 pub mod exponential;
 pub mod volume_grid;
 
-use bbox::HasBounds;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use ray::Ray;
-use spectrum::Spectrum;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use volume::VolumeRegion;
+use crate::bbox::HasBounds;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::volume::VolumeRegion;
+use crate::volume::majorant::MajorantGrid;
 
 use std::fmt::Debug;
 use std::ops::Deref;
 use std::marker::Send;
 use std::marker::Sync;
 
-use volume::phase_hg;
+use crate::volume::phase_hg;
 
 mod internal {
     use super::*;
@@ -29,6 +30,9 @@ mod internal {
         fn world_to_volume(&self) -> Transform;
 
         fn density(&self, p: Point) -> f32;
+
+        // Regions that can bound their density exactly over a res^3 grid
+        fn density_majorants(&self, _: usize) -> Option<MajorantGrid> { None }
     }
 }
 
@@ -51,7 +55,36 @@ impl<T: internal::DensityRegion +
         phase_hg(w, wp, self.get_g())
     }
 
-    fn tau(&self, _: &Ray, _: f32, _: f32) -> Spectrum {
-        unimplemented!()
+    fn majorants(&self, res: usize) -> MajorantGrid {
+        self.density_majorants(res).unwrap_or_else(|| MajorantGrid::sampled(self, res))
+    }
+
+    fn tau(&self, r: &Ray, step_size: f32, offset: f32) -> Spectrum {
+        let length = r.d.length();
+        if length == 0.0 {
+            return Spectrum::from(0.0);
+        }
+
+        // Work with a normalized ray so that step_size is a world space
+        // distance
+        let mut rn = Ray::new_with(r.o.clone(), &r.d / length, r.mint() * length);
+        rn.set_maxt(r.maxt() * length);
+        rn.set_time(r.time);
+
+        let (mut t0, t1) = match self.intersect(&rn) {
+            Some(ts) => ts,
+            None => return Spectrum::from(0.0)
+        };
+
+        // Accumulate sigma_t at regular steps along the ray
+        let w = -(&rn.d);
+        let mut tau = Spectrum::from(0.0);
+        t0 += offset * step_size;
+        while t0 < t1 {
+            tau = tau + self.sigma_t(&rn.point_at(t0), &w, r.time);
+            t0 += step_size;
+        }
+
+        tau * step_size
     }
 }
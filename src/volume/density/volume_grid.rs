@@ -1,18 +1,19 @@
-use bbox::BBox;
-use bbox::HasBounds;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use ray::Ray;
-use spectrum::Spectrum;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use utils::Lerp;
-use volume::density::internal::DensityRegion;
-use volume::VolumeRegion;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::utils::Lerp;
+use crate::volume::density::internal::DensityRegion;
+use crate::volume::VolumeRegion;
+use crate::volume::majorant::MajorantGrid;
 
 #[derive(Clone, Debug, PartialEq)]
-struct VolumeGridDensity {
+pub struct VolumeGridDensity {
     sigma_a: Spectrum,
     sigma_s: Spectrum,
     g: f32,
@@ -69,6 +70,13 @@ impl DensityRegion for VolumeGridDensity {
     fn get_g(&self) -> f32 { self.g }
     fn world_to_volume(&self) -> Transform { self.world_to_volume.clone() }
 
+    fn density_majorants(&self, res: usize) -> Option<MajorantGrid> {
+        let sigma_t = (self.sigma_a + self.sigma_s).max_component_value();
+        Some(MajorantGrid::from_voxels(self.extent.clone(), self.world_to_volume.clone(),
+                                       (self.nx, self.ny, self.nz), &self.density,
+                                       sigma_t, res))
+    }
+
     fn density(&self, p_obj: Point) -> f32 {
         if !self.extent.inside(&p_obj) {
             return 0.0;
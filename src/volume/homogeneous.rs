@@ -1,15 +1,15 @@
-use bbox::BBox;
-use bbox::HasBounds;
-use geometry::point::Point;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use ray::Ray;
-use spectrum::Spectrum;
-use transform::transform::ApplyTransform;
-use transform::transform::Transform;
-use volume::VolumeRegion;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::volume::VolumeRegion;
 
-use volume::phase_schlick;
+use crate::volume::phase_schlick;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct HomogeneousVolumeDensity {
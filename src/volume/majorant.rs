@@ -0,0 +1,387 @@
+use crate::bbox::BBox;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Vector;
+use crate::ray::Ray;
+use crate::rng::RNG;
+use crate::spectrum::Spectrum;
+use crate::transform::transform::ApplyTransform;
+use crate::transform::transform::Transform;
+use crate::volume::VolumeRegion;
+
+// The number of samples taken along each axis of a cell when estimating
+// the extinction within it
+const SAMPLES_PER_CELL: usize = 4;
+
+// A coarse grid over a volume region that records the largest and smallest
+// extinction within each of its cells. Ray marching uses it to skip over
+// empty space and to take steps that are as large as the density allows,
+// and delta tracking uses it to sample collisions against a tight bound.
+// The grid covers bounds in its own space, which world_to_grid maps to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MajorantGrid {
+    bounds: BBox,
+    world_to_grid: Transform,
+    res: [usize; 3],
+    max_sigma_t: Vec<f32>,
+    min_sigma_t: Vec<f32>
+}
+
+impl MajorantGrid {
+    // Estimates the extinction in each cell of a res^3 grid over the world
+    // bound of the region from a lattice of samples that includes the cell
+    // corners. Features that are smaller than the spacing of the samples
+    // can fall between them.
+    pub fn sampled<V: VolumeRegion + ?Sized>(vr: &V, res: usize) -> MajorantGrid {
+        let bounds = vr.world_bound();
+        let res = res.max(1);
+        let w = Vector::new_with(0.0, 0.0, 1.0);
+        let mut max_sigma_t = Vec::with_capacity(res * res * res);
+        let mut min_sigma_t = Vec::with_capacity(res * res * res);
+        let lattice = |i: usize, j: usize| {
+            ((i * (SAMPLES_PER_CELL - 1) + j) as f32) / ((res * (SAMPLES_PER_CELL - 1)) as f32)
+        };
+
+        for z in 0..res {
+            for y in 0..res {
+                for x in 0..res {
+                    let mut hi: f32 = 0.0;
+                    let mut lo = f32::INFINITY;
+                    for k in 0..SAMPLES_PER_CELL {
+                        for j in 0..SAMPLES_PER_CELL {
+                            for i in 0..SAMPLES_PER_CELL {
+                                let p = bounds.lerp_point(
+                                    lattice(x, i), lattice(y, j), lattice(z, k));
+                                let s = vr.sigma_t(&p, &w, 0.0).max_component_value();
+                                hi = hi.max(s);
+                                lo = lo.min(s);
+                            }
+                        }
+                    }
+                    max_sigma_t.push(hi);
+                    min_sigma_t.push(lo);
+                }
+            }
+        }
+
+        MajorantGrid {
+            bounds,
+            world_to_grid: Transform::new(),
+            res: [res, res, res],
+            max_sigma_t,
+            min_sigma_t
+        }
+    }
+
+    // Bounds the trilinearly interpolated density of an nx X ny X nz voxel
+    // grid over extent exactly, by taking the extremes of every voxel that
+    // contributes to each cell of a res^3 grid, scaled by sigma_t.
+    pub fn from_voxels(extent: BBox, world_to_grid: Transform,
+                       (nx, ny, nz): (usize, usize, usize), density: &[f32],
+                       sigma_t: f32, res: usize) -> MajorantGrid {
+        let res = [res.clamp(1, nx), res.clamp(1, ny), res.clamp(1, nz)];
+
+        // The voxels that contribute to the points of cell c along an axis
+        // with n voxels, which sit at the centers of n equal intervals
+        let voxels = |c: usize, n: usize, r: usize| {
+            let lo = ((c * n) as f32) / (r as f32) - 0.5;
+            let hi = (((c + 1) * n) as f32) / (r as f32) - 0.5;
+            let v0 = (lo.floor().max(0.0) as usize).min(n - 1);
+            let v1 = ((hi.floor() + 1.0).max(0.0) as usize).min(n - 1);
+            v0..=v1
+        };
+
+        let mut max_sigma_t = Vec::with_capacity(res[0] * res[1] * res[2]);
+        let mut min_sigma_t = Vec::with_capacity(res[0] * res[1] * res[2]);
+        for z in 0..res[2] {
+            for y in 0..res[1] {
+                for x in 0..res[0] {
+                    let mut hi: f32 = 0.0;
+                    let mut lo = f32::INFINITY;
+                    for vz in voxels(z, nz, res[2]) {
+                        for vy in voxels(y, ny, res[1]) {
+                            for vx in voxels(x, nx, res[0]) {
+                                let d = density[(vz * ny + vy) * nx + vx];
+                                hi = hi.max(d);
+                                lo = lo.min(d);
+                            }
+                        }
+                    }
+                    max_sigma_t.push(hi * sigma_t);
+                    min_sigma_t.push(lo * sigma_t);
+                }
+            }
+        }
+
+        MajorantGrid { bounds: extent, world_to_grid, res, max_sigma_t, min_sigma_t }
+    }
+
+    pub fn resolution(&self) -> [usize; 3] { self.res }
+
+    fn cell_size(&self) -> Vector {
+        let d = &self.bounds.p_max - &self.bounds.p_min;
+        Vector::new_with(d.x / (self.res[0] as f32), d.y / (self.res[1] as f32),
+                         d.z / (self.res[2] as f32))
+    }
+
+    fn cell(&self, p: &Point) -> [usize; 3] {
+        let o = self.bounds.offset(p);
+        let mut c = [0; 3];
+        for (i, ci) in c.iter_mut().enumerate() {
+            *ci = ((o[i] * (self.res[i] as f32)).max(0.0) as usize).min(self.res[i] - 1);
+        }
+        c
+    }
+
+    fn index(&self, c: [usize; 3]) -> usize {
+        (c[2] * self.res[1] + c[1]) * self.res[0] + c[0]
+    }
+
+    // The largest extinction within the cell containing the world space
+    // point p
+    pub fn max_sigma_t(&self, p: &Point) -> f32 {
+        self.max_sigma_t[self.index(self.cell(&self.world_to_grid.t(p)))]
+    }
+
+    // Returns the parametric distance from t to where the ray, given in
+    // grid space, leaves the cell that it is in at t, along with the index
+    // of that cell. Points right on the boundary of a cell still make some
+    // progress.
+    fn cell_exit(&self, ray: &Ray, t: f32) -> (f32, usize) {
+        let p = ray.point_at(t);
+        let c = self.cell(&p);
+        let size = self.cell_size();
+
+        // Find the distance to where the ray leaves the cell
+        let mut t_exit = f32::INFINITY;
+        for i in 0..3 {
+            let lo = self.bounds.p_min[i] + (c[i] as f32) * size[i];
+            let edge = if ray.d[i] > 0.0 { lo + size[i] } else { lo };
+            if ray.d[i] != 0.0 {
+                t_exit = t_exit.min((edge - p[i]) / ray.d[i]);
+            }
+        }
+
+        let min_size = size.x.min(size.y).min(size.z);
+        (t_exit.max(1e-4 * min_size / ray.d.length()), self.index(c))
+    }
+
+    // Returns the parametric length of the next ray marching step from t
+    // along the world space ray, and whether the region that it covers is
+    // empty. Steps never leave the cell containing the ray at t, so that
+    // its bounds hold over all of them. Within a cell, the optical
+    // thickness of a step is at most cfl, and steps are shorter where the
+    // extinction changes more quickly.
+    pub fn step(&self, ray: &Ray, t: f32, cfl: f32) -> (f32, bool) {
+        let ray_g = self.world_to_grid.t(ray);
+        let (t_exit, idx) = self.cell_exit(&ray_g, t);
+        let (hi, lo) = (self.max_sigma_t[idx], self.min_sigma_t[idx]);
+        if hi <= 0.0 {
+            return (t_exit, true);
+        }
+
+        // Extinction is given per world space distance
+        let mut dt = cfl / (hi * ray.d.length());
+        if hi > lo {
+            let size = self.cell_size();
+            let min_size = size.x.min(size.y).min(size.z);
+            dt = dt.min(cfl * hi / (hi - lo) * min_size / ray_g.d.length());
+        }
+
+        (dt.min(t_exit), false)
+    }
+
+    // Walks the cells that the world space ray passes through between t0
+    // and t1, calling f with the start and end of the ray's span in each of
+    // them and the cell's majorant, until it returns false
+    fn walk<F: FnMut(f32, f32, f32) -> bool>(&self, ray: &Ray, t0: f32, t1: f32, mut f: F) {
+        let ray_g = self.world_to_grid.t(ray);
+        let mut t = t0;
+        while t < t1 {
+            let (dt, idx) = self.cell_exit(&ray_g, t);
+            let t_end = (t + dt).min(t1);
+            if !f(t, t_end, self.max_sigma_t[idx]) {
+                return;
+            }
+            t = t_end;
+        }
+    }
+
+    // Delta tracking: samples the distance along the ray to the first
+    // collision with the medium between t0 and t1, if there is one. Cells
+    // with a majorant of zero are skipped over entirely.
+    pub fn sample_collision(&self, vr: &dyn VolumeRegion, ray: &Ray, t0: f32, t1: f32,
+                            rng: &mut RNG) -> Option<f32> {
+        let len = ray.d.length();
+        let w = -(&ray.d);
+        let mut collision = None;
+        self.walk(ray, t0, t1, |mut t, t_end, majorant| {
+            if majorant <= 0.0 {
+                return true;
+            }
+
+            loop {
+                t -= (1.0 - rng.random_float()).ln() / (majorant * len);
+                if t >= t_end {
+                    return true;
+                }
+
+                // Real collisions happen with probability sigma_t / majorant
+                let sigma_t = vr.sigma_t(&ray.point_at(t), &w, ray.time).max_component_value();
+                if rng.random_float() * majorant < sigma_t {
+                    collision = Some(t);
+                    return false;
+                }
+            }
+        });
+        collision
+    }
+
+    // Ratio tracking: an unbiased estimate of the transmittance along the
+    // ray between t0 and t1 that, rather than stopping at the first real
+    // collision, weights the path by the chance that each tentative one
+    // wasn't real
+    pub fn transmittance(&self, vr: &dyn VolumeRegion, ray: &Ray, t0: f32, t1: f32,
+                         rng: &mut RNG) -> Spectrum {
+        let len = ray.d.length();
+        let w = -(&ray.d);
+        let mut tr = Spectrum::from(1.0);
+        self.walk(ray, t0, t1, |mut t, t_end, majorant| {
+            if majorant <= 0.0 {
+                return true;
+            }
+
+            loop {
+                t -= (1.0 - rng.random_float()).ln() / (majorant * len);
+                if t >= t_end {
+                    return true;
+                }
+
+                let sigma_t = vr.sigma_t(&ray.point_at(t), &w, ray.time);
+                tr = tr * (Spectrum::from(1.0) - sigma_t / majorant);
+                if tr.is_black() {
+                    return false;
+                }
+            }
+        });
+        tr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbox::HasBounds;
+    use crate::geometry::point::Point;
+    use crate::intersection::Intersectable;
+    use crate::spectrum::Spectrum;
+    use crate::transform::transform::Transform;
+    use crate::volume::density::volume_grid::VolumeGridDensity;
+    use crate::volume::homogeneous::HomogeneousVolumeDensity;
+
+    fn unit_cube(sig_t: f32) -> HomogeneousVolumeDensity {
+        HomogeneousVolumeDensity::new(
+            Spectrum::from(sig_t), Spectrum::from(0.0), 0.0, Spectrum::from(0.0),
+            BBox::new_with(Point::new_with(0.0, 0.0, 0.0), Point::new_with(1.0, 1.0, 1.0)),
+            Transform::new())
+    }
+
+    #[test]
+    fn it_bounds_the_extinction_in_each_cell() {
+        let grid = MajorantGrid::sampled(&unit_cube(2.0), 4);
+        assert_eq!(grid.max_sigma_t(&Point::new_with(0.1, 0.5, 0.9)), 2.0);
+        assert_eq!(grid.max_sigma_t(&Point::new_with(1.0, 1.0, 1.0)), 2.0);
+
+        let ray = Ray::new_with(Point::new_with(0.0, 0.1, 0.1),
+                                Vector::new_with(2.0, 0.0, 0.0), 0.0);
+
+        // Steps are limited by the optical thickness...
+        let (dt, empty) = grid.step(&ray, 0.0, 0.1);
+        assert!(!empty);
+        assert!((dt - 0.025).abs() < 1e-6);
+
+        // ... and by the cell boundaries
+        let (dt, _) = grid.step(&ray, 0.0, 10.0);
+        assert!((dt - 0.125).abs() < 1e-6);
+        let (dt, _) = grid.step(&ray, 0.1, 10.0);
+        assert!((dt - 0.025).abs() < 1e-6);
+    }
+
+    #[test]
+    fn it_skips_empty_cells() {
+        let grid = MajorantGrid::sampled(&unit_cube(0.0), 2);
+        let ray = Ray::new_with(Point::new_with(0.0, 0.1, 0.1),
+                                Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        assert_eq!(grid.step(&ray, 0.0, 0.1), (0.5, true));
+        assert_eq!(grid.step(&ray, 0.25, 0.1), (0.25, true));
+    }
+
+    // A puff of smoke in the middle of a mostly empty, rotated voxel grid
+    fn puff() -> VolumeGridDensity {
+        let n = 12;
+        let d = (0..(n * n * n)).map(|i| {
+            let (x, y, z) = (i % n, (i / n) % n, i / (n * n));
+            let c = |v: usize| ((v as f32) + 0.5) / (n as f32) - 0.5;
+            let r = (c(x) * c(x) + c(y) * c(y) + c(z) * c(z)).sqrt();
+            (4.0 * (0.3 - r)).max(0.0)
+        }).collect();
+        let v2w = &Transform::translate(&Vector::new_with(1.0, 0.0, 0.0))
+            * &Transform::rotate_z(30.0);
+        VolumeGridDensity::new(
+            Spectrum::from(1.0), Spectrum::from(1.0), 0.0, Spectrum::from(0.0),
+            BBox::new_with(Point::new_with(0.0, 0.0, 0.0), Point::new_with(1.0, 1.0, 1.0)),
+            v2w, n, n, n, d)
+    }
+
+    #[test]
+    fn it_bounds_voxel_grids_exactly() {
+        let vr = puff();
+        let grid = vr.majorants(4);
+        assert_eq!(grid.resolution(), [4, 4, 4]);
+
+        let bound = vr.world_bound();
+        let w = Vector::new_with(0.0, 0.0, 1.0);
+        let mut rng = RNG::new(0);
+        let mut empty = 0;
+        for _ in 0..4096 {
+            let p = bound.lerp_point(rng.random_float(), rng.random_float(),
+                                     rng.random_float());
+            let sigma_t = vr.sigma_t(&p, &w, 0.0).y();
+            assert!(sigma_t <= grid.max_sigma_t(&p) + 1e-5);
+            if grid.max_sigma_t(&p) == 0.0 {
+                empty += 1;
+            }
+        }
+
+        // The corners of the grid are empty and can be skipped
+        assert!(empty > 0);
+    }
+
+    #[test]
+    fn tracking_matches_the_brute_force_march() {
+        let vr = puff();
+        let grid = vr.majorants(4);
+        let ray = Ray::new_with(Point::new_with(0.0, 0.6, 0.5),
+                                Vector::new_with(1.0, 0.0, 0.0), 0.0);
+        let (t0, t1) = vr.intersect(&ray).unwrap();
+        let expected = (-vr.tau(&ray, 1e-4, 0.5)).exp().y();
+        assert!(expected < 0.9 && expected > 0.1, "Transmittance: {}", expected);
+
+        let mut rng = RNG::new(0);
+        let n = 4000;
+        let ratio = (0..n).map(|_| grid.transmittance(&vr, &ray, t0, t1, &mut rng).y())
+            .sum::<f32>() / (n as f32);
+        assert!((ratio - expected).abs() < 0.02, "Ratio tracking: {} vs {}", ratio, expected);
+
+        let escaped = (0..n).filter(|_| {
+            match grid.sample_collision(&vr, &ray, t0, t1, &mut rng) {
+                Some(t) => {
+                    assert!(t > t0 && t < t1);
+                    false
+                },
+                None => true
+            }
+        }).count();
+        let delta = (escaped as f32) / (n as f32);
+        assert!((delta - expected).abs() < 0.03, "Delta tracking: {} vs {}", delta, expected);
+    }
+}
@@ -1,15 +1,17 @@
 pub mod aggregate;
 pub mod density;
 pub mod homogeneous;
+pub mod majorant;
 
-use bbox::BBox;
-use bbox::HasBounds;
-use geometry::point::Point;
-use geometry::vector::Dot;
-use geometry::vector::Vector;
-use intersection::Intersectable;
-use ray::Ray;
-use spectrum::Spectrum;
+use crate::bbox::BBox;
+use crate::bbox::HasBounds;
+use crate::geometry::point::Point;
+use crate::geometry::vector::Dot;
+use crate::geometry::vector::Vector;
+use crate::intersection::Intersectable;
+use crate::ray::Ray;
+use crate::spectrum::Spectrum;
+use crate::volume::majorant::MajorantGrid;
 
 use std::fmt::Debug;
 use std::marker::Send;
@@ -23,11 +25,22 @@ Send + Sync + Debug + HasBounds + Intersectable<(f32, f32)> {
         fn sigma_s(&self, p: &Point, w: &Vector, time: f32) -> Spectrum;
         fn l_ve(&self, p: &Point, w: &Vector, time: f32) -> Spectrum;
         fn p(&self, p: &Point, w: &Vector, wp: &Vector, time: f32) -> f32;
-        fn tau(&self, ray: &Ray, t0: f32, t1: f32) -> Spectrum;
+
+        // The optical thickness along the parametric range of the ray.
+        // Regions that aren't homogeneous estimate it by ray marching with
+        // the given step size, starting at a fraction offset of the first
+        // step.
+        fn tau(&self, ray: &Ray, step_size: f32, offset: f32) -> Spectrum;
 
         fn sigma_t(&self, p: &Point, w: &Vector, time: f32) -> Spectrum {
             self.sigma_a(p, w, time) + self.sigma_s(p, w, time)
         }
+
+        // A res^3 grid that bounds the extinction of the region. Unless a
+        // region knows better, it's estimated from samples of sigma_t.
+        fn majorants(&self, res: usize) -> MajorantGrid {
+            MajorantGrid::sampled(self, res)
+        }
 }
 
 pub fn phase_isotropic(_: &Vector, _: &Vector) -> f32 {
@@ -1,7 +1,5 @@
 #![allow(unused)]
 
-extern crate pbrt_rust;
-
 use pbrt_rust::bbox::BBox;
 use pbrt_rust::bbox::HasBounds;
 use pbrt_rust::primitive::Primitive;
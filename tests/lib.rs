@@ -1,3 +1 @@
-extern crate pbrt_rust;
-
 pub mod aggregates;